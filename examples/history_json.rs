@@ -0,0 +1,26 @@
+//! Converts an execution history, saved with `History::to_bytes`, to JSON for tools that don't
+//! read the binary format (see the z80::history module for both).
+//! Run it with `cargo run --example history_json -- FILE`, and the JSON is printed.
+use std::env;
+use std::fs;
+use std::process;
+
+use zeerust::z80::history::History;
+
+fn main() {
+    let path = env::args().nth(1).unwrap_or_else(|| {
+        eprintln!("Usage: history_json FILE");
+        process::exit(1);
+    });
+    let data = fs::read(&path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        process::exit(1);
+    });
+    match History::from_bytes(&data) {
+        Ok(history) => println!("{}", history.to_json()),
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,65 @@
+//! Runs a small program on the homebrew machine: it greets you over the serial console, then
+//! prints a tick every second, counted from the 50Hz timer interrupt.
+//! Run it with `cargo run --example homebrew`.
+use std::io::Write;
+
+use zeerust::machine::homebrew::{self, Homebrew};
+use zeerust::machine::Machine;
+
+// Hand-assembled, with the address of each instruction alongside
+const PROGRAM: &[u8] = &[
+    // Print the message, a byte at a time, until the terminating 0
+    0x21, 0x50, 0x00, // 0000 LD HL, message
+    0x7E, // 0003 next: LD A, (HL)
+    0xB7, // 0004 OR A
+    0x28, 0x05, // 0005 JR Z, done
+    0xD3, 0x00, // 0007 OUT (0x00), A
+    0x2C, // 0009 INC L (the message doesn't cross a page, so L is enough)
+    0x18, 0xF7, // 000A JR next
+    // Count timer ticks in B, and wait for interrupts
+    0x06, 0x32, // 000C done: LD B, 50
+    0xED, 0x56, // 000E IM 1
+    0xFB, // 0010 EI
+    0x76, // 0011 wait: HALT
+    0x18, 0xFD, // 0012 JR wait
+];
+
+// Every 50 ticks, print a '*'
+const HANDLER: &[u8] = &[
+    0xF5, // 0038 PUSH AF
+    0x10, 0x06, // 0039 DJNZ skip
+    0x06, 0x32, // 003B LD B, 50
+    0x3E, b'*', // 003D LD A, '*'
+    0xD3, 0x00, // 003F OUT (0x00), A
+    0xF1, // 0041 skip: POP AF
+    0xFB, // 0042 EI
+    0xED, 0x4D, // 0043 RETI
+];
+
+const MESSAGE: &[u8] = b"Hello from a homebrew Z80!\n\0";
+
+fn main() {
+    // Lay the program out in memory: code from 0x0000, the interrupt handler at 0x0038, and
+    // the message at 0x0050
+    let mut memory = PROGRAM.to_vec();
+    memory.resize(0x38, 0x00);
+    memory.extend_from_slice(HANDLER);
+    memory.resize(0x50, 0x00);
+    memory.extend_from_slice(MESSAGE);
+
+    let mut machine = Homebrew::new(&memory);
+    let stdout = std::io::stdout();
+    // Five seconds of emulated time
+    while machine.frame() < u64::from(homebrew::TIMER_RATE) * 5 {
+        machine.run_frame();
+        let mut out = stdout.lock();
+        out.write_all(&machine.take_output()).unwrap();
+        out.flush().unwrap();
+    }
+    println!();
+    println!(
+        "Ran {} timer ticks, ending at PC 0x{:04x}",
+        machine.frame(),
+        machine.z80().registers.get_pc()
+    );
+}
@@ -0,0 +1,191 @@
+//! Mixing and resampling of sound produced by emulated hardware.
+//! Sound devices (a beeper, an AY chip...) each get a channel on the Mixer, and report level
+//! changes at the T-state they happen. The mixer averages the combined signal over every host
+//! sample period, and hands finished buffers of samples to a callback.
+
+/// Common host sample rates
+pub const RATE_44_1KHZ: u32 = 44_100;
+pub const RATE_48KHZ: u32 = 48_000;
+
+/// Receives each full buffer of samples
+pub type BufferCallback = Box<dyn FnMut(&[i16])>;
+
+/// Identifies a channel added to a Mixer
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ChannelId(usize);
+
+struct Channel {
+    level: f32,
+    volume: f32,
+}
+
+/// Mixes any number of channels at emulated-cycle resolution, and resamples them to the host rate
+pub struct Mixer {
+    tstates_per_sample: f64,
+    buffer_size: usize,
+    channels: Vec<Channel>,
+
+    // Time up to which samples have been generated
+    tstate: u64,
+    // Progress through the sample currently being generated
+    elapsed: f64,
+    accumulator: f64,
+
+    buffer: Vec<i16>,
+    on_buffer: Option<BufferCallback>,
+}
+
+impl Mixer {
+    /// Create a mixer for a CPU running at cpu_clock Hz, producing sample_rate samples per second
+    /// in buffers of buffer_size samples.
+    pub fn new(cpu_clock: u32, sample_rate: u32, buffer_size: usize) -> Self {
+        assert!(buffer_size > 0, "buffer size must be positive");
+        Self {
+            tstates_per_sample: f64::from(cpu_clock) / f64::from(sample_rate),
+            buffer_size,
+            channels: vec![],
+            tstate: 0,
+            elapsed: 0.0,
+            accumulator: 0.0,
+            buffer: Vec::with_capacity(buffer_size),
+            on_buffer: None,
+        }
+    }
+
+    /// Add a new, silent channel. Volume scales the channel's contribution to the mix.
+    pub fn add_channel(&mut self, volume: f32) -> ChannelId {
+        self.channels.push(Channel { level: 0.0, volume });
+        ChannelId(self.channels.len() - 1)
+    }
+
+    /// Called with every full buffer of samples
+    pub fn on_buffer(&mut self, callback: BufferCallback) {
+        self.on_buffer = Some(callback);
+    }
+
+    /// Change a channel's output level (between -1.0 and 1.0) at the given T-state.
+    /// Updates must be made in chronological order.
+    pub fn set_level(&mut self, channel: ChannelId, tstate: u64, level: f32) {
+        self.advance_to(tstate);
+        self.channels[channel.0].level = level;
+    }
+
+    /// Generate samples up to the given T-state, using the current channel levels
+    pub fn advance_to(&mut self, tstate: u64) {
+        if tstate <= self.tstate {
+            return;
+        }
+        let mix = self.mix();
+        let mut remaining = (tstate - self.tstate) as f64;
+        self.tstate = tstate;
+
+        while remaining > 0.0 {
+            let step = remaining.min(self.tstates_per_sample - self.elapsed);
+            self.accumulator += mix * step;
+            self.elapsed += step;
+            remaining -= step;
+
+            if self.elapsed >= self.tstates_per_sample {
+                let sample = self.accumulator / self.tstates_per_sample;
+                self.push_sample(sample);
+                self.elapsed = 0.0;
+                self.accumulator = 0.0;
+            }
+        }
+    }
+
+    /// Hand the samples made since the last full buffer to the callback now, as a short
+    /// buffer. For when samples are wanted at a moment rather than in fixed amounts, such
+    /// as the end of a frame.
+    pub fn flush(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        if let Some(f) = self.on_buffer.as_mut() {
+            f(&self.buffer);
+        }
+        self.buffer.clear();
+    }
+
+    fn mix(&self) -> f64 {
+        let sum: f32 = self.channels.iter().map(|c| c.level * c.volume).sum();
+        f64::from(sum.clamp(-1.0, 1.0))
+    }
+
+    fn push_sample(&mut self, sample: f64) {
+        self.buffer.push((sample * f64::from(i16::MAX)) as i16);
+        if self.buffer.len() == self.buffer_size {
+            if let Some(f) = self.on_buffer.as_mut() {
+                f(&self.buffer);
+            }
+            self.buffer.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn collect(mixer: &mut Mixer) -> Rc<RefCell<Vec<i16>>> {
+        let out = Rc::new(RefCell::new(vec![]));
+        let o = out.clone();
+        mixer.on_buffer(Box::new(move |buf| o.borrow_mut().extend_from_slice(buf)));
+        out
+    }
+
+    #[test]
+    fn silence() {
+        let mut mixer = Mixer::new(1000, 100, 4);
+        let out = collect(&mut mixer);
+        mixer.add_channel(1.0);
+        mixer.advance_to(80);
+        assert_eq!(vec![0; 8], *out.borrow());
+    }
+
+    #[test]
+    fn fixed_buffers() {
+        let mut mixer = Mixer::new(1000, 100, 4);
+        let out = collect(&mut mixer);
+        let beeper = mixer.add_channel(1.0);
+        mixer.set_level(beeper, 0, 1.0);
+        // Only complete buffers are delivered
+        mixer.advance_to(70);
+        assert_eq!(4, out.borrow().len());
+        mixer.advance_to(80);
+        assert_eq!(vec![i16::MAX; 8], *out.borrow());
+        mixer.advance_to(100);
+        mixer.flush();
+        assert_eq!(vec![i16::MAX; 10], *out.borrow());
+        mixer.flush();
+        assert_eq!(10, out.borrow().len());
+    }
+
+    #[test]
+    fn averages_within_sample() {
+        let mut mixer = Mixer::new(1000, 100, 2);
+        let out = collect(&mut mixer);
+        let beeper = mixer.add_channel(1.0);
+        // High for the first half of the first sample
+        mixer.set_level(beeper, 0, 1.0);
+        mixer.set_level(beeper, 5, 0.0);
+        mixer.advance_to(20);
+        assert_eq!(vec![i16::MAX / 2, 0], *out.borrow());
+    }
+
+    #[test]
+    fn mixes_and_clips() {
+        let mut mixer = Mixer::new(1000, 100, 1);
+        let out = collect(&mut mixer);
+        let beeper = mixer.add_channel(0.5);
+        let ay = mixer.add_channel(1.0);
+        mixer.set_level(beeper, 0, 1.0);
+        mixer.set_level(ay, 0, -1.0);
+        mixer.set_level(beeper, 10, 1.0);
+        mixer.set_level(ay, 10, 1.0);
+        mixer.advance_to(20);
+        assert_eq!(vec![-i16::MAX / 2, i16::MAX], *out.borrow());
+    }
+}
@@ -0,0 +1,172 @@
+//! The Spectrum's border, drawn as the ULA draws it: a line at a time as the frame goes by, so a
+//! colour written halfway along a scanline changes the border from there on. Multicolour border
+//! demos, and the stripes of a loading tape, depend on it.
+//!
+//! A Border is an IoTrace, which sees every OUT with the T-state its I/O cycle started at; the
+//! ULA takes writes to any even port. Like the 48K ULA, it only picks up a new colour every 4
+//! T-states, so the border changes in steps of 8 pixels.
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::machine::Framebuffer;
+use crate::screenshot::{self, HEIGHT, PALETTE, WIDTH};
+use crate::z80::io::{Direction, IoEvent, IoTrace};
+
+/// How much border is drawn around the screen, in pixels (and so scanlines)
+pub const BORDER: usize = 48;
+/// The size of the picture with its border
+pub const FULL_WIDTH: usize = WIDTH + 2 * BORDER;
+pub const FULL_HEIGHT: usize = HEIGHT + 2 * BORDER;
+
+/// T-states from the frame interrupt to the first pixel of the screen, on the 48K Spectrum
+pub const PAPER_START: u64 = 14336;
+const TSTATES_PER_LINE: u64 = 224;
+// T-states from the frame interrupt to the top left of the border, two pixels to a T-state
+const ORIGIN: u64 = PAPER_START - BORDER as u64 * TSTATES_PER_LINE - BORDER as u64 / 2;
+
+#[derive(Debug)]
+struct State {
+    // The colour at the start of the frame, and every change since
+    colour: u8,
+    changes: Vec<(u64, u8)>,
+}
+
+/// The border colours written in a frame. Clones share the same border.
+#[derive(Debug, Clone)]
+pub struct Border(Rc<RefCell<State>>);
+
+impl IoTrace for Border {
+    fn trace(&self, event: IoEvent) {
+        if event.direction == Direction::Out && event.port & 1 == 0 {
+            let colour = event.value & 0x07;
+            self.0.borrow_mut().changes.push((event.tstate, colour));
+        }
+    }
+}
+
+impl Border {
+    /// A border starting in `colour`. For example:
+    /// ```
+    /// use zeerust::border::{self, Border};
+    /// use zeerust::z80::{io::BufOutput, Z80};
+    ///
+    /// let mut z80 = Z80::default();
+    /// z80.install_output(0xFE, Box::new(BufOutput::default()));
+    /// let border = Border::new(7);
+    /// z80.set_io_trace(Box::new(border.clone()));
+    /// z80.load(&[0x3E, 0x02, 0xD3, 0xFE, 0x76]); // LD A, 2; OUT (0xFE), A; HALT
+    /// z80.run();
+    /// assert_eq!(2, border.colour());
+    /// let picture = border.render(0, &[0; 6912], false);
+    /// assert_eq!((border::FULL_WIDTH, border::FULL_HEIGHT), (picture.width, picture.height));
+    /// assert_eq!([0xD7, 0x00, 0x00], picture.pixels[0]);
+    ///```
+    pub fn new(colour: u8) -> Self {
+        Self(Rc::new(RefCell::new(State {
+            colour: colour & 0x07,
+            changes: vec![],
+        })))
+    }
+
+    /// The colour most recently written
+    pub fn colour(&self) -> u8 {
+        let state = self.0.borrow();
+        state
+            .changes
+            .last()
+            .map_or(state.colour, |(_, colour)| *colour)
+    }
+
+    /// Draw the frame that began (with its interrupt) at T-state `start`: the border as it was
+    /// written while each part of it was drawn, around a .SCR file. See screenshot::render_scr.
+    ///
+    /// # Panics
+    /// Panics if `scr` is shorter than SCR_SIZE
+    pub fn render(&self, start: u64, scr: &[u8], flash: bool) -> Framebuffer {
+        let state = self.0.borrow();
+        let mut colour = state.colour;
+        let mut changes = state.changes.iter().peekable();
+        let mut indices = Vec::with_capacity(FULL_WIDTH * FULL_HEIGHT);
+        for y in 0..FULL_HEIGHT {
+            let line = start + ORIGIN + y as u64 * TSTATES_PER_LINE;
+            for x in 0..FULL_WIDTH {
+                // The colour the ULA last picked up
+                let latched = (line + x as u64 / 2 - start) & !3;
+                while let Some((_, c)) = changes.next_if(|(t, _)| *t <= start + latched) {
+                    colour = *c;
+                }
+                indices.push(colour);
+            }
+        }
+        let mut picture = Framebuffer::from_indexed(FULL_WIDTH, FULL_HEIGHT, &indices, &PALETTE);
+
+        let paper = screenshot::render_scr(scr, flash);
+        for (y, row) in paper.pixels.chunks(WIDTH).enumerate() {
+            let offset = (BORDER + y) * FULL_WIDTH + BORDER;
+            picture.pixels[offset..offset + WIDTH].copy_from_slice(row);
+        }
+        picture
+    }
+
+    /// Forget the changes before T-state `start`, when the next frame begins
+    pub fn end_frame(&self, start: u64) {
+        let mut state = self.0.borrow_mut();
+        let done = state.changes.partition_point(|(t, _)| *t < start);
+        if done > 0 {
+            state.colour = state.changes[done - 1].1;
+            state.changes.drain(..done);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn out(border: &Border, tstate: u64, colour: u8) {
+        border.trace(IoEvent {
+            tstate,
+            port: 0x00FE,
+            value: colour,
+            direction: Direction::Out,
+        });
+    }
+
+    #[test]
+    fn mid_scanline() {
+        let border = Border::new(1);
+        let start = 70_000;
+        let row = start + ORIGIN + 10 * TSTATES_PER_LINE;
+        out(&border, row + 100, 2);
+        // Not picked up until 4 T-states later
+        out(&border, row + 150, 3);
+        // Odd ports only
+        border.trace(IoEvent {
+            tstate: row + 160,
+            port: 0x00FF,
+            value: 4,
+            direction: Direction::Out,
+        });
+
+        let picture = border.render(start, &[0; screenshot::SCR_SIZE], false);
+        let pixel = |x: usize, y: usize| picture.pixels[y * FULL_WIDTH + x];
+        assert_eq!(PALETTE[1], pixel(FULL_WIDTH - 1, 9));
+        assert_eq!(PALETTE[1], pixel(199, 10));
+        assert_eq!(PALETTE[2], pixel(200, 10));
+        assert_eq!(PALETTE[2], pixel(303, 10));
+        assert_eq!(PALETTE[3], pixel(304, 10));
+        assert_eq!(PALETTE[3], pixel(0, FULL_HEIGHT - 1));
+        // The paper covers the middle
+        assert_eq!(PALETTE[0], pixel(BORDER, BORDER + 100));
+
+        border.end_frame(row + 120);
+        assert_eq!(3, border.colour());
+        // Drawn again, the border starts in the colour of the change forgotten
+        let picture = border.render(start, &[0; screenshot::SCR_SIZE], false);
+        assert_eq!(PALETTE[2], picture.pixels[0]);
+        assert_eq!(PALETTE[3], picture.pixels[10 * FULL_WIDTH + 304]);
+        border.end_frame(row + 200);
+        let picture = border.render(start, &[0; screenshot::SCR_SIZE], false);
+        assert_eq!(PALETTE[3], picture.pixels[0]);
+    }
+}
@@ -0,0 +1,669 @@
+//! High level emulation of the Spectrum ROM's floating point calculator.
+//! BASIC does all its arithmetic through RST 0x28: the bytes after the restart are a little
+//! program of "literals" for the calculator, ending with end-calc (0x38), that work on a stack of
+//! 5 byte numbers in memory. Interpreting those in Z80 code is slow, so this trap runs them in
+//! Rust instead, reading and writing the same stack, and returns after the end-calc.
+//!
+//! With Precision::Exact, only the literals whose results are exactly the ROM's are run: moving
+//! numbers about, constants, comparisons and sums of small integers, jumps and the like.
+//! Precision::Native also runs the arithmetic and functions in f64, rounded into the
+//! Spectrum's format, which is a great deal faster for BASIC-heavy programs but can differ
+//! in the last bit. Either way, a calculation with anything the trap doesn't handle (strings,
+//! series, errors such as dividing by zero) is left to the ROM from the start, so the ROM
+//! reports the error as it would have.
+//!
+//! The calculator stack is above the processor's own memory, so this needs a bus.
+use std::collections::HashMap;
+
+use crate::ops::{Reg16, Reg8};
+use crate::z80::trap::TrapAction;
+use crate::z80::Z80;
+
+/// The RST 0x28 entry point
+pub const RESTART: u16 = 0x0028;
+
+// System variables
+const STKBOT: u16 = 0x5C63;
+const STKEND: u16 = 0x5C65;
+const BREG: u16 = 0x5C67;
+const MEM: u16 = 0x5C68;
+
+// The ROM's TEST-ROOM wants this much between the stack and the machine stack
+const ROOM: u16 = 80;
+// Give up on calculations that loop for longer than this
+const MAX_LITERALS: usize = 100_000;
+
+/// A number in the calculator's 5 byte format
+type Number = [u8; 5];
+
+const ZERO: Number = [0, 0, 0, 0, 0];
+const ONE: Number = [0, 0, 1, 0, 0];
+
+/// Which literals the trap runs itself
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Precision {
+    /// Only those with results identical to the ROM's
+    Exact,
+    /// Arithmetic and functions too, in f64
+    Native,
+}
+
+impl Z80 {
+    /// Run the ROM calculator's literals natively, with a trap at RST 0x28. For example:
+    /// ```
+    /// use zeerust::calculator::Precision;
+    /// # use zeerust::testing::FlatRam;
+    /// # use zeerust::z80::Z80;
+    /// # let mut z80 = Z80::default();
+    /// # z80.set_bus(Box::new(FlatRam::new()));
+    ///
+    /// // A Spectrum, with its ROM and system variables
+    /// z80.trap_calculator(Precision::Native);
+    ///```
+    pub fn trap_calculator(&mut self, precision: Precision) {
+        self.install_trap(RESTART, Box::new(move |z80| calculate(z80, precision)));
+    }
+}
+
+fn calculate(z80: &mut Z80, precision: Precision) -> TrapAction {
+    let sp = z80.registers.get_reg16(&Reg16::SP);
+    let mut calc = match Calculation::new(z80, precision) {
+        Some(calc) => calc,
+        None => return TrapAction::Continue,
+    };
+    let next = match calc.run() {
+        Some(next) => next,
+        None => return TrapAction::Continue,
+    };
+    let stkend = calc.word(STKEND).unwrap();
+    for (addr, val) in calc.writes {
+        z80.poke(addr, val);
+    }
+    // As the ROM leaves them: the return address taken, and the last number and the end of
+    // the stack in HL and DE
+    let regs = &mut z80.registers;
+    regs.set_reg16(&Reg16::SP, sp.wrapping_add(2));
+    regs.set_reg16(&Reg16::HL, stkend.wrapping_sub(5));
+    regs.set_reg16(&Reg16::DE, stkend);
+    TrapAction::Jump(next)
+}
+
+// A calculation run against memory, with its writes held back until it's known to have
+// finished without needing the ROM
+struct Calculation<'a> {
+    z80: &'a Z80,
+    precision: Precision,
+    writes: HashMap<u16, u8>,
+    // The next literal
+    pc: u16,
+}
+
+impl<'a> Calculation<'a> {
+    fn new(z80: &'a Z80, precision: Precision) -> Option<Self> {
+        let sp = z80.registers.get_reg16(&Reg16::SP);
+        let mut calc = Self {
+            z80,
+            precision,
+            writes: HashMap::new(),
+            pc: 0,
+        };
+        calc.pc = calc.word(sp)?;
+        // CALCULATE keeps B for dec-jr-nz
+        calc.write(BREG, z80.registers.get_reg8(Reg8::B));
+        Some(calc)
+    }
+
+    fn read(&self, addr: u16) -> Option<u8> {
+        match self.writes.get(&addr) {
+            Some(val) => Some(*val),
+            None => self.z80.peek(addr),
+        }
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        self.writes.insert(addr, val);
+    }
+
+    fn word(&self, addr: u16) -> Option<u16> {
+        Some(u16::from_le_bytes([
+            self.read(addr)?,
+            self.read(addr.wrapping_add(1))?,
+        ]))
+    }
+
+    fn set_word(&mut self, addr: u16, val: u16) {
+        let [low, high] = val.to_le_bytes();
+        self.write(addr, low);
+        self.write(addr.wrapping_add(1), high);
+    }
+
+    fn number(&self, addr: u16) -> Option<Number> {
+        let mut n = ZERO;
+        for (i, b) in n.iter_mut().enumerate() {
+            *b = self.read(addr.wrapping_add(i as u16))?;
+        }
+        Some(n)
+    }
+
+    fn set_number(&mut self, addr: u16, n: Number) {
+        for (i, b) in n.iter().enumerate() {
+            self.write(addr.wrapping_add(i as u16), *b);
+        }
+    }
+
+    fn fetch(&mut self) -> Option<u8> {
+        let b = self.read(self.pc)?;
+        self.pc = self.pc.wrapping_add(1);
+        Some(b)
+    }
+
+    // The relative jump at pc, as the ROM's JUMP-2 does it
+    fn jump(&mut self) -> Option<()> {
+        let offset = self.read(self.pc)? as i8;
+        self.pc = self.pc.wrapping_add(offset as u16);
+        Some(())
+    }
+
+    fn push(&mut self, n: Number) -> Option<()> {
+        let end = self.word(STKEND)?;
+        let sp = self.z80.registers.get_reg16(&Reg16::SP);
+        if u32::from(end) + 5 + u32::from(ROOM) > u32::from(sp) {
+            return None;
+        }
+        self.set_number(end, n);
+        self.set_word(STKEND, end + 5);
+        Some(())
+    }
+
+    fn pop(&mut self) -> Option<Number> {
+        let end = self.word(STKEND)?;
+        if end < self.word(STKBOT)?.checked_add(5)? {
+            return None;
+        }
+        self.set_word(STKEND, end - 5);
+        self.number(end - 5)
+    }
+
+    fn last(&self) -> Option<Number> {
+        let end = self.word(STKEND)?;
+        if end < self.word(STKBOT)?.checked_add(5)? {
+            return None;
+        }
+        self.number(end - 5)
+    }
+
+    fn memory(&self, n: u8) -> Option<u16> {
+        Some(self.word(MEM)?.wrapping_add(5 * u16::from(n)))
+    }
+
+    fn native(&self) -> Option<()> {
+        match self.precision {
+            Precision::Native => Some(()),
+            Precision::Exact => None,
+        }
+    }
+
+    // Run literals up to the end-calc, returning the address after it. Returns None if the
+    // ROM has to do the calculation.
+    fn run(&mut self) -> Option<u16> {
+        for _ in 0..MAX_LITERALS {
+            let literal = self.fetch()?;
+            if literal == 0x38 {
+                return Some(self.pc);
+            }
+            self.literal(literal)?;
+        }
+        None
+    }
+
+    fn literal(&mut self, literal: u8) -> Option<()> {
+        match literal {
+            // jump-true, on the third byte as the ROM tests it
+            0x00 => {
+                if self.pop()?[2] != 0 {
+                    self.jump()?;
+                } else {
+                    self.pc = self.pc.wrapping_add(1);
+                }
+            }
+            // exchange
+            0x01 => {
+                let (x, y) = (self.pop()?, self.pop()?);
+                self.push(x)?;
+                self.push(y)?;
+            }
+            // delete
+            0x02 => {
+                self.pop()?;
+            }
+            0x03 | 0x04 | 0x0F => self.binary(literal)?,
+            // division
+            0x05 => {
+                self.native()?;
+                let (y, x) = (decode(self.pop()?), decode(self.pop()?));
+                if y == 0.0 {
+                    return None;
+                }
+                self.push(encode(x / y)?)?;
+            }
+            // to-power
+            0x06 => {
+                self.native()?;
+                let (y, x) = (decode(self.pop()?), decode(self.pop()?));
+                // As the ROM has it, 0 to the power 0 is 1
+                let result = if x < 0.0 || (x == 0.0 && y < 0.0) {
+                    return None;
+                } else if x == 0.0 {
+                    f64::from(u8::from(y == 0.0))
+                } else {
+                    x.powf(y)
+                };
+                self.push(encode(result)?)?;
+            }
+            // or
+            0x07 => {
+                let (y, x) = (self.pop()?, self.pop()?);
+                self.push(if is_zero(&y) { x } else { ONE })?;
+            }
+            // no-&-no
+            0x08 => {
+                let (y, x) = (self.pop()?, self.pop()?);
+                self.push(if is_zero(&y) { ZERO } else { x })?;
+            }
+            // Comparisons of numbers
+            0x09..=0x0E => {
+                let (y, x) = (self.pop()?, self.pop()?);
+                if small_int(&x).is_none() || small_int(&y).is_none() {
+                    self.native()?;
+                }
+                let (x, y) = (decode(x), decode(y));
+                let result = match literal {
+                    0x09 => x <= y,
+                    0x0A => x >= y,
+                    0x0B => x != y,
+                    0x0C => x > y,
+                    0x0D => x < y,
+                    _ => x == y,
+                };
+                self.push(boolean(result))?;
+            }
+            // negate
+            0x1B => {
+                let x = self.pop()?;
+                self.push(negate(x)?)?;
+            }
+            // The functions from sin to exp
+            0x1F..=0x26 => {
+                self.native()?;
+                let x = decode(self.pop()?);
+                let result = match literal {
+                    0x1F => x.sin(),
+                    0x20 => x.cos(),
+                    0x21 => x.tan(),
+                    0x22 if x.abs() <= 1.0 => x.asin(),
+                    0x23 if x.abs() <= 1.0 => x.acos(),
+                    0x24 => x.atan(),
+                    0x25 if x > 0.0 => x.ln(),
+                    0x26 => x.exp(),
+                    _ => return None,
+                };
+                self.push(encode(result)?)?;
+            }
+            // int and truncate, which leave small integers alone
+            0x27 | 0x3A => {
+                let x = self.pop()?;
+                if small_int(&x).is_some() {
+                    self.push(x)?;
+                } else {
+                    self.native()?;
+                    let x = decode(x);
+                    let result = if literal == 0x27 {
+                        x.floor()
+                    } else {
+                        x.trunc()
+                    };
+                    self.push(encode(result)?)?;
+                }
+            }
+            // sqr
+            0x28 => {
+                self.native()?;
+                let x = decode(self.pop()?);
+                if x < 0.0 {
+                    return None;
+                }
+                self.push(encode(x.sqrt())?)?;
+            }
+            // sgn
+            0x29 => {
+                let x = self.pop()?;
+                if is_zero(&x) {
+                    self.push(x)?;
+                } else {
+                    self.push(if x[1] & 0x80 != 0 { negate(ONE)? } else { ONE })?;
+                }
+            }
+            // abs
+            0x2A => {
+                let x = self.pop()?;
+                self.push(if x[1] & 0x80 != 0 { negate(x)? } else { x })?;
+            }
+            // not
+            0x30 => {
+                let x = self.pop()?;
+                self.push(boolean(is_zero(&x)))?;
+            }
+            // duplicate
+            0x31 => {
+                let x = self.last()?;
+                self.push(x)?;
+            }
+            // n-mod-m: the remainder, then the quotient
+            0x32 => {
+                self.native()?;
+                let (m, n) = (decode(self.pop()?), decode(self.pop()?));
+                if m == 0.0 {
+                    return None;
+                }
+                let quotient = (n / m).floor();
+                self.push(encode(n - quotient * m)?)?;
+                self.push(encode(quotient)?)?;
+            }
+            // jump
+            0x33 => self.jump()?,
+            // stk-data
+            0x34 => {
+                let n = self.stack_literal()?;
+                self.push(n)?;
+            }
+            // dec-jr-nz
+            0x35 => {
+                let b = self.read(BREG)?.wrapping_sub(1);
+                self.write(BREG, b);
+                if b != 0 {
+                    self.jump()?;
+                } else {
+                    self.pc = self.pc.wrapping_add(1);
+                }
+            }
+            // less-0
+            0x36 => {
+                let x = self.pop()?;
+                self.push(boolean(x[1] & 0x80 != 0))?;
+            }
+            // greater-0
+            0x37 => {
+                let x = self.pop()?;
+                self.push(if is_zero(&x) {
+                    x
+                } else {
+                    boolean(x[1] & 0x80 == 0)
+                })?;
+            }
+            // re-stack
+            0x3D => {
+                let x = self.pop()?;
+                self.push(match small_int(&x) {
+                    Some(n) => encode_float(f64::from(n))?,
+                    None => x,
+                })?;
+            }
+            // stk-zero, stk-one, stk-half, stk-pi/2 and stk-ten
+            0xA0 => self.push(ZERO)?,
+            0xA1 => self.push(ONE)?,
+            0xA2 => self.push([0x80, 0x00, 0x00, 0x00, 0x00])?,
+            0xA3 => self.push([0x81, 0x49, 0x0F, 0xDA, 0xA2])?,
+            0xA4 => self.push([0x00, 0x00, 0x0A, 0x00, 0x00])?,
+            // st-mem-n
+            0xC0..=0xC5 => {
+                let x = self.last()?;
+                let addr = self.memory(literal & 0x07)?;
+                self.set_number(addr, x);
+            }
+            // get-mem-n
+            0xE0..=0xE5 => {
+                let x = self.number(self.memory(literal & 0x07)?)?;
+                self.push(x)?;
+            }
+            // Strings, series, and anything else is the ROM's
+            _ => return None,
+        }
+        Some(())
+    }
+
+    // subtract, multiply and addition. On small integers the ROM keeps them as integers
+    // when the result fits, which is exact either way.
+    fn binary(&mut self, literal: u8) -> Option<()> {
+        let (y, x) = (self.pop()?, self.pop()?);
+        if let (Some(a), Some(b)) = (small_int(&x), small_int(&y)) {
+            let result = match literal {
+                0x03 => a - b,
+                0x04 => a * b,
+                _ => a + b,
+            };
+            if let Some(n) = encode_int(result) {
+                return self.push(n);
+            }
+        }
+        self.native()?;
+        let (x, y) = (decode(x), decode(y));
+        let result = match literal {
+            0x03 => x - y,
+            0x04 => x * y,
+            _ => x + y,
+        };
+        self.push(encode(result)?)
+    }
+
+    // A number packed into the literals, as STK-DATA unpacks it: the top two bits of the first
+    // byte are how many mantissa bytes follow, less one, and the rest the exponent (or the
+    // next byte is, if they're 0), less 0x50. The mantissa bytes left out are zero.
+    fn stack_literal(&mut self) -> Option<Number> {
+        let first = self.fetch()?;
+        let count = usize::from(first >> 6) + 1;
+        let exponent = match first & 0x3F {
+            0 => self.fetch()?,
+            e => e,
+        };
+        let mut n = [exponent.wrapping_add(0x50), 0, 0, 0, 0];
+        for b in n.iter_mut().skip(1).take(count) {
+            *b = self.fetch()?;
+        }
+        Some(n)
+    }
+}
+
+// Whether a number is zero, as TEST-ZERO sees it: the first four bytes all zero
+fn is_zero(n: &Number) -> bool {
+    n[..4].iter().all(|b| *b == 0)
+}
+
+fn boolean(b: bool) -> Number {
+    if b {
+        ONE
+    } else {
+        ZERO
+    }
+}
+
+// The value of a number in the small integer form: a zero, a sign byte, and 16 bits
+fn small_int(n: &Number) -> Option<i32> {
+    if n[0] != 0 {
+        return None;
+    }
+    let value = i32::from(u16::from_le_bytes([n[2], n[3]]));
+    Some(if n[1] == 0 { value } else { value - 0x10000 })
+}
+
+fn encode_int(n: i32) -> Option<Number> {
+    if !(-0xFFFF..=0xFFFF).contains(&n) {
+        return None;
+    }
+    let [low, high] = (n as u16).to_le_bytes();
+    Some([0, if n < 0 { 0xFF } else { 0 }, low, high, 0])
+}
+
+fn decode(n: Number) -> f64 {
+    if let Some(n) = small_int(&n) {
+        return f64::from(n);
+    }
+    let mantissa = u32::from_be_bytes([n[1] | 0x80, n[2], n[3], n[4]]);
+    let value = f64::from(mantissa) * 2f64.powi(i32::from(n[0]) - 128 - 32);
+    if n[1] & 0x80 != 0 {
+        -value
+    } else {
+        value
+    }
+}
+
+// An integer in the small form if it fits, and anything else as floating point
+fn encode(x: f64) -> Option<Number> {
+    if x.fract() == 0.0 && x.abs() <= 65535.0 {
+        return encode_int(x as i32);
+    }
+    encode_float(x)
+}
+
+// A mantissa of 0.5 to 1 in 32 bits, with the top bit (always 1) replaced by the sign, and an
+// exponent biased by 128. None if it's too big.
+fn encode_float(x: f64) -> Option<Number> {
+    if !x.is_finite() {
+        return None;
+    }
+    if x == 0.0 {
+        return Some(ZERO);
+    }
+    let mut exponent = x.abs().log2().floor() as i32 + 1;
+    let mut mantissa = (x.abs() * 2f64.powi(32 - exponent)).round() as u64;
+    // log2 can be a little out either side of a power of two
+    while mantissa >= 1 << 32 {
+        exponent += 1;
+        mantissa = (x.abs() * 2f64.powi(32 - exponent)).round() as u64;
+    }
+    while mantissa < 1 << 31 {
+        exponent -= 1;
+        mantissa = (x.abs() * 2f64.powi(32 - exponent)).round() as u64;
+    }
+    if exponent + 128 > 0xFF {
+        return None;
+    }
+    if exponent + 128 < 1 {
+        return Some(ZERO);
+    }
+    let [m0, m1, m2, m3] = (mantissa as u32).to_be_bytes();
+    let sign = if x < 0.0 { 0x80 } else { 0 };
+    Some([(exponent + 128) as u8, m0 & 0x7F | sign, m1, m2, m3])
+}
+
+// The ROM's negate: zero is left alone, integers stay integers, and anything else has its
+// sign flipped
+fn negate(n: Number) -> Option<Number> {
+    if is_zero(&n) {
+        return Some(n);
+    }
+    match small_int(&n) {
+        Some(value) => encode_int(-value),
+        None => Some([n[0], n[1] ^ 0x80, n[2], n[3], n[4]]),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::FlatRam;
+    use std::convert::TryInto;
+
+    const STACK: u16 = 0x6000;
+
+    // Run RST 0x28 and the literals with B set to 3, and a HALT where the ROM's calculator
+    // would be. Returns the numbers on the stack, or None if the ROM was needed.
+    fn calculate(precision: Precision, literals: &[u8]) -> Option<Vec<Number>> {
+        let ram = FlatRam::new();
+        ram.load(RESTART, &[0x76]);
+        ram.load(0x8000, &[0xEF]); // RST 0x28
+        ram.load(0x8001, literals);
+        ram.load(0x8001 + literals.len() as u16, &[0x76]);
+        for (addr, val) in [(STKBOT, STACK), (STKEND, STACK), (MEM, 0x5C92)] {
+            ram.load(addr, &val.to_le_bytes());
+        }
+        let mut z80 = Z80::default();
+        z80.set_bus(Box::new(ram.clone()));
+        z80.registers.set_reg16(&Reg16::SP, 0xFF00);
+        z80.registers.set_pc(0x8000);
+        z80.registers.set_reg8(Reg8::B, 3);
+        z80.trap_calculator(precision);
+        z80.run();
+        if z80.registers.get_pc() == RESTART + 1 {
+            return None;
+        }
+        assert_eq!(0xFF00, z80.registers.get_reg16(&Reg16::SP));
+        let end = u16::from_le_bytes([ram.peek(0x5C65), ram.peek(0x5C66)]);
+        assert_eq!(end, z80.registers.get_reg16(&Reg16::DE));
+        let stack: Vec<u8> = (STACK..end).map(|addr| ram.peek(addr)).collect();
+        let numbers = stack.chunks(5).map(|n| n.try_into().unwrap()).collect();
+        Some(numbers)
+    }
+
+    #[test]
+    fn format() {
+        assert_eq!(Some([0x00, 0xFF, 0xFF, 0xFF, 0x00]), encode(-1.0));
+        assert_eq!(
+            Some([0x81, 0x49, 0x0F, 0xDA, 0xA2]),
+            encode(std::f64::consts::FRAC_PI_2)
+        );
+        assert_eq!(Some([0x91, 0x80, 0x00, 0x00, 0x00]), encode(-65536.0));
+        assert_eq!(Some([0x80, 0x00, 0x00, 0x00, 0x00]), encode(0.5));
+        assert_eq!(None, encode(1e40));
+        for x in [0.1, -123.456, 1e-20, 65535.5, 1e30] {
+            let n = encode(x).unwrap();
+            assert!(
+                (decode(n) - x).abs() <= x.abs() / 4e9,
+                "{} became {:?}",
+                x,
+                n
+            );
+        }
+    }
+
+    #[test]
+    fn exact() {
+        // 2 * 2 * 2, with a loop: stk-one, then duplicate and add B times
+        let doubled = [0xA1, 0x31, 0x0F, 0x35, 0xFD, 0x38];
+        assert_eq!(
+            Some(vec![[0, 0, 8, 0, 0]]),
+            calculate(Precision::Exact, &doubled)
+        );
+        // 10 - 0.5 takes the ROM, and then the trap
+        let sum = [0xA4, 0xA2, 0x03, 0x38];
+        assert_eq!(None, calculate(Precision::Exact, &sum));
+        assert_eq!(
+            Some(vec![encode(9.5).unwrap()]),
+            calculate(Precision::Native, &sum)
+        );
+        // stk-data 3.25 = 0x82 0x50, then st-mem-0, delete, get-mem-0, negate, 10 < -3.25
+        let literals = [0x34, 0x32, 0x50, 0xC0, 0x02, 0xA4, 0xE0, 0x1B, 0x0D, 0x38];
+        assert_eq!(Some(vec![ZERO]), calculate(Precision::Native, &literals));
+        // Deleting from an empty stack, or dividing by zero, is for the ROM
+        assert_eq!(None, calculate(Precision::Native, &[0x02, 0x38]));
+        assert_eq!(
+            None,
+            calculate(Precision::Native, &[0xA1, 0xA0, 0x05, 0x38])
+        );
+    }
+
+    #[test]
+    fn native() {
+        // int(pi/2 * 10), sgn -1, abs -1, sqr 16 and 7 mod 3
+        let literals = [
+            0xA3, 0xA4, 0x04, 0x27, // 15
+            0xA1, 0x1B, 0x29, // -1
+            0xA1, 0x1B, 0x2A, // 1
+            0x34, 0x40, 0xB0, 0x00, 0x10, 0x28, // 4
+            0x34, 0x40, 0xB0, 0x00, 0x07, 0x34, 0x40, 0xB0, 0x00, 0x03, 0x32, // 1, 2
+            0x38,
+        ];
+        let stack = calculate(Precision::Native, &literals).unwrap();
+        let values: Vec<_> = stack.into_iter().map(decode).collect();
+        assert_eq!(vec![15.0, -1.0, 1.0, 4.0, 1.0, 2.0], values);
+    }
+}
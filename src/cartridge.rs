@@ -0,0 +1,311 @@
+//! Working out what a cartridge ROM is, and how to plug it in.
+//! Large cartridges carry hardware to page their ROM into the address space, and the ROM
+//! dump alone doesn't say which. `identify` guesses from the header, the size and, for MSX
+//! MegaROMs, the addresses the program writes to; that gets most cartridges right. For the
+//! rest, a `Database` of CRC-32s has the final say.
+use std::collections::HashMap;
+use std::fmt;
+
+/// The system a cartridge is for
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum System {
+    Msx,
+    /// The SG-1000 and Master System
+    Sega,
+}
+
+/// The hardware a cartridge uses to page its ROM in
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Mapper {
+    /// No mapper: the whole ROM is always visible
+    Plain,
+    /// MSX: Konami's mapper without the SCC, with 8K banks
+    Konami,
+    /// MSX: Konami's mapper with the SCC sound chip, with 8K banks
+    KonamiScc,
+    /// MSX: the ASCII mapper with 8K banks
+    Ascii8,
+    /// MSX: the ASCII mapper with 16K banks
+    Ascii16,
+    /// See `chips::sega_mapper`
+    Sega,
+}
+
+impl Mapper {
+    // The name used in database files
+    fn name(self) -> &'static str {
+        match self {
+            Mapper::Plain => "plain",
+            Mapper::Konami => "konami",
+            Mapper::KonamiScc => "konami-scc",
+            Mapper::Ascii8 => "ascii8",
+            Mapper::Ascii16 => "ascii16",
+            Mapper::Sega => "sega",
+        }
+    }
+}
+
+/// What a cartridge is, and what it needs
+#[derive(Debug, PartialEq, Clone)]
+pub struct RomInfo {
+    pub system: System,
+    pub mapper: Mapper,
+    /// The title, when it came from a database
+    pub name: Option<String>,
+}
+
+const SEGA_SIGNATURE: &[u8; 8] = b"TMR SEGA";
+// The header can be at any of these, the last being the usual one
+const SEGA_HEADERS: [usize; 3] = [0x1FF0, 0x3FF0, 0x7FF0];
+// The largest ROM that fits in the address space without a mapper
+const MSX_PLAIN_MAX: usize = 0x10000;
+const SEGA_PLAIN_MAX: usize = 0xC000;
+
+/// Guess what a cartridge is from the ROM alone.
+/// A "TMR SEGA" header means a Sega cartridge, which needs the Sega mapper if it's over 48K.
+/// Anything else is taken as an MSX cartridge, which needs a mapper if it's over 64K: the one
+/// picked is the one whose registers the program writes to most. SG-1000 cartridges have no
+/// header, so they need a database entry.
+/// ```
+/// use zeerust::cartridge::{self, Mapper, System};
+///
+/// let mut rom = vec![0; 0x20000];
+/// rom[..2].copy_from_slice(b"AB");
+/// rom[0x10..0x13].copy_from_slice(&[0x32, 0x00, 0x70]); // LD (0x7000), A
+/// rom[0x20..0x23].copy_from_slice(&[0x32, 0x00, 0x78]); // LD (0x7800), A
+/// let info = cartridge::identify(&rom);
+/// assert_eq!((System::Msx, Mapper::Ascii8), (info.system, info.mapper));
+///```
+pub fn identify(rom: &[u8]) -> RomInfo {
+    let sega = SEGA_HEADERS
+        .iter()
+        .any(|&at| rom.get(at..at + SEGA_SIGNATURE.len()) == Some(SEGA_SIGNATURE));
+    let (system, mapper) = if sega {
+        let mapper = if rom.len() > SEGA_PLAIN_MAX {
+            Mapper::Sega
+        } else {
+            Mapper::Plain
+        };
+        (System::Sega, mapper)
+    } else if rom.len() > MSX_PLAIN_MAX {
+        (System::Msx, guess_msx_mapper(rom))
+    } else {
+        (System::Msx, Mapper::Plain)
+    };
+    RomInfo {
+        system,
+        mapper,
+        name: None,
+    }
+}
+
+// Count the `LD (nn), A` instructions that write to each mapper's registers. Some addresses
+// are registers of more than one mapper, so they count for each of them.
+fn guess_msx_mapper(rom: &[u8]) -> Mapper {
+    const CANDIDATES: [Mapper; 4] = [
+        Mapper::KonamiScc,
+        Mapper::Konami,
+        Mapper::Ascii8,
+        Mapper::Ascii16,
+    ];
+    let mut votes = [0; 4];
+    for w in rom.windows(3).filter(|w| w[0] == 0x32) {
+        let hits: &[usize] = match u16::from_le_bytes([w[1], w[2]]) {
+            0x5000 | 0x9000 | 0xB000 => &[0],
+            0x4000 | 0x8000 | 0xA000 => &[1],
+            0x6800 | 0x7800 => &[2],
+            0x77FF => &[3],
+            0x6000 => &[1, 2, 3],
+            0x7000 => &[0, 2, 3],
+            _ => &[],
+        };
+        for &hit in hits {
+            votes[hit] += 1;
+        }
+    }
+    // The first of any tie wins, and with no votes at all that's the Konami SCC mapper
+    let best =
+        (0..CANDIDATES.len()).fold(0, |best, i| if votes[i] > votes[best] { i } else { best });
+    CANDIDATES[best]
+}
+
+/// The CRC-32 of some data, as used to identify ROMs in databases
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &b| {
+        (0..8).fold(crc ^ u32::from(b), |crc, _| {
+            if crc & 1 == 1 {
+                crc >> 1 ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+/// A line of a database that couldn't be read
+#[derive(Debug, PartialEq)]
+pub struct DatabaseError {
+    /// Counting from 1
+    pub line: usize,
+}
+
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bad ROM database entry on line {}", self.line)
+    }
+}
+
+impl std::error::Error for DatabaseError {}
+
+/// Known cartridges, by the CRC-32 of their ROM
+#[derive(Debug, Default)]
+pub struct Database {
+    entries: HashMap<u32, RomInfo>,
+}
+
+impl Database {
+    /// Read a database in text form. Each line holds the CRC-32 in hex, the system (`msx` or
+    /// `sega`), the mapper (`plain`, `konami`, `konami-scc`, `ascii8`, `ascii16` or `sega`)
+    /// and the title, separated by spaces. Blank lines, and lines starting with `#`, are
+    /// skipped.
+    /// ```
+    /// use zeerust::cartridge::{Database, Mapper, System};
+    ///
+    /// let db = Database::parse("# Sega\n2fb8f9c5 sega plain Some Game\n").unwrap();
+    /// let info = db.get(0x2FB8_F9C5).unwrap();
+    /// assert_eq!((System::Sega, Mapper::Plain), (info.system, info.mapper));
+    /// assert_eq!(Some("Some Game"), info.name.as_deref());
+    ///```
+    pub fn parse(text: &str) -> Result<Self, DatabaseError> {
+        let mut db = Self::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = || DatabaseError { line: i + 1 };
+            let mut fields = line.splitn(4, ' ');
+            let crc = fields
+                .next()
+                .and_then(|crc| u32::from_str_radix(crc, 16).ok())
+                .ok_or_else(error)?;
+            let system = match fields.next() {
+                Some("msx") => System::Msx,
+                Some("sega") => System::Sega,
+                _ => return Err(error()),
+            };
+            let mapper = fields
+                .next()
+                .and_then(|name| {
+                    [
+                        Mapper::Plain,
+                        Mapper::Konami,
+                        Mapper::KonamiScc,
+                        Mapper::Ascii8,
+                        Mapper::Ascii16,
+                        Mapper::Sega,
+                    ]
+                    .iter()
+                    .copied()
+                    .find(|m| m.name() == name)
+                })
+                .ok_or_else(error)?;
+            let name = fields.next().map(|name| name.trim().to_string());
+            db.insert(
+                crc,
+                RomInfo {
+                    system,
+                    mapper,
+                    name,
+                },
+            );
+        }
+        Ok(db)
+    }
+
+    /// Add a cartridge, replacing any entry with the same CRC-32
+    pub fn insert(&mut self, crc: u32, info: RomInfo) {
+        self.entries.insert(crc, info);
+    }
+
+    /// The entry for a CRC-32
+    pub fn get(&self, crc: u32) -> Option<&RomInfo> {
+        self.entries.get(&crc)
+    }
+
+    /// Look a ROM up, falling back to guessing when it isn't in the database
+    pub fn identify(&self, rom: &[u8]) -> RomInfo {
+        self.get(crc32(rom))
+            .cloned()
+            .unwrap_or_else(|| identify(rom))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write_to(rom: &mut [u8], at: usize, addrs: &[u16]) {
+        for (i, addr) in addrs.iter().enumerate() {
+            let [low, high] = addr.to_le_bytes();
+            rom[at + i * 3..at + i * 3 + 3].copy_from_slice(&[0x32, low, high]);
+        }
+    }
+
+    #[test]
+    fn msx() {
+        let mut rom = vec![0; 0x8000];
+        rom[..2].copy_from_slice(b"AB");
+        assert_eq!(Mapper::Plain, identify(&rom).mapper);
+
+        rom.resize(0x20000, 0);
+        write_to(&mut rom, 0x100, &[0x5000, 0x7000, 0x9000]);
+        assert_eq!(Mapper::KonamiScc, identify(&rom).mapper);
+        write_to(&mut rom, 0x100, &[0x6000, 0x8000, 0xA000]);
+        assert_eq!(Mapper::Konami, identify(&rom).mapper);
+        write_to(&mut rom, 0x100, &[0x6000, 0x7000, 0x77FF]);
+        assert_eq!(Mapper::Ascii16, identify(&rom).mapper);
+        assert_eq!(System::Msx, identify(&rom).system);
+    }
+
+    #[test]
+    fn sega() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x7FF0..0x7FF8].copy_from_slice(SEGA_SIGNATURE);
+        assert_eq!(
+            RomInfo {
+                system: System::Sega,
+                mapper: Mapper::Plain,
+                name: None
+            },
+            identify(&rom)
+        );
+        rom.resize(0x40000, 0);
+        assert_eq!(Mapper::Sega, identify(&rom).mapper);
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(0, crc32(&[]));
+        assert_eq!(0xCBF4_3926, crc32(b"123456789"));
+    }
+
+    #[test]
+    fn database() {
+        let rom = vec![0; 0x20000];
+        let crc = crc32(&rom);
+        let db = Database::parse(&format!("{:08x} msx ascii16 Nothing at all\n", crc)).unwrap();
+        assert_eq!(Mapper::Ascii16, db.identify(&rom).mapper);
+        // Not in the database
+        assert_eq!(Mapper::KonamiScc, db.identify(&rom[1..]).mapper);
+
+        assert_eq!(
+            Err(DatabaseError { line: 2 }),
+            Database::parse("\n12345678 msx mega\n").map(|_| ())
+        );
+        assert_eq!(
+            Err(DatabaseError { line: 1 }),
+            Database::parse("zz msx plain").map(|_| ())
+        );
+    }
+}
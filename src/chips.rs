@@ -0,0 +1,9 @@
+//! Peripheral chips found on more than one board: video, sound and the like.
+//! Each is a plain struct, driven by whichever machine or Bus it is wired into. Chips reached
+//! through I/O ports also come with devices for routing them with a PortDecoder.
+
+pub mod ay38910;
+pub mod crtc6845;
+pub mod sega_mapper;
+pub mod sn76489;
+pub mod tms9918;
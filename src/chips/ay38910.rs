@@ -0,0 +1,382 @@
+//! The AY-3-8910 sound generator, and its smaller siblings the AY-3-8912 and YM2149, as used by
+//! the Amstrad CPC, MSX and ZX Spectrum 128.
+//! Three square wave channels, a noise generator that can be mixed into any of them, and an
+//! envelope generator, programmed through 16 registers: one write picks a register, and the
+//! next reads or writes it. Registers 14 and 15 are general purpose I/O ports, which machines
+//! use for the keyboard or joysticks.
+//! Like the SN76489, each channel gets its own channel on the Mixer, and the chip converts its
+//! own clock cycles to T-states of the CPU when it reports a change of level.
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::audio::{ChannelId, Mixer};
+use crate::z80::savestate::{SaveStateError, StateReader};
+
+// The tone counters are stepped once every 8 clock cycles, and the noise and envelope every 16
+const DIVIDER: u64 = 8;
+const CHANNELS: usize = 3;
+const MIXER: usize = 7;
+const AMPLITUDE: usize = 8;
+const ENVELOPE_SHAPE: usize = 13;
+const PORT_A: usize = 14;
+
+// The bits that exist in each register
+const MASKS: [u8; 16] = [
+    0xFF, 0x0F, 0xFF, 0x0F, 0xFF, 0x0F, 0x1F, 0xFF, 0x1F, 0x1F, 0x1F, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF,
+];
+
+// The bits of the envelope shape
+const HOLD: u8 = 0b0001;
+const ALTERNATE: u8 = 0b0010;
+const ATTACK: u8 = 0b0100;
+const CONTINUE: u8 = 0b1000;
+
+/// An AY-3-8910, reporting its output to a Mixer
+pub struct Ay38910 {
+    clock: u32,
+    cpu_clock: u32,
+    mixer: Rc<RefCell<Mixer>>,
+    channels: [ChannelId; CHANNELS],
+    levels: [f32; CHANNELS],
+
+    registers: [u8; 16],
+    selected: usize,
+    // What the outside world puts on the I/O ports, when they are set as inputs
+    inputs: [u8; 2],
+
+    counters: [u16; CHANNELS],
+    outputs: [bool; CHANNELS],
+    noise_counter: u16,
+    lfsr: u32,
+    envelope_counter: u16,
+    envelope_step: u8,
+    attack: bool,
+    holding: bool,
+    cycles: u64,
+}
+
+impl Ay38910 {
+    /// Create a chip running at `clock` Hz next to a CPU at `cpu_clock` Hz, adding 3 channels
+    /// to the mixer. Every register starts at 0, so every channel starts silent.
+    pub fn new(clock: u32, cpu_clock: u32, mixer: Rc<RefCell<Mixer>>) -> Self {
+        assert!(clock > 0, "clock must be positive");
+        let channels = {
+            let mut m = mixer.borrow_mut();
+            [(); CHANNELS].map(|_| m.add_channel(1.0 / 3.0))
+        };
+        Self {
+            clock,
+            cpu_clock,
+            mixer,
+            channels,
+            levels: [0.0; CHANNELS],
+            registers: [0; 16],
+            selected: 0,
+            inputs: [0xFF; 2],
+            counters: [0; CHANNELS],
+            outputs: [false; CHANNELS],
+            noise_counter: 0,
+            lfsr: 1,
+            envelope_counter: 0,
+            envelope_step: 0,
+            attack: false,
+            holding: false,
+            cycles: 0,
+        }
+    }
+
+    /// Pick the register (0 to 15) the next read or write goes to
+    pub fn select(&mut self, reg: u8) {
+        self.selected = usize::from(reg & 0x0F);
+    }
+
+    /// The register picked by the last select
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+
+    /// Write to the selected register. It takes effect at the time the chip has been run to.
+    pub fn write(&mut self, val: u8) {
+        self.registers[self.selected] = val & MASKS[self.selected];
+        if self.selected == ENVELOPE_SHAPE {
+            // Writing the shape restarts the envelope
+            self.envelope_step = 0;
+            self.attack = val & ATTACK != 0;
+            self.holding = false;
+        }
+        self.update_levels();
+    }
+
+    /// Read the selected register. An I/O port set as an input reads what the outside world
+    /// puts on it.
+    pub fn read(&self) -> u8 {
+        match self.selected {
+            PORT_A | 15 if self.registers[MIXER] & (1 << (self.selected - 8)) == 0 => {
+                self.inputs[self.selected - PORT_A]
+            }
+            reg => self.registers[reg],
+        }
+    }
+
+    /// The value in any register
+    pub fn register(&self, reg: usize) -> u8 {
+        self.registers[reg]
+    }
+
+    /// Set what the outside world puts on I/O port A (0) or B (1)
+    pub fn set_port_input(&mut self, port: usize, val: u8) {
+        self.inputs[port] = val;
+    }
+
+    /// The registers and generators, for a machine's savestate chunk. What is on the I/O
+    /// ports and how far the chip has been run aren't part of it, so the sound it reports
+    /// carries on forwards after a restore.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut out = self.registers.to_vec();
+        out.push(self.selected as u8);
+        for counter in &self.counters {
+            out.extend_from_slice(&counter.to_le_bytes());
+        }
+        out.extend(self.outputs.iter().map(|&output| output as u8));
+        out.extend_from_slice(&self.noise_counter.to_le_bytes());
+        out.extend_from_slice(&self.lfsr.to_le_bytes());
+        out.extend_from_slice(&self.envelope_counter.to_le_bytes());
+        out.extend_from_slice(&[self.envelope_step, self.attack as u8, self.holding as u8]);
+        out
+    }
+
+    /// Go back to a state from `save_state`
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), SaveStateError> {
+        let mut reader = StateReader::new(state);
+        let registers = reader.array()?;
+        let selected = usize::from(reader.u8()? & 0x0F);
+        let mut counters = [0; CHANNELS];
+        for counter in &mut counters {
+            *counter = reader.u16()?;
+        }
+        let mut outputs = [false; CHANNELS];
+        for output in &mut outputs {
+            *output = reader.bool()?;
+        }
+        let noise_counter = reader.u16()?;
+        let lfsr = reader.u32()?;
+        let envelope_counter = reader.u16()?;
+        let envelope_step = reader.u8()? & 0x0F;
+        let attack = reader.bool()?;
+        let holding = reader.bool()?;
+        self.registers = registers;
+        self.selected = selected;
+        self.counters = counters;
+        self.outputs = outputs;
+        self.noise_counter = noise_counter;
+        self.lfsr = lfsr;
+        self.envelope_counter = envelope_counter;
+        self.envelope_step = envelope_step;
+        self.attack = attack;
+        self.holding = holding;
+        self.update_levels();
+        Ok(())
+    }
+
+    /// Run for the given number of the chip's clock cycles
+    pub fn run(&mut self, cycles: u64) {
+        let end = self.cycles + cycles;
+        loop {
+            let next = (self.cycles / DIVIDER + 1) * DIVIDER;
+            if next > end {
+                break;
+            }
+            self.cycles = next;
+            self.step();
+            self.update_levels();
+        }
+        self.cycles = end;
+        // Keep the mixer up to date, even when nothing changed
+        let tstate = self.tstate();
+        self.mixer.borrow_mut().advance_to(tstate);
+    }
+
+    // The time the chip has been run to, in T-states of the CPU
+    fn tstate(&self) -> u64 {
+        (u128::from(self.cycles) * u128::from(self.cpu_clock) / u128::from(self.clock)) as u64
+    }
+
+    fn period(&self, reg: usize) -> u16 {
+        u16::from_le_bytes([self.registers[reg], self.registers[reg + 1]]).max(1)
+    }
+
+    fn step(&mut self) {
+        for channel in 0..CHANNELS {
+            let period = self.period(channel * 2);
+            if count_down(&mut self.counters[channel], period) {
+                self.outputs[channel] = !self.outputs[channel];
+            }
+        }
+
+        let noise_period = u16::from(self.registers[6]).max(1) * 2;
+        if count_down(&mut self.noise_counter, noise_period) {
+            // A 17-bit shift register, tapped at bits 0 and 3
+            let feedback = (self.lfsr ^ self.lfsr >> 3) & 1;
+            self.lfsr = self.lfsr >> 1 | feedback << 16;
+        }
+
+        let envelope_period = self.period(11).saturating_mul(2);
+        if count_down(&mut self.envelope_counter, envelope_period) {
+            self.step_envelope();
+        }
+    }
+
+    fn step_envelope(&mut self) {
+        if self.holding {
+            return;
+        }
+        self.envelope_step += 1;
+        if self.envelope_step < 16 {
+            return;
+        }
+        let shape = self.registers[ENVELOPE_SHAPE];
+        if shape & CONTINUE == 0 {
+            // Stay silent
+            self.attack = true;
+            self.envelope_step = 0;
+            self.holding = true;
+            return;
+        }
+        if shape & ALTERNATE != 0 {
+            self.attack = !self.attack;
+        }
+        if shape & HOLD != 0 {
+            self.envelope_step = 15;
+            self.holding = true;
+        } else {
+            self.envelope_step = 0;
+        }
+    }
+
+    fn envelope_volume(&self) -> u8 {
+        if self.attack {
+            self.envelope_step
+        } else {
+            15 - self.envelope_step
+        }
+    }
+
+    fn update_levels(&mut self) {
+        let tstate = self.tstate();
+        let mixer = self.registers[MIXER];
+        let noise = self.lfsr & 1 == 1;
+        for channel in 0..CHANNELS {
+            // A disabled tone or noise counts as always high
+            let tone = self.outputs[channel] || mixer & (1 << channel) != 0;
+            let noise = noise || mixer & (8 << channel) != 0;
+            let amplitude = self.registers[AMPLITUDE + channel];
+            let level = if tone && noise {
+                volume(if amplitude & 0x10 != 0 {
+                    self.envelope_volume()
+                } else {
+                    amplitude
+                })
+            } else {
+                0.0
+            };
+            if level != self.levels[channel] {
+                self.levels[channel] = level;
+                self.mixer
+                    .borrow_mut()
+                    .set_level(self.channels[channel], tstate, level);
+            }
+        }
+    }
+}
+
+// Returns true when the counter runs out and is reloaded
+fn count_down(counter: &mut u16, period: u16) -> bool {
+    if *counter > 1 {
+        *counter -= 1;
+        return false;
+    }
+    *counter = period;
+    true
+}
+
+// Each step of volume is 3dB louder, and 0 is silence
+fn volume(level: u8) -> f32 {
+    if level == 0 {
+        0.0
+    } else {
+        2f32.powf((f32::from(level) - 15.0) / 2.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // One host sample for every 8 clock cycles
+    fn ay() -> (Ay38910, Rc<RefCell<Vec<i16>>>) {
+        let mixer = Rc::new(RefCell::new(Mixer::new(800, 100, 8)));
+        let out = Rc::new(RefCell::new(vec![]));
+        let o = out.clone();
+        mixer
+            .borrow_mut()
+            .on_buffer(Box::new(move |buf| o.borrow_mut().extend_from_slice(buf)));
+        (Ay38910::new(800, 800, mixer), out)
+    }
+
+    fn set(ay: &mut Ay38910, reg: u8, val: u8) {
+        ay.select(reg);
+        ay.write(val);
+    }
+
+    #[test]
+    fn registers() {
+        let (mut ay, _) = ay();
+        set(&mut ay, 1, 0xFF);
+        assert_eq!(0x0F, ay.read());
+        set(&mut ay, 0x13, 0xFF);
+        assert_eq!(3, ay.selected());
+
+        // Port A reads its input until it is made an output
+        ay.set_port_input(0, 0x5A);
+        set(&mut ay, 14, 0x12);
+        assert_eq!(0x5A, ay.read());
+        set(&mut ay, 7, 0x40);
+        ay.select(14);
+        assert_eq!(0x12, ay.read());
+    }
+
+    #[test]
+    fn tone() {
+        let (mut ay, out) = ay();
+        // Channel A at full volume, toggling every other step, with everything else off
+        set(&mut ay, 0, 2);
+        set(&mut ay, 7, 0b0011_1110);
+        set(&mut ay, 8, 15);
+        ay.run(8 * 8);
+        let high = (f32::from(i16::MAX) / 3.0) as i16;
+        assert_eq!(vec![0, high, high, 0, 0, high, high, 0], *out.borrow());
+    }
+
+    #[test]
+    fn envelope() {
+        let (mut ay, _) = ay();
+        // A single decay, one step every 16 clock cycles
+        set(&mut ay, 11, 1);
+        set(&mut ay, 13, 0b0000);
+        assert_eq!(15, ay.envelope_volume());
+        ay.run(16 * 5);
+        assert_eq!(10, ay.envelope_volume());
+        ay.run(16 * 20);
+        assert_eq!(0, ay.envelope_volume());
+
+        // Attack, then hold at the top
+        set(&mut ay, 13, 0b1101);
+        ay.run(16 * 20);
+        assert_eq!(15, ay.envelope_volume());
+
+        // A triangle
+        set(&mut ay, 13, 0b1110);
+        ay.run(16 * 18);
+        assert_eq!(13, ay.envelope_volume());
+    }
+}
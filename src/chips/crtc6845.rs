@@ -0,0 +1,390 @@
+//! The 6845 CRT controller, as used by the Amstrad CPC and many other machines.
+//! The CRTC generates the addresses a display is read from, and the sync pulses around it. It
+//! is programmed through 18 registers: one port picks a register, and the other writes it
+//! (or, for the few that can be, reads it).
+//! Run it by its character clock, and it steps through each scanline and frame the registers
+//! describe, calling back at both edges of every horizontal and vertical sync pulse. Those
+//! pulses are what machines hang their interrupts on.
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::z80::io::{InputDevice, OutputDevice};
+use crate::z80::savestate::{write_option, SaveStateError, StateReader};
+
+/// Horizontal total, in characters, less one
+pub const HORIZONTAL_TOTAL: usize = 0;
+/// Characters displayed per row
+pub const HORIZONTAL_DISPLAYED: usize = 1;
+pub const HORIZONTAL_SYNC_POSITION: usize = 2;
+/// Horizontal sync width in the low nibble, and vertical sync width in the high one
+pub const SYNC_WIDTHS: usize = 3;
+/// Vertical total, in character rows, less one
+pub const VERTICAL_TOTAL: usize = 4;
+/// Extra scanlines at the end of a frame
+pub const VERTICAL_TOTAL_ADJUST: usize = 5;
+/// Character rows displayed
+pub const VERTICAL_DISPLAYED: usize = 6;
+pub const VERTICAL_SYNC_POSITION: usize = 7;
+pub const INTERLACE: usize = 8;
+/// Scanlines per character row, less one
+pub const MAX_RASTER: usize = 9;
+pub const START_ADDRESS_HIGH: usize = 12;
+pub const START_ADDRESS_LOW: usize = 13;
+
+// The bits that exist in each register
+const MASKS: [u8; 18] = [
+    0xFF, 0xFF, 0xFF, 0xFF, 0x7F, 0x1F, 0x7F, 0x7F, 0x03, 0x1F, 0x7F, 0x1F, 0x3F, 0xFF, 0x3F, 0xFF,
+    0x3F, 0xFF,
+];
+
+/// Called with true as a sync pulse starts, and false as it ends
+pub type SyncCallback = Box<dyn FnMut(bool)>;
+
+/// A 6845 and its registers
+#[derive(Default)]
+pub struct Crtc6845 {
+    registers: [u8; 18],
+    selected: usize,
+
+    // Characters into the current scanline
+    column: u8,
+    // Scanlines into the current character row, or into the vertical adjust
+    raster: u8,
+    row: u8,
+    adjusting: bool,
+    // The address of the first character of the current row
+    row_address: u16,
+    hsync: Option<u8>,
+    vsync: Option<u8>,
+
+    on_hsync: Option<SyncCallback>,
+    on_vsync: Option<SyncCallback>,
+}
+
+impl Crtc6845 {
+    /// Pick the register the next read or write goes to
+    pub fn select(&mut self, reg: u8) {
+        self.selected = usize::from(reg & 0x1F);
+    }
+
+    /// Write to the selected register. Writes to registers past 17 are ignored.
+    pub fn write(&mut self, val: u8) {
+        if let Some(mask) = MASKS.get(self.selected) {
+            self.registers[self.selected] = val & mask;
+        }
+    }
+
+    /// Read the selected register. Only the start address, cursor and light pen registers
+    /// (12 to 17) can be read; the rest read as 0.
+    pub fn read(&self) -> u8 {
+        match self.selected {
+            12..=17 => self.registers[self.selected],
+            _ => 0,
+        }
+    }
+
+    /// The value in any register
+    pub fn register(&self, reg: usize) -> u8 {
+        self.registers[reg]
+    }
+
+    /// The 14-bit address the display starts from
+    pub fn start_address(&self) -> u16 {
+        u16::from_be_bytes([
+            self.registers[START_ADDRESS_HIGH],
+            self.registers[START_ADDRESS_LOW],
+        ])
+    }
+
+    /// The number of scanlines in each character row
+    pub fn rasters_per_row(&self) -> u32 {
+        u32::from(self.registers[MAX_RASTER]) + 1
+    }
+
+    /// Called at both edges of every horizontal sync pulse
+    pub fn on_hsync(&mut self, callback: SyncCallback) {
+        self.on_hsync = Some(callback);
+    }
+
+    /// Called at both edges of every vertical sync pulse
+    pub fn on_vsync(&mut self, callback: SyncCallback) {
+        self.on_vsync = Some(callback);
+    }
+
+    /// Returns true during a horizontal sync pulse
+    pub fn hsync(&self) -> bool {
+        self.hsync.is_some()
+    }
+
+    /// Returns true during a vertical sync pulse
+    pub fn vsync(&self) -> bool {
+        self.vsync.is_some()
+    }
+
+    /// Returns true while the beam is in the displayed area
+    pub fn display_enabled(&self) -> bool {
+        !self.adjusting
+            && self.column < self.registers[HORIZONTAL_DISPLAYED]
+            && self.row < self.registers[VERTICAL_DISPLAYED]
+    }
+
+    /// The memory address of the current character
+    pub fn address(&self) -> u16 {
+        self.row_address.wrapping_add(u16::from(self.column)) & 0x3FFF
+    }
+
+    /// The scanline within the current character row
+    pub fn raster(&self) -> u8 {
+        self.raster
+    }
+
+    /// The registers and beam position, for a machine's savestate chunk. The callbacks aren't
+    /// part of it.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut out = self.registers.to_vec();
+        out.extend_from_slice(&[
+            self.selected as u8,
+            self.column,
+            self.raster,
+            self.row,
+            self.adjusting as u8,
+        ]);
+        out.extend_from_slice(&self.row_address.to_le_bytes());
+        write_option(&mut out, self.hsync);
+        write_option(&mut out, self.vsync);
+        out
+    }
+
+    /// Go back to a state from `save_state`, keeping the callbacks
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), SaveStateError> {
+        let mut reader = StateReader::new(state);
+        let registers = reader.array()?;
+        let selected = usize::from(reader.u8()? & 0x1F);
+        let column = reader.u8()?;
+        let raster = reader.u8()?;
+        let row = reader.u8()?;
+        let adjusting = reader.bool()?;
+        let row_address = reader.u16()?;
+        let hsync = reader.option()?;
+        let vsync = reader.option()?;
+        self.registers = registers;
+        self.selected = selected;
+        self.column = column;
+        self.raster = raster;
+        self.row = row;
+        self.adjusting = adjusting;
+        self.row_address = row_address;
+        self.hsync = hsync;
+        self.vsync = vsync;
+        Ok(())
+    }
+
+    /// Run for the given number of character clock cycles
+    pub fn run(&mut self, chars: u64) {
+        for _ in 0..chars {
+            self.tick();
+        }
+    }
+
+    fn tick(&mut self) {
+        if self.column == self.registers[HORIZONTAL_TOTAL] {
+            self.column = 0;
+            self.next_line();
+        } else {
+            self.column = self.column.wrapping_add(1);
+        }
+
+        let width = sync_width(self.registers[SYNC_WIDTHS] & 0x0F);
+        if let Some(count) = self.hsync {
+            if count + 1 == width {
+                self.hsync = None;
+                call(&mut self.on_hsync, false);
+            } else {
+                self.hsync = Some(count + 1);
+            }
+        }
+        if self.hsync.is_none() && self.column == self.registers[HORIZONTAL_SYNC_POSITION] {
+            self.hsync = Some(0);
+            call(&mut self.on_hsync, true);
+        }
+    }
+
+    fn next_line(&mut self) {
+        let width = sync_width(self.registers[SYNC_WIDTHS] >> 4);
+        if let Some(count) = self.vsync {
+            if count + 1 == width {
+                self.vsync = None;
+                call(&mut self.on_vsync, false);
+            } else {
+                self.vsync = Some(count + 1);
+            }
+        }
+
+        if self.adjusting {
+            // Extra scanlines after the last row
+            self.raster += 1;
+            if self.raster >= self.registers[VERTICAL_TOTAL_ADJUST] {
+                self.new_frame();
+            }
+        } else if self.raster == self.registers[MAX_RASTER] {
+            self.raster = 0;
+            self.row_address = self
+                .row_address
+                .wrapping_add(u16::from(self.registers[HORIZONTAL_DISPLAYED]));
+            if self.row != self.registers[VERTICAL_TOTAL] {
+                self.row += 1;
+            } else if self.registers[VERTICAL_TOTAL_ADJUST] == 0 {
+                self.new_frame();
+            } else {
+                self.adjusting = true;
+            }
+        } else {
+            self.raster += 1;
+        }
+
+        let top_of_row = !self.adjusting && self.raster == 0;
+        if self.vsync.is_none() && top_of_row && self.row == self.registers[VERTICAL_SYNC_POSITION]
+        {
+            self.vsync = Some(0);
+            call(&mut self.on_vsync, true);
+        }
+    }
+
+    fn new_frame(&mut self) {
+        self.row = 0;
+        self.raster = 0;
+        self.adjusting = false;
+        self.row_address = self.start_address();
+    }
+}
+
+// A sync width of 0 means 16
+fn sync_width(width: u8) -> u8 {
+    if width == 0 {
+        16
+    } else {
+        width
+    }
+}
+
+fn call(callback: &mut Option<SyncCallback>, active: bool) {
+    if let Some(f) = callback.as_mut() {
+        f(active);
+    }
+}
+
+/// The register select port, for routing with a PortDecoder
+pub struct SelectPort(pub Rc<RefCell<Crtc6845>>);
+
+impl OutputDevice for SelectPort {
+    fn output(&self, val: u8) {
+        self.0.borrow_mut().select(val);
+    }
+}
+
+/// The register port, for routing with a PortDecoder: reads and writes the selected register
+pub struct DataPort(pub Rc<RefCell<Crtc6845>>);
+
+impl InputDevice for DataPort {
+    fn input(&self) -> u8 {
+        self.0.borrow().read()
+    }
+}
+
+impl OutputDevice for DataPort {
+    fn output(&self, val: u8) {
+        self.0.borrow_mut().write(val);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn registers() {
+        let crtc = Rc::new(RefCell::new(Crtc6845::default()));
+        let select = SelectPort(crtc.clone());
+        let data = DataPort(crtc.clone());
+
+        select.output(START_ADDRESS_HIGH as u8);
+        data.output(0xF0);
+        assert_eq!(0x30, data.input());
+        select.output(START_ADDRESS_LOW as u8);
+        data.output(0x12);
+        assert_eq!(0x3012, crtc.borrow().start_address());
+
+        // Write only
+        select.output(VERTICAL_TOTAL as u8);
+        data.output(0xFF);
+        assert_eq!(0, data.input());
+        assert_eq!(0x7F, crtc.borrow().register(VERTICAL_TOTAL));
+
+        // Nothing past 17
+        select.output(18);
+        data.output(0xFF);
+        assert_eq!(0, data.input());
+    }
+
+    // 10 characters a line, 5 rows of 2 scanlines and 2 more to adjust: 12 lines a frame.
+    // Syncs are 2 wide, from character 8 and row 3.
+    fn small() -> Crtc6845 {
+        let mut crtc = Crtc6845::default();
+        for (reg, val) in [9, 8, 8, 0x22, 4, 2, 4, 3, 0, 1].iter().enumerate() {
+            crtc.select(reg as u8);
+            crtc.write(*val);
+        }
+        crtc
+    }
+
+    #[test]
+    fn sync_callbacks() {
+        let mut crtc = small();
+        let events = Rc::new(RefCell::new(vec![]));
+        let (h, v) = (events.clone(), events.clone());
+        let tick = Rc::new(RefCell::new(0));
+        let (th, tv) = (tick.clone(), tick.clone());
+        crtc.on_hsync(Box::new(move |active| {
+            h.borrow_mut().push(("h", active, *th.borrow()))
+        }));
+        crtc.on_vsync(Box::new(move |active| {
+            v.borrow_mut().push(("v", active, *tv.borrow()))
+        }));
+        for _ in 0..120 {
+            *tick.borrow_mut() += 1;
+            crtc.run(1);
+        }
+
+        let events = events.borrow();
+        let hsyncs: Vec<_> = events.iter().filter(|e| e.0 == "h").collect();
+        assert_eq!(24, hsyncs.len());
+        assert_eq!((&("h", true, 8), &("h", false, 10)), (hsyncs[0], hsyncs[1]));
+        assert_eq!(&("h", false, 120), hsyncs[23]);
+        let vsyncs: Vec<_> = events.iter().filter(|e| e.0 == "v").collect();
+        assert_eq!(vec![&("v", true, 60), &("v", false, 80)], vsyncs);
+    }
+
+    #[test]
+    fn addresses() {
+        let mut crtc = small();
+        crtc.select(START_ADDRESS_HIGH as u8);
+        crtc.write(0x01);
+        // The start address is picked up at the start of the next frame
+        crtc.run(120);
+        assert_eq!(0x100, crtc.address());
+        assert!(crtc.display_enabled());
+
+        // Row 1, character 3
+        crtc.run(23);
+        assert_eq!(0x10B, crtc.address());
+        assert_eq!(0, crtc.raster());
+        crtc.run(6);
+        assert!(!crtc.display_enabled());
+        assert!(crtc.hsync());
+
+        // The vertical adjust
+        crtc.run(78);
+        assert!(!crtc.display_enabled());
+        assert!(!crtc.vsync());
+    }
+}
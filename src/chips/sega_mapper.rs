@@ -0,0 +1,270 @@
+//! The Sega mapper, which pages 16K banks of a large cartridge ROM into the SG-1000 and Master
+//! System memory maps. Writing to the last four addresses of memory sets its registers:
+//!
+//! * 0xFFFC: bit 3 puts cartridge RAM in slot 2, and bit 2 picks which 16K of it
+//! * 0xFFFD: the ROM bank in slot 0, 0x0000 to 0x3FFF (except the first 1K, always bank 0)
+//! * 0xFFFE: the ROM bank in slot 1, 0x4000 to 0x7FFF
+//! * 0xFFFF: the ROM bank in slot 2, 0x8000 to 0xBFFF
+//!
+//! The 8K of system RAM from 0xC000 is mirrored at 0xE000, so the registers also land in RAM,
+//! which is how programs read them back. Bank numbers wrap round at the size of the ROM.
+//! A bus built on a shared mapper should add its `state_chunk` to the processor, so checkpoints
+//! keep the registers and RAM rather than writing memory back through the mapper.
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::z80::savestate::{write_block, SaveStateError, StateChunk, StateReader};
+
+const BANK_SIZE: usize = 0x4000;
+// The first 1K is never paged, so the interrupt vectors are always there
+const FIXED: u16 = 0x0400;
+const RAM_SIZE: usize = 0x2000;
+const CONTROL: u16 = 0xFFFC;
+const RAM_ENABLE: u8 = 0x08;
+const RAM_BANK: u8 = 0x04;
+
+/// The cartridge ROM and RAM, and the system RAM, as seen through the mapper
+pub struct SegaMapper {
+    rom: Vec<u8>,
+    banks: usize,
+    // 0xFFFC to 0xFFFF
+    registers: [u8; 4],
+    // Whether a register has changed since take_bank_switch was last called
+    switched: bool,
+    cart_ram: Vec<u8>,
+    ram: Vec<u8>,
+}
+
+impl SegaMapper {
+    /// Map a cartridge ROM, padded to a whole number of banks.
+    /// Slots 0 to 2 start out with banks 0 to 2, and cartridge RAM is paged out.
+    pub fn new(rom: &[u8]) -> Self {
+        let banks = rom.len().div_ceil(BANK_SIZE).max(1);
+        let mut rom = rom.to_vec();
+        rom.resize(banks * BANK_SIZE, 0xFF);
+        Self {
+            rom,
+            banks,
+            registers: [0, 0, 1, 2],
+            switched: false,
+            cart_ram: vec![0; 2 * BANK_SIZE],
+            ram: vec![0; RAM_SIZE],
+        }
+    }
+
+    /// The ROM bank paged into slot 0, 1 or 2
+    pub fn bank(&self, slot: usize) -> usize {
+        usize::from(self.registers[slot + 1]) % self.banks
+    }
+
+    /// The cartridge RAM, 32K, for saving to disk
+    pub fn cart_ram(&self) -> &[u8] {
+        &self.cart_ram
+    }
+
+    /// Whether a different bank has been paged in since this was last called, for a Bus
+    /// built on the mapper to pass on from `Bus::take_bank_switch`
+    pub fn take_bank_switch(&mut self) -> bool {
+        std::mem::take(&mut self.switched)
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        let offset = usize::from(addr) % BANK_SIZE;
+        match addr {
+            _ if addr < FIXED => self.rom[usize::from(addr)],
+            0x8000..=0xBFFF if self.registers[0] & RAM_ENABLE != 0 => {
+                self.cart_ram[self.cart_ram_offset(offset)]
+            }
+            0x0000..=0xBFFF => self.rom[self.bank(usize::from(addr >> 14)) * BANK_SIZE + offset],
+            _ => self.ram[usize::from(addr) % RAM_SIZE],
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x8000..=0xBFFF if self.registers[0] & RAM_ENABLE != 0 => {
+                let offset = self.cart_ram_offset(usize::from(addr) % BANK_SIZE);
+                self.cart_ram[offset] = val;
+            }
+            // ROM
+            0x0000..=0xBFFF => (),
+            _ => {
+                if addr >= CONTROL {
+                    let register = &mut self.registers[usize::from(addr - CONTROL)];
+                    self.switched |= *register != val;
+                    *register = val;
+                }
+                self.ram[usize::from(addr) % RAM_SIZE] = val;
+            }
+        }
+    }
+
+    /// The registers and both RAMs, for a savestate chunk. The ROM isn't part of it.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut out = self.registers.to_vec();
+        write_block(&mut out, &self.cart_ram);
+        write_block(&mut out, &self.ram);
+        out
+    }
+
+    /// Go back to a state from `save_state`
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), SaveStateError> {
+        let mut reader = StateReader::new(state);
+        let registers = reader.array()?;
+        let cart_ram = reader.block()?;
+        let ram = reader.block()?;
+        if cart_ram.len() != self.cart_ram.len() || ram.len() != RAM_SIZE {
+            return Err(SaveStateError::Truncated);
+        }
+        self.switched |= registers != self.registers;
+        self.registers = registers;
+        self.cart_ram.copy_from_slice(cart_ram);
+        self.ram.copy_from_slice(ram);
+        Ok(())
+    }
+
+    /// A state chunk, tagged `SEGA`, holding the mapper's registers and all of its RAM
+    pub fn state_chunk(mapper: &Rc<RefCell<SegaMapper>>) -> Box<dyn StateChunk> {
+        Box::new(MapperChunk(Rc::clone(mapper)))
+    }
+
+    fn cart_ram_offset(&self, offset: usize) -> usize {
+        let bank = usize::from(self.registers[0] & RAM_BANK != 0);
+        bank * BANK_SIZE + offset
+    }
+}
+
+struct MapperChunk(Rc<RefCell<SegaMapper>>);
+
+impl StateChunk for MapperChunk {
+    fn tag(&self) -> [u8; 4] {
+        *b"SEGA"
+    }
+
+    fn save(&self) -> Vec<u8> {
+        self.0.borrow().save_state()
+    }
+
+    fn load(&self, chunk: Option<&[u8]>) -> Result<(), SaveStateError> {
+        // The memory can't be made up, so the chunk has to be there
+        let bad = || SaveStateError::BadChunk(self.tag());
+        let chunk = chunk.ok_or_else(bad)?;
+        self.0.borrow_mut().load_state(chunk).map_err(|_| bad())
+    }
+
+    fn holds_memory(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // 8 banks, each filled with its own number
+    fn mapper() -> SegaMapper {
+        let rom: Vec<u8> = (0..8).flat_map(|b| vec![b; BANK_SIZE]).collect();
+        SegaMapper::new(&rom)
+    }
+
+    #[test]
+    fn paging() {
+        let mut mapper = mapper();
+        assert_eq!(0, mapper.read(0x0400));
+        assert_eq!(1, mapper.read(0x4000));
+        assert_eq!(2, mapper.read(0xBFFF));
+
+        mapper.write(0xFFFD, 5);
+        mapper.write(0xFFFE, 6);
+        mapper.write(0xFFFF, 7);
+        assert_eq!(0, mapper.read(0x03FF));
+        assert_eq!(5, mapper.read(0x0400));
+        assert_eq!(6, mapper.read(0x4000));
+        assert_eq!(7, mapper.read(0x8000));
+        // Read back through the RAM mirror
+        assert_eq!(7, mapper.read(0xFFFF));
+        assert_eq!(7, mapper.read(0xDFFF));
+        assert!(mapper.take_bank_switch());
+        assert!(!mapper.take_bank_switch());
+
+        // Past the end of the ROM wraps round
+        mapper.write(0xFFFF, 11);
+        assert_eq!(3, mapper.read(0x8000));
+        assert_eq!(3, mapper.bank(2));
+    }
+
+    #[test]
+    fn rom_is_read_only() {
+        let mut mapper = mapper();
+        mapper.write(0x4000, 0x12);
+        assert_eq!(1, mapper.read(0x4000));
+    }
+
+    #[test]
+    fn ram() {
+        let mut mapper = mapper();
+        mapper.write(0xC000, 0x12);
+        assert_eq!(0x12, mapper.read(0xE000));
+
+        mapper.write(0xFFFC, RAM_ENABLE);
+        mapper.write(0x8000, 0x34);
+        mapper.write(0xFFFC, RAM_ENABLE | RAM_BANK);
+        mapper.write(0x8000, 0x56);
+        assert_eq!(0x56, mapper.read(0x8000));
+        mapper.write(0xFFFC, RAM_ENABLE);
+        assert_eq!(0x34, mapper.read(0x8000));
+        mapper.write(0xFFFC, 0);
+        assert_eq!(2, mapper.read(0x8000));
+        assert_eq!(
+            &[0x34, 0x56],
+            &[mapper.cart_ram()[0], mapper.cart_ram()[BANK_SIZE]]
+        );
+    }
+
+    #[test]
+    fn rewinding() {
+        use crate::z80::bus::Bus;
+        use crate::z80::checkpoint::Checkpoints;
+        use crate::z80::Z80;
+
+        struct Cartridge(Rc<RefCell<SegaMapper>>);
+        impl Bus for Cartridge {
+            fn mem_read(&self, addr: u16) -> u8 {
+                self.0.borrow().read(addr)
+            }
+            fn mem_write(&self, addr: u16, val: u8) {
+                self.0.borrow_mut().write(addr, val)
+            }
+            fn io_read(&self, _port: u16) -> u8 {
+                0xFF
+            }
+            fn io_write(&self, _port: u16, _val: u8) {}
+            fn take_bank_switch(&self) -> bool {
+                self.0.borrow_mut().take_bank_switch()
+            }
+        }
+
+        let mapper = Rc::new(RefCell::new(mapper()));
+        let mut z80 = Z80::default();
+        z80.set_bus(Box::new(Cartridge(mapper.clone())));
+        z80.add_state_chunk(SegaMapper::state_chunk(&mapper));
+        let mut checkpoints = Checkpoints::new(1, 4);
+        for frame in 0..3 {
+            let mut m = mapper.borrow_mut();
+            m.write(0xFFFC, RAM_ENABLE | RAM_BANK);
+            m.write(0x8000, frame);
+            m.write(0xFFFC, 0);
+            m.write(0xFFFF, 5 + frame);
+            m.write(0xC000, frame);
+            drop(m);
+            checkpoints.record(u64::from(frame), &z80);
+        }
+
+        assert_eq!(Some(1), checkpoints.rewind(&mut z80, 2, 1));
+        let m = mapper.borrow();
+        assert_eq!(6, m.bank(2));
+        assert_eq!(6, m.read(0x8000));
+        assert_eq!(1, m.read(0xC000));
+        assert_eq!(1, m.cart_ram()[BANK_SIZE]);
+    }
+}
@@ -0,0 +1,293 @@
+//! The SN76489 sound generator, as used by the ColecoVision, SG-1000 and Master System.
+//! Three square wave channels and a noise channel, each with its own volume, programmed
+//! through a single write-only port. A byte with bit 7 set latches a register (and sets its low
+//! 4 bits); a byte with bit 7 clear sets the rest of the latched register: the top 6 bits of a
+//! tone period, or all 4 bits of a volume or the noise control.
+//! Unlike the AY, there is nothing to read back, and no envelopes.
+//! Each channel gets its own channel on the Mixer, and the chip reports its output level
+//! whenever it changes. The chip is run by its own clock, so it keeps a count of the clock
+//! cycles it has run, and converts them to T-states of the CPU for the Mixer.
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::audio::{ChannelId, Mixer};
+use crate::scheduler::Coprocessor;
+use crate::z80::io::OutputDevice;
+
+// The counters are stepped once every 16 clock cycles
+const DIVIDER: u64 = 16;
+const TONES: usize = 3;
+const NOISE: usize = 3;
+const SILENT: u8 = 0x0F;
+// The 15-bit shift register is reset to this when the noise control is written
+const LFSR_RESET: u16 = 0x4000;
+
+/// An SN76489, reporting its output to a Mixer
+pub struct Sn76489 {
+    clock: u32,
+    cpu_clock: u32,
+    mixer: Rc<RefCell<Mixer>>,
+    channels: [ChannelId; 4],
+    levels: [f32; 4],
+
+    periods: [u16; TONES],
+    noise: u8,
+    attenuation: [u8; 4],
+    // Which of the 8 registers the next data byte goes to: the channel, then 1 for volume
+    latched: usize,
+
+    counters: [u16; 4],
+    outputs: [bool; 4],
+    lfsr: u16,
+    cycles: u64,
+}
+
+impl Sn76489 {
+    /// Create a chip running at `clock` Hz next to a CPU at `cpu_clock` Hz, adding 4 channels
+    /// to the mixer. Every channel starts silent.
+    pub fn new(clock: u32, cpu_clock: u32, mixer: Rc<RefCell<Mixer>>) -> Self {
+        assert!(clock > 0, "clock must be positive");
+        let channels = {
+            let mut m = mixer.borrow_mut();
+            [(); 4].map(|_| m.add_channel(0.25))
+        };
+        Self {
+            clock,
+            cpu_clock,
+            mixer,
+            channels,
+            levels: [0.0; 4],
+            periods: [0; TONES],
+            noise: 0,
+            attenuation: [SILENT; 4],
+            latched: 0,
+            counters: [0; 4],
+            outputs: [false; 4],
+            lfsr: LFSR_RESET,
+            cycles: 0,
+        }
+    }
+
+    /// Write a byte to the chip's port. It takes effect at the time the chip has been run to.
+    pub fn write(&mut self, val: u8) {
+        if val & 0x80 != 0 {
+            self.latched = usize::from(val >> 4 & 0b111);
+        }
+        let data = val & 0x0F;
+        let channel = self.latched / 2;
+        match (channel, self.latched % 2 == 1) {
+            (_, true) => self.attenuation[channel] = data,
+            (NOISE, false) => {
+                self.noise = data & 0b111;
+                self.lfsr = LFSR_RESET;
+            }
+            (_, false) if val & 0x80 != 0 => {
+                self.periods[channel] = self.periods[channel] & 0x3F0 | u16::from(data);
+            }
+            (_, false) => {
+                self.periods[channel] = self.periods[channel] & 0x00F | u16::from(val & 0x3F) << 4;
+            }
+        }
+        self.update_levels();
+    }
+
+    /// The 10-bit period of a tone channel (0 to 2), in steps of 16 clock cycles
+    pub fn period(&self, channel: usize) -> u16 {
+        self.periods[channel]
+    }
+
+    /// The attenuation of a channel (0 to 3, the noise being 3), in steps of 2dB. 15 is silent.
+    pub fn attenuation(&self, channel: usize) -> u8 {
+        self.attenuation[channel]
+    }
+
+    /// The noise control: bit 2 is set for white noise rather than periodic, and the low bits
+    /// pick the shift rate, 3 being the period of tone channel 2
+    pub fn noise(&self) -> u8 {
+        self.noise
+    }
+
+    /// Run for the given number of the chip's clock cycles
+    pub fn run(&mut self, cycles: u64) {
+        let end = self.cycles + cycles;
+        loop {
+            let next = (self.cycles / DIVIDER + 1) * DIVIDER;
+            if next > end {
+                break;
+            }
+            self.cycles = next;
+            self.step();
+            self.update_levels();
+        }
+        self.cycles = end;
+        // Keep the mixer up to date, even when nothing changed
+        let tstate = self.tstate();
+        self.mixer.borrow_mut().advance_to(tstate);
+    }
+
+    // The time the chip has been run to, in T-states of the CPU
+    fn tstate(&self) -> u64 {
+        (u128::from(self.cycles) * u128::from(self.cpu_clock) / u128::from(self.clock)) as u64
+    }
+
+    fn step(&mut self) {
+        let mut noise_clock = false;
+        for channel in 0..TONES {
+            if self.count_down(channel, self.periods[channel]) {
+                self.outputs[channel] = !self.outputs[channel];
+                noise_clock |= channel == 2;
+            }
+        }
+        let noise_period = match self.noise & 0b11 {
+            0 => 0x10,
+            1 => 0x20,
+            2 => 0x40,
+            _ => 0,
+        };
+        if noise_period != 0 {
+            noise_clock = self.count_down(NOISE, noise_period);
+        }
+        if noise_clock {
+            // The shift register moves on every other edge
+            self.outputs[NOISE] = !self.outputs[NOISE];
+            if self.outputs[NOISE] {
+                let feedback = if self.noise & 0b100 != 0 {
+                    (self.lfsr ^ self.lfsr >> 1) & 1
+                } else {
+                    self.lfsr & 1
+                };
+                self.lfsr = self.lfsr >> 1 | feedback << 14;
+            }
+        }
+    }
+
+    // Returns true when the counter reaches zero and is reloaded. A period of 0 counts as 1024.
+    fn count_down(&mut self, channel: usize, period: u16) -> bool {
+        if self.counters[channel] > 1 {
+            self.counters[channel] -= 1;
+            return false;
+        }
+        self.counters[channel] = if period == 0 { 0x400 } else { period };
+        true
+    }
+
+    fn update_levels(&mut self) {
+        let tstate = self.tstate();
+        for channel in 0..4 {
+            let high = if channel == NOISE {
+                self.lfsr & 1 == 1
+            } else {
+                self.outputs[channel]
+            };
+            let level = if high {
+                volume(self.attenuation[channel])
+            } else {
+                0.0
+            };
+            if level != self.levels[channel] {
+                self.levels[channel] = level;
+                self.mixer
+                    .borrow_mut()
+                    .set_level(self.channels[channel], tstate, level);
+            }
+        }
+    }
+}
+
+// Each step of attenuation is 2dB quieter, and the last is silence
+fn volume(attenuation: u8) -> f32 {
+    if attenuation >= SILENT {
+        0.0
+    } else {
+        10f32.powf(-0.1 * f32::from(attenuation))
+    }
+}
+
+/// The chip's port, for routing with a PortDecoder.
+/// It also runs the chip as a Coprocessor, when added to a Scheduler at the chip's clock.
+pub struct Port(pub Rc<RefCell<Sn76489>>);
+
+impl OutputDevice for Port {
+    fn output(&self, val: u8) {
+        self.0.borrow_mut().write(val);
+    }
+}
+
+impl Coprocessor for Port {
+    fn tick(&self, cycles: u64) {
+        self.0.borrow_mut().run(cycles);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // One host sample for every 16 clock cycles
+    fn psg() -> (Sn76489, Rc<RefCell<Vec<i16>>>) {
+        let mixer = Rc::new(RefCell::new(Mixer::new(1600, 100, 8)));
+        let out = Rc::new(RefCell::new(vec![]));
+        let o = out.clone();
+        mixer
+            .borrow_mut()
+            .on_buffer(Box::new(move |buf| o.borrow_mut().extend_from_slice(buf)));
+        (Sn76489::new(1600, 1600, mixer), out)
+    }
+
+    #[test]
+    fn registers() {
+        let (mut psg, _) = psg();
+        // Tone 1, period 0x3FE
+        psg.write(0xAE);
+        psg.write(0x3F);
+        assert_eq!(0x3FE, psg.period(1));
+        // Only the low bits change with a latch byte
+        psg.write(0xA1);
+        assert_eq!(0x3F1, psg.period(1));
+
+        // A data byte after a volume latch sets the volume again
+        psg.write(0xD5);
+        psg.write(0x07);
+        assert_eq!(7, psg.attenuation(2));
+        assert_eq!(SILENT, psg.attenuation(0));
+
+        psg.write(0xE6);
+        assert_eq!(6, psg.noise());
+    }
+
+    #[test]
+    fn tone() {
+        let (mut psg, out) = psg();
+        // Tone 0 at full volume, toggling every 32 clock cycles
+        psg.write(0x82);
+        psg.write(0x00);
+        psg.write(0x90);
+        psg.run(16 * 8);
+        let high = (0.25 * f32::from(i16::MAX)) as i16;
+        assert_eq!(vec![0, high, high, 0, 0, high, high, 0], *out.borrow());
+
+        // Silence it
+        psg.write(0x9F);
+        psg.run(16 * 8);
+        assert!(out.borrow()[8..].iter().all(|&s| s == 0));
+    }
+
+    #[test]
+    fn noise() {
+        let (mut psg, out) = psg();
+        // Periodic noise is a single pulse every 15 shifts
+        psg.write(0xE0);
+        psg.write(0xF0);
+        psg.run(16 * 0x20 * 15);
+        let on = out.borrow().iter().filter(|&&s| s != 0).count();
+        assert_eq!(0x20, on);
+
+        // White noise doesn't repeat so soon
+        psg.write(0xE4);
+        out.borrow_mut().clear();
+        psg.run(16 * 0x20 * 30);
+        let shifts: Vec<bool> = out.borrow().iter().step_by(0x20).map(|&s| s != 0).collect();
+        assert!(shifts.contains(&true));
+        assert_ne!(shifts[..15], shifts[15..]);
+    }
+}
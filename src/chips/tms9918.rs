@@ -0,0 +1,606 @@
+//! The TMS9918 video display processor, as used by the MSX, ColecoVision and SG-1000.
+//! The VDP has 16K of its own VRAM, reached only through two ports: the data port reads and
+//! writes VRAM at an auto-incrementing address, and the control port takes that address (or a
+//! register write) as a pair of bytes, and reads back the status register.
+//! The TMS9929 is the same chip with PAL timing, so only the number of lines differs.
+//! The picture is drawn a whole frame at a time, and sprite collisions and the fifth sprite
+//! flag are worked out when the vertical blank starts, rather than as the beam passes.
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::z80::io::{InputDevice, OutputDevice};
+use crate::z80::savestate::{write_block, write_option, SaveStateError, StateReader};
+
+/// The active display, without the border
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 192;
+/// Lines per frame of the TMS9918, at 60 frames a second
+pub const NTSC_LINES: u32 = 262;
+/// Lines per frame of the TMS9929, at 50 frames a second
+pub const PAL_LINES: u32 = 313;
+
+/// The 16 colours, as red, green and blue. Colour 0 is transparent, and shows as black.
+pub const PALETTE: [[u8; 3]; 16] = [
+    [0, 0, 0],
+    [0, 0, 0],
+    [33, 200, 66],
+    [94, 220, 120],
+    [84, 85, 237],
+    [125, 118, 252],
+    [212, 82, 77],
+    [66, 235, 245],
+    [252, 85, 84],
+    [255, 121, 120],
+    [212, 193, 84],
+    [230, 206, 128],
+    [33, 176, 59],
+    [201, 91, 186],
+    [204, 204, 204],
+    [255, 255, 255],
+];
+
+const VRAM_SIZE: usize = 0x4000;
+const SPRITES: usize = 32;
+const SPRITES_PER_LINE: usize = 4;
+// A sprite Y position that hides it and every sprite after it
+const LAST_SPRITE: u8 = 0xD0;
+
+const STATUS_VBLANK: u8 = 0x80;
+const STATUS_FIFTH_SPRITE: u8 = 0x40;
+const STATUS_COLLISION: u8 = 0x20;
+
+/// The screen modes, picked by the M1, M2 and M3 bits of registers 0 and 1
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Mode {
+    /// 32 by 24 tiles, with a colour pair for every 8 patterns
+    Graphics1,
+    /// 32 by 24 tiles, with 256 patterns and colours for each third of the screen
+    Graphics2,
+    /// 64 by 48 blocks of 4 by 4 pixels, each its own colour
+    Multicolor,
+    /// 40 by 24 characters of 6 by 8 pixels, in two colours and without sprites
+    Text,
+}
+
+/// A TMS9918 and its VRAM
+pub struct Tms9918 {
+    vram: Vec<u8>,
+    registers: [u8; 8],
+    status: u8,
+    address: u16,
+    // The first byte of a control port pair, once written
+    latch: Option<u8>,
+    // Reads are served from a buffer, which is refilled from VRAM after every access
+    read_ahead: u8,
+}
+
+impl Default for Tms9918 {
+    fn default() -> Self {
+        Self {
+            vram: vec![0; VRAM_SIZE],
+            registers: [0; 8],
+            status: 0,
+            address: 0,
+            latch: None,
+            read_ahead: 0,
+        }
+    }
+}
+
+impl Tms9918 {
+    /// Read the next byte of VRAM
+    pub fn read_data(&mut self) -> u8 {
+        self.latch = None;
+        let val = self.read_ahead;
+        self.read_ahead = self.vram[usize::from(self.address)];
+        self.increment();
+        val
+    }
+
+    /// Write the next byte of VRAM
+    pub fn write_data(&mut self, val: u8) {
+        self.latch = None;
+        self.vram[usize::from(self.address)] = val;
+        self.read_ahead = val;
+        self.increment();
+    }
+
+    /// Read the status register, which clears the interrupt, fifth sprite and collision flags
+    pub fn read_status(&mut self) -> u8 {
+        self.latch = None;
+        let status = self.status;
+        self.status &= !(STATUS_VBLANK | STATUS_FIFTH_SPRITE | STATUS_COLLISION);
+        status
+    }
+
+    /// Write one byte of a pair to the control port.
+    /// The second byte says what to do with the first: with bit 7 set, write it to the register
+    /// in the low 3 bits; otherwise it is the low byte of a VRAM address, and bit 6 is set to
+    /// write there, or clear to read (which fetches the first byte straight away).
+    pub fn write_control(&mut self, val: u8) {
+        let low = match self.latch.take() {
+            Some(low) => low,
+            None => {
+                self.latch = Some(val);
+                return;
+            }
+        };
+        if val & 0x80 != 0 {
+            self.registers[usize::from(val & 0b111)] = low;
+            return;
+        }
+        self.address = u16::from_le_bytes([low, val & 0x3F]);
+        if val & 0x40 == 0 {
+            self.read_ahead = self.vram[usize::from(self.address)];
+            self.increment();
+        }
+    }
+
+    /// The value last written to a register (0 to 7)
+    pub fn register(&self, reg: usize) -> u8 {
+        self.registers[reg]
+    }
+
+    /// All 16K of VRAM
+    pub fn vram(&self) -> &[u8] {
+        &self.vram
+    }
+
+    /// The VRAM, registers and port state, for a machine's savestate chunk
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut out = self.registers.to_vec();
+        out.push(self.status);
+        out.extend_from_slice(&self.address.to_le_bytes());
+        write_option(&mut out, self.latch);
+        out.push(self.read_ahead);
+        write_block(&mut out, &self.vram);
+        out
+    }
+
+    /// Go back to a state from `save_state`
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), SaveStateError> {
+        let mut reader = StateReader::new(state);
+        let registers = reader.array()?;
+        let status = reader.u8()?;
+        let address = reader.u16()?;
+        let latch = reader.option()?;
+        let read_ahead = reader.u8()?;
+        let vram = reader.block()?;
+        if vram.len() != VRAM_SIZE {
+            return Err(SaveStateError::Truncated);
+        }
+        *self = Self {
+            vram: vram.to_vec(),
+            registers,
+            status,
+            address,
+            latch,
+            read_ahead,
+        };
+        Ok(())
+    }
+
+    /// The current screen mode. Undocumented mixes of mode bits are drawn as the first
+    /// mode set, in the order text, multicolor, graphics 2.
+    pub fn mode(&self) -> Mode {
+        if self.registers[1] & 0x10 != 0 {
+            Mode::Text
+        } else if self.registers[1] & 0x08 != 0 {
+            Mode::Multicolor
+        } else if self.registers[0] & 0x02 != 0 {
+            Mode::Graphics2
+        } else {
+            Mode::Graphics1
+        }
+    }
+
+    /// Returns true while the interrupt output is active: the vertical blank flag is set and
+    /// interrupts are enabled in register 1. Reading the status register clears it.
+    pub fn interrupt(&self) -> bool {
+        self.status & STATUS_VBLANK != 0 && self.registers[1] & 0x20 != 0
+    }
+
+    /// Call at the end of the last visible line, as the vertical blank starts.
+    /// Updates the sprite flags for the frame just drawn, and raises the vertical blank flag.
+    /// Returns true if that makes the interrupt output active.
+    pub fn vblank(&mut self) -> bool {
+        let mut line = [0; WIDTH];
+        for y in 0..HEIGHT {
+            let flags = self.draw_sprites(y, &mut line);
+            self.status |= flags & STATUS_COLLISION;
+            if flags & STATUS_FIFTH_SPRITE != 0 && self.status & STATUS_FIFTH_SPRITE == 0 {
+                self.status = self.status & 0xE0 | flags & !STATUS_COLLISION;
+            }
+        }
+        self.status |= STATUS_VBLANK;
+        self.interrupt()
+    }
+
+    /// Draw the picture: WIDTH by HEIGHT pixels, a row at a time, each an index into PALETTE.
+    /// Transparent pixels show the backdrop colour from register 7.
+    pub fn render(&self) -> Vec<u8> {
+        let backdrop = self.registers[7] & 0x0F;
+        let mut screen = Vec::with_capacity(WIDTH * HEIGHT);
+        for y in 0..HEIGHT {
+            let mut line = [0; WIDTH];
+            // Clearing the blank bit shows nothing but the backdrop
+            if self.registers[1] & 0x40 != 0 {
+                self.draw_background(y, &mut line);
+                if self.mode() != Mode::Text {
+                    self.draw_sprites(y, &mut line);
+                }
+            }
+            screen.extend(line.iter().map(|&c| if c == 0 { backdrop } else { c }));
+        }
+        screen
+    }
+
+    fn increment(&mut self) {
+        self.address = (self.address + 1) & 0x3FFF;
+    }
+
+    fn vram_at(&self, addr: usize) -> u8 {
+        self.vram[addr & (VRAM_SIZE - 1)]
+    }
+
+    fn name_table(&self) -> usize {
+        usize::from(self.registers[2] & 0x0F) << 10
+    }
+
+    fn color_table(&self) -> usize {
+        usize::from(self.registers[3]) << 6
+    }
+
+    fn pattern_table(&self) -> usize {
+        usize::from(self.registers[4] & 0b111) << 11
+    }
+
+    fn sprite_attributes(&self) -> usize {
+        usize::from(self.registers[5] & 0x7F) << 7
+    }
+
+    fn sprite_patterns(&self) -> usize {
+        usize::from(self.registers[6] & 0b111) << 11
+    }
+
+    fn draw_background(&self, y: usize, line: &mut [u8; WIDTH]) {
+        let mode = self.mode();
+        if mode == Mode::Text {
+            let fg = self.registers[7] >> 4;
+            let bg = self.registers[7] & 0x0F;
+            // 240 pixels, centred
+            for col in 0..40 {
+                let name = self.vram_at(self.name_table() + y / 8 * 40 + col);
+                let pattern = self.vram_at(self.pattern_table() + usize::from(name) * 8 + y % 8);
+                for bit in 0..6 {
+                    let lit = pattern & (0x80 >> bit) != 0;
+                    line[8 + col * 6 + bit] = if lit { fg } else { bg };
+                }
+            }
+            return;
+        }
+
+        for col in 0..32 {
+            let name = usize::from(self.vram_at(self.name_table() + y / 8 * 32 + col));
+            let (pattern, colors) = match mode {
+                Mode::Graphics1 => (
+                    self.vram_at(self.pattern_table() + name * 8 + y % 8),
+                    self.vram_at(self.color_table() + name / 8),
+                ),
+                Mode::Graphics2 => {
+                    // Each third of the screen has its own 256 patterns and colours, unless
+                    // the table masks in registers 3 and 4 fold them together
+                    let offset = (y / 64 * 256 + name) * 8 + y % 8;
+                    let pattern_mask = usize::from(self.registers[4] & 0b11) << 11 | 0x7FF;
+                    let color_mask = usize::from(self.registers[3] & 0x7F) << 6 | 0x3F;
+                    let pattern_base = usize::from(self.registers[4] & 0b100) << 11;
+                    let color_base = usize::from(self.registers[3] & 0x80) << 6;
+                    (
+                        self.vram_at(pattern_base | offset & pattern_mask),
+                        self.vram_at(color_base | offset & color_mask),
+                    )
+                }
+                _ => {
+                    // A byte per 4 lines of a tile, which four rows of tiles share
+                    let block = self
+                        .vram_at(self.pattern_table() + name * 8 + (y / 8 % 4) * 2 + (y % 8) / 4);
+                    (0xF0, block)
+                }
+            };
+            for bit in 0..8 {
+                line[col * 8 + bit] = if pattern & (0x80 >> bit) != 0 {
+                    colors >> 4
+                } else {
+                    colors & 0x0F
+                };
+            }
+        }
+    }
+
+    // Draw the sprites on one line over the background, returning the status flags they set:
+    // the collision flag, and the fifth sprite flag with that sprite's number
+    fn draw_sprites(&self, y: usize, line: &mut [u8; WIDTH]) -> u8 {
+        let large = self.registers[1] & 0x02 != 0;
+        let magnify = if self.registers[1] & 0x01 != 0 { 2 } else { 1 };
+        let size = if large { 16 } else { 8 };
+
+        // Lower numbered sprites are in front, even where they are transparent, but only
+        // their coloured pixels are drawn
+        let mut covered = [false; WIDTH];
+        let mut painted = [false; WIDTH];
+        let mut flags = 0;
+        let mut on_line = 0;
+        for n in 0..SPRITES {
+            let attrs = self.sprite_attributes() + n * 4;
+            let sprite_y = self.vram_at(attrs);
+            if sprite_y == LAST_SPRITE {
+                break;
+            }
+            // Sprites are drawn a line below their position, and wrap round from the bottom
+            let top = if sprite_y > 0xC0 {
+                i32::from(sprite_y) - 255
+            } else {
+                i32::from(sprite_y) + 1
+            };
+            let row = y as i32 - top;
+            if row < 0 || row >= size * magnify {
+                continue;
+            }
+            on_line += 1;
+            if on_line > SPRITES_PER_LINE {
+                flags |= STATUS_FIFTH_SPRITE | n as u8;
+                break;
+            }
+
+            let name = self.vram_at(attrs + 2) & if large { 0xFC } else { 0xFF };
+            let attr = self.vram_at(attrs + 3);
+            let color = attr & 0x0F;
+            // The early clock bit moves the sprite 32 pixels left
+            let x = i32::from(self.vram_at(attrs + 1)) - if attr & 0x80 != 0 { 32 } else { 0 };
+            let pattern = self.sprite_patterns() + usize::from(name) * 8 + (row / magnify) as usize;
+            // 16 by 16 sprites have their right half 16 bytes after the left
+            let bits = u16::from_be_bytes([
+                self.vram_at(pattern),
+                if large { self.vram_at(pattern + 16) } else { 0 },
+            ]);
+            for col in 0..size * magnify {
+                let px = x + col;
+                if bits & (0x8000 >> (col / magnify)) == 0 || !(0..WIDTH as i32).contains(&px) {
+                    continue;
+                }
+                let px = px as usize;
+                if covered[px] {
+                    flags |= STATUS_COLLISION;
+                }
+                covered[px] = true;
+                if color != 0 && !painted[px] {
+                    painted[px] = true;
+                    line[px] = color;
+                }
+            }
+        }
+        flags
+    }
+}
+
+/// The data port, for routing with a PortDecoder. It is usually the even one of the pair.
+pub struct DataPort(pub Rc<RefCell<Tms9918>>);
+
+impl InputDevice for DataPort {
+    fn input(&self) -> u8 {
+        self.0.borrow_mut().read_data()
+    }
+}
+
+impl OutputDevice for DataPort {
+    fn output(&self, val: u8) {
+        self.0.borrow_mut().write_data(val);
+    }
+}
+
+/// The control port, for routing with a PortDecoder: reads the status, and takes register
+/// writes and VRAM addresses
+pub struct ControlPort(pub Rc<RefCell<Tms9918>>);
+
+impl InputDevice for ControlPort {
+    fn input(&self) -> u8 {
+        self.0.borrow_mut().read_status()
+    }
+}
+
+impl OutputDevice for ControlPort {
+    fn output(&self, val: u8) {
+        self.0.borrow_mut().write_control(val);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn set_register(vdp: &mut Tms9918, reg: u8, val: u8) {
+        vdp.write_control(val);
+        vdp.write_control(0x80 | reg);
+    }
+
+    fn write_vram(vdp: &mut Tms9918, addr: u16, data: &[u8]) {
+        vdp.write_control(addr as u8);
+        vdp.write_control((addr >> 8) as u8 | 0x40);
+        for &b in data {
+            vdp.write_data(b);
+        }
+    }
+
+    // Graphics 1, display on, tables at 0x1800 (names), 0x2000 (colours), 0x0000 (patterns),
+    // 0x1B00 (sprite attributes) and 0x3800 (sprite patterns), and a blue backdrop
+    fn graphics1() -> Tms9918 {
+        let mut vdp = Tms9918::default();
+        for (reg, val) in [0x00, 0x40, 0x06, 0x80, 0x00, 0x36, 0x07, 0x04]
+            .iter()
+            .enumerate()
+        {
+            set_register(&mut vdp, reg as u8, *val);
+        }
+        vdp
+    }
+
+    #[test]
+    fn ports() {
+        let vdp = Rc::new(RefCell::new(Tms9918::default()));
+        let data = DataPort(vdp.clone());
+        let control = ControlPort(vdp.clone());
+        // Write 1, 2, 3 from 0x3FFF, wrapping round
+        control.output(0xFF);
+        control.output(0x7F);
+        for b in 1..=3 {
+            data.output(b);
+        }
+        assert_eq!(&[2, 3], &vdp.borrow().vram()[..2]);
+        assert_eq!(1, vdp.borrow().vram()[0x3FFF]);
+
+        // Reading from 0x0000 has the first byte ready straight away
+        control.output(0x00);
+        control.output(0x00);
+        assert_eq!(2, data.input());
+        assert_eq!(3, data.input());
+
+        // Reading the status register starts a new pair
+        control.output(0x12);
+        control.input();
+        control.output(0xF7);
+        control.output(0x87);
+        assert_eq!(0xF7, vdp.borrow().register(7));
+    }
+
+    #[test]
+    fn vblank_interrupt() {
+        let mut vdp = Tms9918::default();
+        assert!(!vdp.vblank());
+        assert!(!vdp.interrupt());
+        assert_eq!(0x80, vdp.read_status() & 0x80);
+
+        set_register(&mut vdp, 1, 0x20);
+        assert!(vdp.vblank());
+        assert!(vdp.interrupt());
+        vdp.read_status();
+        assert!(!vdp.interrupt());
+    }
+
+    #[test]
+    fn graphics1_and_backdrop() {
+        let mut vdp = graphics1();
+        // Tile 9 is a vertical bar, white on transparent
+        write_vram(&mut vdp, 0x0048, &[0x80; 8]);
+        write_vram(&mut vdp, 0x2001, &[0xF0]);
+        write_vram(&mut vdp, 0x1821, &[0x09]);
+        let screen = vdp.render();
+        assert_eq!(WIDTH * HEIGHT, screen.len());
+        assert_eq!(15, screen[8 * WIDTH + 8]);
+        assert_eq!(15, screen[15 * WIDTH + 8]);
+        assert_eq!(4, screen[8 * WIDTH + 9]);
+        assert_eq!(4, screen[0]);
+
+        // Blanked
+        set_register(&mut vdp, 1, 0x00);
+        assert!(vdp.render().iter().all(|&c| c == 4));
+    }
+
+    #[test]
+    fn graphics2_thirds() {
+        let mut vdp = graphics1();
+        set_register(&mut vdp, 0, 0x02);
+        set_register(&mut vdp, 3, 0xFF);
+        set_register(&mut vdp, 4, 0x03);
+        // Tile 0 of the middle third is solid red on white
+        write_vram(&mut vdp, 0x0800, &[0xFF; 8]);
+        write_vram(&mut vdp, 0x2800, &[0x8F; 8]);
+        let screen = vdp.render();
+        assert_eq!(4, screen[0]);
+        assert_eq!(8, screen[64 * WIDTH]);
+        assert_eq!(4, screen[128 * WIDTH]);
+    }
+
+    #[test]
+    fn text_mode() {
+        let mut vdp = graphics1();
+        set_register(&mut vdp, 1, 0x50);
+        set_register(&mut vdp, 7, 0xF4);
+        write_vram(&mut vdp, 0x0008, &[0xFC; 8]);
+        write_vram(&mut vdp, 0x1801, &[0x01]);
+        let screen = vdp.render();
+        // The second character, after the border and the first
+        assert_eq!(&[4, 15, 15, 15, 15, 15, 15, 4], &screen[13..21]);
+    }
+
+    #[test]
+    fn multicolor() {
+        let mut vdp = graphics1();
+        set_register(&mut vdp, 1, 0x48);
+        write_vram(&mut vdp, 0x0000, &[0x2F, 0x00, 0x00, 0x00, 0xD0]);
+        let screen = vdp.render();
+        assert_eq!(&[2, 2, 2, 2, 15, 15, 15, 15], &screen[..8]);
+        assert_eq!(4, screen[4 * WIDTH]);
+        // The third of the four rows of tiles sharing the pattern uses the next but one pair
+        assert_eq!(&[13, 13, 13, 13, 4], &screen[16 * WIDTH..16 * WIDTH + 5]);
+    }
+
+    #[test]
+    fn sprites() {
+        let mut vdp = graphics1();
+        write_vram(&mut vdp, 0x3800, &[0xFF; 8]);
+        // Sprite 0 at (10, 20) in red, sprite 1 overlapping it in green, and sprite 2
+        // transparent but in front of sprite 3
+        write_vram(
+            &mut vdp,
+            0x1B00,
+            &[
+                19,
+                10,
+                0,
+                8, //
+                19,
+                14,
+                0,
+                2, //
+                99,
+                100,
+                0,
+                0, //
+                99,
+                100,
+                0,
+                15, //
+                LAST_SPRITE,
+            ],
+        );
+        let screen = vdp.render();
+        assert_eq!(4, screen[19 * WIDTH + 10]);
+        assert_eq!(
+            &[4, 8, 8, 8, 8, 8, 8, 8, 8, 2],
+            &screen[20 * WIDTH + 9..20 * WIDTH + 19]
+        );
+        assert_eq!(15, screen[100 * WIDTH + 100]);
+
+        assert!(!vdp.vblank());
+        assert_eq!(STATUS_VBLANK | STATUS_COLLISION, vdp.read_status());
+    }
+
+    #[test]
+    fn fifth_sprite() {
+        let mut vdp = graphics1();
+        write_vram(&mut vdp, 0x3800, &[0xFF; 8]);
+        let mut attrs = vec![];
+        for n in 0..6 {
+            attrs.extend_from_slice(&[0xFF, n * 16, 0, 15]);
+        }
+        attrs.push(LAST_SPRITE);
+        write_vram(&mut vdp, 0x1B00, &attrs);
+        let screen = vdp.render();
+        assert_eq!(15, screen[48]);
+        assert_eq!(4, screen[64]);
+
+        vdp.vblank();
+        assert_eq!(STATUS_VBLANK | STATUS_FIFTH_SPRITE | 4, vdp.read_status());
+        assert_eq!(4, vdp.read_status());
+    }
+}
@@ -1,11 +1,25 @@
 //! The internal representation of the z80's memory.
-//! Currently just a large array.
+//! Currently just a large array, but use the methods here rather than the array itself, as they
+//! will keep working when watchpoints, ROM protection or banking are added.
+//! Code using the `memory` field can move over like so:
+//!
+//! | Before                     | After                        |
+//! |----------------------------|------------------------------|
+//! | `mem.memory[addr]`         | `mem.read_u8(addr)`          |
+//! | `mem.memory[addr] = val`   | `mem.write_u8(addr, val)`    |
+//! | `&mem.memory[from..to]`    | `mem.view(from..to)`         |
+//! | `&mut mem.memory[from..to]`| `mem.view_mut(from..to)`     |
+use std::fmt;
+use std::slice::SliceIndex;
+
 pub const MEMORY_SIZE: usize = 16 * 1024; // 16 kibibytes
 
 pub struct Memory {
+    #[deprecated(note = "use read_u8, write_u8, view or view_mut instead")]
     pub memory: [u8; MEMORY_SIZE],
 }
 
+#[allow(deprecated)]
 impl Default for Memory {
     fn default() -> Self {
         Memory {
@@ -13,3 +27,134 @@ impl Default for Memory {
         }
     }
 }
+
+/// A block of bytes that doesn't fit in memory
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct OutOfRange {
+    pub addr: usize,
+    pub len: usize,
+}
+
+impl fmt::Display for OutOfRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes at {:04x} run past the end of memory ({:04x})",
+            self.len, self.addr, MEMORY_SIZE
+        )
+    }
+}
+
+impl std::error::Error for OutOfRange {}
+
+#[allow(deprecated)]
+impl Memory {
+    /// # Panics
+    /// Panics if the address is past the end of memory
+    pub fn read_u8(&self, addr: u16) -> u8 {
+        self.memory[usize::from(addr)]
+    }
+
+    /// # Panics
+    /// Panics if the address is past the end of memory
+    pub fn write_u8(&mut self, addr: u16, val: u8) {
+        self.memory[usize::from(addr)] = val;
+    }
+
+    /// Read a little-endian word. The second byte wraps around to 0x0000 after 0xFFFF.
+    ///
+    /// # Panics
+    /// Panics if either address is past the end of memory
+    pub fn read_u16(&self, addr: u16) -> u16 {
+        u16::from_le_bytes([self.read_u8(addr), self.read_u8(addr.wrapping_add(1))])
+    }
+
+    /// Write a little-endian word. The second byte wraps around to 0x0000 after 0xFFFF.
+    ///
+    /// # Panics
+    /// Panics if either address is past the end of memory
+    pub fn write_u16(&mut self, addr: u16, val: u16) {
+        let [low, high] = val.to_le_bytes();
+        self.write_u8(addr, low);
+        self.write_u8(addr.wrapping_add(1), high);
+    }
+
+    /// The bytes in a range of addresses, such as `0x1800..0x1B00`.
+    /// Returns None if the range runs past the end of memory.
+    pub fn view<R: SliceIndex<[u8], Output = [u8]>>(&self, range: R) -> Option<&[u8]> {
+        self.memory.get(range)
+    }
+
+    /// Like view, but the bytes can be changed
+    pub fn view_mut<R: SliceIndex<[u8], Output = [u8]>>(&mut self, range: R) -> Option<&mut [u8]> {
+        self.memory.get_mut(range)
+    }
+
+    /// Write several blocks of bytes, each at its own address.
+    /// Either every block is written, or (if any would run past the end of memory) none are.
+    /// ```
+    /// use zeerust::cpu::mem::Memory;
+    ///
+    /// let mut mem = Memory::default();
+    /// let header = [0xF3, 0xC3, 0x00, 0x01];
+    /// let body = [0x3E, 0x2A, 0x76];
+    /// mem.write_blocks(&[(0x0000, &header), (0x0100, &body)]).unwrap();
+    /// assert_eq!(Some(&body[..]), mem.view(0x0100..0x0103));
+    ///```
+    pub fn write_blocks(&mut self, blocks: &[(u16, &[u8])]) -> Result<(), OutOfRange> {
+        for (addr, bytes) in blocks {
+            let addr = usize::from(*addr);
+            if addr + bytes.len() > MEMORY_SIZE {
+                return Err(OutOfRange {
+                    addr,
+                    len: bytes.len(),
+                });
+            }
+        }
+        for (addr, bytes) in blocks {
+            let addr = usize::from(*addr);
+            self.memory[addr..addr + bytes.len()].copy_from_slice(bytes);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn views() {
+        let mut mem = Memory::default();
+        mem.view_mut(0x10..0x12).unwrap().copy_from_slice(&[1, 2]);
+        assert_eq!(Some(&[0, 1, 2, 0][..]), mem.view(0x0F..=0x12));
+        assert_eq!(MEMORY_SIZE, mem.view(..).unwrap().len());
+        assert_eq!(None, mem.view(MEMORY_SIZE - 1..MEMORY_SIZE + 1));
+        assert!(mem.view_mut(MEMORY_SIZE..=MEMORY_SIZE).is_none());
+    }
+
+    #[test]
+    fn words() {
+        let mut mem = Memory::default();
+        mem.write_u16(0x1000, 0xBEEF);
+        assert_eq!(0xEF, mem.read_u8(0x1000));
+        assert_eq!(0xBE, mem.read_u8(0x1001));
+        assert_eq!(0xBEEF, mem.read_u16(0x1000));
+    }
+
+    #[test]
+    fn out_of_range_blocks() {
+        let mut mem = Memory::default();
+        let top = (MEMORY_SIZE - 2) as u16;
+        assert_eq!(
+            Err(OutOfRange {
+                addr: MEMORY_SIZE - 2,
+                len: 3
+            }),
+            mem.write_blocks(&[(0x0000, &[1]), (top, &[2, 3, 4])])
+        );
+        // Nothing was written
+        assert_eq!(0, mem.read_u8(0));
+        assert_eq!(Ok(()), mem.write_blocks(&[(top, &[2, 3])]));
+    }
+}
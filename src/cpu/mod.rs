@@ -3,3 +3,4 @@
 pub mod mem;
 pub mod opcodes;
 pub mod reg;
+pub mod timing;
@@ -0,0 +1,74 @@
+//! Auditing the decoder for completeness.
+//! Every byte on every opcode page is decoded, and anything the decoder can't handle is reported
+//! as a gap. The list of gaps is kept in tests/decode_gaps.txt, so that progress on the
+//! instruction set is tracked along with the code. Build with the `strict-decode` feature to
+//! fail the tests until there are no gaps left.
+use super::try_opcode;
+
+/// An opcode the decoder can't handle
+#[derive(Debug, PartialEq, Clone)]
+pub struct Gap {
+    /// The opcode bytes. The displacement of DDCB and FDCB opcodes is always zero.
+    pub bytes: Vec<u8>,
+    /// Why it couldn't be decoded
+    pub reason: String,
+}
+
+// Every opcode page, as the bytes preceding the opcode
+const PAGES: [&[u8]; 7] = [
+    &[],
+    &[0xCB],
+    &[0xED],
+    &[0xDD],
+    &[0xFD],
+    &[0xDD, 0xCB, 0x00],
+    &[0xFD, 0xCB, 0x00],
+];
+
+/// Decode every opcode on every page, returning the ones that fail, or that decode to something
+/// shorter than the opcode itself.
+/// Prefixes themselves (such as 0xCB on the unprefixed page) are not counted.
+pub fn audit() -> Vec<Gap> {
+    let mut gaps = vec![];
+    for page in PAGES.iter() {
+        for op in 0..=0xFF_u8 {
+            let mut bytes = page.to_vec();
+            bytes.push(op);
+            if is_prefix(&bytes) {
+                continue;
+            }
+            let mut code = [0; 4];
+            code[..bytes.len()].copy_from_slice(&bytes);
+            let reason = match try_opcode(code) {
+                // Something else entirely was decoded, ignoring the prefix
+                Ok((op, length)) if length < bytes.len() => {
+                    format!("Decoded as {:?}, {} bytes long", op, length)
+                }
+                Ok(_) => continue,
+                Err(reason) => reason,
+            };
+            gaps.push(Gap { bytes, reason });
+        }
+    }
+
+    gaps
+}
+
+fn is_prefix(bytes: &[u8]) -> bool {
+    match bytes {
+        [0xCB] | [0xED] | [0xDD] | [0xFD] => true,
+        // Prefixes can be repeated, the last one wins
+        [0xDD, op] | [0xFD, op] => [0xCB, 0xDD, 0xED, 0xFD].contains(op),
+        _ => false,
+    }
+}
+
+/// Format gaps as a report, one per line
+pub fn report(gaps: &[Gap]) -> String {
+    let mut report = format!("{} opcodes can't be decoded\n", gaps.len());
+    for gap in gaps {
+        let bytes: Vec<String> = gap.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        report += &format!("{:<12} {}\n", bytes.join(" "), gap.reason);
+    }
+    report
+}
@@ -0,0 +1,29 @@
+//! Where the 8080 and Z80 part ways.
+//! The Z80 filled the 8080's spare opcodes with new instructions. On an 8080 they are aliases of
+//! others, which some programs did use, so they are decoded that way for an 8080. Everything
+//! else is shared.
+use crate::cpu::opcodes::util::*;
+use crate::ops::{JumpConditional, Op};
+
+/// Parse one of the opcodes the 8080 treats differently. Returns None for the shared ones.
+pub fn opcode(code: [u8; 4]) -> Option<(Op, usize)> {
+    let decoded = match code {
+        [0x08, _, _, _]
+        | [0x10, _, _, _]
+        | [0x18, _, _, _]
+        | [0x20, _, _, _]
+        | [0x28, _, _, _]
+        | [0x30, _, _, _]
+        | [0x38, _, _, _] => (Op::NOP, 1),
+        [0xCB, n1, n2, _] => (
+            Op::JP(JumpConditional::Unconditional, le_immediate(n1, n2)),
+            3,
+        ),
+        [0xD9, _, _, _] => (Op::RET(JumpConditional::Unconditional), 1),
+        [0xDD, n1, n2, _] | [0xED, n1, n2, _] | [0xFD, n1, n2, _] => {
+            (Op::CALL(JumpConditional::Unconditional, le_word(n1, n2)), 3)
+        }
+        _ => return None,
+    };
+    Some(decoded)
+}
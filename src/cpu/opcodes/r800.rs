@@ -0,0 +1,22 @@
+//! The instructions the R800 adds to the Z80's: its multipliers.
+//! Like the Z180's, they live in holes of the ED table, so are only decoded for an R800.
+use crate::cpu::opcodes::util::*;
+use crate::ops::{Location16, Location8, Op};
+
+/// Parse an R800 instruction. Returns None for everything else, including plain Z80 instructions.
+pub fn opcode(code: [u8; 4]) -> Option<(Op, usize)> {
+    let decoded = match code {
+        // MULUB A, B/C/D/E
+        [0xED, op, _, _] if op & 0b1110_0111 == 0b1100_0001 => match reg_bits(op >> 3) {
+            Location8::Reg(reg) => (Op::MULUB(reg), 2),
+            _ => unreachable!(),
+        },
+        // MULUW HL, BC/SP
+        [0xED, op @ 0xC3, _, _] | [0xED, op @ 0xF3, _, _] => match reg16_bits(op >> 4) {
+            Location16::Reg(reg) => (Op::MULUW(reg), 2),
+            _ => unreachable!(),
+        },
+        _ => return None,
+    };
+    Some(decoded)
+}
@@ -0,0 +1,1160 @@
+//! The Z80's instructions, as a table.
+//! Each instruction is written down once, as the Zilog manual has it: its syntax, its encoding,
+//! the T-states it takes and what it does to the flags. The decoder, the encoder, the
+//! disassembler and the metadata all come from that, so they can't disagree with each other.
+//!
+//! An encoding is a list of bytes. A byte is either fixed (`ED`), an opcode with fields in it
+//! (`01rrrsss`), or an operand: `n` for a byte, `nn` for a little-endian word, `e` for a
+//! relative jump and `d` for an index displacement. The fields are:
+//!
+//! | Field    | Operand   | Values                                           |
+//! |----------|-----------|--------------------------------------------------|
+//! | `r`, `s` | `r`, `r'` | A, B, C, D, E, H or L; (HL) has rows of its own  |
+//! | `b`      | `b`       | A bit                                            |
+//! | `c`      | `cc`      | A condition. JR only has room for the first four |
+//! | `p`      | `dd`      | BC, DE, HL or SP                                 |
+//! | `q`      | `qq`      | BC, DE, HL or AF                                 |
+//! | `t`      | `p`       | A restart address, over 8                        |
+//! | `x`      |           | Ignored, for undocumented mirrors                |
+//!
+//! The flags are S, Z, H, P/V, N and C, in that order: `-` unchanged, `0` reset, `1` set, `*`
+//! affected, `V` overflow, `P` parity and `?` unknown.
+//! Instructions using IX are repeated for IY, with the FD prefix. Where two rows share an
+//! instruction, the first is the one it's encoded as.
+//!
+//! The instructions only some relatives of the Z80 have are decoded by their own modules.
+use std::fmt;
+use std::sync::OnceLock;
+
+use super::util::*;
+use crate::ops::{JumpConditional, Location16, Location8, Op, Reg16, Reg8, StatusFlag};
+
+struct Definition {
+    syntax: &'static str,
+    encoding: &'static str,
+    tstates: u32,
+    not_taken: u32,
+    flags: &'static str,
+}
+
+macro_rules! instructions {
+    ($($syntax:literal => $encoding:literal, $tstates:literal $(/ $not_taken:literal)?, $flags:literal;)*) => {
+        const DEFINITIONS: &[Definition] = &[$(
+            Definition {
+                syntax: $syntax,
+                encoding: $encoding,
+                tstates: $tstates,
+                not_taken: [$($not_taken,)? $tstates][0],
+                flags: $flags,
+            },
+        )*];
+    };
+}
+
+instructions! {
+    "NOP" => "00", 4, "------";
+    "HALT" => "76", 4, "------";
+    "DI" => "F3", 4, "------";
+    "EI" => "FB", 4, "------";
+
+    // 8-bit loads
+    "LD r, r'" => "01rrrsss", 4, "------";
+    "LD r, n" => "00rrr110 n", 7, "------";
+    "LD r, (HL)" => "01rrr110", 7, "------";
+    "LD (HL), r" => "01110rrr", 7, "------";
+    "LD (HL), n" => "36 n", 10, "------";
+    "LD A, (BC)" => "0A", 7, "------";
+    "LD A, (DE)" => "1A", 7, "------";
+    "LD A, (nn)" => "3A nn", 13, "------";
+    "LD (BC), A" => "02", 7, "------";
+    "LD (DE), A" => "12", 7, "------";
+    "LD (nn), A" => "32 nn", 13, "------";
+    "LD A, I" => "ED 57", 9, "**0*0-";
+    "LD A, R" => "ED 5F", 9, "**0*0-";
+    "LD I, A" => "ED 47", 9, "------";
+    "LD R, A" => "ED 4F", 9, "------";
+
+    // 16-bit loads
+    "LD dd, nn" => "00pp0001 nn", 10, "------";
+    "LD IX, nn" => "DD 21 nn", 14, "------";
+    "LD HL, (nn)" => "2A nn", 16, "------";
+    "LD dd, (nn)" => "ED 01pp1011 nn", 20, "------";
+    "LD IX, (nn)" => "DD 2A nn", 20, "------";
+    "LD (nn), HL" => "22 nn", 16, "------";
+    "LD (nn), dd" => "ED 01pp0011 nn", 20, "------";
+    "LD (nn), IX" => "DD 22 nn", 20, "------";
+    "LD SP, HL" => "F9", 6, "------";
+    "LD SP, IX" => "DD F9", 10, "------";
+    "PUSH qq" => "11qq0101", 11, "------";
+    "PUSH IX" => "DD E5", 15, "------";
+    "POP qq" => "11qq0001", 10, "------";
+    "POP IX" => "DD E1", 14, "------";
+
+    // Exchanges
+    "EX (SP), HL" => "E3", 19, "------";
+    "EX (SP), IX" => "DD E3", 23, "------";
+
+    // 8-bit arithmetic
+    "ADD A, r" => "10000rrr", 4, "***V0*";
+    "ADD A, n" => "C6 n", 7, "***V0*";
+    "ADD A, (HL)" => "86", 7, "***V0*";
+    "ADC A, r" => "10001rrr", 4, "***V0*";
+    "ADC A, n" => "CE n", 7, "***V0*";
+    "ADC A, (HL)" => "8E", 7, "***V0*";
+    "SUB r" => "10010rrr", 4, "***V1*";
+    "SUB n" => "D6 n", 7, "***V1*";
+    "SUB (HL)" => "96", 7, "***V1*";
+    "SBC A, r" => "10011rrr", 4, "***V1*";
+    "SBC A, n" => "DE n", 7, "***V1*";
+    "SBC A, (HL)" => "9E", 7, "***V1*";
+    "AND r" => "10100rrr", 4, "**1P00";
+    "AND n" => "E6 n", 7, "**1P00";
+    "AND (HL)" => "A6", 7, "**1P00";
+    "OR r" => "10110rrr", 4, "**0P00";
+    "OR n" => "F6 n", 7, "**0P00";
+    "OR (HL)" => "B6", 7, "**0P00";
+    "XOR r" => "10101rrr", 4, "**0P00";
+    "XOR n" => "EE n", 7, "**0P00";
+    "XOR (HL)" => "AE", 7, "**0P00";
+    "CP r" => "10111rrr", 4, "***V1*";
+    "CP n" => "FE n", 7, "***V1*";
+    "CP (HL)" => "BE", 7, "***V1*";
+    "INC r" => "00rrr100", 4, "***V0-";
+    "INC (HL)" => "34", 11, "***V0-";
+    "DEC r" => "00rrr101", 4, "***V1-";
+    "DEC (HL)" => "35", 11, "***V1-";
+
+    // General purpose arithmetic
+    "CPL" => "2F", 4, "--1-1-";
+    "NEG" => "ED 44", 8, "***V1*";
+    "CCF" => "3F", 4, "--*-0*";
+    "SCF" => "37", 4, "--0-01";
+    "IM 0" => "ED 01x0x110", 8, "------";
+    "IM 1" => "ED 01x10110", 8, "------";
+    "IM 2" => "ED 01x11110", 8, "------";
+
+    // Rotates and shifts
+    "RLCA" => "07", 4, "--0-0*";
+    "RLA" => "17", 4, "--0-0*";
+    "RRCA" => "0F", 4, "--0-0*";
+    "RRA" => "1F", 4, "--0-0*";
+    "RLC r" => "CB 00000rrr", 8, "**0P0*";
+    "RLC (HL)" => "CB 06", 15, "**0P0*";
+    "RLC (IX+d)" => "DD CB d 06", 23, "**0P0*";
+    "RLC (IX+d), r" => "DD CB d 00000rrr", 23, "**0P0*";
+    "RRC r" => "CB 00001rrr", 8, "**0P0*";
+    "RRC (HL)" => "CB 0E", 15, "**0P0*";
+    "RRC (IX+d)" => "DD CB d 0E", 23, "**0P0*";
+    "RRC (IX+d), r" => "DD CB d 00001rrr", 23, "**0P0*";
+    "RL r" => "CB 00010rrr", 8, "**0P0*";
+    "RL (HL)" => "CB 16", 15, "**0P0*";
+    "RL (IX+d)" => "DD CB d 16", 23, "**0P0*";
+    "RL (IX+d), r" => "DD CB d 00010rrr", 23, "**0P0*";
+    "RR r" => "CB 00011rrr", 8, "**0P0*";
+    "RR (HL)" => "CB 1E", 15, "**0P0*";
+    "RR (IX+d)" => "DD CB d 1E", 23, "**0P0*";
+    "RR (IX+d), r" => "DD CB d 00011rrr", 23, "**0P0*";
+    "SLA r" => "CB 00100rrr", 8, "**0P0*";
+    "SLA (HL)" => "CB 26", 15, "**0P0*";
+    "SLA (IX+d)" => "DD CB d 26", 23, "**0P0*";
+    "SLA (IX+d), r" => "DD CB d 00100rrr", 23, "**0P0*";
+    "SRA r" => "CB 00101rrr", 8, "**0P0*";
+    "SRA (HL)" => "CB 2E", 15, "**0P0*";
+    "SRA (IX+d)" => "DD CB d 2E", 23, "**0P0*";
+    "SRA (IX+d), r" => "DD CB d 00101rrr", 23, "**0P0*";
+    "SRL r" => "CB 00111rrr", 8, "**0P0*";
+    "SRL (HL)" => "CB 3E", 15, "**0P0*";
+    "SRL (IX+d)" => "DD CB d 3E", 23, "**0P0*";
+    "SRL (IX+d), r" => "DD CB d 00111rrr", 23, "**0P0*";
+    "RLD" => "ED 6F", 18, "**0P0-";
+    "RRD" => "ED 67", 18, "**0P0-";
+
+    // Bits
+    "BIT b, r" => "CB 01bbbrrr", 8, "?*1?0-";
+    "BIT b, (HL)" => "CB 01bbb110", 12, "?*1?0-";
+    "BIT b, (IX+d)" => "DD CB d 01bbb110", 20, "?*1?0-";
+    "BIT b, (IX+d)" => "DD CB d 01bbbxxx", 20, "?*1?0-";
+    "SET b, r" => "CB 11bbbrrr", 8, "------";
+    "SET b, (HL)" => "CB 11bbb110", 15, "------";
+    "SET b, (IX+d)" => "DD CB d 11bbb110", 23, "------";
+    "SET b, (IX+d), r" => "DD CB d 11bbbrrr", 23, "------";
+    "RES b, r" => "CB 10bbbrrr", 8, "------";
+    "RES b, (HL)" => "CB 10bbb110", 15, "------";
+    "RES b, (IX+d)" => "DD CB d 10bbb110", 23, "------";
+    "RES b, (IX+d), r" => "DD CB d 10bbbrrr", 23, "------";
+
+    // Jumps
+    "JP nn" => "C3 nn", 10, "------";
+    "JP cc, nn" => "11ccc010 nn", 10, "------";
+    "JR e" => "18 e", 12, "------";
+    "JR cc, e" => "001cc000 e", 12 / 7, "------";
+    "JP (HL)" => "E9", 4, "------";
+    "JP (IX)" => "DD E9", 8, "------";
+    "DJNZ e" => "10 e", 13 / 8, "------";
+
+    // Calls and returns
+    "CALL nn" => "CD nn", 17, "------";
+    "CALL cc, nn" => "11ccc100 nn", 17 / 10, "------";
+    "RET" => "C9", 10, "------";
+    "RET cc" => "11ccc000", 11 / 5, "------";
+    "RETI" => "ED 4D", 14, "------";
+    "RETN" => "ED 01xxx101", 14, "------";
+    "RST p" => "11ttt111", 11, "------";
+
+    // Input and output
+    "IN A, (n)" => "DB n", 11, "------";
+    "IN r, (C)" => "ED 01rrr000", 12, "**0P0-";
+    "OUT (n), A" => "D3 n", 11, "------";
+    "OUT (C), r" => "ED 01rrr001", 12, "------";
+    "OUT (C), 0" => "ED 71", 12, "------";
+}
+
+/// The flags the table describes, in its order, with their bits in F
+pub const FLAGS: [(StatusFlag, u8); 6] = [
+    (StatusFlag::Sign, 1 << 7),
+    (StatusFlag::Zero, 1 << 6),
+    (StatusFlag::HalfCarry, 1 << 4),
+    (StatusFlag::ParityOverflow, 1 << 2),
+    (StatusFlag::AddSubtract, 1 << 1),
+    (StatusFlag::Carry, 1),
+];
+
+/// What an instruction does to a flag
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FlagEffect {
+    Unchanged,
+    Reset,
+    Set,
+    /// Set or reset depending on the result, or left in a state nobody has documented
+    Affected,
+}
+
+// Every opcode page, as the bytes preceding the opcode. DDCB and FDCB opcodes also have a
+// displacement between the two.
+const PAGES: [&[u8]; 7] = [
+    &[],
+    &[0xCB],
+    &[0xED],
+    &[0xDD],
+    &[0xFD],
+    &[0xDD, 0xCB],
+    &[0xFD, 0xCB],
+];
+
+/// An operand, as an instruction's syntax has it
+#[derive(Debug, PartialEq, Clone)]
+enum Operand {
+    Byte(Location8),
+    Word(Location16),
+    /// The port of an IN or OUT: (n) or (C)
+    Port(Location8),
+    Condition(JumpConditional),
+    /// A bit, an interrupt mode, or what OUT (C), 0 writes
+    Number(u8),
+    /// The offset of a relative jump
+    Offset(i8),
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Port(Location8::Reg(reg)) => write!(f, "({})", reg),
+            Operand::Port(Location8::Immediate(n)) => write!(f, "(${:02x})", n),
+            Operand::Byte(loc) | Operand::Port(loc) => match loc {
+                Location8::Reg(reg) => write!(f, "{}", reg),
+                Location8::RegIndirect(reg) => write!(f, "({})", reg),
+                Location8::ImmediateIndirect(addr) => write!(f, "(${:04x})", addr),
+                Location8::Immediate(n) => write!(f, "${:02x}", n),
+                Location8::Indexed(reg, d) | Location8::IndexedCopy(reg, d, _) => {
+                    write!(f, "({}{:+})", reg, d)
+                }
+            },
+            Operand::Word(loc) => match loc {
+                Location16::Reg(reg) => write!(f, "{}", reg),
+                Location16::RegIndirect(reg) => write!(f, "({})", reg),
+                Location16::ImmediateIndirect(addr) => write!(f, "(${:04x})", addr),
+                Location16::Immediate(nn) => write!(f, "${:04x}", nn),
+            },
+            Operand::Condition(c) => {
+                let name = match c {
+                    JumpConditional::Unconditional => "",
+                    JumpConditional::NonZero => "NZ",
+                    JumpConditional::Zero => "Z",
+                    JumpConditional::NoCarry => "NC",
+                    JumpConditional::Carry => "C",
+                    JumpConditional::ParityOdd => "PO",
+                    JumpConditional::ParityEven => "PE",
+                    JumpConditional::SignPositive => "P",
+                    JumpConditional::SignNegative => "M",
+                };
+                write!(f, "{}", name)
+            }
+            Operand::Number(n) => write!(f, "{}", n),
+            Operand::Offset(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+// An operand in an instruction's syntax
+#[derive(Debug, PartialEq, Clone)]
+enum Token {
+    Fixed(Operand),
+    // r and r', from the field named
+    Register(u8),
+    Bit,
+    Condition,
+    // dd, and qq with AF instead of SP
+    Pair,
+    PairAf,
+    Restart,
+    Immediate,
+    Port,
+    Word,
+    Indirect,
+    Offset,
+    Indexed(Reg16),
+}
+
+impl Token {
+    fn parse(text: &str) -> Token {
+        let reg8 = |reg| Token::Fixed(Operand::Byte(Location8::Reg(reg)));
+        let reg16 = |reg| Token::Fixed(Operand::Word(Location16::Reg(reg)));
+        let indirect = |reg| Token::Fixed(Operand::Byte(Location8::RegIndirect(reg)));
+        match text {
+            "r" => Token::Register(b'r'),
+            "r'" => Token::Register(b's'),
+            "b" => Token::Bit,
+            "cc" => Token::Condition,
+            "dd" => Token::Pair,
+            "qq" => Token::PairAf,
+            "p" => Token::Restart,
+            "n" => Token::Immediate,
+            "(n)" => Token::Port,
+            "nn" => Token::Word,
+            "(nn)" => Token::Indirect,
+            "e" => Token::Offset,
+            "(IX+d)" => Token::Indexed(Reg16::IX),
+            "(IY+d)" => Token::Indexed(Reg16::IY),
+            "A" => reg8(Reg8::A),
+            "I" => reg8(Reg8::I),
+            "R" => reg8(Reg8::R),
+            "(C)" => Token::Fixed(Operand::Port(Location8::Reg(Reg8::C))),
+            "(BC)" => indirect(Reg16::BC),
+            "(DE)" => indirect(Reg16::DE),
+            "(HL)" => indirect(Reg16::HL),
+            "(IX)" => indirect(Reg16::IX),
+            "(IY)" => indirect(Reg16::IY),
+            "HL" => reg16(Reg16::HL),
+            "SP" => reg16(Reg16::SP),
+            "IX" => reg16(Reg16::IX),
+            "IY" => reg16(Reg16::IY),
+            "(SP)" => Token::Fixed(Operand::Word(Location16::RegIndirect(Reg16::SP))),
+            _ => match text.parse() {
+                Ok(n) => Token::Fixed(Operand::Number(n)),
+                Err(_) => panic!("unknown operand {} in the instruction table", text),
+            },
+        }
+    }
+
+    fn is_wide(&self) -> bool {
+        matches!(
+            self,
+            Token::Fixed(Operand::Word(_)) | Token::Pair | Token::PairAf
+        )
+    }
+}
+
+// An opcode byte: which bits are fixed, and which belong to fields
+#[derive(Debug, PartialEq, Clone)]
+struct Pattern {
+    mask: u8,
+    value: u8,
+    // The field each bit is in, from the top, or 0 for fixed bits
+    fields: [u8; 8],
+}
+
+impl Pattern {
+    fn parse(text: &str) -> Option<Pattern> {
+        if text.len() == 2 {
+            let value = u8::from_str_radix(text, 16).ok()?;
+            return Some(Pattern {
+                mask: 0xFF,
+                value,
+                fields: [0; 8],
+            });
+        }
+        if text.len() != 8 {
+            return None;
+        }
+        let mut pattern = Pattern {
+            mask: 0,
+            value: 0,
+            fields: [0; 8],
+        };
+        for (i, c) in text.bytes().enumerate() {
+            let bit = 0x80 >> i;
+            match c {
+                b'0' => pattern.mask |= bit,
+                b'1' => {
+                    pattern.mask |= bit;
+                    pattern.value |= bit;
+                }
+                b'a'..=b'z' => pattern.fields[i] = c,
+                _ => return None,
+            }
+        }
+        Some(pattern)
+    }
+
+    fn matches(&self, byte: u8) -> bool {
+        // (HL) is never an r
+        byte & self.mask == self.value
+            && self.field(byte, b'r') != Some(0b110)
+            && self.field(byte, b's') != Some(0b110)
+    }
+
+    fn field(&self, byte: u8, name: u8) -> Option<u8> {
+        let mut value = None;
+        for (i, field) in self.fields.iter().enumerate() {
+            if *field == name {
+                let bit = (byte >> (7 - i)) & 1;
+                value = Some((value.unwrap_or(0) << 1) | bit);
+            }
+        }
+        value
+    }
+
+    // Put a value in a field, if it fits
+    fn set_field(&self, byte: u8, name: u8, value: u8) -> Option<u8> {
+        let width = self.fields.iter().filter(|f| **f == name).count();
+        if width == 0 || u32::from(value) >= 1 << width {
+            return None;
+        }
+        let mut byte = byte;
+        let mut remaining = width;
+        for (i, field) in self.fields.iter().enumerate() {
+            if *field == name {
+                remaining -= 1;
+                let bit = 0x80 >> i;
+                if (value >> remaining) & 1 == 1 {
+                    byte |= bit;
+                } else {
+                    byte &= !bit;
+                }
+            }
+        }
+        Some(byte)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Byte {
+    Opcode(Pattern),
+    Immediate,
+    Word,
+    Offset,
+    Displacement,
+}
+
+// What an instruction's encoding holds, besides its opcode
+#[derive(Debug, Default)]
+struct Values {
+    fields: [u8; 26],
+    byte: u8,
+    word: u16,
+    offset: i8,
+    displacement: i8,
+}
+
+impl Values {
+    fn field(&mut self, name: u8) -> &mut u8 {
+        &mut self.fields[usize::from(name - b'a')]
+    }
+}
+
+/// An instruction from the table
+#[derive(Debug, PartialEq, Clone)]
+pub struct Instruction {
+    /// As the Zilog manual writes it, such as `LD r, n`
+    pub syntax: String,
+    /// Its bytes, such as `00rrr110 n`. See the module documentation.
+    pub encoding: String,
+    /// How many bytes long it is
+    pub length: usize,
+    /// The T-states it takes
+    pub tstates: u32,
+    /// The T-states it takes when its condition isn't met, or tstates if it hasn't one
+    pub not_taken: u32,
+    /// What it does to the S, Z, H, P/V, N and C flags. See the module documentation.
+    pub flags: &'static str,
+    mnemonic: String,
+    operands: Vec<Token>,
+    bytes: Vec<Byte>,
+    // Which of PAGES it's on, and which byte is the opcode
+    page: usize,
+    opcode: usize,
+    // Whether (nn) is a word
+    wide: bool,
+}
+
+impl Instruction {
+    fn new(definition: &Definition, syntax: String, encoding: String) -> Self {
+        let (mnemonic, operands) = syntax.split_once(' ').unwrap_or((&syntax, ""));
+        let operands: Vec<Token> = operands
+            .split(',')
+            .map(str::trim)
+            .filter(|o| !o.is_empty())
+            .map(Token::parse)
+            .collect();
+        let bytes: Vec<Byte> = encoding
+            .split(' ')
+            .map(|b| match b {
+                "n" => Byte::Immediate,
+                "nn" => Byte::Word,
+                "e" => Byte::Offset,
+                "d" => Byte::Displacement,
+                _ => Byte::Opcode(
+                    Pattern::parse(b)
+                        .unwrap_or_else(|| panic!("bad byte {} in the encoding of {}", b, syntax)),
+                ),
+            })
+            .collect();
+        let opcode = bytes
+            .iter()
+            .rposition(|b| matches!(b, Byte::Opcode(_)))
+            .unwrap_or_else(|| panic!("{} has no opcode", syntax));
+        let prefix: Vec<u8> = bytes[..opcode]
+            .iter()
+            .filter_map(|b| match b {
+                Byte::Opcode(p) => Some(p.value),
+                _ => None,
+            })
+            .collect();
+        let page = PAGES
+            .iter()
+            .position(|p| *p == &prefix[..])
+            .unwrap_or_else(|| panic!("{} isn't on any page", syntax));
+        assert!(
+            definition.flags.len() == FLAGS.len()
+                && definition.flags.bytes().all(|f| b"-01*VP?".contains(&f)),
+            "bad flags {} for {}",
+            definition.flags,
+            syntax
+        );
+        let length = bytes
+            .iter()
+            .map(|b| if *b == Byte::Word { 2 } else { 1 })
+            .sum();
+        Instruction {
+            mnemonic: mnemonic.to_string(),
+            wide: operands.iter().any(Token::is_wide),
+            operands,
+            bytes,
+            page,
+            opcode,
+            length,
+            tstates: definition.tstates,
+            not_taken: definition.not_taken,
+            flags: definition.flags,
+            syntax,
+            encoding,
+        }
+    }
+
+    /// What the instruction does to a flag. None for X and Y, which the table doesn't cover.
+    /// For example:
+    /// ```
+    /// use zeerust::cpu::opcodes::table::{self, FlagEffect};
+    /// use zeerust::ops::StatusFlag;
+    ///
+    /// let and = table::lookup([0xA0, 0x00, 0x00, 0x00]).unwrap(); // AND B
+    /// assert_eq!(Some(FlagEffect::Set), and.flag_effect(&StatusFlag::HalfCarry));
+    /// assert_eq!(Some(FlagEffect::Reset), and.flag_effect(&StatusFlag::Carry));
+    /// assert_eq!(0b1101_0111, and.changed_flags());
+    ///```
+    pub fn flag_effect(&self, flag: &StatusFlag) -> Option<FlagEffect> {
+        let i = FLAGS.iter().position(|(f, _)| f == flag)?;
+        let effect = match self.flags.as_bytes()[i] {
+            b'-' => FlagEffect::Unchanged,
+            b'0' => FlagEffect::Reset,
+            b'1' => FlagEffect::Set,
+            _ => FlagEffect::Affected,
+        };
+        Some(effect)
+    }
+
+    /// The bits of F the instruction can change, for a debugger to point out
+    pub fn changed_flags(&self) -> u8 {
+        FLAGS
+            .iter()
+            .filter(|(flag, _)| self.flag_effect(flag) != Some(FlagEffect::Unchanged))
+            .fold(0, |mask, (_, bit)| mask | bit)
+    }
+
+    fn pattern(&self) -> &Pattern {
+        match &self.bytes[self.opcode] {
+            Byte::Opcode(pattern) => pattern,
+            _ => unreachable!(),
+        }
+    }
+
+    fn decode(&self, code: [u8; 4]) -> Op {
+        let mut values = Values::default();
+        let mut at = 0;
+        for byte in &self.bytes {
+            match byte {
+                Byte::Opcode(pattern) => {
+                    for name in pattern.fields.iter().filter(|f| **f != 0) {
+                        *values.field(*name) = pattern.field(code[at], *name).unwrap_or(0);
+                    }
+                }
+                Byte::Immediate => values.byte = code[at],
+                Byte::Word => {
+                    values.word = u16::from_le_bytes([code[at], code[at + 1]]);
+                    at += 1;
+                }
+                Byte::Offset => values.offset = code[at] as i8,
+                Byte::Displacement => values.displacement = code[at] as i8,
+            }
+            at += 1;
+        }
+        let operands: Vec<Operand> = self
+            .operands
+            .iter()
+            .map(|token| self.operand(token, &mut values))
+            .collect();
+        build(&self.mnemonic, &operands)
+            .unwrap_or_else(|| panic!("{} can't be written as an Op", self.syntax))
+    }
+
+    fn operand(&self, token: &Token, values: &mut Values) -> Operand {
+        match token {
+            Token::Fixed(operand) => operand.clone(),
+            Token::Register(name) => Operand::Byte(reg_bits(*values.field(*name))),
+            Token::Bit => Operand::Number(*values.field(b'b')),
+            Token::Condition => Operand::Condition(jump_conditional(*values.field(b'c'))),
+            Token::Pair => Operand::Word(reg16_bits(*values.field(b'p'))),
+            Token::PairAf => Operand::Word(reg16_bits_af(*values.field(b'q'))),
+            Token::Restart => Operand::Byte(Location8::Immediate(*values.field(b't') << 3)),
+            Token::Immediate => Operand::Byte(Location8::Immediate(values.byte)),
+            Token::Port => Operand::Port(Location8::Immediate(values.byte)),
+            Token::Word => Operand::Word(Location16::Immediate(values.word)),
+            Token::Indirect if self.wide => {
+                Operand::Word(Location16::ImmediateIndirect(values.word))
+            }
+            Token::Indirect => Operand::Byte(Location8::ImmediateIndirect(values.word)),
+            Token::Offset => Operand::Offset(values.offset),
+            Token::Indexed(reg) => {
+                Operand::Byte(Location8::Indexed(reg.clone(), values.displacement))
+            }
+        }
+    }
+
+    // The values an operand gives, if this instruction can take it
+    fn matches(&self, token: &Token, operand: &Operand, values: &mut Values) -> bool {
+        let (name, value) = match (token, operand) {
+            (Token::Fixed(fixed), _) => return fixed == operand,
+            (Token::Register(name), Operand::Byte(loc @ Location8::Reg(_))) => {
+                match (0..8).find(|bits| reg_bits(*bits) == *loc) {
+                    Some(bits) => (*name, bits),
+                    None => return false,
+                }
+            }
+            (Token::Bit, Operand::Number(b)) => (b'b', *b),
+            (Token::Condition, Operand::Condition(c)) => {
+                match (0..8).find(|bits| jump_conditional(*bits) == *c) {
+                    Some(bits) => (b'c', bits),
+                    None => return false,
+                }
+            }
+            (Token::Pair, Operand::Word(loc)) => {
+                match (0..4).find(|bits| reg16_bits(*bits) == *loc) {
+                    Some(bits) => (b'p', bits),
+                    None => return false,
+                }
+            }
+            (Token::PairAf, Operand::Word(loc)) => {
+                match (0..4).find(|bits| reg16_bits_af(*bits) == *loc) {
+                    Some(bits) => (b'q', bits),
+                    None => return false,
+                }
+            }
+            (Token::Restart, Operand::Byte(Location8::Immediate(p))) if p & !0x38 == 0 => {
+                (b't', p >> 3)
+            }
+            (Token::Immediate, Operand::Byte(Location8::Immediate(n)))
+            | (Token::Port, Operand::Port(Location8::Immediate(n))) => {
+                values.byte = *n;
+                return true;
+            }
+            (Token::Word, Operand::Word(Location16::Immediate(nn))) => {
+                values.word = *nn;
+                return true;
+            }
+            (Token::Indirect, Operand::Word(Location16::ImmediateIndirect(nn))) if self.wide => {
+                values.word = *nn;
+                return true;
+            }
+            (Token::Indirect, Operand::Byte(Location8::ImmediateIndirect(nn))) if !self.wide => {
+                values.word = *nn;
+                return true;
+            }
+            (Token::Offset, Operand::Offset(e)) => {
+                values.offset = *e;
+                return true;
+            }
+            (Token::Indexed(reg), Operand::Byte(Location8::Indexed(r, d))) if reg == r => {
+                values.displacement = *d;
+                return true;
+            }
+            _ => return false,
+        };
+        // The field has to have room for it
+        *values.field(name) = value;
+        self.pattern().set_field(0, name, value).is_some()
+    }
+
+    fn encode(&self, operands: &[Operand]) -> Option<Vec<u8>> {
+        if operands.len() != self.operands.len() {
+            return None;
+        }
+        let mut values = Values::default();
+        for (token, operand) in self.operands.iter().zip(operands) {
+            if !self.matches(token, operand, &mut values) {
+                return None;
+            }
+        }
+        let mut code = Vec::with_capacity(self.length);
+        for byte in &self.bytes {
+            match byte {
+                Byte::Opcode(pattern) => {
+                    let mut opcode = pattern.value;
+                    for name in pattern.fields.iter().filter(|f| **f != 0 && **f != b'x') {
+                        opcode = pattern.set_field(opcode, *name, *values.field(*name))?;
+                    }
+                    code.push(opcode);
+                }
+                Byte::Immediate => code.push(values.byte),
+                Byte::Word => code.extend_from_slice(&values.word.to_le_bytes()),
+                Byte::Offset => code.push(values.offset as u8),
+                Byte::Displacement => code.push(values.displacement as u8),
+            }
+        }
+        Some(code)
+    }
+}
+
+struct Table {
+    instructions: Vec<Instruction>,
+    // The instruction for each opcode on each page
+    index: [[Option<u16>; 256]; PAGES.len()],
+}
+
+fn table() -> &'static Table {
+    static TABLE: OnceLock<Table> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut instructions = vec![];
+        for definition in DEFINITIONS {
+            let syntax = definition.syntax.to_string();
+            let encoding = definition.encoding.to_string();
+            if definition.encoding.starts_with("DD") {
+                let iy = Instruction::new(
+                    definition,
+                    syntax.replace("IX", "IY"),
+                    encoding.replacen("DD", "FD", 1),
+                );
+                instructions.push(Instruction::new(definition, syntax, encoding));
+                instructions.push(iy);
+            } else {
+                instructions.push(Instruction::new(definition, syntax, encoding));
+            }
+        }
+        let mut index = [[None; 256]; PAGES.len()];
+        for (i, instruction) in instructions.iter().enumerate() {
+            let pattern = instruction.pattern();
+            for opcode in 0..=0xFF_u8 {
+                let slot = &mut index[instruction.page][usize::from(opcode)];
+                if slot.is_none() && pattern.matches(opcode) {
+                    *slot = Some(i as u16);
+                }
+            }
+        }
+        Table {
+            instructions,
+            index,
+        }
+    })
+}
+
+// Which page some bytes are on, and where their opcode is
+fn page_of(code: [u8; 4]) -> (usize, usize) {
+    match code {
+        [0xCB, _, _, _] => (1, 1),
+        [0xED, _, _, _] => (2, 1),
+        [0xDD, 0xCB, _, _] => (5, 3),
+        [0xFD, 0xCB, _, _] => (6, 3),
+        [0xDD, _, _, _] => (3, 1),
+        [0xFD, _, _, _] => (4, 1),
+        _ => (0, 0),
+    }
+}
+
+/// Every instruction in the table
+pub fn instructions() -> &'static [Instruction] {
+    &table().instructions
+}
+
+/// The instruction at the start of the given bytes
+pub fn lookup(code: [u8; 4]) -> Option<&'static Instruction> {
+    let table = table();
+    let (page, at) = page_of(code);
+    table.index[page][usize::from(code[at])].map(|i| &table.instructions[usize::from(i)])
+}
+
+/// The instruction an op is encoded as
+pub fn find(op: &Op) -> Option<&'static Instruction> {
+    let (mnemonic, operands) = split(op);
+    instructions()
+        .iter()
+        .find(|i| i.mnemonic == mnemonic && i.encode(&operands).is_some())
+}
+
+/// Decode the instruction at the start of the given bytes, returning it and its length.
+/// Returns the reason if they aren't an instruction in the table.
+pub fn decode(code: [u8; 4]) -> Result<(Op, usize), String> {
+    match lookup(code) {
+        Some(instruction) => Ok((instruction.decode(code), instruction.length)),
+        None => Err(missing(code)),
+    }
+}
+
+// Why some bytes aren't an instruction
+fn missing(code: [u8; 4]) -> String {
+    let (page, at) = page_of(code);
+    let op = code[at];
+    match page {
+        0 => format!(
+            "Unimplemented opcode [{:02x}, {:02x}, {:02x}, {:02x}]",
+            code[0], code[1], code[2], code[3]
+        ),
+        1 | 5 | 6 if op >> 3 == 0b110 => "Use of undocumented instruction SLL".to_string(),
+        1 | 5 | 6 => format!("Unknown bit operation {:02x}", op),
+        2 => format!("Unknown ExtendeD operation {:02x}", op),
+        3 => format!("not implemented: IX {:02x}", op),
+        _ => format!("not implemented: IY {:02x}", op),
+    }
+}
+
+/// Encode an op as bytes. For example:
+/// ```
+/// use zeerust::cpu::opcodes::table;
+/// use zeerust::ops::build::*;
+/// use zeerust::ops::Op;
+///
+/// assert_eq!(Some(vec![0xDD, 0xCB, 0x02, 0xC6]), table::encode(&Op::SET(0, ix_ind(2))));
+/// assert_eq!(None, table::encode(&Op::ld(imm(1), a())));
+///```
+/// Returns None for ops the Z80 has no instruction for.
+pub fn encode(op: &Op) -> Option<Vec<u8>> {
+    let (mnemonic, operands) = split(op);
+    instructions()
+        .iter()
+        .filter(|i| i.mnemonic == mnemonic)
+        .find_map(|i| i.encode(&operands))
+}
+
+/// Write an op as assembly. For example:
+/// ```
+/// use zeerust::cpu::opcodes::table;
+/// use zeerust::ops::build::*;
+/// use zeerust::ops::Op;
+///
+/// assert_eq!("LD (IX-3), $2a", table::disassemble(&Op::ld(ix_ind(-3), imm(42))));
+///```
+pub fn disassemble(op: &Op) -> String {
+    let (mnemonic, operands) = split(op);
+    let operands: Vec<String> = operands.iter().map(|o| o.to_string()).collect();
+    if operands.is_empty() {
+        mnemonic.to_string()
+    } else {
+        format!("{} {}", mnemonic, operands.join(", "))
+    }
+}
+
+// An op as a mnemonic and its operands, as build takes them
+fn split(op: &Op) -> (&'static str, Vec<Operand>) {
+    use Operand::*;
+    let byte = |loc: &Location8| Byte(loc.clone());
+    let word = |loc: &Location16| Word(loc.clone());
+    // The undocumented DDCB instructions name the register they copy to
+    let target = |loc: &Location8| match loc {
+        Location8::IndexedCopy(reg, d, copy) => vec![
+            Byte(Location8::Indexed(reg.clone(), *d)),
+            Byte(Location8::Reg(*copy)),
+        ],
+        _ => vec![byte(loc)],
+    };
+    let conditional = |c: &JumpConditional, mut rest: Vec<Operand>| {
+        if *c != JumpConditional::Unconditional {
+            rest.insert(0, Condition(*c));
+        }
+        rest
+    };
+    match op {
+        Op::ADC(a, b) => ("ADC", vec![byte(a), byte(b)]),
+        Op::ADD8(a, b) => ("ADD", vec![byte(a), byte(b)]),
+        Op::INC(loc) => ("INC", vec![byte(loc)]),
+        Op::SBC(a, b) => ("SBC", vec![byte(a), byte(b)]),
+        Op::SUB8(Location8::Reg(Reg8::A), b) => ("SUB", vec![byte(b)]),
+        Op::SUB8(a, b) => ("SUB", vec![byte(a), byte(b)]),
+        Op::DEC(loc) => ("DEC", vec![byte(loc)]),
+        Op::AND(loc) => ("AND", vec![byte(loc)]),
+        Op::OR(loc) => ("OR", vec![byte(loc)]),
+        Op::XOR(loc) => ("XOR", vec![byte(loc)]),
+        Op::CP(loc) => ("CP", vec![byte(loc)]),
+        Op::CPL => ("CPL", vec![]),
+        Op::NEG => ("NEG", vec![]),
+        Op::CCF => ("CCF", vec![]),
+        Op::SCF => ("SCF", vec![]),
+        Op::NOP => ("NOP", vec![]),
+        Op::HALT => ("HALT", vec![]),
+        Op::DAA => ("DAA", vec![]),
+        Op::RLCA => ("RLCA", vec![]),
+        Op::RLA => ("RLA", vec![]),
+        Op::RRCA => ("RRCA", vec![]),
+        Op::RRA => ("RRA", vec![]),
+        Op::RLC(loc) => ("RLC", target(loc)),
+        Op::RL(loc) => ("RL", target(loc)),
+        Op::RRC(loc) => ("RRC", target(loc)),
+        Op::RR(loc) => ("RR", target(loc)),
+        Op::SLA(loc) => ("SLA", target(loc)),
+        Op::SRL(loc) => ("SRL", target(loc)),
+        Op::SRA(loc) => ("SRA", target(loc)),
+        Op::RLD => ("RLD", vec![]),
+        Op::RRD => ("RRD", vec![]),
+        Op::BIT(b, loc) => ("BIT", [vec![Number(*b)], target(loc)].concat()),
+        Op::SET(b, loc) => ("SET", [vec![Number(*b)], target(loc)].concat()),
+        Op::RES(b, loc) => ("RES", [vec![Number(*b)], target(loc)].concat()),
+        Op::IN(dst, port) => ("IN", vec![byte(dst), Port(port.clone())]),
+        Op::OUT(Location8::Immediate(n), port) => ("OUT", vec![Port(port.clone()), Number(*n)]),
+        Op::OUT(src, port) => ("OUT", vec![Port(port.clone()), byte(src)]),
+        Op::JP(c, loc) => ("JP", conditional(c, vec![word(loc)])),
+        Op::JPI(reg) => ("JP", vec![Byte(Location8::RegIndirect(reg.clone()))]),
+        Op::JR(c, e) => ("JR", conditional(c, vec![Offset(*e)])),
+        Op::DJNZ(e) => ("DJNZ", vec![Offset(*e)]),
+        Op::CALL(c, addr) => (
+            "CALL",
+            conditional(c, vec![Word(Location16::Immediate(*addr))]),
+        ),
+        Op::RET(c) => ("RET", conditional(c, vec![])),
+        Op::RETI => ("RETI", vec![]),
+        Op::RETN => ("RETN", vec![]),
+        Op::RST(p) => ("RST", vec![Byte(Location8::Immediate(*p))]),
+        Op::DI => ("DI", vec![]),
+        Op::EI => ("EI", vec![]),
+        Op::IM(mode) => ("IM", vec![Number(*mode)]),
+        Op::POP(loc) => ("POP", vec![word(loc)]),
+        Op::PUSH(loc) => ("PUSH", vec![word(loc)]),
+        Op::LD8(a, b) => ("LD", vec![byte(a), byte(b)]),
+        Op::LD16(a, b) => ("LD", vec![word(a), word(b)]),
+        Op::EX(a, b) => ("EX", vec![word(a), word(b)]),
+        Op::MLT(reg) => ("MLT", vec![Word(Location16::Reg(reg.clone()))]),
+        Op::TST(loc) => ("TST", vec![byte(loc)]),
+        Op::IN0(dst, n) => ("IN0", vec![byte(dst), Port(Location8::Immediate(*n))]),
+        Op::OUT0(src, n) => ("OUT0", vec![Port(Location8::Immediate(*n)), byte(src)]),
+        Op::SLP => ("SLP", vec![]),
+        Op::MULUB(reg) => ("MULUB", vec![Byte(Location8::Reg(*reg))]),
+        Op::MULUW(reg) => ("MULUW", vec![Word(Location16::Reg(reg.clone()))]),
+    }
+}
+
+// The op a mnemonic and its operands make, if there is one
+fn build(mnemonic: &str, operands: &[Operand]) -> Option<Op> {
+    use Operand::*;
+    let target = |operands: &[Operand]| match operands {
+        [Byte(loc)] => Some(loc.clone()),
+        [Byte(Location8::Indexed(reg, d)), Byte(Location8::Reg(copy))] => {
+            Some(Location8::IndexedCopy(reg.clone(), *d, *copy))
+        }
+        _ => None,
+    };
+    let uncond = JumpConditional::Unconditional;
+    let op = match (mnemonic, operands) {
+        ("ADC", [Byte(a), Byte(b)]) => Op::ADC(a.clone(), b.clone()),
+        ("ADD", [Byte(a), Byte(b)]) => Op::ADD8(a.clone(), b.clone()),
+        ("INC", [Byte(loc)]) => Op::INC(loc.clone()),
+        ("SBC", [Byte(a), Byte(b)]) => Op::SBC(a.clone(), b.clone()),
+        ("SUB", [Byte(b)]) => Op::SUB8(Location8::Reg(Reg8::A), b.clone()),
+        ("DEC", [Byte(loc)]) => Op::DEC(loc.clone()),
+        ("AND", [Byte(loc)]) => Op::AND(loc.clone()),
+        ("OR", [Byte(loc)]) => Op::OR(loc.clone()),
+        ("XOR", [Byte(loc)]) => Op::XOR(loc.clone()),
+        ("CP", [Byte(loc)]) => Op::CP(loc.clone()),
+        ("CPL", []) => Op::CPL,
+        ("NEG", []) => Op::NEG,
+        ("CCF", []) => Op::CCF,
+        ("SCF", []) => Op::SCF,
+        ("NOP", []) => Op::NOP,
+        ("HALT", []) => Op::HALT,
+        ("DAA", []) => Op::DAA,
+        ("RLCA", []) => Op::RLCA,
+        ("RLA", []) => Op::RLA,
+        ("RRCA", []) => Op::RRCA,
+        ("RRA", []) => Op::RRA,
+        ("RLC", rest) => Op::RLC(target(rest)?),
+        ("RL", rest) => Op::RL(target(rest)?),
+        ("RRC", rest) => Op::RRC(target(rest)?),
+        ("RR", rest) => Op::RR(target(rest)?),
+        ("SLA", rest) => Op::SLA(target(rest)?),
+        ("SRL", rest) => Op::SRL(target(rest)?),
+        ("SRA", rest) => Op::SRA(target(rest)?),
+        ("RLD", []) => Op::RLD,
+        ("RRD", []) => Op::RRD,
+        ("BIT", [Number(b), rest @ ..]) => Op::BIT(*b, target(rest)?),
+        ("SET", [Number(b), rest @ ..]) => Op::SET(*b, target(rest)?),
+        ("RES", [Number(b), rest @ ..]) => Op::RES(*b, target(rest)?),
+        ("IN", [Byte(dst), Port(port)]) => Op::IN(dst.clone(), port.clone()),
+        ("OUT", [Port(port), Number(n)]) => Op::OUT(Location8::Immediate(*n), port.clone()),
+        ("OUT", [Port(port), Byte(src)]) => Op::OUT(src.clone(), port.clone()),
+        ("JP", [Byte(Location8::RegIndirect(reg))]) => Op::JPI(reg.clone()),
+        ("JP", [Word(loc)]) => Op::JP(uncond, loc.clone()),
+        ("JP", [Condition(c), Word(loc)]) => Op::JP(*c, loc.clone()),
+        ("JR", [Offset(e)]) => Op::JR(uncond, *e),
+        ("JR", [Condition(c), Offset(e)]) => Op::JR(*c, *e),
+        ("DJNZ", [Offset(e)]) => Op::DJNZ(*e),
+        ("CALL", [Word(Location16::Immediate(addr))]) => Op::CALL(uncond, *addr),
+        ("CALL", [Condition(c), Word(Location16::Immediate(addr))]) => Op::CALL(*c, *addr),
+        ("RET", []) => Op::RET(uncond),
+        ("RET", [Condition(c)]) => Op::RET(*c),
+        ("RETI", []) => Op::RETI,
+        ("RETN", []) => Op::RETN,
+        ("RST", [Byte(Location8::Immediate(p))]) => Op::RST(*p),
+        ("DI", []) => Op::DI,
+        ("EI", []) => Op::EI,
+        ("IM", [Number(mode)]) => Op::IM(*mode),
+        ("POP", [Word(loc)]) => Op::POP(loc.clone()),
+        ("PUSH", [Word(loc)]) => Op::PUSH(loc.clone()),
+        ("LD", [Byte(a), Byte(b)]) => Op::LD8(a.clone(), b.clone()),
+        ("LD", [Word(a), Word(b)]) => Op::LD16(a.clone(), b.clone()),
+        ("EX", [Word(a), Word(b)]) => Op::EX(a.clone(), b.clone()),
+        ("MLT", [Word(Location16::Reg(reg))]) => Op::MLT(reg.clone()),
+        ("TST", [Byte(loc)]) => Op::TST(loc.clone()),
+        ("IN0", [Byte(dst), Port(Location8::Immediate(n))]) => Op::IN0(dst.clone(), *n),
+        ("OUT0", [Port(Location8::Immediate(n)), Byte(src)]) => Op::OUT0(src.clone(), *n),
+        ("SLP", []) => Op::SLP,
+        ("MULUB", [Byte(Location8::Reg(reg))]) => Op::MULUB(*reg),
+        ("MULUW", [Word(Location16::Reg(reg))]) => Op::MULUW(reg.clone()),
+        _ => return None,
+    };
+    Some(op)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::timing::{machine_cycles, total};
+    use crate::ops::build::*;
+
+    #[test]
+    fn round_trip() {
+        for op in [
+            Op::ld(a(), imm(0x3F)),
+            Op::LD16(
+                Location16::Reg(Reg16::HL),
+                Location16::ImmediateIndirect(0x1234),
+            ),
+            Op::LD16(
+                Location16::Reg(Reg16::DE),
+                Location16::ImmediateIndirect(0x1234),
+            ),
+            Op::RES(3, Location8::IndexedCopy(Reg16::IY, -2, Reg8::E)),
+            Op::JR(JumpConditional::Carry, -5),
+            Op::OUT(imm(0), c()),
+            Op::IM(2),
+            Op::RST(0x38),
+        ] {
+            let bytes = encode(&op).unwrap_or_else(|| panic!("{:?} can't be encoded", op));
+            let mut code = [0; 4];
+            code[..bytes.len()].copy_from_slice(&bytes);
+            assert_eq!(Ok((op, bytes.len())), decode(code));
+        }
+        assert_eq!(
+            Some(vec![0x2A, 0x34, 0x12]),
+            encode(&Op::LD16(
+                Location16::Reg(Reg16::HL),
+                Location16::ImmediateIndirect(0x1234)
+            ))
+        );
+        assert_eq!(None, encode(&Op::JR(JumpConditional::ParityOdd, 0)));
+        assert_eq!(None, encode(&Op::SUB8(b(), c())));
+        assert_eq!(None, encode(&Op::LD8(hl_ind(), hl_ind())));
+    }
+
+    #[test]
+    fn metadata() {
+        let ld = lookup([0xDD, 0x2A, 0x00, 0x00]).unwrap();
+        assert_eq!(
+            ("LD IX, (nn)", 4, 20),
+            (ld.syntax.as_str(), ld.length, ld.tstates)
+        );
+        let ld = lookup([0xFD, 0x2A, 0x00, 0x00]).unwrap();
+        assert_eq!(
+            ("LD IY, (nn)", "FD 2A nn"),
+            (ld.syntax.as_str(), ld.encoding.as_str())
+        );
+        let jr = find(&Op::JR(JumpConditional::Zero, 2)).unwrap();
+        assert_eq!((12, 7, "------"), (jr.tstates, jr.not_taken, jr.flags));
+        assert_eq!(None, lookup([0xED, 0x00, 0x00, 0x00]));
+
+        assert_eq!(
+            "JR NZ, -3",
+            disassemble(&Op::JR(JumpConditional::NonZero, -3))
+        );
+        assert_eq!(
+            "RLC (IY+5), B",
+            disassemble(&Op::RLC(Location8::IndexedCopy(Reg16::IY, 5, Reg8::B)))
+        );
+        assert_eq!("OUT ($fe), A", disassemble(&Op::OUT(a(), imm(0xFE))));
+        assert_eq!("IN B, (C)", disassemble(&Op::IN(b(), c())));
+        assert_eq!("RET", disassemble(&Op::RET(JumpConditional::Unconditional)));
+    }
+
+    // The timings the processor uses agree with the table, for the instructions it can tell
+    // apart: undocumented mirrors are timed as the instruction they mirror
+    #[test]
+    fn timings() {
+        for (page, prefix) in PAGES.iter().enumerate() {
+            for opcode in 0..=0xFF_u8 {
+                let mut code = [0; 4];
+                code[..prefix.len()].copy_from_slice(prefix);
+                code[page_of(code).1] = opcode;
+                let instruction = match lookup(code) {
+                    Some(instruction) if page_of(code).0 == page => instruction,
+                    _ => continue,
+                };
+                let (op, _) = decode(code).unwrap();
+                if encode(&op).as_deref() != Some(&code[..instruction.length]) {
+                    continue;
+                }
+                let conditional = matches!(
+                    op,
+                    Op::JR(c, _) | Op::CALL(c, _) | Op::RET(c) if c != JumpConditional::Unconditional
+                ) || matches!(op, Op::DJNZ(_));
+                assert_eq!(
+                    instruction.tstates,
+                    total(&machine_cycles(&op, true)),
+                    "{}",
+                    instruction.syntax
+                );
+                if conditional {
+                    assert_eq!(
+                        instruction.not_taken,
+                        total(&machine_cycles(&op, false)),
+                        "{}",
+                        instruction.syntax
+                    );
+                }
+            }
+        }
+    }
+}
@@ -756,12 +756,70 @@ fn output() {
     assert_opcode!(OUT(Reg(E), Reg(C)), 2, 0xED, 0x59);
     assert_opcode!(OUT(Reg(H), Reg(C)), 2, 0xED, 0x61);
     assert_opcode!(OUT(Reg(L), Reg(C)), 2, 0xED, 0x69);
+    // Undocumented
+    assert_opcode!(OUT(Immediate(0), Reg(C)), 2, 0xED, 0x71);
 }
 
 #[test]
-#[should_panic(expected = "Unknown ExtendeD operation")]
-fn output_hl() {
-    opcode(op4!(0xED, 0x71));
+fn z180() {
+    use crate::cpu::opcodes::z180;
+
+    assert_eq!(Some((MLT(BC), 2)), z180::opcode(op4!(0xED, 0x4C)));
+    assert_eq!(Some((MLT(SP), 2)), z180::opcode(op4!(0xED, 0x7C)));
+    assert_eq!(Some((TST(Reg(B)), 2)), z180::opcode(op4!(0xED, 0x04)));
+    assert_eq!(
+        Some((TST(RegIndirect(HL)), 2)),
+        z180::opcode(op4!(0xED, 0x34))
+    );
+    assert_eq!(Some((TST(Reg(A)), 2)), z180::opcode(op4!(0xED, 0x3C)));
+    assert_eq!(
+        Some((TST(Immediate(0x81)), 3)),
+        z180::opcode(op4!(0xED, 0x64, 0x81))
+    );
+    assert_eq!(
+        Some((IN0(Reg(D), 0x3F), 3)),
+        z180::opcode(op4!(0xED, 0x10, 0x3F))
+    );
+    assert_eq!(
+        Some((OUT0(Reg(A), 0x3F), 3)),
+        z180::opcode(op4!(0xED, 0x39, 0x3F))
+    );
+    assert_eq!(Some((SLP, 2)), z180::opcode(op4!(0xED, 0x76)));
+
+    // IN0 (HL) doesn't exist, and the rest are left to the Z80 decoder
+    assert_eq!(None, z180::opcode(op4!(0xED, 0x31, 0x00)));
+    assert_eq!(None, z180::opcode(op4!(0xED, 0x44)));
+    assert_eq!(None, z180::opcode(op4!(0x76)));
+}
+
+#[test]
+fn r800() {
+    use crate::cpu::opcodes::r800;
+
+    assert_eq!(Some((MULUB(B), 2)), r800::opcode(op4!(0xED, 0xC1)));
+    assert_eq!(Some((MULUB(E), 2)), r800::opcode(op4!(0xED, 0xD9)));
+    assert_eq!(Some((MULUW(BC), 2)), r800::opcode(op4!(0xED, 0xC3)));
+    assert_eq!(Some((MULUW(SP), 2)), r800::opcode(op4!(0xED, 0xF3)));
+    assert_eq!(None, r800::opcode(op4!(0xED, 0xE1)));
+    assert_eq!(None, r800::opcode(op4!(0xED, 0x4C)));
+}
+
+#[test]
+fn i8080() {
+    use crate::cpu::opcodes::i8080;
+
+    assert_eq!(Some((NOP, 1)), i8080::opcode(op4!(0x08)));
+    assert_eq!(Some((NOP, 1)), i8080::opcode(op4!(0x38, 0x12)));
+    assert_eq!(
+        Some((JP(Unconditional, I16(0x1234)), 3)),
+        i8080::opcode(op4!(0xCB, 0x34, 0x12))
+    );
+    assert_eq!(Some((RET(Unconditional), 1)), i8080::opcode(op4!(0xD9)));
+    assert_eq!(
+        Some((CALL(Unconditional, 0x1234), 3)),
+        i8080::opcode(op4!(0xFD, 0x34, 0x12))
+    );
+    assert_eq!(None, i8080::opcode(op4!(0x3E, 0x12)));
 }
 
 #[test]
@@ -792,6 +850,13 @@ fn jr() {
     assert_opcode!(JR(Carry, -127), 2, 0x38, 0x81);
 }
 
+#[test]
+fn jpi() {
+    assert_opcode!(JPI(HL), 1, 0xE9);
+    assert_opcode!(JPI(IX), 2, 0xDD, 0xE9);
+    assert_opcode!(JPI(IY), 2, 0xFD, 0xE9);
+}
+
 #[test]
 fn djnz() {
     assert_opcode!(DJNZ(-10), 2, 0x10, 0xF6);
@@ -819,6 +884,14 @@ fn pop() {
     assert_opcode!(POP(R16(IY)), 2, 0xFD, 0xE1);
 }
 
+#[test]
+fn ex() {
+    use crate::ops::Location16::RegIndirect as RI16;
+    assert_opcode!(EX(RI16(SP), R16(HL)), 1, 0xE3);
+    assert_opcode!(EX(RI16(SP), R16(IX)), 2, 0xDD, 0xE3);
+    assert_opcode!(EX(RI16(SP), R16(IY)), 2, 0xFD, 0xE3);
+}
+
 #[test]
 fn call() {
     assert_opcode!(CALL(Unconditional, 0xD37A), 3, 0xCD, 0x7A, 0xD3);
@@ -847,3 +920,63 @@ fn ret() {
     assert_opcode!(RET(SignPositive), 1, 0xF0);
     assert_opcode!(RET(SignNegative), 1, 0xF8);
 }
+
+#[test]
+fn interrupts() {
+    assert_opcode!(DI, 1, 0xF3);
+    assert_opcode!(EI, 1, 0xFB);
+    assert_opcode!(IM(0), 2, 0xED, 0x46);
+    assert_opcode!(IM(1), 2, 0xED, 0x56);
+    assert_opcode!(IM(2), 2, 0xED, 0x5E);
+    // Undocumented mirrors
+    assert_opcode!(IM(0), 2, 0xED, 0x4E);
+    assert_opcode!(IM(1), 2, 0xED, 0x76);
+    assert_opcode!(IM(2), 2, 0xED, 0x7E);
+
+    assert_opcode!(RETI, 2, 0xED, 0x4D);
+    assert_opcode!(RETN, 2, 0xED, 0x45);
+    assert_opcode!(RETN, 2, 0xED, 0x55);
+}
+
+#[test]
+fn ld_i_r() {
+    assert_opcode!(LD8(Reg(I), Reg(A)), 2, 0xED, 0x47);
+    assert_opcode!(LD8(Reg(R), Reg(A)), 2, 0xED, 0x4F);
+    assert_opcode!(LD8(Reg(A), Reg(I)), 2, 0xED, 0x57);
+    assert_opcode!(LD8(Reg(A), Reg(R)), 2, 0xED, 0x5F);
+}
+
+#[test]
+fn rst() {
+    assert_opcode!(RST(0x00), 1, 0xC7);
+    assert_opcode!(RST(0x08), 1, 0xCF);
+    assert_opcode!(RST(0x10), 1, 0xD7);
+    assert_opcode!(RST(0x18), 1, 0xDF);
+    assert_opcode!(RST(0x20), 1, 0xE7);
+    assert_opcode!(RST(0x28), 1, 0xEF);
+    assert_opcode!(RST(0x30), 1, 0xF7);
+    assert_opcode!(RST(0x38), 1, 0xFF);
+}
+
+#[test]
+fn indexed_bits() {
+    assert_opcode!(BIT(0, Indexed(IX, 0x05)), 4, 0xDD, 0xCB, 0x05, 0x46);
+    assert_opcode!(BIT(7, Indexed(IY, -2)), 4, 0xFD, 0xCB, 0xFE, 0x7E);
+    // Every BIT form reads (IX+d)
+    assert_opcode!(BIT(7, Indexed(IX, 0x10)), 4, 0xDD, 0xCB, 0x10, 0x78);
+    assert_opcode!(SET(3, Indexed(IX, 0x01)), 4, 0xDD, 0xCB, 0x01, 0xDE);
+    assert_opcode!(RES(1, Indexed(IY, 0x7F)), 4, 0xFD, 0xCB, 0x7F, 0x8E);
+    assert_opcode!(RLC(Indexed(IX, -128)), 4, 0xDD, 0xCB, 0x80, 0x06);
+    assert_opcode!(SRL(Indexed(IY, 0x00)), 4, 0xFD, 0xCB, 0x00, 0x3E);
+
+    // Undocumented: the result is copied into a register too
+    assert_opcode!(RLC(IndexedCopy(IX, 0x02, B)), 4, 0xDD, 0xCB, 0x02, 0x00);
+    assert_opcode!(SET(0, IndexedCopy(IY, 0x02, A)), 4, 0xFD, 0xCB, 0x02, 0xC7);
+    assert_opcode!(RES(7, IndexedCopy(IX, -1, L)), 4, 0xDD, 0xCB, 0xFF, 0xBD);
+}
+
+#[test]
+#[should_panic(expected = "Unknown ExtendeD operation")]
+fn unknown_extended() {
+    opcode(op4!(0xED, 0x00));
+}
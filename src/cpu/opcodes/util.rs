@@ -36,12 +36,12 @@ pub fn reg16_bits_af(bits: u8) -> Location16 {
     }
 }
 
-pub fn le_immediate(n0: u8, n1: u8) -> Location16 {
-    Location16::Immediate(u16::from_le_bytes([n0, n1]))
+pub fn le_word(n0: u8, n1: u8) -> u16 {
+    u16::from_le_bytes([n0, n1])
 }
 
-pub fn le_imm_indir(n0: u8, n1: u8) -> Location16 {
-    Location16::ImmediateIndirect(u16::from_le_bytes([n0, n1]))
+pub fn le_immediate(n0: u8, n1: u8) -> Location16 {
+    Location16::Immediate(le_word(n0, n1))
 }
 
 pub fn jump_conditional(c: u8) -> JumpConditional {
@@ -0,0 +1,33 @@
+//! The instructions the Z180 adds to the Z80's.
+//! They all live in holes of the ED table, so are only decoded when asked for, by a processor
+//! emulating a Z180.
+use crate::cpu::opcodes::util::*;
+use crate::ops::{Location16, Location8, Op};
+
+/// Parse a Z180 instruction. Returns None for everything else, including plain Z80 instructions.
+pub fn opcode(code: [u8; 4]) -> Option<(Op, usize)> {
+    let (op, n) = match code {
+        [0xED, op, n, _] => (op, n),
+        _ => return None,
+    };
+    let decoded = match op {
+        0x76 => (Op::SLP, 2),
+        0x64 => (Op::TST(Location8::Immediate(n)), 3),
+        _ if op & 0b1100_1111 == 0b0100_1100 => match reg16_bits(op >> 4) {
+            Location16::Reg(reg) => (Op::MLT(reg), 2),
+            _ => unreachable!(),
+        },
+        _ if op & 0b1100_0111 == 0b0000_0100 => (Op::TST(reg_bits(op >> 3)), 2),
+        // (HL) isn't allowed for IN0 and OUT0
+        _ if op & 0b1100_0110 == 0b0000_0000 && op & 0b0011_1000 != 0b0011_0000 => {
+            let reg = reg_bits(op >> 3);
+            if op & 0b1 == 0b1 {
+                (Op::OUT0(reg, n), 3)
+            } else {
+                (Op::IN0(reg, n), 3)
+            }
+        }
+        _ => return None,
+    };
+    Some(decoded)
+}
@@ -1,4 +1,6 @@
 //!  The internal representation of all the registers of the z80.
+//! The flags are kept as they are on the chip, as the bits of register F, so F can be read,
+//! written, pushed and popped like any other register.
 use crate::ops::{Reg16, Reg8, StatusFlag};
 
 #[derive(Default, Debug, Clone, PartialEq)]
@@ -21,6 +23,9 @@ pub struct Registers {
     hp: u8,
     lp: u8,
 
+    i: u8,
+    r: u8,
+
     pc: u16,
     ix: u16,
     iy: u16,
@@ -33,9 +38,9 @@ impl Registers {
             StatusFlag::Carry => 1,
             StatusFlag::AddSubtract => 1 << 1,
             StatusFlag::ParityOverflow => 1 << 2,
-            // bit 3 is unused
+            StatusFlag::X => 1 << 3,
             StatusFlag::HalfCarry => 1 << 4,
-            // bit 5 is unused
+            StatusFlag::Y => 1 << 5,
             StatusFlag::Zero => 1 << 6,
             StatusFlag::Sign => 1 << 7,
         }
@@ -74,6 +79,9 @@ impl Registers {
             Reg8::FP => self.fp,
             Reg8::HP => self.hp,
             Reg8::LP => self.lp,
+
+            Reg8::I => self.i,
+            Reg8::R => self.r,
         }
     }
 
@@ -97,12 +105,16 @@ impl Registers {
             Reg8::FP => self.fp = v,
             Reg8::HP => self.hp = v,
             Reg8::LP => self.lp = v,
+
+            Reg8::I => self.i = v,
+            Reg8::R => self.r = v,
         }
     }
 
-    /// Set a 16-bit registers. These are made of two 8-bit registers, little-endian.
+    /// Set a 16-bit register. The pairs are made of two 8-bit registers, the first named one
+    /// being the high byte: B is the high byte of BC, A of AF.
     pub fn set_reg16(&mut self, r: &Reg16, v: u16) {
-        let [r0, r1] = v.to_le_bytes();
+        let [r0, r1] = v.to_be_bytes();
         match r {
             Reg16::AF => {
                 self.a = r0;
@@ -142,7 +154,7 @@ impl Registers {
         }
     }
 
-    /// Get a 16-bit register. These are a little-endian combination of two 8-bit registers.
+    /// Get a 16-bit register. The first named register of a pair is the high byte.
     pub fn get_reg16(&self, r: &Reg16) -> u16 {
         let (r0, r1) = match r {
             Reg16::AF => (self.a, self.f),
@@ -157,7 +169,13 @@ impl Registers {
             Reg16::IY => return self.iy,
             Reg16::SP => return self.sp,
         };
-        (u16::from(r1) << 8) + u16::from(r0)
+        u16::from_be_bytes([r0, r1])
+    }
+
+    /// Advance the memory refresh register after the given number of opcode fetches.
+    /// Only the bottom seven bits count; the top bit is left alone.
+    pub fn refresh(&mut self, fetches: u8) {
+        self.r = (self.r & 0x80) | (self.r.wrapping_add(fetches) & 0x7F);
     }
 
     /// Get the current program counter
@@ -214,6 +232,20 @@ mod test {
         assert_eq!("01000101", format!("{:08b}", regs.f))
     }
 
+    #[test]
+    fn undocumented_flags() {
+        let mut regs = Registers::default();
+        regs.set_reg8(Reg8::F, 0b0010_1000);
+        assert!(regs.get_flag(&StatusFlag::X));
+        assert!(regs.get_flag(&StatusFlag::Y));
+        assert!(!regs.get_flag(&StatusFlag::HalfCarry));
+
+        regs.set_flag(&StatusFlag::Y, false);
+        regs.set_flag(&StatusFlag::Sign, true);
+        assert_eq!(0b1000_1000, regs.get_reg8(Reg8::F));
+        assert_eq!(0x0088, regs.get_reg16(&Reg16::AF));
+    }
+
     #[test]
     fn get_set_reg8() {
         let mut regs = Registers::default();
@@ -282,6 +314,28 @@ mod test {
         assert_eq!(0x2322, regs.get_reg16(&Reg16::BCP));
         assert_eq!(0x2524, regs.get_reg16(&Reg16::DEP));
         assert_eq!(0x2827, regs.get_reg16(&Reg16::HLP));
+
+        // The first register of a pair is the high byte
+        assert_eq!(0x06, regs.get_reg8(Reg8::A));
+        assert_eq!(0x01, regs.get_reg8(Reg8::F));
+        assert_eq!(0x03, regs.get_reg8(Reg8::B));
+        assert_eq!(0x02, regs.get_reg8(Reg8::C));
+        assert_eq!(0x28, regs.get_reg8(Reg8::HP));
+        assert_eq!(0x27, regs.get_reg8(Reg8::LP));
+    }
+
+    #[test]
+    fn refresh() {
+        let mut regs = Registers::default();
+        regs.refresh(2);
+        assert_eq!(2, regs.get_reg8(Reg8::R));
+
+        regs.set_reg8(Reg8::R, 0xFF);
+        regs.refresh(1);
+        assert_eq!(0x80, regs.get_reg8(Reg8::R));
+        regs.set_reg8(Reg8::R, 0x7F);
+        regs.refresh(1);
+        assert_eq!(0x00, regs.get_reg8(Reg8::R));
     }
 
     #[test]
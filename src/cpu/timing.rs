@@ -0,0 +1,676 @@
+//! Machine cycle timings for each operation.
+//! Every z80 instruction is made up of a handful of machine cycles (opcode fetches, memory reads
+//! and writes, I/O and internal processing), each some number of T-states long.
+//! This module describes that breakdown, which is needed for anything that cares about _when_
+//! the bus is accessed, not just how long an instruction takes.
+use crate::ops::{Location16, Location8, Op, Reg16, Reg8};
+
+/// The kind of work done during a machine cycle
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CycleKind {
+    /// M1: fetch an opcode (or prefix) byte. Also refreshes memory.
+    OpcodeFetch,
+    /// Read a byte from memory (including operands)
+    MemoryRead,
+    /// Write a byte to memory
+    MemoryWrite,
+    /// Read a byte from a peripheral
+    IoRead,
+    /// Write a byte to a peripheral
+    IoWrite,
+    /// The CPU is busy internally, and the bus is idle
+    Internal,
+    /// Like an opcode fetch, but the interrupting device supplies the data
+    InterruptAcknowledge,
+}
+
+/// A single machine cycle within an instruction
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct MachineCycle {
+    pub kind: CycleKind,
+    /// T-states since the start of the instruction at which this cycle begins
+    pub start: u32,
+    /// Length of this cycle in T-states
+    pub tstates: u32,
+}
+
+/// Total T-states taken by a series of machine cycles
+pub fn total(cycles: &[MachineCycle]) -> u32 {
+    cycles.iter().map(|c| c.tstates).sum()
+}
+
+#[derive(Default)]
+struct Builder {
+    cycles: Vec<MachineCycle>,
+    offset: u32,
+}
+
+impl Builder {
+    fn push(&mut self, kind: CycleKind, tstates: u32) -> &mut Self {
+        self.cycles.push(MachineCycle {
+            kind,
+            start: self.offset,
+            tstates,
+        });
+        self.offset += tstates;
+        self
+    }
+
+    fn fetch(&mut self) -> &mut Self {
+        self.push(CycleKind::OpcodeFetch, 4)
+    }
+
+    fn read(&mut self, tstates: u32) -> &mut Self {
+        self.push(CycleKind::MemoryRead, tstates)
+    }
+
+    fn write(&mut self, tstates: u32) -> &mut Self {
+        self.push(CycleKind::MemoryWrite, tstates)
+    }
+
+    fn internal(&mut self, tstates: u32) -> &mut Self {
+        self.push(CycleKind::Internal, tstates)
+    }
+
+    // The DD or FD prefix, the displacement, and adding it to the index register
+    fn displacement(&mut self) -> &mut Self {
+        self.fetch().read(3).internal(5)
+    }
+
+    // Reading an 8-bit operand from anywhere but a register
+    fn read8(&mut self, loc: &Location8) -> &mut Self {
+        match loc {
+            Location8::Reg(_) => self,
+            Location8::Immediate(_) | Location8::RegIndirect(_) => self.read(3),
+            Location8::Indexed(..) | Location8::IndexedCopy(..) => self.displacement().read(3),
+            Location8::ImmediateIndirect(_) => self.read(3).read(3).read(3),
+        }
+    }
+
+    // Writing an 8-bit result anywhere but a register
+    fn write8(&mut self, loc: &Location8) -> &mut Self {
+        match loc {
+            Location8::Reg(_) | Location8::Immediate(_) => self,
+            Location8::RegIndirect(_) => self.write(3),
+            Location8::Indexed(..) | Location8::IndexedCopy(..) => self.displacement().write(3),
+            Location8::ImmediateIndirect(_) => self.read(3).read(3).write(3),
+        }
+    }
+
+    // Read, modify and write back an 8-bit location
+    fn modify8(&mut self, loc: &Location8) -> &mut Self {
+        match loc {
+            Location8::Reg(_) | Location8::Immediate(_) => self,
+            Location8::Indexed(..) | Location8::IndexedCopy(..) => {
+                self.displacement().read(4).write(3)
+            }
+            _ => self.read(4).write(3),
+        }
+    }
+}
+
+fn is_index(loc: &Location16) -> bool {
+    matches!(loc, Location16::Reg(Reg16::IX) | Location16::Reg(Reg16::IY))
+}
+
+fn is_indexed(loc: &Location8) -> bool {
+    matches!(loc, Location8::Indexed(..) | Location8::IndexedCopy(..))
+}
+
+// The operand of a CB-prefixed instruction
+fn cb_operand(op: &Op) -> Option<&Location8> {
+    match op {
+        Op::RLC(loc)
+        | Op::RL(loc)
+        | Op::RRC(loc)
+        | Op::RR(loc)
+        | Op::SLA(loc)
+        | Op::SRL(loc)
+        | Op::SRA(loc)
+        | Op::BIT(_, loc)
+        | Op::SET(_, loc)
+        | Op::RES(_, loc) => Some(loc),
+        _ => None,
+    }
+}
+
+/// The machine cycles taken by the given operation.
+/// `taken` indicates whether a conditional operation (JR, DJNZ, CALL, RET) took its branch;
+/// it is ignored for everything else.
+pub fn machine_cycles(op: &Op, taken: bool) -> Vec<MachineCycle> {
+    let mut b = Builder::default();
+    b.fetch();
+    if let Some(loc) = cb_operand(op) {
+        b.fetch();
+        if is_indexed(loc) {
+            // The displacement, then the final opcode byte, which is read rather than fetched
+            b.read(3).read(5);
+        }
+    }
+
+    match op {
+        Op::NOP
+        | Op::DI
+        | Op::EI
+        | Op::HALT
+        | Op::DAA
+        | Op::CPL
+        | Op::CCF
+        | Op::SCF
+        | Op::RLCA
+        | Op::RLA
+        | Op::RRCA
+        | Op::RRA => (),
+        Op::NEG | Op::IM(_) => {
+            b.fetch();
+        }
+
+        Op::LD8(Location8::Reg(Reg8::I), _)
+        | Op::LD8(Location8::Reg(Reg8::R), _)
+        | Op::LD8(_, Location8::Reg(Reg8::I))
+        | Op::LD8(_, Location8::Reg(Reg8::R)) => {
+            b.fetch().internal(1);
+        }
+        // The operand is read while the displacement is added, leaving only two T-states over
+        Op::LD8(dst, Location8::Immediate(_)) if is_indexed(dst) => {
+            b.fetch().read(3).read(3).internal(2).write(3);
+        }
+        Op::LD8(dst, src) => {
+            b.read8(src).write8(dst);
+        }
+        Op::ADD8(_, src)
+        | Op::ADC(_, src)
+        | Op::SUB8(_, src)
+        | Op::SBC(_, src)
+        | Op::AND(src)
+        | Op::OR(src)
+        | Op::XOR(src)
+        | Op::CP(src) => {
+            b.read8(src);
+        }
+        Op::INC(loc) | Op::DEC(loc) => {
+            b.modify8(loc);
+        }
+
+        Op::RLC(loc)
+        | Op::RL(loc)
+        | Op::RRC(loc)
+        | Op::RR(loc)
+        | Op::SLA(loc)
+        | Op::SRL(loc)
+        | Op::SRA(loc)
+        | Op::SET(_, loc)
+        | Op::RES(_, loc) => {
+            // The prefix and any displacement were counted above
+            if !matches!(loc, Location8::Reg(_)) {
+                b.read(4).write(3);
+            }
+        }
+        Op::BIT(_, loc) => {
+            if !matches!(loc, Location8::Reg(_)) {
+                b.read(4);
+            }
+        }
+        Op::RLD | Op::RRD => {
+            b.fetch().read(3).internal(4).write(3);
+        }
+
+        Op::IN(_, port) => {
+            match port {
+                Location8::Reg(_) => b.fetch(),
+                _ => b.read(3),
+            }
+            .push(CycleKind::IoRead, 4);
+        }
+        Op::OUT(_, port) => {
+            match port {
+                Location8::Reg(_) => b.fetch(),
+                _ => b.read(3),
+            }
+            .push(CycleKind::IoWrite, 4);
+        }
+
+        Op::LD16(dst, src) => {
+            if is_index(dst) || is_index(src) {
+                b.fetch();
+            }
+            match (dst, src) {
+                (Location16::Reg(Reg16::SP), Location16::Reg(_)) => {
+                    b.internal(2);
+                }
+                (Location16::Reg(reg), Location16::ImmediateIndirect(_))
+                | (Location16::ImmediateIndirect(_), Location16::Reg(reg)) => {
+                    match reg {
+                        Reg16::HL | Reg16::IX | Reg16::IY => (),
+                        // Everything else is ED-prefixed
+                        _ => {
+                            b.fetch();
+                        }
+                    }
+                    b.read(3).read(3);
+                    if let Location16::Reg(_) = dst {
+                        b.read(3).read(3);
+                    } else {
+                        b.write(3).write(3);
+                    }
+                }
+                (_, Location16::Immediate(_)) => {
+                    b.read(3).read(3);
+                }
+                _ => (),
+            }
+        }
+        Op::PUSH(src) => {
+            if is_index(src) {
+                b.fetch();
+            }
+            b.internal(1).write(3).write(3);
+        }
+        Op::EX(x, y) => {
+            if is_index(x) || is_index(y) {
+                b.fetch();
+            }
+            if let Location16::RegIndirect(_) = x {
+                b.read(3).read(4).write(3).write(5);
+            }
+        }
+        Op::POP(dst) => {
+            if is_index(dst) {
+                b.fetch();
+            }
+            b.read(3).read(3);
+        }
+
+        Op::JP(_, loc) => match loc {
+            Location16::Reg(_) => {
+                if is_index(loc) {
+                    b.fetch();
+                }
+            }
+            _ => {
+                b.read(3).read(3);
+            }
+        },
+        Op::JPI(reg) => {
+            if *reg == Reg16::IX || *reg == Reg16::IY {
+                b.fetch();
+            }
+        }
+        Op::JR(_, _) => {
+            b.read(3);
+            if taken {
+                b.internal(5);
+            }
+        }
+        Op::DJNZ(_) => {
+            b.internal(1).read(3);
+            if taken {
+                b.internal(5);
+            }
+        }
+        Op::CALL(_, _) => {
+            b.read(3).read(3);
+            if taken {
+                b.internal(1).write(3).write(3);
+            }
+        }
+        Op::RET(cond) => {
+            if *cond != crate::ops::JumpConditional::Unconditional {
+                b.internal(1);
+            }
+            if taken {
+                b.read(3).read(3);
+            }
+        }
+        Op::RETI | Op::RETN => {
+            b.fetch().read(3).read(3);
+        }
+        Op::RST(_) => {
+            b.internal(1).write(3).write(3);
+        }
+
+        // The Z180 has shorter fetches than the Z80, so these are only as close as anything else
+        Op::MLT(_) => {
+            b.fetch().internal(9);
+        }
+        Op::TST(src) => {
+            b.fetch().read8(src);
+        }
+        Op::IN0(_, _) => {
+            b.fetch().read(3).push(CycleKind::IoRead, 4);
+        }
+        Op::OUT0(_, _) => {
+            b.fetch().read(3).push(CycleKind::IoWrite, 4);
+        }
+        Op::SLP => {
+            b.fetch();
+        }
+
+        // Only the R800 has these; see r800_machine_cycles
+        Op::MULUB(_) => {
+            b.fetch().internal(12);
+        }
+        Op::MULUW(_) => {
+            b.fetch().internal(34);
+        }
+    }
+    b.cycles
+}
+
+/// The machine cycles the R800 takes for the given operation.
+/// The R800 fetches opcodes and reads and writes memory in a single clock, and rarely needs
+/// more than one for internal work, so its cycles are the Z80's shrunk to fit. This is usually
+/// right to within a clock; the waits on page breaks and the I/O waits added by the MSX engine
+/// are not counted.
+pub fn r800_machine_cycles(op: &Op, taken: bool) -> Vec<MachineCycle> {
+    let mut b = Builder::default();
+    for c in machine_cycles(op, taken) {
+        let tstates = match (op, c.kind) {
+            // The multipliers really are slow
+            (Op::MULUB(_), CycleKind::Internal) => 12,
+            (Op::MULUW(_), CycleKind::Internal) => 34,
+            _ => 1,
+        };
+        b.push(c.kind, tstates);
+    }
+    b.cycles
+}
+
+/// The machine cycles the 8080 takes for the given operation.
+/// Its fetches are four or five T-states long, and it does in a longer fetch the register work
+/// the Z80 gives its own cycle. Otherwise its cycles are the Z80's, but for HLT, which idles for
+/// three T-states, and its shorter I/O and read-modify-write cycles.
+pub fn i8080_machine_cycles(op: &Op, taken: bool) -> Vec<MachineCycle> {
+    let mut b = Builder::default();
+    match op {
+        Op::LD8(Location8::Reg(_), Location8::Reg(_))
+        | Op::INC(Location8::Reg(_))
+        | Op::DEC(Location8::Reg(_))
+        | Op::JPI(_)
+        | Op::LD16(Location16::Reg(Reg16::SP), Location16::Reg(_)) => {
+            b.push(CycleKind::OpcodeFetch, 5);
+        }
+        Op::INC(_) | Op::DEC(_) => {
+            b.fetch().read(3).write(3);
+        }
+        Op::HALT => {
+            b.fetch().internal(3);
+        }
+        Op::IN(_, _) => {
+            b.fetch().read(3).push(CycleKind::IoRead, 3);
+        }
+        Op::OUT(_, _) => {
+            b.fetch().read(3).push(CycleKind::IoWrite, 3);
+        }
+        Op::EX(Location16::RegIndirect(_), _) => {
+            b.fetch().read(3).read(3).write(3).write(5);
+        }
+        Op::PUSH(_) | Op::RST(_) => {
+            b.push(CycleKind::OpcodeFetch, 5).write(3).write(3);
+        }
+        Op::CALL(_, _) => {
+            b.push(CycleKind::OpcodeFetch, 5).read(3).read(3);
+            if taken {
+                b.write(3).write(3);
+            }
+        }
+        Op::RET(cond) if *cond != crate::ops::JumpConditional::Unconditional => {
+            b.push(CycleKind::OpcodeFetch, 5);
+            if taken {
+                b.read(3).read(3);
+            }
+        }
+        _ => return machine_cycles(op, taken),
+    }
+    b.cycles
+}
+
+/// The machine cycles taken to accept a non-maskable interrupt
+pub fn nmi_cycles() -> Vec<MachineCycle> {
+    let mut b = Builder::default();
+    b.fetch().internal(1).write(3).write(3);
+    b.cycles
+}
+
+/// The machine cycles taken to accept a maskable interrupt in mode 1 or 2.
+/// In mode 0 the interrupting device supplies an instruction instead; see acknowledge.
+pub fn interrupt_cycles(mode: u8) -> Vec<MachineCycle> {
+    let mut b = Builder::default();
+    b.push(CycleKind::InterruptAcknowledge, 6)
+        .internal(1)
+        .write(3)
+        .write(3);
+    if mode == 2 {
+        b.read(3).read(3);
+    }
+    b.cycles
+}
+
+/// Turn the cycles of an instruction supplied during a mode 0 interrupt into the cycles taken
+/// to accept it: the opcode fetch becomes an acknowledge cycle, which is two T-states longer.
+pub fn acknowledge(cycles: &[MachineCycle]) -> Vec<MachineCycle> {
+    let mut b = Builder::default();
+    for (i, c) in cycles.iter().enumerate() {
+        if i == 0 && c.kind == CycleKind::OpcodeFetch {
+            b.push(CycleKind::InterruptAcknowledge, c.tstates + 2);
+        } else {
+            b.push(c.kind, c.tstates);
+        }
+    }
+    b.cycles
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ops::JumpConditional;
+
+    fn tstates(op: Op, taken: bool) -> u32 {
+        total(&machine_cycles(&op, taken))
+    }
+
+    #[test]
+    fn totals() {
+        let a = Location8::Reg(Reg8::A);
+        let hl = Location8::RegIndirect(Reg16::HL);
+        assert_eq!(4, tstates(Op::NOP, false));
+        assert_eq!(
+            4,
+            tstates(Op::LD8(a.clone(), Location8::Reg(Reg8::B)), false)
+        );
+        assert_eq!(
+            7,
+            tstates(Op::LD8(a.clone(), Location8::Immediate(1)), false)
+        );
+        assert_eq!(
+            10,
+            tstates(Op::LD8(hl.clone(), Location8::Immediate(1)), false)
+        );
+        assert_eq!(
+            13,
+            tstates(Op::LD8(a.clone(), Location8::ImmediateIndirect(1)), false)
+        );
+        assert_eq!(
+            13,
+            tstates(Op::LD8(Location8::ImmediateIndirect(1), a.clone()), false)
+        );
+        assert_eq!(11, tstates(Op::INC(hl.clone()), false));
+        let ix = Location8::Indexed(Reg16::IX, 1);
+        assert_eq!(19, tstates(Op::LD8(a.clone(), ix.clone()), false));
+        assert_eq!(19, tstates(Op::LD8(ix.clone(), a.clone()), false));
+        assert_eq!(
+            19,
+            tstates(Op::LD8(ix.clone(), Location8::Immediate(1)), false)
+        );
+        assert_eq!(19, tstates(Op::ADD8(a.clone(), ix.clone()), false));
+        assert_eq!(23, tstates(Op::INC(ix), false));
+        assert_eq!(8, tstates(Op::RLC(a.clone()), false));
+        assert_eq!(15, tstates(Op::SET(1, hl.clone()), false));
+        assert_eq!(12, tstates(Op::BIT(1, hl), false));
+        assert_eq!(18, tstates(Op::RLD, false));
+        assert_eq!(
+            11,
+            tstates(Op::OUT(a.clone(), Location8::Immediate(0)), false)
+        );
+        assert_eq!(12, tstates(Op::IN(a, Location8::Reg(Reg8::C)), false));
+
+        let imm = Location16::Immediate(0x1234);
+        let ind = Location16::ImmediateIndirect(0x1234);
+        let reg = |r| Location16::Reg(r);
+        assert_eq!(10, tstates(Op::LD16(reg(Reg16::BC), imm.clone()), false));
+        assert_eq!(14, tstates(Op::LD16(reg(Reg16::IX), imm), false));
+        assert_eq!(16, tstates(Op::LD16(reg(Reg16::HL), ind.clone()), false));
+        assert_eq!(20, tstates(Op::LD16(reg(Reg16::DE), ind.clone()), false));
+        assert_eq!(20, tstates(Op::LD16(ind, reg(Reg16::IY)), false));
+        assert_eq!(6, tstates(Op::LD16(reg(Reg16::SP), reg(Reg16::HL)), false));
+        assert_eq!(10, tstates(Op::LD16(reg(Reg16::SP), reg(Reg16::IX)), false));
+        assert_eq!(10, tstates(Op::LD16(reg(Reg16::SP), reg(Reg16::IY)), false));
+        assert_eq!(11, tstates(Op::PUSH(reg(Reg16::AF)), false));
+        assert_eq!(15, tstates(Op::PUSH(reg(Reg16::IX)), false));
+        assert_eq!(10, tstates(Op::POP(reg(Reg16::BC)), false));
+        assert_eq!(14, tstates(Op::POP(reg(Reg16::IY)), false));
+
+        let sp = Location16::RegIndirect(Reg16::SP);
+        assert_eq!(19, tstates(Op::EX(sp.clone(), reg(Reg16::HL)), false));
+        assert_eq!(23, tstates(Op::EX(sp, reg(Reg16::IX)), false));
+
+        assert_eq!(17, tstates(Op::MLT(Reg16::HL), false));
+        assert_eq!(8, tstates(Op::TST(Location8::Reg(Reg8::B)), false));
+        assert_eq!(11, tstates(Op::TST(Location8::Immediate(1)), false));
+        let b = Location8::Reg(Reg8::B);
+        assert_eq!(15, tstates(Op::IN0(b.clone(), 0x10), false));
+        assert_eq!(15, tstates(Op::OUT0(b, 0x10), false));
+    }
+
+    #[test]
+    fn r800() {
+        let r800 = |op, taken| total(&r800_machine_cycles(&op, taken));
+        let a = Location8::Reg(Reg8::A);
+        assert_eq!(1, r800(Op::LD8(a.clone(), Location8::Reg(Reg8::B)), false));
+        assert_eq!(2, r800(Op::LD8(a.clone(), Location8::Immediate(1)), false));
+        assert_eq!(3, r800(Op::IN(a, Location8::Immediate(0)), false));
+        assert_eq!(3, r800(Op::JR(JumpConditional::Zero, 2), true));
+        assert_eq!(2, r800(Op::JR(JumpConditional::Zero, 2), false));
+        let bc = Location16::Reg(Reg16::BC);
+        assert_eq!(4, r800(Op::PUSH(bc.clone()), false));
+        assert_eq!(3, r800(Op::POP(bc), false));
+        assert_eq!(14, r800(Op::MULUB(Reg8::B), false));
+        assert_eq!(36, r800(Op::MULUW(Reg16::BC), false));
+    }
+
+    #[test]
+    fn i8080() {
+        let i8080 = |op, taken| total(&i8080_machine_cycles(&op, taken));
+        let a = Location8::Reg(Reg8::A);
+        let m = Location8::RegIndirect(Reg16::HL);
+        assert_eq!(5, i8080(Op::LD8(a.clone(), Location8::Reg(Reg8::B)), false));
+        assert_eq!(7, i8080(Op::LD8(a.clone(), m.clone()), false));
+        assert_eq!(7, i8080(Op::LD8(a.clone(), Location8::Immediate(1)), false));
+        assert_eq!(5, i8080(Op::INC(a.clone()), false));
+        assert_eq!(10, i8080(Op::DEC(m), false));
+        assert_eq!(
+            4,
+            i8080(Op::ADD8(a.clone(), Location8::Reg(Reg8::C)), false)
+        );
+        assert_eq!(7, i8080(Op::HALT, false));
+        assert_eq!(10, i8080(Op::IN(a.clone(), Location8::Immediate(0)), false));
+        assert_eq!(10, i8080(Op::OUT(Location8::Immediate(0), a), false));
+
+        let hl = Location16::Reg(Reg16::HL);
+        let sp = Location16::RegIndirect(Reg16::SP);
+        assert_eq!(5, i8080(Op::JPI(Reg16::HL), true));
+        assert_eq!(
+            5,
+            i8080(Op::LD16(Location16::Reg(Reg16::SP), hl.clone()), false)
+        );
+        assert_eq!(18, i8080(Op::EX(sp, hl.clone()), false));
+        assert_eq!(11, i8080(Op::PUSH(hl.clone()), false));
+        assert_eq!(10, i8080(Op::POP(hl), false));
+        assert_eq!(11, i8080(Op::RST(0x38), true));
+
+        let cond = JumpConditional::Zero;
+        assert_eq!(10, i8080(Op::JP(cond, Location16::Immediate(0)), false));
+        assert_eq!(17, i8080(Op::CALL(cond, 0x1234), true));
+        assert_eq!(11, i8080(Op::CALL(cond, 0x1234), false));
+        assert_eq!(11, i8080(Op::RET(cond), true));
+        assert_eq!(5, i8080(Op::RET(cond), false));
+        assert_eq!(10, i8080(Op::RET(JumpConditional::Unconditional), true));
+    }
+
+    #[test]
+    fn branches() {
+        let cond = JumpConditional::Zero;
+        assert_eq!(12, tstates(Op::JR(cond, 2), true));
+        assert_eq!(7, tstates(Op::JR(cond, 2), false));
+        assert_eq!(13, tstates(Op::DJNZ(2), true));
+        assert_eq!(8, tstates(Op::DJNZ(2), false));
+        assert_eq!(17, tstates(Op::CALL(cond, 0x1234), true));
+        assert_eq!(10, tstates(Op::CALL(cond, 0x1234), false));
+        assert_eq!(11, tstates(Op::RET(cond), true));
+        assert_eq!(5, tstates(Op::RET(cond), false));
+        assert_eq!(10, tstates(Op::RET(JumpConditional::Unconditional), true));
+        assert_eq!(10, tstates(Op::JP(cond, Location16::Immediate(0)), false));
+        assert_eq!(4, tstates(Op::JPI(Reg16::HL), true));
+        assert_eq!(8, tstates(Op::JPI(Reg16::IY), true));
+        assert_eq!(11, tstates(Op::RST(0x38), true));
+
+        let ix = Location8::Indexed(Reg16::IX, -3);
+        assert_eq!(20, tstates(Op::BIT(2, ix.clone()), false));
+        assert_eq!(23, tstates(Op::SET(2, ix), false));
+        let copy = Location8::IndexedCopy(Reg16::IY, 5, Reg8::B);
+        assert_eq!(23, tstates(Op::RLC(copy), false));
+        assert_eq!(14, tstates(Op::RETI, true));
+    }
+
+    #[test]
+    fn interrupts() {
+        assert_eq!(4, tstates(Op::DI, false));
+        assert_eq!(8, tstates(Op::IM(2), false));
+        let ld_a_i = Op::LD8(Location8::Reg(Reg8::A), Location8::Reg(Reg8::I));
+        assert_eq!(9, tstates(ld_a_i, false));
+
+        assert_eq!(11, total(&nmi_cycles()));
+        assert_eq!(13, total(&interrupt_cycles(1)));
+        assert_eq!(19, total(&interrupt_cycles(2)));
+        let rst = acknowledge(&machine_cycles(&Op::RST(0x38), true));
+        assert_eq!(13, total(&rst));
+        assert_eq!(CycleKind::InterruptAcknowledge, rst[0].kind);
+        assert_eq!(6, rst[1].start);
+    }
+
+    #[test]
+    fn breakdown() {
+        let op = Op::LD8(
+            Location8::RegIndirect(Reg16::HL),
+            Location8::Immediate(0x55),
+        );
+        assert_eq!(
+            vec![
+                MachineCycle {
+                    kind: CycleKind::OpcodeFetch,
+                    start: 0,
+                    tstates: 4
+                },
+                MachineCycle {
+                    kind: CycleKind::MemoryRead,
+                    start: 4,
+                    tstates: 3
+                },
+                MachineCycle {
+                    kind: CycleKind::MemoryWrite,
+                    start: 7,
+                    tstates: 3
+                },
+            ],
+            machine_cycles(&op, false)
+        );
+
+        let cycles = machine_cycles(
+            &Op::OUT(Location8::Reg(Reg8::A), Location8::Immediate(0)),
+            false,
+        );
+        let io = cycles.last().unwrap();
+        assert_eq!(CycleKind::IoWrite, io.kind);
+        assert_eq!(7, io.start);
+    }
+}
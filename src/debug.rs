@@ -0,0 +1,681 @@
+//! A debugger that front ends can drive with JSON messages.
+//! The Debugger holds the breakpoints, and runs a processor until it reaches one. Its protocol
+//! takes one JSON request and gives back one JSON response, so it can be carried by anything:
+//! `serve` runs it over a WebSocket, for debugger UIs in a browser, and a WASM build can call
+//! `handle` directly.
+//!
+//! Every request is an object with a `command`, and an `id` that is copied into the response.
+//! A response has `success`, and then either a `body` or an error `message`:
+//!
+//! * `registers`: every register, the interrupt state and the T-states elapsed
+//! * `set_register`: `name` (such as `"hl"` or `"a'"`) and `value`
+//! * `read_memory`: `address` and `length`, giving the `data` as an array of bytes. The data
+//!   stops short at the end of memory.
+//! * `write_memory`: `address` and `data`
+//! * `step`: run `count` instructions (1 if not given)
+//! * `continue`: run until a breakpoint or a HALT, or for at most `limit` instructions
+//! * `set_breakpoint`, `clear_breakpoint`: at an `address`
+//! * `breakpoints`: every breakpoint set
+//! * `add_watch`: an `expression` to work out again whenever the processor stops, such as
+//!   `"(hl)"` or `"bc+de"`, giving its `index` and `value`
+//! * `remove_watch`: by `index`, which moves the later watches down one
+//! * `watches`: every watch's `expression`, `value` (null if it can't be worked out) and
+//!   whether it `changed` the last time the processor ran
+//! * `instruction`: the instruction at an `address` (the PC if not given): its `text`, its
+//!   `length` and what it does to each of the `flags` (`s`, `z`, `h`, `pv`, `n` and `c`), one
+//!   of `"unchanged"`, `"reset"`, `"set"` or `"affected"`
+//! * `backtrace`: the `frames` on the stack, innermost first, each with its `pc`, its `name`
+//!   from the symbols and its `arguments` (null if they aren't known), found with the
+//!   Debugger's calling convention (see the backtrace module)
+//!
+//! An `address` can also be given as a string holding an expression, such as
+//! `"print_char+3"` or `"hl+2"`, using the Debugger's symbols (see the expression module).
+//! `step` and `continue` reply with the `reason` they stopped and the `pc` they stopped at, and
+//! the `watches` if there are any.
+//! ```
+//! use zeerust::debug::Debugger;
+//! use zeerust::z80::Z80;
+//!
+//! let mut z80 = Z80::default();
+//! z80.load(&[0x3E, 0x2A, 0x76]); // LD A, 42; HALT
+//! let mut debugger = Debugger::default();
+//! let response = debugger.handle(&mut z80, r#"{"id": 1, "command": "continue"}"#);
+//! assert_eq!(
+//!     r#"{"id":1,"success":true,"body":{"reason":"halted","pc":3}}"#,
+//!     response
+//! );
+//!```
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+
+use crate::cpu::opcodes::table::{self, FlagEffect};
+use crate::ops::{Reg16, Reg8};
+use crate::z80::Z80;
+use backtrace::{Convention, Frame};
+use expression::ExpressionError;
+use json::Value;
+use symbols::Symbols;
+use websocket::WebSocket;
+
+pub mod annotations;
+pub mod backtrace;
+pub mod dap;
+pub mod expression;
+pub mod json;
+pub mod listing;
+pub mod repl;
+pub mod source;
+pub mod symbols;
+pub mod websocket;
+
+/// How many instructions `continue` runs when no limit is given
+pub const CONTINUE_LIMIT: u64 = 10_000_000;
+
+// The names registers go by in the protocol
+const REG8: [(&str, Reg8); 18] = [
+    ("a", Reg8::A),
+    ("f", Reg8::F),
+    ("b", Reg8::B),
+    ("c", Reg8::C),
+    ("d", Reg8::D),
+    ("e", Reg8::E),
+    ("h", Reg8::H),
+    ("l", Reg8::L),
+    ("a'", Reg8::AP),
+    ("f'", Reg8::FP),
+    ("b'", Reg8::BP),
+    ("c'", Reg8::CP),
+    ("d'", Reg8::DP),
+    ("e'", Reg8::EP),
+    ("h'", Reg8::HP),
+    ("l'", Reg8::LP),
+    ("i", Reg8::I),
+    ("r", Reg8::R),
+];
+const REG16: [(&str, Reg16); 11] = [
+    ("af", Reg16::AF),
+    ("bc", Reg16::BC),
+    ("de", Reg16::DE),
+    ("hl", Reg16::HL),
+    ("af'", Reg16::AFP),
+    ("bc'", Reg16::BCP),
+    ("de'", Reg16::DEP),
+    ("hl'", Reg16::HLP),
+    ("ix", Reg16::IX),
+    ("iy", Reg16::IY),
+    ("sp", Reg16::SP),
+];
+
+/// Why the processor stopped running
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Stop {
+    /// It ran the number of instructions it was asked to
+    Step,
+    /// It reached a breakpoint
+    Breakpoint,
+    /// It halted
+    Halted,
+    /// It ran for as long as it was allowed to
+    Limit,
+}
+
+impl Stop {
+    fn name(self) -> &'static str {
+        match self {
+            Stop::Step => "step",
+            Stop::Breakpoint => "breakpoint",
+            Stop::Halted => "halted",
+            Stop::Limit => "limit",
+        }
+    }
+}
+
+/// An expression worked out again whenever the processor stops
+#[derive(Debug, PartialEq, Clone)]
+pub struct Watch {
+    pub expression: String,
+    /// None if it couldn't be worked out, say because it reads past the end of memory
+    pub value: Option<u16>,
+    /// Whether the value changed the last time the processor ran
+    pub changed: bool,
+}
+
+/// Breakpoints, and the commands that run up to them
+#[derive(Debug, Default)]
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    watches: Vec<Watch>,
+    /// The labels that addresses can be given as
+    pub symbols: Symbols,
+    /// How the program's functions keep their frames, for backtraces
+    pub convention: Convention,
+    /// The size in bytes of each argument of a function, by the function's label, for
+    /// backtraces to show what it was called with
+    pub functions: BTreeMap<String, Vec<u8>>,
+}
+
+impl Debugger {
+    /// Stop before executing the instruction at an address. Returns false if there was
+    /// already a breakpoint there.
+    pub fn set_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.insert(addr)
+    }
+
+    /// Returns false if there was no breakpoint at the address
+    pub fn clear_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    /// Every breakpoint, in address order
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Watch an expression, returning its value now
+    pub fn add_watch(&mut self, z80: &Z80, expression: &str) -> Result<u16, ExpressionError> {
+        let value = expression::evaluate(expression, z80, &self.symbols)?;
+        self.watches.push(Watch {
+            expression: expression.trim().to_string(),
+            value: Some(value),
+            changed: false,
+        });
+        Ok(value)
+    }
+
+    /// Returns false if there's no watch with that index
+    pub fn remove_watch(&mut self, index: usize) -> bool {
+        if index < self.watches.len() {
+            self.watches.remove(index);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Every watch, in the order they were added
+    pub fn watches(&self) -> &[Watch] {
+        &self.watches
+    }
+
+    // Work out every watch again, flagging the ones that changed
+    fn update_watches(&mut self, z80: &Z80) {
+        for watch in &mut self.watches {
+            let value = expression::evaluate(&watch.expression, z80, &self.symbols).ok();
+            watch.changed = value != watch.value;
+            watch.value = value;
+        }
+    }
+
+    /// The frames on the stack, innermost first, with the arguments of the ones in functions
+    /// whose argument sizes are known
+    pub fn backtrace(&self, z80: &Z80) -> Vec<(Frame, Option<Vec<u32>>)> {
+        backtrace::backtrace(z80, self.convention)
+            .into_iter()
+            .map(|frame| {
+                let sizes = self
+                    .symbols
+                    .name_at(frame.pc)
+                    .and_then(|(name, _)| self.functions.get(name));
+                let arguments =
+                    sizes.and_then(|sizes| frame.arguments(z80, self.convention, sizes));
+                (frame, arguments)
+            })
+            .collect()
+    }
+
+    /// Execute `count` instructions, stopping early at a breakpoint or a HALT
+    pub fn step(&mut self, z80: &mut Z80, count: u64) -> Stop {
+        match self.run_until(z80, count, |_| false) {
+            Stop::Limit => Stop::Step,
+            stop => stop,
+        }
+    }
+
+    /// Run until a breakpoint or a HALT, for at most `limit` instructions. At least one
+    /// instruction is always run, so continuing from a breakpoint leaves it.
+    pub fn resume(&mut self, z80: &mut Z80, limit: u64) -> Stop {
+        self.run_until(z80, limit, |_| false)
+    }
+
+    /// Like resume, but also stopping (with Stop::Step) once `done` returns true. It's asked
+    /// after every instruction. The watches are worked out again when it stops.
+    pub fn run_until<F: FnMut(&Z80) -> bool>(
+        &mut self,
+        z80: &mut Z80,
+        limit: u64,
+        done: F,
+    ) -> Stop {
+        let stop = self.run(z80, limit, done);
+        self.update_watches(z80);
+        stop
+    }
+
+    fn run<F: FnMut(&Z80) -> bool>(&mut self, z80: &mut Z80, limit: u64, mut done: F) -> Stop {
+        for _ in 0..limit {
+            z80.step();
+            if z80.is_halted() {
+                return Stop::Halted;
+            }
+            if self.breakpoints.contains(&z80.registers.get_pc()) {
+                return Stop::Breakpoint;
+            }
+            if done(z80) {
+                return Stop::Step;
+            }
+        }
+        Stop::Limit
+    }
+
+    /// Handle a request in the JSON protocol, returning the response
+    pub fn handle(&mut self, z80: &mut Z80, request: &str) -> String {
+        let (id, result) = match json::parse(request) {
+            Ok(request) => {
+                let id = request.get("id").cloned().unwrap_or(Value::Null);
+                (id, self.dispatch(z80, &request))
+            }
+            Err(e) => (Value::Null, Err(e.to_string())),
+        };
+        let response = match result {
+            Ok(body) => Value::object(vec![("id", id), ("success", true.into()), ("body", body)]),
+            Err(message) => Value::object(vec![
+                ("id", id),
+                ("success", false.into()),
+                ("message", message.into()),
+            ]),
+        };
+        response.to_string()
+    }
+
+    fn dispatch(&mut self, z80: &mut Z80, request: &Value) -> Result<Value, String> {
+        let command = request
+            .get("command")
+            .and_then(Value::as_str)
+            .ok_or("missing command")?;
+        match command {
+            "registers" => Ok(registers(z80)),
+            "set_register" => {
+                let name = request
+                    .get("name")
+                    .and_then(Value::as_str)
+                    .ok_or("missing name")?;
+                let value = number(request, "value", 0xFFFF)?;
+                set_register(z80, name, value as u16)?;
+                Ok(Value::object(vec![]))
+            }
+            "read_memory" => {
+                let addr = self.address(z80, request)?;
+                let length = number(request, "length", 0x10000)?;
+                let data: Vec<u8> = (0..length)
+                    .map_while(|i| z80.peek(addr.wrapping_add(i as u16)))
+                    .collect();
+                Ok(Value::object(vec![
+                    ("address", addr.into()),
+                    ("data", data.into()),
+                ]))
+            }
+            "write_memory" => {
+                let addr = self.address(z80, request)?;
+                let data = request
+                    .get("data")
+                    .and_then(Value::as_array)
+                    .ok_or("missing data")?;
+                for (i, byte) in data.iter().enumerate() {
+                    let byte = byte
+                        .as_u64()
+                        .and_then(|b| u8::try_from(b).ok())
+                        .ok_or("data must be bytes")?;
+                    if !z80.poke(addr.wrapping_add(i as u16), byte) {
+                        return Err("address out of range".to_string());
+                    }
+                }
+                Ok(Value::object(vec![]))
+            }
+            "step" => {
+                let count = match request.get("count") {
+                    Some(_) => number(request, "count", u64::MAX)?,
+                    None => 1,
+                };
+                let stop = self.step(z80, count);
+                Ok(self.stopped(z80, stop))
+            }
+            "continue" => {
+                let limit = match request.get("limit") {
+                    Some(_) => number(request, "limit", u64::MAX)?,
+                    None => CONTINUE_LIMIT,
+                };
+                let stop = self.resume(z80, limit);
+                Ok(self.stopped(z80, stop))
+            }
+            "set_breakpoint" => {
+                let addr = self.address(z80, request)?;
+                self.set_breakpoint(addr);
+                Ok(Value::object(vec![]))
+            }
+            "clear_breakpoint" => {
+                let addr = self.address(z80, request)?;
+                self.clear_breakpoint(addr);
+                Ok(Value::object(vec![]))
+            }
+            "breakpoints" => Ok(Value::object(vec![(
+                "breakpoints",
+                self.breakpoints().collect::<Vec<_>>().into(),
+            )])),
+            "add_watch" => {
+                let expression = request
+                    .get("expression")
+                    .and_then(Value::as_str)
+                    .ok_or("missing expression")?;
+                let value = self.add_watch(z80, expression).map_err(|e| e.to_string())?;
+                Ok(Value::object(vec![
+                    ("index", (self.watches.len() - 1).into()),
+                    ("value", value.into()),
+                ]))
+            }
+            "remove_watch" => {
+                let index = number(request, "index", u64::MAX)?;
+                if !self.remove_watch(index as usize) {
+                    return Err("no such watch".to_string());
+                }
+                Ok(Value::object(vec![]))
+            }
+            "watches" => Ok(Value::object(vec![("watches", self.watches_value())])),
+            "instruction" => {
+                let addr = match request.get("address") {
+                    Some(_) => self.address(z80, request)?,
+                    None => z80.registers.get_pc(),
+                };
+                instruction(z80, addr)
+            }
+            "backtrace" => {
+                let frames = self.backtrace(z80).into_iter().map(|(frame, arguments)| {
+                    Value::object(vec![
+                        ("pc", frame.pc.into()),
+                        ("name", self.symbols.describe(frame.pc).into()),
+                        ("arguments", arguments.map_or(Value::Null, Value::from)),
+                    ])
+                });
+                Ok(Value::object(vec![(
+                    "frames",
+                    Value::Array(frames.collect()),
+                )]))
+            }
+            _ => Err(format!("unknown command {}", command)),
+        }
+    }
+
+    fn stopped(&self, z80: &Z80, stop: Stop) -> Value {
+        let mut fields = vec![
+            ("reason", stop.name().into()),
+            ("pc", z80.registers.get_pc().into()),
+        ];
+        if !self.watches.is_empty() {
+            fields.push(("watches", self.watches_value()));
+        }
+        Value::object(fields)
+    }
+
+    fn watches_value(&self) -> Value {
+        let watches = self.watches.iter().map(|w| {
+            Value::object(vec![
+                ("expression", w.expression.as_str().into()),
+                ("value", w.value.map_or(Value::Null, Value::from)),
+                ("changed", w.changed.into()),
+            ])
+        });
+        Value::Array(watches.collect())
+    }
+
+    // The address in a request, as a number or an expression
+    fn address(&self, z80: &Z80, request: &Value) -> Result<u16, String> {
+        match request.get("address").and_then(Value::as_str) {
+            Some(text) => expression::evaluate(text, z80, &self.symbols).map_err(|e| e.to_string()),
+            None => Ok(number(request, "address", 0xFFFF)? as u16),
+        }
+    }
+
+    /// Answer requests from a WebSocket client, until it closes the connection
+    pub fn serve<S: Read + Write>(
+        &mut self,
+        z80: &mut Z80,
+        ws: &mut WebSocket<S>,
+    ) -> io::Result<()> {
+        while let Some(request) = ws.receive()? {
+            let response = self.handle(z80, &request);
+            ws.send(&response)?;
+        }
+        Ok(())
+    }
+
+    /// Wait for a WebSocket client to connect on the given address, and serve it
+    pub fn listen<A: ToSocketAddrs>(&mut self, z80: &mut Z80, addr: A) -> io::Result<()> {
+        let (stream, _) = TcpListener::bind(addr)?.accept()?;
+        let mut ws = WebSocket::accept(stream)?;
+        self.serve(z80, &mut ws)
+    }
+}
+
+// A whole number in a request, no larger than max
+fn number(request: &Value, key: &str, max: u64) -> Result<u64, String> {
+    request
+        .get(key)
+        .and_then(Value::as_u64)
+        .filter(|n| *n <= max)
+        .ok_or_else(|| format!("missing or invalid {}", key))
+}
+
+// Set a register by its name in the protocol
+fn set_register(z80: &mut Z80, name: &str, value: u16) -> Result<(), String> {
+    if let Some((_, reg)) = REG8.iter().find(|(n, _)| *n == name) {
+        let value = u8::try_from(value).map_err(|_| "value out of range")?;
+        z80.registers.set_reg8(*reg, value);
+    } else if let Some((_, reg)) = REG16.iter().find(|(n, _)| *n == name) {
+        z80.registers.set_reg16(reg, value);
+    } else if name == "pc" {
+        z80.registers.set_pc(value);
+    } else {
+        return Err(format!("unknown register {}", name));
+    }
+    Ok(())
+}
+
+// The instruction at an address, and what it does to the flags
+fn instruction(z80: &Z80, addr: u16) -> Result<Value, String> {
+    let code = [0, 1, 2, 3].map(|i| z80.peek(addr.wrapping_add(i)).unwrap_or(0));
+    let (op, length) = table::decode(code)?;
+    let instruction = table::lookup(code).ok_or("no instruction")?;
+    let names = ["s", "z", "h", "pv", "n", "c"];
+    let flags = names
+        .iter()
+        .zip(table::FLAGS.iter())
+        .map(|(name, (flag, _))| {
+            let effect = match instruction.flag_effect(flag) {
+                Some(FlagEffect::Unchanged) | None => "unchanged",
+                Some(FlagEffect::Reset) => "reset",
+                Some(FlagEffect::Set) => "set",
+                Some(FlagEffect::Affected) => "affected",
+            };
+            (*name, effect.into())
+        });
+    Ok(Value::object(vec![
+        ("address", addr.into()),
+        ("text", table::disassemble(&op).into()),
+        ("length", length.into()),
+        ("flags", Value::object(flags.collect())),
+    ]))
+}
+
+// Every register, and the interrupt state
+fn registers(z80: &Z80) -> Value {
+    let regs = &z80.registers;
+    let mut fields: Vec<(&str, Value)> = REG8
+        .iter()
+        .map(|(name, reg)| (*name, regs.get_reg8(*reg).into()))
+        .collect();
+    for (name, reg) in &REG16[8..] {
+        fields.push((name, regs.get_reg16(reg).into()));
+    }
+    fields.extend(vec![
+        ("pc", regs.get_pc().into()),
+        ("iff1", z80.iff1().into()),
+        ("iff2", z80.iff2().into()),
+        ("im", z80.interrupt_mode().into()),
+        ("halted", z80.is_halted().into()),
+        ("tstates", z80.tstates().into()),
+    ]);
+    Value::object(fields)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // LD B, 3; loop: DEC B; JR NZ, loop; HALT
+    const COUNTDOWN: &[u8] = &[0x06, 0x03, 0x05, 0x20, 0xFD, 0x76];
+
+    fn body(response: &str) -> Value {
+        let response = json::parse(response).unwrap();
+        assert_eq!(Some(true), response.get("success").and_then(Value::as_bool));
+        response.get("body").unwrap().clone()
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut z80 = Z80::default();
+        z80.load(COUNTDOWN);
+        let mut debugger = Debugger::default();
+        debugger.symbols.insert("loop", 2);
+        let mut request = |text: &str| body(&debugger.handle(&mut z80, text));
+        request(r#"{"command": "set_breakpoint", "address": "loop"}"#);
+        let stop = request(r#"{"command": "continue"}"#);
+        assert_eq!(r#"{"reason":"breakpoint","pc":2}"#, stop.to_string());
+        let stop = request(r#"{"command": "continue"}"#);
+        assert_eq!(r#"{"reason":"breakpoint","pc":2}"#, stop.to_string());
+        let b = request(r#"{"command": "registers"}"#).get("b").cloned();
+        assert_eq!(Some(Value::from(2u8)), b);
+
+        let stop = request(r#"{"command": "step", "count": 2}"#);
+        assert_eq!(r#"{"reason":"breakpoint","pc":2}"#, stop.to_string());
+        request(r#"{"command": "clear_breakpoint", "address": 2}"#);
+        let list = request(r#"{"command": "breakpoints"}"#);
+        assert_eq!(r#"{"breakpoints":[]}"#, list.to_string());
+        let stop = request(r#"{"command": "continue", "limit": 1}"#);
+        assert_eq!(r#"{"reason":"limit","pc":3}"#, stop.to_string());
+        let stop = request(r#"{"command": "continue"}"#);
+        assert_eq!(r#"{"reason":"halted","pc":6}"#, stop.to_string());
+    }
+
+    #[test]
+    fn watches() {
+        let mut z80 = Z80::default();
+        z80.load(COUNTDOWN);
+        let mut debugger = Debugger::default();
+        let mut request = |text: &str| body(&debugger.handle(&mut z80, text));
+
+        let added = request(r#"{"command": "add_watch", "expression": "b + 1"}"#);
+        assert_eq!(r#"{"index":0,"value":1}"#, added.to_string());
+        request(r#"{"command": "add_watch", "expression": "(hl)"}"#);
+        let stop = request(r#"{"command": "step"}"#);
+        assert_eq!(
+            r#"{"reason":"step","pc":2,"watches":[{"expression":"b + 1","value":4,"changed":true},{"expression":"(hl)","value":6,"changed":false}]}"#,
+            stop.to_string()
+        );
+        request(r#"{"command": "remove_watch", "index": 1}"#);
+        request(r#"{"command": "step"}"#);
+        let watches = request(r#"{"command": "watches"}"#);
+        assert_eq!(
+            r#"{"watches":[{"expression":"b + 1","value":3,"changed":true}]}"#,
+            watches.to_string()
+        );
+        let stop = request(r#"{"command": "step"}"#);
+        assert_eq!(
+            r#"{"reason":"step","pc":2,"watches":[{"expression":"b + 1","value":3,"changed":false}]}"#,
+            stop.to_string()
+        );
+    }
+
+    #[test]
+    fn memory_and_registers() {
+        let mut z80 = Z80::default();
+        let mut debugger = Debugger::default();
+        let mut request = |text: &str| body(&debugger.handle(&mut z80, text));
+
+        request(r#"{"command": "write_memory", "address": 16382, "data": [1, 2]}"#);
+        let read = request(r#"{"command": "read_memory", "address": 16381, "length": 8}"#);
+        assert_eq!(r#"{"address":16381,"data":[0,1,2]}"#, read.to_string());
+
+        request(r#"{"command": "set_register", "name": "hl'", "value": 4660}"#);
+        request(r#"{"command": "set_register", "name": "pc", "value": 256}"#);
+        let regs = request(r#"{"command": "registers"}"#);
+        assert_eq!(Some(0x12), regs.get("h'").and_then(Value::as_u64));
+        assert_eq!(Some(0x100), regs.get("pc").and_then(Value::as_u64));
+        assert_eq!(Some(false), regs.get("halted").and_then(Value::as_bool));
+    }
+
+    #[test]
+    fn instructions() {
+        let mut z80 = Z80::default();
+        z80.load(COUNTDOWN);
+        let mut debugger = Debugger::default();
+        let mut request = |text: &str| body(&debugger.handle(&mut z80, text));
+        assert_eq!(
+            r#"{"address":0,"text":"LD B, $03","length":2,"flags":{"s":"unchanged","z":"unchanged","h":"unchanged","pv":"unchanged","n":"unchanged","c":"unchanged"}}"#,
+            request(r#"{"command": "instruction"}"#).to_string()
+        );
+        let dec = request(r#"{"command": "instruction", "address": 2}"#);
+        let flags = dec.get("flags").unwrap();
+        assert_eq!(Some("DEC B"), dec.get("text").and_then(Value::as_str));
+        assert_eq!(Some("set"), flags.get("n").and_then(Value::as_str));
+        assert_eq!(Some("affected"), flags.get("z").and_then(Value::as_str));
+        assert_eq!(Some("unchanged"), flags.get("c").and_then(Value::as_str));
+    }
+
+    #[test]
+    fn backtraces() {
+        let mut z80 = Z80::default();
+        // main: LD HL, 5; PUSH HL; CALL f; HALT; f: NOP
+        z80.load(&[0x21, 0x05, 0x00, 0xE5, 0xCD, 0x08, 0x00, 0x76, 0x00]);
+        let mut debugger = Debugger::default();
+        debugger.symbols.insert("main", 0);
+        debugger.symbols.insert("f", 8);
+        debugger.functions.insert("f".to_string(), vec![2]);
+        debugger.convention = Convention::Sccz80;
+        debugger.step(&mut z80, 3);
+        let frames = body(&debugger.handle(&mut z80, r#"{"command": "backtrace"}"#));
+        assert_eq!(
+            r#"{"frames":[{"pc":8,"name":"f","arguments":[5]},{"pc":4,"name":"main+4","arguments":null}]}"#,
+            frames.to_string()
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let mut z80 = Z80::default();
+        let mut debugger = Debugger::default();
+        assert_eq!(
+            r#"{"id":"x","success":false,"message":"unknown command fly"}"#,
+            debugger.handle(&mut z80, r#"{"id": "x", "command": "fly"}"#)
+        );
+        assert_eq!(
+            r#"{"id":null,"success":false,"message":"invalid JSON at byte 1"}"#,
+            debugger.handle(&mut z80, "{")
+        );
+        let response = debugger.handle(
+            &mut z80,
+            r#"{"id": 2, "command": "set_register", "name": "a", "value": 256}"#,
+        );
+        assert_eq!(
+            r#"{"id":2,"success":false,"message":"value out of range"}"#,
+            response
+        );
+        let response = debugger.handle(
+            &mut z80,
+            r#"{"id": 3, "command": "write_memory", "address": 16384, "data": [1]}"#,
+        );
+        assert_eq!(
+            r#"{"id":3,"success":false,"message":"address out of range"}"#,
+            response
+        );
+    }
+}
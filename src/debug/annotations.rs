@@ -0,0 +1,317 @@
+//! Notes on what memory holds: code, data, text or hardware registers, so the debuggers can
+//! show each range the way it should be read instead of disassembling everything.
+//!
+//! They're saved in a project file, one range to a line, as the kind, the first and last
+//! addresses, and an optional note:
+//! ```text
+//! # Lines starting with # are comments
+//! code $0000 $00ff
+//! text $0100 $010b greeting
+//! hardware $3ff0 $3fff VDP registers
+//! ```
+use std::collections::BTreeMap;
+use std::fmt;
+
+use super::symbols::parse_number;
+use crate::cpu::opcodes::{table, try_opcode};
+use crate::z80::Z80;
+
+/// A line of a project file that couldn't be read
+#[derive(Debug, PartialEq)]
+pub struct AnnotationError {
+    /// Counting from 1
+    pub line: usize,
+}
+
+impl fmt::Display for AnnotationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bad annotation on line {}", self.line)
+    }
+}
+
+impl std::error::Error for AnnotationError {}
+
+/// What a range of memory holds
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Region {
+    /// Instructions, to be disassembled
+    Code,
+    /// Bytes, to be shown as they are
+    Data,
+    /// ASCII text
+    Text,
+    /// Memory-mapped hardware, which isn't read when it's shown, as reading it can have
+    /// side effects
+    Hardware,
+}
+
+impl Region {
+    /// The name a region goes by in project files and commands
+    pub fn name(self) -> &'static str {
+        match self {
+            Region::Code => "code",
+            Region::Data => "data",
+            Region::Text => "text",
+            Region::Hardware => "hardware",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Region::Code, Region::Data, Region::Text, Region::Hardware]
+            .iter()
+            .copied()
+            .find(|r| r.name() == name)
+    }
+}
+
+/// A range of memory, from `start` to `end` inclusive, and what it holds
+#[derive(Debug, PartialEq, Clone)]
+pub struct Annotation {
+    pub start: u16,
+    pub end: u16,
+    pub region: Region,
+    pub note: String,
+}
+
+/// The annotated ranges of memory. Memory that isn't annotated is taken to be code.
+#[derive(Debug, Default, Clone)]
+pub struct Annotations {
+    by_start: BTreeMap<u16, Annotation>,
+}
+
+impl Annotations {
+    /// Read a project file
+    /// ```
+    /// use zeerust::debug::annotations::{Annotations, Region};
+    ///
+    /// let annotations = Annotations::parse("data $4000 $40ff\ntext $5000 $500f title\n").unwrap();
+    /// assert_eq!(Some(Region::Data), annotations.get(0x4080).map(|a| a.region));
+    /// assert_eq!(None, annotations.get(0x4100));
+    /// assert_eq!("data $4000 $40ff\ntext $5000 $500f title\n", annotations.to_string());
+    ///```
+    pub fn parse(text: &str) -> Result<Self, AnnotationError> {
+        let mut annotations = Self::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.splitn(4, char::is_whitespace);
+            let mut field = || fields.next().filter(|f| !f.is_empty());
+            let region = field().and_then(Region::from_name);
+            let start = field().and_then(parse_number);
+            let end = field().and_then(parse_number);
+            let note = field().unwrap_or("").trim();
+            match (region, start, end) {
+                (Some(region), Some(start), Some(end)) if start <= end => {
+                    annotations.annotate(start, end, region, note)
+                }
+                _ => return Err(AnnotationError { line: i + 1 }),
+            }
+        }
+        Ok(annotations)
+    }
+
+    /// Say what the memory from `start` to `end` (inclusive) holds. Whatever was said about it
+    /// before is forgotten, though the parts of other ranges outside it are kept.
+    pub fn annotate(&mut self, start: u16, end: u16, region: Region, note: &str) {
+        let overlapping: Vec<u16> = self
+            .by_start
+            .range(..=end)
+            .filter(|(_, a)| a.end >= start)
+            .map(|(s, _)| *s)
+            .collect();
+        for s in overlapping {
+            let old = self.by_start.remove(&s).unwrap();
+            if old.start < start {
+                let before = Annotation {
+                    end: start - 1,
+                    ..old.clone()
+                };
+                self.by_start.insert(before.start, before);
+            }
+            if old.end > end {
+                let after = Annotation {
+                    start: end + 1,
+                    ..old
+                };
+                self.by_start.insert(after.start, after);
+            }
+        }
+        self.by_start.insert(
+            start,
+            Annotation {
+                start,
+                end,
+                region,
+                note: note.to_string(),
+            },
+        );
+    }
+
+    /// Forget what was said about the memory from `start` to `end`
+    pub fn clear(&mut self, start: u16, end: u16) {
+        self.annotate(start, end, Region::Code, "");
+        self.by_start.remove(&start);
+    }
+
+    /// The range an address is in
+    pub fn get(&self, addr: u16) -> Option<&Annotation> {
+        self.by_start
+            .range(..=addr)
+            .next_back()
+            .map(|(_, a)| a)
+            .filter(|a| a.end >= addr)
+    }
+
+    /// Every range, in address order
+    pub fn iter(&self) -> impl Iterator<Item = &Annotation> {
+        self.by_start.values()
+    }
+
+    /// Show memory from `start`, for `length` bytes, as each part of it should be read:
+    /// instructions, `db` lines of data or text, or a line for each range of hardware. A line
+    /// can run on past the end, to finish an instruction.
+    pub fn render(&self, z80: &Z80, start: u16, length: u16) -> Vec<String> {
+        let end = u32::from(start) + u32::from(length);
+        let mut addr = u32::from(start);
+        let mut lines = vec![];
+        while addr < end {
+            let a = addr as u16;
+            let annotation = self.get(a);
+            let region = annotation.map_or(Region::Code, |a| a.region);
+            if region != Region::Hardware && z80.peek(a).is_none() {
+                // The end of memory
+                break;
+            }
+            // Data and text stop at the end of their range, or of what was asked for
+            let limit = annotation.map_or(end, |an| end.min(u32::from(an.end) + 1));
+            let (text, size) = match region {
+                Region::Code => {
+                    let code = [0, 1, 2, 3].map(|i| z80.peek(a.wrapping_add(i)).unwrap_or(0));
+                    match try_opcode(code) {
+                        Ok((op, size)) => (table::disassemble(&op), size as u32),
+                        Err(_) => (format!("db ${:02x}", code[0]), 1),
+                    }
+                }
+                Region::Data => {
+                    let bytes = bytes(z80, a, (limit - addr).min(8));
+                    let hex: Vec<String> = bytes.iter().map(|b| format!("${:02x}", b)).collect();
+                    (format!("db {}", hex.join(", ")), bytes.len() as u32)
+                }
+                Region::Text => {
+                    let bytes = bytes(z80, a, (limit - addr).min(32));
+                    (format!("db {}", text(&bytes)), bytes.len() as u32)
+                }
+                Region::Hardware => ("; hardware".to_string(), limit - addr),
+            };
+            let line = match annotation {
+                Some(an) if an.start == a && !an.note.is_empty() => {
+                    format!("{:04x}  {} ; {}", a, text, an.note)
+                }
+                _ => format!("{:04x}  {}", a, text),
+            };
+            lines.push(line);
+            addr += size;
+        }
+        lines
+    }
+}
+
+impl fmt::Display for Annotations {
+    /// The project file
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for a in self.iter() {
+            write!(f, "{} ${:04x} ${:04x}", a.region.name(), a.start, a.end)?;
+            if !a.note.is_empty() {
+                write!(f, " {}", a.note)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+// Up to `length` bytes, stopping short at the end of memory
+fn bytes(z80: &Z80, addr: u16, length: u32) -> Vec<u8> {
+    (0..length as u16)
+        .map_while(|i| z80.peek(addr.wrapping_add(i)))
+        .collect()
+}
+
+// Bytes as a db operand: printable runs in quotes, anything else in hex
+fn text(bytes: &[u8]) -> String {
+    let mut parts: Vec<String> = vec![];
+    let mut run = String::new();
+    for &b in bytes {
+        if (0x20..0x7F).contains(&b) && b != b'"' {
+            run.push(b as char);
+            continue;
+        }
+        if !run.is_empty() {
+            parts.push(format!("\"{}\"", run));
+            run.clear();
+        }
+        parts.push(format!("${:02x}", b));
+    }
+    if !run.is_empty() {
+        parts.push(format!("\"{}\"", run));
+    }
+    parts.join(", ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn overlapping() {
+        let mut annotations = Annotations::default();
+        annotations.annotate(0x0000, 0x00FF, Region::Data, "table");
+        annotations.annotate(0x0010, 0x001F, Region::Text, "");
+        annotations.annotate(0x00F0, 0x010F, Region::Hardware, "ports");
+        assert_eq!(
+            "data $0000 $000f table\ntext $0010 $001f\ndata $0020 $00ef table\nhardware $00f0 $010f ports\n",
+            annotations.to_string()
+        );
+        annotations.clear(0x0000, 0x00FF);
+        assert_eq!("hardware $0100 $010f ports\n", annotations.to_string());
+        annotations.annotate(0x0000, 0xFFFF, Region::Code, "");
+        assert_eq!(1, annotations.iter().count());
+
+        assert_eq!(
+            Err(AnnotationError { line: 2 }),
+            Annotations::parse("# ok\ndata $10 $0f\n").map(|_| ())
+        );
+        assert_eq!(
+            Err(AnnotationError { line: 1 }),
+            Annotations::parse("stack $10 $20\n").map(|_| ())
+        );
+    }
+
+    #[test]
+    fn rendering() {
+        let mut z80 = Z80::default();
+        // LD A, 42; HALT; "Hi!\n"; 1, 2, 3
+        z80.load(&[0x3E, 0x2A, 0x76, b'H', b'i', b'!', 0x0A, 1, 2, 3]);
+        let mut annotations = Annotations::default();
+        annotations.annotate(0x0003, 0x0006, Region::Text, "greeting");
+        annotations.annotate(0x0007, 0x0009, Region::Data, "");
+        annotations.annotate(0x000A, 0x00FF, Region::Hardware, "");
+        assert_eq!(
+            vec![
+                "0000  LD A, $2a",
+                "0002  HALT",
+                "0003  db \"Hi!\", $0a ; greeting",
+                "0007  db $01, $02, $03",
+                "000a  ; hardware",
+                "0100  NOP",
+            ],
+            annotations.render(&z80, 0x0000, 0x0101)
+        );
+        assert_eq!(
+            vec!["3ffe  NOP", "3fff  NOP"],
+            annotations.render(&z80, 0x3FFE, 4)
+        );
+    }
+}
@@ -0,0 +1,272 @@
+//! Backtraces: the calls that led to where the processor is, found on the stack.
+//! Hand-written assembly keeps no record of its frames, so by default the stack is searched
+//! for return addresses, words that point just past a CALL or an RST. Code from a C compiler
+//! is more orderly, and knowing its calling convention gives the frames exactly and the
+//! arguments each function was called with:
+//!
+//! * SDCC (and z88dk's zsdcc), with arguments on the stack as in `__sdcccall(0)`: a function
+//!   starts with `PUSH IX; LD IX, 0; ADD IX, SP`, so IX points to the caller's IX and then the
+//!   return address, and the arguments follow it, the first one first. A `char` takes a byte.
+//! * z88dk's sccz80: there's no frame pointer, so the frames are searched for as for assembly,
+//!   but the arguments were pushed left to right, so the last one is next to the return
+//!   address. Every argument takes at least a word.
+//!
+//! Until a function has set up its frame, SDCC's IX is still its caller's, and the backtrace
+//! starts one frame out.
+use crate::cpu::opcodes::table;
+use crate::ops::{Op, Reg16};
+use crate::z80::Z80;
+
+/// The most frames a backtrace goes back
+pub const MAX_FRAMES: usize = 64;
+// How far up the stack to look for the next return address
+const SEARCH: u16 = 256;
+
+/// How a program's functions keep their frames and pass their arguments
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Convention {
+    /// No convention: frames are found by searching the stack, and arguments are unknown
+    #[default]
+    Assembly,
+    /// SDCC, with IX as the frame pointer
+    Sdcc,
+    /// z88dk's sccz80
+    Sccz80,
+}
+
+impl Convention {
+    /// A convention by its name: `assembly`, `sdcc` or `sccz80`
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "assembly" | "asm" => Some(Convention::Assembly),
+            "sdcc" | "zsdcc" => Some(Convention::Sdcc),
+            "sccz80" => Some(Convention::Sccz80),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Convention::Assembly => "assembly",
+            Convention::Sdcc => "sdcc",
+            Convention::Sccz80 => "sccz80",
+        }
+    }
+}
+
+/// A function that hasn't returned yet
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frame {
+    /// Where it is: the program counter in the innermost frame, and the call it's making in
+    /// the others
+    pub pc: u16,
+    /// Where its return address is on the stack, unless it's the outermost frame found
+    pub ret: Option<u16>,
+}
+
+impl Frame {
+    /// The arguments the function was called with, given the size of each in bytes (1 for a
+    /// `char`, 2 for an `int` or a pointer and 4 for a `long`), or None if they can't be found
+    pub fn arguments(&self, z80: &Z80, convention: Convention, sizes: &[u8]) -> Option<Vec<u32>> {
+        let start = self.ret?.checked_add(2)?;
+        let sizes: Vec<u16> = match convention {
+            Convention::Assembly => return None,
+            Convention::Sdcc => sizes.iter().map(|s| u16::from(*s)).collect(),
+            Convention::Sccz80 => sizes.iter().map(|s| u16::from(*s).max(2)).collect(),
+        };
+        let mut offset = match convention {
+            Convention::Sccz80 => sizes.iter().sum(),
+            _ => 0,
+        };
+        let mut values = vec![];
+        for size in sizes {
+            if convention == Convention::Sccz80 {
+                offset -= size;
+            }
+            let mut value = 0;
+            for i in (0..size).rev() {
+                let byte = z80.peek(start.checked_add(offset + i)?)?;
+                value = value << 8 | u32::from(byte);
+            }
+            values.push(value);
+            if convention != Convention::Sccz80 {
+                offset += size;
+            }
+        }
+        Some(values)
+    }
+}
+
+/// The frames on the stack, innermost first. For example, with an RST 0x08 that CALLs 0x0010:
+/// ```
+/// use zeerust::debug::backtrace::{backtrace, Convention};
+/// use zeerust::z80::Z80;
+///
+/// let mut z80 = Z80::default();
+/// z80.load(&[0xCF, 0x76, 0, 0, 0, 0, 0, 0, 0xCD, 0x10, 0x00, 0xC9]);
+/// z80.step();
+/// z80.step();
+/// let pcs: Vec<u16> = backtrace(&z80, Convention::Assembly)
+///     .iter()
+///     .map(|frame| frame.pc)
+///     .collect();
+/// assert_eq!(vec![0x0010, 0x0008, 0x0000], pcs);
+///```
+pub fn backtrace(z80: &Z80, convention: Convention) -> Vec<Frame> {
+    let mut frames = vec![];
+    let mut pc = z80.registers.get_pc();
+    let mut sp = z80.registers.get_reg16(&Reg16::SP);
+    let mut ix = z80.registers.get_reg16(&Reg16::IX);
+    while frames.len() < MAX_FRAMES - 1 {
+        let ret = match convention {
+            Convention::Sdcc => ix.checked_add(2).filter(|ret| *ret >= sp),
+            _ => (0..SEARCH).map_while(|i| sp.checked_add(i)).find(|slot| {
+                read_word(z80, *slot)
+                    .and_then(|r| call_before(z80, r))
+                    .is_some()
+            }),
+        };
+        let call = ret.and_then(|ret| call_before(z80, read_word(z80, ret)?));
+        let (ret, call) = match (ret, call) {
+            (Some(ret), Some(call)) => (ret, call),
+            _ => break,
+        };
+        frames.push(Frame { pc, ret: Some(ret) });
+        pc = call;
+        sp = ret.saturating_add(2);
+        if convention == Convention::Sdcc {
+            // The caller's frame is further up the stack, unless it has none
+            match read_word(z80, ix).filter(|next| *next > ix) {
+                Some(next) => ix = next,
+                None => break,
+            }
+        }
+    }
+    frames.push(Frame { pc, ret: None });
+    frames
+}
+
+// The address of the CALL or RST that a return address returns from
+fn call_before(z80: &Z80, ret: u16) -> Option<u16> {
+    [3, 1].iter().find_map(|length| {
+        let addr = ret.checked_sub(*length)?;
+        let code = [0, 1, 2, 3].map(|i| z80.peek(addr.wrapping_add(i)).unwrap_or(0));
+        match (table::decode(code), length) {
+            (Ok((Op::CALL(..), 3)), 3) | (Ok((Op::RST(_), 1)), 1) => Some(addr),
+            _ => None,
+        }
+    })
+}
+
+fn read_word(z80: &Z80, addr: u16) -> Option<u16> {
+    Some(u16::from_le_bytes([
+        z80.peek(addr)?,
+        z80.peek(addr.checked_add(1)?)?,
+    ]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // The program, run up to the end
+    fn run(program: &[u8], steps: usize) -> Z80 {
+        let mut z80 = Z80::default();
+        z80.load(program);
+        for _ in 0..steps {
+            z80.step();
+        }
+        z80
+    }
+
+    #[test]
+    fn sdcc() {
+        // f's prologue has run, but the processor has no INC SP or ADD IX, SP to run it with,
+        // so the stack is set up by hand
+        let mut z80 = Z80::default();
+        z80.load(&[
+            0xDD, 0x21, 0x00, 0x00, // 0000 main: LD IX, 0
+            0x21, 0x02, 0x00, 0xE5, // 0004 LD HL, 2; PUSH HL
+            0x3E, 0x41, 0xF5, 0x33, // 0008 LD A, 'A'; PUSH AF; INC SP
+            0xCD, 0x11, 0x00, 0x76, // 000C CALL f; HALT
+            0x00, // 0010 NOP
+            0xDD, 0xE5, 0xDD, 0x21, 0x00, 0x00, // 0011 f: PUSH IX; LD IX, 0
+            0xDD, 0x39, 0x00, // 0017 ADD IX, SP; NOP
+        ]);
+        // IX, then the return address, then the arguments
+        for (i, byte) in [0x00, 0x00, 0x0F, 0x00, 0x41, 0x02, 0x00]
+            .iter()
+            .enumerate()
+        {
+            z80.poke(0x3FF9 + i as u16, *byte);
+        }
+        z80.registers.set_reg16(&Reg16::SP, 0x3FF9);
+        z80.registers.set_reg16(&Reg16::IX, 0x3FF9);
+        z80.registers.set_pc(0x0019);
+        let frames = backtrace(&z80, Convention::Sdcc);
+        assert_eq!(
+            vec![
+                Frame {
+                    pc: 0x0019,
+                    ret: Some(0x3FFB)
+                },
+                Frame {
+                    pc: 0x000C,
+                    ret: None
+                }
+            ],
+            frames
+        );
+        // f(char c, int n)
+        assert_eq!(
+            Some(vec![0x41, 0x0002]),
+            frames[0].arguments(&z80, Convention::Sdcc, &[1, 2])
+        );
+        assert_eq!(None, frames[1].arguments(&z80, Convention::Sdcc, &[]));
+
+        // Searching the stack finds the same frames, without knowing the arguments
+        assert_eq!(frames, backtrace(&z80, Convention::Assembly));
+        assert_eq!(
+            None,
+            frames[0].arguments(&z80, Convention::Assembly, &[1, 2])
+        );
+    }
+
+    #[test]
+    fn sccz80() {
+        let z80 = run(
+            &[
+                0x21, 0x11, 0x11, 0xE5, // 0000 main: LD HL, 0x1111; PUSH HL
+                0x21, 0x22, 0x00, 0xE5, // 0004 LD HL, 0x22; PUSH HL
+                0xCD, 0x0D, 0x00, 0x76, // 0008 CALL f; HALT
+                0x00, // 000C NOP
+                0xC5, 0x00, // 000D f: PUSH BC; NOP
+            ],
+            6,
+        );
+        let frames = backtrace(&z80, Convention::Sccz80);
+        assert_eq!(
+            vec![(0x000E, Some(0x3FFA)), (0x0008, None)],
+            frames.iter().map(|f| (f.pc, f.ret)).collect::<Vec<_>>()
+        );
+        // f(int n, char c), pushed left to right, with the char taking a word
+        assert_eq!(
+            Some(vec![0x1111, 0x22]),
+            frames[0].arguments(&z80, Convention::Sccz80, &[2, 1])
+        );
+        // A long is pushed high word first, as DE then HL
+        assert_eq!(
+            Some(vec![0x1111_0022]),
+            frames[0].arguments(&z80, Convention::Sccz80, &[4])
+        );
+    }
+
+    #[test]
+    fn conventions() {
+        for convention in [Convention::Assembly, Convention::Sdcc, Convention::Sccz80] {
+            assert_eq!(Some(convention), Convention::parse(convention.name()));
+        }
+        assert_eq!(Some(Convention::Sdcc), Convention::parse("zsdcc"));
+        assert_eq!(None, Convention::parse("pascal"));
+    }
+}
@@ -0,0 +1,492 @@
+//! A Debug Adapter Protocol server, so editors such as VS Code can debug a program running on
+//! the emulator. It speaks DAP's Content-Length framed JSON over any stream: `listen` waits for
+//! the editor to connect over TCP (a `debugServer` port, in VS Code's launch.json).
+//!
+//! There is one thread, with the registers as its variables. Its stack frames are found as
+//! the backtrace module finds them, with the calling convention given as `convention` in
+//! the launch arguments (`"sdcc"`, say). With a SourceMap (from a Listing, say), the frame is placed at its source line, breakpoints can be set
+//! on lines, and stepping goes a line at a time: `next` runs over calls, and `stepOut` runs
+//! until the current routine returns. Without one, stepping goes an instruction at a time.
+//! Watches are expressions, as in the expression module. A HALT ends the session.
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+
+use super::backtrace::Convention;
+use super::expression::evaluate;
+use super::json::{self, Value};
+use super::listing::Listing;
+use super::source::{SourceLine, SourceMap};
+use super::{registers, Debugger, Stop, CONTINUE_LIMIT};
+use crate::ops::Reg16;
+use crate::z80::Z80;
+
+const THREAD: u64 = 1;
+const REGISTERS: u64 = 1;
+
+/// A debug session with an editor
+pub struct Dap {
+    pub debugger: Debugger,
+    source_map: SourceMap,
+    seq: u64,
+    // The breakpoints set in each file, so a new set can replace them
+    file_breakpoints: HashMap<String, Vec<u16>>,
+    finished: bool,
+}
+
+impl Dap {
+    pub fn new(source_map: SourceMap) -> Self {
+        Self {
+            debugger: Debugger::default(),
+            source_map,
+            seq: 0,
+            file_breakpoints: HashMap::new(),
+            finished: false,
+        }
+    }
+
+    /// A session using an assembler listing's source lines, and its labels to name the frame
+    pub fn with_listing(listing: Listing) -> Self {
+        let mut dap = Self::new(listing.source_map);
+        dap.debugger.symbols = listing.symbols;
+        dap
+    }
+
+    /// Wait for an editor to connect on the given address, and debug until it disconnects
+    pub fn listen<A: ToSocketAddrs>(&mut self, z80: &mut Z80, addr: A) -> io::Result<()> {
+        let (stream, _) = TcpListener::bind(addr)?.accept()?;
+        self.serve(z80, BufReader::new(stream.try_clone()?), stream)
+    }
+
+    /// Debug over the given streams, until the editor disconnects or closes them
+    pub fn serve<R: BufRead, W: Write>(
+        &mut self,
+        z80: &mut Z80,
+        mut input: R,
+        mut output: W,
+    ) -> io::Result<()> {
+        while !self.finished {
+            let message = match read_message(&mut input)? {
+                Some(message) => message,
+                None => return Ok(()),
+            };
+            let request =
+                json::parse(&message).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            for reply in self.handle(z80, &request) {
+                let reply = reply.to_string();
+                write!(output, "Content-Length: {}\r\n\r\n{}", reply.len(), reply)?;
+            }
+            output.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Handle one request, returning the response and any events that follow it
+    pub fn handle(&mut self, z80: &mut Z80, request: &Value) -> Vec<Value> {
+        let command = request.get("command").and_then(Value::as_str).unwrap_or("");
+        let args = request.get("arguments").cloned().unwrap_or(Value::Null);
+        let mut events = vec![];
+        let body = match command {
+            "initialize" => {
+                events.push(("initialized", None));
+                Ok(Value::object(vec![(
+                    "supportsConfigurationDoneRequest",
+                    true.into(),
+                )]))
+            }
+            "launch" | "attach" => {
+                if let Some(name) = args.get("convention").and_then(Value::as_str) {
+                    self.debugger.convention = Convention::parse(name).unwrap_or_default();
+                }
+                if args.get("stopOnEntry").and_then(Value::as_bool) == Some(false) {
+                    let stop = self.debugger.resume(z80, CONTINUE_LIMIT);
+                    events.push(self.stopped(stop));
+                } else {
+                    events.push(stopped_event("entry"));
+                }
+                Ok(Value::Null)
+            }
+            "configurationDone" | "pause" => Ok(Value::Null),
+            "disconnect" => {
+                self.finished = true;
+                Ok(Value::Null)
+            }
+            "setBreakpoints" => Ok(self.set_breakpoints(&args)),
+            "threads" => Ok(Value::object(vec![(
+                "threads",
+                Value::Array(vec![Value::object(vec![
+                    ("id", THREAD.into()),
+                    ("name", "Z80".into()),
+                ])]),
+            )])),
+            "stackTrace" => Ok(self.stack_trace(z80)),
+            "scopes" => Ok(Value::object(vec![(
+                "scopes",
+                Value::Array(vec![Value::object(vec![
+                    ("name", "Registers".into()),
+                    ("variablesReference", REGISTERS.into()),
+                    ("expensive", false.into()),
+                ])]),
+            )])),
+            "variables" => Ok(variables(z80)),
+            // Watches and the debug console, with the expression module's syntax
+            "evaluate" => {
+                let expression = args.get("expression").and_then(Value::as_str).unwrap_or("");
+                evaluate(expression, z80, &self.debugger.symbols)
+                    .map(|value| {
+                        Value::object(vec![
+                            ("result", format!("{:04x} ({})", value, value).into()),
+                            ("variablesReference", 0u8.into()),
+                        ])
+                    })
+                    .map_err(|e| e.to_string())
+            }
+            "continue" => {
+                let stop = self.debugger.resume(z80, CONTINUE_LIMIT);
+                events.push(self.stopped(stop));
+                Ok(Value::object(vec![("allThreadsContinued", true.into())]))
+            }
+            "next" | "stepIn" | "stepOut" => {
+                let stop = self.step(z80, command);
+                events.push(self.stopped(stop));
+                Ok(Value::Null)
+            }
+            _ => Err(format!("unsupported request {}", command)),
+        };
+
+        let mut fields = vec![
+            ("seq", self.next_seq().into()),
+            ("type", "response".into()),
+            (
+                "request_seq",
+                request.get("seq").cloned().unwrap_or(Value::Null),
+            ),
+            ("command", command.into()),
+        ];
+        match body {
+            Ok(Value::Null) => fields.push(("success", true.into())),
+            Ok(body) => fields.extend(vec![("success", true.into()), ("body", body)]),
+            Err(message) => {
+                fields.extend(vec![("success", false.into()), ("message", message.into())])
+            }
+        }
+        let mut replies = vec![Value::object(fields)];
+        for (event, body) in events {
+            let mut fields = vec![
+                ("seq", self.next_seq().into()),
+                ("type", "event".into()),
+                ("event", event.into()),
+            ];
+            fields.extend(body.map(|body| ("body", body)));
+            replies.push(Value::object(fields));
+        }
+        replies
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+
+    // The event to send once the processor has stopped running
+    fn stopped(&mut self, stop: Stop) -> (&'static str, Option<Value>) {
+        match stop {
+            Stop::Halted => {
+                self.finished = true;
+                ("terminated", None)
+            }
+            Stop::Breakpoint => stopped_event("breakpoint"),
+            Stop::Step => stopped_event("step"),
+            Stop::Limit => stopped_event("pause"),
+        }
+    }
+
+    fn step(&mut self, z80: &mut Z80, command: &str) -> Stop {
+        if self.source_map.is_empty() {
+            return self.debugger.step(z80, 1);
+        }
+        let start = self.source_map.line(z80.registers.get_pc()).cloned();
+        let sp = z80.registers.get_reg16(&Reg16::SP);
+        let map = &self.source_map;
+        // On a line, and not the one we started on
+        let new_line = |z80: &Z80| {
+            let line = map.line(z80.registers.get_pc());
+            line.is_some() && line != start.as_ref()
+        };
+        let returned = |z80: &Z80| z80.registers.get_reg16(&Reg16::SP) > sp;
+        let debugger = &mut self.debugger;
+        match command {
+            "next" => debugger.run_until(z80, CONTINUE_LIMIT, |z80| {
+                z80.registers.get_reg16(&Reg16::SP) >= sp && new_line(z80)
+            }),
+            "stepIn" => debugger.run_until(z80, CONTINUE_LIMIT, new_line),
+            _ => debugger.run_until(z80, CONTINUE_LIMIT, returned),
+        }
+    }
+
+    // Replace the breakpoints in a file. Lines with no instructions can't have one.
+    fn set_breakpoints(&mut self, args: &Value) -> Value {
+        let path = args
+            .get("source")
+            .and_then(|s| s.get("path"))
+            .and_then(Value::as_str)
+            .unwrap_or("")
+            .to_string();
+        for addr in self.file_breakpoints.remove(&path).unwrap_or_default() {
+            self.debugger.clear_breakpoint(addr);
+        }
+        let mut addrs = vec![];
+        let mut results = vec![];
+        let requested = args
+            .get("breakpoints")
+            .and_then(Value::as_array)
+            .unwrap_or(&[]);
+        for bp in requested {
+            let line = bp.get("line").and_then(Value::as_u64).unwrap_or(0);
+            let addr = self.source_map.address(&path, line as u32);
+            if let Some(addr) = addr {
+                self.debugger.set_breakpoint(addr);
+                addrs.push(addr);
+            }
+            results.push(Value::object(vec![
+                ("verified", addr.is_some().into()),
+                ("line", line.into()),
+            ]));
+        }
+        self.file_breakpoints.insert(path, addrs);
+        Value::object(vec![("breakpoints", Value::Array(results))])
+    }
+
+    fn stack_trace(&self, z80: &Z80) -> Value {
+        let frames: Vec<Value> = self
+            .debugger
+            .backtrace(z80)
+            .into_iter()
+            .enumerate()
+            .map(|(id, (frame, arguments))| {
+                let mut name = self.debugger.symbols.describe(frame.pc);
+                if let Some(arguments) = arguments {
+                    let arguments: Vec<String> = arguments.iter().map(u32::to_string).collect();
+                    name.push_str(&format!("({})", arguments.join(", ")));
+                }
+                let mut fields = vec![
+                    ("id", id.into()),
+                    ("name", name.into()),
+                    (
+                        "instructionPointerReference",
+                        format!("0x{:04x}", frame.pc).into(),
+                    ),
+                    ("column", 1u8.into()),
+                ];
+                match self.source_map.line(frame.pc) {
+                    Some(SourceLine { path, line }) => {
+                        fields.push(("line", (*line).into()));
+                        fields.push((
+                            "source",
+                            Value::object(vec![("path", path.as_str().into())]),
+                        ));
+                    }
+                    None => fields.push(("line", 0u8.into())),
+                }
+                Value::object(fields)
+            })
+            .collect();
+        let total = frames.len();
+        Value::object(vec![
+            ("stackFrames", Value::Array(frames)),
+            ("totalFrames", total.into()),
+        ])
+    }
+}
+
+fn stopped_event(reason: &str) -> (&'static str, Option<Value>) {
+    (
+        "stopped",
+        Some(Value::object(vec![
+            ("reason", reason.into()),
+            ("threadId", THREAD.into()),
+            ("allThreadsStopped", true.into()),
+        ])),
+    )
+}
+
+// The registers, in hex, and the interrupt state
+fn variables(z80: &Z80) -> Value {
+    let vars = match registers(z80) {
+        Value::Object(fields) => fields
+            .into_iter()
+            .map(|(name, value)| {
+                let value = match (name.as_str(), value) {
+                    ("im", Value::Number(n)) | ("tstates", Value::Number(n)) => n.to_string(),
+                    ("sp", Value::Number(n))
+                    | ("pc", Value::Number(n))
+                    | ("ix", Value::Number(n))
+                    | ("iy", Value::Number(n)) => format!("0x{:04x}", n as u16),
+                    (_, Value::Number(n)) => format!("0x{:02x}", n as u8),
+                    (_, value) => value.to_string(),
+                };
+                Value::object(vec![
+                    ("name", name.into()),
+                    ("value", value.into()),
+                    ("variablesReference", 0u8.into()),
+                ])
+            })
+            .collect(),
+        _ => vec![],
+    };
+    Value::object(vec![("variables", Value::Array(vars))])
+}
+
+// Read one message, or None at the end of the stream
+fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let length = length.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "DAP message without a length")
+    })?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    String::from_utf8(body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // main: CALL sub; CALL sub; HALT; sub: NOP; RET
+    const PROGRAM: &[u8] = &[0xCD, 0x07, 0x00, 0xCD, 0x07, 0x00, 0x76, 0x00, 0xC9];
+
+    fn session() -> (Dap, Z80) {
+        let mut map = SourceMap::default();
+        for (addr, line) in &[(0, 2), (3, 3), (6, 4), (7, 6), (8, 7)] {
+            map.insert(*addr, "prog.asm", *line);
+        }
+        (Dap::new(map), Z80::with_program(PROGRAM, 0))
+    }
+
+    fn request(dap: &mut Dap, z80: &mut Z80, text: &str) -> Vec<String> {
+        let request = json::parse(text).unwrap();
+        dap.handle(z80, &request)
+            .iter()
+            .map(|r| r.to_string())
+            .collect()
+    }
+
+    fn line(dap: &mut Dap, z80: &mut Z80) -> Option<u64> {
+        let reply = &dap.handle(z80, &json::parse(r#"{"command":"stackTrace"}"#).unwrap())[0];
+        let frames = reply
+            .get("body")
+            .and_then(|b| b.get("stackFrames"))
+            .unwrap();
+        frames.as_array().unwrap()[0]
+            .get("line")
+            .and_then(Value::as_u64)
+    }
+
+    #[test]
+    fn breakpoints_and_stepping() {
+        let (mut dap, mut z80) = session();
+        let replies = request(&mut dap, &mut z80, r#"{"seq":1,"command":"initialize"}"#);
+        assert_eq!(
+            vec![
+                r#"{"seq":1,"type":"response","request_seq":1,"command":"initialize","success":true,"body":{"supportsConfigurationDoneRequest":true}}"#,
+                r#"{"seq":2,"type":"event","event":"initialized"}"#,
+            ],
+            replies
+        );
+
+        let replies = request(
+            &mut dap,
+            &mut z80,
+            r#"{"seq":2,"command":"setBreakpoints","arguments":{"source":{"path":"/work/prog.asm"},"breakpoints":[{"line":7},{"line":5}]}}"#,
+        );
+        assert!(replies[0]
+            .contains(r#""breakpoints":[{"verified":true,"line":7},{"verified":false,"line":5}]"#));
+
+        request(
+            &mut dap,
+            &mut z80,
+            r#"{"seq":3,"command":"launch","arguments":{}}"#,
+        );
+        assert_eq!(Some(2), line(&mut dap, &mut z80));
+        let replies = request(&mut dap, &mut z80, r#"{"seq":4,"command":"continue"}"#);
+        assert!(replies[1].contains(r#""reason":"breakpoint""#));
+        assert_eq!(Some(7), line(&mut dap, &mut z80));
+
+        // Back out to the second call, then over it
+        request(&mut dap, &mut z80, r#"{"seq":5,"command":"stepOut"}"#);
+        assert_eq!(Some(3), line(&mut dap, &mut z80));
+        request(
+            &mut dap,
+            &mut z80,
+            r#"{"seq":6,"command":"setBreakpoints","arguments":{"source":{"path":"/work/prog.asm"},"breakpoints":[]}}"#,
+        );
+        request(&mut dap, &mut z80, r#"{"seq":7,"command":"next"}"#);
+        assert_eq!(Some(4), line(&mut dap, &mut z80));
+
+        let replies = request(&mut dap, &mut z80, r#"{"seq":8,"command":"next"}"#);
+        assert!(replies[1].contains(r#""event":"terminated""#));
+        assert!(dap.finished);
+    }
+
+    #[test]
+    fn step_in_and_variables() {
+        let (mut dap, mut z80) = session();
+        request(&mut dap, &mut z80, r#"{"seq":1,"command":"stepIn"}"#);
+        assert_eq!(Some(6), line(&mut dap, &mut z80));
+        let replies = request(
+            &mut dap,
+            &mut z80,
+            r#"{"seq":2,"command":"variables","arguments":{"variablesReference":1}}"#,
+        );
+        assert!(replies[0].contains(r#"{"name":"sp","value":"0x3ffe","variablesReference":0}"#));
+        assert!(replies[0].contains(r#"{"name":"pc","value":"0x0007","variablesReference":0}"#));
+        // In sub, called from main's first line
+        let replies = request(&mut dap, &mut z80, r#"{"seq":2,"command":"stackTrace"}"#);
+        assert!(replies[0].contains(
+            r#"{"id":1,"name":"0000","instructionPointerReference":"0x0000","column":1,"line":2,"#
+        ));
+        assert!(replies[0].contains(r#""totalFrames":2"#));
+        let replies = request(
+            &mut dap,
+            &mut z80,
+            r#"{"seq":3,"command":"evaluate","arguments":{"expression":"sp+2"}}"#,
+        );
+        assert!(replies[0].contains(r#""body":{"result":"4000 (16384)","variablesReference":0}"#));
+
+        let replies = request(&mut dap, &mut z80, r#"{"seq":4,"command":"fly"}"#);
+        assert!(replies[0].contains(r#""success":false,"message":"unsupported request fly""#));
+    }
+
+    #[test]
+    fn framing() {
+        let (mut dap, mut z80) = session();
+        let body = r#"{"seq":1,"type":"request","command":"disconnect"}"#;
+        let input = format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+        let mut output = vec![];
+        dap.serve(&mut z80, input.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            "Content-Length: 81\r\n\r\n{\"seq\":1,\"type\":\"response\",\"request_seq\":1,\
+             \"command\":\"disconnect\",\"success\":true}",
+            output
+        );
+        assert!(dap.finished);
+    }
+}
@@ -0,0 +1,245 @@
+//! Addresses written the way people think of them: `print_char+3`, `HL+2`, `$4000-1`.
+//! An expression adds and subtracts numbers (`$1F`, `0x1F`, `1Fh` or decimal), registers
+//! (in either case, with `pc` and `$` for the program counter), flags (`cf`, `nf`, `pf`, `hf`,
+//! `zf` and `sf`, which are 0 or 1), labels from a symbol table, and memory: `(HL)` and
+//! `byte[$5C78]` are the byte at an address, and `word[sp]` the little-endian word there.
+//! The arithmetic wraps around, as addresses do.
+use std::fmt;
+
+use super::symbols::{parse_number, Symbols};
+use super::{REG16, REG8};
+use crate::ops::StatusFlag;
+use crate::z80::Z80;
+
+// The names flags go by
+const FLAGS: [(&str, StatusFlag); 6] = [
+    ("cf", StatusFlag::Carry),
+    ("nf", StatusFlag::AddSubtract),
+    ("pf", StatusFlag::ParityOverflow),
+    ("hf", StatusFlag::HalfCarry),
+    ("zf", StatusFlag::Zero),
+    ("sf", StatusFlag::Sign),
+];
+
+#[derive(Debug, PartialEq)]
+pub enum ExpressionError {
+    /// A name that isn't a register or a known label
+    UnknownName(String),
+    /// Something that doesn't belong, at a byte offset into the expression
+    Syntax(usize),
+    /// Memory was read past its end
+    NoMemory(u16),
+}
+
+impl fmt::Display for ExpressionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExpressionError::UnknownName(name) => write!(f, "unknown name {}", name),
+            ExpressionError::Syntax(offset) => write!(f, "bad expression at byte {}", offset),
+            ExpressionError::NoMemory(addr) => write!(f, "no memory at {:04x}", addr),
+        }
+    }
+}
+
+impl std::error::Error for ExpressionError {}
+
+/// Work out the value of an expression, with the processor's registers as they are now
+/// ```
+/// use zeerust::debug::expression::evaluate;
+/// use zeerust::debug::symbols::Symbols;
+/// use zeerust::ops::Reg16;
+/// use zeerust::z80::Z80;
+///
+/// let mut z80 = Z80::default();
+/// z80.registers.set_reg16(&Reg16::HL, 0x4000);
+/// let mut symbols = Symbols::default();
+/// symbols.insert("print_char", 0x0102);
+/// assert_eq!(Ok(0x0105), evaluate("print_char+3", &z80, &symbols));
+/// assert_eq!(Ok(0x4002), evaluate("HL + 2", &z80, &symbols));
+///```
+pub fn evaluate(text: &str, z80: &Z80, symbols: &Symbols) -> Result<u16, ExpressionError> {
+    let mut parser = Parser {
+        text,
+        offset: 0,
+        z80,
+        symbols,
+    };
+    let value = parser.sum()?;
+    parser.skip_spaces();
+    if parser.offset < text.len() {
+        return Err(ExpressionError::Syntax(parser.offset));
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    offset: usize,
+    z80: &'a Z80,
+    symbols: &'a Symbols,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_spaces(&mut self) {
+        let rest = &self.text[self.offset..];
+        self.offset += rest.len() - rest.trim_start().len();
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_spaces();
+        self.text[self.offset..].chars().next()
+    }
+
+    // Terms added and subtracted, with an optional leading minus
+    fn sum(&mut self) -> Result<u16, ExpressionError> {
+        let mut value = if self.peek() == Some('-') {
+            self.offset += 1;
+            0u16.wrapping_sub(self.term()?)
+        } else {
+            self.term()?
+        };
+        loop {
+            match self.peek() {
+                Some('+') => {
+                    self.offset += 1;
+                    value = value.wrapping_add(self.term()?);
+                }
+                Some('-') => {
+                    self.offset += 1;
+                    value = value.wrapping_sub(self.term()?);
+                }
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<u16, ExpressionError> {
+        match self.peek() {
+            Some('(') => {
+                let addr = self.bracketed(')')?;
+                self.read(addr).map(u16::from)
+            }
+            Some(c) if c == '$' || c.is_ascii_alphanumeric() || c == '_' || c == '.' => {
+                let start = self.offset;
+                let rest = &self.text[start + c.len_utf8()..];
+                let len = rest
+                    .find(|c: char| {
+                        !(c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '\'')
+                    })
+                    .unwrap_or(rest.len());
+                let token = &self.text[start..start + c.len_utf8() + len];
+                self.offset += token.len();
+                match token {
+                    "byte" | "word" if self.peek() == Some('[') => {
+                        let addr = self.bracketed(']')?;
+                        let low = self.read(addr)?;
+                        if token == "byte" {
+                            return Ok(low.into());
+                        }
+                        let high = self.read(addr.wrapping_add(1))?;
+                        Ok(u16::from_le_bytes([low, high]))
+                    }
+                    _ => self.value(token),
+                }
+            }
+            _ => Err(ExpressionError::Syntax(self.offset)),
+        }
+    }
+
+    // An expression in brackets, starting at the opening one
+    fn bracketed(&mut self, close: char) -> Result<u16, ExpressionError> {
+        self.offset += 1;
+        let value = self.sum()?;
+        if self.peek() != Some(close) {
+            return Err(ExpressionError::Syntax(self.offset));
+        }
+        self.offset += 1;
+        Ok(value)
+    }
+
+    fn read(&self, addr: u16) -> Result<u8, ExpressionError> {
+        self.z80.peek(addr).ok_or(ExpressionError::NoMemory(addr))
+    }
+
+    fn value(&self, token: &str) -> Result<u16, ExpressionError> {
+        if token.starts_with(|c: char| c == '$' || c.is_ascii_digit()) {
+            return match token {
+                "$" => Ok(self.z80.registers.get_pc()),
+                _ => parse_number(token)
+                    .ok_or_else(|| ExpressionError::Syntax(self.offset - token.len())),
+            };
+        }
+        let regs = &self.z80.registers;
+        let lower = token.to_ascii_lowercase();
+        if let Some((_, reg)) = REG8.iter().find(|(name, _)| *name == lower) {
+            Ok(regs.get_reg8(*reg).into())
+        } else if let Some((_, reg)) = REG16.iter().find(|(name, _)| *name == lower) {
+            Ok(regs.get_reg16(reg))
+        } else if let Some((_, flag)) = FLAGS.iter().find(|(name, _)| *name == lower) {
+            Ok(regs.get_flag(flag).into())
+        } else if lower == "pc" {
+            Ok(regs.get_pc())
+        } else {
+            self.symbols
+                .get(token)
+                .ok_or_else(|| ExpressionError::UnknownName(token.to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ops::{Reg16, Reg8};
+
+    #[test]
+    fn memory_and_flags() {
+        let mut z80 = Z80::default();
+        z80.load(&[0x34, 0x12, 0xFF]);
+        z80.registers.set_reg16(&Reg16::HL, 0x0001);
+        z80.registers.set_flag(&StatusFlag::Zero, true);
+        let symbols = Symbols::default();
+        let eval = |text| evaluate(text, &z80, &symbols);
+
+        assert_eq!(Ok(0x0012), eval("(HL)"));
+        assert_eq!(Ok(0x0035), eval("(hl - 1) + 1"));
+        assert_eq!(Ok(0x00FF), eval("byte[(0) - $32]"));
+        assert_eq!(Ok(0x1234), eval("word [0]"));
+        assert_eq!(Ok(0x0002), eval("zf + cf + hl"));
+        assert_eq!(Ok(1), eval("ZF"));
+        assert_eq!(Err(ExpressionError::Syntax(6)), eval("byte[1"));
+    }
+
+    #[test]
+    fn expressions() {
+        let mut z80 = Z80::default();
+        z80.registers.set_reg8(Reg8::A, 0x10);
+        z80.registers.set_reg16(&Reg16::HLP, 0x1234);
+        z80.registers.set_pc(0x0100);
+        let mut symbols = Symbols::default();
+        symbols.insert("screen", 0x4000);
+        symbols.insert("loop.2", 0x0020);
+        let eval = |text| evaluate(text, &z80, &symbols);
+
+        assert_eq!(Ok(42), eval("42"));
+        assert_eq!(Ok(0x5AFF), eval("screen + 1AFFh"));
+        assert_eq!(Ok(0x3FFF), eval("screen-a+$0F"));
+        assert_eq!(Ok(0x1234), eval("HL'"));
+        assert_eq!(Ok(0x0122), eval("$ + loop.2 + 2"));
+        assert_eq!(Ok(0xFFFF), eval("-1"));
+        assert_eq!(Ok(0x0000), eval("pc + 0xFF00"));
+
+        assert_eq!(
+            Err(ExpressionError::UnknownName("nowhere".into())),
+            eval("nowhere+1")
+        );
+        assert_eq!(Err(ExpressionError::Syntax(7)), eval("screen+"));
+        assert_eq!(Err(ExpressionError::Syntax(4)), eval("(1+2"));
+        assert_eq!(
+            Err(ExpressionError::NoMemory(0x4000)),
+            eval("word[screen-1]")
+        );
+        assert_eq!(Err(ExpressionError::Syntax(0)), eval("12x"));
+        assert_eq!(Err(ExpressionError::Syntax(2)), eval("1 2"));
+    }
+}
@@ -0,0 +1,396 @@
+//! Just enough JSON for the debug protocol: a value type, a parser, and Display to write one out
+//! again. Numbers are kept as f64, which holds every address and register exactly.
+use std::fmt;
+
+/// A JSON value. Objects keep their keys in order.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Build an object from its keys and values
+    pub fn object(fields: Vec<(&str, Value)>) -> Self {
+        Value::Object(
+            fields
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
+        )
+    }
+
+    /// The value of a key in an object
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    /// A number, if it's a whole one that isn't negative
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Value::Number(n) if n >= 0.0 && n.fract() == 0.0 && n <= u64::MAX as f64 => {
+                Some(n as u64)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Value::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+macro_rules! from_number {
+    ($($t:ty),*) => {
+        $(impl From<$t> for Value {
+            fn from(n: $t) -> Self {
+                Value::Number(n as f64)
+            }
+        })*
+    };
+}
+
+from_number!(u8, u16, u32, u64, usize, i64, f64);
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(items: Vec<T>) -> Self {
+        Value::Array(items.into_iter().map(Into::into).collect())
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "null"),
+            Value::Bool(b) => write!(f, "{}", b),
+            // JSON has no infinities or NaN
+            Value::Number(n) if !n.is_finite() => write!(f, "null"),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::String(s) => write_string(f, s),
+            Value::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Value::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+/// Text that isn't valid JSON
+#[derive(Debug, PartialEq)]
+pub struct JsonError {
+    /// Where the problem was found, in bytes
+    pub offset: usize,
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid JSON at byte {}", self.offset)
+    }
+}
+
+impl std::error::Error for JsonError {}
+
+/// Parse a single JSON value, with nothing but whitespace around it
+pub fn parse(text: &str) -> Result<Value, JsonError> {
+    let mut parser = Parser {
+        text: text.as_bytes(),
+        pos: 0,
+    };
+    let value = parser.value()?;
+    parser.whitespace();
+    if parser.pos != text.len() {
+        return Err(parser.error());
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self) -> JsonError {
+        JsonError { offset: self.pos }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.pos).copied()
+    }
+
+    fn whitespace(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, token: &[u8]) -> Result<(), JsonError> {
+        if self.text[self.pos..].starts_with(token) {
+            self.pos += token.len();
+            Ok(())
+        } else {
+            Err(self.error())
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, JsonError> {
+        self.whitespace();
+        match self.peek() {
+            Some(b'n') => self.expect(b"null").map(|_| Value::Null),
+            Some(b't') => self.expect(b"true").map(|_| Value::Bool(true)),
+            Some(b'f') => self.expect(b"false").map(|_| Value::Bool(false)),
+            Some(b'"') => self.string().map(Value::String),
+            Some(b'[') => self.array(),
+            Some(b'{') => self.object(),
+            Some(b'-') | Some(b'0'..=b'9') => self.number(),
+            _ => Err(self.error()),
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, JsonError> {
+        self.pos += 1;
+        let mut items = vec![];
+        self.whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Value::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(Value::Array(items));
+                }
+                _ => return Err(self.error()),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, JsonError> {
+        self.pos += 1;
+        let mut fields = vec![];
+        self.whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Value::Object(fields));
+        }
+        loop {
+            self.whitespace();
+            if self.peek() != Some(b'"') {
+                return Err(self.error());
+            }
+            let key = self.string()?;
+            self.whitespace();
+            self.expect(b":")?;
+            fields.push((key, self.value()?));
+            self.whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(Value::Object(fields));
+                }
+                _ => return Err(self.error()),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, JsonError> {
+        let start = self.pos;
+        while let Some(b'-') | Some(b'+') | Some(b'.') | Some(b'e') | Some(b'E')
+        | Some(b'0'..=b'9') = self.peek()
+        {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.text[start..self.pos])
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .map(Value::Number)
+            .ok_or(JsonError { offset: start })
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        self.pos += 1;
+        let mut s = vec![];
+        loop {
+            match self.peek() {
+                None => return Err(self.error()),
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let escaped = match self.peek() {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => self.unicode_escape()?,
+                        _ => return Err(self.error()),
+                    };
+                    let mut buf = [0; 4];
+                    s.extend_from_slice(escaped.encode_utf8(&mut buf).as_bytes());
+                    self.pos += 1;
+                }
+                Some(b) => {
+                    s.push(b);
+                    self.pos += 1;
+                }
+            }
+        }
+        self.pos += 1;
+        // The input was a str, and escapes are whole characters, so this can't fail
+        Ok(String::from_utf8(s).expect("JSON string isn't UTF-8"))
+    }
+
+    // At the 'u' of a \u escape, leaving pos on its last digit. A high surrogate must be followed
+    // by the escape for its low one.
+    fn unicode_escape(&mut self) -> Result<char, JsonError> {
+        let high = self.hex4()?;
+        let code = if (0xD800..0xDC00).contains(&high) {
+            self.pos += 1;
+            self.expect(b"\\")?;
+            let low = self.hex4()?;
+            if !(0xDC00..0xE000).contains(&low) {
+                return Err(self.error());
+            }
+            0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+        } else {
+            high
+        };
+        char::from_u32(code).ok_or_else(|| self.error())
+    }
+
+    fn hex4(&mut self) -> Result<u32, JsonError> {
+        let digits = self
+            .text
+            .get(self.pos + 1..self.pos + 5)
+            .and_then(|d| std::str::from_utf8(d).ok())
+            .and_then(|d| u32::from_str_radix(d, 16).ok())
+            .ok_or_else(|| self.error())?;
+        self.pos += 4;
+        Ok(digits)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let text = r#"{"id":7,"list":[true,false,null,-1.5,"a\"b\\c\n"],"empty":{},"none":[]}"#;
+        let value = parse(text).unwrap();
+        assert_eq!(Some(7), value.get("id").and_then(Value::as_u64));
+        assert_eq!(
+            5,
+            value.get("list").and_then(Value::as_array).unwrap().len()
+        );
+        assert_eq!(text, value.to_string());
+
+        let spaced = parse(" { \"a\" : [ 1 , 2 ] } ").unwrap();
+        assert_eq!(Value::object(vec![("a", vec![1u8, 2].into())]), spaced);
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(
+            Value::String("é😀\u{1}".to_string()),
+            parse(r#""é😀\u0001""#).unwrap()
+        );
+        assert_eq!(r#""\u0001""#, Value::from("\u{1}").to_string());
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(Err(JsonError { offset: 5 }), parse("[1,2,"));
+        assert_eq!(Err(JsonError { offset: 1 }), parse("{1:2}"));
+        assert_eq!(Err(JsonError { offset: 5 }), parse("true false"));
+        assert_eq!(
+            Err(JsonError { offset: 7 }),
+            parse(r#""\ud800x""#).map(|_| ())
+        );
+        assert_eq!(None, Value::Number(-1.0).as_u64());
+        assert_eq!(None, Value::Number(1.5).as_u64());
+    }
+}
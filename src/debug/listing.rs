@@ -0,0 +1,151 @@
+//! Reading assembler listings, for source-level debugging.
+//! The examples are built with z80asm (see tests/Makefile), which writes a listing with
+//! `--list`: every source line, prefixed by its address and the bytes assembled from it.
+//!
+//! ```text
+//! # File countdown.asm
+//! 0000 06 09          ld B, 9
+//! 0002 78       jump: ld A, B
+//! # End of file countdown.asm
+//! ```
+//!
+//! Included files are bracketed the same way, inside the file that includes them. Reading one
+//! gives a SourceMap for the debuggers, and the labels defined at the start of a line. A
+//! label file (`--label`) has every label, including those defined with `equ`, so load that
+//! too when there is one.
+use super::source::SourceMap;
+use super::symbols::Symbols;
+
+/// What an assembler listing says about a program
+#[derive(Debug, Default, Clone)]
+pub struct Listing {
+    /// The line each instruction came from
+    pub source_map: SourceMap,
+    pub symbols: Symbols,
+}
+
+impl Listing {
+    /// Read a listing. Lines that aren't in the expected form are skipped.
+    /// ```
+    /// use zeerust::debug::listing::Listing;
+    ///
+    /// let listing = Listing::parse("# File a.asm\n0000 3e 2a\tstart: ld a, 42\n0002 76\t  halt\n");
+    /// assert_eq!(Some(2), listing.source_map.line(0x0002).map(|l| l.line));
+    /// assert_eq!(Some(0x0000), listing.symbols.get("start"));
+    ///```
+    pub fn parse(text: &str) -> Self {
+        let mut listing = Self::default();
+        // The files being read, innermost last, with the line reached in each
+        let mut files: Vec<(String, u32)> = vec![];
+        for line in text.lines() {
+            if let Some(name) = line.strip_prefix("# End of file") {
+                if files.last().map(|(f, _)| f.as_str()) == Some(name.trim()) {
+                    files.pop();
+                }
+                continue;
+            }
+            if let Some(name) = line.strip_prefix("# File") {
+                files.push((name.trim().to_string(), 0));
+                continue;
+            }
+            let (file, number) = match files.last_mut() {
+                Some(file) => file,
+                None => continue,
+            };
+            let (addr, bytes, source) = match split_line(line) {
+                Some(parts) => parts,
+                None => continue,
+            };
+            // Long data runs onto extra lines, with no source of their own
+            if bytes > 0 && source.trim().is_empty() {
+                continue;
+            }
+            *number += 1;
+            if bytes > 0 {
+                listing.source_map.insert(addr, file, *number);
+            }
+            if let Some(label) = label(source) {
+                listing.symbols.insert(label, addr);
+            }
+        }
+        listing
+    }
+}
+
+// The address, the number of bytes and the source text of a line
+fn split_line(line: &str) -> Option<(u16, usize, &str)> {
+    let addr = line
+        .get(..4)
+        .and_then(|addr| u16::from_str_radix(addr, 16).ok())?;
+    let mut rest = &line[4..];
+    let mut bytes = 0;
+    // Each byte is a space and two digits, and the source starts after a tab
+    while let Some(byte) = rest.strip_prefix(' ') {
+        match byte.get(..2) {
+            Some(digits) if u8::from_str_radix(digits, 16).is_ok() => {
+                bytes += 1;
+                rest = &byte[2..];
+            }
+            _ => break,
+        }
+    }
+    Some((addr, bytes, rest.strip_prefix('\t').unwrap_or(rest)))
+}
+
+// A label at the start of a line, as in `loop: djnz loop`
+fn label(source: &str) -> Option<&str> {
+    let (name, _) = source.trim_start().split_once(':')?;
+    let mut chars = name.chars();
+    let starts_well = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '.');
+    if starts_well && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.') {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const LISTING: &str = "# File main.asm
+0000 06 09\t\t      ld B, 9
+0002\t\t; Print each digit
+0002 cd 10 00\t\tloop: call print
+0005 10 fb\t\t      djnz loop
+0007\t\t      include \"print.asm\"
+# File print.asm
+0007 76\t\t      halt
+0008\t\t
+0008 3e 0a d3 00\t\tprint: db 0x3e, 0x0a, 0xd3, 0x00, 0xc9
+000c c9
+# End of file print.asm
+000d 3a 00 40\t\t      ld a, (0x4000) ; x: y
+# End of file main.asm
+0010
+";
+
+    #[test]
+    fn lines_and_labels() {
+        let listing = Listing::parse(LISTING);
+        let line = |addr| {
+            listing
+                .source_map
+                .line(addr)
+                .map(|l| (l.path.as_str(), l.line))
+        };
+        assert_eq!(Some(("main.asm", 1)), line(0x0000));
+        assert_eq!(Some(("main.asm", 3)), line(0x0002));
+        assert_eq!(Some(("print.asm", 1)), line(0x0007));
+        assert_eq!(Some(("print.asm", 3)), line(0x0008));
+        assert_eq!(None, line(0x000C));
+        assert_eq!(Some(("main.asm", 6)), line(0x000D));
+
+        assert_eq!(
+            vec![("loop", 0x0002), ("print", 0x0008)],
+            listing.symbols.iter().collect::<Vec<_>>()
+        );
+    }
+}
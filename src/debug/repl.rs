@@ -0,0 +1,484 @@
+//! A debugger to type commands at, for when there's no UI to hand.
+//! Anywhere a command takes an address or a number, it takes an expression, so with the
+//! program's labels loaded `break print_char+3` and `x hl+2` work (see the expression module).
+//!
+//! * `break ADDRESS` (`b`), `delete ADDRESS` (`d`), `breakpoints`
+//! * `step [COUNT]` (`s`), `continue` (`c`)
+//! * `registers` (`r`), `set REGISTER VALUE`
+//! * `x ADDRESS[, LENGTH]`: show memory, 16 bytes unless told otherwise
+//! * `print VALUE` (`p`): show the value of an expression
+//! * `watch VALUE` (`w`): show an expression's value whenever the processor stops, with a `*`
+//!   when it has changed. `unwatch INDEX` removes one, and `watches` shows them all.
+//! * `list ADDRESS[, LENGTH]` (`l`): disassemble 32 bytes (unless told otherwise), showing
+//!   data, text and hardware as annotated
+//! * `region KIND START, END[, NOTE]`: annotate memory as `code`, `data`, `text` or
+//!   `hardware`, or `clear` what was said about it. `regions` lists them, and `save` writes
+//!   them to the project file.
+//! * `backtrace` (`bt`): the frames on the stack, innermost first. `convention NAME` says how
+//!   the program keeps them: `assembly` (the default), `sdcc` or `sccz80`, as in the backtrace
+//!   module. `function LABEL SIZE...` gives the size in bytes of each argument of the function
+//!   at a label, and its frames then show its arguments.
+//! * `symbols FILE`: load the labels in a label, symbol or map file (see Symbols::parse)
+//! * `quit` (`q`)
+//! ```
+//! use zeerust::debug::repl::Repl;
+//! use zeerust::z80::Z80;
+//!
+//! let mut z80 = Z80::default();
+//! z80.load(&[0x3E, 0x2A, 0x76]); // start: LD A, 42; HALT
+//! let mut repl = Repl::default();
+//! repl.debugger.symbols.insert("start", 0x0000);
+//! repl.command(&mut z80, "break start+2").unwrap();
+//! assert_eq!(Ok("breakpoint at 0002 (start+2)".to_string()), repl.command(&mut z80, "c"));
+//! assert_eq!(Ok("002a 42".to_string()), repl.command(&mut z80, "p a"));
+//!```
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+
+use super::annotations::{Annotations, Region};
+use super::backtrace::Convention;
+use super::expression::evaluate;
+use super::symbols::Symbols;
+use super::{set_register, Debugger, Stop, CONTINUE_LIMIT, REG16};
+use crate::ops::Reg8;
+use crate::z80::Z80;
+
+/// A debug session driven by typed commands
+#[derive(Debug, Default)]
+pub struct Repl {
+    pub debugger: Debugger,
+    pub annotations: Annotations,
+    /// Where `save` writes the annotations
+    pub project: Option<PathBuf>,
+}
+
+impl Repl {
+    /// Run a command, returning what it has to say, or what was wrong with it
+    pub fn command(&mut self, z80: &mut Z80, line: &str) -> Result<String, String> {
+        let line = line.trim();
+        let (command, args) = match line.split_once(char::is_whitespace) {
+            Some((command, args)) => (command, args.trim()),
+            None => (line, ""),
+        };
+        match command {
+            "" => Ok(String::new()),
+            "b" | "break" => {
+                let addr = self.value(z80, args)?;
+                self.debugger.set_breakpoint(addr);
+                Ok(format!("breakpoint at {}", self.location(addr)))
+            }
+            "d" | "delete" => {
+                let addr = self.value(z80, args)?;
+                if self.debugger.clear_breakpoint(addr) {
+                    Ok(String::new())
+                } else {
+                    Err(format!("no breakpoint at {}", self.location(addr)))
+                }
+            }
+            "breakpoints" => Ok(self
+                .debugger
+                .breakpoints()
+                .map(|addr| self.location(addr))
+                .collect::<Vec<_>>()
+                .join("\n")),
+            "s" | "step" => {
+                let count = match args {
+                    "" => 1,
+                    _ => self.value(z80, args)?,
+                };
+                let stop = self.debugger.step(z80, count.into());
+                Ok(self.stopped(z80, stop))
+            }
+            "c" | "continue" => {
+                let stop = self.debugger.resume(z80, CONTINUE_LIMIT);
+                Ok(self.stopped(z80, stop))
+            }
+            "r" | "registers" => Ok(registers(z80)),
+            "set" => {
+                let (name, value) = args
+                    .split_once(char::is_whitespace)
+                    .ok_or("usage: set REGISTER VALUE")?;
+                let value = self.value(z80, value)?;
+                set_register(z80, &name.to_ascii_lowercase(), value)?;
+                Ok(String::new())
+            }
+            "x" => {
+                let (addr, length) = match args.split_once(',') {
+                    Some((addr, length)) => (addr, self.value(z80, length)?),
+                    None => (args, 16),
+                };
+                let addr = self.value(z80, addr)?;
+                Ok(dump(z80, addr, length))
+            }
+            "w" | "watch" => {
+                let value = self
+                    .debugger
+                    .add_watch(z80, args)
+                    .map_err(|e| e.to_string())?;
+                let index = self.debugger.watches().len() - 1;
+                Ok(format!("{}: {} = {:04x}", index, args, value))
+            }
+            "unwatch" => {
+                let index = self.value(z80, args)?;
+                if self.debugger.remove_watch(index.into()) {
+                    Ok(String::new())
+                } else {
+                    Err(format!("no watch {}", index))
+                }
+            }
+            "watches" => Ok(self.watches()),
+            "p" | "print" => {
+                let value = self.value(z80, args)?;
+                Ok(format!("{:04x} {}", value, value))
+            }
+            "l" | "list" => {
+                let (addr, length) = match args.split_once(',') {
+                    Some((addr, length)) => (addr, self.value(z80, length)?),
+                    None => (args, 32),
+                };
+                let addr = self.value(z80, addr)?;
+                Ok(self.annotations.render(z80, addr, length).join("\n"))
+            }
+            "region" => {
+                let usage = "usage: region KIND START, END[, NOTE]";
+                let (kind, range) = args.split_once(char::is_whitespace).ok_or(usage)?;
+                let mut fields = range.splitn(3, ',');
+                let start = self.value(z80, fields.next().unwrap_or(""))?;
+                let end = self.value(z80, fields.next().ok_or(usage)?)?;
+                let note = fields.next().unwrap_or("").trim();
+                if end < start {
+                    return Err("the region ends before it starts".to_string());
+                }
+                match kind {
+                    "clear" => self.annotations.clear(start, end),
+                    _ => {
+                        let region = Region::from_name(kind)
+                            .ok_or_else(|| format!("unknown region kind {}", kind))?;
+                        self.annotations.annotate(start, end, region, note)
+                    }
+                }
+                Ok(String::new())
+            }
+            "regions" => Ok(self.annotations.to_string().trim_end().to_string()),
+            "save" => {
+                let path = self.project.as_ref().ok_or("no project file")?;
+                fs::write(path, self.annotations.to_string()).map_err(|e| e.to_string())?;
+                Ok(format!("saved {}", path.display()))
+            }
+            "bt" | "backtrace" => {
+                let frames: Vec<String> = self
+                    .debugger
+                    .backtrace(z80)
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, (frame, arguments))| {
+                        let mut line = format!("#{} {}", i, self.location(frame.pc));
+                        if let Some(arguments) = arguments {
+                            let arguments: Vec<String> =
+                                arguments.iter().map(u32::to_string).collect();
+                            line.push_str(&format!(" with {}", arguments.join(", ")));
+                        }
+                        line
+                    })
+                    .collect();
+                Ok(frames.join("\n"))
+            }
+            "convention" => {
+                if !args.is_empty() {
+                    self.debugger.convention = Convention::parse(args)
+                        .ok_or_else(|| format!("unknown convention {}", args))?;
+                }
+                Ok(self.debugger.convention.name().to_string())
+            }
+            "function" => {
+                let mut words = args.split_whitespace();
+                let name = words.next().ok_or("missing function")?;
+                let sizes = words
+                    .map(|size| match size.parse::<u8>() {
+                        Ok(size @ (1 | 2 | 4)) => Ok(size),
+                        _ => Err(format!("bad argument size {}", size)),
+                    })
+                    .collect::<Result<Vec<u8>, String>>()?;
+                self.debugger.functions.insert(name.to_string(), sizes);
+                Ok(String::new())
+            }
+            "symbols" => {
+                if args.is_empty() {
+                    return Err("missing file".to_string());
+                }
+                let text = fs::read_to_string(args).map_err(|e| e.to_string())?;
+                let symbols = Symbols::parse(&text).map_err(|e| e.to_string())?;
+                self.debugger.symbols.extend(&symbols);
+                Ok(format!("{} labels", symbols.iter().count()))
+            }
+            _ => Err(format!("unknown command {}", command)),
+        }
+    }
+
+    /// Read commands from `input` until `quit` or the end of the input, writing what they say
+    /// to `output`
+    pub fn serve<R: BufRead, W: Write>(
+        &mut self,
+        z80: &mut Z80,
+        input: R,
+        mut output: W,
+    ) -> io::Result<()> {
+        write!(output, "> ")?;
+        output.flush()?;
+        for line in input.lines() {
+            let line = line?;
+            if line.trim() == "q" || line.trim() == "quit" {
+                break;
+            }
+            match self.command(z80, &line) {
+                Ok(text) if text.is_empty() => {}
+                Ok(text) => writeln!(output, "{}", text)?,
+                Err(message) => writeln!(output, "error: {}", message)?,
+            }
+            write!(output, "> ")?;
+            output.flush()?;
+        }
+        Ok(())
+    }
+
+    fn value(&self, z80: &Z80, text: &str) -> Result<u16, String> {
+        if text.trim().is_empty() {
+            return Err("missing address".to_string());
+        }
+        evaluate(text, z80, &self.debugger.symbols).map_err(|e| e.to_string())
+    }
+
+    // An address, with the label it's in if there is one
+    fn location(&self, addr: u16) -> String {
+        match self.debugger.symbols.name_at(addr) {
+            Some(_) => format!("{:04x} ({})", addr, self.debugger.symbols.describe(addr)),
+            None => format!("{:04x}", addr),
+        }
+    }
+
+    fn stopped(&self, z80: &Z80, stop: Stop) -> String {
+        let at = format!(
+            "{} at {}",
+            stop.name(),
+            self.location(z80.registers.get_pc())
+        );
+        if self.debugger.watches().is_empty() {
+            at
+        } else {
+            format!("{}\n{}", at, self.watches())
+        }
+    }
+
+    // A line for each watch
+    fn watches(&self) -> String {
+        let lines: Vec<String> = self
+            .debugger
+            .watches()
+            .iter()
+            .enumerate()
+            .map(|(i, w)| {
+                let value = w.value.map_or("?".to_string(), |v| format!("{:04x}", v));
+                let changed = if w.changed { " *" } else { "" };
+                format!("{}: {} = {}{}", i, w.expression, value, changed)
+            })
+            .collect();
+        lines.join("\n")
+    }
+}
+
+fn registers(z80: &Z80) -> String {
+    let regs = &z80.registers;
+    let mut text = String::new();
+    for (i, (name, reg)) in REG16.iter().enumerate() {
+        text.push_str(&format!("{}={:04x}", name, regs.get_reg16(reg)));
+        text.push(if i == 3 || i == 7 { '\n' } else { ' ' });
+    }
+    text.push_str(&format!(
+        "pc={:04x}\ni={:02x} r={:02x} iff1={} iff2={} im={} halted={} tstates={}",
+        regs.get_pc(),
+        regs.get_reg8(Reg8::I),
+        regs.get_reg8(Reg8::R),
+        z80.iff1(),
+        z80.iff2(),
+        z80.interrupt_mode(),
+        z80.is_halted(),
+        z80.tstates()
+    ));
+    text
+}
+
+// Memory as lines of 16 bytes in hex, stopping short at the end of memory
+fn dump(z80: &Z80, addr: u16, length: u16) -> String {
+    let bytes: Vec<u8> = (0..length)
+        .map_while(|i| z80.peek(addr.wrapping_add(i)))
+        .collect();
+    bytes
+        .chunks(16)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+            format!(
+                "{:04x}: {}",
+                addr.wrapping_add(i as u16 * 16),
+                hex.join(" ")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn commands() {
+        let mut z80 = Z80::default();
+        // LD B, 3; loop: DEC B; JR NZ, loop; HALT
+        z80.load(&[0x06, 0x03, 0x05, 0x20, 0xFD, 0x76]);
+        let mut repl = Repl::default();
+        repl.debugger.symbols.insert("start", 0x0000);
+        repl.debugger.symbols.insert("loop", 0x0002);
+
+        let mut output = vec![];
+        let input = "break loop\nc\nc\np b\nset HL loop+1\nx hl, 3\nd loop\nd 2\nfly\ns 2\nq\nc\n";
+        repl.serve(&mut z80, input.as_bytes(), &mut output).unwrap();
+        assert_eq!(
+            "> breakpoint at 0002 (loop)
+> breakpoint at 0002 (loop)
+> breakpoint at 0002 (loop)
+> 0002 2
+> > 0003: 20 fd 76
+> > error: no breakpoint at 0002 (loop)
+> error: unknown command fly
+> step at 0002 (loop)
+> ",
+            String::from_utf8(output).unwrap()
+        );
+        assert_eq!(0x0002, z80.registers.get_pc());
+    }
+
+    #[test]
+    fn regions() {
+        let mut z80 = Z80::default();
+        z80.load(&[0x3E, 0x2A, 0x76, b'H', b'i', 0x00]);
+        let mut repl = Repl::default();
+        repl.debugger.symbols.insert("msg", 0x0003);
+        let mut command = |line| repl.command(&mut z80, line);
+
+        assert_eq!(
+            Ok(String::new()),
+            command("region text msg, msg+1, greeting")
+        );
+        assert_eq!(Ok(String::new()), command("region data msg+2, msg+2"));
+        assert_eq!(
+            Ok(
+                "0000  LD A, $2a\n0002  HALT\n0003  db \"Hi\" ; greeting\n0005  db $00\n0006  NOP"
+                    .to_string()
+            ),
+            command("list 0, 7")
+        );
+        assert_eq!(Ok(String::new()), command("region clear msg+1, msg+2"));
+        assert_eq!(
+            Ok("text $0003 $0003 greeting".to_string()),
+            command("regions")
+        );
+        assert_eq!(
+            Err("unknown region kind stack".to_string()),
+            command("region stack 0, 1")
+        );
+        assert_eq!(Err("no project file".to_string()), command("save"));
+    }
+
+    #[test]
+    fn watches() {
+        let mut z80 = Z80::default();
+        // LD B, 3; loop: DEC B; JR NZ, loop; HALT
+        z80.load(&[0x06, 0x03, 0x05, 0x20, 0xFD, 0x76]);
+        let mut repl = Repl::default();
+        let mut command = |line| repl.command(&mut z80, line);
+
+        assert_eq!(Ok("0: b = 0000".to_string()), command("w b"));
+        assert_eq!(Ok("1: (pc) = 0006".to_string()), command("watch (pc)"));
+        assert_eq!(
+            Ok("step at 0002\n0: b = 0003 *\n1: (pc) = 0005 *".to_string()),
+            command("s")
+        );
+        assert_eq!(Ok(String::new()), command("unwatch 1"));
+        assert_eq!(Err("no watch 1".to_string()), command("unwatch 1"));
+        command("s 2").unwrap();
+        assert_eq!(Ok("0: b = 0002 *".to_string()), command("watches"));
+    }
+
+    #[test]
+    fn symbol_files() {
+        let mut z80 = Z80::default();
+        z80.load(&[0x06, 0x03, 0x05, 0x20, 0xFD, 0x76]);
+        let path = std::env::temp_dir().join(format!("zeerust-symbols-{}.map", std::process::id()));
+        fs::write(
+            &path,
+            "_loop = $0002 ; addr, local\n_end = $0005 ; addr, local\n",
+        )
+        .unwrap();
+        let mut repl = Repl::default();
+        let command = format!("symbols {}", path.display());
+        assert_eq!(Ok("2 labels".to_string()), repl.command(&mut z80, &command));
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            Ok("breakpoint at 0005 (_end)".to_string()),
+            repl.command(&mut z80, "b _end")
+        );
+        assert_eq!(
+            Err("missing file".to_string()),
+            repl.command(&mut z80, "symbols")
+        );
+        assert!(repl.command(&mut z80, &command).is_err());
+    }
+
+    #[test]
+    fn backtraces() {
+        let mut z80 = Z80::default();
+        z80.load(&[
+            0x21, 0x11, 0x11, 0xE5, // 0000 main: LD HL, 0x1111; PUSH HL
+            0x21, 0x22, 0x00, 0xE5, // 0004 LD HL, 0x22; PUSH HL
+            0xCD, 0x0D, 0x00, 0x76, // 0008 CALL f; HALT
+            0x00, // 000C NOP
+            0xC5, 0x00, // 000D f: PUSH BC; NOP
+        ]);
+        let mut repl = Repl::default();
+        repl.debugger.symbols.insert("main", 0x0000);
+        repl.debugger.symbols.insert("f", 0x000D);
+        let mut command = |line| repl.command(&mut z80, line);
+
+        command("s 6").unwrap();
+        assert_eq!(Ok(String::new()), command("function f 2 1"));
+        assert_eq!(
+            Ok("#0 000e (f+1)\n#1 0008 (main+8)".to_string()),
+            command("bt")
+        );
+        assert_eq!(Ok("sccz80".to_string()), command("convention sccz80"));
+        assert_eq!(
+            Ok("#0 000e (f+1) with 4369, 34\n#1 0008 (main+8)".to_string()),
+            command("backtrace")
+        );
+        assert_eq!(Ok("sccz80".to_string()), command("convention"));
+        assert_eq!(
+            Err("unknown convention pascal".to_string()),
+            command("convention pascal")
+        );
+        assert_eq!(
+            Err("bad argument size 3".to_string()),
+            command("function f 3")
+        );
+    }
+
+    #[test]
+    fn register_dump() {
+        let z80 = Z80::default();
+        let text = registers(&z80);
+        assert!(text.starts_with("af=0000 bc=0000 de=0000 hl=0000\naf'=0000"));
+        assert!(text.contains("ix=0000 iy=0000 sp=4000 pc=0000\ni=00"));
+    }
+}
@@ -0,0 +1,75 @@
+//! Mapping addresses to the source lines they were assembled from, for source-level debugging.
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// A line of a source file
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SourceLine {
+    pub path: String,
+    /// Counting from 1
+    pub line: u32,
+}
+
+/// Which source line each instruction came from
+#[derive(Debug, Default, Clone)]
+pub struct SourceMap {
+    lines: BTreeMap<u16, SourceLine>,
+}
+
+impl SourceMap {
+    /// Record that the instruction at `addr` came from a line of a file
+    pub fn insert(&mut self, addr: u16, path: &str, line: u32) {
+        self.lines.insert(
+            addr,
+            SourceLine {
+                path: path.to_string(),
+                line,
+            },
+        );
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    /// The line the instruction at `addr` came from
+    pub fn line(&self, addr: u16) -> Option<&SourceLine> {
+        self.lines.get(&addr)
+    }
+
+    /// The address of the first instruction on a line, for setting a breakpoint on it.
+    /// Paths match if either ends with the other, so a map written with relative paths still
+    /// works with the absolute ones an editor uses.
+    pub fn address(&self, path: &str, line: u32) -> Option<u16> {
+        self.lines
+            .iter()
+            .find(|(_, l)| l.line == line && same_file(&l.path, path))
+            .map(|(addr, _)| *addr)
+    }
+}
+
+fn same_file(a: &str, b: &str) -> bool {
+    let (a, b) = (Path::new(a), Path::new(b));
+    a.ends_with(b) || b.ends_with(a)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lookups() {
+        let mut map = SourceMap::default();
+        map.insert(0x0000, "src/main.asm", 3);
+        map.insert(0x0002, "src/main.asm", 4);
+        map.insert(0x0005, "src/main.asm", 4);
+        map.insert(0x0100, "lib.asm", 4);
+
+        assert_eq!(Some(4), map.line(0x0005).map(|l| l.line));
+        assert_eq!(None, map.line(0x0001));
+        assert_eq!(Some(0x0002), map.address("/home/me/src/main.asm", 4));
+        assert_eq!(Some(0x0100), map.address("lib.asm", 4));
+        assert_eq!(None, map.address("main.asm", 5));
+        assert_eq!(None, map.address("in.asm", 3));
+    }
+}
@@ -0,0 +1,267 @@
+//! Symbol tables: the addresses of a program's labels.
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+
+/// A line of a label file that couldn't be read
+#[derive(Debug, PartialEq)]
+pub struct SymbolError {
+    /// Counting from 1
+    pub line: usize,
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bad label on line {}", self.line)
+    }
+}
+
+impl std::error::Error for SymbolError {}
+
+/// Labels and their addresses
+#[derive(Debug, Default, Clone)]
+pub struct Symbols {
+    by_name: BTreeMap<String, u16>,
+}
+
+impl Symbols {
+    /// Read a label file, as written by z80asm's `--label` option: a line such as
+    /// `print_char: equ $0102` for every label.
+    /// ```
+    /// use zeerust::debug::symbols::Symbols;
+    ///
+    /// let symbols = Symbols::parse_labels("start:\tequ $0000\nloop:\tequ $0005\n").unwrap();
+    /// assert_eq!(Some(0x0005), symbols.get("loop"));
+    /// assert_eq!(Some(("loop", 2)), symbols.name_at(0x0007));
+    ///```
+    pub fn parse_labels(text: &str) -> Result<Self, SymbolError> {
+        let mut symbols = Self::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+            let label = line.split_once(':').and_then(|(name, value)| {
+                let value = value.trim();
+                let value = value
+                    .get(..3)
+                    .filter(|equ| equ.eq_ignore_ascii_case("equ"))
+                    .map(|_| value[3..].trim())?;
+                Some((name.trim(), parse_number(value)?))
+            });
+            match label {
+                Some((name, addr)) => symbols.insert(name, addr),
+                None => return Err(SymbolError { line: i + 1 }),
+            }
+        }
+        Ok(symbols)
+    }
+
+    /// Read a symbol file in any of the formats assemblers commonly write, a line at a time:
+    /// * `label: equ $0102`, from z80asm's `--label` or sjasmplus's `--sym` and `--exp`
+    /// * `label = $0102 ; ...`, from a z88dk map file
+    /// * `00:0102 label`, a bank and an address as in the `[labels]` section of a no$ or
+    ///   WLA-DX (SDSC) symbol file. Other sections, such as `[definitions]`, are skipped.
+    ///
+    /// Values too big for an address are constants rather than labels, and are skipped too.
+    /// ```
+    /// use zeerust::debug::symbols::Symbols;
+    ///
+    /// let map = "_main = $0100 ; addr, public, , main_c, code_compiler, main.c:5\n";
+    /// assert_eq!(Some(0x0100), Symbols::parse(map).unwrap().get("_main"));
+    /// let sym = "[labels]\n00:0005 loop\n[definitions]\n00000010 SIZE\n";
+    /// let symbols = Symbols::parse(sym).unwrap();
+    /// assert_eq!((Some(0x0005), None), (symbols.get("loop"), symbols.get("SIZE")));
+    ///```
+    pub fn parse(text: &str) -> Result<Self, SymbolError> {
+        let mut symbols = Self::default();
+        let mut labels = true;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(section) = line.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+                labels = section.eq_ignore_ascii_case("labels");
+                continue;
+            }
+            if !labels {
+                continue;
+            }
+            let label = if let Some((name, value)) = line.split_once('=') {
+                Some((name, parse_wide(value.trim())))
+            } else if let Some((name, value)) = line.split_once(':').filter(|(_, value)| {
+                let value = value.trim_start();
+                value
+                    .get(..3)
+                    .is_some_and(|e| e.eq_ignore_ascii_case("equ"))
+            }) {
+                Some((name, parse_wide(value.trim_start()[3..].trim())))
+            } else {
+                // The bank is ignored, and the address is in hex without a prefix
+                line.split_once(char::is_whitespace)
+                    .and_then(|(addr, name)| {
+                        let (_, addr) = addr.split_once(':')?;
+                        Some((name, u32::from_str_radix(addr, 16).ok()))
+                    })
+            };
+            let value = label
+                .map(|(name, value)| (name.trim(), value))
+                .filter(|(name, _)| !name.is_empty() && !name.contains(char::is_whitespace))
+                .and_then(|(name, value)| Some((name, value?)));
+            match value {
+                Some((name, value)) => {
+                    if let Ok(addr) = u16::try_from(value) {
+                        symbols.insert(name, addr);
+                    }
+                }
+                None => return Err(SymbolError { line: i + 1 }),
+            }
+        }
+        Ok(symbols)
+    }
+
+    /// Add the labels of another table, replacing any with the same names
+    pub fn extend(&mut self, other: &Symbols) {
+        for (name, addr) in other.iter() {
+            self.insert(name, addr);
+        }
+    }
+
+    /// Add a label, replacing any with the same name
+    pub fn insert(&mut self, name: &str, addr: u16) {
+        self.by_name.insert(name.to_string(), addr);
+    }
+
+    /// The address of a label
+    pub fn get(&self, name: &str) -> Option<u16> {
+        self.by_name.get(name).copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    /// Every label, in name order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u16)> {
+        self.by_name
+            .iter()
+            .map(|(name, addr)| (name.as_str(), *addr))
+    }
+
+    /// The closest label at or below an address, and how far past it the address is.
+    /// Of several labels at the same address, the first by name wins.
+    pub fn name_at(&self, addr: u16) -> Option<(&str, u16)> {
+        self.iter()
+            .filter(|(_, a)| *a <= addr)
+            .fold(None, |best: Option<(&str, u16)>, (name, a)| match best {
+                Some((_, b)) if b >= a => best,
+                _ => Some((name, a)),
+            })
+            .map(|(name, a)| (name, addr - a))
+    }
+
+    /// An address as the label it's in, such as `print+3`, or in hex if it's in none
+    pub fn describe(&self, addr: u16) -> String {
+        match self.name_at(addr) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, offset)) => format!("{}+{}", name, offset),
+            None => format!("{:04x}", addr),
+        }
+    }
+}
+
+// A number as assemblers write them: `$1F`, `0x1F`, `1Fh` or decimal
+pub(crate) fn parse_number(text: &str) -> Option<u16> {
+    parse_wide(text).and_then(|n| u16::try_from(n).ok())
+}
+
+// A number as parse_number reads it, which may be too big for an address, as symbol files
+// write their constants with 32 bits
+fn parse_wide(text: &str) -> Option<u32> {
+    let lower = text.to_ascii_lowercase();
+    let (digits, radix) = if let Some(hex) = lower.strip_prefix('$') {
+        (hex, 16)
+    } else if let Some(hex) = lower.strip_prefix("0x") {
+        (hex, 16)
+    } else if let Some(hex) = lower.strip_suffix('h') {
+        (hex, 16)
+    } else {
+        (lower.as_str(), 10)
+    };
+    u32::from_str_radix(digits, radix).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn labels() {
+        let symbols = Symbols::parse_labels("b: EQU 0x10\na:\tequ 10h\n\nc: equ 3\n").unwrap();
+        assert_eq!(
+            vec![("a", 0x10), ("b", 0x10), ("c", 3)],
+            symbols.iter().collect::<Vec<_>>()
+        );
+        assert_eq!(Some(("a", 0)), symbols.name_at(0x10));
+        assert_eq!(Some(("c", 1)), symbols.name_at(4));
+        assert_eq!(None, symbols.name_at(2));
+        assert_eq!("a", symbols.describe(0x10));
+        assert_eq!("c+1", symbols.describe(4));
+        assert_eq!("0002", symbols.describe(2));
+
+        assert_eq!(
+            Err(SymbolError { line: 2 }),
+            Symbols::parse_labels("a: equ 1\nb = 2\n").map(|_| ())
+        );
+    }
+
+    #[test]
+    fn symbol_files() {
+        // sjasmplus --sym, with a constant too big to be an address
+        let sym = "; File created by sjasmplus\nmain: EQU 0x00008000\nmain.loop: EQU 0x00008003\n\
+                   BIG: EQU 0x00012345\n";
+        let symbols = Symbols::parse(sym).unwrap();
+        assert_eq!(
+            vec![("main", 0x8000), ("main.loop", 0x8003)],
+            symbols.iter().collect::<Vec<_>>()
+        );
+
+        // z88dk
+        let map = "__head                          = $0000 ; const, public, def, , ,\n\
+                   _print                          = $012A ; addr, public, , print_c, code_compiler, print.c:3\n";
+        let symbols = Symbols::parse(map).unwrap();
+        assert_eq!(
+            vec![("__head", 0x0000), ("_print", 0x012A)],
+            symbols.iter().collect::<Vec<_>>()
+        );
+
+        // no$ and WLA-DX, with the addresses in hex
+        let sym = "; no$gmb symbolic information\n[labels]\n00:0150 start\n01:4010 bank_one\n\
+                   [definitions]\n00000010 SIZE\n[breakpoints]\n00:0150\n";
+        let symbols = Symbols::parse(sym).unwrap();
+        assert_eq!(
+            vec![("bank_one", 0x4010), ("start", 0x0150)],
+            symbols.iter().collect::<Vec<_>>()
+        );
+
+        // Label files are symbol files too
+        let labels = "a: equ 1\nb: equ 2\n";
+        assert_eq!(
+            Symbols::parse_labels(labels)
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>(),
+            Symbols::parse(labels).unwrap().iter().collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            Err(SymbolError { line: 2 }),
+            Symbols::parse("a = 1\nb = two\n").map(|_| ())
+        );
+        assert_eq!(
+            Err(SymbolError { line: 1 }),
+            Symbols::parse("start\n").map(|_| ())
+        );
+    }
+}
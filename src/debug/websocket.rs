@@ -0,0 +1,321 @@
+//! The server side of a WebSocket (RFC 6455), enough to carry the debug protocol's text
+//! messages. It works over any stream, though in practice that's a TcpStream.
+//! Messages are sent whole and unmasked, as servers do. Pings are answered as they arrive, and
+//! a close is answered and reported as the end of the messages.
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// Nothing the debug protocol sends is anywhere near this
+const MAX_MESSAGE: u64 = 16 * 1024 * 1024;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+const FIN: u8 = 0x80;
+
+/// A connection that has finished its opening handshake
+pub struct WebSocket<S: Read + Write> {
+    stream: BufReader<S>,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+impl<S: Read + Write> WebSocket<S> {
+    /// Read the client's HTTP upgrade request, and agree to it
+    pub fn accept(stream: S) -> io::Result<Self> {
+        let mut stream = BufReader::new(stream);
+        let mut key = None;
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line)? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("sec-websocket-key") {
+                    key = Some(value.trim().to_string());
+                }
+            }
+        }
+        let key = key.ok_or_else(|| invalid("not a WebSocket upgrade request"))?;
+        let accept = base64(&sha1(format!("{}{}", key, GUID).as_bytes()));
+        write!(
+            stream.get_mut(),
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n",
+            accept
+        )?;
+        stream.get_mut().flush()?;
+        Ok(Self { stream })
+    }
+
+    /// Wait for the next text message. Returns None once the client closes the connection.
+    pub fn receive(&mut self) -> io::Result<Option<String>> {
+        let mut message = vec![];
+        loop {
+            let (fin, opcode, payload) = self.read_frame()?;
+            match opcode {
+                TEXT | CONTINUATION => {
+                    message.extend_from_slice(&payload);
+                    if message.len() as u64 > MAX_MESSAGE {
+                        return Err(invalid("WebSocket message too long"));
+                    }
+                    if fin {
+                        return String::from_utf8(message)
+                            .map(Some)
+                            .map_err(|_| invalid("WebSocket text isn't UTF-8"));
+                    }
+                }
+                PING => self.write_frame(PONG, &payload)?,
+                PONG => (),
+                CLOSE => {
+                    // Echo the status code back
+                    self.write_frame(CLOSE, &payload[..payload.len().min(2)])?;
+                    return Ok(None);
+                }
+                _ => return Err(invalid("unsupported WebSocket frame")),
+            }
+        }
+    }
+
+    /// Send a text message
+    pub fn send(&mut self, text: &str) -> io::Result<()> {
+        self.write_frame(TEXT, text.as_bytes())
+    }
+
+    fn read_frame(&mut self) -> io::Result<(bool, u8, Vec<u8>)> {
+        let mut head = [0; 2];
+        self.stream.read_exact(&mut head)?;
+        let len = match head[1] & 0x7F {
+            126 => {
+                let mut len = [0; 2];
+                self.stream.read_exact(&mut len)?;
+                u64::from(u16::from_be_bytes(len))
+            }
+            127 => {
+                let mut len = [0; 8];
+                self.stream.read_exact(&mut len)?;
+                u64::from_be_bytes(len)
+            }
+            len => u64::from(len),
+        };
+        if len > MAX_MESSAGE {
+            return Err(invalid("WebSocket message too long"));
+        }
+        // Clients always mask what they send
+        let mut mask = [0; 4];
+        if head[1] & 0x80 != 0 {
+            self.stream.read_exact(&mut mask)?;
+        }
+        let mut payload = vec![0; len as usize];
+        self.stream.read_exact(&mut payload)?;
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+        Ok((head[0] & FIN != 0, head[0] & 0x0F, payload))
+    }
+
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = vec![FIN | opcode];
+        match payload.len() {
+            len if len < 126 => frame.push(len as u8),
+            len if len <= 0xFFFF => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        let stream = self.stream.get_mut();
+        stream.write_all(&frame)?;
+        stream.flush()
+    }
+}
+
+// The handshake is the only thing that needs these, so the simplest versions will do
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [
+        0x6745_2301,
+        0xEFCD_AB89,
+        0x98BA_DCFE,
+        0x1032_5476,
+        0xC3D2_E1F0,
+    ];
+    let mut padded = data.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in padded.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ED9_EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1B_BCDC),
+                _ => (b ^ c ^ d, 0xCA62_C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (i, v) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&v.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | u32::from(*b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(char::from(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize]));
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    // Reads from a script of what the client sends, and records what the server writes
+    struct Client {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Client {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Client {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn masked(head: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![head, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn hashes() {
+        assert_eq!("", base64(b""));
+        assert_eq!("Zm9vYg==", base64(b"foob"));
+        assert_eq!("Zm9vYmE=", base64(b"fooba"));
+        assert_eq!(
+            "2fd4e1c67a2d28fced849ee1bb76e7391b93eb12",
+            sha1(b"The quick brown fox jumps over the lazy dog")
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>()
+        );
+    }
+
+    #[test]
+    fn session() {
+        // The example from RFC 6455
+        let mut input = b"GET /debug HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+            Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Version: 13\r\n\r\n"
+            .to_vec();
+        input.extend(masked(TEXT, b"hel"));
+        input.extend(masked(PING, b"?"));
+        input.extend(masked(FIN | CONTINUATION, b"lo"));
+        input.extend(masked(FIN | CLOSE, &[0x03, 0xE8]));
+        let client = Client {
+            input: Cursor::new(input),
+            output: vec![],
+        };
+
+        let mut ws = WebSocket::accept(client).unwrap();
+        let response = String::from_utf8(ws.stream.get_ref().output.clone()).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 "));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        ws.stream.get_mut().output.clear();
+
+        assert_eq!(Some("hello".to_string()), ws.receive().unwrap());
+        ws.send("hi").unwrap();
+        assert_eq!(None, ws.receive().unwrap());
+        assert_eq!(
+            vec![
+                FIN | PONG,
+                1,
+                b'?',
+                FIN | TEXT,
+                2,
+                b'h',
+                b'i',
+                FIN | CLOSE,
+                2,
+                0x03,
+                0xE8
+            ],
+            ws.stream.get_ref().output
+        );
+    }
+
+    #[test]
+    fn not_websocket() {
+        let client = Client {
+            input: Cursor::new(b"GET / HTTP/1.1\r\n\r\n".to_vec()),
+            output: vec![],
+        };
+        assert!(WebSocket::accept(client).is_err());
+    }
+}
@@ -0,0 +1,16 @@
+//! Devices for guest programs: things a small program wants from its host, such as a source
+//! of random numbers, that no particular machine provides, and add-ons that plug into a
+//! machine, such as printers and tape decks. Most are an InputDevice or OutputDevice (or
+//! both), to be installed on whichever port the program expects.
+
+pub mod assertions;
+pub mod cassette;
+pub mod centronics;
+pub mod divmmc;
+pub mod files;
+pub mod interface1;
+pub mod log_console;
+pub mod printer;
+pub mod rng;
+pub mod rtc;
+pub mod tcp;
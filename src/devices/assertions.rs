@@ -0,0 +1,225 @@
+//! Assertions for test programs: a program running in the emulator checks itself and reports
+//! through a few ports, and the host turns the reports into a Rust test's pass or fail.
+//! From the base port (0xF0, say) a program writes:
+//!
+//! | Port     | Value                                                                      |
+//! |----------|----------------------------------------------------------------------------|
+//! | base     | A status: 0 for a check that passed, 1 to 0xFE for one that failed with that code, 0xFF when the tests are done |
+//! | base + 1 | The low byte of the address of a message, ending with a zero byte          |
+//! | base + 2 | The high byte: the message goes with the next status                       |
+//! | base + 3 | A character of the message, for a program that would rather send it a byte at a time |
+//!
+//! The device only sees the address of a message, so Assertions::run, which reads it from
+//! memory, should run the program.
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::z80::io::OutputDevice;
+use crate::z80::Z80;
+
+const DONE: u8 = 0xFF;
+// The longest message read from memory
+const MAX_MESSAGE: u16 = 256;
+
+/// A check a program made
+#[derive(Debug, PartialEq, Clone)]
+pub struct Check {
+    /// 0 if it passed, or the program's code for the failure
+    pub code: u8,
+    pub message: String,
+}
+
+impl Check {
+    pub fn passed(&self) -> bool {
+        self.code == 0
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.passed(), self.message.is_empty()) {
+            (true, true) => write!(f, "passed"),
+            (true, false) => write!(f, "passed: {}", self.message),
+            (false, true) => write!(f, "failed with code {}", self.code),
+            (false, false) => write!(f, "failed with code {}: {}", self.code, self.message),
+        }
+    }
+}
+
+/// How a run of a test program went
+#[derive(Debug, PartialEq, Clone)]
+pub struct Outcome {
+    /// Every check, in order
+    pub checks: Vec<Check>,
+    /// Whether the program said it was done, rather than stopping or running out of time
+    pub done: bool,
+}
+
+impl Outcome {
+    /// The checks that failed
+    pub fn failures(&self) -> impl Iterator<Item = &Check> {
+        self.checks.iter().filter(|check| !check.passed())
+    }
+
+    /// Whether the program finished with every check passing
+    pub fn passed(&self) -> bool {
+        self.done && self.failures().next().is_none()
+    }
+
+    /// Assert that the program finished with every check passing, in a Rust test
+    ///
+    /// # Panics
+    /// Panics listing the failures, or if the program didn't finish
+    pub fn assert_passed(&self) {
+        let failures: Vec<String> = self.failures().map(|c| c.to_string()).collect();
+        assert!(
+            failures.is_empty(),
+            "{} of {} checks failed:\n{}",
+            failures.len(),
+            self.checks.len(),
+            failures.join("\n")
+        );
+        assert!(self.done, "the test program didn't finish");
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    checks: Vec<Check>,
+    message: String,
+    pointer: [u8; 2],
+    // The address of a message, until it's been read
+    pending: Option<u16>,
+    done: bool,
+}
+
+/// The device, for a test program to report to. Clones share the same reports.
+#[derive(Debug, Clone, Default)]
+pub struct Assertions {
+    state: Rc<RefCell<State>>,
+}
+
+/// One of the device's ports
+#[derive(Debug, Clone)]
+pub struct Port(Assertions, u8);
+
+impl OutputDevice for Port {
+    fn output(&self, val: u8) {
+        let mut state = self.0.state.borrow_mut();
+        match self.1 {
+            0 if val == DONE => state.done = true,
+            0 => {
+                let message = std::mem::take(&mut state.message);
+                state.checks.push(Check { code: val, message });
+            }
+            1 => state.pointer[0] = val,
+            2 => {
+                state.pointer[1] = val;
+                state.pending = Some(u16::from_le_bytes(state.pointer));
+            }
+            _ => state.message.push(char::from(val)),
+        }
+    }
+}
+
+impl Assertions {
+    /// Install the device on four ports from `base`. For example:
+    /// ```
+    /// use zeerust::devices::assertions::Assertions;
+    /// use zeerust::z80::Z80;
+    ///
+    /// let mut z80 = Z80::default();
+    /// let assertions = Assertions::default();
+    /// assertions.install(&mut z80, 0xF0);
+    /// z80.load(&[
+    ///     0x3E, 0x02, 0xC6, 0x02, // LD A, 2; ADD A, 2
+    ///     0xD6, 0x04, 0xD3, 0xF0, // SUB 4; OUT (0xF0), A: passes if A is 0
+    ///     0x3E, 0xFF, 0xD3, 0xF0, // LD A, 0xFF; OUT (0xF0), A: done
+    ///     0x76, // HALT
+    /// ]);
+    /// assertions.run(&mut z80, 1_000_000).assert_passed();
+    ///```
+    pub fn install(&self, z80: &mut Z80, base: u8) {
+        for port in 0..4 {
+            let device = Port(self.clone(), port);
+            z80.install_output(base.wrapping_add(port), Box::new(device));
+        }
+    }
+
+    /// Run the program until it says it's done, halts with interrupts off, or has run for
+    /// `limit` T-states, reading the messages it points to as it goes
+    pub fn run(&self, z80: &mut Z80, limit: u64) -> Outcome {
+        let end = z80.tstates().saturating_add(limit);
+        while !self.state.borrow().done && z80.tstates() < end {
+            if z80.is_halted() && !z80.iff1() && !z80.is_nmi_pending() {
+                break;
+            }
+            z80.step();
+            self.read_message(z80);
+        }
+        self.outcome()
+    }
+
+    /// The reports so far
+    pub fn outcome(&self) -> Outcome {
+        let state = self.state.borrow();
+        Outcome {
+            checks: state.checks.clone(),
+            done: state.done,
+        }
+    }
+
+    // Read the message the program just pointed to, while it's still there
+    fn read_message(&self, z80: &Z80) {
+        let addr = match self.state.borrow_mut().pending.take() {
+            Some(addr) => addr,
+            None => return,
+        };
+        let message: String = (0..MAX_MESSAGE)
+            .map_while(|i| z80.peek(addr.wrapping_add(i)).filter(|b| *b != 0))
+            .map(char::from)
+            .collect();
+        self.state.borrow_mut().message.push_str(&message);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reports() {
+        let mut z80 = Z80::default();
+        let assertions = Assertions::default();
+        assertions.install(&mut z80, 0xF0);
+        z80.load(&[
+            0x3E, 0x00, 0xD3, 0xF0, // 0000 LD A, 0; OUT (0xF0), A
+            0x3E, 0x20, 0xD3, 0xF1, // 0004 LD A, 0x20; OUT (0xF1), A
+            0x3E, 0x00, 0xD3, 0xF2, // 0008 LD A, 0x00; OUT (0xF2), A
+            0x3E, 0x21, 0xD3, 0xF3, // 000C LD A, '!'; OUT (0xF3), A
+            0x3E, 0x07, 0xD3, 0xF0, // 0010 LD A, 7; OUT (0xF0), A
+            0x76, // 0014 HALT
+        ]);
+        z80.poke(0x0020, b'A');
+        z80.poke(0x0021, b'=');
+        z80.poke(0x0022, b'1');
+
+        let outcome = assertions.run(&mut z80, 1000);
+        assert!(!outcome.done);
+        assert!(!outcome.passed());
+        assert_eq!(
+            vec!["passed", "failed with code 7: A=1!"],
+            outcome
+                .checks
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<_>>()
+        );
+        let failure = std::panic::catch_unwind(|| outcome.assert_passed()).unwrap_err();
+        assert_eq!(
+            Some(&"1 of 2 checks failed:\nfailed with code 7: A=1!".to_string()),
+            failure.downcast_ref::<String>()
+        );
+    }
+}
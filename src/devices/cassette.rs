@@ -0,0 +1,408 @@
+//! A cassette deck: plays a recording into the EAR input, and records the MIC output.
+//! Recordings are WAV files, so real tapes can be loaded from a sampled recording and what a
+//! program saves can be kept as audio. Playback squares the audio up with a little hysteresis,
+//! as the tape input's comparator does, so noise around the zero line doesn't add edges.
+//!
+//! The deck keeps time in T-states, and has to be ticked as the processor runs: add it to a
+//! Scheduler at the CPU clock, or tick it with the T-states of each frame. The EAR and MIC
+//! ports take a mask, for the bit the machine has them on (bit 6 of port 0xFE for EAR on a
+//! Spectrum, say), and other devices on the same port can OR their bits in.
+use std::cell::RefCell;
+use std::convert::TryInto;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::input::TapeControl;
+use crate::scheduler::Coprocessor;
+use crate::z80::io::{InputDevice, OutputDevice};
+
+// How far from the zero line the audio has to swing to change the level
+const HYSTERESIS: i16 = 1024;
+// How loud the recorded square wave is
+const AMPLITUDE: i16 = 0x4000;
+
+#[derive(Debug, PartialEq)]
+pub enum WavError {
+    /// The data isn't a RIFF WAVE file
+    NotWav,
+    /// The audio isn't 8 or 16-bit PCM
+    Unsupported,
+    /// The data ends in the middle of a chunk, or has no audio
+    Truncated,
+}
+
+impl fmt::Display for WavError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WavError::NotWav => write!(f, "not a WAV file"),
+            WavError::Unsupported => write!(f, "only 8 and 16-bit PCM WAV files are supported"),
+            WavError::Truncated => write!(f, "WAV file is truncated"),
+        }
+    }
+}
+
+impl std::error::Error for WavError {}
+
+/// Mono audio, as 16-bit samples
+#[derive(Debug, PartialEq, Clone)]
+pub struct Wav {
+    /// Samples per second
+    pub rate: u32,
+    pub samples: Vec<i16>,
+}
+
+impl Wav {
+    /// Read an 8 or 16-bit PCM WAV file, mixing the channels down to one
+    pub fn from_bytes(data: &[u8]) -> Result<Self, WavError> {
+        if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+            return Err(WavError::NotWav);
+        }
+        let mut format = None;
+        let mut audio = None;
+        let mut rest = &data[12..];
+        while rest.len() >= 8 {
+            let len = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+            let body = rest.get(8..8 + len).ok_or(WavError::Truncated)?;
+            match &rest[0..4] {
+                b"fmt " => format = Some(body),
+                b"data" => audio = Some(body),
+                _ => (),
+            }
+            // Chunks are padded to an even length
+            rest = rest.get(8 + len + len % 2..).unwrap_or(&[]);
+        }
+        let (format, audio) = format.zip(audio).ok_or(WavError::Truncated)?;
+        if format.len() < 16 {
+            return Err(WavError::Truncated);
+        }
+        let word = |i: usize| u16::from_le_bytes([format[i], format[i + 1]]);
+        let (tag, channels, bits) = (word(0), usize::from(word(2)), word(14));
+        let rate = u32::from_le_bytes(format[4..8].try_into().unwrap());
+        let size = match bits {
+            8 => 1,
+            16 => 2,
+            _ => return Err(WavError::Unsupported),
+        };
+        if tag != 1 || channels == 0 {
+            return Err(WavError::Unsupported);
+        }
+        let samples = audio
+            .chunks_exact(channels * size)
+            .map(|frame| {
+                let sum: i32 = frame
+                    .chunks_exact(size)
+                    .map(|s| match s {
+                        [b] => (i32::from(*b) - 0x80) << 8,
+                        _ => i32::from(i16::from_le_bytes([s[0], s[1]])),
+                    })
+                    .sum();
+                (sum / channels as i32) as i16
+            })
+            .collect();
+        Ok(Self { rate, samples })
+    }
+
+    /// The audio as a 16-bit mono PCM WAV file
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let len = self.samples.len() as u32 * 2;
+        let mut data = b"RIFF".to_vec();
+        data.extend_from_slice(&(36 + len).to_le_bytes());
+        data.extend_from_slice(b"WAVEfmt ");
+        data.extend_from_slice(&16u32.to_le_bytes());
+        for word in &[1u16, 1] {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        data.extend_from_slice(&self.rate.to_le_bytes());
+        data.extend_from_slice(&(self.rate * 2).to_le_bytes());
+        for word in &[2u16, 16] {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        data.extend_from_slice(b"data");
+        data.extend_from_slice(&len.to_le_bytes());
+        for sample in &self.samples {
+            data.extend_from_slice(&sample.to_le_bytes());
+        }
+        data
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    clock: u32,
+    now: u64,
+    tape: Option<Wav>,
+    playing: bool,
+    // How far into the tape, in T-states
+    position: u64,
+    ear: bool,
+    // When recording started, and every change of the MIC level since
+    recording: Option<(u64, Vec<(u64, bool)>)>,
+    mic: bool,
+}
+
+/// A cassette deck for a processor running at `clock` Hz. Clones share the same deck.
+#[derive(Debug, Clone)]
+pub struct Cassette {
+    state: Rc<RefCell<State>>,
+}
+
+/// The EAR input of a Cassette: reads as the mask while the level is high, or 0
+#[derive(Debug, Clone)]
+pub struct Ear(Cassette, u8);
+
+/// The MIC output of a Cassette: the level is high while any bit of the mask is set
+#[derive(Debug, Clone)]
+pub struct Mic(Cassette, u8);
+
+impl Cassette {
+    /// A deck with no tape in it, for a processor running at `clock` Hz. For example:
+    /// ```
+    /// use zeerust::devices::cassette::{Cassette, Wav};
+    /// use zeerust::scheduler::Coprocessor;
+    /// use zeerust::z80::io::InputDevice;
+    ///
+    /// let deck = Cassette::new(3_500_000);
+    /// let ear = deck.ear_port(0x40);
+    /// // A 1kHz square wave, sampled at 8kHz
+    /// let samples = (0..8000).map(|i| if i % 8 < 4 { 20000 } else { -20000 }).collect();
+    /// deck.insert(Wav { rate: 8000, samples });
+    /// deck.play();
+    /// assert_eq!(0x40, ear.input());
+    /// deck.tick(2000); // Half a millisecond later
+    /// assert_eq!(0, ear.input());
+    ///```
+    pub fn new(clock: u32) -> Self {
+        Self {
+            state: Rc::new(RefCell::new(State {
+                clock,
+                now: 0,
+                tape: None,
+                playing: false,
+                position: 0,
+                ear: false,
+                recording: None,
+                mic: false,
+            })),
+        }
+    }
+
+    /// Put a tape in, rewound and stopped
+    pub fn insert(&self, tape: Wav) {
+        let mut state = self.state.borrow_mut();
+        state.tape = Some(tape);
+        state.playing = false;
+        state.position = 0;
+    }
+
+    /// Take the tape out
+    pub fn eject(&self) -> Option<Wav> {
+        let mut state = self.state.borrow_mut();
+        state.playing = false;
+        state.tape.take()
+    }
+
+    /// Start playing the tape from where it is
+    pub fn play(&self) {
+        self.state.borrow_mut().playing = true;
+    }
+
+    /// Stop playing or recording
+    pub fn stop(&self) {
+        let mut state = self.state.borrow_mut();
+        state.playing = false;
+        state.recording = None;
+    }
+
+    /// Go back to the start of the tape
+    pub fn rewind(&self) {
+        self.state.borrow_mut().position = 0;
+    }
+
+    /// Work a button of the deck
+    pub fn control(&self, control: TapeControl) {
+        match control {
+            TapeControl::Play => self.play(),
+            TapeControl::Stop => self.stop(),
+            TapeControl::Rewind => self.rewind(),
+            TapeControl::Record => self.record(),
+        }
+    }
+
+    /// Whether the tape is playing and hasn't run out
+    pub fn is_playing(&self) -> bool {
+        let state = self.state.borrow();
+        state.playing && state.sample().is_some()
+    }
+
+    /// Start recording the MIC output, forgetting anything recorded before
+    pub fn record(&self) {
+        let mut state = self.state.borrow_mut();
+        let start = (state.now, vec![(state.now, state.mic)]);
+        state.recording = Some(start);
+    }
+
+    /// What's been recorded so far, at `rate` samples per second
+    pub fn recording(&self, rate: u32) -> Option<Wav> {
+        let state = self.state.borrow();
+        let (start, changes) = state.recording.as_ref()?;
+        let length = (state.now - start) * u64::from(rate) / u64::from(state.clock);
+        let mut samples = Vec::with_capacity(length as usize);
+        let mut next = changes.iter().peekable();
+        let mut level = false;
+        for i in 0..length {
+            let tstate = start + i * u64::from(state.clock) / u64::from(rate);
+            while let Some((_, l)) = next.next_if(|(t, _)| *t <= tstate) {
+                level = *l;
+            }
+            samples.push(if level { AMPLITUDE } else { -AMPLITUDE });
+        }
+        Some(Wav { rate, samples })
+    }
+
+    /// The EAR input, reading as `mask` while the level is high
+    pub fn ear_port(&self, mask: u8) -> Ear {
+        Ear(self.clone(), mask)
+    }
+
+    /// The MIC output, high while any bit of `mask` is set
+    pub fn mic_port(&self, mask: u8) -> Mic {
+        Mic(self.clone(), mask)
+    }
+
+    /// The level of the EAR input
+    pub fn ear(&self) -> bool {
+        let mut state = self.state.borrow_mut();
+        if state.playing {
+            if let Some(sample) = state.sample() {
+                if sample > HYSTERESIS {
+                    state.ear = true;
+                } else if sample < -HYSTERESIS {
+                    state.ear = false;
+                }
+            }
+        }
+        state.ear
+    }
+}
+
+impl State {
+    // The sample of the tape under the head, if there's any tape left
+    fn sample(&self) -> Option<i16> {
+        let tape = self.tape.as_ref()?;
+        let i = self.position * u64::from(tape.rate) / u64::from(self.clock);
+        tape.samples.get(i as usize).copied()
+    }
+}
+
+impl Coprocessor for Cassette {
+    /// Run the tape on by some T-states
+    fn tick(&self, cycles: u64) {
+        let mut state = self.state.borrow_mut();
+        state.now += cycles;
+        if state.playing {
+            state.position += cycles;
+        }
+    }
+}
+
+impl InputDevice for Ear {
+    fn input(&self) -> u8 {
+        if self.0.ear() {
+            self.1
+        } else {
+            0
+        }
+    }
+}
+
+impl OutputDevice for Mic {
+    fn output(&self, val: u8) {
+        let mut state = self.0.state.borrow_mut();
+        let level = val & self.1 != 0;
+        if level == state.mic {
+            return;
+        }
+        state.mic = level;
+        let now = state.now;
+        if let Some((_, changes)) = &mut state.recording {
+            changes.push((now, level));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn wav() {
+        let wav = Wav {
+            rate: 22050,
+            samples: vec![0, 100, -100, i16::MAX],
+        };
+        let data = wav.to_bytes();
+        assert_eq!(44 + 8, data.len());
+        assert_eq!(Ok(wav), Wav::from_bytes(&data));
+
+        // 8-bit stereo, with a chunk to skip and an odd one to pad
+        let mut data = b"RIFF\0\0\0\0WAVEfmt \x10\0\0\0\x01\0\x02\0\x44\xac\0\0".to_vec();
+        data.extend_from_slice(b"\x10\xb1\x02\0\x02\0\x08\0LIST\x01\0\0\0x\0");
+        data.extend_from_slice(b"data\x04\0\0\0\x80\x80\xff\xc0");
+        let wav = Wav::from_bytes(&data).unwrap();
+        assert_eq!(44100, wav.rate);
+        assert_eq!(vec![0, 0x5F80], wav.samples);
+
+        data[20] = 3;
+        assert_eq!(Err(WavError::Unsupported), Wav::from_bytes(&data));
+        assert_eq!(Err(WavError::Truncated), Wav::from_bytes(&data[..50]));
+        assert_eq!(Err(WavError::NotWav), Wav::from_bytes(b"RIFF\0\0\0\0AVI "));
+    }
+
+    #[test]
+    fn playing() {
+        let deck = Cassette::new(1000);
+        let ear = deck.ear_port(0x40);
+        // Noise near zero leaves the level where it was
+        deck.insert(Wav {
+            rate: 100,
+            samples: vec![5000, 500, -500, -5000, 100],
+        });
+        assert_eq!(0, ear.input());
+        deck.play();
+        let levels: Vec<u8> = (0..5)
+            .map(|_| {
+                let level = ear.input();
+                deck.tick(10);
+                level
+            })
+            .collect();
+        assert_eq!(vec![0x40, 0x40, 0x40, 0, 0], levels);
+        assert!(!deck.is_playing());
+
+        deck.rewind();
+        deck.stop();
+        deck.tick(100);
+        deck.play();
+        assert_eq!(0x40, ear.input());
+        assert!(deck.eject().is_some());
+    }
+
+    #[test]
+    fn recording() {
+        let deck = Cassette::new(1000);
+        let mic = deck.mic_port(0x08);
+        assert_eq!(None, deck.recording(100));
+        deck.record();
+        deck.tick(20);
+        mic.output(0x08);
+        deck.tick(15);
+        mic.output(0x0F);
+        mic.output(0x00);
+        deck.tick(15);
+        let wav = deck.recording(100).unwrap();
+        let high = AMPLITUDE;
+        assert_eq!(vec![-high, -high, high, high, -high], wav.samples);
+        deck.stop();
+        assert_eq!(None, deck.recording(100));
+    }
+}
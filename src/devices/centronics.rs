@@ -0,0 +1,178 @@
+//! A Centronics parallel port, with its handshake, for printer drivers that poll it.
+//! There are three ports: the data latch, a control port whose bit 0 is STROBE (active low),
+//! and a status port. The printer takes the latched byte when STROBE falls, and is then BUSY
+//! for a few reads of the status, after which it pulses ACK (active low) for one read.
+//! A byte strobed while the printer is busy is lost, as it would be on real hardware, so a
+//! driver that doesn't wait for BUSY to clear drops characters.
+//!
+//! Whatever the printer receives goes on to any OutputDevice: usually a Printer.
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::z80::io::{InputDevice, OutputDevice};
+
+/// Set in the status while the printer is busy
+pub const BUSY: u8 = 0x01;
+/// Cleared in the status for one read, when the printer has finished with a byte
+pub const ACK: u8 = 0x02;
+/// Set in the status while the printer is on line
+pub const SELECT: u8 = 0x04;
+
+/// Set in the control port when STROBE is high (inactive)
+pub const STROBE: u8 = 0x01;
+
+struct State {
+    printer: Box<dyn OutputDevice>,
+    data: u8,
+    strobe: bool,
+    busy_reads: u32,
+    // Reads of the status left until the printer is ready again
+    busy: u32,
+    acknowledging: bool,
+}
+
+/// A parallel port, as its data latch; see control_port and status_port for the others.
+/// Clones share the same port.
+#[derive(Clone)]
+pub struct Centronics {
+    state: Rc<RefCell<State>>,
+}
+
+/// The control port of a Centronics port
+#[derive(Clone)]
+pub struct CentronicsControl(Centronics);
+
+/// The status port of a Centronics port
+#[derive(Clone)]
+pub struct CentronicsStatus(Centronics);
+
+impl Centronics {
+    /// A port connected to a printer, which is busy for one read of the status after each
+    /// byte. For example:
+    /// ```
+    /// use zeerust::devices::centronics::Centronics;
+    /// use zeerust::z80::io::BufOutput;
+    /// use zeerust::z80::Z80;
+    ///
+    /// let mut z80 = Z80::default();
+    /// let printed = BufOutput::default();
+    /// let port = Centronics::new(Box::new(printed.clone()));
+    /// z80.install_output(0x50, Box::new(port.clone()));
+    /// z80.install_output(0x51, Box::new(port.control_port()));
+    /// z80.install_input(0x52, Box::new(port.status_port()));
+    /// z80.load(&[
+    ///     0xDB, 0x52, // wait: IN A, (0x52)
+    ///     0xE6, 0x01, // AND BUSY
+    ///     0x20, 0xFA, // JR NZ, wait
+    ///     0x3E, 0x41, // LD A, 'A'
+    ///     0xD3, 0x50, // OUT (0x50), A
+    ///     0xAF,       // XOR A
+    ///     0xD3, 0x51, // OUT (0x51), A
+    ///     0x3C,       // INC A
+    ///     0xD3, 0x51, // OUT (0x51), A
+    ///     0x76,       // HALT
+    /// ]);
+    /// z80.run();
+    /// assert_eq!(vec![b'A'], printed.result());
+    ///```
+    pub fn new(printer: Box<dyn OutputDevice>) -> Self {
+        Self {
+            state: Rc::new(RefCell::new(State {
+                printer,
+                data: 0,
+                strobe: true,
+                busy_reads: 1,
+                busy: 0,
+                acknowledging: false,
+            })),
+        }
+    }
+
+    /// Stay busy for this many reads of the status after each byte, like a slower printer
+    pub fn busy_for(self, reads: u32) -> Self {
+        self.state.borrow_mut().busy_reads = reads;
+        self
+    }
+
+    /// The port to set STROBE with
+    pub fn control_port(&self) -> CentronicsControl {
+        CentronicsControl(self.clone())
+    }
+
+    /// The port to read BUSY and ACK from
+    pub fn status_port(&self) -> CentronicsStatus {
+        CentronicsStatus(self.clone())
+    }
+}
+
+impl OutputDevice for Centronics {
+    /// Latch a byte, for the next STROBE
+    fn output(&self, val: u8) {
+        self.state.borrow_mut().data = val;
+    }
+}
+
+impl OutputDevice for CentronicsControl {
+    /// Set STROBE: the printer takes the latched byte as it falls, if it isn't busy
+    fn output(&self, val: u8) {
+        let mut state = self.0.state.borrow_mut();
+        let strobe = val & STROBE != 0;
+        let falling = state.strobe && !strobe;
+        state.strobe = strobe;
+        if falling && state.busy == 0 {
+            state.printer.output(state.data);
+            state.busy = state.busy_reads;
+            state.acknowledging = true;
+        }
+    }
+}
+
+impl InputDevice for CentronicsStatus {
+    /// BUSY, ACK and SELECT
+    fn input(&self) -> u8 {
+        let mut state = self.0.state.borrow_mut();
+        if state.busy > 0 {
+            state.busy -= 1;
+            return BUSY | ACK | SELECT;
+        }
+        if state.acknowledging {
+            state.acknowledging = false;
+            return SELECT;
+        }
+        ACK | SELECT
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::z80::io::BufOutput;
+
+    #[test]
+    fn handshake() {
+        let printed = BufOutput::default();
+        let port = Centronics::new(Box::new(printed.clone())).busy_for(2);
+        let (control, status) = (port.control_port(), port.status_port());
+        assert_eq!(ACK | SELECT, status.input());
+
+        port.output(b'h');
+        control.output(STROBE);
+        assert!(printed.result().is_empty());
+        control.output(0);
+        assert_eq!(vec![b'h'], printed.result());
+
+        // Strobed while busy, and lost
+        port.output(b'x');
+        control.output(STROBE);
+        control.output(0);
+        control.output(STROBE);
+        assert_eq!(BUSY | ACK | SELECT, status.input());
+        assert_eq!(BUSY | ACK | SELECT, status.input());
+        assert_eq!(SELECT, status.input());
+        assert_eq!(ACK | SELECT, status.input());
+
+        port.output(b'i');
+        control.output(0);
+        assert_eq!(b"hi".to_vec(), printed.result());
+    }
+}
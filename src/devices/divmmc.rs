@@ -0,0 +1,700 @@
+//! The DivMMC: 8K of EEPROM (usually esxDOS), 128K of RAM and an SD card slot for the Spectrum.
+//! Like the Interface 1, its memory is a Bus overlaying the Spectrum's and its automapper a
+//! fetch hook, and both have to be installed (see `interface1` for an example).
+//!
+//! While mapped, the bottom 8K is the EEPROM (or RAM bank 3, read only, once MAPRAM is set)
+//! and 0x2000 to 0x3FFF is the RAM bank picked by the control port, 0xE3: bits 0-3 are the
+//! bank, bit 6 is MAPRAM (which stays set until reset) and bit 7, CONMEM, maps the memory in
+//! regardless of the automapper. The automapper maps it in when an instruction is fetched
+//! from 0x0000, 0x0008, 0x0038, 0x0066, 0x04C6 or 0x0562, after the opcode itself has been
+//! read from the Spectrum's ROM; straight away for anything fetched from 0x3D00 to 0x3DFF;
+//! and maps it out after the opcode is read from 0x1FF8 to 0x1FFF.
+//!
+//! The SD cards are reached over SPI: port 0xE7 selects a card (bit 0 low for the first, bit
+//! 1 for the second), and port 0xEB sends a byte to it, or reads the next byte of its
+//! response. Cards are SDHC, so blocks are addressed by number, and answer the commands a
+//! driver needs to start a card and read and write blocks: CMD0, 1, 8, 9, 12, 16, 17, 18, 24,
+//! 25, 55, 58 and ACMD41. Anything else is answered as an illegal command. A card is any
+//! DiskImage with 512 byte sectors, usually a `RawImage` of a file, and writing to one that's
+//! write protected is answered with a write error.
+//!
+//! Add its `state_chunk` to the processor as well, so checkpoints and savestates keep the
+//! paging and all of the RAM. The cards, and any SPI transfer under way, aren't part of it.
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use crate::disk::DiskImage;
+use crate::z80::bus::Bus;
+use crate::z80::savestate::{write_block, SaveStateError, StateChunk, StateReader};
+use crate::z80::trap::FetchHook;
+
+pub const EEPROM_SIZE: usize = 0x2000;
+const BANK_SIZE: usize = 0x2000;
+const BANKS: usize = 16;
+const BLOCK_SIZE: usize = 512;
+
+pub const CONTROL_PORT: u8 = 0xE3;
+pub const CARD_SELECT_PORT: u8 = 0xE7;
+pub const SPI_PORT: u8 = 0xEB;
+
+/// Control: map the memory in
+pub const CONMEM: u8 = 0x80;
+/// Control: map RAM bank 3 in place of the EEPROM, until reset
+pub const MAPRAM: u8 = 0x40;
+
+const ENTRY_POINTS: [u16; 6] = [0x0000, 0x0008, 0x0038, 0x0066, 0x04C6, 0x0562];
+
+// R1 responses
+const READY: u8 = 0x00;
+const IDLE: u8 = 0x01;
+const ILLEGAL: u8 = 0x04;
+const OUT_OF_RANGE: u8 = 0x40;
+// Data tokens
+const START_BLOCK: u8 = 0xFE;
+const START_MULTIPLE: u8 = 0xFC;
+const STOP_MULTIPLE: u8 = 0xFD;
+const DATA_ACCEPTED: u8 = 0x05;
+
+#[derive(Debug, PartialEq)]
+enum Receiving {
+    Command,
+    // Waiting for the data token of a block to write, then the block and its CRC
+    Block { block: u32, multiple: bool },
+    Data { block: u32, multiple: bool },
+}
+
+struct Card {
+    image: Box<dyn DiskImage>,
+    blocks: u32,
+    idle: bool,
+    app_command: bool,
+    // Bytes received and not yet acted on
+    input: Vec<u8>,
+    receiving: Receiving,
+    output: VecDeque<u8>,
+    // The next block to send, while reading multiple blocks
+    reading: Option<u32>,
+}
+
+impl Card {
+    fn new(image: Box<dyn DiskImage>) -> Self {
+        Self {
+            blocks: image.geometry().sector_count(),
+            image,
+            idle: true,
+            app_command: false,
+            input: vec![],
+            receiving: Receiving::Command,
+            output: VecDeque::new(),
+            reading: None,
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        if self.output.is_empty() {
+            if let Some(block) = self.reading {
+                self.send_block(block);
+                self.reading = Some(block + 1);
+            }
+        }
+        self.output.pop_front().unwrap_or(0xFF)
+    }
+
+    fn write(&mut self, val: u8) {
+        match self.receiving {
+            Receiving::Command => {
+                // Commands start with 01 in the top two bits
+                if self.input.is_empty() && val & 0xC0 != 0x40 {
+                    return;
+                }
+                self.input.push(val);
+                if self.input.len() == 6 {
+                    let command = std::mem::take(&mut self.input);
+                    self.command(command[0] & 0x3F, &command[1..5]);
+                }
+            }
+            Receiving::Block { block, multiple } => match val {
+                START_BLOCK | START_MULTIPLE => {
+                    self.receiving = Receiving::Data { block, multiple }
+                }
+                STOP_MULTIPLE => self.receiving = Receiving::Command,
+                _ => (),
+            },
+            Receiving::Data { block, multiple } => {
+                self.input.push(val);
+                if self.input.len() == BLOCK_SIZE + 2 {
+                    let data = std::mem::take(&mut self.input);
+                    let written = self.write_block(block, &data[..BLOCK_SIZE]);
+                    self.output
+                        .push_back(if written { DATA_ACCEPTED } else { 0x0D });
+                    self.receiving = if multiple {
+                        Receiving::Block {
+                            block: block + 1,
+                            multiple,
+                        }
+                    } else {
+                        Receiving::Command
+                    };
+                }
+            }
+        }
+    }
+
+    fn command(&mut self, command: u8, arg: &[u8]) {
+        let arg_u32 = u32::from_be_bytes([arg[0], arg[1], arg[2], arg[3]]);
+        let app_command = std::mem::replace(&mut self.app_command, false);
+        let status = if self.idle { IDLE } else { READY };
+        self.output.clear();
+        match (app_command, command) {
+            (_, 0) => {
+                self.idle = true;
+                self.reading = None;
+                self.output.push_back(IDLE);
+            }
+            (true, 41) | (false, 1) => {
+                self.idle = false;
+                self.output.push_back(READY);
+            }
+            (_, 8) => self.output.extend(&[status, 0, 0, arg[2], arg[3]]),
+            (_, 9) => {
+                self.output.extend(&[READY, START_BLOCK]);
+                let csd = self.csd();
+                self.output.extend(&csd);
+                self.output.extend(&[0xFF, 0xFF]);
+            }
+            (_, 12) => {
+                self.reading = None;
+                self.output.extend(&[0xFF, READY]);
+            }
+            (_, 16) => self.output.push_back(status),
+            (_, 17) | (_, 18) if arg_u32 >= self.blocks => self.output.push_back(OUT_OF_RANGE),
+            (_, 17) => {
+                self.output.push_back(READY);
+                self.send_block(arg_u32);
+            }
+            (_, 18) => {
+                self.output.push_back(READY);
+                self.reading = Some(arg_u32);
+            }
+            (_, 24) | (_, 25) if arg_u32 >= self.blocks => self.output.push_back(OUT_OF_RANGE),
+            (_, 24) | (_, 25) => {
+                self.output.push_back(READY);
+                self.receiving = Receiving::Block {
+                    block: arg_u32,
+                    multiple: command == 25,
+                };
+            }
+            (_, 55) => {
+                self.app_command = true;
+                self.output.push_back(status);
+            }
+            // The OCR: 3.3V, powered up, and SDHC
+            (_, 58) => self.output.extend(&[status, 0xC0, 0xFF, 0x80, 0x00]),
+            _ => self.output.push_back(status | ILLEGAL),
+        }
+    }
+
+    // A version 2 CSD, which holds the size in 512K units
+    fn csd(&self) -> [u8; 16] {
+        let size = (self.blocks / 1024).saturating_sub(1);
+        let mut csd = [0; 16];
+        csd[0] = 0x40;
+        csd[1] = 0x0E;
+        csd[3] = 0x32;
+        csd[4] = 0x5B;
+        csd[5] = 0x59;
+        csd[7] = (size >> 16) as u8 & 0x3F;
+        csd[8] = (size >> 8) as u8;
+        csd[9] = size as u8;
+        csd[10] = 0x7F;
+        csd[11] = 0x80;
+        csd[12] = 0x0A;
+        csd[13] = 0x40;
+        csd[15] = 0x01;
+        csd
+    }
+
+    fn send_block(&mut self, block: u32) {
+        let mut data = vec![0; BLOCK_SIZE];
+        if self.image.read_block(block, &mut data).is_err() {
+            // A data error token
+            self.output.push_back(0x08);
+            self.reading = None;
+            return;
+        }
+        self.output.push_back(START_BLOCK);
+        self.output.extend(data);
+        self.output.extend(&[0xFF, 0xFF]);
+    }
+
+    fn write_block(&mut self, block: u32, data: &[u8]) -> bool {
+        block < self.blocks && self.image.write_block(block, data).is_ok()
+    }
+}
+
+struct State {
+    eeprom: Vec<u8>,
+    ram: Vec<u8>,
+    control: u8,
+    automapped: bool,
+    // The address whose opcode fetch maps the memory in (or out) once it's been read
+    map_after: Option<(u16, bool)>,
+    cards: [Option<Card>; 2],
+    selected: u8,
+    // Whether mapped, and the control port, when the processor last asked for bank switches
+    seen: (bool, u8),
+}
+
+impl State {
+    fn mapped(&self) -> bool {
+        self.control & CONMEM != 0 || self.automapped
+    }
+
+    fn bank(&self) -> usize {
+        usize::from(self.control & 0x0F)
+    }
+
+    // The byte at an address while mapped, if the DivMMC answers for it
+    fn read(&self, addr: u16) -> Option<u8> {
+        let addr = usize::from(addr);
+        if !self.mapped() || addr >= 2 * BANK_SIZE {
+            return None;
+        }
+        Some(if addr >= BANK_SIZE {
+            self.ram[self.bank() * BANK_SIZE + addr - BANK_SIZE]
+        } else if self.control & MAPRAM != 0 && self.control & CONMEM == 0 {
+            self.ram[3 * BANK_SIZE + addr]
+        } else {
+            self.eeprom[addr]
+        })
+    }
+
+    // Whether the DivMMC took a write: the EEPROM and a mapped bank 3 are read only
+    fn write(&mut self, addr: u16, val: u8) -> bool {
+        let addr = usize::from(addr);
+        if !self.mapped() || addr >= 2 * BANK_SIZE {
+            return false;
+        }
+        let read_only = self.control & MAPRAM != 0 && self.bank() == 3;
+        if addr >= BANK_SIZE && !read_only {
+            let bank = self.bank();
+            self.ram[bank * BANK_SIZE + addr - BANK_SIZE] = val;
+        }
+        true
+    }
+
+    fn card(&mut self) -> Option<&mut Card> {
+        let slot = match self.selected & 0x03 {
+            0b10 => 0,
+            0b01 => 1,
+            _ => return None,
+        };
+        self.cards[slot].as_mut()
+    }
+}
+
+/// A DivMMC. Clones share the same interface.
+#[derive(Clone)]
+pub struct DivMmc {
+    state: Rc<RefCell<State>>,
+}
+
+/// The DivMMC's view of the bus, overlaying the Spectrum's
+pub struct DivMmcBus {
+    divmmc: DivMmc,
+    spectrum: Box<dyn Bus>,
+}
+
+impl DivMmc {
+    /// A DivMMC with the given EEPROM, and no cards.
+    ///
+    /// # Panics
+    /// Panics if the EEPROM is larger than 8K
+    pub fn new(eeprom: &[u8]) -> Self {
+        assert!(eeprom.len() <= EEPROM_SIZE, "the EEPROM is at most 8K");
+        let mut padded = eeprom.to_vec();
+        padded.resize(EEPROM_SIZE, 0xFF);
+        Self {
+            state: Rc::new(RefCell::new(State {
+                eeprom: padded,
+                ram: vec![0; BANKS * BANK_SIZE],
+                control: 0,
+                automapped: false,
+                map_after: None,
+                cards: [None, None],
+                selected: 0x03,
+                seen: (false, 0),
+            })),
+        }
+    }
+
+    /// A bus that overlays the DivMMC's memory on the Spectrum's bus while it's mapped in, and
+    /// handles its ports
+    pub fn bus(&self, spectrum: Box<dyn Bus>) -> DivMmcBus {
+        DivMmcBus {
+            divmmc: self.clone(),
+            spectrum,
+        }
+    }
+
+    /// The automapper, as a fetch hook
+    pub fn fetch_hook(&self) -> FetchHook {
+        let divmmc = self.clone();
+        Box::new(move |pc| {
+            let mut state = divmmc.state.borrow_mut();
+            if (0x3D00..=0x3DFF).contains(&pc) {
+                state.automapped = true;
+            } else if ENTRY_POINTS.contains(&pc) {
+                state.map_after = Some((pc, true));
+            } else if (0x1FF8..=0x1FFF).contains(&pc) {
+                state.map_after = Some((pc, false));
+            }
+            None
+        })
+    }
+
+    /// Whether the DivMMC's memory is mapped in
+    pub fn is_mapped(&self) -> bool {
+        self.state.borrow().mapped()
+    }
+
+    /// Put a card in a slot (0 or 1), returning the image of any that was there
+    pub fn insert(&self, slot: usize, image: Box<dyn DiskImage>) -> Option<Box<dyn DiskImage>> {
+        let card = Card::new(image);
+        self.state.borrow_mut().cards[slot]
+            .replace(card)
+            .map(|c| c.image)
+    }
+
+    /// Take the card out of a slot
+    pub fn eject(&self, slot: usize) -> Option<Box<dyn DiskImage>> {
+        self.state.borrow_mut().cards[slot].take().map(|c| c.image)
+    }
+
+    /// Unmap the memory and clear the control port, MAPRAM included, as at power on
+    pub fn reset(&self) {
+        let mut state = self.state.borrow_mut();
+        state.control = 0;
+        state.automapped = false;
+        state.map_after = None;
+    }
+
+    /// A state chunk, tagged `DMMC`, holding the paging, the selected card and the RAM.
+    /// A savestate without it resets the DivMMC.
+    pub fn state_chunk(&self) -> Box<dyn StateChunk> {
+        Box::new(DivMmcChunk(self.clone()))
+    }
+}
+
+struct DivMmcChunk(DivMmc);
+
+impl DivMmcChunk {
+    fn load_state(&self, state: &[u8]) -> Result<(), SaveStateError> {
+        let mut reader = StateReader::new(state);
+        let [control, automapped, selected, map_after] = reader.array()?;
+        let map_at = reader.u16()?;
+        let map_in = reader.bool()?;
+        let ram = reader.block()?;
+        let mut state = self.0.state.borrow_mut();
+        if ram.len() != state.ram.len() {
+            return Err(SaveStateError::Truncated);
+        }
+        state.control = control;
+        state.automapped = automapped != 0;
+        state.selected = selected;
+        state.map_after = if map_after != 0 {
+            Some((map_at, map_in))
+        } else {
+            None
+        };
+        state.ram.copy_from_slice(ram);
+        Ok(())
+    }
+}
+
+impl StateChunk for DivMmcChunk {
+    fn tag(&self) -> [u8; 4] {
+        *b"DMMC"
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let state = self.0.state.borrow();
+        let (map_at, map_in) = state.map_after.unwrap_or((0, false));
+        let mut out = vec![
+            state.control,
+            state.automapped as u8,
+            state.selected,
+            state.map_after.is_some() as u8,
+        ];
+        out.extend_from_slice(&map_at.to_le_bytes());
+        out.push(map_in as u8);
+        write_block(&mut out, &state.ram);
+        out
+    }
+
+    fn load(&self, chunk: Option<&[u8]>) -> Result<(), SaveStateError> {
+        match chunk {
+            Some(chunk) => self
+                .load_state(chunk)
+                .map_err(|_| SaveStateError::BadChunk(self.tag())),
+            None => {
+                self.0.reset();
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Bus for DivMmcBus {
+    fn mem_read(&self, addr: u16) -> u8 {
+        let mut state = self.divmmc.state.borrow_mut();
+        let value = state.read(addr);
+        if let Some((at, map)) = state.map_after {
+            if at == addr {
+                state.map_after = None;
+                state.automapped = map;
+            }
+        }
+        drop(state);
+        value.unwrap_or_else(|| self.spectrum.mem_read(addr))
+    }
+
+    fn mem_write(&self, addr: u16, val: u8) {
+        if !self.divmmc.state.borrow_mut().write(addr, val) {
+            self.spectrum.mem_write(addr, val)
+        }
+    }
+
+    fn io_read(&self, port: u16) -> u8 {
+        let mut state = self.divmmc.state.borrow_mut();
+        match port as u8 {
+            SPI_PORT => state.card().map_or(0xFF, Card::read),
+            _ => {
+                drop(state);
+                self.spectrum.io_read(port)
+            }
+        }
+    }
+
+    fn io_write(&self, port: u16, val: u8) {
+        let mut state = self.divmmc.state.borrow_mut();
+        match port as u8 {
+            // MAPRAM can only be set
+            CONTROL_PORT => state.control = val | (state.control & MAPRAM),
+            CARD_SELECT_PORT => state.selected = val,
+            SPI_PORT => {
+                if let Some(card) = state.card() {
+                    card.write(val);
+                }
+            }
+            _ => {
+                drop(state);
+                self.spectrum.io_write(port, val)
+            }
+        }
+    }
+
+    fn acknowledge(&self) -> u8 {
+        self.spectrum.acknowledge()
+    }
+
+    fn take_bank_switch(&self) -> bool {
+        let mut state = self.divmmc.state.borrow_mut();
+        let now = (state.mapped(), state.control);
+        let switched = now != state.seen;
+        state.seen = now;
+        drop(state);
+        self.spectrum.take_bank_switch() | switched
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::disk::raw::RawImage;
+    use crate::ops::Reg8;
+    use crate::testing::FlatRam;
+    use crate::z80::Z80;
+    use std::io::Cursor;
+
+    fn ram(program: &[(u16, &[u8])]) -> Box<FlatRam> {
+        let ram = FlatRam::new();
+        for (addr, bytes) in program {
+            ram.load(*addr, bytes);
+        }
+        Box::new(ram)
+    }
+
+    #[test]
+    fn automapping() {
+        let mut eeprom = vec![0; EEPROM_SIZE];
+        // The operand of the LD A, n at 0x0000, then JP 0x1FF8
+        eeprom[0x0001..0x0005].copy_from_slice(&[0x42, 0xC3, 0xF8, 0x1F]);
+        // JP n, whose address comes from the Spectrum
+        eeprom[0x1FF8] = 0xC3;
+        let divmmc = DivMmc::new(&eeprom);
+        let spectrum = ram(&[
+            (0x0000, &[0x3E, 0x00]),
+            (0x1FF9, &[0x00, 0x30]),
+            // 0x3000: HALT
+            (0x3000, &[0x76]),
+        ]);
+        let mut z80 = Z80::default();
+        z80.set_bus(Box::new(divmmc.bus(spectrum)));
+        z80.on_fetch(divmmc.fetch_hook());
+
+        // The opcode comes from the Spectrum, and the operand from the EEPROM
+        z80.step();
+        assert!(divmmc.is_mapped());
+        assert_eq!(0x42, z80.registers.get_reg8(Reg8::A));
+        z80.run();
+        assert!(!divmmc.is_mapped());
+        assert_eq!(0x3001, z80.registers.get_pc());
+
+        // Straight away, from 0x3D00
+        let mut automapper = divmmc.fetch_hook();
+        automapper(0x3D00);
+        assert!(divmmc.is_mapped());
+    }
+
+    #[test]
+    fn checkpoints() {
+        let divmmc = DivMmc::new(&[]);
+        let mut z80 = Z80::default();
+        z80.set_bus(Box::new(divmmc.bus(ram(&[]))));
+        z80.add_state_chunk(divmmc.state_chunk());
+        // A bank that isn't mapped in when the checkpoint is taken
+        let bus = divmmc.bus(ram(&[]));
+        bus.io_write(0xE3, CONMEM | 5);
+        bus.mem_write(0x2000, 0x55);
+        bus.io_write(0xE3, 0);
+        let checkpoint = z80.checkpoint();
+
+        bus.io_write(0xE3, CONMEM | 5);
+        bus.mem_write(0x2000, 0x66);
+        z80.restore(&checkpoint);
+        assert!(!divmmc.is_mapped());
+        bus.io_write(0xE3, CONMEM | 5);
+        assert_eq!(0x55, bus.mem_read(0x2000));
+    }
+
+    #[test]
+    fn memory() {
+        let divmmc = DivMmc::new(&[0xAA]);
+        let bus = divmmc.bus(ram(&[(0x0000, &[0x11]), (0x2000, &[0x22])]));
+        assert_eq!(0x11, bus.mem_read(0x0000));
+        assert!(!bus.take_bank_switch());
+        bus.io_write(0xE3, CONMEM | 3);
+        assert!(bus.take_bank_switch() && !bus.take_bank_switch());
+        assert_eq!(0xAA, bus.mem_read(0x0000));
+        bus.mem_write(0x2001, 0x55);
+        bus.mem_write(0x0000, 0x44);
+        assert_eq!(0x55, bus.mem_read(0x2001));
+        assert_eq!(0xAA, bus.mem_read(0x0000));
+        bus.io_write(0xE3, 0);
+        bus.mem_write(0x2000, 0x33);
+        assert_eq!(0x33, bus.mem_read(0x2000));
+
+        // MAPRAM puts bank 3 at the bottom, read only, and can't be cleared
+        bus.io_write(0xE3, MAPRAM | 3);
+        bus.io_write(0xE3, 3);
+        divmmc.state.borrow_mut().automapped = true;
+        assert_eq!(0x55, bus.mem_read(0x0001));
+        bus.mem_write(0x2001, 0x66);
+        assert_eq!(0x55, bus.mem_read(0x2001));
+        divmmc.reset();
+        assert_eq!(0x11, bus.mem_read(0x0000));
+    }
+
+    fn command(bus: &DivMmcBus, cmd: u8, arg: u32) -> u8 {
+        bus.io_write(0xEB, 0x40 | cmd);
+        for b in &arg.to_be_bytes() {
+            bus.io_write(0xEB, *b);
+        }
+        bus.io_write(0xEB, 0x95);
+        bus.io_read(0xEB)
+    }
+
+    #[test]
+    fn sd_card() {
+        let divmmc = DivMmc::new(&[]);
+        let mut image = vec![0; 4 * 1024 * BLOCK_SIZE];
+        image[BLOCK_SIZE..BLOCK_SIZE + 4].copy_from_slice(b"esx!");
+        let image = RawImage::blocks(Cursor::new(image), BLOCK_SIZE).unwrap();
+        divmmc.insert(0, Box::new(image));
+        let bus = divmmc.bus(ram(&[]));
+
+        assert_eq!(0xFF, bus.io_read(0xEB));
+        bus.io_write(0xE7, 0xFE);
+        assert_eq!(IDLE, command(&bus, 0, 0));
+        assert_eq!(IDLE, command(&bus, 8, 0x1AA));
+        assert_eq!(
+            vec![0, 0, 1, 0xAA],
+            (0..4).map(|_| bus.io_read(0xEB)).collect::<Vec<_>>()
+        );
+        assert_eq!(IDLE, command(&bus, 55, 0));
+        assert_eq!(READY, command(&bus, 41, 0x4000_0000));
+        assert_eq!(READY, command(&bus, 58, 0));
+        assert_eq!(0xC0, bus.io_read(0xEB));
+        assert_eq!(ILLEGAL, command(&bus, 63, 0));
+
+        // The CSD gives the size: 4 units of 512K
+        assert_eq!(READY, command(&bus, 9, 0));
+        let csd: Vec<u8> = (0..17).map(|_| bus.io_read(0xEB)).collect();
+        assert_eq!(&[START_BLOCK, 0x40], &csd[..2]);
+        assert_eq!(&[0, 0, 3], &csd[8..11]);
+        (0..2).for_each(|_| {
+            bus.io_read(0xEB);
+        });
+
+        assert_eq!(READY, command(&bus, 17, 1));
+        assert_eq!(START_BLOCK, bus.io_read(0xEB));
+        assert_eq!(
+            b"esx!",
+            &(0..4).map(|_| bus.io_read(0xEB)).collect::<Vec<_>>()[..]
+        );
+        assert_eq!(OUT_OF_RANGE, command(&bus, 17, 4096));
+
+        assert_eq!(READY, command(&bus, 24, 2));
+        bus.io_write(0xEB, 0xFF);
+        bus.io_write(0xEB, START_BLOCK);
+        for i in 0..BLOCK_SIZE + 2 {
+            bus.io_write(0xEB, i as u8);
+        }
+        assert_eq!(DATA_ACCEPTED, bus.io_read(0xEB));
+
+        // Read blocks 1 and 2 back, until told to stop
+        assert_eq!(READY, command(&bus, 18, 1));
+        let blocks: Vec<u8> = (0..2 * (BLOCK_SIZE + 3))
+            .map(|_| bus.io_read(0xEB))
+            .collect();
+        assert_eq!(b"esx!", &blocks[1..5]);
+        assert_eq!(
+            &[START_BLOCK, 0, 1, 2],
+            &blocks[BLOCK_SIZE + 3..BLOCK_SIZE + 7]
+        );
+        command(&bus, 12, 0);
+        assert_eq!(READY, bus.io_read(0xEB));
+        assert_eq!(0xFF, bus.io_read(0xEB));
+
+        // Nothing answers with no card selected
+        bus.io_write(0xE7, 0xFF);
+        assert_eq!(0xFF, command(&bus, 0, 0));
+        let mut image = divmmc.eject(0).unwrap();
+        let mut block = [0; BLOCK_SIZE];
+        image.read_block(2, &mut block).unwrap();
+        assert_eq!([0, 1, 2, 3], block[..4]);
+
+        // A write protected card refuses writes
+        image.set_write_protected(true);
+        divmmc.insert(0, image);
+        bus.io_write(0xE7, 0xFE);
+        assert_eq!(READY, command(&bus, 24, 2));
+        bus.io_write(0xEB, START_BLOCK);
+        for _ in 0..BLOCK_SIZE + 2 {
+            bus.io_write(0xEB, 0xAA);
+        }
+        assert_eq!(0x0D, bus.io_read(0xEB));
+    }
+}
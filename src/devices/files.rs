@@ -0,0 +1,244 @@
+//! A bridge to a directory on the host, so guest programs can exchange files without a disk
+//! controller. The guest writes commands to a port and reads the response back from the same
+//! port; the response to a command replaces any of the last one that wasn't read. Every
+//! response starts with a status byte, and only carries the rest when that's OK (0).
+//!
+//! | Command     | Bytes written                          | Response                         |
+//! |-------------|----------------------------------------|----------------------------------|
+//! | OPEN (1)    | 1, mode, name, 0                       | status, handle                   |
+//! | READ (2)    | 2, handle, count                       | status, count read, the bytes    |
+//! | WRITE (3)   | 3, handle, count, that many bytes      | status, count written            |
+//! | CLOSE (4)   | 4, handle                              | status                           |
+//!
+//! The mode is 0 to read, 1 to write (creating or truncating the file) and 2 to append.
+//! Names are relative to the directory, and can't leave it: absolute paths and `..` are
+//! refused. At most eight files are open at once.
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+
+use crate::z80::io::{InputDevice, OutputDevice};
+
+pub const OPEN: u8 = 1;
+pub const READ: u8 = 2;
+pub const WRITE: u8 = 3;
+pub const CLOSE: u8 = 4;
+
+pub const OK: u8 = 0;
+/// The host couldn't open, read or write the file
+pub const IO_ERROR: u8 = 1;
+/// The name is empty, isn't UTF-8, or leaves the directory
+pub const BAD_NAME: u8 = 2;
+/// The handle isn't an open file
+pub const BAD_HANDLE: u8 = 3;
+/// All the handles are in use
+pub const TOO_MANY_FILES: u8 = 4;
+/// The command or mode isn't one of the above
+pub const BAD_COMMAND: u8 = 5;
+
+const MAX_FILES: usize = 8;
+
+#[derive(Debug)]
+struct State {
+    root: PathBuf,
+    command: Vec<u8>,
+    response: VecDeque<u8>,
+    files: Vec<Option<File>>,
+}
+
+/// A bridge to a host directory. Clones share the same bridge, so one can be installed for
+/// input and another for output on the same port.
+#[derive(Debug, Clone)]
+pub struct FileBridge {
+    state: Rc<RefCell<State>>,
+}
+
+impl FileBridge {
+    /// A bridge to the files under `root`. For example:
+    /// ```no_run
+    /// use zeerust::devices::files::FileBridge;
+    /// use zeerust::z80::Z80;
+    ///
+    /// let mut z80 = Z80::default();
+    /// let files = FileBridge::new("shared");
+    /// z80.install_input(0x30, Box::new(files.clone()));
+    /// z80.install_output(0x30, Box::new(files));
+    ///```
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self {
+            state: Rc::new(RefCell::new(State {
+                root: root.into(),
+                command: vec![],
+                response: VecDeque::new(),
+                files: (0..MAX_FILES).map(|_| None).collect(),
+            })),
+        }
+    }
+
+    /// Close every file, and forget any command or response in progress
+    pub fn reset(&self) {
+        let mut state = self.state.borrow_mut();
+        state.command.clear();
+        state.response.clear();
+        state.files.iter_mut().for_each(|f| *f = None);
+    }
+}
+
+impl State {
+    // Whether the command so far is complete
+    fn complete(&self) -> bool {
+        let cmd = &self.command;
+        match cmd[0] {
+            OPEN => cmd.len() >= 3 && cmd[cmd.len() - 1] == 0,
+            READ => cmd.len() == 3,
+            WRITE => cmd.len() >= 3 && cmd.len() == 3 + usize::from(cmd[2]),
+            CLOSE => cmd.len() == 2,
+            _ => true,
+        }
+    }
+
+    fn execute(&mut self) -> Vec<u8> {
+        let cmd = std::mem::take(&mut self.command);
+        let result = match cmd[0] {
+            OPEN => self.open(cmd[1], &cmd[2..cmd.len() - 1]),
+            READ => self.file(cmd[1]).and_then(|file| {
+                let mut buf = vec![0; usize::from(cmd[2])];
+                let n = file.read(&mut buf).map_err(|_| IO_ERROR)?;
+                buf.truncate(n);
+                buf.insert(0, n as u8);
+                Ok(buf)
+            }),
+            WRITE => self.file(cmd[1]).and_then(|file| {
+                file.write_all(&cmd[3..]).map_err(|_| IO_ERROR)?;
+                Ok(vec![cmd[2]])
+            }),
+            CLOSE => self
+                .files
+                .get_mut(usize::from(cmd[1]))
+                .and_then(Option::take)
+                .map(|_| vec![])
+                .ok_or(BAD_HANDLE),
+            _ => Err(BAD_COMMAND),
+        };
+        match result {
+            Ok(mut rest) => {
+                rest.insert(0, OK);
+                rest
+            }
+            Err(status) => vec![status],
+        }
+    }
+
+    fn open(&mut self, mode: u8, name: &[u8]) -> Result<Vec<u8>, u8> {
+        let path = std::str::from_utf8(name)
+            .ok()
+            .filter(|name| !name.is_empty() && inside(Path::new(name)))
+            .map(|name| self.root.join(name))
+            .ok_or(BAD_NAME)?;
+        let mut options = OpenOptions::new();
+        match mode {
+            0 => options.read(true),
+            1 => options.write(true).create(true).truncate(true),
+            2 => options.append(true).create(true),
+            _ => return Err(BAD_COMMAND),
+        };
+        let handle = self
+            .files
+            .iter()
+            .position(Option::is_none)
+            .ok_or(TOO_MANY_FILES)?;
+        self.files[handle] = Some(options.open(path).map_err(|_| IO_ERROR)?);
+        Ok(vec![handle as u8])
+    }
+
+    fn file(&mut self, handle: u8) -> Result<&mut File, u8> {
+        self.files
+            .get_mut(usize::from(handle))
+            .and_then(Option::as_mut)
+            .ok_or(BAD_HANDLE)
+    }
+}
+
+// Whether a relative path stays inside the directory it's relative to
+fn inside(path: &Path) -> bool {
+    path.components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+impl InputDevice for FileBridge {
+    /// The next byte of the response, or 0xFF if there's nothing left
+    fn input(&self) -> u8 {
+        self.state.borrow_mut().response.pop_front().unwrap_or(0xFF)
+    }
+}
+
+impl OutputDevice for FileBridge {
+    /// The next byte of a command, which runs once it's complete
+    fn output(&self, val: u8) {
+        let mut state = self.state.borrow_mut();
+        state.command.push(val);
+        if state.complete() {
+            let response = state.execute();
+            state.response = response.into();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn send(bridge: &FileBridge, bytes: &[u8]) -> Vec<u8> {
+        for b in bytes {
+            bridge.output(*b);
+        }
+        let mut response = vec![];
+        while !bridge.state.borrow().response.is_empty() {
+            response.push(bridge.input());
+        }
+        response
+    }
+
+    #[test]
+    fn files() {
+        let root = std::env::temp_dir().join(format!("zeerust-files-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let bridge = FileBridge::new(&root);
+
+        assert_eq!(vec![OK, 0], send(&bridge, b"\x01\x01hello.txt\x00"));
+        assert_eq!(vec![OK, 5], send(&bridge, b"\x03\x00\x05hello"));
+        assert_eq!(vec![OK], send(&bridge, &[CLOSE, 0]));
+        assert_eq!(
+            b"hello",
+            &std::fs::read(root.join("hello.txt")).unwrap()[..]
+        );
+
+        assert_eq!(vec![OK, 0], send(&bridge, b"\x01\x00hello.txt\x00"));
+        assert_eq!(b"\x00\x03hel", &send(&bridge, &[READ, 0, 3])[..]);
+        assert_eq!(b"\x00\x02lo", &send(&bridge, &[READ, 0, 3])[..]);
+        assert_eq!(vec![OK, 0], send(&bridge, &[READ, 0, 3]));
+        assert_eq!(vec![OK], send(&bridge, &[CLOSE, 0]));
+
+        assert_eq!(vec![BAD_HANDLE], send(&bridge, &[READ, 0, 3]));
+        assert_eq!(vec![IO_ERROR], send(&bridge, b"\x01\x00missing\x00"));
+        assert_eq!(vec![BAD_NAME], send(&bridge, b"\x01\x00../escape\x00"));
+        assert_eq!(vec![BAD_NAME], send(&bridge, b"\x01\x01/etc/passwd\x00"));
+        assert_eq!(vec![BAD_NAME], send(&bridge, b"\x01\x00\x00"));
+        assert_eq!(vec![BAD_COMMAND], send(&bridge, b"\x01\x07hello.txt\x00"));
+        assert_eq!(vec![BAD_COMMAND], send(&bridge, &[0x99]));
+
+        for handle in 0..8 {
+            assert_eq!(vec![OK, handle], send(&bridge, b"\x01\x00hello.txt\x00"));
+        }
+        assert_eq!(
+            vec![TOO_MANY_FILES],
+            send(&bridge, b"\x01\x00hello.txt\x00")
+        );
+        bridge.reset();
+        assert_eq!(vec![OK, 0], send(&bridge, b"\x01\x00hello.txt\x00"));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
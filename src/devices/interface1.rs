@@ -0,0 +1,631 @@
+//! The ZX Interface 1, with its Microdrives.
+//! The Interface 1 adds an 8K shadow ROM, which it pages over the bottom of the Spectrum's ROM
+//! when the processor fetches an instruction from 0x0008 (the error restart, which the
+//! Spectrum ROM uses for the new commands) or 0x1708 (CLOSE #), and pages out again after the
+//! instruction at 0x0700. The overlay is a Bus around the Spectrum's own, and the paging is
+//! a fetch hook, so both have to be installed:
+//!
+//! ```
+//! # use zeerust::z80::{bus::Bus, Z80};
+//! # struct Spectrum;
+//! # impl Bus for Spectrum {
+//! #     fn mem_read(&self, _: u16) -> u8 { 0 }
+//! #     fn mem_write(&self, _: u16, _: u8) {}
+//! #     fn io_read(&self, _: u16) -> u8 { 0xFF }
+//! #     fn io_write(&self, _: u16, _: u8) {}
+//! # }
+//! use zeerust::devices::interface1::{Cartridge, Interface1};
+//!
+//! let if1 = Interface1::new(&[0; 8192]);
+//! if1.insert(0, Cartridge::blank());
+//! let mut z80 = Z80::default();
+//! z80.set_bus(Box::new(if1.bus(Box::new(Spectrum))));
+//! z80.on_fetch(if1.fetch_hook());
+//! ```
+//!
+//! Up to eight Microdrives are driven through ports 0xE7 (data) and 0xEF (control and
+//! status). Writing the control port clocks a bit into the drive select shift register on
+//! each falling edge of COMMS CLK: a 0 in COMMS DATA starts the first drive, and the rest move
+//! along. A running drive passes its blocks under the head, each a gap, then sync, then the
+//! block's bytes for the data port to read or write. The gap and sync are counted in reads of
+//! the status rather than in time, and reading the status partway through a block moves on
+//! to the next one, as the tape would have while the program wasn't reading. Writes start
+//! with 12 bytes of preamble, which aren't stored.
+//!
+//! Cartridges are `.mdr` images: 254 sectors, each a 15 byte header block and a 528 byte
+//! record block, and a final byte that's set if the cartridge is write protected.
+//! The RS232 and network port (0xF7) isn't emulated.
+//!
+//! Add its `state_chunk` to the processor as well, so checkpoints and savestates keep the
+//! paging and where each drive's tape is. The cartridges aren't part of it.
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::z80::bus::Bus;
+use crate::z80::savestate::{SaveStateError, StateChunk, StateReader};
+use crate::z80::trap::FetchHook;
+
+pub const ROM_SIZE: usize = 0x2000;
+pub const DRIVES: usize = 8;
+
+pub const DATA_PORT: u8 = 0xE7;
+pub const CONTROL_PORT: u8 = 0xEF;
+
+/// Status: clear if the cartridge is write protected
+pub const WRITE_PROTECT: u8 = 0x01;
+/// Status: clear while sync is under the head
+pub const SYNC: u8 = 0x02;
+/// Status: clear while a gap is under the head
+pub const GAP: u8 = 0x04;
+
+/// Control: the bit clocked into the drive select register (0 to start a drive)
+pub const COMMS_DATA: u8 = 0x01;
+/// Control: the drive select register shifts as this falls
+pub const COMMS_CLK: u8 = 0x02;
+/// Control: set to read, clear to write
+pub const READ: u8 = 0x04;
+/// Control: clear to erase, which writing needs
+pub const ERASE: u8 = 0x08;
+
+pub const SECTORS: usize = 254;
+const HEADER_LEN: usize = 15;
+const RECORD_LEN: usize = 528;
+const SECTOR_LEN: usize = HEADER_LEN + RECORD_LEN;
+/// The length of an `.mdr` image, with its write protect byte
+pub const MDR_LEN: usize = SECTORS * SECTOR_LEN + 1;
+
+const PREAMBLE_LEN: usize = 12;
+const GAP_READS: u32 = 8;
+const SYNC_READS: u32 = 2;
+
+// The addresses that page the shadow ROM in, and the one that pages it out
+const PAGE_IN: [u16; 2] = [0x0008, 0x1708];
+const PAGE_OUT: u16 = 0x0700;
+
+/// The data isn't a Microdrive image, being the wrong length
+#[derive(Debug, PartialEq)]
+pub struct MdrError {
+    pub len: usize,
+}
+
+impl fmt::Display for MdrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "a microdrive image is {} bytes, not {}",
+            MDR_LEN, self.len
+        )
+    }
+}
+
+impl std::error::Error for MdrError {}
+
+/// A Microdrive cartridge
+#[derive(Debug, PartialEq, Clone)]
+pub struct Cartridge {
+    data: Vec<u8>,
+    pub write_protected: bool,
+}
+
+impl Cartridge {
+    /// An unformatted cartridge
+    pub fn blank() -> Self {
+        Self {
+            data: vec![0; SECTORS * SECTOR_LEN],
+            write_protected: false,
+        }
+    }
+
+    /// Read an `.mdr` image. Images without the write protect byte are accepted too.
+    pub fn from_mdr(data: &[u8]) -> Result<Self, MdrError> {
+        match data.len() {
+            MDR_LEN => Ok(Self {
+                data: data[..MDR_LEN - 1].to_vec(),
+                write_protected: data[MDR_LEN - 1] != 0,
+            }),
+            len if len == MDR_LEN - 1 => Ok(Self {
+                data: data.to_vec(),
+                write_protected: false,
+            }),
+            len => Err(MdrError { len }),
+        }
+    }
+
+    /// The cartridge as an `.mdr` image
+    pub fn to_mdr(&self) -> Vec<u8> {
+        let mut data = self.data.clone();
+        data.push(self.write_protected as u8);
+        data
+    }
+
+    /// A sector's header block and record block
+    pub fn sector(&self, sector: usize) -> (&[u8], &[u8]) {
+        let start = sector * SECTOR_LEN;
+        let block = &self.data[start..start + SECTOR_LEN];
+        block.split_at(HEADER_LEN)
+    }
+
+    // The range of the data in the nth block: sectors alternate header and record blocks
+    fn block(n: usize) -> std::ops::Range<usize> {
+        let start = n / 2 * SECTOR_LEN;
+        match n % 2 {
+            0 => start..start + HEADER_LEN,
+            _ => start + HEADER_LEN..start + SECTOR_LEN,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Phase {
+    Gap(u32),
+    Sync(u32),
+    // The offset into the block, counting the preamble while writing
+    Data(usize),
+}
+
+#[derive(Debug)]
+struct Drive {
+    cartridge: Option<Cartridge>,
+    motor: bool,
+    block: usize,
+    phase: Phase,
+}
+
+impl Drive {
+    fn next_block(&mut self) {
+        self.block = (self.block + 1) % (SECTORS * 2);
+        self.phase = Phase::Gap(GAP_READS);
+    }
+}
+
+struct State {
+    rom: Vec<u8>,
+    paged: bool,
+    // Paged out once the instruction at PAGE_OUT has run
+    paging_out: bool,
+    // Whether paged in when the processor last asked for bank switches
+    seen_paged: bool,
+    drives: Vec<Drive>,
+    control: u8,
+}
+
+impl State {
+    fn running(&mut self) -> Option<&mut Drive> {
+        self.drives
+            .iter_mut()
+            .find(|d| d.motor && d.cartridge.is_some())
+    }
+
+    fn writing(&self) -> bool {
+        self.control & (READ | ERASE) == 0
+    }
+
+    fn status(&mut self) -> u8 {
+        let drive = match self.running() {
+            Some(drive) => drive,
+            None => return WRITE_PROTECT | SYNC | GAP,
+        };
+        let protect = match drive.cartridge.as_ref().map(|c| c.write_protected) {
+            Some(true) => 0,
+            _ => WRITE_PROTECT,
+        };
+        let status = match drive.phase {
+            Phase::Gap(n) => {
+                drive.phase = if n > 1 {
+                    Phase::Gap(n - 1)
+                } else {
+                    Phase::Sync(SYNC_READS)
+                };
+                SYNC
+            }
+            Phase::Sync(n) => {
+                drive.phase = if n > 1 {
+                    Phase::Sync(n - 1)
+                } else {
+                    Phase::Data(0)
+                };
+                GAP
+            }
+            Phase::Data(_) => {
+                drive.next_block();
+                SYNC | GAP
+            }
+        };
+        status | protect
+    }
+
+    fn read_data(&mut self) -> u8 {
+        let drive = match self.running() {
+            Some(drive) => drive,
+            None => return 0xFF,
+        };
+        let offset = match drive.phase {
+            Phase::Data(offset) => offset,
+            _ => 0,
+        };
+        let range = Cartridge::block(drive.block);
+        let len = range.len();
+        let value = drive
+            .cartridge
+            .as_ref()
+            .map_or(0xFF, |c| c.data[range][offset]);
+        if offset + 1 == len {
+            drive.next_block();
+        } else {
+            drive.phase = Phase::Data(offset + 1);
+        }
+        value
+    }
+
+    fn write_data(&mut self, val: u8) {
+        if !self.writing() {
+            return;
+        }
+        let drive = match self.running() {
+            Some(drive) => drive,
+            None => return,
+        };
+        let offset = match drive.phase {
+            Phase::Data(offset) => offset,
+            _ => 0,
+        };
+        let range = Cartridge::block(drive.block);
+        let len = range.len();
+        if let Some(cartridge) = &mut drive.cartridge {
+            if !cartridge.write_protected && offset >= PREAMBLE_LEN {
+                cartridge.data[range][offset - PREAMBLE_LEN] = val;
+            }
+        }
+        if offset + 1 == PREAMBLE_LEN + len {
+            drive.next_block();
+        } else {
+            drive.phase = Phase::Data(offset + 1);
+        }
+    }
+
+    fn write_control(&mut self, val: u8) {
+        let falling = self.control & COMMS_CLK != 0 && val & COMMS_CLK == 0;
+        if falling {
+            for i in (1..DRIVES).rev() {
+                self.drives[i].motor = self.drives[i - 1].motor;
+            }
+            self.drives[0].motor = val & COMMS_DATA == 0;
+        }
+        self.control = val;
+    }
+}
+
+/// The Interface 1. Clones share the same interface.
+#[derive(Clone)]
+pub struct Interface1 {
+    state: Rc<RefCell<State>>,
+}
+
+/// The Interface 1's view of the bus, overlaying the Spectrum's
+pub struct Interface1Bus {
+    if1: Interface1,
+    spectrum: Box<dyn Bus>,
+}
+
+impl Interface1 {
+    /// An Interface 1 with the given shadow ROM, paged out, and no cartridges.
+    ///
+    /// # Panics
+    /// Panics if the ROM is larger than 8K
+    pub fn new(rom: &[u8]) -> Self {
+        assert!(rom.len() <= ROM_SIZE, "the shadow ROM is at most 8K");
+        let mut padded = rom.to_vec();
+        padded.resize(ROM_SIZE, 0xFF);
+        let drives = (0..DRIVES)
+            .map(|_| Drive {
+                cartridge: None,
+                motor: false,
+                block: 0,
+                phase: Phase::Gap(GAP_READS),
+            })
+            .collect();
+        Self {
+            state: Rc::new(RefCell::new(State {
+                rom: padded,
+                paged: false,
+                paging_out: false,
+                seen_paged: false,
+                drives,
+                control: READ | ERASE | COMMS_CLK,
+            })),
+        }
+    }
+
+    /// A bus that overlays the shadow ROM on the Spectrum's bus while it's paged in, and
+    /// handles the Interface 1's ports
+    pub fn bus(&self, spectrum: Box<dyn Bus>) -> Interface1Bus {
+        Interface1Bus {
+            if1: self.clone(),
+            spectrum,
+        }
+    }
+
+    /// The fetch hook that pages the shadow ROM in and out
+    pub fn fetch_hook(&self) -> FetchHook {
+        let if1 = self.clone();
+        Box::new(move |pc| {
+            let mut state = if1.state.borrow_mut();
+            if state.paging_out {
+                state.paged = false;
+                state.paging_out = false;
+            }
+            if PAGE_IN.contains(&pc) {
+                state.paged = true;
+            } else if pc == PAGE_OUT && state.paged {
+                state.paging_out = true;
+            }
+            None
+        })
+    }
+
+    /// Whether the shadow ROM is paged in
+    pub fn is_paged(&self) -> bool {
+        self.state.borrow().paged
+    }
+
+    /// Put a cartridge in a drive, numbered from 0, returning any that was there
+    pub fn insert(&self, drive: usize, cartridge: Cartridge) -> Option<Cartridge> {
+        self.state.borrow_mut().drives[drive]
+            .cartridge
+            .replace(cartridge)
+    }
+
+    /// Take the cartridge out of a drive
+    pub fn eject(&self, drive: usize) -> Option<Cartridge> {
+        self.state.borrow_mut().drives[drive].cartridge.take()
+    }
+
+    /// Which drives are running
+    pub fn motors(&self) -> [bool; DRIVES] {
+        let state = self.state.borrow();
+        let mut motors = [false; DRIVES];
+        for (motor, drive) in motors.iter_mut().zip(&state.drives) {
+            *motor = drive.motor;
+        }
+        motors
+    }
+
+    /// A state chunk, tagged `IF1 `, holding the paging and the drives' motors and positions.
+    /// A savestate without it pages the shadow ROM out and stops the drives.
+    pub fn state_chunk(&self) -> Box<dyn StateChunk> {
+        Box::new(Interface1Chunk(self.clone()))
+    }
+}
+
+struct Interface1Chunk(Interface1);
+
+impl Interface1Chunk {
+    fn load_state(&self, state: &[u8]) -> Result<(), SaveStateError> {
+        let mut reader = StateReader::new(state);
+        let [paged, paging_out, control] = reader.array()?;
+        let mut drives = [(false, 0, Phase::Gap(GAP_READS)); DRIVES];
+        for drive in &mut drives {
+            let motor = reader.bool()?;
+            let block = usize::from(reader.u16()?) % (SECTORS * 2);
+            let count = reader.u32()?;
+            let phase = match reader.u8()? {
+                0 => Phase::Gap(count),
+                1 => Phase::Sync(count),
+                2 if (count as usize) < PREAMBLE_LEN + Cartridge::block(block).len() => {
+                    Phase::Data(count as usize)
+                }
+                _ => return Err(SaveStateError::Truncated),
+            };
+            *drive = (motor, block, phase);
+        }
+        let mut state = self.0.state.borrow_mut();
+        state.paged = paged != 0;
+        state.paging_out = paging_out != 0;
+        state.control = control;
+        for (drive, (motor, block, phase)) in state.drives.iter_mut().zip(drives) {
+            drive.motor = motor;
+            drive.block = block;
+            drive.phase = phase;
+        }
+        Ok(())
+    }
+}
+
+impl StateChunk for Interface1Chunk {
+    fn tag(&self) -> [u8; 4] {
+        *b"IF1 "
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let state = self.0.state.borrow();
+        let mut out = vec![state.paged as u8, state.paging_out as u8, state.control];
+        for drive in &state.drives {
+            out.push(drive.motor as u8);
+            out.extend_from_slice(&(drive.block as u16).to_le_bytes());
+            let (count, kind) = match drive.phase {
+                Phase::Gap(n) => (n, 0),
+                Phase::Sync(n) => (n, 1),
+                Phase::Data(offset) => (offset as u32, 2),
+            };
+            out.extend_from_slice(&count.to_le_bytes());
+            out.push(kind);
+        }
+        out
+    }
+
+    fn load(&self, chunk: Option<&[u8]>) -> Result<(), SaveStateError> {
+        if let Some(chunk) = chunk {
+            return self
+                .load_state(chunk)
+                .map_err(|_| SaveStateError::BadChunk(self.tag()));
+        }
+        let mut state = self.0.state.borrow_mut();
+        state.paged = false;
+        state.paging_out = false;
+        for drive in &mut state.drives {
+            drive.motor = false;
+        }
+        Ok(())
+    }
+}
+
+impl Bus for Interface1Bus {
+    fn mem_read(&self, addr: u16) -> u8 {
+        let state = self.if1.state.borrow();
+        if state.paged && usize::from(addr) < ROM_SIZE {
+            return state.rom[usize::from(addr)];
+        }
+        drop(state);
+        self.spectrum.mem_read(addr)
+    }
+
+    fn mem_write(&self, addr: u16, val: u8) {
+        self.spectrum.mem_write(addr, val)
+    }
+
+    fn io_read(&self, port: u16) -> u8 {
+        let mut state = self.if1.state.borrow_mut();
+        match port as u8 {
+            DATA_PORT => state.read_data(),
+            CONTROL_PORT => state.status(),
+            _ => {
+                drop(state);
+                self.spectrum.io_read(port)
+            }
+        }
+    }
+
+    fn io_write(&self, port: u16, val: u8) {
+        let mut state = self.if1.state.borrow_mut();
+        match port as u8 {
+            DATA_PORT => state.write_data(val),
+            CONTROL_PORT => state.write_control(val),
+            _ => {
+                drop(state);
+                self.spectrum.io_write(port, val)
+            }
+        }
+    }
+
+    fn acknowledge(&self) -> u8 {
+        self.spectrum.acknowledge()
+    }
+
+    fn take_bank_switch(&self) -> bool {
+        let mut state = self.if1.state.borrow_mut();
+        let switched = state.paged != state.seen_paged;
+        state.seen_paged = state.paged;
+        drop(state);
+        self.spectrum.take_bank_switch() | switched
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ops::Reg8;
+    use crate::testing::FlatRam;
+    use crate::z80::Z80;
+
+    fn ram(program: &[u8]) -> Box<FlatRam> {
+        let ram = FlatRam::new();
+        ram.load(0x0000, program);
+        Box::new(ram)
+    }
+
+    #[test]
+    fn paging() {
+        let mut rom = vec![0; ROM_SIZE];
+        // 0x0008: LD A, 0x42; JP 0x0700
+        rom[0x0008..0x000D].copy_from_slice(&[0x3E, 0x42, 0xC3, 0x00, 0x07]);
+        // 0x0700: RET
+        rom[0x0700] = 0xC9;
+        let if1 = Interface1::new(&rom);
+        let mut z80 = Z80::default();
+        // RST 8; IN A, (0xFE); HALT
+        z80.set_bus(Box::new(if1.bus(ram(&[0xCF, 0xDB, 0xFE, 0x76]))));
+        z80.on_fetch(if1.fetch_hook());
+
+        z80.step();
+        assert!(!if1.is_paged());
+        z80.step();
+        assert!(if1.is_paged());
+        assert_eq!(0x42, z80.registers.get_reg8(Reg8::A));
+        z80.run();
+        assert!(!if1.is_paged());
+        // Other ports still reach the Spectrum
+        assert_eq!(0xFE, z80.registers.get_reg8(Reg8::A));
+        assert_eq!(0x0004, z80.registers.get_pc());
+    }
+
+    fn select_first(bus: &Interface1Bus, control: u8) {
+        bus.io_write(0xEF, control | COMMS_CLK);
+        bus.io_write(0xEF, control);
+    }
+
+    #[test]
+    fn microdrive() {
+        let if1 = Interface1::new(&[]);
+        let mut cartridge = Cartridge::blank();
+        cartridge.data[..HEADER_LEN].copy_from_slice(b"\x01\xFEhdCARTRIDGE1\x00");
+        cartridge.data[HEADER_LEN] = 0x77;
+        if1.insert(0, cartridge);
+        let bus = if1.bus(ram(&[]));
+
+        assert_eq!(WRITE_PROTECT | SYNC | GAP, bus.io_read(0xEF));
+        select_first(&bus, READ | ERASE);
+        assert_eq!(
+            [true, false, false, false, false, false, false, false],
+            if1.motors()
+        );
+
+        let status: Vec<u8> = (0..GAP_READS + SYNC_READS)
+            .map(|_| bus.io_read(0xEF))
+            .collect();
+        assert!(status[..8].iter().all(|s| *s == WRITE_PROTECT | SYNC));
+        assert!(status[8..].iter().all(|s| *s == WRITE_PROTECT | GAP));
+        let header: Vec<u8> = (0..HEADER_LEN).map(|_| bus.io_read(0xE7)).collect();
+        assert_eq!(b"\x01\xFEhdCARTRIDGE1\x00", &header[..]);
+        // Straight on to the record block
+        assert_eq!(WRITE_PROTECT | SYNC, bus.io_read(0xEF));
+        assert_eq!(0x77, bus.io_read(0xE7));
+        // Reading the status partway through skips the rest of the block
+        assert_eq!(WRITE_PROTECT | SYNC | GAP, bus.io_read(0xEF));
+        assert_eq!(WRITE_PROTECT | SYNC, bus.io_read(0xEF));
+
+        // Write the next header, after the preamble
+        select_first(&bus, 0);
+        for b in [0; 10].iter().chain(&[0xFF, 0xFF]).chain(b"\x01\x01") {
+            bus.io_write(0xE7, *b);
+        }
+        let cartridge = if1.eject(0).unwrap();
+        assert_eq!(&[1, 1], &cartridge.sector(1).0[..2]);
+
+        // Select the second drive: a 1, then the 0 moves along
+        let mut protected = Cartridge::from_mdr(&cartridge.to_mdr()).unwrap();
+        protected.write_protected = true;
+        if1.insert(1, protected);
+        select_first(&bus, READ | ERASE | COMMS_DATA);
+        assert_eq!([false, true], [if1.motors()[0], if1.motors()[1]]);
+        assert_eq!(SYNC, bus.io_read(0xEF));
+    }
+
+    #[test]
+    fn images() {
+        let mut data = vec![0; MDR_LEN];
+        data[MDR_LEN - 1] = 1;
+        let cartridge = Cartridge::from_mdr(&data).unwrap();
+        assert!(cartridge.write_protected);
+        assert_eq!(data, cartridge.to_mdr());
+        assert!(
+            !Cartridge::from_mdr(&data[..MDR_LEN - 1])
+                .unwrap()
+                .write_protected
+        );
+        assert_eq!(
+            Err(MdrError { len: 100 }),
+            Cartridge::from_mdr(&data[..100])
+        );
+    }
+}
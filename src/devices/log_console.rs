@@ -0,0 +1,186 @@
+//! A console for firmware's debug output, such as printf to a serial port: the bytes a program
+//! writes to a port are split into lines, and each line is passed to the `log` crate with the
+//! T-state it was finished at, so it shows up among the host's own log messages.
+//!
+//! Like a Border, a LogConsole is an IoTrace, which sees every OUT with the T-state its I/O
+//! cycle started at; the port needs an output device installed as well (a BufOutput, say), for
+//! the OUT to go anywhere. A line ends at LF, CR LF or a CR on its own, and a line that gets
+//! too long is passed on in pieces. Bytes that aren't UTF-8 are replaced.
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use log::Level;
+
+use crate::z80::io::{Direction, IoEvent, IoTrace};
+
+/// The longest line passed on whole
+pub const MAX_LINE: usize = 256;
+
+/// A line of output
+#[derive(Debug, PartialEq, Clone)]
+pub struct Line {
+    /// The T-state of the OUT that finished it
+    pub tstate: u64,
+    pub text: String,
+}
+
+#[derive(Debug)]
+struct State {
+    line: Vec<u8>,
+    // Whether the last byte was a CR, so an LF after it ends nothing
+    after_cr: bool,
+    lines: Vec<Line>,
+}
+
+/// The console, watching one port. Clones share the same lines.
+#[derive(Debug, Clone)]
+pub struct LogConsole {
+    port: u8,
+    level: Level,
+    target: String,
+    state: Rc<RefCell<State>>,
+}
+
+impl IoTrace for LogConsole {
+    fn trace(&self, event: IoEvent) {
+        if event.direction != Direction::Out || event.port as u8 != self.port {
+            return;
+        }
+        let ends = {
+            let mut state = self.state.borrow_mut();
+            let after_cr = std::mem::replace(&mut state.after_cr, event.value == b'\r');
+            match event.value {
+                b'\n' => !after_cr,
+                b'\r' => true,
+                byte => {
+                    state.line.push(byte);
+                    state.line.len() == MAX_LINE
+                }
+            }
+        };
+        if ends {
+            self.end_line(event.tstate);
+        }
+    }
+}
+
+impl LogConsole {
+    /// A console for what's written to a port (decoding the low byte of the address, as
+    /// install_output does), logging at the Info level with the target `guest`. For example:
+    /// ```
+    /// use zeerust::devices::log_console::{Line, LogConsole};
+    /// use zeerust::z80::io::BufOutput;
+    /// use zeerust::z80::Z80;
+    ///
+    /// let mut z80 = Z80::default();
+    /// let console = LogConsole::new(0x01);
+    /// z80.install_output(0x01, Box::new(BufOutput::default()));
+    /// z80.set_io_trace(Box::new(console.clone()));
+    /// // LD A, 'k'; OUT (1), A; LD A, '\n'; OUT (1), A; HALT
+    /// z80.load(&[0x3E, b'k', 0xD3, 0x01, 0x3E, b'\n', 0xD3, 0x01, 0x76]);
+    /// z80.run();
+    /// let line = Line { tstate: 32, text: "k".to_string() };
+    /// assert_eq!(vec![line], console.take_lines());
+    ///```
+    pub fn new(port: u8) -> Self {
+        Self {
+            port,
+            level: Level::Info,
+            target: "guest".to_string(),
+            state: Rc::new(RefCell::new(State {
+                line: vec![],
+                after_cr: false,
+                lines: vec![],
+            })),
+        }
+    }
+
+    /// Log at another level
+    pub fn level(mut self, level: Level) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Log with another target, to tell several consoles apart or to filter them
+    pub fn target(mut self, target: &str) -> Self {
+        self.target = target.to_string();
+        self
+    }
+
+    /// Pass on what there is of an unfinished line, as if it had ended at T-state `tstate`
+    pub fn flush(&self, tstate: u64) {
+        if !self.state.borrow().line.is_empty() {
+            self.end_line(tstate);
+        }
+    }
+
+    /// The lines passed on since the last time they were taken
+    pub fn take_lines(&self) -> Vec<Line> {
+        std::mem::take(&mut self.state.borrow_mut().lines)
+    }
+
+    fn end_line(&self, tstate: u64) {
+        let mut state = self.state.borrow_mut();
+        let text = String::from_utf8_lossy(&state.line).into_owned();
+        state.line.clear();
+        log::log!(target: &self.target, self.level, "[{}] {}", tstate, text);
+        state.lines.push(Line { tstate, text });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write(console: &LogConsole, port: u16, text: &[u8]) {
+        for (i, byte) in text.iter().enumerate() {
+            console.trace(IoEvent {
+                tstate: 100 + i as u64,
+                port,
+                value: *byte,
+                direction: Direction::Out,
+            });
+        }
+    }
+
+    #[test]
+    fn lines() {
+        let console = LogConsole::new(0x01).level(Level::Debug).target("firmware");
+        write(&console, 0x0001, b"boot\r\nok\n\nsp=\xff\rdone");
+        // Another port, and a read of this one
+        write(&console, 0x0002, b"not this\n");
+        console.trace(IoEvent {
+            tstate: 200,
+            port: 0x0001,
+            value: b'\n',
+            direction: Direction::In,
+        });
+        assert_eq!(
+            vec![(104, "boot"), (108, "ok"), (109, ""), (114, "sp=\u{fffd}")],
+            console
+                .take_lines()
+                .iter()
+                .map(|l| (l.tstate, l.text.as_str()))
+                .collect::<Vec<_>>()
+        );
+
+        // The rest of the port's address isn't decoded
+        write(&console, 0x1201, b"\n");
+        assert_eq!(
+            vec![Line {
+                tstate: 100,
+                text: "done".to_string()
+            }],
+            console.take_lines()
+        );
+        console.flush(400);
+        assert!(console.take_lines().is_empty());
+
+        write(&console, 0x0001, &[b'x'; MAX_LINE + 1]);
+        let lines = console.take_lines();
+        assert_eq!(1, lines.len());
+        assert_eq!(MAX_LINE, lines[0].text.len());
+        console.flush(500);
+        assert_eq!("x", console.take_lines()[0].text);
+    }
+}
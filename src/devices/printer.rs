@@ -0,0 +1,377 @@
+//! A printer, capturing what's printed as text: for software that prints listings.
+//! Lines are written out as they're finished, with trailing spaces trimmed. Control codes are
+//! handled as one of two kinds of printer would:
+//!
+//! - Ascii, as under CP/M: CR LF ends a line (a CR on its own goes back to the start of the
+//!   line, so later characters print over earlier ones), a form feed starts a new page (as a
+//!   form feed in the text), tabs are every 8 columns and backspace goes back one. Bit 7 is
+//!   ignored, and so are other control codes.
+//! - Spectrum, as with LPRINT and LLIST: ENTER ends a line and lines wrap at 32 columns. The
+//!   comma control goes to the next half of the line, and AT and TAB to their column; colour
+//!   controls are skipped along with their parameters. Keyword tokens are spelled out, spaced
+//!   as in a listing, block graphics become the Unicode quadrant characters, and
+//!   user-defined graphics (which could be anything) become `?`.
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+use log::warn;
+
+use crate::z80::io::OutputDevice;
+
+/// Which printer's control codes to follow
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Style {
+    Ascii,
+    Spectrum,
+}
+
+const SPECTRUM_WIDTH: usize = 32;
+
+// The Spectrum's block graphics, 0x80 to 0x8F
+const BLOCKS: [char; 16] = [
+    ' ', '▝', '▘', '▀', '▗', '▐', '▚', '▜', '▖', '▞', '▌', '▛', '▄', '▟', '▙', '█',
+];
+
+// The Spectrum's keyword tokens, 0xA5 to 0xFF
+const TOKENS: [&str; 91] = [
+    "RND",
+    "INKEY$",
+    "PI",
+    "FN",
+    "POINT",
+    "SCREEN$",
+    "ATTR",
+    "AT",
+    "TAB",
+    "VAL$",
+    "CODE",
+    "VAL",
+    "LEN",
+    "SIN",
+    "COS",
+    "TAN",
+    "ASN",
+    "ACS",
+    "ATN",
+    "LN",
+    "EXP",
+    "INT",
+    "SQR",
+    "SGN",
+    "ABS",
+    "PEEK",
+    "IN",
+    "USR",
+    "STR$",
+    "CHR$",
+    "NOT",
+    "BIN",
+    "OR",
+    "AND",
+    "<=",
+    ">=",
+    "<>",
+    "LINE",
+    "THEN",
+    "TO",
+    "STEP",
+    "DEF FN",
+    "CAT",
+    "FORMAT",
+    "MOVE",
+    "ERASE",
+    "OPEN #",
+    "CLOSE #",
+    "MERGE",
+    "VERIFY",
+    "BEEP",
+    "CIRCLE",
+    "INK",
+    "PAPER",
+    "FLASH",
+    "BRIGHT",
+    "INVERSE",
+    "OVER",
+    "OUT",
+    "LPRINT",
+    "LLIST",
+    "STOP",
+    "READ",
+    "DATA",
+    "RESTORE",
+    "NEW",
+    "BORDER",
+    "CONTINUE",
+    "DIM",
+    "REM",
+    "FOR",
+    "GO TO",
+    "GO SUB",
+    "INPUT",
+    "LOAD",
+    "LIST",
+    "LET",
+    "PAUSE",
+    "NEXT",
+    "POKE",
+    "PRINT",
+    "PLOT",
+    "RUN",
+    "SAVE",
+    "RANDOMIZE",
+    "IF",
+    "CLS",
+    "DRAW",
+    "CLEAR",
+    "RETURN",
+    "COPY",
+];
+
+struct State {
+    style: Style,
+    out: Box<dyn Write>,
+    line: Vec<char>,
+    column: usize,
+    // A Spectrum control code, and the parameters it has so far
+    control: Vec<u8>,
+}
+
+/// A printer writing text to a file or any other Write. Clones share the same printer.
+#[derive(Clone)]
+pub struct Printer {
+    state: Rc<RefCell<State>>,
+}
+
+impl Printer {
+    /// A printer writing to `out`. For example:
+    /// ```
+    /// use zeerust::devices::printer::{Printer, Style};
+    /// use zeerust::z80::io::OutputDevice;
+    ///
+    /// let printer = Printer::new(Vec::new(), Style::Spectrum);
+    /// for b in b"\xF5\"HELLO\"\r" {
+    ///     printer.output(*b);
+    /// }
+    ///```
+    pub fn new<W: Write + 'static>(out: W, style: Style) -> Self {
+        Self {
+            state: Rc::new(RefCell::new(State {
+                style,
+                out: Box::new(out),
+                line: vec![],
+                column: 0,
+                control: vec![],
+            })),
+        }
+    }
+
+    /// A printer writing to a file, replacing anything already in it
+    pub fn to_file<P: AsRef<Path>>(path: P, style: Style) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?), style))
+    }
+
+    /// Write out the line so far, if there is one, and flush the output
+    pub fn finish(&self) -> io::Result<()> {
+        let mut state = self.state.borrow_mut();
+        if !state.line.is_empty() {
+            state.end_line();
+        }
+        state.out.flush()
+    }
+}
+
+impl State {
+    fn write(&mut self, text: &str) {
+        if let Err(e) = self.out.write_all(text.as_bytes()) {
+            warn!("Printer output failed: {}", e);
+        }
+    }
+
+    fn end_line(&mut self) {
+        let line: String = self.line.drain(..).collect();
+        self.write(line.trim_end());
+        self.write("\n");
+        self.column = 0;
+    }
+
+    // Print a character at the current column, over anything already there
+    fn put(&mut self, c: char) {
+        if self.column < self.line.len() {
+            if c != ' ' {
+                self.line[self.column] = c;
+            }
+        } else {
+            self.line.resize(self.column, ' ');
+            self.line.push(c);
+        }
+        self.column += 1;
+        if self.style == Style::Spectrum && self.column == SPECTRUM_WIDTH {
+            self.end_line();
+        }
+    }
+
+    // Move forward to a column, starting a new line first if it's behind
+    fn move_to(&mut self, column: usize) {
+        if column < self.column {
+            self.end_line();
+        }
+        self.column = column;
+    }
+
+    fn ascii(&mut self, b: u8) {
+        match b & 0x7F {
+            b'\r' => self.column = 0,
+            b'\n' => self.end_line(),
+            0x0C => {
+                if !self.line.is_empty() {
+                    self.end_line();
+                }
+                self.write("\x0C");
+            }
+            b'\t' => self.column = (self.column / 8 + 1) * 8,
+            0x08 => self.column = self.column.saturating_sub(1),
+            c @ 0x20..=0x7E => self.put(char::from(c)),
+            _ => (),
+        }
+    }
+
+    fn spectrum(&mut self, b: u8) {
+        if !self.control.is_empty() {
+            self.control.push(b);
+            self.spectrum_control();
+            return;
+        }
+        match b {
+            b'\r' => self.end_line(),
+            // The comma in PRINT
+            0x06 => self.move_to((self.column / 16 + 1) * 16 % SPECTRUM_WIDTH),
+            0x10..=0x17 => self.control.push(b),
+            0x60 => self.put('£'),
+            0x7F => self.put('©'),
+            0x20..=0x7E => self.put(char::from(b)),
+            0x80..=0x8F => self.put(BLOCKS[usize::from(b - 0x80)]),
+            0x90..=0xA4 => self.put('?'),
+            0xA5..=0xFF => self.token(TOKENS[usize::from(b - 0xA5)]),
+            _ => (),
+        }
+    }
+
+    // A control code with parameters, once it has them all
+    fn spectrum_control(&mut self) {
+        let control = &self.control;
+        match control[0] {
+            0x16 | 0x17 if control.len() < 3 => return,
+            // AT line, column: only the column means anything on paper
+            0x16 => {
+                let column = usize::from(control[2]) % SPECTRUM_WIDTH;
+                self.move_to(column);
+            }
+            0x17 => {
+                let column = usize::from(u16::from_le_bytes([control[1], control[2]]));
+                self.move_to(column % SPECTRUM_WIDTH);
+            }
+            // Colours, which the paper doesn't show
+            _ => (),
+        }
+        self.control.clear();
+    }
+
+    fn token(&mut self, token: &str) {
+        let spaced = !matches!(token, "RND" | "INKEY$" | "PI" | "<=" | ">=" | "<>");
+        let after_space = self.column == 0 || self.line.get(self.column - 1) == Some(&' ');
+        if spaced && !after_space {
+            self.put(' ');
+        }
+        token.chars().for_each(|c| self.put(c));
+        if spaced {
+            self.put(' ');
+        }
+    }
+}
+
+impl Drop for State {
+    fn drop(&mut self) {
+        if !self.line.is_empty() {
+            self.end_line();
+        }
+        let _ = self.out.flush();
+    }
+}
+
+impl OutputDevice for Printer {
+    /// Print a byte, or follow a control code
+    fn output(&self, val: u8) {
+        let mut state = self.state.borrow_mut();
+        match state.style {
+            Style::Ascii => state.ascii(val),
+            Style::Spectrum => state.spectrum(val),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Paper(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Paper {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn print(style: Style, bytes: &[u8]) -> String {
+        let paper = Paper::default();
+        let printer = Printer::new(paper.clone(), style);
+        bytes.iter().for_each(|b| printer.output(*b));
+        printer.finish().unwrap();
+        let text = paper.0.borrow().clone();
+        String::from_utf8(text).unwrap()
+    }
+
+    #[test]
+    fn ascii() {
+        assert_eq!(
+            "A>DIR\nfoo     bar\n",
+            print(Style::Ascii, b"A>DIR\r\nfoo\tbar   \r\n")
+        );
+        assert_eq!("one\n\x0Ctwo\n", print(Style::Ascii, b"one\x0Ctwo"));
+        // Overstriking, backspacing, a bell and bit 7
+        assert_eq!("bdc\n", print(Style::Ascii, b"abc\r_\x08b\x07\xE4\n"));
+    }
+
+    #[test]
+    fn spectrum() {
+        assert_eq!(TOKENS.len(), 0x100 - 0xA5);
+        // 10 PRINT AT 0,12;"£";: GO TO RND
+        let listing = b" 10 \xF5\x16\x00\x0C\"`\";:\xEC\xA5\r";
+        assert_eq!(
+            " 10 PRINT   \"£\";: GO TO RND\n",
+            print(Style::Spectrum, listing)
+        );
+        assert_eq!(
+            "a               b\n▚█?\n",
+            print(Style::Spectrum, b"a\x06\x10\x02b\r\x86\x8F\x90")
+        );
+        let long = [b'x'; 40];
+        assert_eq!(
+            format!("{}\n{}\n", "x".repeat(32), "x".repeat(8)),
+            print(Style::Spectrum, &long)
+        );
+        // TAB 4 from column 6 starts a new line
+        assert_eq!(
+            "abcdef\n    g\n",
+            print(Style::Spectrum, b"abcdef\x17\x04\x00g")
+        );
+    }
+}
@@ -0,0 +1,98 @@
+//! A random number generator: every read gives another random byte.
+//! The generator is SplitMix64, which is small, fast and plenty random for games. Seeded, it
+//! gives the same bytes every time, so tests and replays stay deterministic; a frontend that
+//! wants real entropy can seed it from the host with Rng::from_entropy.
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::z80::io::InputDevice;
+
+/// A seedable source of random bytes. Clones share the same generator.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: Rc<Cell<u64>>,
+}
+
+impl Rng {
+    /// A generator that always gives the same bytes for the same seed. For example:
+    /// ```
+    /// use zeerust::devices::rng::Rng;
+    /// use zeerust::ops::Reg8;
+    /// use zeerust::z80::Z80;
+    ///
+    /// let mut z80 = Z80::default();
+    /// z80.install_input(0x10, Box::new(Rng::new(1234)));
+    /// z80.load(&[0xDB, 0x10, 0x76]); // IN A, (0x10); HALT
+    /// z80.run();
+    /// assert_eq!(Rng::new(1234).next_byte(), z80.registers.get_reg8(Reg8::A));
+    ///```
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: Rc::new(Cell::new(seed)),
+        }
+    }
+
+    /// A generator seeded from the host, different every time
+    pub fn from_entropy() -> Self {
+        let mut hasher = RandomState::new().build_hasher();
+        if let Ok(time) = SystemTime::now().duration_since(UNIX_EPOCH) {
+            hasher.write_u128(time.as_nanos());
+        }
+        Self::new(hasher.finish())
+    }
+
+    /// Start again from a new seed
+    pub fn reseed(&self, seed: u64) {
+        self.state.set(seed);
+    }
+
+    /// The next 64 random bits
+    pub fn next_u64(&self) -> u64 {
+        let state = self.state.get().wrapping_add(0x9E37_79B9_7F4A_7C15);
+        self.state.set(state);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// The next random byte
+    pub fn next_byte(&self) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+}
+
+impl InputDevice for Rng {
+    fn input(&self) -> u8 {
+        self.next_byte()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn deterministic() {
+        // The first outputs of SplitMix64 seeded with 0
+        let rng = Rng::new(0);
+        assert_eq!(0xE220_A839_7B1D_CDAF, rng.next_u64());
+        assert_eq!(0x6E78_9E6A_A1B9_65F4, rng.next_u64());
+
+        let a = Rng::new(42);
+        let b = a.clone();
+        let first: Vec<u8> = (0..4).map(|_| a.input()).collect();
+        // Clones share the generator
+        assert_ne!(first, (0..4).map(|_| b.input()).collect::<Vec<_>>());
+        b.reseed(42);
+        assert_eq!(first, (0..4).map(|_| a.input()).collect::<Vec<_>>());
+
+        assert_ne!(
+            Rng::from_entropy().next_u64(),
+            Rng::from_entropy().next_u64()
+        );
+    }
+}
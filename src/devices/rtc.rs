@@ -0,0 +1,214 @@
+//! A real-time clock, so guest programs can read the date and time.
+//! The clock is a bank of registers on one port: writing selects a register, and each read
+//! gives the selected register and moves on to the next. Selecting register 0 latches the
+//! time, so a program reading the registers in order never sees a minute roll over halfway.
+//!
+//! The registers are either BCD, like a DS1302's (with the year as two digits):
+//!
+//! | Register | 0       | 1       | 2     | 3    | 4     | 5                   | 6    |
+//! |----------|---------|---------|-------|------|-------|---------------------|------|
+//! |          | Seconds | Minutes | Hours | Date | Month | Day, 1 is Sunday    | Year |
+//!
+//! or the eight bytes of the seconds since 1970 (in UTC), least significant first.
+//! The time comes from the host, or is frozen at a given time for tests.
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::z80::io::{InputDevice, OutputDevice};
+
+/// How the clock's registers present the time
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Format {
+    /// BCD seconds, minutes, hours, date, month, day of the week and year, like a DS1302
+    Bcd,
+    /// The seconds since 1970, as a little-endian u64
+    Epoch,
+}
+
+#[derive(Debug)]
+struct State {
+    format: Format,
+    // The seconds since 1970 if frozen, or None to follow the host
+    frozen: Option<u64>,
+    selected: u8,
+    latched: Option<u64>,
+}
+
+/// A clock for guest programs. Clones share the same clock, so one can be installed for
+/// input and another for output on the same port.
+#[derive(Debug, Clone)]
+pub struct Rtc {
+    state: Rc<RefCell<State>>,
+}
+
+impl Rtc {
+    /// A clock following the host's time
+    pub fn new(format: Format) -> Self {
+        Self::with_clock(format, None)
+    }
+
+    /// A clock stopped at the given number of seconds since 1970. For example:
+    /// ```
+    /// use zeerust::devices::rtc::{Format, Rtc};
+    /// use zeerust::ops::Reg8;
+    /// use zeerust::z80::Z80;
+    ///
+    /// let mut z80 = Z80::default();
+    /// let rtc = Rtc::frozen(Format::Bcd, 1_000_000_000); // 2001-09-09 01:46:40
+    /// z80.install_input(0x20, Box::new(rtc.clone()));
+    /// z80.install_output(0x20, Box::new(rtc));
+    /// // LD A, 1; OUT (0x20), A; IN A, (0x20); HALT
+    /// z80.load(&[0x3E, 0x01, 0xD3, 0x20, 0xDB, 0x20, 0x76]);
+    /// z80.run();
+    /// assert_eq!(0x46, z80.registers.get_reg8(Reg8::A));
+    ///```
+    pub fn frozen(format: Format, seconds: u64) -> Self {
+        Self::with_clock(format, Some(seconds))
+    }
+
+    fn with_clock(format: Format, frozen: Option<u64>) -> Self {
+        Self {
+            state: Rc::new(RefCell::new(State {
+                format,
+                frozen,
+                selected: 0,
+                latched: None,
+            })),
+        }
+    }
+
+    /// Stop the clock at the given time, or move it if it's already stopped
+    pub fn freeze(&self, seconds: u64) {
+        self.state.borrow_mut().frozen = Some(seconds);
+    }
+
+    /// Follow the host's time again
+    pub fn unfreeze(&self) {
+        self.state.borrow_mut().frozen = None;
+    }
+
+    /// The seconds since 1970, as the guest would see them now
+    pub fn now(&self) -> u64 {
+        self.state.borrow().frozen.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs())
+        })
+    }
+
+    /// The clock's registers at a time
+    pub fn registers(format: Format, seconds: u64) -> Vec<u8> {
+        match format {
+            Format::Epoch => seconds.to_le_bytes().to_vec(),
+            Format::Bcd => {
+                let days = seconds / 86400;
+                let time = seconds % 86400;
+                let (year, month, date) = civil(days);
+                [
+                    time % 60,
+                    time / 60 % 60,
+                    time / 3600,
+                    date,
+                    month,
+                    // 1970-01-01 was a Thursday
+                    (days + 4) % 7 + 1,
+                    year % 100,
+                ]
+                .iter()
+                .map(|n| ((n / 10) << 4 | (n % 10)) as u8)
+                .collect()
+            }
+        }
+    }
+}
+
+// The year, month and date of a day since 1970, from Howard Hinnant's civil_from_days
+fn civil(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let date = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, date)
+}
+
+impl InputDevice for Rtc {
+    /// Read the selected register, and select the next
+    fn input(&self) -> u8 {
+        let seconds = match self.state.borrow().latched {
+            Some(seconds) => seconds,
+            None => self.now(),
+        };
+        let mut state = self.state.borrow_mut();
+        state.latched = Some(seconds);
+        let registers = Rtc::registers(state.format, seconds);
+        let value = registers
+            .get(usize::from(state.selected))
+            .copied()
+            .unwrap_or(0xFF);
+        state.selected = state.selected.wrapping_add(1);
+        value
+    }
+}
+
+impl OutputDevice for Rtc {
+    /// Select a register. Selecting register 0 latches the time.
+    fn output(&self, val: u8) {
+        let now = if val == 0 { Some(self.now()) } else { None };
+        let mut state = self.state.borrow_mut();
+        state.selected = val;
+        if now.is_some() {
+            state.latched = now;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn registers() {
+        // 2024-02-29 23:59:58, a Thursday
+        let leap = 1_709_251_198;
+        assert_eq!(
+            vec![0x58, 0x59, 0x23, 0x29, 0x02, 0x05, 0x24],
+            Rtc::registers(Format::Bcd, leap)
+        );
+        assert_eq!(
+            vec![0x00, 0x00, 0x00, 0x01, 0x01, 0x05, 0x70],
+            Rtc::registers(Format::Bcd, 0)
+        );
+        assert_eq!(
+            vec![0x7E, 0x1A, 0xE1, 0x65, 0, 0, 0, 0],
+            Rtc::registers(Format::Epoch, leap)
+        );
+    }
+
+    #[test]
+    fn latching() {
+        let rtc = Rtc::frozen(Format::Bcd, 1_709_251_198);
+        rtc.output(0);
+        assert_eq!(0x58, rtc.input());
+        // The minute rolls over, but the latched time doesn't
+        rtc.freeze(1_709_251_200);
+        let rest: Vec<u8> = (0..6).map(|_| rtc.input()).collect();
+        assert_eq!(vec![0x59, 0x23, 0x29, 0x02, 0x05, 0x24], rest);
+        assert_eq!(0xFF, rtc.input());
+        rtc.output(3);
+        assert_eq!(0x29, rtc.input());
+        rtc.output(0);
+        assert_eq!(
+            vec![0x00, 0x00, 0x00, 0x01, 0x03],
+            (0..5).map(|_| rtc.input()).collect::<Vec<_>>()
+        );
+
+        rtc.unfreeze();
+        assert!(rtc.now() > 1_709_251_200);
+    }
+}
@@ -0,0 +1,241 @@
+//! A TCP connection tunnelled through a pair of ports, for serial-over-TCP setups and
+//! homebrew that talks to the network through a WiFi module. The data port reads the next
+//! byte received (0xFF if there's none) and sends each byte written; the status port says
+//! whether there's anything to read, and whether the connection is up.
+//!
+//! The bridge either connects out, or listens for one connection at a time (so a terminal can
+//! telnet in to the guest). Nothing blocks: the connection is polled whenever the guest reads
+//! the status port, and sends are buffered until the host can take them.
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::rc::Rc;
+
+use crate::z80::io::{InputDevice, OutputDevice};
+
+/// Set in the status when there's a byte to read
+pub const RECEIVED: u8 = 0x01;
+/// Set in the status while the connection is up
+pub const CONNECTED: u8 = 0x02;
+
+#[derive(Debug, Default)]
+struct State {
+    listener: Option<TcpListener>,
+    stream: Option<TcpStream>,
+    received: VecDeque<u8>,
+    sending: Vec<u8>,
+}
+
+/// A bridge to a TCP connection. The bridge is the data port; see status_port for the other.
+#[derive(Debug, Clone)]
+pub struct TcpBridge {
+    state: Rc<RefCell<State>>,
+}
+
+/// The status port of a TcpBridge
+#[derive(Debug, Clone)]
+pub struct TcpStatus(TcpBridge);
+
+impl TcpBridge {
+    /// Connect to a host. For example:
+    /// ```no_run
+    /// use zeerust::devices::tcp::TcpBridge;
+    /// use zeerust::z80::Z80;
+    ///
+    /// let mut z80 = Z80::default();
+    /// let modem = TcpBridge::connect("localhost:2323").unwrap();
+    /// z80.install_input(0x40, Box::new(modem.status_port()));
+    /// z80.install_input(0x41, Box::new(modem.clone()));
+    /// z80.install_output(0x41, Box::new(modem));
+    ///```
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nonblocking(true)?;
+        Ok(Self::with_state(State {
+            stream: Some(stream),
+            ..State::default()
+        }))
+    }
+
+    /// Listen for connections, taking one at a time
+    pub fn listen<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self::with_state(State {
+            listener: Some(listener),
+            ..State::default()
+        }))
+    }
+
+    fn with_state(state: State) -> Self {
+        Self {
+            state: Rc::new(RefCell::new(state)),
+        }
+    }
+
+    /// The port to read the status from
+    pub fn status_port(&self) -> TcpStatus {
+        TcpStatus(self.clone())
+    }
+
+    /// Accept a connection if listening for one, send what's waiting to be sent, and take in
+    /// anything received. The status port does this whenever it's read.
+    pub fn poll(&self) {
+        let mut state = self.state.borrow_mut();
+        if state.stream.is_none() {
+            let accepted = state.listener.as_ref().and_then(|l| l.accept().ok());
+            if let Some((stream, _)) = accepted {
+                if stream.set_nonblocking(true).is_ok() {
+                    state.stream = Some(stream);
+                }
+            }
+        }
+        let State {
+            stream,
+            received,
+            sending,
+            ..
+        } = &mut *state;
+        let open = match stream {
+            Some(stream) => flush(stream, sending).and_then(|_| fill(stream, received)),
+            None => return,
+        };
+        if open.is_err() {
+            state.stream = None;
+            state.sending.clear();
+        }
+    }
+
+    /// The address being listened on, if listening
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        let state = self.state.borrow();
+        state.listener.as_ref().and_then(|l| l.local_addr().ok())
+    }
+
+    /// Whether the connection is up
+    pub fn is_connected(&self) -> bool {
+        self.state.borrow().stream.is_some()
+    }
+}
+
+// Send as much as the host will take
+fn flush(stream: &mut TcpStream, sending: &mut Vec<u8>) -> io::Result<()> {
+    while !sending.is_empty() {
+        match stream.write(sending) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                sending.drain(..n);
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// Take in everything received so far
+fn fill(stream: &mut TcpStream, received: &mut VecDeque<u8>) -> io::Result<()> {
+    let mut buf = [0; 512];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => received.extend(&buf[..n]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+impl InputDevice for TcpBridge {
+    /// The next byte received, or 0xFF if there's none
+    fn input(&self) -> u8 {
+        self.state.borrow_mut().received.pop_front().unwrap_or(0xFF)
+    }
+}
+
+impl OutputDevice for TcpBridge {
+    /// Send a byte, if the connection is up
+    fn output(&self, val: u8) {
+        let mut state = self.state.borrow_mut();
+        if state.stream.is_some() {
+            state.sending.push(val);
+        }
+    }
+}
+
+impl InputDevice for TcpStatus {
+    /// RECEIVED and CONNECTED, as they are after polling the connection
+    fn input(&self) -> u8 {
+        self.0.poll();
+        let state = self.0.state.borrow();
+        let mut status = 0;
+        if !state.received.is_empty() {
+            status |= RECEIVED;
+        }
+        if state.stream.is_some() {
+            status |= CONNECTED;
+        }
+        status
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    // Read the status until it's what's wanted, or it's taking too long
+    fn wait_for(status: &TcpStatus, wanted: impl Fn(u8) -> bool) -> u8 {
+        let start = Instant::now();
+        loop {
+            let s = status.input();
+            if wanted(s) || start.elapsed() > Duration::from_secs(5) {
+                return s;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn connecting() {
+        let host = TcpListener::bind("127.0.0.1:0").unwrap();
+        let bridge = TcpBridge::connect(host.local_addr().unwrap()).unwrap();
+        let status = bridge.status_port();
+        let (mut remote, _) = host.accept().unwrap();
+
+        assert_eq!(CONNECTED, status.input());
+        assert_eq!(0xFF, bridge.input());
+        remote.write_all(b"hi").unwrap();
+        assert_eq!(
+            CONNECTED | RECEIVED,
+            wait_for(&status, |s| s & RECEIVED != 0)
+        );
+        assert_eq!(b'h', bridge.input());
+        assert_eq!(b'i', bridge.input());
+
+        for b in b"ok\n" {
+            bridge.output(*b);
+        }
+        bridge.poll();
+        let mut buf = [0; 3];
+        remote.read_exact(&mut buf).unwrap();
+        assert_eq!(b"ok\n", &buf);
+
+        drop(remote);
+        assert_eq!(0, wait_for(&status, |s| s & CONNECTED == 0));
+        assert!(!bridge.is_connected());
+    }
+
+    #[test]
+    fn listening() {
+        let bridge = TcpBridge::listen("127.0.0.1:0").unwrap();
+        let status = bridge.status_port();
+        assert_eq!(0, status.input());
+        let mut remote = TcpStream::connect(bridge.local_addr().unwrap()).unwrap();
+        assert_eq!(CONNECTED, wait_for(&status, |s| s & CONNECTED != 0));
+        remote.write_all(b"x").unwrap();
+        wait_for(&status, |s| s & RECEIVED != 0);
+        assert_eq!(b'x', bridge.input());
+    }
+}
@@ -0,0 +1,172 @@
+//! Disk images: floppies, hard disks and memory cards, as the controllers that read them see
+//! them. A DiskImage has a geometry, and sectors that can be read and written by cylinder,
+//! head and sector ID, or by block number for controllers that count sectors from the start
+//! of the disk (as SD cards do).
+//!
+//! `dsk` reads and writes the CPC's DSK images (standard and extended), which keep each
+//! sector's ID, so copy protected disks with odd sector numbering survive. `raw` works on
+//! flat images such as IMG files, with every sector the same size, straight from a file.
+//! `cpm` makes a CP/M disk of a directory on the host, for a CP/M machine to run programs from.
+use std::fmt;
+use std::io;
+
+pub mod cpm;
+pub mod dsk;
+pub mod raw;
+
+/// The shape of a disk. Disks with tracks of different shapes give the shape of the first.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Geometry {
+    pub cylinders: u32,
+    pub heads: u8,
+    /// Sectors on each track
+    pub sectors: u8,
+    /// In bytes
+    pub sector_size: usize,
+    /// The ID of the first sector on each track: 1 on a PC, 0xC1 on a CPC data disk...
+    pub first_sector: u8,
+}
+
+impl Geometry {
+    /// A disk that's just a run of blocks, such as a memory card
+    pub fn linear(blocks: u32, block_size: usize) -> Self {
+        Self {
+            cylinders: blocks,
+            heads: 1,
+            sectors: 1,
+            sector_size: block_size,
+            first_sector: 0,
+        }
+    }
+
+    /// The number of sectors on the disk
+    pub fn sector_count(&self) -> u32 {
+        self.cylinders * u32::from(self.heads) * u32::from(self.sectors)
+    }
+
+    /// The size of the disk, in bytes
+    pub fn size(&self) -> u64 {
+        u64::from(self.sector_count()) * self.sector_size as u64
+    }
+
+    /// The cylinder, head and sector ID of a block, counting every sector of every track in
+    /// order from the first
+    pub fn chs(&self, block: u32) -> Option<(u32, u8, u8)> {
+        if block >= self.sector_count() {
+            return None;
+        }
+        let sectors = u32::from(self.sectors);
+        let track = block / sectors;
+        let sector = (block % sectors) as u8 + self.first_sector;
+        let heads = u32::from(self.heads);
+        Some((track / heads, (track % heads) as u8, sector))
+    }
+
+    /// The block at a cylinder, head and sector ID, if the disk has it
+    pub fn block(&self, cylinder: u32, head: u8, sector: u8) -> Option<u32> {
+        let index = sector.checked_sub(self.first_sector)?;
+        if cylinder >= self.cylinders || head >= self.heads || index >= self.sectors {
+            return None;
+        }
+        let track = cylinder * u32::from(self.heads) + u32::from(head);
+        Some(track * u32::from(self.sectors) + u32::from(index))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DiskError {
+    /// There's no such sector on the disk
+    NotFound,
+    /// The disk is write protected
+    WriteProtected,
+    /// The image isn't one this format reads
+    BadImage(&'static str),
+    /// Reading or writing the image failed
+    Io(io::ErrorKind),
+}
+
+impl fmt::Display for DiskError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DiskError::NotFound => write!(f, "sector not found"),
+            DiskError::WriteProtected => write!(f, "disk is write protected"),
+            DiskError::BadImage(why) => write!(f, "bad disk image: {}", why),
+            DiskError::Io(kind) => write!(f, "disk image I/O failed: {:?}", kind),
+        }
+    }
+}
+
+impl std::error::Error for DiskError {}
+
+impl From<io::Error> for DiskError {
+    fn from(e: io::Error) -> Self {
+        DiskError::Io(e.kind())
+    }
+}
+
+/// A disk, as a controller sees it
+pub trait DiskImage {
+    fn geometry(&self) -> Geometry;
+
+    /// Read a sector into `buf`, which should be the size of the sector
+    fn read_sector(
+        &mut self,
+        cylinder: u32,
+        head: u8,
+        sector: u8,
+        buf: &mut [u8],
+    ) -> Result<(), DiskError>;
+
+    /// Write a sector
+    fn write_sector(
+        &mut self,
+        cylinder: u32,
+        head: u8,
+        sector: u8,
+        data: &[u8],
+    ) -> Result<(), DiskError>;
+
+    fn is_write_protected(&self) -> bool;
+
+    fn set_write_protected(&mut self, protected: bool);
+
+    /// Read a sector by its block number; see Geometry::chs
+    fn read_block(&mut self, block: u32, buf: &mut [u8]) -> Result<(), DiskError> {
+        let (c, h, s) = self.geometry().chs(block).ok_or(DiskError::NotFound)?;
+        self.read_sector(c, h, s, buf)
+    }
+
+    /// Write a sector by its block number; see Geometry::chs
+    fn write_block(&mut self, block: u32, data: &[u8]) -> Result<(), DiskError> {
+        let (c, h, s) = self.geometry().chs(block).ok_or(DiskError::NotFound)?;
+        self.write_sector(c, h, s, data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn geometry() {
+        let pc = Geometry {
+            cylinders: 80,
+            heads: 2,
+            sectors: 18,
+            sector_size: 512,
+            first_sector: 1,
+        };
+        assert_eq!(1_474_560, pc.size());
+        assert_eq!(Some((0, 0, 1)), pc.chs(0));
+        assert_eq!(Some((0, 1, 1)), pc.chs(18));
+        assert_eq!(Some((1, 0, 3)), pc.chs(38));
+        assert_eq!(None, pc.chs(2880));
+        assert_eq!(Some(38), pc.block(1, 0, 3));
+        assert_eq!(None, pc.block(1, 0, 0));
+        assert_eq!(None, pc.block(1, 2, 1));
+
+        let card = Geometry::linear(4096, 512);
+        assert_eq!(Some((4095, 0, 0)), card.chs(4095));
+        assert_eq!(Some(4095), card.block(4095, 0, 0));
+    }
+}
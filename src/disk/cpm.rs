@@ -0,0 +1,447 @@
+//! A host directory as a CP/M disk.
+//! DirectoryDisk builds a CP/M 2.2 filesystem from the files in a directory: each file whose
+//! name fits CP/M's 8.3 form gets directory entries, and its contents, padded with ^Z to a
+//! whole number of 128 byte records, are laid out in allocation blocks. Whenever the guest
+//! writes a directory sector, the filesystem is read back: files it changed or made are
+//! written to the directory (without the ^Z padding of their last record), and files it
+//! erased are deleted. Drop .COM files in a folder, and they're ready to run.
+//!
+//! Sectors are laid out in order, without skew, so the BIOS's disk parameter header should
+//! have no translation table. Files read from the host belong to user 0, and files of any
+//! user are written back.
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use super::{DiskError, DiskImage, Geometry};
+
+const RECORD: usize = 128;
+const ENTRY: usize = 32;
+// A logical extent, the most one directory entry's record count covers
+const EXTENT: usize = 128 * RECORD;
+const ERASED: u8 = 0xE5;
+const EOF: u8 = 0x1A;
+
+type Name = [u8; 11];
+
+/// The shape of a CP/M filesystem, as its disk parameter block gives it
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Format {
+    pub geometry: Geometry,
+    /// The allocation block size: 1K, 2K, 4K...
+    pub block_size: usize,
+    pub directory_entries: usize,
+    /// The tracks before the filesystem, which hold the system
+    pub reserved_tracks: u32,
+}
+
+impl Format {
+    /// The 8" single sided, single density disk that every CP/M system reads
+    pub const IBM_3740: Format = Format {
+        geometry: Geometry {
+            cylinders: 77,
+            heads: 1,
+            sectors: 26,
+            sector_size: 128,
+            first_sector: 1,
+        },
+        block_size: 1024,
+        directory_entries: 64,
+        reserved_tracks: 2,
+    };
+
+    fn reserved_size(&self) -> usize {
+        self.reserved_tracks as usize
+            * usize::from(self.geometry.sectors)
+            * self.geometry.sector_size
+    }
+
+    /// The number of allocation blocks, the directory's included (DSM + 1)
+    pub fn blocks(&self) -> usize {
+        (self.geometry.size() as usize).saturating_sub(self.reserved_size()) / self.block_size
+    }
+
+    fn directory_blocks(&self) -> usize {
+        (self.directory_entries * ENTRY).div_ceil(self.block_size)
+    }
+
+    // Block numbers are a byte each on small disks, and two bytes on large ones
+    fn pointers(&self) -> usize {
+        if self.blocks() <= 256 {
+            16
+        } else {
+            8
+        }
+    }
+
+    fn records_per_entry(&self) -> usize {
+        self.pointers() * self.block_size / RECORD
+    }
+
+    // EXM + 1
+    fn extents_per_entry(&self) -> usize {
+        self.pointers() * self.block_size / EXTENT
+    }
+
+    fn block_offset(&self, block: usize) -> usize {
+        self.reserved_size() + block * self.block_size
+    }
+}
+
+// The CP/M name of a host file, if it has one
+fn cpm_name(host: &str) -> Option<Name> {
+    let (base, ext) = match host.rfind('.') {
+        Some(i) => (&host[..i], &host[i + 1..]),
+        None => (host, ""),
+    };
+    let allowed = |c: char| c.is_ascii_alphanumeric() || "!#$%&'()-@^_{}~".contains(c);
+    if base.is_empty()
+        || base.len() > 8
+        || ext.len() > 3
+        || !(base.chars().chain(ext.chars())).all(allowed)
+    {
+        return None;
+    }
+    let mut name = [b' '; 11];
+    name[..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+    name[8..8 + ext.len()].copy_from_slice(ext.to_ascii_uppercase().as_bytes());
+    Some(name)
+}
+
+// The host name of a file the guest made
+fn host_name(name: &Name) -> String {
+    let part = |bytes: &[u8]| {
+        String::from_utf8_lossy(bytes)
+            .trim_end()
+            .to_ascii_lowercase()
+    };
+    let (base, ext) = (part(&name[..8]), part(&name[8..]));
+    if ext.is_empty() {
+        base
+    } else {
+        format!("{}.{}", base, ext)
+    }
+}
+
+/// A host directory, as a CP/M disk
+#[derive(Debug)]
+pub struct DirectoryDisk {
+    root: PathBuf,
+    format: Format,
+    data: Vec<u8>,
+    // Each file as last read from or written to the host: its path, and padded contents
+    files: BTreeMap<Name, (PathBuf, Vec<u8>)>,
+    write_protected: bool,
+}
+
+impl DirectoryDisk {
+    /// The files in `root`, on a disk of the given format. Files whose names don't fit, and
+    /// those that don't fit on the disk, are left out with a warning. For example:
+    /// ```
+    /// use zeerust::disk::cpm::{DirectoryDisk, Format};
+    /// use zeerust::disk::DiskImage;
+    ///
+    /// let root = std::env::temp_dir().join(format!("zeerust-cpm-doc-{}", std::process::id()));
+    /// std::fs::create_dir_all(&root).unwrap();
+    /// std::fs::write(root.join("Hello.com"), &[0xC9]).unwrap();
+    /// let mut disk = DirectoryDisk::open(&root, Format::IBM_3740).unwrap();
+    /// // The first directory entry, after the two system tracks
+    /// let mut entry = [0; 128];
+    /// disk.read_sector(2, 0, 1, &mut entry).unwrap();
+    /// assert_eq!(b"\0HELLO   COM", &entry[..12]);
+    /// # std::fs::remove_dir_all(&root).unwrap();
+    ///```
+    pub fn open(root: impl AsRef<Path>, format: Format) -> io::Result<Self> {
+        if format.extents_per_entry() == 0 || format.directory_blocks() >= format.blocks() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a usable CP/M format",
+            ));
+        }
+        let root = root.as_ref().to_path_buf();
+        let mut paths = fs::read_dir(&root)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        paths.sort();
+
+        let mut disk = Self {
+            root,
+            format,
+            data: vec![ERASED; format.geometry.size() as usize],
+            files: BTreeMap::new(),
+            write_protected: false,
+        };
+        let mut blocks = format.directory_blocks();
+        let mut entries = 0;
+        for path in paths.into_iter().filter(|p| p.is_file()) {
+            let host = path.file_name().unwrap().to_string_lossy().into_owned();
+            let name = match cpm_name(&host) {
+                Some(name) if !disk.files.contains_key(&name) => name,
+                _ => {
+                    warn!("{} has no CP/M name, so is left off the disk", host);
+                    continue;
+                }
+            };
+            let mut contents = fs::read(&path)?;
+            contents.resize(contents.len().div_ceil(RECORD) * RECORD, EOF);
+            let records = contents.len() / RECORD;
+            let needed = (
+                contents.len().div_ceil(format.block_size),
+                records.div_ceil(format.records_per_entry()).max(1),
+            );
+            if blocks + needed.0 > format.blocks() || entries + needed.1 > format.directory_entries
+            {
+                warn!("{} doesn't fit on the disk", host);
+                continue;
+            }
+            disk.lay_out(&name, &contents, blocks, entries);
+            blocks += needed.0;
+            entries += needed.1;
+            disk.files.insert(name, (path, contents));
+        }
+        Ok(disk)
+    }
+
+    // Write a file's directory entries and blocks, starting at the given ones
+    fn lay_out(&mut self, name: &Name, contents: &[u8], first_block: usize, first_entry: usize) {
+        let format = self.format;
+        let offset = format.block_offset(first_block);
+        self.data[offset..offset + contents.len()].copy_from_slice(contents);
+
+        let records = contents.len() / RECORD;
+        let per_entry = format.records_per_entry();
+        let blocks_per_entry = per_entry * RECORD / format.block_size;
+        for i in 0..records.div_ceil(per_entry).max(1) {
+            let in_entry = (records - i * per_entry).min(per_entry);
+            // The entry's last logical extent, and the records in it
+            let extent = (i * per_entry + in_entry.max(1) - 1) / 128;
+            let rc = if in_entry == 0 {
+                0
+            } else {
+                (in_entry - 1) % 128 + 1
+            };
+
+            let mut entry = [0; ENTRY];
+            entry[1..12].copy_from_slice(name);
+            entry[12] = extent as u8 & 0x1F;
+            entry[14] = (extent >> 5) as u8 & 0x3F;
+            entry[15] = rc as u8;
+            let first = first_block + i * blocks_per_entry;
+            let used = (in_entry * RECORD).div_ceil(format.block_size);
+            for (j, block) in (first..first + used).enumerate() {
+                if format.pointers() == 16 {
+                    entry[16 + j] = block as u8;
+                } else {
+                    entry[16 + j * 2..18 + j * 2].copy_from_slice(&(block as u16).to_le_bytes());
+                }
+            }
+            let offset = format.block_offset(0) + (first_entry + i) * ENTRY;
+            self.data[offset..offset + ENTRY].copy_from_slice(&entry);
+        }
+    }
+
+    // The files on the disk as it is now, padded to whole records
+    fn read_files(&self) -> BTreeMap<Name, Vec<u8>> {
+        let format = self.format;
+        let directory = &self.data[format.block_offset(0)..][..format.directory_entries * ENTRY];
+        let per_entry = format.records_per_entry();
+        let mut files: BTreeMap<Name, Vec<u8>> = BTreeMap::new();
+        for entry in directory.chunks(ENTRY).filter(|e| e[0] <= 15) {
+            let mut name = [0; 11];
+            name.iter_mut()
+                .zip(&entry[1..12])
+                .for_each(|(n, b)| *n = b & 0x7F);
+            let extent = usize::from(entry[12] & 0x1F) | usize::from(entry[14] & 0x3F) << 5;
+            let index = extent / format.extents_per_entry();
+            let in_entry =
+                (extent % format.extents_per_entry()) * 128 + usize::from(entry[15].min(0x80));
+
+            let mut contents = vec![];
+            let pointers: Vec<usize> = if format.pointers() == 16 {
+                entry[16..].iter().map(|b| usize::from(*b)).collect()
+            } else {
+                entry[16..]
+                    .chunks(2)
+                    .map(|p| usize::from(u16::from_le_bytes([p[0], p[1]])))
+                    .collect()
+            };
+            for block in pointers
+                .into_iter()
+                .take_while(|b| *b != 0 && *b < format.blocks())
+            {
+                contents.extend_from_slice(
+                    &self.data[format.block_offset(block)..][..format.block_size],
+                );
+            }
+            contents.resize(in_entry * RECORD, EOF);
+
+            let file = files.entry(name).or_default();
+            let start = index * per_entry * RECORD;
+            if file.len() < start + contents.len() {
+                file.resize(start + contents.len(), EOF);
+            }
+            file[start..start + contents.len()].copy_from_slice(&contents);
+        }
+        files
+    }
+
+    /// Write the guest's changes to the directory. This happens whenever the guest writes a
+    /// directory sector, so is only needed to be sure of errors.
+    pub fn sync(&mut self) -> io::Result<()> {
+        let files = self.read_files();
+        let erased: Vec<Name> = self
+            .files
+            .keys()
+            .filter(|n| !files.contains_key(*n))
+            .copied()
+            .collect();
+        for name in erased {
+            let (path, _) = self.files.remove(&name).unwrap();
+            fs::remove_file(path)?;
+        }
+        for (name, contents) in files {
+            let path = match self.files.get(&name) {
+                Some((_, old)) if *old == contents => continue,
+                Some((path, _)) => path.clone(),
+                None => self.root.join(host_name(&name)),
+            };
+            // Only the last record's padding is dropped
+            let last = contents.len().saturating_sub(RECORD);
+            let end = contents[last..]
+                .iter()
+                .rposition(|b| *b != EOF)
+                .map_or(last, |i| last + i + 1);
+            fs::write(&path, &contents[..end])?;
+            self.files.insert(name, (path, contents));
+        }
+        Ok(())
+    }
+
+    fn offset(&self, cylinder: u32, head: u8, sector: u8) -> Result<usize, DiskError> {
+        let geometry = self.format.geometry;
+        let block = geometry
+            .block(cylinder, head, sector)
+            .ok_or(DiskError::NotFound)?;
+        Ok(block as usize * geometry.sector_size)
+    }
+}
+
+impl DiskImage for DirectoryDisk {
+    fn geometry(&self) -> Geometry {
+        self.format.geometry
+    }
+
+    fn read_sector(
+        &mut self,
+        cylinder: u32,
+        head: u8,
+        sector: u8,
+        buf: &mut [u8],
+    ) -> Result<(), DiskError> {
+        let offset = self.offset(cylinder, head, sector)?;
+        let len = buf.len().min(self.format.geometry.sector_size);
+        buf[..len].copy_from_slice(&self.data[offset..offset + len]);
+        Ok(())
+    }
+
+    fn write_sector(
+        &mut self,
+        cylinder: u32,
+        head: u8,
+        sector: u8,
+        data: &[u8],
+    ) -> Result<(), DiskError> {
+        if self.write_protected {
+            return Err(DiskError::WriteProtected);
+        }
+        let offset = self.offset(cylinder, head, sector)?;
+        let len = data.len().min(self.format.geometry.sector_size);
+        self.data[offset..offset + len].copy_from_slice(&data[..len]);
+        let directory = self.format.block_offset(0);
+        if (directory..directory + self.format.directory_entries * ENTRY).contains(&offset) {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    fn is_write_protected(&self) -> bool {
+        self.write_protected
+    }
+
+    fn set_write_protected(&mut self, protected: bool) {
+        self.write_protected = protected;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert_eq!(Some(*b"HELLO   COM"), cpm_name("hello.com"));
+        assert_eq!(Some(*b"MAKEFILE   "), cpm_name("Makefile"));
+        assert_eq!(None, cpm_name("toolongname.txt"));
+        assert_eq!(None, cpm_name("a.b.c"));
+        assert_eq!(None, cpm_name(".hidden"));
+        assert_eq!(None, cpm_name("sp ace.txt"));
+        assert_eq!("new.txt", host_name(b"NEW     TXT"));
+        assert_eq!("makefile", host_name(b"MAKEFILE   "));
+    }
+
+    #[test]
+    fn directory() {
+        let root = std::env::temp_dir().join(format!("zeerust-cpm-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("Hello.com"), [0xC9; 3]).unwrap();
+        fs::write(root.join("readme.txt"), [b'x'; 20_000]).unwrap();
+        fs::write(root.join("toolongname.txt"), b"").unwrap();
+
+        let mut disk = DirectoryDisk::open(&root, Format::IBM_3740).unwrap();
+        assert_eq!(243, Format::IBM_3740.blocks());
+        // The directory is blocks 0 and 1, in the sectors after the system tracks
+        let dir = 52;
+        let block = |b: u32| dir + b * 8;
+        let mut sector = [0; 128];
+        disk.read_block(dir, &mut sector).unwrap();
+        assert_eq!(b"\0HELLO   COM\0\0\0\x01\x02\0", &sector[..18]);
+        // 157 records of README.TXT: a full first extent, and 29 records in the second
+        assert_eq!(b"\0README  TXT\0\0\0\x80\x03\x04", &sector[32..50]);
+        assert_eq!(
+            b"\0README  TXT\x01\0\0\x1D\x13\x14\x15\x16\0",
+            &sector[64..85]
+        );
+        assert_eq!(ERASED, sector[96]);
+        disk.read_block(block(2), &mut sector).unwrap();
+        assert_eq!(&[0xC9, 0xC9, 0xC9, EOF], &sector[..4]);
+        assert_eq!(Some(&EOF), sector.last());
+
+        // Erase README.TXT, and save NEW.TXT in block 30
+        let mut record = [EOF; 128];
+        record[..8].copy_from_slice(b"new file");
+        disk.write_block(block(30), &record).unwrap();
+        disk.read_block(dir, &mut sector).unwrap();
+        sector[32] = ERASED;
+        sector[64] = ERASED;
+        sector[96..114].copy_from_slice(b"\0NEW     TXT\0\0\0\x01\x1E\0");
+        disk.write_block(dir, &sector).unwrap();
+        assert_eq!(b"new file", &fs::read(root.join("new.txt")).unwrap()[..]);
+        assert!(!root.join("readme.txt").exists());
+        assert_eq!(vec![0xC9; 3], fs::read(root.join("Hello.com")).unwrap());
+
+        // A change to an existing file goes back to the host name
+        disk.write_block(block(2), &[0xC3; 128]).unwrap();
+        disk.sync().unwrap();
+        assert_eq!(vec![0xC3; 128], fs::read(root.join("Hello.com")).unwrap());
+
+        disk.set_write_protected(true);
+        assert_eq!(
+            Err(DiskError::WriteProtected),
+            disk.write_block(dir, &sector)
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,387 @@
+//! DSK images, as made by CPCEMU and kept by every CPC and +3 emulator since.
+//! An image is a 256 byte disk information block, then each track: a 256 byte track
+//! information block listing the ID (C, H, R and N) and FDC status of each sector, followed
+//! by the sectors' data. Standard images give one size for every track and take each sector's
+//! size from N; extended images (`EXTENDED CPC DSK File`) give the size of each track, and
+//! the length of each sector, so unformatted tracks and odd sectors can be kept. Images are
+//! always written extended.
+//!
+//! Sectors are found by their ID (R) on the physical track, as the FDC finds them.
+use std::convert::TryInto;
+
+use super::{DiskError, DiskImage, Geometry};
+
+const STANDARD: &[u8] = b"MV - CPC";
+const EXTENDED: &[u8] = b"EXTENDED";
+const DISK_INFO: &[u8; 34] = b"EXTENDED CPC DSK File\r\nDisk-Info\r\n";
+const TRACK_INFO: &[u8; 12] = b"Track-Info\r\n";
+const CREATOR: &[u8; 14] = b"zeerust\0\0\0\0\0\0\0";
+const INFO_SIZE: usize = 0x100;
+
+/// A sector, with its ID and the status the FDC reports reading it
+#[derive(Debug, PartialEq, Clone)]
+pub struct Sector {
+    /// C, H, R and N: the cylinder, head, sector number and size code recorded in the ID
+    pub id: [u8; 4],
+    pub st1: u8,
+    pub st2: u8,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+struct Track {
+    gap: u8,
+    filler: u8,
+    sectors: Vec<Sector>,
+}
+
+/// A DSK image, held in memory
+#[derive(Debug, PartialEq, Clone)]
+pub struct DskImage {
+    cylinders: u8,
+    heads: u8,
+    // By cylinder, then head; None if unformatted
+    tracks: Vec<Option<Track>>,
+    write_protected: bool,
+}
+
+// The size code of a sector's length
+fn size_code(len: usize) -> u8 {
+    (0..8).find(|n| 128 << n >= len).unwrap_or(8)
+}
+
+impl DskImage {
+    /// A freshly formatted disk: every track has `geometry.sectors` sectors, numbered from
+    /// `first_sector` and filled with `filler`. For example, a CPC data disk:
+    /// ```
+    /// use zeerust::disk::{dsk::DskImage, DiskImage, Geometry};
+    ///
+    /// let geometry = Geometry {
+    ///     cylinders: 40,
+    ///     heads: 1,
+    ///     sectors: 9,
+    ///     sector_size: 512,
+    ///     first_sector: 0xC1,
+    /// };
+    /// let mut disk = DskImage::formatted(geometry, 0xE5);
+    /// disk.write_sector(0, 0, 0xC1, &[0; 512]).unwrap();
+    /// let saved = disk.to_bytes();
+    /// assert_eq!(geometry, DskImage::from_bytes(&saved).unwrap().geometry());
+    ///```
+    pub fn formatted(geometry: Geometry, filler: u8) -> Self {
+        let n = size_code(geometry.sector_size);
+        let cylinders = geometry.cylinders.min(255) as u8;
+        let mut tracks = vec![];
+        for c in 0..cylinders {
+            for h in 0..geometry.heads {
+                let sectors = (0..geometry.sectors)
+                    .map(|i| Sector {
+                        id: [c, h, geometry.first_sector.wrapping_add(i), n],
+                        st1: 0,
+                        st2: 0,
+                        data: vec![filler; geometry.sector_size],
+                    })
+                    .collect();
+                tracks.push(Some(Track {
+                    gap: 0x52,
+                    filler,
+                    sectors,
+                }));
+            }
+        }
+        Self {
+            cylinders,
+            heads: geometry.heads,
+            tracks,
+            write_protected: false,
+        }
+    }
+
+    /// Read a standard or extended DSK image
+    pub fn from_bytes(data: &[u8]) -> Result<Self, DiskError> {
+        let extended = data.starts_with(EXTENDED);
+        if !extended && !data.starts_with(STANDARD) {
+            return Err(DiskError::BadImage("not a DSK image"));
+        }
+        let info = data
+            .get(..INFO_SIZE)
+            .ok_or(DiskError::BadImage("truncated"))?;
+        let (cylinders, heads) = (info[0x30], info[0x31]);
+        let standard_size = usize::from(u16::from_le_bytes([info[0x32], info[0x33]]));
+        let count = usize::from(cylinders) * usize::from(heads);
+        if extended && count > INFO_SIZE - 0x34 {
+            return Err(DiskError::BadImage("too many tracks"));
+        }
+
+        let mut tracks = Vec::with_capacity(count);
+        let mut offset = INFO_SIZE;
+        for i in 0..count {
+            let size = if extended {
+                usize::from(info[0x34 + i]) * 256
+            } else {
+                standard_size
+            };
+            if size == 0 {
+                tracks.push(None);
+                continue;
+            }
+            let block = data
+                .get(offset..offset + size)
+                .ok_or(DiskError::BadImage("truncated"))?;
+            tracks.push(Some(Self::parse_track(block, extended)?));
+            offset += size;
+        }
+        Ok(Self {
+            cylinders,
+            heads,
+            tracks,
+            write_protected: false,
+        })
+    }
+
+    fn parse_track(block: &[u8], extended: bool) -> Result<Track, DiskError> {
+        if block.len() < INFO_SIZE || !block.starts_with(TRACK_INFO) {
+            return Err(DiskError::BadImage("bad track information block"));
+        }
+        let count = usize::from(block[0x15]);
+        if 0x18 + count * 8 > INFO_SIZE {
+            return Err(DiskError::BadImage("too many sectors"));
+        }
+        let mut sectors = vec![];
+        let mut offset = INFO_SIZE;
+        for info in block[0x18..0x18 + count * 8].chunks(8) {
+            let len = if extended {
+                usize::from(u16::from_le_bytes([info[6], info[7]]))
+            } else {
+                128 << block[0x14].min(6)
+            };
+            let data = block
+                .get(offset..offset + len)
+                .ok_or(DiskError::BadImage("truncated"))?;
+            sectors.push(Sector {
+                id: info[..4].try_into().unwrap(),
+                st1: info[4],
+                st2: info[5],
+                data: data.to_vec(),
+            });
+            offset += len;
+        }
+        Ok(Track {
+            gap: block[0x16],
+            filler: block[0x17],
+            sectors,
+        })
+    }
+
+    /// The image as an extended DSK
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = DISK_INFO.to_vec();
+        data.extend_from_slice(CREATOR);
+        data.extend_from_slice(&[self.cylinders, self.heads, 0, 0]);
+        let blocks: Vec<Vec<u8>> = self
+            .tracks
+            .iter()
+            .enumerate()
+            .map(|(i, track)| match track {
+                Some(track) => self.track_block(i, track),
+                None => vec![],
+            })
+            .collect();
+        data.extend(blocks.iter().map(|b| (b.len() / 256) as u8));
+        data.resize(INFO_SIZE, 0);
+        blocks.iter().for_each(|b| data.extend_from_slice(b));
+        data
+    }
+
+    fn track_block(&self, index: usize, track: &Track) -> Vec<u8> {
+        let heads = usize::from(self.heads);
+        let n = track.sectors.first().map_or(2, |s| s.id[3]);
+        let mut block = TRACK_INFO.to_vec();
+        block.extend_from_slice(&[0; 4]);
+        block.extend_from_slice(&[(index / heads) as u8, (index % heads) as u8, 0, 0]);
+        block.extend_from_slice(&[n, track.sectors.len() as u8, track.gap, track.filler]);
+        for sector in &track.sectors {
+            block.extend_from_slice(&sector.id);
+            block.extend_from_slice(&[sector.st1, sector.st2]);
+            block.extend_from_slice(&(sector.data.len() as u16).to_le_bytes());
+        }
+        block.resize(INFO_SIZE, 0);
+        track
+            .sectors
+            .iter()
+            .for_each(|s| block.extend_from_slice(&s.data));
+        // Track blocks are a whole number of 256 byte pages
+        block.resize(block.len().div_ceil(256) * 256, 0);
+        block
+    }
+
+    /// The sectors on a track, in the order they pass the head
+    pub fn sectors(&self, cylinder: u8, head: u8) -> &[Sector] {
+        self.track(u32::from(cylinder), head)
+            .map_or(&[], |t| t.sectors.as_slice())
+    }
+
+    fn track(&self, cylinder: u32, head: u8) -> Option<&Track> {
+        if cylinder >= u32::from(self.cylinders) || head >= self.heads {
+            return None;
+        }
+        let index = cylinder as usize * usize::from(self.heads) + usize::from(head);
+        self.tracks[index].as_ref()
+    }
+
+    fn sector_mut(&mut self, cylinder: u32, head: u8, sector: u8) -> Option<&mut Sector> {
+        if cylinder >= u32::from(self.cylinders) || head >= self.heads {
+            return None;
+        }
+        let index = cylinder as usize * usize::from(self.heads) + usize::from(head);
+        self.tracks[index]
+            .as_mut()?
+            .sectors
+            .iter_mut()
+            .find(|s| s.id[2] == sector)
+    }
+}
+
+impl DiskImage for DskImage {
+    fn geometry(&self) -> Geometry {
+        let first = self.tracks.iter().flatten().next();
+        let sectors = first.map_or(&[][..], |t| t.sectors.as_slice());
+        Geometry {
+            cylinders: u32::from(self.cylinders),
+            heads: self.heads,
+            sectors: sectors.len() as u8,
+            sector_size: sectors.first().map_or(512, |s| s.data.len()),
+            first_sector: sectors.iter().map(|s| s.id[2]).min().unwrap_or(1),
+        }
+    }
+
+    fn read_sector(
+        &mut self,
+        cylinder: u32,
+        head: u8,
+        sector: u8,
+        buf: &mut [u8],
+    ) -> Result<(), DiskError> {
+        let found = self
+            .sector_mut(cylinder, head, sector)
+            .ok_or(DiskError::NotFound)?;
+        let len = buf.len().min(found.data.len());
+        buf[..len].copy_from_slice(&found.data[..len]);
+        Ok(())
+    }
+
+    fn write_sector(
+        &mut self,
+        cylinder: u32,
+        head: u8,
+        sector: u8,
+        data: &[u8],
+    ) -> Result<(), DiskError> {
+        if self.write_protected {
+            return Err(DiskError::WriteProtected);
+        }
+        let found = self
+            .sector_mut(cylinder, head, sector)
+            .ok_or(DiskError::NotFound)?;
+        let len = data.len().min(found.data.len());
+        found.data[..len].copy_from_slice(&data[..len]);
+        Ok(())
+    }
+
+    fn is_write_protected(&self) -> bool {
+        self.write_protected
+    }
+
+    fn set_write_protected(&mut self, protected: bool) {
+        self.write_protected = protected;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn extended() {
+        let geometry = Geometry {
+            cylinders: 40,
+            heads: 1,
+            sectors: 9,
+            sector_size: 512,
+            first_sector: 0xC1,
+        };
+        let mut disk = DskImage::formatted(geometry, 0xE5);
+        disk.write_sector(2, 0, 0xC5, b"hello").unwrap();
+        // The CPC's own format is the well known 194816 bytes
+        let data = disk.to_bytes();
+        assert_eq!(194_816, data.len());
+        assert_eq!(0x13, data[0x34]);
+
+        let mut read = DskImage::from_bytes(&data).unwrap();
+        assert_eq!(disk, read);
+        let mut buf = [0; 512];
+        read.read_sector(2, 0, 0xC5, &mut buf).unwrap();
+        assert_eq!(b"hello\xE5", &buf[..6]);
+        read.read_block(2 * 9 + 4, &mut buf).unwrap();
+        assert_eq!(b"hello", &buf[..5]);
+        assert_eq!(
+            Err(DiskError::NotFound),
+            read.read_sector(2, 0, 0x01, &mut buf)
+        );
+        assert_eq!(
+            Err(DiskError::NotFound),
+            read.read_sector(40, 0, 0xC1, &mut buf)
+        );
+        read.set_write_protected(true);
+        assert_eq!(Err(DiskError::WriteProtected), read.write_block(0, &buf));
+
+        // An unformatted track
+        let mut data = data;
+        data[0x35] = 0;
+        data.truncate(0x100 + 0x1300);
+        data[0x30] = 2;
+        let disk = DskImage::from_bytes(&data).unwrap();
+        assert!(disk.sectors(1, 0).is_empty());
+        assert_eq!(9, disk.sectors(0, 0).len());
+    }
+
+    #[test]
+    fn standard() {
+        let mut data = b"MV - CPCEMU Disk-File\r\nDisk-Info\r\n".to_vec();
+        data.resize(0x30, 0);
+        // One track of two 256 byte sectors, numbered backwards
+        data.extend_from_slice(&[1, 1, 0x00, 0x03]);
+        data.resize(0x100, 0);
+        data.extend_from_slice(TRACK_INFO);
+        data.resize(0x114, 0);
+        data.extend_from_slice(&[1, 2, 0x4E, 0xE5]);
+        data.extend_from_slice(&[0, 0, 2, 1, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0, 0, 0]);
+        data.resize(0x200, 0);
+        data.extend_from_slice(&[2; 256]);
+        data.extend_from_slice(&[1; 256]);
+
+        let mut disk = DskImage::from_bytes(&data).unwrap();
+        let geometry = disk.geometry();
+        assert_eq!(
+            (1, 2, 256, 1),
+            (
+                geometry.heads,
+                geometry.sectors,
+                geometry.sector_size,
+                geometry.first_sector
+            )
+        );
+        let mut buf = [0; 256];
+        disk.read_sector(0, 0, 1, &mut buf).unwrap();
+        assert_eq!([1; 256], buf);
+
+        assert_eq!(
+            Some(DiskError::BadImage("truncated")),
+            DskImage::from_bytes(&data[..0x300]).err()
+        );
+        assert_eq!(
+            Some(DiskError::BadImage("not a DSK image")),
+            DskImage::from_bytes(b"RIFF").err()
+        );
+    }
+}
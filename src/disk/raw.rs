@@ -0,0 +1,166 @@
+//! Flat disk images: every sector, the same size, one after another, tracks in order and
+//! heads alternating within each cylinder. IMG files are these, as are memory card dumps.
+//! The image is read and written in place, so a File works as well as a Vec in a Cursor.
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use super::{DiskError, DiskImage, Geometry};
+
+/// A flat image, in anything that can be read, written and seeked
+#[derive(Debug)]
+pub struct RawImage<S> {
+    storage: S,
+    geometry: Geometry,
+    write_protected: bool,
+}
+
+// The sizes of PC floppies: cylinders, heads and sectors of 512 bytes
+const IMG_GEOMETRIES: [(u32, u8, u8); 8] = [
+    (40, 1, 8),
+    (40, 1, 9),
+    (40, 2, 8),
+    (40, 2, 9),
+    (80, 2, 9),
+    (80, 2, 15),
+    (80, 2, 18),
+    (80, 2, 36),
+];
+
+impl<S: Read + Write + Seek> RawImage<S> {
+    /// An image of the given shape
+    pub fn new(storage: S, geometry: Geometry) -> Self {
+        Self {
+            storage,
+            geometry,
+            write_protected: false,
+        }
+    }
+
+    /// An IMG file, its shape worked out from its size. For example:
+    /// ```
+    /// use std::io::Cursor;
+    /// use zeerust::disk::{raw::RawImage, DiskImage};
+    ///
+    /// let mut img = RawImage::img(Cursor::new(vec![0; 737_280])).unwrap();
+    /// assert_eq!(9, img.geometry().sectors);
+    /// img.write_sector(79, 1, 9, &[0xE5; 512]).unwrap();
+    /// assert_eq!(0xE5, img.into_inner().into_inner()[737_279]);
+    ///```
+    pub fn img(mut storage: S) -> Result<Self, DiskError> {
+        let len = storage.seek(SeekFrom::End(0))?;
+        let (cylinders, heads, sectors) = IMG_GEOMETRIES
+            .iter()
+            .copied()
+            .find(|(c, h, s)| u64::from(*c) * u64::from(*h) * u64::from(*s) * 512 == len)
+            .ok_or(DiskError::BadImage("not the size of a PC floppy"))?;
+        let geometry = Geometry {
+            cylinders,
+            heads,
+            sectors,
+            sector_size: 512,
+            first_sector: 1,
+        };
+        Ok(Self::new(storage, geometry))
+    }
+
+    /// A run of blocks, as many as fit in the image: a memory card, say
+    pub fn blocks(mut storage: S, block_size: usize) -> Result<Self, DiskError> {
+        let len = storage.seek(SeekFrom::End(0))?;
+        let blocks = (len / block_size as u64) as u32;
+        Ok(Self::new(storage, Geometry::linear(blocks, block_size)))
+    }
+
+    /// The storage, to save or look at
+    pub fn into_inner(self) -> S {
+        self.storage
+    }
+
+    fn seek_to(&mut self, cylinder: u32, head: u8, sector: u8) -> Result<(), DiskError> {
+        let block = self
+            .geometry
+            .block(cylinder, head, sector)
+            .ok_or(DiskError::NotFound)?;
+        let offset = u64::from(block) * self.geometry.sector_size as u64;
+        self.storage.seek(SeekFrom::Start(offset))?;
+        Ok(())
+    }
+}
+
+impl<S: Read + Write + Seek> DiskImage for RawImage<S> {
+    fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
+    fn read_sector(
+        &mut self,
+        cylinder: u32,
+        head: u8,
+        sector: u8,
+        buf: &mut [u8],
+    ) -> Result<(), DiskError> {
+        self.seek_to(cylinder, head, sector)?;
+        let len = buf.len().min(self.geometry.sector_size);
+        self.storage.read_exact(&mut buf[..len])?;
+        Ok(())
+    }
+
+    fn write_sector(
+        &mut self,
+        cylinder: u32,
+        head: u8,
+        sector: u8,
+        data: &[u8],
+    ) -> Result<(), DiskError> {
+        if self.write_protected {
+            return Err(DiskError::WriteProtected);
+        }
+        self.seek_to(cylinder, head, sector)?;
+        let len = data.len().min(self.geometry.sector_size);
+        self.storage.write_all(&data[..len])?;
+        Ok(())
+    }
+
+    fn is_write_protected(&self) -> bool {
+        self.write_protected
+    }
+
+    fn set_write_protected(&mut self, protected: bool) {
+        self.write_protected = protected;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn sectors() {
+        let mut data = vec![0; 163_840];
+        data[512..516].copy_from_slice(b"two!");
+        let mut img = RawImage::img(Cursor::new(data)).unwrap();
+        assert_eq!((40, 1, 8), {
+            let g = img.geometry();
+            (g.cylinders, g.heads, g.sectors)
+        });
+        let mut buf = [0; 512];
+        img.read_sector(0, 0, 2, &mut buf).unwrap();
+        assert_eq!(b"two!", &buf[..4]);
+        img.read_block(1, &mut buf).unwrap();
+        assert_eq!(b"two!", &buf[..4]);
+        assert_eq!(Err(DiskError::NotFound), img.read_sector(0, 0, 9, &mut buf));
+        assert_eq!(Err(DiskError::NotFound), img.read_block(320, &mut buf));
+
+        img.set_write_protected(true);
+        assert_eq!(Err(DiskError::WriteProtected), img.write_block(0, &buf));
+        img.set_write_protected(false);
+        img.write_block(319, &[7; 512]).unwrap();
+        assert_eq!(7, img.into_inner().into_inner()[163_839]);
+
+        assert_eq!(
+            Some(DiskError::BadImage("not the size of a PC floppy")),
+            RawImage::img(Cursor::new(vec![0; 1000])).err()
+        );
+        let card = RawImage::blocks(Cursor::new(vec![0; 2048]), 512).unwrap();
+        assert_eq!(4, card.geometry().sector_count());
+    }
+}
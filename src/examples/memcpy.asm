@@ -0,0 +1,21 @@
+; A benchmark: copy 256 bytes from 0x1000 to 0x2000, 64 times over, a byte at a time.
+; Then print the copy of 'Z' to show it worked.
+      ld HL, 0x1000
+      ld B, 0
+fill: ld (HL), L                 ; the source is 0 to 255
+      inc L
+      djnz fill
+      ld C, 64
+pass: ld HL, 0x1000
+      ld DE, 0x2000
+      ld B, 0                    ; 256 bytes
+copy: ld A, (HL)
+      ld (DE), A
+      inc L
+      inc E
+      djnz copy
+      dec C
+      jr nz, pass
+      ld A, (0x205A)
+      out (0), A
+      halt
@@ -1,3 +1,13 @@
+//! Ready-made guest programs, as assembly and machine code, for demos and quick starts.
+//! Each prints to port 0x00, and runs as testing::run_program runs it:
+//! ```
+//! use zeerust::examples;
+//!
+//! let hello = examples::find("hello world").unwrap();
+//! assert_eq!(b"Hello World\n".to_vec(), hello.run().outputs);
+//!```
+use crate::testing::{self, ProgramResult};
+
 /// Calculates fizzbuzz (assembly)
 pub const FIZZBUZZ_ASM: &str = include_str!("fizzbuzz.asm");
 /// Calculates fizzbuzz (machine code)
@@ -18,6 +28,11 @@ pub const COUNTDOWN_ASM: &str = include_str!("countdown.asm");
 /// Count down from 9 to 1 (machine code)
 pub const COUNTDOWN_BIN: &[u8] = include_bytes!("countdown.bin");
 
+/// Copy 256 bytes 64 times over, a byte at a time, as a benchmark (assembly)
+pub const MEMCPY_ASM: &str = include_str!("memcpy.asm");
+/// Copy 256 bytes 64 times over, a byte at a time, as a benchmark (machine code)
+pub const MEMCPY_BIN: &[u8] = include_bytes!("memcpy.bin");
+
 /// Example is a named z80 program, along with its associated assembly.
 pub struct Example {
     pub name: &'static str,
@@ -46,4 +61,29 @@ pub const EXAMPLES: &[Example] = &[
         assembly: COUNTDOWN_ASM,
         binary: COUNTDOWN_BIN,
     },
+    Example {
+        name: "memcpy",
+        assembly: MEMCPY_ASM,
+        binary: MEMCPY_BIN,
+    },
 ];
+
+impl Example {
+    /// Run the program to the end, returning what it printed and how long it took.
+    /// For example, to time the benchmark:
+    /// ```
+    /// use zeerust::examples;
+    ///
+    /// let result = examples::find("memcpy").unwrap().run();
+    /// assert_eq!(b"Z".to_vec(), result.outputs);
+    /// println!("{} T-states", result.cycles);
+    ///```
+    pub fn run(&self) -> ProgramResult {
+        testing::run_program(self.binary, &[])
+    }
+}
+
+/// The example with the given name
+pub fn find(name: &str) -> Option<&'static Example> {
+    EXAMPLES.iter().find(|example| example.name == name)
+}
@@ -0,0 +1,402 @@
+//! Scanline and frame timing for machines with a video display.
+//! A FrameTimer is fed the T-states taken by each instruction, and converts them into scanline
+//! and frame boundaries, calling back into the machine as each one passes.
+//! A Pacer keeps a run loop in step with real time, at whatever speed its SpeedControl says,
+//! and decides which frames are worth showing.
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The shape of a video frame, in T-states
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct FrameTiming {
+    /// How long it takes to draw a single scanline (including borders and retrace)
+    pub tstates_per_line: u32,
+    /// How many scanlines make up a frame
+    pub lines_per_frame: u32,
+}
+
+impl FrameTiming {
+    /// The 48K ZX Spectrum: 224 T-states per line, 312 lines per frame
+    pub const ZX_SPECTRUM_48K: FrameTiming = FrameTiming {
+        tstates_per_line: 224,
+        lines_per_frame: 312,
+    };
+
+    /// The number of T-states in a whole frame
+    pub fn tstates_per_frame(&self) -> u32 {
+        self.tstates_per_line * self.lines_per_frame
+    }
+
+    /// How long a frame lasts on a processor running at `clock` Hz
+    ///
+    /// # Panics
+    /// Panics if the clock is 0
+    pub fn frame_duration(&self, clock: u32) -> Duration {
+        assert!(clock > 0, "the clock must be at least 1 Hz");
+        Duration::from_nanos(u64::from(self.tstates_per_frame()) * 1_000_000_000 / u64::from(clock))
+    }
+}
+
+/// Tracks the position of the beam as the CPU executes.
+/// Install callbacks with on_line and on_vblank, then call advance after every instruction.
+pub struct FrameTimer {
+    timing: FrameTiming,
+    tstate: u32,
+    frame: u64,
+
+    on_line: Option<Box<dyn FnMut(u32)>>,
+    on_vblank: Option<Box<dyn FnMut(u64)>>,
+}
+
+impl FrameTimer {
+    /// # Panics
+    /// Panics if the lines or the frame are empty
+    pub fn new(timing: FrameTiming) -> Self {
+        assert!(
+            timing.tstates_per_line > 0 && timing.lines_per_frame > 0,
+            "a frame must have at least one line of at least one T-state"
+        );
+        Self {
+            timing,
+            tstate: 0,
+            frame: 0,
+            on_line: None,
+            on_vblank: None,
+        }
+    }
+
+    /// Called with the line number every time a scanline finishes
+    pub fn on_line(&mut self, callback: Box<dyn FnMut(u32)>) {
+        self.on_line = Some(callback);
+    }
+
+    /// Called with the number of the new frame every time a frame finishes.
+    /// This is where machines should raise their vertical blank interrupt.
+    pub fn on_vblank(&mut self, callback: Box<dyn FnMut(u64)>) {
+        self.on_vblank = Some(callback);
+    }
+
+    /// The timing this timer was created with
+    pub fn timing(&self) -> FrameTiming {
+        self.timing
+    }
+
+    /// T-states elapsed since the start of the current frame
+    pub fn frame_tstate(&self) -> u32 {
+        self.tstate
+    }
+
+    /// The scanline currently being drawn
+    pub fn line(&self) -> u32 {
+        self.tstate / self.timing.tstates_per_line
+    }
+
+    /// The number of frames completed so far
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Move the beam forward, firing callbacks for every line and frame boundary crossed.
+    /// Returns true if at least one frame was completed.
+    pub fn advance(&mut self, tstates: u32) -> bool {
+        let per_line = self.timing.tstates_per_line;
+        let per_frame = self.timing.tstates_per_frame();
+        let mut completed = false;
+
+        let mut remaining = tstates;
+        while remaining > 0 {
+            let to_line_end = per_line - self.tstate % per_line;
+            if remaining < to_line_end {
+                self.tstate += remaining;
+                break;
+            }
+            remaining -= to_line_end;
+            self.tstate += to_line_end;
+
+            let line = self.tstate / per_line - 1;
+            if let Some(f) = self.on_line.as_mut() {
+                f(line);
+            }
+            if self.tstate >= per_frame {
+                self.tstate = 0;
+                self.frame += 1;
+                completed = true;
+                #[cfg(feature = "tracing")]
+                tracing::debug!(frame = self.frame, "frame");
+                if let Some(f) = self.on_vblank.as_mut() {
+                    f(self.frame);
+                }
+            }
+        }
+        completed
+    }
+}
+
+/// How fast to run, compared to the real machine
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Speed {
+    /// No frames at all
+    Paused,
+    /// This many times as fast: 1 is the real speed
+    Times(u32),
+    /// As fast as the host can go
+    Unlimited,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Settings {
+    speed: Speed,
+    frame_skip: u32,
+}
+
+/// Changes a Pacer's speed and frame skipping, from any thread: a front end hands one to its
+/// user interface, and keeps the Pacer with the run loop. Clones control the same Pacer.
+#[derive(Debug, Clone)]
+pub struct SpeedControl(Arc<Mutex<Settings>>);
+
+impl SpeedControl {
+    pub fn speed(&self) -> Speed {
+        self.get().speed
+    }
+
+    pub fn set_speed(&self, speed: Speed) {
+        self.0.lock().unwrap().speed = speed;
+    }
+
+    pub fn pause(&self) {
+        self.set_speed(Speed::Paused);
+    }
+
+    /// Back to the real speed
+    pub fn resume(&self) {
+        self.set_speed(Speed::Times(1));
+    }
+
+    /// Show only one frame in every `skip + 1`
+    pub fn set_frame_skip(&self, skip: u32) {
+        self.0.lock().unwrap().frame_skip = skip;
+    }
+
+    fn get(&self) -> Settings {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Paces a run loop to real time. Each time round, `wait` until the next frame is due, run
+/// it, and draw it if `should_render` says so:
+/// ```
+/// use zeerust::frame::{FrameTiming, Pacer, Speed};
+/// use zeerust::machine::homebrew::Homebrew;
+///
+/// let mut machine = Homebrew::new(&[0x18, 0xFE]); // JR -2
+/// let mut pacer = Pacer::new(FrameTiming::ZX_SPECTRUM_48K.frame_duration(3_500_000));
+/// let control = pacer.control(); // For the user interface
+/// control.set_speed(Speed::Unlimited);
+/// control.set_frame_skip(1);
+/// let mut rendered = 0;
+/// for _ in 0..10 {
+///     if pacer.wait() {
+///         machine.run_frame();
+///         if pacer.should_render() {
+///             rendered += 1;
+///         }
+///     }
+/// }
+/// assert_eq!(10, machine.frame());
+/// assert!(rendered <= 5);
+///```
+#[derive(Debug)]
+pub struct Pacer {
+    period: Duration,
+    control: SpeedControl,
+    // When the next frame should start
+    due: Instant,
+    last_render: Option<Instant>,
+    // Frames to skip before the next one shown
+    to_skip: u32,
+}
+
+// How far behind the pacer can fall before it gives up catching up
+const MAX_LAG: u32 = 5;
+
+impl Pacer {
+    /// A pacer for frames of the given length, at the real speed and without frame skipping
+    pub fn new(period: Duration) -> Self {
+        Self {
+            period,
+            control: SpeedControl(Arc::new(Mutex::new(Settings {
+                speed: Speed::Times(1),
+                frame_skip: 0,
+            }))),
+            due: Instant::now(),
+            last_render: None,
+            to_skip: 0,
+        }
+    }
+
+    /// A handle to change the speed with
+    pub fn control(&self) -> SpeedControl {
+        self.control.clone()
+    }
+
+    /// Sleep until the next frame is due, and return true to run it. While paused, this sleeps
+    /// for a frame and returns false, so the loop can still see to its other business.
+    /// A loop that falls more than a few frames behind (a slow host, or a breakpoint) carries
+    /// on from now, rather than racing to catch up.
+    pub fn wait(&mut self) -> bool {
+        let now = Instant::now();
+        let period = match self.control.speed() {
+            Speed::Paused => {
+                thread::sleep(self.period);
+                self.due = Instant::now();
+                return false;
+            }
+            Speed::Unlimited => {
+                self.due = now;
+                return true;
+            }
+            Speed::Times(n) => self.period / n.max(1),
+        };
+        if now < self.due {
+            thread::sleep(self.due - now);
+        } else if now - self.due > period * MAX_LAG {
+            self.due = now;
+        }
+        self.due += period;
+        true
+    }
+
+    /// Whether the frame just run should be shown. Besides the frame skip, frames faster than
+    /// the real machine's are only shown as often as the real machine would show them.
+    pub fn should_render(&mut self) -> bool {
+        let settings = self.control.get();
+        if self.to_skip > 0 {
+            self.to_skip -= 1;
+            return false;
+        }
+        let now = Instant::now();
+        let fast = matches!(settings.speed, Speed::Times(n) if n > 1)
+            || settings.speed == Speed::Unlimited;
+        if fast
+            && self
+                .last_render
+                .is_some_and(|last| now - last < self.period)
+        {
+            return false;
+        }
+        self.to_skip = settings.frame_skip;
+        self.last_render = Some(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const SMALL: FrameTiming = FrameTiming {
+        tstates_per_line: 10,
+        lines_per_frame: 3,
+    };
+
+    #[test]
+    fn spectrum_frame() {
+        assert_eq!(69888, FrameTiming::ZX_SPECTRUM_48K.tstates_per_frame());
+        assert_eq!(
+            Duration::from_nanos(19_968_000),
+            FrameTiming::ZX_SPECTRUM_48K.frame_duration(3_500_000)
+        );
+    }
+
+    #[test]
+    fn advance() {
+        let mut timer = FrameTimer::new(SMALL);
+        assert!(!timer.advance(9));
+        assert_eq!(0, timer.line());
+        assert!(!timer.advance(1));
+        assert_eq!(1, timer.line());
+        assert!(!timer.advance(15));
+        assert_eq!(2, timer.line());
+        assert_eq!(25, timer.frame_tstate());
+        assert!(timer.advance(7));
+        assert_eq!(1, timer.frame());
+        assert_eq!(2, timer.frame_tstate());
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one line")]
+    fn empty_frame() {
+        FrameTimer::new(FrameTiming {
+            tstates_per_line: 224,
+            lines_per_frame: 0,
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "at least 1 Hz")]
+    fn stopped_clock() {
+        FrameTiming::ZX_SPECTRUM_48K.frame_duration(0);
+    }
+
+    #[test]
+    fn callbacks() {
+        let lines = Rc::new(RefCell::new(vec![]));
+        let frames = Rc::new(RefCell::new(vec![]));
+
+        let mut timer = FrameTimer::new(SMALL);
+        let l = lines.clone();
+        timer.on_line(Box::new(move |line| l.borrow_mut().push(line)));
+        let f = frames.clone();
+        timer.on_vblank(Box::new(move |frame| f.borrow_mut().push(frame)));
+
+        // A single huge instruction crosses several boundaries at once
+        timer.advance(65);
+        assert_eq!(vec![0, 1, 2, 0, 1, 2], *lines.borrow());
+        assert_eq!(vec![1, 2], *frames.borrow());
+        assert_eq!(5, timer.frame_tstate());
+    }
+
+    #[test]
+    fn pacing() {
+        let period = Duration::from_millis(2);
+        let mut pacer = Pacer::new(period);
+        let control = pacer.control();
+        let start = Instant::now();
+        for _ in 0..5 {
+            assert!(pacer.wait());
+        }
+        // The first frame is due at once
+        assert!(start.elapsed() >= period * 4);
+
+        control.set_speed(Speed::Unlimited);
+        let start = Instant::now();
+        for _ in 0..1000 {
+            assert!(pacer.wait());
+        }
+        assert!(start.elapsed() < period * 1000);
+
+        let remote = control.clone();
+        std::thread::spawn(move || remote.pause()).join().unwrap();
+        assert_eq!(Speed::Paused, control.speed());
+        assert!(!pacer.wait());
+        control.resume();
+        assert!(pacer.wait());
+    }
+
+    #[test]
+    fn frame_skip() {
+        let mut pacer = Pacer::new(Duration::from_millis(1));
+        pacer.control().set_frame_skip(2);
+        let rendered: Vec<bool> = (0..7).map(|_| pacer.should_render()).collect();
+        assert_eq!(vec![true, false, false, true, false, false, true], rendered);
+
+        // Much faster than real time, only one frame is shown in each period
+        let mut pacer = Pacer::new(Duration::from_secs(60));
+        pacer.control().set_speed(Speed::Times(4));
+        assert!(pacer.should_render());
+        assert!(!pacer.should_render());
+    }
+}
@@ -0,0 +1,239 @@
+//! A queue of input events, each applied when the processor reaches its T-state.
+//! Front ends read the keyboard and joysticks on their own thread, and send what happens through
+//! an InputSender. The emulation thread owns the InputQueue, and machines take events off it
+//! between instructions, so input lands at an exact point in the run rather than whenever the
+//! host thread got round to it.
+//!
+//! Events sent without a time are stamped with the T-state they're picked up at. A recording of
+//! the stamped events replays the run exactly, whatever the host's timing was the first time.
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use log::debug;
+
+use crate::devices::cassette::Cassette;
+
+/// A joystick direction or button
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum JoystickControl {
+    Up,
+    Down,
+    Left,
+    Right,
+    Fire,
+}
+
+/// What to do with the cassette deck
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TapeControl {
+    Play,
+    Stop,
+    Rewind,
+    Record,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum InputEvent {
+    /// Press or release the key at a row and column of the keyboard matrix
+    Key {
+        row: usize,
+        col: u8,
+        pressed: bool,
+    },
+    /// Push or release a control of a joystick, the first being 0
+    Joystick {
+        joystick: u8,
+        control: JoystickControl,
+        pressed: bool,
+    },
+    Tape(TapeControl),
+}
+
+/// An event and the T-state it happens at
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct TimedEvent {
+    pub tstate: u64,
+    pub event: InputEvent,
+}
+
+/// Sends events to an InputQueue from any thread. Clones send to the same queue.
+#[derive(Debug, Clone)]
+pub struct InputSender(Sender<(Option<u64>, InputEvent)>);
+
+impl InputSender {
+    /// Send an event to happen as soon as the emulation picks it up. Returns false if the
+    /// queue has gone.
+    pub fn send(&self, event: InputEvent) -> bool {
+        self.0.send((None, event)).is_ok()
+    }
+
+    /// Send an event to happen at a T-state, or as soon as it's picked up if that's passed
+    pub fn send_at(&self, tstate: u64, event: InputEvent) -> bool {
+        self.0.send((Some(tstate), event)).is_ok()
+    }
+}
+
+/// Events waiting for their time, on the emulation thread
+#[derive(Debug)]
+pub struct InputQueue {
+    receiver: Receiver<(Option<u64>, InputEvent)>,
+    sender: Sender<(Option<u64>, InputEvent)>,
+    // In order of time, and then of arrival
+    pending: Vec<TimedEvent>,
+    recording: Option<Vec<TimedEvent>>,
+    replaying: bool,
+    tape: Option<Cassette>,
+}
+
+impl Default for InputQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InputQueue {
+    /// An empty queue. For example:
+    /// ```
+    /// use zeerust::input::{InputEvent, InputQueue};
+    ///
+    /// let mut queue = InputQueue::new();
+    /// let sender = queue.sender();
+    /// let space = InputEvent::Key { row: 8, col: 0, pressed: true };
+    /// std::thread::spawn(move || sender.send_at(1000, space)).join().unwrap();
+    /// assert_eq!(None, queue.next_due(999));
+    /// assert_eq!(Some(space), queue.next_due(1003));
+    ///```
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Self {
+            receiver,
+            sender,
+            pending: vec![],
+            recording: None,
+            replaying: false,
+            tape: None,
+        }
+    }
+
+    /// A queue that replays recorded events, ignoring anything sent to it
+    pub fn replay(events: Vec<TimedEvent>) -> Self {
+        let mut queue = Self::new();
+        for event in events {
+            queue.push(event);
+        }
+        queue.replaying = true;
+        queue
+    }
+
+    /// A sender for host threads to send events with
+    pub fn sender(&self) -> InputSender {
+        InputSender(self.sender.clone())
+    }
+
+    /// Have tape events play, stop and rewind a cassette deck, rather than handing them on
+    pub fn attach_tape(&mut self, deck: Cassette) {
+        self.tape = Some(deck);
+    }
+
+    /// Start keeping every event as it's handed on, forgetting any kept so far
+    pub fn record(&mut self) {
+        self.recording = Some(vec![]);
+    }
+
+    /// Stop recording, returning the events handed on since it started
+    pub fn take_recording(&mut self) -> Option<Vec<TimedEvent>> {
+        self.recording.take()
+    }
+
+    /// Whether there are events waiting, not counting any still on their way from a sender
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// The next event due by T-state `now`, in order of time and then of arrival. Events
+    /// for the tape go to the attached deck, if there is one.
+    pub fn next_due(&mut self, now: u64) -> Option<InputEvent> {
+        while let Ok((tstate, event)) = self.receiver.try_recv() {
+            if !self.replaying {
+                let tstate = tstate.map_or(now, |t| t.max(now));
+                self.push(TimedEvent { tstate, event });
+            }
+        }
+        loop {
+            if self.pending.first()?.tstate > now {
+                return None;
+            }
+            let timed = self.pending.remove(0);
+            if let Some(recording) = &mut self.recording {
+                recording.push(timed);
+            }
+            match (timed.event, &self.tape) {
+                (InputEvent::Tape(control), Some(deck)) => deck.control(control),
+                (event, _) => return Some(event),
+            }
+        }
+    }
+
+    fn push(&mut self, event: TimedEvent) {
+        let i = self.pending.partition_point(|e| e.tstate <= event.tstate);
+        self.pending.insert(i, event);
+    }
+}
+
+// For machines to log the events they have no use for
+pub(crate) fn ignore(event: InputEvent) {
+    debug!("Ignoring input {:?}", event);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::devices::cassette::Wav;
+
+    fn key(col: u8) -> InputEvent {
+        InputEvent::Key {
+            row: 0,
+            col,
+            pressed: true,
+        }
+    }
+
+    #[test]
+    fn ordering_and_replay() {
+        let mut queue = InputQueue::new();
+        let sender = queue.sender();
+        queue.record();
+        sender.send_at(200, key(0));
+        sender.send_at(100, key(1));
+        sender.send(key(2));
+        sender.send_at(100, key(3));
+        assert_eq!(Some(key(2)), queue.next_due(50));
+        assert_eq!(None, queue.next_due(99));
+        let due: Vec<_> = std::iter::from_fn(|| queue.next_due(200)).collect();
+        assert_eq!(vec![key(1), key(3), key(0)], due);
+        assert!(queue.is_empty());
+
+        let recording = queue.take_recording().unwrap();
+        let times: Vec<_> = recording.iter().map(|e| e.tstate).collect();
+        assert_eq!(vec![50, 100, 100, 200], times);
+
+        let mut replay = InputQueue::replay(recording);
+        replay.sender().send(key(4));
+        let due: Vec<_> = std::iter::from_fn(|| replay.next_due(150)).collect();
+        assert_eq!(vec![key(2), key(1), key(3)], due);
+    }
+
+    #[test]
+    fn tape() {
+        let deck = Cassette::new(1000);
+        deck.insert(Wav {
+            rate: 1000,
+            samples: vec![20000; 10],
+        });
+        let mut queue = InputQueue::new();
+        queue.attach_tape(deck.clone());
+        queue.sender().send(InputEvent::Tape(TapeControl::Play));
+        queue.sender().send(key(0));
+        assert_eq!(Some(key(0)), queue.next_due(0));
+        assert!(deck.is_playing());
+    }
+}
@@ -3,9 +3,26 @@
 #[macro_use]
 extern crate enum_display_derive;
 
+pub mod audio;
+pub mod border;
+pub mod calculator;
+pub mod cartridge;
+pub mod chips;
 pub mod cpu;
+pub mod debug;
+pub mod devices;
+pub mod disk;
+pub mod input;
 pub mod ops;
+pub mod rzx;
+pub mod scheduler;
+pub mod screenshot;
+#[cfg(feature = "script")]
+pub mod script;
+pub mod testing;
 #[macro_use]
 mod assert;
 pub mod examples;
+pub mod frame;
+pub mod machine;
 pub mod z80;
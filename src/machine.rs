@@ -0,0 +1,74 @@
+//! Ready-made machines: a processor wired up to the memory map, devices and interrupts of a
+//! particular board. They double as end-to-end tests of how the pieces fit together.
+//! ROMs are not included; bring your own. To build a machine of your own, start from
+//! `homebrew`.
+use crate::z80::Z80;
+
+pub mod cpc;
+pub mod homebrew;
+pub mod invaders;
+pub mod keyboard;
+pub mod msx;
+pub mod pacman;
+pub mod regression;
+
+/// A picture, ready to show: `width` by `height` pixels, a row at a time, as red, green and blue
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Framebuffer {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<[u8; 3]>,
+}
+
+impl Framebuffer {
+    /// A picture drawn as indices into a palette, as most video chips draw them
+    pub fn from_indexed(width: usize, height: usize, indices: &[u8], palette: &[[u8; 3]]) -> Self {
+        Self {
+            width,
+            height,
+            pixels: indices.iter().map(|i| palette[usize::from(*i)]).collect(),
+        }
+    }
+}
+
+/// Given the picture and the sound of each frame as it ends: the samples made during the frame
+/// (empty for machines whose sound isn't emulated)
+pub type FrameCallback = Box<dyn FnMut(&Framebuffer, &[i16])>;
+
+/// What every machine can do, so that a front end can drive any of them
+pub trait Machine {
+    /// The processor, to inspect or change
+    fn z80(&mut self) -> &mut Z80;
+
+    /// Run until the end of the next frame
+    fn run_frame(&mut self);
+
+    /// The number of frames run so far
+    fn frame(&self) -> u64;
+
+    /// The picture as it stands. Machines without a display give an empty one.
+    fn framebuffer(&self) -> Framebuffer {
+        Framebuffer::default()
+    }
+
+    /// Call back at the end of every frame with the picture and sound, replacing any callback
+    /// already set. Front ends can show and play them as they come, without polling. For
+    /// example:
+    /// ```
+    /// use std::cell::Cell;
+    /// use std::rc::Rc;
+    /// use zeerust::machine::{invaders::{self, SpaceInvaders}, Machine};
+    ///
+    /// let mut machine = SpaceInvaders::new(&[0xC3, 0x00, 0x00]); // JMP 0
+    /// let shown = Rc::new(Cell::new(0));
+    /// let s = shown.clone();
+    /// machine.on_frame(Box::new(move |picture, _audio| {
+    ///     assert_eq!(invaders::WIDTH * invaders::HEIGHT, picture.pixels.len());
+    ///     s.set(s.get() + 1);
+    /// }));
+    /// machine.run_frame();
+    /// machine.run_frame();
+    /// assert_eq!(2, shown.get());
+    ///```
+    fn on_frame(&mut self, callback: FrameCallback);
+}
@@ -0,0 +1,870 @@
+//! An Amstrad CPC 464.
+//! A Z80 at 4 MHz with 64K of RAM, which the OS ROM can overlay at 0x0000 and the BASIC ROM at
+//! 0xC000. Writes always go to RAM, and the screen is read from it by the CRTC.
+//! The CPC only decodes a few bits of the top byte of each port address, so its devices are
+//! routed by a PortDecoder: the Gate Array answers when A15 is clear and A14 set, the CRTC when
+//! A14 is clear, and the PPI when A11 is clear. The PPI in turn drives the AY sound chip, whose
+//! I/O port reads the keyboard matrix.
+//! The Gate Array counts the CRTC's horizontal syncs, raising an interrupt every 52 scanlines
+//! (six times a frame), and keeps them in step with its vertical sync. The processor
+//! acknowledges them in mode 1.
+//! Memory cycles are not yet stretched to the CPC's 4 T-state boundaries.
+//! The RAM, the Gate Array, the PPI, the AY and the CRTC are kept in a state chunk, so
+//! checkpoints and savestates taken between frames restore the whole machine.
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use super::keyboard::{self, MatrixKey, Typist};
+use super::{FrameCallback, Framebuffer, Machine};
+use crate::audio::Mixer;
+use crate::chips::ay38910::Ay38910;
+use crate::chips::crtc6845::{self, Crtc6845};
+use crate::frame::{FrameTimer, FrameTiming};
+use crate::input::{self, InputEvent, InputQueue, JoystickControl};
+use crate::z80::{
+    bus::Bus,
+    io::{InputDevice, OutputDevice},
+    ports::PortDecoder,
+    savestate::{write_block, SaveStateError, StateChunk, StateReader},
+    Z80,
+};
+
+/// The processor clock, in Hz
+pub const CLOCK: u32 = 4_000_000;
+/// The clock of the AY sound chip and the CRTC, which reads a character every cycle, in Hz
+pub const CHIP_CLOCK: u32 = 1_000_000;
+/// 50 frames a second, of 64 microsecond lines
+pub const TIMING: FrameTiming = FrameTiming {
+    tstates_per_line: 256,
+    lines_per_frame: 312,
+};
+/// The rows in the keyboard matrix
+pub const KEY_ROWS: usize = 10;
+
+// What each key of the matrix types, without shift and with it. Row 9 is the joystick.
+const PLAIN: [&str; 9] = [
+    "\0\0\0\0\0\0\0\0",
+    "\0\0\0\0\0\0\0\0",
+    "\0[\n]\0\0\\\0",
+    "^-@p;:/.",
+    "09oilkm,",
+    "87uyhjn ",
+    "65rtgfbv",
+    "43ewsdcx",
+    "12\0q\ta\0z",
+];
+const SHIFTED: [&str; 9] = [
+    "",
+    "",
+    "\0{\0}\0\0`\0",
+    "£=|P+*?>",
+    "_)OILKM<",
+    "('UYHJN\0",
+    "&%RTGFBV",
+    "$#EWSDCX",
+    "!\"\0Q\0A\0Z",
+];
+const SHIFT: MatrixKey = (2, 5);
+
+fn keymap(c: char) -> Option<(MatrixKey, bool)> {
+    keyboard::lookup(&PLAIN, &SHIFTED, c)
+}
+
+/// The 32 hardware colours, as red, green and blue, by the number given to the Gate Array.
+/// Only 27 are different.
+pub const PALETTE: [[u8; 3]; 32] = [
+    [0x80, 0x80, 0x80],
+    [0x80, 0x80, 0x80],
+    [0x00, 0xFF, 0x80],
+    [0xFF, 0xFF, 0x80],
+    [0x00, 0x00, 0x80],
+    [0xFF, 0x00, 0x80],
+    [0x00, 0x80, 0x80],
+    [0xFF, 0x80, 0x80],
+    [0xFF, 0x00, 0x80],
+    [0xFF, 0xFF, 0x80],
+    [0xFF, 0xFF, 0x00],
+    [0xFF, 0xFF, 0xFF],
+    [0xFF, 0x00, 0x00],
+    [0xFF, 0x00, 0xFF],
+    [0xFF, 0x80, 0x00],
+    [0xFF, 0x80, 0xFF],
+    [0x00, 0x00, 0x80],
+    [0x00, 0xFF, 0x80],
+    [0x00, 0xFF, 0x00],
+    [0x00, 0xFF, 0xFF],
+    [0x00, 0x00, 0x00],
+    [0x00, 0x00, 0xFF],
+    [0x00, 0x80, 0x00],
+    [0x00, 0x80, 0xFF],
+    [0x80, 0x00, 0x80],
+    [0x80, 0xFF, 0x80],
+    [0x80, 0xFF, 0x00],
+    [0x80, 0xFF, 0xFF],
+    [0x80, 0x00, 0x00],
+    [0x80, 0x00, 0xFF],
+    [0x80, 0x80, 0x00],
+    [0x80, 0x80, 0xFF],
+];
+
+const ROM_SIZE: usize = 0x4000;
+const INTERRUPT_LINES: u8 = 52;
+const BORDER: usize = 16;
+// The CRTC registers as the firmware sets them up: 64 characters by 312 lines, showing 40 by 25
+const CRTC_SETUP: [u8; 14] = [63, 40, 46, 0x8E, 38, 0, 25, 30, 0, 7, 0, 0, 0x30, 0x00];
+
+// Port B of the PPI: an Amstrad machine with a 50Hz screen, and no expansion or printer
+const PORT_B: u8 = 0b0101_1110;
+// What the top two bits of PPI port C ask the AY to do
+const PSG_READ: u8 = 0b01;
+const PSG_WRITE: u8 = 0b10;
+const PSG_SELECT: u8 = 0b11;
+
+struct GateArray {
+    pen: usize,
+    // The colours of the 16 pens, and then the border
+    inks: [u8; 17],
+    mode: u8,
+    lower_rom: bool,
+    upper_rom: bool,
+    // Scanlines since the last interrupt
+    counter: u8,
+    // Scanlines to go until the counter is brought in step with the vertical sync
+    vsync_delay: u8,
+    // An interrupt not yet passed on to the processor
+    raised: bool,
+}
+
+impl GateArray {
+    fn write(&mut self, val: u8) {
+        match val >> 6 {
+            0 if val & 0x10 != 0 => self.pen = BORDER,
+            0 => self.pen = usize::from(val & 0x0F),
+            1 => self.inks[self.pen] = val & 0x1F,
+            2 => {
+                self.mode = val & 0b11;
+                self.lower_rom = val & 0x04 == 0;
+                self.upper_rom = val & 0x08 == 0;
+                if val & 0x10 != 0 {
+                    self.counter = 0;
+                }
+            }
+            // RAM banking, on the 6128
+            _ => (),
+        }
+    }
+
+    // Called at the end of every horizontal sync
+    fn hsync(&mut self) {
+        self.counter += 1;
+        if self.vsync_delay > 0 {
+            self.vsync_delay -= 1;
+            if self.vsync_delay == 0 {
+                // Unless an interrupt has only just happened, this is a good time for one
+                self.raised |= self.counter >= 32;
+                self.counter = 0;
+                return;
+            }
+        }
+        if self.counter == INTERRUPT_LINES {
+            self.counter = 0;
+            self.raised = true;
+        }
+    }
+}
+
+struct Board {
+    ram: Vec<u8>,
+    os: Vec<u8>,
+    basic: Vec<u8>,
+    gate_array: GateArray,
+}
+
+impl Board {
+    fn read(&self, addr: u16) -> u8 {
+        let addr = usize::from(addr);
+        match addr {
+            0x0000..=0x3FFF if self.gate_array.lower_rom => self.os[addr],
+            0xC000..=0xFFFF if self.gate_array.upper_rom => self.basic[addr - 0xC000],
+            _ => self.ram[addr],
+        }
+    }
+}
+
+struct Ppi {
+    port_a: u8,
+    port_c: u8,
+    vsync: bool,
+    // Active low, like the hardware
+    keys: [u8; KEY_ROWS],
+    ay: Ay38910,
+}
+
+impl Ppi {
+    fn read(&mut self, port: u8) -> u8 {
+        match port {
+            0 if self.port_c >> 6 == PSG_READ => {
+                let row = usize::from(self.port_c & 0x0F);
+                let keys = self.keys.get(row).copied().unwrap_or(0xFF);
+                self.ay.set_port_input(0, keys);
+                self.ay.read()
+            }
+            0 => self.port_a,
+            1 => PORT_B | u8::from(self.vsync),
+            2 => self.port_c,
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, port: u8, val: u8) {
+        match port {
+            0 => self.port_a = val,
+            2 => self.port_c = val,
+            // Setting the mode clears the outputs
+            _ if val & 0x80 != 0 => {
+                self.port_a = 0;
+                self.port_c = 0;
+            }
+            // Set or reset a single bit of port C
+            _ => {
+                let bit = 1 << ((val >> 1) & 0b111);
+                if val & 1 == 1 {
+                    self.port_c |= bit;
+                } else {
+                    self.port_c &= !bit;
+                }
+            }
+        }
+        match self.port_c >> 6 {
+            PSG_WRITE => self.ay.write(self.port_a),
+            PSG_SELECT => self.ay.select(self.port_a),
+            _ => (),
+        }
+    }
+}
+
+struct GateArrayPort(Rc<RefCell<Board>>);
+
+impl OutputDevice for GateArrayPort {
+    fn output(&self, val: u8) {
+        self.0.borrow_mut().gate_array.write(val);
+    }
+}
+
+// One of the PPI's ports: A, B, C or control
+struct PpiPort(Rc<RefCell<Ppi>>, u8);
+
+impl InputDevice for PpiPort {
+    fn input(&self) -> u8 {
+        self.0.borrow_mut().read(self.1)
+    }
+}
+
+impl OutputDevice for PpiPort {
+    fn output(&self, val: u8) {
+        self.0.borrow_mut().write(self.1, val);
+    }
+}
+
+struct BoardBus {
+    board: Rc<RefCell<Board>>,
+    ports: PortDecoder,
+}
+
+impl Bus for BoardBus {
+    fn mem_read(&self, addr: u16) -> u8 {
+        self.board.borrow().read(addr)
+    }
+
+    fn mem_write(&self, addr: u16, val: u8) {
+        self.board.borrow_mut().ram[usize::from(addr)] = val;
+    }
+
+    fn io_read(&self, port: u16) -> u8 {
+        self.ports.input(port).unwrap_or(0xFF)
+    }
+
+    fn io_write(&self, port: u16, val: u8) {
+        self.ports.output(port, val);
+    }
+
+    fn acknowledge(&self) -> u8 {
+        // So that the next interrupt can't come too soon
+        self.board.borrow_mut().gate_array.counter &= 0x1F;
+        0xFF
+    }
+}
+
+// Everything on the board, memory included. The keys aren't restored, as they are whatever is
+// held down now.
+struct BoardChunk {
+    board: Rc<RefCell<Board>>,
+    ppi: Rc<RefCell<Ppi>>,
+    crtc: Rc<RefCell<Crtc6845>>,
+}
+
+impl BoardChunk {
+    fn load_state(&self, state: &[u8]) -> Result<(), SaveStateError> {
+        let mut reader = StateReader::new(state);
+        let ram = reader.block()?;
+        let pen = usize::from(reader.u8()?).min(BORDER);
+        let inks = reader.array()?;
+        let [mode, lower_rom, upper_rom, counter, vsync_delay, raised] = reader.array()?;
+        let [port_a, port_c, vsync] = reader.array()?;
+        let ay = reader.block()?;
+        let crtc = reader.block()?;
+        if ram.len() != 0x10000 {
+            return Err(SaveStateError::Truncated);
+        }
+        let mut ppi = self.ppi.borrow_mut();
+        ppi.ay.load_state(ay)?;
+        self.crtc.borrow_mut().load_state(crtc)?;
+        ppi.port_a = port_a;
+        ppi.port_c = port_c;
+        ppi.vsync = vsync != 0;
+        let mut board = self.board.borrow_mut();
+        board.ram.copy_from_slice(ram);
+        board.gate_array = GateArray {
+            pen,
+            inks,
+            mode,
+            lower_rom: lower_rom != 0,
+            upper_rom: upper_rom != 0,
+            counter,
+            vsync_delay,
+            raised: raised != 0,
+        };
+        Ok(())
+    }
+}
+
+impl StateChunk for BoardChunk {
+    fn tag(&self) -> [u8; 4] {
+        *b"CPC4"
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let board = self.board.borrow();
+        let ppi = self.ppi.borrow();
+        let gate_array = &board.gate_array;
+        let mut out = Vec::new();
+        write_block(&mut out, &board.ram);
+        out.push(gate_array.pen as u8);
+        out.extend_from_slice(&gate_array.inks);
+        out.extend_from_slice(&[
+            gate_array.mode,
+            gate_array.lower_rom as u8,
+            gate_array.upper_rom as u8,
+            gate_array.counter,
+            gate_array.vsync_delay,
+            gate_array.raised as u8,
+            ppi.port_a,
+            ppi.port_c,
+            ppi.vsync as u8,
+        ]);
+        write_block(&mut out, &ppi.ay.save_state());
+        write_block(&mut out, &self.crtc.borrow().save_state());
+        out
+    }
+
+    fn load(&self, chunk: Option<&[u8]>) -> Result<(), SaveStateError> {
+        let bad = || SaveStateError::BadChunk(self.tag());
+        let chunk = chunk.ok_or_else(bad)?;
+        self.load_state(chunk).map_err(|_| bad())
+    }
+
+    fn holds_memory(&self) -> bool {
+        true
+    }
+}
+
+/// A CPC 464, ready to run
+pub struct Cpc {
+    pub z80: Z80,
+    board: Rc<RefCell<Board>>,
+    ppi: Rc<RefCell<Ppi>>,
+    crtc: Rc<RefCell<Crtc6845>>,
+    timer: FrameTimer,
+    mixer: Rc<RefCell<Mixer>>,
+    on_frame: Option<FrameCallback>,
+    // The samples made so far this frame, while there's a frame callback
+    audio: Rc<RefCell<Vec<i16>>>,
+    typist: Typist,
+    input: Option<InputQueue>,
+}
+
+impl Cpc {
+    /// Create a CPC with the given OS and BASIC ROMs, both paged in, playing sound through a
+    /// mixer made for CLOCK.
+    ///
+    /// # Panics
+    /// Panics if either ROM is larger than 16K
+    pub fn new(os: &[u8], basic: &[u8], mixer: Rc<RefCell<Mixer>>) -> Self {
+        assert!(os.len() <= ROM_SIZE, "the OS ROM must fit in 16K");
+        assert!(basic.len() <= ROM_SIZE, "the BASIC ROM must fit in 16K");
+        let rom = |data: &[u8]| {
+            let mut rom = data.to_vec();
+            rom.resize(ROM_SIZE, 0xFF);
+            rom
+        };
+        let board = Rc::new(RefCell::new(Board {
+            ram: vec![0; 0x10000],
+            os: rom(os),
+            basic: rom(basic),
+            gate_array: GateArray {
+                pen: 0,
+                inks: [0; 17],
+                mode: 0,
+                lower_rom: true,
+                upper_rom: true,
+                counter: 0,
+                vsync_delay: 0,
+                raised: false,
+            },
+        }));
+        let ppi = Rc::new(RefCell::new(Ppi {
+            port_a: 0,
+            port_c: 0,
+            vsync: false,
+            keys: [0xFF; KEY_ROWS],
+            ay: Ay38910::new(CHIP_CLOCK, CLOCK, mixer.clone()),
+        }));
+
+        let mut crtc = Crtc6845::default();
+        for (reg, &val) in CRTC_SETUP.iter().enumerate() {
+            crtc.select(reg as u8);
+            crtc.write(val);
+        }
+        let b = board.clone();
+        crtc.on_hsync(Box::new(move |active| {
+            if !active {
+                b.borrow_mut().gate_array.hsync();
+            }
+        }));
+        let (b, p) = (board.clone(), ppi.clone());
+        crtc.on_vsync(Box::new(move |active| {
+            p.borrow_mut().vsync = active;
+            if active {
+                b.borrow_mut().gate_array.vsync_delay = 2;
+            }
+        }));
+        let crtc = Rc::new(RefCell::new(crtc));
+
+        let mut ports = PortDecoder::default();
+        ports.route_output(0xC000, 0x4000, Box::new(GateArrayPort(board.clone())));
+        ports.route_output(0x4300, 0x0000, Box::new(crtc6845::SelectPort(crtc.clone())));
+        ports.route_output(0x4300, 0x0100, Box::new(crtc6845::DataPort(crtc.clone())));
+        ports.route_input(0x4300, 0x0300, Box::new(crtc6845::DataPort(crtc.clone())));
+        for port in 0..4 {
+            let value = u16::from(port) << 8;
+            ports.route_input(0x0B00, value, Box::new(PpiPort(ppi.clone(), port)));
+            ports.route_output(0x0B00, value, Box::new(PpiPort(ppi.clone(), port)));
+        }
+
+        let mut z80 = Z80::default();
+        z80.set_bus(Box::new(BoardBus {
+            board: board.clone(),
+            ports,
+        }));
+        z80.add_state_chunk(Box::new(BoardChunk {
+            board: board.clone(),
+            ppi: ppi.clone(),
+            crtc: crtc.clone(),
+        }));
+
+        Self {
+            z80,
+            board,
+            ppi,
+            crtc,
+            timer: FrameTimer::new(TIMING),
+            mixer,
+            on_frame: None,
+            audio: Rc::default(),
+            typist: Typist::new(keymap, SHIFT),
+            input: None,
+        }
+    }
+
+    /// Press or release the key at the given row (0 to 9) and column (0 to 7) of the
+    /// keyboard matrix. Row 9 also has the joystick.
+    pub fn set_key(&mut self, row: usize, col: u8, pressed: bool) {
+        let keys = &mut self.ppi.borrow_mut().keys;
+        if pressed {
+            keys[row] &= !(1 << col);
+        } else {
+            keys[row] |= 1 << col;
+        }
+    }
+
+    /// Take input from a queue, each event applied between instructions once its T-state
+    /// comes. Joystick 0 is on row 9 of the matrix and joystick 1 on row 6, and there's no
+    /// tape deck.
+    pub fn set_input(&mut self, queue: InputQueue) {
+        self.input = Some(queue);
+    }
+
+    /// Stop taking input from the queue, returning it
+    pub fn take_input(&mut self) -> Option<InputQueue> {
+        self.input.take()
+    }
+
+    fn apply_input(&mut self) {
+        let now = self.z80.tstates();
+        while let Some(event) = self.input.as_mut().and_then(|queue| queue.next_due(now)) {
+            match event {
+                InputEvent::Key { row, col, pressed } if row < KEY_ROWS && col < 8 => {
+                    self.set_key(row, col, pressed)
+                }
+                InputEvent::Joystick {
+                    joystick: joystick @ 0..=1,
+                    control,
+                    pressed,
+                } => {
+                    let col = match control {
+                        JoystickControl::Up => 0,
+                        JoystickControl::Down => 1,
+                        JoystickControl::Left => 2,
+                        JoystickControl::Right => 3,
+                        JoystickControl::Fire => 5,
+                    };
+                    self.set_key([9, 6][usize::from(joystick)], col, pressed);
+                }
+                event => input::ignore(event),
+            }
+        }
+    }
+
+    /// Type some text on the keyboard, a key every few frames from the next, after any text
+    /// still being typed. Letters type as they would with caps lock off, so capitals are
+    /// typed with shift, and a newline presses RETURN:
+    /// `cpc.type_text("10 PRINT \"HI\"\nRUN\n")`.
+    pub fn type_text(&mut self, text: &str) {
+        self.typist.type_text(text);
+    }
+
+    /// Whether there's still text being typed
+    pub fn is_typing(&self) -> bool {
+        self.typist.is_typing()
+    }
+
+    /// The number of frames run so far
+    pub fn frame(&self) -> u64 {
+        self.timer.frame()
+    }
+
+    /// Run until the end of the next frame
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "frame", level = "debug", skip_all)
+    )]
+    pub fn run_frame(&mut self) {
+        for ((row, col), pressed) in self.typist.frame() {
+            self.set_key(row, col, pressed);
+        }
+        loop {
+            self.apply_input();
+            if self.z80.is_halted() && !self.z80.iff1() && !self.z80.is_nmi_pending() {
+                // Halted with interrupts off, so nothing more can happen this frame
+                let remaining = TIMING.tstates_per_frame() - self.timer.frame_tstate();
+                self.timer.advance(remaining);
+                break;
+            }
+            let before = self.z80.tstates();
+            self.z80.step();
+            let done = self.timer.advance((self.z80.tstates() - before) as u32);
+
+            // From the T-states either side, rather than a running total, so that going back
+            // to a checkpoint doesn't upset it
+            let chip = |tstates: u64| tstates * u64::from(CHIP_CLOCK) / u64::from(CLOCK);
+            let cycles = chip(self.z80.tstates()) - chip(before);
+            self.ppi.borrow_mut().ay.run(cycles);
+            self.crtc.borrow_mut().run(cycles);
+
+            if std::mem::take(&mut self.board.borrow_mut().gate_array.raised) {
+                self.z80.request_interrupt();
+            }
+            if done {
+                break;
+            }
+        }
+        self.end_frame();
+    }
+
+    // Flush the sound made this frame, and hand it to the callback with the picture
+    fn end_frame(&mut self) {
+        if self.on_frame.is_none() {
+            return;
+        }
+        self.mixer.borrow_mut().flush();
+        let audio = std::mem::take(&mut *self.audio.borrow_mut());
+        let picture = Machine::framebuffer(self);
+        if let Some(f) = &mut self.on_frame {
+            f(&picture, &audio);
+        }
+    }
+
+    /// The size of the picture the CRTC is set up to display, without the border
+    pub fn screen_size(&self) -> (usize, usize) {
+        let crtc = self.crtc.borrow();
+        (
+            usize::from(crtc.register(crtc6845::HORIZONTAL_DISPLAYED)) * 16,
+            usize::from(crtc.register(crtc6845::VERTICAL_DISPLAYED))
+                * crtc.rasters_per_row() as usize,
+        )
+    }
+
+    /// Draw the picture: screen_size pixels, a row at a time, each an index into PALETTE.
+    /// Pixels are as wide as mode 2's, so modes 1 and 0 repeat each pixel 2 or 4 times.
+    pub fn render(&self) -> Vec<u8> {
+        let crtc = self.crtc.borrow();
+        let board = self.board.borrow();
+        let (width, height) = self.screen_size();
+        let chars = usize::from(crtc.register(crtc6845::HORIZONTAL_DISPLAYED));
+        let rasters = crtc.rasters_per_row() as usize;
+
+        let mut screen = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for col in 0..chars {
+                // Each character is two bytes, and each of its scanlines is 2K further on
+                let ma = usize::from(crtc.start_address()) + y / rasters * chars + col;
+                let addr = (ma & 0x3000) << 2 | ((y % rasters) & 0b111) << 11 | (ma & 0x3FF) << 1;
+                for &byte in &board.ram[addr..addr + 2] {
+                    let pens = pens(board.gate_array.mode, byte);
+                    screen.extend(pens.iter().map(|&pen| board.gate_array.inks[pen]));
+                }
+            }
+        }
+        screen
+    }
+}
+
+// The pens of the 8 mode 2 pixels a byte of screen memory covers
+fn pens(mode: u8, byte: u8) -> [usize; 8] {
+    let bit = |n: u8| usize::from(byte >> n & 1);
+    let mut pens = [0; 8];
+    for (i, pen) in pens.iter_mut().enumerate() {
+        let i = i as u8;
+        *pen = match mode {
+            2 => bit(7 - i),
+            1 => bit(7 - i / 2) | bit(3 - i / 2) << 1,
+            // Mode 3 is mode 0 with only 4 pens
+            _ => {
+                let p = i / 4;
+                let pen = bit(7 - p) | bit(3 - p) << 1 | bit(5 - p) << 2 | bit(1 - p) << 3;
+                if mode == 3 {
+                    pen & 0b11
+                } else {
+                    pen
+                }
+            }
+        };
+    }
+    pens
+}
+
+impl Machine for Cpc {
+    fn z80(&mut self) -> &mut Z80 {
+        &mut self.z80
+    }
+
+    fn run_frame(&mut self) {
+        Cpc::run_frame(self)
+    }
+
+    fn frame(&self) -> u64 {
+        Cpc::frame(self)
+    }
+
+    fn framebuffer(&self) -> Framebuffer {
+        let (width, height) = self.screen_size();
+        Framebuffer::from_indexed(width, height, &self.render(), &PALETTE)
+    }
+
+    /// The sound is taken from the mixer, so this replaces the mixer's buffer callback
+    fn on_frame(&mut self, callback: FrameCallback) {
+        let audio = self.audio.clone();
+        self.mixer.borrow_mut().on_buffer(Box::new(move |buf| {
+            audio.borrow_mut().extend_from_slice(buf)
+        }));
+        self.on_frame = Some(callback);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::audio;
+
+    fn cpc(os: &[u8]) -> Cpc {
+        let mixer = Mixer::new(CLOCK, audio::RATE_44_1KHZ, 1024);
+        Cpc::new(os, &[0xAA], Rc::new(RefCell::new(mixer)))
+    }
+
+    #[test]
+    fn ports_and_interrupts() {
+        let mut os = vec![
+            0x01, 0x00, 0x7F, // 0000 LD BC, 0x7F00: pen 0
+            0xED, 0x49, // 0003 OUT (C), C
+            0x0E, 0x54, // 0005 LD C, 0x54: black
+            0xED, 0x49, // 0007 OUT (C), C
+            0x0E, 0x89, // 0009 LD C, 0x89: mode 1, upper ROM off
+            0xED, 0x49, // 000B OUT (C), C
+            0x01, 0x0C, 0xBC, // 000D LD BC, 0xBC0C: CRTC register 12
+            0xED, 0x49, // 0010 OUT (C), C
+            0x01, 0x30, 0xBD, // 0012 LD BC, 0xBD30
+            0xED, 0x49, // 0015 OUT (C), C
+            0x01, 0x0E, 0xF4, // 0017 LD BC, 0xF40E: AY register 14
+            0xED, 0x49, // 001A OUT (C), C
+            0x01, 0xC0, 0xF6, // 001C LD BC, 0xF6C0: select it
+            0xED, 0x49, // 001F OUT (C), C
+            0x0E, 0x49, // 0021 LD C, 0x49: read it, with keyboard row 9
+            0xED, 0x49, // 0023 OUT (C), C
+            0x06, 0xF4, // 0025 LD B, 0xF4
+            0xED, 0x78, // 0027 IN A, (C)
+            0x32, 0x00, 0x80, // 0029 LD (0x8000), A
+            0xED, 0x56, // 002C IM 1
+            0xFB, // 002E EI
+            0x76, // 002F HALT
+            0x18, 0xFD, // 0030 JR -3
+        ];
+        os.resize(0x38, 0x00);
+        os.extend_from_slice(&[
+            0x21, 0x01, 0x80, // 0038 LD HL, 0x8001
+            0x34, // 003B INC (HL)
+            0xFB, // 003C EI
+            0xC9, // 003D RET
+        ]);
+        let mut cpc = cpc(&os);
+        cpc.set_key(9, 4, true);
+        cpc.run_frame();
+
+        let board = cpc.board.borrow();
+        assert_eq!(0x14, board.gate_array.inks[0]);
+        assert_eq!(1, board.gate_array.mode);
+        assert_eq!(0x00, board.read(0xC000));
+        assert_eq!(0x3000, cpc.crtc.borrow().start_address());
+        assert_eq!(0b1110_1111, board.ram[0x8000]);
+        let interrupts = board.ram[0x8001];
+        drop(board);
+
+        // Six a frame
+        cpc.run_frame();
+        assert_eq!(interrupts + 6, cpc.board.borrow().ram[0x8001]);
+    }
+
+    #[test]
+    fn vsync() {
+        let cpc = cpc(&[]);
+        // Row 30 of 8 scanlines, each 64 characters long
+        cpc.crtc.borrow_mut().run(64 * 240 - 1);
+        assert_eq!(0, cpc.ppi.borrow_mut().read(1) & 1);
+        cpc.crtc.borrow_mut().run(1);
+        assert_eq!(1, cpc.ppi.borrow_mut().read(1) & 1);
+        assert_eq!(2, cpc.board.borrow().gate_array.vsync_delay);
+        cpc.crtc.borrow_mut().run(64 * 16);
+        assert_eq!(0, cpc.ppi.borrow_mut().read(1) & 1);
+    }
+
+    #[test]
+    fn render() {
+        let cpc = cpc(&[]);
+        {
+            let mut crtc = cpc.crtc.borrow_mut();
+            for (reg, val) in [
+                (crtc6845::HORIZONTAL_DISPLAYED, 40),
+                (crtc6845::VERTICAL_DISPLAYED, 25),
+                (crtc6845::MAX_RASTER, 7),
+                (crtc6845::START_ADDRESS_HIGH, 0x30),
+            ] {
+                crtc.select(reg as u8);
+                crtc.write(val);
+            }
+        }
+        assert_eq!((640, 200), cpc.screen_size());
+
+        let mut board = cpc.board.borrow_mut();
+        board.gate_array.mode = 1;
+        board.gate_array.inks[..4].copy_from_slice(&[0x14, 0x04, 0x15, 0x0B]);
+        // The first pixel in pen 3, and the next in pen 1; then the second scanline
+        board.ram[0xC000] = 0b1100_1000;
+        board.ram[0xC800] = 0b0000_0001;
+        drop(board);
+
+        let screen = cpc.render();
+        assert_eq!(640 * 200, screen.len());
+        assert_eq!(&[0x0B, 0x0B, 0x04, 0x04, 0x14], &screen[..5]);
+        assert_eq!(&[0x14, 0x15, 0x15], &screen[645..648]);
+    }
+
+    #[test]
+    fn on_frame() {
+        let mut cpc = cpc(&[0x18, 0xFE]); // JR -2
+        let frames = Rc::new(RefCell::new(vec![]));
+        let f = frames.clone();
+        cpc.on_frame(Box::new(move |picture, audio| {
+            f.borrow_mut()
+                .push((picture.width, picture.pixels[0], audio.len()))
+        }));
+        cpc.run_frame();
+        cpc.run_frame();
+        // A fiftieth of a second of sound each, rather than whole buffers of the mixer's
+        let frames = frames.borrow();
+        assert_eq!(2, frames.len());
+        for (width, pixel, samples) in frames.iter() {
+            assert_eq!((640, PALETTE[0]), (*width, *pixel));
+            assert!((880..=884).contains(samples), "{} samples", samples);
+        }
+    }
+
+    #[test]
+    fn typing() {
+        assert_eq!(Some(((2, 2), false)), keymap('\n'));
+        assert_eq!(Some(((3, 0), true)), keymap('£'));
+        assert_eq!(Some(((8, 1), true)), keymap('"'));
+        assert_eq!(None, keymap('~'));
+
+        let mut cpc = cpc(&[0x18, 0xFE]); // JR -2
+        cpc.type_text("P");
+        cpc.run_frame();
+        let keys = cpc.ppi.borrow().keys;
+        assert_eq!((0b1101_1111, 0b1111_0111), (keys[2], keys[3]));
+        for _ in 0..3 {
+            cpc.run_frame();
+        }
+        assert_eq!([0xFF; KEY_ROWS], cpc.ppi.borrow().keys);
+        assert!(!cpc.is_typing());
+    }
+
+    #[test]
+    fn input_queue() {
+        let mut cpc = cpc(&[0x18, 0xFE]); // JR -2
+        let queue = InputQueue::new();
+        let sender = queue.sender();
+        cpc.set_input(queue);
+        let fire = |pressed| InputEvent::Joystick {
+            joystick: 1,
+            control: JoystickControl::Fire,
+            pressed,
+        };
+        sender.send_at(1000, fire(true));
+        sender.send_at(1012, fire(false));
+        while cpc.z80.tstates() < 1000 {
+            cpc.apply_input();
+            cpc.z80.step();
+        }
+        cpc.apply_input();
+        assert_eq!(0b1101_1111, cpc.ppi.borrow().keys[6]);
+        cpc.run_frame();
+        assert_eq!(0xFF, cpc.ppi.borrow().keys[6]);
+        assert!(cpc.take_input().unwrap().is_empty());
+    }
+
+    #[test]
+    fn pixels() {
+        assert_eq!([1, 0, 1, 0, 1, 0, 1, 0], pens(2, 0xAA));
+        assert_eq!([3, 3, 1, 1, 2, 2, 0, 0], pens(1, 0b1100_1010));
+        assert_eq!([15, 15, 15, 15, 0, 0, 0, 0], pens(0, 0b1010_1010));
+        assert_eq!([3, 3, 3, 3, 0, 0, 0, 0], pens(3, 0b1010_1010));
+    }
+}
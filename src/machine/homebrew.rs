@@ -0,0 +1,225 @@
+//! A minimal homebrew computer, in the spirit of the Galaksija: the template to copy when
+//! building a machine of your own. It has:
+//!
+//! * 16K of RAM, holding the program
+//! * a serial console on ports 0x00 (data) and 0x01 (status)
+//! * a timer that interrupts the processor 50 times a second
+//!
+//! Real machines need more than this (a Bus for their memory map, video, sound...), and the
+//! other machines in this module show how to add those. See `examples/homebrew.rs` for a
+//! program running on it.
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use super::{FrameCallback, Framebuffer, Machine};
+use crate::frame::{FrameTimer, FrameTiming};
+use crate::z80::{
+    io::{InputDevice, OutputDevice},
+    ports::PortDecoder,
+    Z80,
+};
+
+/// The processor clock, in Hz
+pub const CLOCK: u32 = 4_000_000;
+/// How often the timer interrupts, in Hz
+pub const TIMER_RATE: u32 = 50;
+
+/// The console's data port: reading takes the next byte typed, and writing prints a byte
+pub const DATA_PORT: u8 = 0x00;
+/// The console's status port: bit 0 is set when a byte has been typed, and bit 1 when a byte
+/// can be sent (which is always)
+pub const STATUS_PORT: u8 = 0x01;
+
+const INPUT_READY: u8 = 0b01;
+const OUTPUT_READY: u8 = 0b10;
+
+// The machine has no video, so a "frame" is just one period of the timer. A FrameTimer with a
+// single line per frame is an easy way to be told when each period is up.
+const TIMING: FrameTiming = FrameTiming {
+    tstates_per_line: CLOCK / TIMER_RATE,
+    lines_per_frame: 1,
+};
+
+// The state shared between the machine and its port devices. The processor owns the devices,
+// so anything the machine also wants to see goes behind an Rc<RefCell<..>>.
+#[derive(Default)]
+struct Console {
+    // Typed, but not yet read by the program
+    input: VecDeque<u8>,
+    // Printed by the program, but not yet collected
+    output: Vec<u8>,
+}
+
+// A device for each port. Devices are given `&self`, so the RefCell is what lets reading the
+// data port take a byte from the input queue.
+struct DataPort(Rc<RefCell<Console>>);
+
+impl InputDevice for DataPort {
+    fn input(&self) -> u8 {
+        // Reading when nothing has been typed gives 0, so programs should check the status
+        self.0.borrow_mut().input.pop_front().unwrap_or(0x00)
+    }
+}
+
+impl OutputDevice for DataPort {
+    fn output(&self, val: u8) {
+        self.0.borrow_mut().output.push(val);
+    }
+}
+
+struct StatusPort(Rc<RefCell<Console>>);
+
+impl InputDevice for StatusPort {
+    fn input(&self) -> u8 {
+        if self.0.borrow().input.is_empty() {
+            OUTPUT_READY
+        } else {
+            INPUT_READY | OUTPUT_READY
+        }
+    }
+}
+
+/// The homebrew computer, ready to run
+pub struct Homebrew {
+    pub z80: Z80,
+    console: Rc<RefCell<Console>>,
+    timer: FrameTimer,
+    on_frame: Option<FrameCallback>,
+}
+
+impl Homebrew {
+    /// Create a machine with the given program loaded at 0x0000, where the processor starts.
+    /// The timer interrupt is handled in mode 1, so a program that wants it puts its handler
+    /// at 0x0038, and runs `IM 1` and `EI`.
+    pub fn new(program: &[u8]) -> Self {
+        let console = Rc::new(RefCell::new(Console::default()));
+
+        // The ports are decoded on the low byte of the address only, as most simple
+        // machines do. Anything else reads or writes a port nothing answers, which stops the
+        // processor with a fault: a handy way to find bugs in the program.
+        let mut ports = PortDecoder::default();
+        let data = u16::from(DATA_PORT);
+        let status = u16::from(STATUS_PORT);
+        ports.route_input(0x00FF, data, Box::new(DataPort(console.clone())));
+        ports.route_output(0x00FF, data, Box::new(DataPort(console.clone())));
+        ports.route_input(0x00FF, status, Box::new(StatusPort(console.clone())));
+
+        // With no Bus set, the processor uses its own memory, and the devices routed by the
+        // port decoder.
+        let mut z80 = Z80::default();
+        z80.load(program);
+        z80.set_port_decoder(ports);
+
+        Self {
+            z80,
+            console,
+            timer: FrameTimer::new(TIMING),
+            on_frame: None,
+        }
+    }
+
+    /// Type some text into the console, for the program to read
+    pub fn type_text(&mut self, text: &[u8]) {
+        self.console.borrow_mut().input.extend(text);
+    }
+
+    /// Collect everything the program has printed since last time
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.console.borrow_mut().output)
+    }
+
+    /// The number of timer periods run so far
+    pub fn frame(&self) -> u64 {
+        self.timer.frame()
+    }
+
+    /// Run until the timer fires, then interrupt the processor
+    pub fn run_frame(&mut self) {
+        // Z80::run_frame steps the processor until the timer completes a frame. It gives up
+        // early if the processor halts with interrupts disabled, in which case the rest of
+        // the period passes with nothing happening.
+        if !self.z80.run_frame(&mut self.timer) {
+            let remaining = TIMING.tstates_per_frame() - self.timer.frame_tstate();
+            self.timer.advance(remaining);
+        }
+        // The request stays pending until the program enables interrupts
+        self.z80.request_interrupt();
+        // There's no picture or sound, but front ends may still want to know
+        if let Some(f) = &mut self.on_frame {
+            f(&Framebuffer::default(), &[]);
+        }
+    }
+}
+
+// Implementing Machine lets front ends drive this machine like any other
+impl Machine for Homebrew {
+    fn z80(&mut self) -> &mut Z80 {
+        &mut self.z80
+    }
+
+    fn run_frame(&mut self) {
+        Homebrew::run_frame(self)
+    }
+
+    fn frame(&self) -> u64 {
+        Homebrew::frame(self)
+    }
+
+    fn on_frame(&mut self, callback: FrameCallback) {
+        self.on_frame = Some(callback);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Echoes what is typed, in upper case, and prints a dot on every timer interrupt
+    const ECHO: &[u8] = &[
+        0xED, 0x56, // 0000 IM 1
+        0xFB, // 0002 EI
+        0xDB, 0x01, // 0003 IN A, (0x01)
+        0xE6, 0x01, // 0005 AND 0x01
+        0x28, 0xFA, // 0007 JR Z, -6
+        0xDB, 0x00, // 0009 IN A, (0x00)
+        0xE6, 0xDF, // 000B AND 0xDF
+        0xD3, 0x00, // 000D OUT (0x00), A
+        0x18, 0xF2, // 000F JR -14
+    ];
+
+    fn echo() -> Homebrew {
+        let mut program = ECHO.to_vec();
+        program.resize(0x38, 0x00);
+        program.extend_from_slice(&[
+            0xF5, // 0038 PUSH AF
+            0x3E, b'.', // 0039 LD A, '.'
+            0xD3, 0x00, // 003B OUT (0x00), A
+            0xF1, // 003D POP AF
+            0xFB, // 003E EI
+            0xED, 0x4D, // 003F RETI
+        ]);
+        Homebrew::new(&program)
+    }
+
+    #[test]
+    fn console() {
+        let mut machine = echo();
+        machine.type_text(b"hi");
+        machine.run_frame();
+        assert_eq!(b"HI".to_vec(), machine.take_output());
+        assert!(machine.take_output().is_empty());
+    }
+
+    #[test]
+    fn timer() {
+        let mut machine = echo();
+        for _ in 0..3 {
+            machine.run_frame();
+        }
+        machine.type_text(b"a");
+        machine.run_frame();
+        assert_eq!(b"...A".to_vec(), machine.take_output());
+        assert_eq!(4, Machine::frame(&machine));
+    }
+}
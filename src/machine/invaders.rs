@@ -0,0 +1,420 @@
+//! The Taito/Midway Space Invaders board.
+//! An 8080 at 1.9968 MHz, 8K of ROM, 1K of work RAM and 7K of video RAM, read out as a 1-bit
+//! 256x224 picture on a monitor turned on its side. A hardware shift register helps the program
+//! move sprites a pixel at a time. Two interrupts come each frame: RST 1 half way down the
+//! screen, and RST 2 at the start of vertical blank.
+//! The RAM, the shift register and the interrupt due are kept in a state chunk, so checkpoints
+//! and savestates taken between frames restore the whole board.
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use super::{FrameCallback, Framebuffer, Machine};
+use crate::frame::{FrameTimer, FrameTiming};
+use crate::z80::savestate::{write_block, write_option, SaveStateError, StateChunk, StateReader};
+use crate::z80::{bus::Bus, variant::CpuVariant, Z80};
+
+/// The processor clock, in Hz
+pub const CLOCK: u32 = 1_996_800;
+/// 60 frames a second
+pub const TIMING: FrameTiming = FrameTiming {
+    tstates_per_line: 128,
+    lines_per_frame: 260,
+};
+/// The screen, the right way up
+pub const WIDTH: usize = 224;
+pub const HEIGHT: usize = 256;
+
+const ROM_SIZE: usize = 0x2000;
+const VRAM: usize = 0x2400;
+// Interrupts are raised as these lines finish
+const MID_SCREEN: u32 = 95;
+const VBLANK: u32 = 223;
+const RST_1: u8 = 0xCF;
+const RST_2: u8 = 0xD7;
+
+/// The buttons and switches on the cabinet
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Input {
+    Coin,
+    P1Start,
+    P2Start,
+    P1Fire,
+    P1Left,
+    P1Right,
+    P2Fire,
+    P2Left,
+    P2Right,
+    Tilt,
+}
+
+impl Input {
+    // The input port and bit the input appears on
+    fn bit(self) -> (usize, u8) {
+        match self {
+            Input::Coin => (1, 0),
+            Input::P2Start => (1, 1),
+            Input::P1Start => (1, 2),
+            Input::P1Fire => (1, 4),
+            Input::P1Left => (1, 5),
+            Input::P1Right => (1, 6),
+            Input::Tilt => (2, 2),
+            Input::P2Fire => (2, 4),
+            Input::P2Left => (2, 5),
+            Input::P2Right => (2, 6),
+        }
+    }
+}
+
+struct Board {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    // Input ports 0 to 2
+    inputs: [u8; 3],
+    shift: u16,
+    shift_offset: u8,
+    // Output ports 3 and 5
+    sound: [u8; 2],
+    // The RST to put on the bus when the next interrupt is acknowledged
+    vector: u8,
+}
+
+#[derive(Clone)]
+struct BoardBus(Rc<RefCell<Board>>);
+
+impl Bus for BoardBus {
+    fn mem_read(&self, addr: u16) -> u8 {
+        // A14 and A15 aren't decoded
+        let addr = usize::from(addr & 0x3FFF);
+        let board = self.0.borrow();
+        if addr < ROM_SIZE {
+            board.rom[addr]
+        } else {
+            board.ram[addr - ROM_SIZE]
+        }
+    }
+
+    fn mem_write(&self, addr: u16, val: u8) {
+        let addr = usize::from(addr & 0x3FFF);
+        if addr >= ROM_SIZE {
+            self.0.borrow_mut().ram[addr - ROM_SIZE] = val;
+        }
+    }
+
+    fn io_read(&self, port: u16) -> u8 {
+        let board = self.0.borrow();
+        match port & 0xFF {
+            p @ 0..=2 => board.inputs[usize::from(p)],
+            3 => (board.shift >> (8 - board.shift_offset)) as u8,
+            _ => 0x00,
+        }
+    }
+
+    fn io_write(&self, port: u16, val: u8) {
+        let mut board = self.0.borrow_mut();
+        match port & 0xFF {
+            2 => board.shift_offset = val & 0b111,
+            3 => board.sound[0] = val,
+            4 => board.shift = u16::from(val) << 8 | board.shift >> 8,
+            5 => board.sound[1] = val,
+            // 6 is the watchdog, which is never allowed to bite
+            _ => (),
+        }
+    }
+
+    fn acknowledge(&self) -> u8 {
+        self.0.borrow().vector
+    }
+}
+
+// The RAM and the outputs. The inputs aren't restored, as they are whatever is pressed now.
+struct BoardChunk {
+    board: Rc<RefCell<Board>>,
+    due: Rc<Cell<Option<u8>>>,
+}
+
+impl BoardChunk {
+    fn load_state(&self, state: &[u8]) -> Result<(), SaveStateError> {
+        let mut reader = StateReader::new(state);
+        let ram = reader.block()?;
+        let shift = reader.u16()?;
+        let [shift_offset, sound_3, sound_5, vector] = reader.array()?;
+        let due = reader.option()?;
+        if ram.len() != 0x2000 {
+            return Err(SaveStateError::Truncated);
+        }
+        let mut board = self.board.borrow_mut();
+        board.ram.copy_from_slice(ram);
+        board.shift = shift;
+        board.shift_offset = shift_offset & 0b111;
+        board.sound = [sound_3, sound_5];
+        board.vector = vector;
+        self.due.set(due);
+        Ok(())
+    }
+}
+
+impl StateChunk for BoardChunk {
+    fn tag(&self) -> [u8; 4] {
+        *b"INVD"
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let board = self.board.borrow();
+        let mut out = Vec::new();
+        write_block(&mut out, &board.ram);
+        out.extend_from_slice(&board.shift.to_le_bytes());
+        out.extend_from_slice(&[
+            board.shift_offset,
+            board.sound[0],
+            board.sound[1],
+            board.vector,
+        ]);
+        write_option(&mut out, self.due.get());
+        out
+    }
+
+    fn load(&self, chunk: Option<&[u8]>) -> Result<(), SaveStateError> {
+        let bad = || SaveStateError::BadChunk(self.tag());
+        let chunk = chunk.ok_or_else(bad)?;
+        self.load_state(chunk).map_err(|_| bad())
+    }
+
+    fn holds_memory(&self) -> bool {
+        true
+    }
+}
+
+/// A Space Invaders board, ready to run
+pub struct SpaceInvaders {
+    pub z80: Z80,
+    board: Rc<RefCell<Board>>,
+    timer: FrameTimer,
+    // The RST of an interrupt due, set as the beam passes
+    due: Rc<Cell<Option<u8>>>,
+    on_frame: Option<FrameCallback>,
+}
+
+impl SpaceInvaders {
+    /// Create a board with the given program ROM (the four 2K chips, one after the other).
+    ///
+    /// # Panics
+    /// Panics if the ROM is larger than 8K
+    pub fn new(rom: &[u8]) -> Self {
+        assert!(rom.len() <= ROM_SIZE, "the ROM must fit in 8K");
+        let mut padded = rom.to_vec();
+        padded.resize(ROM_SIZE, 0x00);
+        let board = Rc::new(RefCell::new(Board {
+            rom: padded,
+            ram: vec![0; 0x2000],
+            // Bit 3 of port 1 is always set, and port 0 has a few pulled up
+            inputs: [0b0000_1110, 0b0000_1000, 0b0000_0000],
+            shift: 0,
+            shift_offset: 0,
+            sound: [0; 2],
+            vector: RST_1,
+        }));
+
+        let mut z80 = Z80::default();
+        z80.set_variant(CpuVariant::I8080);
+        z80.set_bus(Box::new(BoardBus(board.clone())));
+
+        let due = Rc::new(Cell::new(None));
+        let mut timer = FrameTimer::new(TIMING);
+        let d = due.clone();
+        timer.on_line(Box::new(move |line| match line {
+            MID_SCREEN => d.set(Some(RST_1)),
+            VBLANK => d.set(Some(RST_2)),
+            _ => (),
+        }));
+        z80.add_state_chunk(Box::new(BoardChunk {
+            board: board.clone(),
+            due: due.clone(),
+        }));
+        Self {
+            z80,
+            board,
+            timer,
+            due,
+            on_frame: None,
+        }
+    }
+
+    /// Press or release a button
+    pub fn set_input(&mut self, input: Input, pressed: bool) {
+        let (port, bit) = input.bit();
+        let mut board = self.board.borrow_mut();
+        if pressed {
+            board.inputs[port] |= 1 << bit;
+        } else {
+            board.inputs[port] &= !(1 << bit);
+        }
+    }
+
+    /// Set the DIP switches: bits 0 and 1 of port 2 are the number of extra lives, bit 3 when
+    /// the extra life is awarded and bit 7 whether the coin info is shown.
+    pub fn set_dip_switches(&mut self, dips: u8) {
+        let mut board = self.board.borrow_mut();
+        board.inputs[2] = board.inputs[2] & 0b0111_0100 | dips & 0b1000_1011;
+    }
+
+    /// The number of frames run so far
+    pub fn frame(&self) -> u64 {
+        self.timer.frame()
+    }
+
+    /// The last values written to the two sound ports, 3 and 5.
+    /// Each bit triggers one of the board's analogue sound effects.
+    pub fn sound(&self) -> [u8; 2] {
+        self.board.borrow().sound
+    }
+
+    /// Run until the end of the next frame, raising both of its interrupts on time
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "frame", level = "debug", skip_all)
+    )]
+    pub fn run_frame(&mut self) {
+        loop {
+            let before = self.z80.tstates();
+            self.z80.step();
+            let done = self.timer.advance((self.z80.tstates() - before) as u32);
+            if let Some(vector) = self.due.take() {
+                self.board.borrow_mut().vector = vector;
+                self.z80.request_interrupt();
+            }
+            if done {
+                break;
+            }
+        }
+        self.end_frame();
+    }
+
+    fn end_frame(&mut self) {
+        if self.on_frame.is_none() {
+            return;
+        }
+        let picture = Machine::framebuffer(self);
+        if let Some(f) = &mut self.on_frame {
+            f(&picture, &[]);
+        }
+    }
+
+    /// The picture, the right way up: WIDTH by HEIGHT pixels, a row at a time, true where lit
+    pub fn screen(&self) -> Vec<bool> {
+        let board = self.board.borrow();
+        let vram = &board.ram[VRAM - ROM_SIZE..];
+        let mut screen = Vec::with_capacity(WIDTH * HEIGHT);
+        for y in 0..HEIGHT {
+            // The picture is drawn in columns, from the bottom up
+            let row = HEIGHT - 1 - y;
+            for x in 0..WIDTH {
+                let byte = vram[x * HEIGHT / 8 + row / 8];
+                screen.push(byte & (1 << (row % 8)) != 0);
+            }
+        }
+        screen
+    }
+}
+
+impl Machine for SpaceInvaders {
+    fn z80(&mut self) -> &mut Z80 {
+        &mut self.z80
+    }
+
+    fn run_frame(&mut self) {
+        SpaceInvaders::run_frame(self)
+    }
+
+    fn frame(&self) -> u64 {
+        SpaceInvaders::frame(self)
+    }
+
+    fn framebuffer(&self) -> Framebuffer {
+        let pixels = self
+            .screen()
+            .iter()
+            .map(|lit| if *lit { [0xFF; 3] } else { [0x00; 3] })
+            .collect();
+        Framebuffer {
+            width: WIDTH,
+            height: HEIGHT,
+            pixels,
+        }
+    }
+
+    fn on_frame(&mut self, callback: FrameCallback) {
+        self.on_frame = Some(callback);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn poke(invaders: &SpaceInvaders, addr: u16, val: u8) {
+        BoardBus(invaders.board.clone()).mem_write(addr, val);
+    }
+
+    fn peek(invaders: &SpaceInvaders, addr: u16) -> u8 {
+        BoardBus(invaders.board.clone()).mem_read(addr)
+    }
+
+    // Counts the interrupts of each kind at 0x2000 and 0x2001, and feeds the shift register
+    const PROGRAM: &[u8] = &[
+        0x31, 0x00, 0x24, // 0000 LD SP, 0x2400
+        0xFB, // 0003 EI
+        0xC3, 0x03, 0x00, // 0004 JP 0x0003
+        0x00, // 0007
+        0xF5, // 0008 PUSH AF
+        0x21, 0x00, 0x20, // 0009 LD HL, 0x2000
+        0xC3, 0x18, 0x00, // 000C JP 0x0018
+        0x00, // 000F
+        0xF5, // 0010 PUSH AF
+        0x21, 0x01, 0x20, // 0011 LD HL, 0x2001
+        0xC3, 0x18, 0x00, // 0014 JP 0x0018
+        0x00, // 0017
+        0x34, // 0018 INC (HL)
+        0x3A, 0x00, 0x24, // 0019 LD A, (0x2400)
+        0xD3, 0x04, // 001C OUT (4), A
+        0x3E, 0x03, // 001E LD A, 3
+        0xD3, 0x02, // 0020 OUT (2), A
+        0xDB, 0x03, // 0022 IN A, (3)
+        0x32, 0x02, 0x20, // 0024 LD (0x2002), A
+        0xDB, 0x01, // 0027 IN A, (1)
+        0x32, 0x03, 0x20, // 0029 LD (0x2003), A
+        0xF1, // 002C POP AF
+        0xFB, // 002D EI
+        0xC9, // 002E RET
+    ];
+
+    #[test]
+    fn interrupts_and_io() {
+        let mut invaders = SpaceInvaders::new(PROGRAM);
+        poke(&invaders, 0x2400, 0b1010_0101);
+        invaders.set_input(Input::P1Fire, true);
+        invaders.run_frame();
+        invaders.run_frame();
+        assert_eq!(2, invaders.frame());
+        assert_eq!(2, peek(&invaders, 0x2000));
+        assert_eq!(2, peek(&invaders, 0x2001));
+        // The same byte shifted in twice, read 3 bits in
+        assert_eq!(0b0010_1101, peek(&invaders, 0x2002));
+        assert_eq!(0b0001_1000, peek(&invaders, 0x2003));
+    }
+
+    #[test]
+    fn screen() {
+        let invaders = SpaceInvaders::new(&[]);
+        // The first byte of VRAM is the bottom left corner, and ROM ignores writes
+        poke(&invaders, 0x2400, 0b0000_0001);
+        poke(&invaders, 0x2400 + 32 * 223 + 31, 0b1000_0000);
+        poke(&invaders, 0x0000, 0xFF);
+        let screen = invaders.screen();
+        assert!(screen[(HEIGHT - 1) * WIDTH]);
+        assert!(screen[WIDTH - 1]);
+        assert_eq!(2, screen.iter().filter(|p| **p).count());
+        assert_eq!(0x00, peek(&invaders, 0x0000));
+        // A14 and A15 aren't decoded
+        assert_eq!(0x01, peek(&invaders, 0x6400));
+    }
+}
@@ -0,0 +1,128 @@
+//! Typing on a keyboard matrix. A Typist turns text into key presses, each held for a couple
+//! of frames and then released for a couple more, about as fast as a quick typist and slow
+//! enough for any keyboard scanning routine to see every key. Machines hand it a keymap, and
+//! apply its presses at the start of every frame, so demos and tests can drive BASIC.
+use std::collections::VecDeque;
+
+use log::warn;
+
+/// A key, by its row and column in the matrix
+pub type MatrixKey = (usize, u8);
+
+/// How to type a character: its key, and whether shift is held with it
+pub type Keymap = fn(char) -> Option<(MatrixKey, bool)>;
+
+// How many frames each key is held, and then released for
+const PRESS_FRAMES: u32 = 2;
+const RELEASE_FRAMES: u32 = 2;
+
+/// Find a character in a keyboard's layout: a string of eight characters for each row of the
+/// matrix, one for each column (or `\0` where it has none), first as typed without shift
+/// and then with
+pub fn lookup(plain: &[&str], shifted: &[&str], c: char) -> Option<(MatrixKey, bool)> {
+    if c == '\0' {
+        return None;
+    }
+    for (layout, shift) in [(plain, false), (shifted, true)] {
+        for (row, keys) in layout.iter().enumerate() {
+            if let Some(col) = keys.chars().position(|k| k == c) {
+                return Some(((row, col as u8), shift));
+            }
+        }
+    }
+    None
+}
+
+/// Text waiting to be typed
+#[derive(Debug)]
+pub struct Typist {
+    keymap: Keymap,
+    shift: MatrixKey,
+    // The keys of each character still to type
+    queue: VecDeque<Vec<MatrixKey>>,
+    held: Vec<MatrixKey>,
+    // Frames left before the next change
+    wait: u32,
+}
+
+impl Typist {
+    pub fn new(keymap: Keymap, shift: MatrixKey) -> Self {
+        Self {
+            keymap,
+            shift,
+            queue: VecDeque::new(),
+            held: vec![],
+            wait: 0,
+        }
+    }
+
+    /// Queue some text to type, after anything already queued. Characters the keymap has no
+    /// key for are left out, with a warning.
+    pub fn type_text(&mut self, text: &str) {
+        for c in text.chars() {
+            match (self.keymap)(c) {
+                Some((key, false)) => self.queue.push_back(vec![key]),
+                Some((key, true)) => self.queue.push_back(vec![self.shift, key]),
+                None => warn!("There's no key to type {:?}", c),
+            }
+        }
+    }
+
+    /// Whether there's still text to type
+    pub fn is_typing(&self) -> bool {
+        !self.queue.is_empty() || !self.held.is_empty()
+    }
+
+    /// Move on a frame, returning the keys to press (true) or release (false) before it runs
+    pub fn frame(&mut self) -> Vec<(MatrixKey, bool)> {
+        if self.wait > 0 {
+            self.wait -= 1;
+            return vec![];
+        }
+        if !self.held.is_empty() {
+            self.wait = RELEASE_FRAMES - 1;
+            return self.held.drain(..).map(|key| (key, false)).collect();
+        }
+        match self.queue.pop_front() {
+            Some(keys) => {
+                self.wait = PRESS_FRAMES - 1;
+                self.held = keys.clone();
+                keys.into_iter().map(|key| (key, true)).collect()
+            }
+            None => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn keymap(c: char) -> Option<(MatrixKey, bool)> {
+        lookup(&["\0\0\0\0\0\0\0\0", "\0\0a"], &["", "\0\0A"], c)
+    }
+
+    #[test]
+    fn typing() {
+        let mut typist = Typist::new(keymap, (0, 5));
+        typist.type_text("aA?");
+        assert!(typist.is_typing());
+        let frames: Vec<_> = (0..9).map(|_| typist.frame()).collect();
+        assert_eq!(
+            vec![
+                vec![((1, 2), true)],
+                vec![],
+                vec![((1, 2), false)],
+                vec![],
+                vec![((0, 5), true), ((1, 2), true)],
+                vec![],
+                vec![((0, 5), false), ((1, 2), false)],
+                vec![],
+                vec![],
+            ],
+            frames
+        );
+        assert!(!typist.is_typing());
+        assert_eq!(None, keymap('\0'));
+    }
+}
@@ -0,0 +1,908 @@
+//! An MSX1 computer.
+//! A Z80 at 3.58 MHz, and a memory map built from slots: four primary slots, any of which may be
+//! expanded into four secondary slots, each filling the whole 64K address space. Port 0xA8
+//! picks which primary slot each 16K page is read from, and writing 0xFFFF picks the secondary
+//! slots of the expanded slot in page 3 (reading it gives back the complement).
+//! The keyboard is a matrix of 11 rows read through the same PPI chip as the slot select.
+//! Every opcode fetch takes an extra T-state, as the MSX adds a wait state to M1 cycles.
+//! A TMS9918 on ports 0x98 and 0x99 draws the picture, and raises the frame interrupt.
+//! The slot selects, the VDP and the state of every slot device are kept in a state chunk, so
+//! checkpoints and savestates taken between frames restore the whole machine.
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use super::keyboard::{self, MatrixKey, Typist};
+use super::{FrameCallback, Framebuffer, Machine};
+use crate::cartridge::{self, Mapper};
+use crate::chips::tms9918::{self, Tms9918};
+use crate::cpu::timing::CycleKind;
+use crate::frame::{FrameTimer, FrameTiming};
+use crate::input::{self, InputEvent, InputQueue, JoystickControl};
+use crate::z80::savestate::{write_block, SaveStateError, StateChunk, StateReader};
+use crate::z80::{bus::Bus, wait::WaitStates, Z80};
+
+/// The processor clock, in Hz
+pub const CLOCK: u32 = 3_579_545;
+/// 60 frames a second
+pub const TIMING: FrameTiming = FrameTiming {
+    tstates_per_line: 228,
+    lines_per_frame: 262,
+};
+/// The rows in the keyboard matrix
+pub const KEY_ROWS: usize = 11;
+
+const VDP_DATA: u8 = 0x98;
+const VDP_CONTROL: u8 = 0x99;
+const PPI_A: u8 = 0xA8;
+const PPI_B: u8 = 0xA9;
+const PPI_C: u8 = 0xAA;
+const PPI_CONTROL: u8 = 0xAB;
+const SECONDARY_SELECT: u16 = 0xFFFF;
+
+/// Something plugged into a slot: ROM, RAM or a cartridge with its own hardware.
+/// It is given the full address, and only sees the pages the slot is selected for.
+pub trait SlotDevice {
+    fn read(&self, addr: u16) -> u8;
+    fn write(&self, _addr: u16, _val: u8) {}
+
+    /// Whether the device has paged in a different bank since this was last asked.
+    /// See `Bus::take_bank_switch`.
+    fn take_bank_switch(&self) -> bool {
+        false
+    }
+
+    /// The device's own state, such as its RAM or mapper registers, to be kept in the
+    /// machine's checkpoints and savestates. A ROM has none.
+    fn save_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Go back to a state from `save_state`
+    fn load_state(&self, _state: &[u8]) -> Result<(), SaveStateError> {
+        Ok(())
+    }
+}
+
+/// A ROM at a fixed address. Reads outside of it return 0xFF, and writes are ignored.
+pub struct Rom {
+    data: Vec<u8>,
+    base: u16,
+}
+
+impl Rom {
+    pub fn new(data: &[u8], base: u16) -> Self {
+        assert!(
+            usize::from(base) + data.len() <= 0x10000,
+            "ROM runs past the end of the address space"
+        );
+        Self {
+            data: data.to_vec(),
+            base,
+        }
+    }
+
+    /// A plain cartridge ROM (with no mapper), placed where the MSX would expect it.
+    /// Up to 32K starts at 0x4000, unless the header says the program starts at 0x8000 or
+    /// above; anything larger starts at 0x0000.
+    pub fn cartridge(data: &[u8]) -> Self {
+        let init = match data {
+            [b'A', b'B', low, high, ..] => u16::from_le_bytes([*low, *high]),
+            _ => 0x4000,
+        };
+        let base = match data.len() {
+            0..=0x4000 if init >= 0x8000 => 0x8000,
+            0..=0x8000 => 0x4000,
+            _ => 0x0000,
+        };
+        Self::new(data, base)
+    }
+}
+
+impl SlotDevice for Rom {
+    fn read(&self, addr: u16) -> u8 {
+        addr.checked_sub(self.base)
+            .and_then(|offset| self.data.get(usize::from(offset)))
+            .copied()
+            .unwrap_or(0xFF)
+    }
+}
+
+/// A MegaROM: a cartridge too big for the address space, with a mapper paging 8K banks of it
+/// into 0x4000 to 0xBFFF. Writing a bank number to one of the mapper's registers (which sit
+/// over the ROM) picks the bank for a page.
+pub struct MegaRom {
+    data: Vec<u8>,
+    mapper: Mapper,
+    // The 8K bank in each of 0x4000, 0x6000, 0x8000 and 0xA000
+    banks: Cell<[usize; 4]>,
+    switched: Cell<bool>,
+}
+
+impl MegaRom {
+    /// Map a ROM with one of the MSX mappers, padded to a whole number of banks.
+    ///
+    /// # Panics
+    /// Panics if the mapper isn't an MSX one
+    pub fn new(data: &[u8], mapper: Mapper) -> Self {
+        let banks = match mapper {
+            Mapper::Konami | Mapper::KonamiScc => [0, 1, 2, 3],
+            Mapper::Ascii8 | Mapper::Ascii16 => [0; 4],
+            _ => panic!("{:?} isn't an MSX mapper", mapper),
+        };
+        let mut data = data.to_vec();
+        data.resize(data.len().div_ceil(0x2000).max(1) * 0x2000, 0xFF);
+        Self {
+            data,
+            mapper,
+            banks: Cell::new(banks),
+            switched: Cell::new(false),
+        }
+    }
+
+    /// The 8K bank paged into 0x4000 (0), 0x6000 (1), 0x8000 (2) or 0xA000 (3)
+    pub fn bank(&self, page: usize) -> usize {
+        self.banks.get()[page] % (self.data.len() / 0x2000)
+    }
+}
+
+impl SlotDevice for MegaRom {
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x4000..=0xBFFF => {
+                let page = usize::from(addr - 0x4000) / 0x2000;
+                self.data[self.bank(page) * 0x2000 + usize::from(addr) % 0x2000]
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&self, addr: u16, val: u8) {
+        let mut banks = self.banks.get();
+        let bank = usize::from(val);
+        match (self.mapper, addr) {
+            // The first page is fixed
+            (Mapper::Konami, 0x6000..=0xBFFF) => banks[usize::from(addr >> 13) - 2] = bank,
+            (Mapper::KonamiScc, 0x5000..=0xB7FF) if addr & 0x1800 == 0x1000 => {
+                banks[usize::from(addr - 0x4000) >> 13] = bank
+            }
+            (Mapper::Ascii8, 0x6000..=0x7FFF) => banks[usize::from(addr >> 11) & 0b11] = bank,
+            (Mapper::Ascii16, 0x6000..=0x67FF) => {
+                banks[..2].copy_from_slice(&[bank * 2, bank * 2 + 1])
+            }
+            (Mapper::Ascii16, 0x7000..=0x77FF) => {
+                banks[2..].copy_from_slice(&[bank * 2, bank * 2 + 1])
+            }
+            _ => (),
+        }
+        if banks != self.banks.get() {
+            self.switched.set(true);
+        }
+        self.banks.set(banks);
+    }
+
+    fn take_bank_switch(&self) -> bool {
+        self.switched.replace(false)
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let banks = self.banks.get();
+        banks
+            .iter()
+            .flat_map(|&b| (b as u32).to_le_bytes())
+            .collect()
+    }
+
+    fn load_state(&self, state: &[u8]) -> Result<(), SaveStateError> {
+        let mut reader = StateReader::new(state);
+        let mut banks = [0; 4];
+        for bank in &mut banks {
+            *bank = reader.u32()? as usize;
+        }
+        if banks != self.banks.get() {
+            self.switched.set(true);
+        }
+        self.banks.set(banks);
+        Ok(())
+    }
+}
+
+/// 64K of RAM
+pub struct Ram(RefCell<Vec<u8>>);
+
+impl Default for Ram {
+    fn default() -> Self {
+        Ram(RefCell::new(vec![0; 0x10000]))
+    }
+}
+
+impl SlotDevice for Ram {
+    fn read(&self, addr: u16) -> u8 {
+        self.0.borrow()[usize::from(addr)]
+    }
+
+    fn write(&self, addr: u16, val: u8) {
+        self.0.borrow_mut()[usize::from(addr)] = val;
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        self.0.borrow().clone()
+    }
+
+    fn load_state(&self, state: &[u8]) -> Result<(), SaveStateError> {
+        let mut ram = self.0.borrow_mut();
+        if state.len() != ram.len() {
+            return Err(SaveStateError::Truncated);
+        }
+        ram.copy_from_slice(state);
+        Ok(())
+    }
+}
+
+struct Board {
+    // By primary, then secondary slot. Unexpanded slots only use the first.
+    slots: [[Option<Box<dyn SlotDevice>>; 4]; 4],
+    expanded: [bool; 4],
+    primary: u8,
+    secondary: [u8; 4],
+    // Whether either slot select has been written since the processor last asked
+    switched: bool,
+    port_c: u8,
+    // Active low, like the hardware
+    keys: [u8; KEY_ROWS],
+    vdp: Tms9918,
+}
+
+impl Board {
+    // The primary slot selected for the page an address is in
+    fn primary_slot(&self, addr: u16) -> usize {
+        usize::from(self.primary >> (2 * (addr >> 14)) & 0b11)
+    }
+
+    fn device(&self, addr: u16) -> Option<&dyn SlotDevice> {
+        let primary = self.primary_slot(addr);
+        let secondary = if self.expanded[primary] {
+            usize::from(self.secondary[primary] >> (2 * (addr >> 14)) & 0b11)
+        } else {
+            0
+        };
+        self.slots[primary][secondary].as_deref()
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut out = vec![self.primary];
+        out.extend_from_slice(&self.secondary);
+        out.push(self.port_c);
+        write_block(&mut out, &self.vdp.save_state());
+        for device in self.slots.iter().flatten() {
+            write_block(
+                &mut out,
+                &device.as_ref().map_or(vec![], |d| d.save_state()),
+            );
+        }
+        out
+    }
+
+    // The keys aren't restored, as they are whatever is held down now
+    fn load_state(&mut self, state: &[u8]) -> Result<(), SaveStateError> {
+        let mut reader = StateReader::new(state);
+        let primary = reader.u8()?;
+        let secondary = reader.array()?;
+        let port_c = reader.u8()?;
+        let vdp = reader.block()?;
+        let mut devices = Vec::new();
+        for device in self.slots.iter().flatten() {
+            let block = reader.block()?;
+            if device.is_none() && !block.is_empty() {
+                // Saved with a device in a slot that is empty now
+                return Err(SaveStateError::Truncated);
+            }
+            devices.push(block);
+        }
+        self.vdp.load_state(vdp)?;
+        for (device, block) in self.slots.iter().flatten().zip(devices) {
+            if let Some(device) = device {
+                device.load_state(block)?;
+            }
+        }
+        self.primary = primary;
+        self.secondary = secondary;
+        self.port_c = port_c;
+        self.switched = true;
+        Ok(())
+    }
+}
+
+// Everything on the board, memory included
+struct BoardChunk(Rc<RefCell<Board>>);
+
+impl StateChunk for BoardChunk {
+    fn tag(&self) -> [u8; 4] {
+        *b"MSX1"
+    }
+
+    fn save(&self) -> Vec<u8> {
+        self.0.borrow().save_state()
+    }
+
+    fn load(&self, chunk: Option<&[u8]>) -> Result<(), SaveStateError> {
+        let bad = || SaveStateError::BadChunk(self.tag());
+        let chunk = chunk.ok_or_else(bad)?;
+        self.0.borrow_mut().load_state(chunk).map_err(|_| bad())
+    }
+
+    fn holds_memory(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Clone)]
+struct BoardBus(Rc<RefCell<Board>>);
+
+impl Bus for BoardBus {
+    fn mem_read(&self, addr: u16) -> u8 {
+        let board = self.0.borrow();
+        let primary = board.primary_slot(addr);
+        if addr == SECONDARY_SELECT && board.expanded[primary] {
+            return !board.secondary[primary];
+        }
+        board.device(addr).map_or(0xFF, |d| d.read(addr))
+    }
+
+    fn mem_write(&self, addr: u16, val: u8) {
+        let mut board = self.0.borrow_mut();
+        let primary = board.primary_slot(addr);
+        if addr == SECONDARY_SELECT && board.expanded[primary] {
+            board.secondary[primary] = val;
+            board.switched = true;
+            return;
+        }
+        if let Some(device) = board.device(addr) {
+            device.write(addr, val);
+        }
+    }
+
+    fn io_read(&self, port: u16) -> u8 {
+        let mut board = self.0.borrow_mut();
+        match port as u8 {
+            VDP_DATA => board.vdp.read_data(),
+            VDP_CONTROL => board.vdp.read_status(),
+            PPI_A => board.primary,
+            PPI_B => board
+                .keys
+                .get(usize::from(board.port_c & 0x0F))
+                .copied()
+                .unwrap_or(0xFF),
+            PPI_C => board.port_c,
+            _ => 0xFF,
+        }
+    }
+
+    fn io_write(&self, port: u16, val: u8) {
+        let mut board = self.0.borrow_mut();
+        match port as u8 {
+            VDP_DATA => board.vdp.write_data(val),
+            VDP_CONTROL => board.vdp.write_control(val),
+            PPI_A => {
+                board.primary = val;
+                board.switched = true;
+            }
+            PPI_C => board.port_c = val,
+            // Set or reset a single bit of port C
+            PPI_CONTROL if val & 0x80 == 0 => {
+                let bit = 1 << ((val >> 1) & 0b111);
+                if val & 1 == 1 {
+                    board.port_c |= bit;
+                } else {
+                    board.port_c &= !bit;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn take_bank_switch(&self) -> bool {
+        let mut board = self.0.borrow_mut();
+        // Ask every device, so none is left with a stale answer
+        let devices = board
+            .slots
+            .iter()
+            .flatten()
+            .flatten()
+            .fold(false, |switched, device| {
+                device.take_bank_switch() | switched
+            });
+        std::mem::take(&mut board.switched) | devices
+    }
+}
+
+// The wait state the MSX adds to every M1 cycle
+struct M1Wait;
+
+impl WaitStates for M1Wait {
+    fn wait_states(&self, kind: CycleKind, _addr: u16) -> u32 {
+        match kind {
+            CycleKind::OpcodeFetch => 1,
+            _ => 0,
+        }
+    }
+}
+
+/// An MSX1, ready to run
+// What each key of the international matrix types, without shift and with it
+const PLAIN: [&str; 9] = [
+    "01234567",
+    "89-=\\[];",
+    "'`,./\0ab",
+    "cdefghij",
+    "klmnopqr",
+    "stuvwxyz",
+    "\0\0\0\0\0\0\0\0",
+    "\0\0\0\t\0\0\0\n",
+    " ",
+];
+const SHIFTED: [&str; 6] = [
+    ")!@#$%^&",
+    "*(_+|{}:",
+    "\"~<>?\0AB",
+    "CDEFGHIJ",
+    "KLMNOPQR",
+    "STUVWXYZ",
+];
+const SHIFT: MatrixKey = (6, 0);
+
+fn keymap(c: char) -> Option<(MatrixKey, bool)> {
+    keyboard::lookup(&PLAIN, &SHIFTED, c)
+}
+
+pub struct Msx {
+    pub z80: Z80,
+    board: Rc<RefCell<Board>>,
+    timer: FrameTimer,
+    on_frame: Option<FrameCallback>,
+    typist: Typist,
+    input: Option<InputQueue>,
+}
+
+impl Msx {
+    /// Create an MSX with the given BIOS (and BASIC) ROM in slot 0, 64K of RAM in slot 3, and
+    /// empty cartridge slots 1 and 2.
+    pub fn new(bios: &[u8]) -> Self {
+        let board = Rc::new(RefCell::new(Board {
+            slots: Default::default(),
+            expanded: [false; 4],
+            primary: 0,
+            secondary: [0; 4],
+            switched: false,
+            port_c: 0,
+            keys: [0xFF; KEY_ROWS],
+            vdp: Tms9918::default(),
+        }));
+
+        let mut z80 = Z80::default();
+        z80.set_bus(Box::new(BoardBus(board.clone())));
+        z80.set_wait_states(Box::new(M1Wait));
+        z80.add_state_chunk(Box::new(BoardChunk(board.clone())));
+
+        let mut timer = FrameTimer::new(TIMING);
+        let b = board.clone();
+        timer.on_line(Box::new(move |line| {
+            if line == tms9918::HEIGHT as u32 - 1 {
+                b.borrow_mut().vdp.vblank();
+            }
+        }));
+        let mut msx = Self {
+            z80,
+            board,
+            timer,
+            on_frame: None,
+            typist: Typist::new(keymap, SHIFT),
+            input: None,
+        };
+        msx.set_slot(0, 0, Box::new(Rom::new(bios, 0x0000)));
+        msx.set_slot(3, 0, Box::new(Ram::default()));
+        msx
+    }
+
+    /// Plug a device into a slot, replacing whatever was there.
+    /// Giving a secondary slot other than 0 expands the primary slot.
+    pub fn set_slot(&mut self, primary: usize, secondary: usize, device: Box<dyn SlotDevice>) {
+        let mut board = self.board.borrow_mut();
+        if secondary != 0 {
+            board.expanded[primary] = true;
+        }
+        board.slots[primary][secondary] = Some(device);
+    }
+
+    /// Insert a cartridge ROM into slot 1 or 2, with the mapper `cartridge::identify` guesses
+    /// it needs. Use `insert_mapped_cartridge` when the guess is wrong.
+    ///
+    /// # Panics
+    /// Panics if the slot is not 1 or 2
+    pub fn insert_cartridge(&mut self, slot: usize, rom: &[u8]) {
+        let mapper = match cartridge::identify(rom) {
+            info if info.system == cartridge::System::Msx => info.mapper,
+            _ => Mapper::Plain,
+        };
+        self.insert_mapped_cartridge(slot, rom, mapper);
+    }
+
+    /// Insert a cartridge ROM with the given mapper into slot 1 or 2. A plain ROM is placed as
+    /// `Rom::cartridge` describes, and anything else is a `MegaRom`.
+    ///
+    /// # Panics
+    /// Panics if the slot is not 1 or 2, or the mapper isn't an MSX one
+    pub fn insert_mapped_cartridge(&mut self, slot: usize, rom: &[u8], mapper: Mapper) {
+        assert!(slot == 1 || slot == 2, "cartridges go in slot 1 or 2");
+        let device: Box<dyn SlotDevice> = match mapper {
+            Mapper::Plain => Box::new(Rom::cartridge(rom)),
+            _ => Box::new(MegaRom::new(rom, mapper)),
+        };
+        self.set_slot(slot, 0, device);
+    }
+
+    /// Press or release the key at the given row (0 to 10) and column (0 to 7) of the
+    /// keyboard matrix
+    pub fn set_key(&mut self, row: usize, col: u8, pressed: bool) {
+        let keys = &mut self.board.borrow_mut().keys;
+        if pressed {
+            keys[row] &= !(1 << col);
+        } else {
+            keys[row] |= 1 << col;
+        }
+    }
+
+    /// Take input from a queue, each event applied between instructions once its T-state
+    /// comes. Joystick 0 is the cursor keys and space, as BASIC's STICK(0) and STRIG(0) read
+    /// them; there are no joystick ports or tape deck.
+    pub fn set_input(&mut self, queue: InputQueue) {
+        self.input = Some(queue);
+    }
+
+    /// Stop taking input from the queue, returning it
+    pub fn take_input(&mut self) -> Option<InputQueue> {
+        self.input.take()
+    }
+
+    fn apply_input(&mut self) {
+        let now = self.z80.tstates();
+        while let Some(event) = self.input.as_mut().and_then(|queue| queue.next_due(now)) {
+            match event {
+                InputEvent::Key { row, col, pressed } if row < KEY_ROWS && col < 8 => {
+                    self.set_key(row, col, pressed)
+                }
+                InputEvent::Joystick {
+                    joystick: 0,
+                    control,
+                    pressed,
+                } => {
+                    let col = match control {
+                        JoystickControl::Fire => 0,
+                        JoystickControl::Left => 4,
+                        JoystickControl::Up => 5,
+                        JoystickControl::Down => 6,
+                        JoystickControl::Right => 7,
+                    };
+                    self.set_key(8, col, pressed);
+                }
+                event => input::ignore(event),
+            }
+        }
+    }
+
+    /// Type some text on the keyboard, a key every few frames from the next, after any text
+    /// still being typed. Capitals are typed with shift, and a newline presses RETURN.
+    pub fn type_text(&mut self, text: &str) {
+        self.typist.type_text(text);
+    }
+
+    /// Whether there's still text being typed
+    pub fn is_typing(&self) -> bool {
+        self.typist.is_typing()
+    }
+
+    /// The number of frames run so far
+    pub fn frame(&self) -> u64 {
+        self.timer.frame()
+    }
+
+    /// Run until the end of the next frame. The VDP holds the interrupt line for as long as
+    /// its interrupt output is active.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "frame", level = "debug", skip_all)
+    )]
+    pub fn run_frame(&mut self) {
+        for ((row, col), pressed) in self.typist.frame() {
+            self.set_key(row, col, pressed);
+        }
+        loop {
+            self.apply_input();
+            if self.z80.is_halted() && !self.z80.iff1() && !self.z80.is_nmi_pending() {
+                // Halted with interrupts off, so nothing more can happen this frame
+                let remaining = TIMING.tstates_per_frame() - self.timer.frame_tstate();
+                self.timer.advance(remaining);
+                break;
+            }
+            let before = self.z80.tstates();
+            self.z80.step();
+            let done = self.timer.advance((self.z80.tstates() - before) as u32);
+            if self.board.borrow().vdp.interrupt() {
+                self.z80.request_interrupt();
+            }
+            if done {
+                break;
+            }
+        }
+        self.end_frame();
+    }
+
+    fn end_frame(&mut self) {
+        if self.on_frame.is_none() {
+            return;
+        }
+        let picture = Machine::framebuffer(self);
+        if let Some(f) = &mut self.on_frame {
+            f(&picture, &[]);
+        }
+    }
+
+    /// Draw the picture, as tms9918::WIDTH by tms9918::HEIGHT indices into tms9918::PALETTE
+    pub fn render(&self) -> Vec<u8> {
+        self.board.borrow().vdp.render()
+    }
+}
+
+impl Machine for Msx {
+    fn z80(&mut self) -> &mut Z80 {
+        &mut self.z80
+    }
+
+    fn run_frame(&mut self) {
+        Msx::run_frame(self)
+    }
+
+    fn frame(&self) -> u64 {
+        Msx::frame(self)
+    }
+
+    fn framebuffer(&self) -> Framebuffer {
+        let (width, height) = (tms9918::WIDTH, tms9918::HEIGHT);
+        Framebuffer::from_indexed(width, height, &self.render(), &tms9918::PALETTE)
+    }
+
+    fn on_frame(&mut self, callback: FrameCallback) {
+        self.on_frame = Some(callback);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bus(msx: &Msx) -> BoardBus {
+        BoardBus(msx.board.clone())
+    }
+
+    // Puts slot 1 in page 1 and RAM in pages 2 and 3, then reads keyboard row 8 and the
+    // cartridge into RAM
+    const BIOS: &[u8] = &[
+        0x3E, 0xF4, // 0000 LD A, 0xF4
+        0xD3, 0xA8, // 0002 OUT (0xA8), A
+        0x3E, 0x08, // 0004 LD A, 8
+        0xD3, 0xAA, // 0006 OUT (0xAA), A
+        0xDB, 0xA9, // 0008 IN A, (0xA9)
+        0x32, 0x00, 0xC0, // 000A LD (0xC000), A
+        0x3A, 0x00, 0x40, // 000D LD A, (0x4000)
+        0x32, 0x01, 0xC0, // 0010 LD (0xC001), A
+        0x76, // 0013 HALT
+    ];
+
+    #[test]
+    fn slots_and_keyboard() {
+        let mut msx = Msx::new(BIOS);
+        msx.insert_cartridge(1, b"AB\x10\x40");
+        // Space
+        msx.set_key(8, 0, true);
+        msx.run_frame();
+        assert_eq!(1, msx.frame());
+        assert!(msx.z80.is_halted());
+        assert_eq!(0b1111_1110, bus(&msx).mem_read(0xC000));
+        assert_eq!(b'A', bus(&msx).mem_read(0xC001));
+        assert_eq!(0xF4, bus(&msx).io_read(0xA8));
+    }
+
+    #[test]
+    fn cheats_follow_slots() {
+        use crate::z80::cheat::Poke;
+        // LD A, 0xF0; OUT (0xA8), A; LD A, (0x8000); HALT
+        let mut msx = Msx::new(&[0x3E, 0xF0, 0xD3, 0xA8, 0x3A, 0x00, 0x80, 0x76]);
+        // Page 2 is the BIOS slot, where the poke goes nowhere
+        msx.z80.add_cheat(vec![Poke {
+            addr: 0x8000,
+            original: None,
+            value: 0x55,
+        }]);
+        assert_eq!(0xFF, bus(&msx).mem_read(0x8000));
+        // Paging RAM in brings it back
+        msx.run_frame();
+        assert_eq!(0x55, msx.z80.registers.get_reg8(crate::ops::Reg8::A));
+    }
+
+    #[test]
+    fn typing() {
+        assert_eq!(Some(((1, 2), true)), keymap('_'));
+        assert_eq!(Some(((7, 7), false)), keymap('\n'));
+        assert_eq!(None, keymap('£'));
+
+        let mut msx = Msx::new(&[0x18, 0xFE]); // JR -2
+        msx.type_text("A\n");
+        msx.run_frame();
+        let keys = msx.board.borrow().keys;
+        assert_eq!((0b1011_1111, 0b1111_1110), (keys[2], keys[6]));
+        for _ in 0..4 {
+            msx.run_frame();
+        }
+        assert_eq!(0b0111_1111, msx.board.borrow().keys[7]);
+    }
+
+    #[test]
+    fn m1_wait_state() {
+        let mut msx = Msx::new(&[0x00, 0xDD, 0x00]);
+        msx.z80.step();
+        assert_eq!(5, msx.z80.tstates());
+    }
+
+    #[test]
+    fn expanded_slot() {
+        let mut msx = Msx::new(&[]);
+        msx.set_slot(3, 1, Box::new(Ram::default()));
+        let bus = bus(&msx);
+        bus.io_write(0xA8, 0xFF);
+        assert_eq!(0xFF, bus.mem_read(0xFFFF));
+        bus.mem_write(0xFFFF, 0b0101_0000);
+        assert_eq!(0b1010_1111, bus.mem_read(0xFFFF));
+        bus.mem_write(0x8000, 0x12);
+        bus.mem_write(0xFFFF, 0b0100_0000);
+        assert_eq!(0x00, bus.mem_read(0x8000));
+        bus.mem_write(0xFFFF, 0b0101_0000);
+        assert_eq!(0x12, bus.mem_read(0x8000));
+    }
+
+    #[test]
+    fn ppi_bit_set_reset() {
+        let msx = Msx::new(&[]);
+        let bus = bus(&msx);
+        bus.io_write(0xAB, 0b0000_1101);
+        assert_eq!(0b0100_0000, bus.io_read(0xAA));
+        bus.io_write(0xAB, 0b0000_1100);
+        assert_eq!(0x00, bus.io_read(0xAA));
+    }
+
+    #[test]
+    fn vdp_interrupt() {
+        let mut bios = vec![
+            0x3E, 0xC0, // 0000 LD A, 0xC0
+            0xD3, 0xA8, // 0002 OUT (0xA8), A
+            0x3E, 0x60, // 0004 LD A, 0x60
+            0xD3, 0x99, // 0006 OUT (0x99), A
+            0x3E, 0x81, // 0008 LD A, 0x81
+            0xD3, 0x99, // 000A OUT (0x99), A
+            0xED, 0x56, // 000C IM 1
+            0xFB, // 000E EI
+            0x76, // 000F HALT
+        ];
+        bios.resize(0x38, 0x00);
+        bios.extend_from_slice(&[
+            0xDB, 0x99, // 0038 IN A, (0x99)
+            0x32, 0x00, 0xC0, // 003A LD (0xC000), A
+            0x76, // 003D HALT
+        ]);
+        let mut msx = Msx::new(&bios);
+        msx.run_frame();
+        assert_eq!(0x3E, msx.z80.registers.get_pc());
+        assert_eq!(0x80, bus(&msx).mem_read(0xC000) & 0x80);
+        assert!(!msx.board.borrow().vdp.interrupt());
+        assert_eq!(tms9918::WIDTH * tms9918::HEIGHT, msx.render().len());
+    }
+
+    #[test]
+    fn cartridge_placement() {
+        let header = |init: u16, len: usize| {
+            let mut rom = vec![0; len];
+            rom[..4].copy_from_slice(&[b'A', b'B', init as u8, (init >> 8) as u8]);
+            Rom::cartridge(&rom).base
+        };
+        assert_eq!(0x4000, header(0x4010, 0x2000));
+        assert_eq!(0x4000, header(0x4010, 0x8000));
+        assert_eq!(0x8000, header(0x8010, 0x4000));
+        assert_eq!(0x0000, header(0x4010, 0xC000));
+
+        let rom = Rom::cartridge(&[0x12; 0x2000]);
+        assert_eq!(0xFF, rom.read(0x3FFF));
+        assert_eq!(0x12, rom.read(0x5FFF));
+        assert_eq!(0xFF, rom.read(0x6000));
+    }
+
+    // Each 8K bank filled with its own number
+    fn megarom(mapper: Mapper) -> MegaRom {
+        let data: Vec<u8> = (0..16).flat_map(|bank| vec![bank; 0x2000]).collect();
+        MegaRom::new(&data, mapper)
+    }
+
+    fn pages(rom: &MegaRom) -> [u8; 4] {
+        [0x4000, 0x6000, 0x8000, 0xA000].map(|addr| rom.read(addr))
+    }
+
+    #[test]
+    fn mappers() {
+        let rom = megarom(Mapper::Konami);
+        assert_eq!([0, 1, 2, 3], pages(&rom));
+        rom.write(0x4000, 5);
+        rom.write(0x6000, 6);
+        rom.write(0xA000, 7);
+        assert_eq!([0, 6, 2, 7], pages(&rom));
+
+        let rom = megarom(Mapper::KonamiScc);
+        rom.write(0x5000, 4);
+        rom.write(0x6000, 9);
+        rom.write(0xB000, 17);
+        assert_eq!([4, 1, 2, 1], pages(&rom));
+
+        let rom = megarom(Mapper::Ascii8);
+        assert_eq!([0; 4], pages(&rom));
+        rom.write(0x6800, 3);
+        rom.write(0x7800, 8);
+        assert_eq!([0, 3, 0, 8], pages(&rom));
+        assert_eq!(0xFF, rom.read(0xC000));
+
+        let rom = megarom(Mapper::Ascii16);
+        rom.write(0x6000, 2);
+        rom.write(0x7000, 3);
+        assert_eq!([4, 5, 6, 7], pages(&rom));
+    }
+
+    #[test]
+    fn detected_mapper() {
+        let mut rom: Vec<u8> = (0..16).flat_map(|bank| vec![bank; 0x2000]).collect();
+        rom[..6].copy_from_slice(&[b'A', b'B', 0x32, 0x00, 0x68, 0x00]); // LD (0x6800), A
+        let mut msx = Msx::new(&[]);
+        msx.insert_cartridge(1, &rom);
+        let bus = bus(&msx);
+        bus.io_write(0xA8, 0b0101_0100);
+        bus.mem_write(0x6800, 3);
+        assert_eq!(3, bus.mem_read(0x6000));
+    }
+
+    #[test]
+    fn rewinding() {
+        use crate::z80::checkpoint::Checkpoints;
+        let mut msx = Msx::new(&[0x18, 0xFE]); // JR -2
+        let rom: Vec<u8> = (0..16).flat_map(|bank| vec![bank; 0x2000]).collect();
+        msx.insert_mapped_cartridge(1, &rom, Mapper::Konami);
+        let bus = bus(&msx);
+        let mut checkpoints = Checkpoints::new(1, 4);
+        for n in 1..=3 {
+            // RAM under the BIOS, then a bank of the cartridge and a VDP register
+            bus.io_write(0xA8, 0b1111_0111);
+            bus.mem_write(0x0000, n);
+            bus.io_write(0xA8, 0b1111_0100);
+            bus.mem_write(0x6000, n + 4);
+            bus.io_write(0x99, n);
+            bus.io_write(0x99, 0x87);
+            msx.run_frame();
+            checkpoints.record(msx.frame(), &msx.z80);
+        }
+        bus.io_write(0xA8, 0xFF);
+
+        assert_eq!(Some(2), checkpoints.rewind(&mut msx.z80, 3, 1));
+        assert_eq!(0b1111_0100, bus.io_read(0xA8));
+        assert_eq!(6, bus.mem_read(0x6000));
+        assert_eq!(2, msx.board.borrow().vdp.register(7));
+        bus.io_write(0xA8, 0b1111_0111);
+        assert_eq!(2, bus.mem_read(0x0000));
+        bus.io_write(0xA8, 0b1111_0100);
+        // And the BIOS carries on from where it was
+        msx.run_frame();
+        assert_eq!(0x0000, msx.z80.registers.get_pc());
+    }
+}
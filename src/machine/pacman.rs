@@ -0,0 +1,549 @@
+//! The Namco Pac-Man board.
+//! A Z80 at 3.072 MHz with 16K of program ROM, 1K each of tile and colour RAM, and 1K of work
+//! RAM, on a monitor turned on its side. The video hardware draws a 28x36 grid of 8x8 tiles and
+//! eight 16x16 sprites. Everything but the interrupt vector is memory-mapped from 0x5000: the
+//! program writes the low byte of the IM 2 vector to a latch on any output port, and enables
+//! the vertical blank interrupt with a write to 0x5000.
+//! The RAM and the registers are kept in a state chunk, so checkpoints and savestates taken
+//! between frames don't write memory back through the registers.
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use super::{FrameCallback, Framebuffer, Machine};
+use crate::frame::{FrameTimer, FrameTiming};
+use crate::z80::savestate::{write_block, SaveStateError, StateChunk, StateReader};
+use crate::z80::{bus::Bus, Z80};
+
+/// The processor clock, in Hz
+pub const CLOCK: u32 = 3_072_000;
+/// Just over 60 frames a second
+pub const TIMING: FrameTiming = FrameTiming {
+    tstates_per_line: 192,
+    lines_per_frame: 264,
+};
+/// The screen, the right way up
+pub const WIDTH: usize = 224;
+pub const HEIGHT: usize = 288;
+
+// The interrupt is raised as this line finishes
+const VBLANK: u32 = 223;
+
+/// The contents of the board's ROM and PROM chips.
+/// Short chips are padded with zeroes.
+pub struct Roms<'a> {
+    /// The program: 16K, from 0x0000
+    pub program: &'a [u8],
+    /// 256 tiles of 16 bytes each
+    pub tiles: &'a [u8],
+    /// 64 sprites of 64 bytes each
+    pub sprites: &'a [u8],
+    /// 32 bytes of colours, as 3 bits red, 3 bits green and 2 bits blue
+    pub colors: &'a [u8],
+    /// 64 palettes of 4 colours each, as indices into the first 16 colours
+    pub palettes: &'a [u8],
+}
+
+fn padded(rom: &[u8], size: usize) -> Vec<u8> {
+    assert!(
+        rom.len() <= size,
+        "ROM is larger than the {} byte chip",
+        size
+    );
+    let mut padded = rom.to_vec();
+    padded.resize(size, 0x00);
+    padded
+}
+
+/// The joysticks, buttons and coin slots.
+/// The DIP switches are set separately.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Input {
+    Up,
+    Left,
+    Right,
+    Down,
+    Coin1,
+    Coin2,
+    Start1,
+    Start2,
+}
+
+impl Input {
+    // The input register and bit the input appears on. They are all active low.
+    fn bit(self) -> (usize, u8) {
+        match self {
+            Input::Up => (0, 0),
+            Input::Left => (0, 1),
+            Input::Right => (0, 2),
+            Input::Down => (0, 3),
+            Input::Coin1 => (0, 5),
+            Input::Coin2 => (0, 6),
+            Input::Start1 => (1, 5),
+            Input::Start2 => (1, 6),
+        }
+    }
+}
+
+struct Board {
+    program: Vec<u8>,
+    tiles: Vec<u8>,
+    sprites: Vec<u8>,
+    colors: Vec<u8>,
+    palettes: Vec<u8>,
+    // Tile RAM, colour RAM and work RAM, including the sprite attributes at the very end
+    ram: Vec<u8>,
+    // IN0, IN1 and the DIP switches
+    inputs: [u8; 3],
+    interrupt_enable: bool,
+    // The registers from 0x5040 to 0x506F: the sound chip, then the sprite coordinates
+    registers: [u8; 0x30],
+    vector: u8,
+}
+
+#[derive(Clone)]
+struct BoardBus(Rc<RefCell<Board>>);
+
+impl Bus for BoardBus {
+    fn mem_read(&self, addr: u16) -> u8 {
+        // A15 isn't decoded
+        let addr = usize::from(addr & 0x7FFF);
+        let board = self.0.borrow();
+        match addr {
+            0x0000..=0x3FFF => board.program[addr],
+            0x4000..=0x4FFF => board.ram[addr - 0x4000],
+            0x5000..=0x503F => board.inputs[0],
+            0x5040..=0x507F => board.inputs[1],
+            0x5080..=0x50BF => board.inputs[2],
+            _ => 0xFF,
+        }
+    }
+
+    fn mem_write(&self, addr: u16, val: u8) {
+        let addr = usize::from(addr & 0x7FFF);
+        let mut board = self.0.borrow_mut();
+        match addr {
+            0x4000..=0x4FFF => board.ram[addr - 0x4000] = val,
+            0x5000 => board.interrupt_enable = val & 1 == 1,
+            0x5040..=0x506F => board.registers[addr - 0x5040] = val,
+            // Sound enable, screen flip, lamps, coin counter and the watchdog are ignored
+            _ => (),
+        }
+    }
+
+    fn io_read(&self, _port: u16) -> u8 {
+        0xFF
+    }
+
+    fn io_write(&self, _port: u16, val: u8) {
+        self.0.borrow_mut().vector = val;
+    }
+
+    fn acknowledge(&self) -> u8 {
+        self.0.borrow().vector
+    }
+}
+
+// The RAM and registers. The inputs aren't restored, as they are whatever is pressed now.
+struct BoardChunk {
+    board: Rc<RefCell<Board>>,
+    vblank: Rc<Cell<bool>>,
+}
+
+impl BoardChunk {
+    fn load_state(&self, state: &[u8]) -> Result<(), SaveStateError> {
+        let mut reader = StateReader::new(state);
+        let ram = reader.block()?;
+        let registers = reader.array()?;
+        let [interrupt_enable, vector, vblank] = reader.array()?;
+        if ram.len() != 0x1000 {
+            return Err(SaveStateError::Truncated);
+        }
+        let mut board = self.board.borrow_mut();
+        board.ram.copy_from_slice(ram);
+        board.registers = registers;
+        board.interrupt_enable = interrupt_enable != 0;
+        board.vector = vector;
+        self.vblank.set(vblank != 0);
+        Ok(())
+    }
+}
+
+impl StateChunk for BoardChunk {
+    fn tag(&self) -> [u8; 4] {
+        *b"PACM"
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let board = self.board.borrow();
+        let mut out = Vec::new();
+        write_block(&mut out, &board.ram);
+        out.extend_from_slice(&board.registers);
+        out.extend_from_slice(&[
+            board.interrupt_enable as u8,
+            board.vector,
+            self.vblank.get() as u8,
+        ]);
+        out
+    }
+
+    fn load(&self, chunk: Option<&[u8]>) -> Result<(), SaveStateError> {
+        let bad = || SaveStateError::BadChunk(self.tag());
+        let chunk = chunk.ok_or_else(bad)?;
+        self.load_state(chunk).map_err(|_| bad())
+    }
+
+    fn holds_memory(&self) -> bool {
+        true
+    }
+}
+
+// The pixel at (x, y) of an 8x8 tile or 16x16 sprite, the right way up.
+// The graphics are stored for the monitor on its side, with two bitplanes to a byte.
+fn gfx_pixel(data: &[u8], xoff: &[usize], yoff: &[usize], x: usize, y: usize) -> u8 {
+    let size = xoff.len();
+    let bit = xoff[y] + yoff[size - 1 - x];
+    let plane = |offset: usize| {
+        let bit = bit + offset;
+        (data[bit / 8] >> (7 - bit % 8)) & 1
+    };
+    plane(0) << 1 | plane(4)
+}
+
+const TILE_X: [usize; 8] = [64, 65, 66, 67, 0, 1, 2, 3];
+const TILE_Y: [usize; 8] = [0, 8, 16, 24, 32, 40, 48, 56];
+const SPRITE_X: [usize; 16] = [
+    64, 65, 66, 67, 128, 129, 130, 131, 192, 193, 194, 195, 0, 1, 2, 3,
+];
+const SPRITE_Y: [usize; 16] = [
+    0, 8, 16, 24, 32, 40, 48, 56, 256, 264, 272, 280, 288, 296, 304, 312,
+];
+
+// Where the tile at the given column and row lives in tile and colour RAM.
+// The middle of the screen is stored in columns from the top right; the two rows at the top
+// and bottom are stored right to left.
+fn tile_offset(col: usize, row: usize) -> usize {
+    match row {
+        0 | 1 => 0x3DD + 0x20 * row - col,
+        34 | 35 => 0x01D + 0x20 * (row - 34) - col,
+        _ => 0x40 + (27 - col) * 32 + (row - 2),
+    }
+}
+
+/// A Pac-Man board, ready to run
+pub struct PacMan {
+    pub z80: Z80,
+    board: Rc<RefCell<Board>>,
+    timer: FrameTimer,
+    vblank: Rc<Cell<bool>>,
+    on_frame: Option<FrameCallback>,
+}
+
+impl PacMan {
+    /// # Panics
+    /// Panics if any ROM is larger than its chip
+    pub fn new(roms: &Roms) -> Self {
+        let board = Rc::new(RefCell::new(Board {
+            program: padded(roms.program, 0x4000),
+            tiles: padded(roms.tiles, 0x1000),
+            sprites: padded(roms.sprites, 0x1000),
+            colors: padded(roms.colors, 32),
+            palettes: padded(roms.palettes, 256),
+            ram: vec![0; 0x1000],
+            // Nothing pressed, an upright cabinet, and the usual DIP switches: one coin, one
+            // credit, three lives and a bonus at 10000 points
+            inputs: [0xFF, 0xFF, 0b1100_1001],
+            interrupt_enable: false,
+            registers: [0; 0x30],
+            vector: 0x00,
+        }));
+
+        let mut z80 = Z80::default();
+        z80.set_bus(Box::new(BoardBus(board.clone())));
+
+        let vblank = Rc::new(Cell::new(false));
+        let mut timer = FrameTimer::new(TIMING);
+        let v = vblank.clone();
+        timer.on_line(Box::new(move |line| {
+            if line == VBLANK {
+                v.set(true);
+            }
+        }));
+        z80.add_state_chunk(Box::new(BoardChunk {
+            board: board.clone(),
+            vblank: vblank.clone(),
+        }));
+        Self {
+            z80,
+            board,
+            timer,
+            vblank,
+            on_frame: None,
+        }
+    }
+
+    /// Press or release a button, or move a joystick
+    pub fn set_input(&mut self, input: Input, pressed: bool) {
+        let (register, bit) = input.bit();
+        let mut board = self.board.borrow_mut();
+        if pressed {
+            board.inputs[register] &= !(1 << bit);
+        } else {
+            board.inputs[register] |= 1 << bit;
+        }
+    }
+
+    /// Set the DIP switches, as read from 0x5080
+    pub fn set_dip_switches(&mut self, dips: u8) {
+        self.board.borrow_mut().inputs[2] = dips;
+    }
+
+    /// The sound registers, 0x5040 to 0x505F, a nibble each
+    pub fn sound_registers(&self) -> [u8; 0x20] {
+        let mut registers = [0; 0x20];
+        registers.copy_from_slice(&self.board.borrow().registers[..0x20]);
+        registers
+    }
+
+    /// The number of frames run so far
+    pub fn frame(&self) -> u64 {
+        self.timer.frame()
+    }
+
+    /// Run until the end of the next frame, raising the vertical blank interrupt if the program
+    /// has enabled it
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "frame", level = "debug", skip_all)
+    )]
+    pub fn run_frame(&mut self) {
+        loop {
+            let before = self.z80.tstates();
+            self.z80.step();
+            let done = self.timer.advance((self.z80.tstates() - before) as u32);
+            if self.vblank.take() && self.board.borrow().interrupt_enable {
+                self.z80.request_interrupt();
+            }
+            if done {
+                break;
+            }
+        }
+        self.end_frame();
+    }
+
+    fn end_frame(&mut self) {
+        if self.on_frame.is_none() {
+            return;
+        }
+        let picture = Machine::framebuffer(self);
+        if let Some(f) = &mut self.on_frame {
+            f(&picture, &[]);
+        }
+    }
+
+    /// The 16 colours of the colour PROM, as red, green and blue
+    pub fn colors(&self) -> [[u8; 3]; 16] {
+        let board = self.board.borrow();
+        let mut colors = [[0; 3]; 16];
+        for (rgb, c) in colors.iter_mut().zip(board.colors.iter()) {
+            let bit = |n: u8| (c >> n) & 1;
+            *rgb = [
+                0x21 * bit(0) + 0x47 * bit(1) + 0x97 * bit(2),
+                0x21 * bit(3) + 0x47 * bit(4) + 0x97 * bit(5),
+                0x51 * bit(6) + 0xAE * bit(7),
+            ];
+        }
+        colors
+    }
+
+    /// Draw the picture, the right way up: WIDTH by HEIGHT pixels, a row at a time, each an
+    /// index into `colors`
+    pub fn render(&self) -> Vec<u8> {
+        let board = self.board.borrow();
+        let color = |palette: u8, pixel: u8| {
+            board.palettes[usize::from(palette & 0x3F) * 4 + usize::from(pixel)] & 0x0F
+        };
+        let mut screen = vec![0; WIDTH * HEIGHT];
+
+        for row in 0..HEIGHT / 8 {
+            for col in 0..WIDTH / 8 {
+                let offset = tile_offset(col, row);
+                let code = usize::from(board.ram[offset]);
+                let palette = board.ram[0x400 + offset];
+                let gfx = &board.tiles[code * 16..][..16];
+                for y in 0..8 {
+                    for x in 0..8 {
+                        let pixel = gfx_pixel(gfx, &TILE_X, &TILE_Y, x, y);
+                        screen[(row * 8 + y) * WIDTH + col * 8 + x] = color(palette, pixel);
+                    }
+                }
+            }
+        }
+
+        // Sprite 0 is on top, so is drawn last
+        for sprite in (0..8).rev() {
+            let attributes = &board.ram[0xFF0 + 2 * sprite..][..2];
+            let coords = &board.registers[0x20 + 2 * sprite..][..2];
+            let gfx = &board.sprites[usize::from(attributes[0] >> 2) * 64..][..64];
+            let flip_x = attributes[0] & 0b01 != 0;
+            let flip_y = attributes[0] & 0b10 != 0;
+            // The coordinates count from the bottom right corner, off the edge of the screen
+            let left = 239 - i32::from(coords[0]);
+            let top = 272 - i32::from(coords[1]);
+            for y in 0..16 {
+                for x in 0..16 {
+                    let (sx, sy) = (left + x as i32, top + y as i32);
+                    if sx < 0 || sy < 0 || sx >= WIDTH as i32 || sy >= HEIGHT as i32 {
+                        continue;
+                    }
+                    let gx = if flip_y { 15 - x } else { x };
+                    let gy = if flip_x { 15 - y } else { y };
+                    let pixel = gfx_pixel(gfx, &SPRITE_X, &SPRITE_Y, gx, gy);
+                    let c = color(attributes[1], pixel);
+                    // Colour 0 is see-through
+                    if c != 0 {
+                        screen[sy as usize * WIDTH + sx as usize] = c;
+                    }
+                }
+            }
+        }
+        screen
+    }
+}
+
+impl Machine for PacMan {
+    fn z80(&mut self) -> &mut Z80 {
+        &mut self.z80
+    }
+
+    fn run_frame(&mut self) {
+        PacMan::run_frame(self)
+    }
+
+    fn frame(&self) -> u64 {
+        PacMan::frame(self)
+    }
+
+    fn framebuffer(&self) -> Framebuffer {
+        Framebuffer::from_indexed(WIDTH, HEIGHT, &self.render(), &self.colors())
+    }
+
+    fn on_frame(&mut self, callback: FrameCallback) {
+        self.on_frame = Some(callback);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn peek(pacman: &PacMan, addr: u16) -> u8 {
+        BoardBus(pacman.board.clone()).mem_read(addr)
+    }
+
+    fn poke(pacman: &PacMan, addr: u16, val: u8) {
+        BoardBus(pacman.board.clone()).mem_write(addr, val);
+    }
+
+    // Counts interrupts at 0x4C00, and copies IN0 to 0x4C01
+    const PROGRAM: &[u8] = &[
+        0x31, 0xF0, 0x4F, // 0000 LD SP, 0x4FF0
+        0x3E, 0x30, // 0003 LD A, 0x30
+        0xED, 0x47, // 0005 LD I, A
+        0x3E, 0x02, // 0007 LD A, 0x02
+        0xD3, 0x00, // 0009 OUT (0), A
+        0xED, 0x5E, // 000B IM 2
+        0x3E, 0x01, // 000D LD A, 1
+        0x32, 0x00, 0x50, // 000F LD (0x5000), A
+        0xFB, // 0012 EI
+        0x18, 0xFE, // 0013 JR 0x0013
+    ];
+
+    const HANDLER: &[u8] = &[
+        0xF5, // 0100 PUSH AF
+        0x3A, 0x00, 0x4C, // 0101 LD A, (0x4C00)
+        0x3C, // 0104 INC A
+        0x32, 0x00, 0x4C, // 0105 LD (0x4C00), A
+        0x3A, 0x00, 0x50, // 0108 LD A, (0x5000)
+        0x32, 0x01, 0x4C, // 010B LD (0x4C01), A
+        0xF1, // 010E POP AF
+        0xFB, // 010F EI
+        0xED, 0x4D, // 0110 RETI
+    ];
+
+    #[test]
+    fn im2_and_inputs() {
+        let mut program = vec![0; 0x4000];
+        program[..PROGRAM.len()].copy_from_slice(PROGRAM);
+        program[0x100..][..HANDLER.len()].copy_from_slice(HANDLER);
+        // The vector table entry at 0x3002
+        program[0x3002..0x3004].copy_from_slice(&[0x00, 0x01]);
+        let mut pacman = PacMan::new(&Roms {
+            program: &program,
+            tiles: &[],
+            sprites: &[],
+            colors: &[],
+            palettes: &[],
+        });
+        pacman.set_input(Input::Left, true);
+        for _ in 0..3 {
+            pacman.run_frame();
+        }
+        assert_eq!(3, peek(&pacman, 0x4C00));
+        assert_eq!(0b1111_1101, peek(&pacman, 0x4C01));
+
+        // No interrupts once they are disabled
+        poke(&pacman, 0x5000, 0);
+        pacman.run_frame();
+        assert_eq!(3, peek(&pacman, 0x4C00));
+        // A15 isn't decoded
+        assert_eq!(3, peek(&pacman, 0xCC00));
+    }
+
+    #[test]
+    fn render() {
+        // Tile 1 is solid pen 3, and sprite 1 is solid pen 1 but for a see-through corner
+        let mut tiles = vec![0; 32];
+        tiles[16..].copy_from_slice(&[0xFF; 16]);
+        let mut sprites = vec![0; 128];
+        sprites[64..].copy_from_slice(&[0x0F; 64]);
+        sprites[64 + 8] = 0x0E;
+        let mut palettes = vec![0; 256];
+        palettes[4 + 3] = 0x05;
+        palettes[4 + 1] = 0x07;
+        let mut colors = vec![0; 32];
+        colors[5] = 0b1100_0111;
+        let pacman = PacMan::new(&Roms {
+            program: &[],
+            tiles: &tiles,
+            sprites: &sprites,
+            colors: &colors,
+            palettes: &palettes,
+        });
+        assert_eq!([0xFF, 0x00, 0xFF], pacman.colors()[5]);
+
+        // The top left tile, and one in the middle
+        poke(&pacman, 0x43DD, 1);
+        poke(&pacman, 0x47DD, 1);
+        poke(&pacman, 0x4040 + 27 * 32, 1);
+        poke(&pacman, 0x4440 + 27 * 32, 1);
+        // Sprite 0 at the left edge, as high as sprites go
+        poke(&pacman, 0x4FF0, 1 << 2);
+        poke(&pacman, 0x4FF1, 1);
+        poke(&pacman, 0x5060, 239);
+        poke(&pacman, 0x5061, 255);
+
+        let screen = pacman.render();
+        let at = |x: usize, y: usize| screen[y * WIDTH + x];
+        assert_eq!(5, at(0, 0));
+        assert_eq!(5, at(7, 7));
+        assert_eq!(0, at(8, 8));
+        assert_eq!(7, at(0, 17));
+        assert_eq!(7, at(15, 32));
+        assert_eq!(0, at(16, 17));
+        // The sprite is in front of the tiles, apart from its see-through pixel
+        assert_eq!(5, at(0, 16));
+        assert_eq!(7, at(7, 23));
+        assert_eq!(0, at(15, 20));
+        assert_eq!(16 * 16 - 1, screen.iter().filter(|c| **c == 7).count());
+    }
+}
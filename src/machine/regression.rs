@@ -0,0 +1,201 @@
+//! Visual regression tests: run a machine for some frames, and compare its picture with a
+//! reference image checked in beside the tests, so a change to a video chip can't quietly
+//! break what's drawn. References are binary PPM files, which any image viewer opens.
+//!
+//! Set ZEERUST_BLESS=1 to write the current pictures as the new references instead. When a
+//! picture doesn't match, it's written beside the reference with `.actual.ppm` on the end,
+//! to look at.
+use std::fs;
+use std::path::Path;
+
+use super::{Framebuffer, Machine};
+
+/// How different a picture can be and still match
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Tolerance {
+    /// How far each of a pixel's red, green and blue can be off before the pixel differs
+    pub channel: u8,
+    /// How many pixels can differ
+    pub pixels: usize,
+}
+
+impl Tolerance {
+    /// Every pixel exactly the same
+    pub const EXACT: Tolerance = Tolerance {
+        channel: 0,
+        pixels: 0,
+    };
+}
+
+impl Framebuffer {
+    /// The picture as a binary (P6) PPM file
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut ppm = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        self.pixels
+            .iter()
+            .for_each(|rgb| ppm.extend_from_slice(rgb));
+        ppm
+    }
+
+    /// Read a binary PPM file with 8 bit samples, as written by to_ppm
+    pub fn from_ppm(data: &[u8]) -> Option<Self> {
+        // The header is four fields separated by whitespace, and comments to the end of a line
+        let mut fields = vec![];
+        let mut i = 0;
+        while fields.len() < 4 {
+            match data.get(i)? {
+                b'#' => i += data[i..].iter().position(|b| *b == b'\n')?,
+                b if b.is_ascii_whitespace() => i += 1,
+                _ => {
+                    let len = data[i..]
+                        .iter()
+                        .take_while(|b| !b.is_ascii_whitespace())
+                        .count();
+                    fields.push(std::str::from_utf8(&data[i..i + len]).ok()?);
+                    i += len;
+                }
+            }
+        }
+        let number = |field: &str| field.parse::<usize>().ok();
+        let (width, height) = (number(fields[1])?, number(fields[2])?);
+        if fields[0] != "P6" || fields[3] != "255" {
+            return None;
+        }
+        // A single whitespace byte ends the header
+        let body = data.get(i + 1..)?;
+        if body.len() != width.checked_mul(height)?.checked_mul(3)? {
+            return None;
+        }
+        Some(Self {
+            width,
+            height,
+            pixels: body.chunks(3).map(|p| [p[0], p[1], p[2]]).collect(),
+        })
+    }
+}
+
+/// Compare a picture with its reference, returning a report of how they differ if they don't
+/// match within the tolerance
+pub fn compare(
+    reference: &Framebuffer,
+    picture: &Framebuffer,
+    tolerance: Tolerance,
+) -> Option<String> {
+    if (reference.width, reference.height) != (picture.width, picture.height) {
+        return Some(format!(
+            "picture is {}x{}, but the reference is {}x{}",
+            picture.width, picture.height, reference.width, reference.height
+        ));
+    }
+    let differs = |(a, b): &(&[u8; 3], &[u8; 3])| {
+        a.iter()
+            .zip(b.iter())
+            .any(|(a, b)| a.abs_diff(*b) > tolerance.channel)
+    };
+    let pairs = reference.pixels.iter().zip(&picture.pixels);
+    let count = pairs.clone().filter(differs).count();
+    if count <= tolerance.pixels {
+        return None;
+    }
+    let first = pairs.clone().position(|p| differs(&p)).unwrap();
+    let width = reference.width.max(1);
+    Some(format!(
+        "{} pixels differ from the reference (at most {} may); the first at ({}, {}) is {:02x?}, not {:02x?}",
+        count,
+        tolerance.pixels,
+        first % width,
+        first / width,
+        picture.pixels[first],
+        reference.pixels[first]
+    ))
+}
+
+/// Run a machine for `frames` frames, and compare its picture with the reference PPM. Returns
+/// a report of what went wrong if the picture doesn't match, or the reference can't be read.
+/// ```no_run
+/// use zeerust::machine::invaders::SpaceInvaders;
+/// use zeerust::machine::regression::{self, Tolerance};
+///
+/// let rom = std::fs::read("invaders.rom").unwrap();
+/// let mut machine = SpaceInvaders::new(&rom);
+/// let reference = std::path::Path::new("tests/visual/invaders.ppm");
+/// if let Err(report) = regression::check_frames(&mut machine, 100, reference, Tolerance::EXACT) {
+///     panic!("{}", report);
+/// }
+///```
+pub fn check_frames<M: Machine>(
+    machine: &mut M,
+    frames: u64,
+    reference: &Path,
+    tolerance: Tolerance,
+) -> Result<(), String> {
+    for _ in 0..frames {
+        machine.run_frame();
+    }
+    let picture = machine.framebuffer();
+    let failed = |e: std::io::Error| format!("{}: {}", reference.display(), e);
+    if std::env::var_os("ZEERUST_BLESS").is_some() {
+        if let Some(dir) = reference.parent() {
+            fs::create_dir_all(dir).map_err(failed)?;
+        }
+        return fs::write(reference, picture.to_ppm()).map_err(failed);
+    }
+    let expected = Framebuffer::from_ppm(&fs::read(reference).map_err(failed)?)
+        .ok_or_else(|| format!("{}: not a PPM file", reference.display()))?;
+    match compare(&expected, &picture, tolerance) {
+        None => Ok(()),
+        Some(report) => {
+            let actual = reference.with_extension("actual.ppm");
+            fs::write(&actual, picture.to_ppm()).map_err(failed)?;
+            Err(format!(
+                "{}: {} (see {})",
+                reference.display(),
+                report,
+                actual.display()
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn picture(pixels: Vec<[u8; 3]>) -> Framebuffer {
+        Framebuffer {
+            width: 2,
+            height: pixels.len() / 2,
+            pixels,
+        }
+    }
+
+    #[test]
+    fn ppm() {
+        let original = picture(vec![[1, 2, 3], [4, 5, 6], [0xFF; 3], [0; 3]]);
+        let ppm = original.to_ppm();
+        assert_eq!(b"P6\n2 2\n255\n\x01\x02\x03", &ppm[..14]);
+        assert_eq!(Some(original.clone()), Framebuffer::from_ppm(&ppm));
+        let commented = [&b"P6 # made by hand\n2\t2 255\n"[..], &ppm[11..]].concat();
+        assert_eq!(Some(original), Framebuffer::from_ppm(&commented));
+        assert_eq!(None, Framebuffer::from_ppm(&ppm[..ppm.len() - 1]));
+        assert_eq!(None, Framebuffer::from_ppm(b"P3\n1 1\n255\n0 0 0"));
+    }
+
+    #[test]
+    fn tolerance() {
+        let reference = picture(vec![[0x10; 3], [0x20; 3], [0x30; 3], [0x40; 3]]);
+        let close = picture(vec![[0x12; 3], [0x20; 3], [0x30; 3], [0x80; 3]]);
+        let tolerance = Tolerance {
+            channel: 2,
+            pixels: 1,
+        };
+        assert_eq!(None, compare(&reference, &close, tolerance));
+        assert_eq!(
+            Some("2 pixels differ from the reference (at most 0 may); the first at (0, 0) is [12, 12, 12], not [10, 10, 10]".to_string()),
+            compare(&reference, &close, Tolerance::EXACT)
+        );
+        assert!(compare(&reference, &picture(vec![[0; 3]; 2]), tolerance)
+            .unwrap()
+            .starts_with("picture is 2x1"));
+    }
+}
@@ -2,10 +2,17 @@ extern crate zeerust;
 
 use std::env;
 use std::fs::File;
-use std::io::{stdout, Read, Result, Write};
+use std::io::{stdin, stdout, Read, Result, Write};
+use std::path::Path;
 
 extern crate stderrlog;
 
+use zeerust::debug::annotations::Annotations;
+use zeerust::debug::dap::Dap;
+use zeerust::debug::listing::Listing;
+use zeerust::debug::repl::Repl;
+use zeerust::debug::symbols::Symbols;
+use zeerust::debug::Debugger;
 use zeerust::z80;
 use zeerust::z80::io;
 
@@ -18,11 +25,24 @@ impl io::OutputDevice for StdoutOutput {
 }
 
 fn main() -> Result<()> {
-    let filename = env::args().nth(1).unwrap_or_else(|| {
+    let mut args = env::args().skip(1);
+    let filename = args.next().unwrap_or_else(|| {
         eprintln!("Missing file to run");
         std::process::exit(1);
     });
-    let mut file = File::open(filename)?;
+    // `--debug 127.0.0.1:9000` waits for a debugger to connect over a WebSocket, `--dap`
+    // for an editor to connect with the Debug Adapter Protocol, and `--repl` takes debugger
+    // commands from the terminal
+    let debugger = match (args.next(), args.next()) {
+        (Some(flag), Some(addr)) if flag == "--debug" || flag == "--dap" => Some((flag, addr)),
+        (Some(flag), None) if flag == "--repl" => Some((flag, String::new())),
+        (None, _) => None,
+        _ => {
+            eprintln!("Usage: zeerust FILE [--debug ADDRESS | --dap ADDRESS | --repl]");
+            std::process::exit(1);
+        }
+    };
+    let mut file = File::open(&filename)?;
     let mut buf = Vec::new();
     file.read_to_end(&mut buf)?;
 
@@ -36,6 +56,45 @@ fn main() -> Result<()> {
     let mut z80 = z80::Z80::default();
     z80.install_output(0x00, Box::new(StdoutOutput {}));
     z80.load(buf.as_slice());
-    z80.run();
+    match debugger {
+        Some((flag, addr)) if flag == "--dap" => {
+            Dap::with_listing(listing(&filename)?).listen(&mut z80, addr)?
+        }
+        Some((flag, _)) if flag == "--repl" => {
+            let mut repl = Repl::default();
+            repl.debugger.symbols = listing(&filename)?.symbols;
+            // The memory annotations, which `save` writes back
+            let project = Path::new(&filename).with_extension("project");
+            if let Ok(text) = std::fs::read_to_string(&project) {
+                repl.annotations = Annotations::parse(&text)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            }
+            repl.project = Some(project);
+            repl.serve(&mut z80, stdin().lock(), stdout())?
+        }
+        Some((_, addr)) => {
+            let mut debugger = Debugger::default();
+            debugger.symbols = listing(&filename)?.symbols;
+            debugger.listen(&mut z80, addr)?
+        }
+        None => z80.run(),
+    }
     Ok(())
 }
+
+// Source lines and labels, when the assembler left a listing, a label file (z80asm), a symbol
+// file (sjasmplus, no$ or WLA-DX) or a map file (z88dk) next to the program
+fn listing(filename: &str) -> Result<Listing> {
+    let mut listing = match std::fs::read_to_string(Path::new(filename).with_extension("lst")) {
+        Ok(text) => Listing::parse(&text),
+        Err(_) => Listing::default(),
+    };
+    for extension in ["lbl", "sym", "map"] {
+        if let Ok(text) = std::fs::read_to_string(Path::new(filename).with_extension(extension)) {
+            let labels = Symbols::parse(&text)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            listing.symbols.extend(&labels);
+        }
+    }
+    Ok(listing)
+}
@@ -3,6 +3,9 @@
 
 use std::fmt::Display;
 
+pub mod asm;
+pub mod build;
+
 /// Op represents a single operation.
 /// This representation (and backing implementation) is more expressive than
 /// the processor itself.
@@ -27,11 +30,11 @@ pub enum Op {
     /// DECrement
     DEC(Location8),
 
-    /// bitwise AND
+    /// bitwise AND with the accumulator
     AND(Location8),
-    /// bitwise OR
+    /// bitwise OR with the accumulator
     OR(Location8),
-    /// bitwise XOR
+    /// bitwise XOR with the accumulator
     XOR(Location8),
     /// two's ComPliment
     CP(Location8),
@@ -96,6 +99,9 @@ pub enum Op {
 
     /// JumP to the given position
     JP(JumpConditional, Location16),
+    /// JumP to the address held In a register.
+    /// Written `JP (HL)`, but unlike other indirect operations no memory is read.
+    JPI(Reg16),
     /// Jump to the given Relative position
     JR(JumpConditional, i8),
     /// Decrement register b, then Jump if register b is Non Zero
@@ -104,6 +110,19 @@ pub enum Op {
     CALL(JumpConditional, u16),
     /// RETurn from a method call
     RET(JumpConditional),
+    /// RETurn from an Interrupt. Like RETN, it also restores the interrupt flip-flop.
+    RETI,
+    /// RETurn from a Non-maskable interrupt, restoring the interrupt flip-flop
+    RETN,
+    /// ReSTart: a single byte call to one of eight fixed addresses
+    RST(u8),
+
+    /// Disable Interrupts
+    DI,
+    /// Enable Interrupts
+    EI,
+    /// set Interrupt Mode (0, 1 or 2)
+    IM(u8),
 
     /// Pop an address off of the stack
     POP(Location16),
@@ -113,16 +132,30 @@ pub enum Op {
     LD8(Location8, Location8),
     /// LoaD the given address (16-bit)
     LD16(Location16, Location16),
+    /// EXchange the contents of two locations
+    EX(Location16, Location16),
+
+    /// MuLTiply the two halves of a register pair together (Z180 only)
+    MLT(Reg16),
+    /// TeST: AND with the accumulator, keeping only the flags (Z180 only)
+    TST(Location8),
+    /// INput from port 0x00nn, setting flags (Z180 only)
+    IN0(Location8, u8),
+    /// OUTput to port 0x00nn (Z180 only)
+    OUT0(Location8, u8),
+    /// SLeeP until woken, like HALT (Z180 only)
+    SLP,
+
+    /// MULtiply Unsigned Byte: HL = A * r (R800 only)
+    MULUB(Reg8),
+    /// MULtiply Unsigned Word: DE:HL = HL * rr (R800 only)
+    MULUW(Reg16),
     // TODO
     // CPD,
     // CPDR,
     // CPI,
     // CPIR,
-    // DI,
-    // EI,
-    // EX,
     // EXX,
-    // IM,
     // IN,
     // IND,
     // INDR,
@@ -135,9 +168,6 @@ pub enum Op {
     // OTIR,
     // OUTD,
     // OUTI,
-    // RETI,
-    // RETN,
-    // RST,
     // SLA,
     // SLL,
     // SL1,
@@ -172,6 +202,11 @@ pub enum Reg8 {
     HP,
     /// L'
     LP,
+
+    /// Interrupt vector
+    I,
+    /// memory Refresh counter
+    R,
 }
 
 /// 16-bit registers
@@ -207,6 +242,11 @@ pub enum Location8 {
     ImmediateIndirect(u16),
     /// A literal number
     Immediate(u8),
+    /// A location in memory, offset from an index register: (IX+d) or (IY+d)
+    Indexed(Reg16, i8),
+    /// Like Indexed, but anything stored is also copied into a register.
+    /// Only the undocumented DDCB and FDCB instructions use this.
+    IndexedCopy(Reg16, i8, Reg8),
 }
 
 /// Anywhere a 16-bit value could could come from or be stored to
@@ -232,9 +272,12 @@ pub enum StatusFlag {
     /// Bit 2. Indicates overflow after arithmetic, or parity after bitwise operations
     /// Parity is set if the number of 1s in the number is even, otherwise it is reset
     ParityOverflow,
-    // Bit 3 unused
+    /// Bit 3. Undocumented, and not yet emulated: on a real z80 it's usually bit 3 of the result
+    X,
     /// Bit 4. Indicates carry or borrows from bit 3
     HalfCarry,
+    /// Bit 5. Undocumented, and not yet emulated: on a real z80 it's usually bit 5 of the result
+    Y,
     /// Bit 6. Set if result of an operation was zero
     Zero,
     /// Bit 7. Set if the 7th bit is 1 after an arithmatic operation, i.e. number is negative if considered as signed
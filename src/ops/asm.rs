@@ -0,0 +1,349 @@
+//! The z80_asm! macro, for writing ops as assembly.
+//! Instructions are separated by semicolons, and mnemonics and registers are upper case:
+//! ```
+//! use zeerust::ops::build::*;
+//! use zeerust::ops::{JumpConditional, Op};
+//! use zeerust::z80_asm;
+//!
+//! let ops = z80_asm! {
+//!     LD A, 0x3F;
+//!     LD (IX+2), A;
+//!     DEC A;
+//!     JR NZ, -3;
+//!     HALT
+//! };
+//! assert_eq!(
+//!     vec![
+//!         Op::ld(a(), imm(0x3F)),
+//!         Op::ld(ix_ind(2), a()),
+//!         Op::dec(a()),
+//!         Op::JR(JumpConditional::NonZero, -3),
+//!         Op::HALT,
+//!     ],
+//!     ops
+//! );
+//!```
+//! Parentheses mean indirection, as in `LD A, (0x4000)`, so any other computed operand goes in
+//! braces: `LD A, {BASE + 1}`. The undocumented registers and instructions aren't supported, and
+//! the shadow registers are written AFP, BCP and so on, as in Reg16.
+
+/// Turn assembly into a `Vec<Op>`. See the `ops::asm` module for the syntax.
+#[macro_export]
+macro_rules! z80_asm {
+    ( $($asm:tt)* ) => { $crate::__z80_asm!(@ [] $($asm)*) };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __z80_asm {
+    (@ [$($ops:expr,)*]) => { vec![$($ops),*] };
+    (@ [$($ops:expr,)*] $mn:ident $(; $($rest:tt)*)?) => {
+        $crate::__z80_asm!(@ [$($ops,)* $crate::__z80_op!($mn),] $($($rest)*)?)
+    };
+    (@ [$($ops:expr,)*] $mn:ident $a:tt , - $b:tt $(; $($rest:tt)*)?) => {
+        $crate::__z80_asm!(@ [$($ops,)* $crate::__z80_op!($mn $a, {-$b}),] $($($rest)*)?)
+    };
+    (@ [$($ops:expr,)*] $mn:ident $a:tt , $b:tt $(; $($rest:tt)*)?) => {
+        $crate::__z80_asm!(@ [$($ops,)* $crate::__z80_op!($mn $a, $b),] $($($rest)*)?)
+    };
+    (@ [$($ops:expr,)*] $mn:ident - $a:tt $(; $($rest:tt)*)?) => {
+        $crate::__z80_asm!(@ [$($ops,)* $crate::__z80_op!($mn {-$a}),] $($($rest)*)?)
+    };
+    (@ [$($ops:expr,)*] $mn:ident $a:tt $(; $($rest:tt)*)?) => {
+        $crate::__z80_asm!(@ [$($ops,)* $crate::__z80_op!($mn $a),] $($($rest)*)?)
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __z80_op {
+    (RET) => {
+        $crate::ops::Op::RET($crate::ops::JumpConditional::Unconditional)
+    };
+    (RET $cc:tt) => {
+        $crate::ops::Op::RET($crate::__z80_cc!($cc))
+    };
+    (JP (HL)) => {
+        $crate::ops::Op::JPI($crate::ops::Reg16::HL)
+    };
+    (JP (IX)) => {
+        $crate::ops::Op::JPI($crate::ops::Reg16::IX)
+    };
+    (JP (IY)) => {
+        $crate::ops::Op::JPI($crate::ops::Reg16::IY)
+    };
+    (JP $cc:tt, $nn:tt) => {
+        $crate::ops::Op::JP($crate::__z80_cc!($cc), $crate::__z80_loc16!($nn))
+    };
+    (JP $nn:tt) => {
+        $crate::ops::Op::JP(
+            $crate::ops::JumpConditional::Unconditional,
+            $crate::__z80_loc16!($nn),
+        )
+    };
+    (JR $cc:tt, $e:tt) => {
+        $crate::ops::Op::JR($crate::__z80_cc!($cc), $crate::__z80_num!($e))
+    };
+    (JR $e:tt) => {
+        $crate::ops::Op::JR(
+            $crate::ops::JumpConditional::Unconditional,
+            $crate::__z80_num!($e),
+        )
+    };
+    (DJNZ $e:tt) => {
+        $crate::ops::Op::DJNZ($crate::__z80_num!($e))
+    };
+    (CALL $cc:tt, $nn:tt) => {
+        $crate::ops::Op::CALL($crate::__z80_cc!($cc), $crate::__z80_num!($nn))
+    };
+    (CALL $nn:tt) => {
+        $crate::ops::Op::CALL(
+            $crate::ops::JumpConditional::Unconditional,
+            $crate::__z80_num!($nn),
+        )
+    };
+    (RST $n:tt) => {
+        $crate::ops::Op::RST($crate::__z80_num!($n))
+    };
+    (IM $n:tt) => {
+        $crate::ops::Op::IM($crate::__z80_num!($n))
+    };
+
+    (PUSH $r:tt) => {
+        $crate::ops::Op::PUSH($crate::__z80_loc16!($r))
+    };
+    (POP $r:tt) => {
+        $crate::ops::Op::POP($crate::__z80_loc16!($r))
+    };
+    (EX $a:tt, $b:tt) => {
+        $crate::ops::Op::EX($crate::__z80_loc16!($a), $crate::__z80_loc16!($b))
+    };
+    (LD $d:tt, $s:tt) => {
+        $crate::__z80_ld!($d, $s)
+    };
+
+    (ADD A, $s:tt) => {
+        $crate::ops::Op::ADD8($crate::ops::build::a(), $crate::__z80_loc8!($s))
+    };
+    (ADC A, $s:tt) => {
+        $crate::ops::Op::ADC($crate::ops::build::a(), $crate::__z80_loc8!($s))
+    };
+    (SUB $s:tt) => {
+        $crate::ops::Op::SUB8($crate::ops::build::a(), $crate::__z80_loc8!($s))
+    };
+    (SBC A, $s:tt) => {
+        $crate::ops::Op::SBC($crate::ops::build::a(), $crate::__z80_loc8!($s))
+    };
+    (IN $r:tt, (C)) => {
+        $crate::ops::Op::IN($crate::__z80_loc8!($r), $crate::ops::build::c())
+    };
+    (IN A, ($n:tt)) => {
+        $crate::ops::Op::IN($crate::ops::build::a(), $crate::__z80_loc8!($n))
+    };
+    (OUT (C), $r:tt) => {
+        $crate::ops::Op::OUT($crate::__z80_loc8!($r), $crate::ops::build::c())
+    };
+    (OUT ($n:tt), A) => {
+        $crate::ops::Op::OUT($crate::ops::build::a(), $crate::__z80_loc8!($n))
+    };
+
+    // BIT, SET and RES
+    ($mn:ident $b:tt, $s:tt) => {
+        $crate::ops::Op::$mn($crate::__z80_num!($b), $crate::__z80_loc8!($s))
+    };
+    // Everything else with one operand: AND, INC, RLC...
+    ($mn:ident $s:tt) => {
+        $crate::ops::Op::$mn($crate::__z80_loc8!($s))
+    };
+    // And with none
+    ($mn:ident) => {
+        $crate::ops::Op::$mn
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __z80_ld {
+    (AF, $s:tt) => { $crate::__z80_ld!(@16 AF, $s) };
+    (BC, $s:tt) => { $crate::__z80_ld!(@16 BC, $s) };
+    (DE, $s:tt) => { $crate::__z80_ld!(@16 DE, $s) };
+    (HL, $s:tt) => { $crate::__z80_ld!(@16 HL, $s) };
+    (SP, $s:tt) => { $crate::__z80_ld!(@16 SP, $s) };
+    (IX, $s:tt) => { $crate::__z80_ld!(@16 IX, $s) };
+    (IY, $s:tt) => { $crate::__z80_ld!(@16 IY, $s) };
+    ($d:tt, BC) => { $crate::__z80_ld!(@16 $d, BC) };
+    ($d:tt, DE) => { $crate::__z80_ld!(@16 $d, DE) };
+    ($d:tt, HL) => { $crate::__z80_ld!(@16 $d, HL) };
+    ($d:tt, SP) => { $crate::__z80_ld!(@16 $d, SP) };
+    ($d:tt, IX) => { $crate::__z80_ld!(@16 $d, IX) };
+    ($d:tt, IY) => { $crate::__z80_ld!(@16 $d, IY) };
+    (@16 $d:tt, $s:tt) => {
+        $crate::ops::Op::LD16($crate::__z80_loc16!($d), $crate::__z80_loc16!($s))
+    };
+    ($d:tt, $s:tt) => { $crate::ops::Op::LD8($crate::__z80_loc8!($d), $crate::__z80_loc8!($s)) };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __z80_cc {
+    (NZ) => {
+        $crate::ops::JumpConditional::NonZero
+    };
+    (Z) => {
+        $crate::ops::JumpConditional::Zero
+    };
+    (NC) => {
+        $crate::ops::JumpConditional::NoCarry
+    };
+    (C) => {
+        $crate::ops::JumpConditional::Carry
+    };
+    (PO) => {
+        $crate::ops::JumpConditional::ParityOdd
+    };
+    (PE) => {
+        $crate::ops::JumpConditional::ParityEven
+    };
+    (P) => {
+        $crate::ops::JumpConditional::SignPositive
+    };
+    (M) => {
+        $crate::ops::JumpConditional::SignNegative
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __z80_num {
+    ({$e:expr}) => {
+        $e
+    };
+    ($n:literal) => {
+        $n
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __z80_loc8 {
+    ((BC)) => {
+        $crate::ops::Reg16::BC.ind()
+    };
+    ((DE)) => {
+        $crate::ops::Reg16::DE.ind()
+    };
+    ((HL)) => {
+        $crate::ops::Reg16::HL.ind()
+    };
+    ((IX)) => {
+        $crate::ops::build::ix_ind(0)
+    };
+    ((IX + $d:tt)) => {
+        $crate::ops::build::ix_ind($crate::__z80_num!($d))
+    };
+    ((IX - $d:tt)) => {
+        $crate::ops::build::ix_ind(-$crate::__z80_num!($d))
+    };
+    ((IY)) => {
+        $crate::ops::build::iy_ind(0)
+    };
+    ((IY + $d:tt)) => {
+        $crate::ops::build::iy_ind($crate::__z80_num!($d))
+    };
+    ((IY - $d:tt)) => {
+        $crate::ops::build::iy_ind(-$crate::__z80_num!($d))
+    };
+    (($nn:tt)) => {
+        $crate::ops::build::mem($crate::__z80_num!($nn))
+    };
+    ($r:ident) => {
+        $crate::ops::Reg8::$r.loc()
+    };
+    ($n:tt) => {
+        $crate::ops::build::imm($crate::__z80_num!($n))
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __z80_loc16 {
+    ((SP)) => {
+        $crate::ops::Location16::RegIndirect($crate::ops::Reg16::SP)
+    };
+    (($nn:tt)) => {
+        $crate::ops::build::mem16($crate::__z80_num!($nn))
+    };
+    ($r:ident) => {
+        $crate::ops::Reg16::$r.loc()
+    };
+    ($n:tt) => {
+        $crate::ops::build::imm16($crate::__z80_num!($n))
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ops::build::*;
+    use crate::ops::{JumpConditional, Location16, Op, Reg16, Reg8};
+    use crate::z80::Z80;
+
+    #[test]
+    fn operands() {
+        const BASE: u16 = 0x4000;
+        assert_eq!(
+            vec![
+                Op::ld(Reg8::B.loc(), iy_ind(-3)),
+                Op::ld16(Reg16::HL.loc(), imm16(BASE)),
+                Op::ld16(mem16(BASE + 2), Reg16::SP.loc()),
+                Op::ld(a(), mem(BASE)),
+                Op::ld(hl_ind(), imm(4)),
+                Op::EX(Location16::RegIndirect(Reg16::SP), Reg16::HL.loc()),
+                Op::BIT(7, ix_ind(1)),
+                Op::add(hl_ind()),
+                Op::AND(imm(0x0F)),
+                Op::IN(e(), c()),
+                Op::out(0xFE),
+                Op::JPI(Reg16::HL),
+                Op::JP(JumpConditional::ParityEven, imm16(0x0038)),
+                Op::RET(JumpConditional::Carry),
+                Op::ret(),
+            ],
+            z80_asm! {
+                LD B, (IY-3);
+                LD HL, {BASE};
+                LD ({BASE + 2}), SP;
+                LD A, ({BASE});
+                LD (HL), 4;
+                EX (SP), HL;
+                BIT 7, (IX+1);
+                ADD A, (HL);
+                AND 0x0F;
+                IN E, (C);
+                OUT (0xFE), A;
+                JP (HL);
+                JP PE, 0x0038;
+                RET C;
+                RET;
+            }
+        );
+    }
+
+    #[test]
+    fn run() {
+        let mut z80 = Z80::default();
+        // Add up 1 to 10
+        let ops = z80_asm! {
+            LD B, 10;
+            XOR A;
+            ADD A, B;
+            DJNZ -3;
+        };
+        z80.exec(ops[0].clone());
+        z80.exec(ops[1].clone());
+        while z80.registers.get_reg8(Reg8::B) > 0 {
+            z80.exec(ops[2].clone());
+            z80.exec(ops[3].clone());
+        }
+        assert_eq!(55, z80.registers.get_reg8(Reg8::A));
+    }
+}
@@ -0,0 +1,223 @@
+//! Shorthand for building ops by hand, mostly in tests:
+//! ```
+//! use zeerust::ops::build::*;
+//! use zeerust::ops::{Location8, Op, Reg8};
+//!
+//! assert_eq!(
+//!     Op::LD8(Location8::Reg(Reg8::A), Location8::Immediate(0x3F)),
+//!     Op::ld(a(), imm(0x3F))
+//! );
+//! ```
+use super::{JumpConditional, Location16, Location8, Op, Reg16, Reg8};
+
+pub fn a() -> Location8 {
+    Location8::Reg(Reg8::A)
+}
+
+pub fn b() -> Location8 {
+    Location8::Reg(Reg8::B)
+}
+
+pub fn c() -> Location8 {
+    Location8::Reg(Reg8::C)
+}
+
+pub fn d() -> Location8 {
+    Location8::Reg(Reg8::D)
+}
+
+pub fn e() -> Location8 {
+    Location8::Reg(Reg8::E)
+}
+
+pub fn h() -> Location8 {
+    Location8::Reg(Reg8::H)
+}
+
+pub fn l() -> Location8 {
+    Location8::Reg(Reg8::L)
+}
+
+/// A literal byte
+pub fn imm(n: u8) -> Location8 {
+    Location8::Immediate(n)
+}
+
+/// The byte at a literal address: (nn)
+pub fn mem(addr: u16) -> Location8 {
+    Location8::ImmediateIndirect(addr)
+}
+
+/// The byte HL points to: (HL)
+pub fn hl_ind() -> Location8 {
+    Location8::RegIndirect(Reg16::HL)
+}
+
+/// (IX+d)
+pub fn ix_ind(d: i8) -> Location8 {
+    Location8::Indexed(Reg16::IX, d)
+}
+
+/// (IY+d)
+pub fn iy_ind(d: i8) -> Location8 {
+    Location8::Indexed(Reg16::IY, d)
+}
+
+/// A literal word
+pub fn imm16(n: u16) -> Location16 {
+    Location16::Immediate(n)
+}
+
+/// The word at a literal address: (nn)
+pub fn mem16(addr: u16) -> Location16 {
+    Location16::ImmediateIndirect(addr)
+}
+
+impl Reg8 {
+    /// This register, as a location
+    pub fn loc(self) -> Location8 {
+        Location8::Reg(self)
+    }
+}
+
+impl Reg16 {
+    /// This register, as a location
+    pub fn loc(self) -> Location16 {
+        Location16::Reg(self)
+    }
+
+    /// The byte this register points to
+    pub fn ind(self) -> Location8 {
+        Location8::RegIndirect(self)
+    }
+}
+
+impl Op {
+    /// LD dst, src
+    pub fn ld(dst: Location8, src: Location8) -> Op {
+        Op::LD8(dst, src)
+    }
+
+    /// LD dst, src (16-bit)
+    pub fn ld16(dst: Location16, src: Location16) -> Op {
+        Op::LD16(dst, src)
+    }
+
+    /// ADD A, src
+    pub fn add(src: Location8) -> Op {
+        Op::ADD8(a(), src)
+    }
+
+    /// ADC A, src
+    pub fn adc(src: Location8) -> Op {
+        Op::ADC(a(), src)
+    }
+
+    /// SUB src
+    pub fn sub(src: Location8) -> Op {
+        Op::SUB8(a(), src)
+    }
+
+    /// SBC A, src
+    pub fn sbc(src: Location8) -> Op {
+        Op::SBC(a(), src)
+    }
+
+    pub fn and(src: Location8) -> Op {
+        Op::AND(src)
+    }
+
+    pub fn or(src: Location8) -> Op {
+        Op::OR(src)
+    }
+
+    pub fn xor(src: Location8) -> Op {
+        Op::XOR(src)
+    }
+
+    pub fn cp(src: Location8) -> Op {
+        Op::CP(src)
+    }
+
+    pub fn inc(loc: Location8) -> Op {
+        Op::INC(loc)
+    }
+
+    pub fn dec(loc: Location8) -> Op {
+        Op::DEC(loc)
+    }
+
+    pub fn push(reg: Reg16) -> Op {
+        Op::PUSH(Location16::Reg(reg))
+    }
+
+    pub fn pop(reg: Reg16) -> Op {
+        Op::POP(Location16::Reg(reg))
+    }
+
+    /// OUT (port), A
+    pub fn out(port: u8) -> Op {
+        Op::OUT(a(), imm(port))
+    }
+
+    /// IN A, (port)
+    pub fn input(port: u8) -> Op {
+        Op::IN(a(), imm(port))
+    }
+
+    /// An unconditional JP to a literal address
+    pub fn jp(addr: u16) -> Op {
+        Op::JP(JumpConditional::Unconditional, imm16(addr))
+    }
+
+    /// An unconditional JR
+    pub fn jr(offset: i8) -> Op {
+        Op::JR(JumpConditional::Unconditional, offset)
+    }
+
+    /// An unconditional CALL
+    pub fn call(addr: u16) -> Op {
+        Op::CALL(JumpConditional::Unconditional, addr)
+    }
+
+    /// An unconditional RET
+    pub fn ret() -> Op {
+        Op::RET(JumpConditional::Unconditional)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn locations() {
+        assert_eq!(Location8::Reg(Reg8::L), l());
+        assert_eq!(Location8::Reg(Reg8::D), Reg8::D.loc());
+        assert_eq!(Location8::RegIndirect(Reg16::HL), hl_ind());
+        assert_eq!(Location8::RegIndirect(Reg16::BC), Reg16::BC.ind());
+        assert_eq!(Location8::Indexed(Reg16::IY, -4), iy_ind(-4));
+        assert_eq!(Location16::Reg(Reg16::SP), Reg16::SP.loc());
+        assert_eq!(Location16::ImmediateIndirect(0x1234), mem16(0x1234));
+    }
+
+    #[test]
+    fn ops() {
+        assert_eq!(
+            Op::ADD8(Location8::Reg(Reg8::A), Location8::RegIndirect(Reg16::HL)),
+            Op::add(hl_ind())
+        );
+        assert_eq!(
+            Op::LD16(Location16::Reg(Reg16::HL), Location16::Immediate(0x4000)),
+            Op::ld16(Reg16::HL.loc(), imm16(0x4000))
+        );
+        assert_eq!(
+            Op::OUT(Location8::Reg(Reg8::A), Location8::Immediate(0x00)),
+            Op::out(0x00)
+        );
+        assert_eq!(
+            Op::CALL(JumpConditional::Unconditional, 0x0010),
+            Op::call(0x0010)
+        );
+    }
+}
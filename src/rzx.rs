@@ -0,0 +1,470 @@
+//! Reading, writing, playing back and recording RZX input recordings.
+//! An RZX file holds a starting snapshot along with every value the machine read from its
+//! peripherals, frame by frame. Playing one back reproduces a session exactly, which makes it
+//! useful for regression testing whole programs.
+//!
+//! Compressed blocks, as most recordings use, are read with the `deflate` feature (on by
+//! default). Files are always written uncompressed.
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::z80::io::InputDevice;
+
+const SIGNATURE: &[u8; 4] = b"RZX!";
+const VERSION: (u8, u8) = (0, 13);
+
+const CREATOR_BLOCK: u8 = 0x10;
+const SNAPSHOT_BLOCK: u8 = 0x30;
+const INPUT_BLOCK: u8 = 0x80;
+
+const COMPRESSED: u32 = 0b10;
+const REPEAT_INPUTS: u16 = 0xFFFF;
+
+#[derive(Debug, PartialEq)]
+pub enum RzxError {
+    /// The file doesn't start with the RZX signature
+    BadSignature,
+    /// The file ended in the middle of a block
+    Truncated,
+    /// The block is compressed, and the `deflate` feature is off
+    Compressed,
+    /// A compressed block doesn't decompress
+    BadCompression,
+}
+
+impl fmt::Display for RzxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RzxError::BadSignature => write!(f, "not an RZX file"),
+            RzxError::Truncated => write!(f, "RZX file is truncated"),
+            RzxError::Compressed => write!(f, "compressed RZX blocks are not supported"),
+            RzxError::BadCompression => write!(f, "compressed RZX block is corrupt"),
+        }
+    }
+}
+
+impl std::error::Error for RzxError {}
+
+/// A single frame of input: the number of opcode fetches before the interrupt ended the frame,
+/// and every value read with IN, in order.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Frame {
+    pub fetch_count: u16,
+    pub inputs: Vec<u8>,
+}
+
+/// A snapshot embedded in the recording, to be loaded before playback starts
+#[derive(Debug, PartialEq, Clone)]
+pub struct Snapshot {
+    /// The snapshot's file extension, such as "z80" or "sna"
+    pub extension: String,
+    pub data: Vec<u8>,
+}
+
+/// The contents of an RZX file
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Rzx {
+    pub snapshot: Option<Snapshot>,
+    /// T-state counter at the start of the recording
+    pub tstates: u32,
+    pub frames: Vec<Frame>,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], RzxError> {
+        let end = self.pos.checked_add(n).ok_or(RzxError::Truncated)?;
+        let bytes = self.data.get(self.pos..end).ok_or(RzxError::Truncated)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.data[self.pos..];
+        self.pos = self.data.len();
+        rest
+    }
+
+    fn u8(&mut self) -> Result<u8, RzxError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, RzxError> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, RzxError> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+}
+
+impl Rzx {
+    /// Parse an RZX file. Unknown blocks (such as security information) are skipped.
+    pub fn parse(data: &[u8]) -> Result<Self, RzxError> {
+        let mut r = Reader { data, pos: 0 };
+        if r.bytes(4).map_err(|_| RzxError::BadSignature)? != SIGNATURE {
+            return Err(RzxError::BadSignature);
+        }
+        let _version = r.bytes(2)?;
+        let _flags = r.u32()?;
+
+        let mut rzx = Rzx::default();
+        let mut first_input = true;
+        while r.pos < data.len() {
+            let id = r.u8()?;
+            let len = r.u32()? as usize;
+            let mut block = Reader {
+                data: r.bytes(len.checked_sub(5).ok_or(RzxError::Truncated)?)?,
+                pos: 0,
+            };
+            match id {
+                SNAPSHOT_BLOCK => {
+                    let flags = block.u32()?;
+                    let ext = block.bytes(4)?;
+                    let len = block.u32()? as usize;
+                    let extension = ext.iter().take_while(|b| **b != 0).map(|b| *b as char);
+                    let data = if flags & COMPRESSED != 0 {
+                        let data = inflate(block.rest())?;
+                        data.get(..len).ok_or(RzxError::Truncated)?.to_vec()
+                    } else {
+                        block.bytes(len)?.to_vec()
+                    };
+                    rzx.snapshot = Some(Snapshot {
+                        extension: extension.collect(),
+                        data,
+                    });
+                }
+                INPUT_BLOCK => {
+                    let count = block.u32()?;
+                    let _reserved = block.u8()?;
+                    let tstates = block.u32()?;
+                    let flags = block.u32()?;
+                    let inflated;
+                    if flags & COMPRESSED != 0 {
+                        inflated = inflate(block.rest())?;
+                        block = Reader {
+                            data: &inflated,
+                            pos: 0,
+                        };
+                    }
+                    if first_input {
+                        rzx.tstates = tstates;
+                        first_input = false;
+                    }
+                    for _ in 0..count {
+                        let fetch_count = block.u16()?;
+                        let in_count = block.u16()?;
+                        let inputs = if in_count == REPEAT_INPUTS {
+                            rzx.frames.last().map_or(vec![], |f| f.inputs.clone())
+                        } else {
+                            block.bytes(in_count as usize)?.to_vec()
+                        };
+                        rzx.frames.push(Frame {
+                            fetch_count,
+                            inputs,
+                        });
+                    }
+                }
+                _ => (),
+            }
+        }
+        Ok(rzx)
+    }
+
+    /// Serialize to an (uncompressed) RZX file
+    pub fn write(&self) -> Vec<u8> {
+        let mut out = SIGNATURE.to_vec();
+        out.extend_from_slice(&[VERSION.0, VERSION.1]);
+        out.extend_from_slice(&0u32.to_le_bytes());
+
+        let mut creator = [0u8; 20];
+        creator[..7].copy_from_slice(b"zeerust");
+        let mut block = creator.to_vec();
+        block.extend_from_slice(&[0, 0, 0, 0]);
+        push_block(&mut out, CREATOR_BLOCK, &block);
+
+        if let Some(snapshot) = &self.snapshot {
+            let mut block = 0u32.to_le_bytes().to_vec();
+            let mut ext = [0u8; 4];
+            for (e, b) in ext.iter_mut().zip(snapshot.extension.bytes()) {
+                *e = b;
+            }
+            block.extend_from_slice(&ext);
+            block.extend_from_slice(&(snapshot.data.len() as u32).to_le_bytes());
+            block.extend_from_slice(&snapshot.data);
+            push_block(&mut out, SNAPSHOT_BLOCK, &block);
+        }
+
+        let mut block = (self.frames.len() as u32).to_le_bytes().to_vec();
+        block.push(0);
+        block.extend_from_slice(&self.tstates.to_le_bytes());
+        block.extend_from_slice(&0u32.to_le_bytes());
+        for frame in &self.frames {
+            block.extend_from_slice(&frame.fetch_count.to_le_bytes());
+            block.extend_from_slice(&(frame.inputs.len() as u16).to_le_bytes());
+            block.extend_from_slice(&frame.inputs);
+        }
+        push_block(&mut out, INPUT_BLOCK, &block);
+        out
+    }
+}
+
+// Decompress the zlib stream making up the rest of a block
+#[cfg(feature = "deflate")]
+fn inflate(data: &[u8]) -> Result<Vec<u8>, RzxError> {
+    miniz_oxide::inflate::decompress_to_vec_zlib(data).map_err(|_| RzxError::BadCompression)
+}
+
+#[cfg(not(feature = "deflate"))]
+fn inflate(_data: &[u8]) -> Result<Vec<u8>, RzxError> {
+    Err(RzxError::Compressed)
+}
+
+fn push_block(out: &mut Vec<u8>, id: u8, block: &[u8]) {
+    out.push(id);
+    out.extend_from_slice(&(block.len() as u32 + 5).to_le_bytes());
+    out.extend_from_slice(block);
+}
+
+#[derive(Default)]
+struct Playback {
+    frames: Vec<Frame>,
+    frame: usize,
+    input: usize,
+}
+
+/// RzxPlayer is an InputDevice that replays the inputs of a recording, regardless of port.
+/// Install a clone of it on every port the program reads from, and call next_frame whenever
+/// the machine's frame interrupt fires.
+#[derive(Clone)]
+pub struct RzxPlayer {
+    state: Rc<RefCell<Playback>>,
+}
+
+impl RzxPlayer {
+    pub fn new(rzx: &Rzx) -> Self {
+        Self {
+            state: Rc::new(RefCell::new(Playback {
+                frames: rzx.frames.clone(),
+                ..Default::default()
+            })),
+        }
+    }
+
+    /// Move on to the next frame of input
+    pub fn next_frame(&self) {
+        let mut state = self.state.borrow_mut();
+        state.frame += 1;
+        state.input = 0;
+    }
+
+    /// The frame currently being played back, or None if the recording is over
+    pub fn current_frame(&self) -> Option<Frame> {
+        let state = self.state.borrow();
+        state.frames.get(state.frame).cloned()
+    }
+}
+
+impl InputDevice for RzxPlayer {
+    /// Return the next recorded input
+    ///
+    /// # Panics
+    /// Panics if the program reads more inputs in a frame than were recorded
+    fn input(&self) -> u8 {
+        let mut state = self.state.borrow_mut();
+        let (frame, input) = (state.frame, state.input);
+        state.input += 1;
+        *state
+            .frames
+            .get(frame)
+            .and_then(|f| f.inputs.get(input))
+            .unwrap_or_else(|| panic!("RZX playback desynchronised in frame {}", frame))
+    }
+}
+
+/// RzxRecorder captures the inputs of a session into an RZX recording.
+/// Wrap every input device with wrap before installing it, and call end_frame at every frame
+/// interrupt.
+#[derive(Clone, Default)]
+pub struct RzxRecorder {
+    rzx: Rc<RefCell<Rzx>>,
+    current: Rc<RefCell<Vec<u8>>>,
+}
+
+impl RzxRecorder {
+    pub fn new(snapshot: Option<Snapshot>, tstates: u32) -> Self {
+        Self {
+            rzx: Rc::new(RefCell::new(Rzx {
+                snapshot,
+                tstates,
+                frames: vec![],
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// Wrap an input device so that everything read from it is recorded
+    pub fn wrap(&self, device: Box<dyn InputDevice>) -> RecordingInput {
+        RecordingInput {
+            recorder: self.clone(),
+            device,
+        }
+    }
+
+    /// Finish the current frame, which took fetch_count opcode fetches
+    pub fn end_frame(&self, fetch_count: u16) {
+        let inputs = self.current.replace(vec![]);
+        self.rzx.borrow_mut().frames.push(Frame {
+            fetch_count,
+            inputs,
+        });
+    }
+
+    /// The recording so far
+    pub fn recording(&self) -> Rzx {
+        self.rzx.borrow().clone()
+    }
+}
+
+/// An InputDevice wrapped by an RzxRecorder
+pub struct RecordingInput {
+    recorder: RzxRecorder,
+    device: Box<dyn InputDevice>,
+}
+
+impl InputDevice for RecordingInput {
+    fn input(&self) -> u8 {
+        let val = self.device.input();
+        self.recorder.current.borrow_mut().push(val);
+        val
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::z80::io::BufInput;
+
+    fn sample() -> Rzx {
+        Rzx {
+            snapshot: Some(Snapshot {
+                extension: "z80".to_string(),
+                data: vec![1, 2, 3],
+            }),
+            tstates: 1234,
+            frames: vec![
+                Frame {
+                    fetch_count: 100,
+                    inputs: vec![0xBF, 0xFF],
+                },
+                Frame {
+                    fetch_count: 50,
+                    inputs: vec![],
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let rzx = sample();
+        assert_eq!(Ok(rzx.clone()), Rzx::parse(&rzx.write()));
+    }
+
+    #[test]
+    fn repeated_inputs() {
+        let mut data = Rzx::default().write();
+        // Replace the (empty) input block with one using the repeat marker
+        let block_start = data.len() - 18;
+        data.truncate(block_start);
+        let mut block = 2u32.to_le_bytes().to_vec();
+        block.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        block.extend_from_slice(&[10, 0, 1, 0, 0x42]);
+        block.extend_from_slice(&[20, 0, 0xFF, 0xFF]);
+        push_block(&mut data, INPUT_BLOCK, &block);
+
+        let rzx = Rzx::parse(&data).unwrap();
+        assert_eq!(vec![0x42], rzx.frames[1].inputs);
+        assert_eq!(20, rzx.frames[1].fetch_count);
+    }
+
+    #[test]
+    #[cfg(feature = "deflate")]
+    fn compressed() {
+        use miniz_oxide::deflate::compress_to_vec_zlib;
+        let mut data = Rzx::default().write();
+        data.truncate(data.len() - 18);
+
+        let mut block = COMPRESSED.to_le_bytes().to_vec();
+        block.extend_from_slice(b"sna\0");
+        block.extend_from_slice(&3u32.to_le_bytes());
+        block.extend_from_slice(&compress_to_vec_zlib(&[1, 2, 3], 6));
+        push_block(&mut data, SNAPSHOT_BLOCK, &block);
+
+        let mut block = 1u32.to_le_bytes().to_vec();
+        block.extend_from_slice(&[0, 0, 0, 0, 0]);
+        block.extend_from_slice(&COMPRESSED.to_le_bytes());
+        block.extend_from_slice(&compress_to_vec_zlib(&[10, 0, 2, 0, 0x42, 0x43], 6));
+        push_block(&mut data, INPUT_BLOCK, &block);
+
+        let rzx = Rzx::parse(&data).unwrap();
+        assert_eq!(vec![1, 2, 3], rzx.snapshot.unwrap().data);
+        assert_eq!(vec![0x42, 0x43], rzx.frames[0].inputs);
+
+        let corrupt = data.len() - 2;
+        data[corrupt] ^= 0xFF;
+        assert_eq!(Err(RzxError::BadCompression), Rzx::parse(&data));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(Err(RzxError::BadSignature), Rzx::parse(b"ZXR!"));
+        let data = sample().write();
+        assert_eq!(
+            Err(RzxError::Truncated),
+            Rzx::parse(&data[..data.len() - 1])
+        );
+    }
+
+    #[test]
+    fn playback() {
+        let player = RzxPlayer::new(&sample());
+        assert_eq!(0xBF, player.input());
+        assert_eq!(0xFF, player.input());
+        player.next_frame();
+        assert_eq!(50, player.current_frame().unwrap().fetch_count);
+        player.next_frame();
+        assert_eq!(None, player.current_frame());
+    }
+
+    #[test]
+    #[should_panic(expected = "desynchronised in frame 1")]
+    fn playback_desync() {
+        let player = RzxPlayer::new(&sample());
+        player.next_frame();
+        player.input();
+    }
+
+    #[test]
+    fn record() {
+        let recorder = RzxRecorder::new(None, 0);
+        let keys = recorder.wrap(Box::new(BufInput::new(vec![3, 2, 1])));
+        assert_eq!(1, keys.input());
+        recorder.end_frame(10);
+        assert_eq!(2, keys.input());
+        assert_eq!(3, keys.input());
+        recorder.end_frame(20);
+
+        let rzx = recorder.recording();
+        assert_eq!(vec![1], rzx.frames[0].inputs);
+        assert_eq!(vec![2, 3], rzx.frames[1].inputs);
+        assert_eq!(Ok(rzx.clone()), Rzx::parse(&rzx.write()));
+    }
+}
@@ -0,0 +1,220 @@
+//! Running several processors side by side.
+//! Arcade boards often pair a main CPU with a sound CPU, each on its own clock, sharing some
+//! memory or a latch. A Scheduler owns every processor and runs them in interleaved slices, so
+//! that none gets more than a slice ahead of the others. To share memory or ports between
+//! processors, give each a Bus (or devices) built around the same shared state.
+//! Other chips with their own clocks, such as sound generators, can be ticked alongside as
+//! Coprocessors.
+use crate::z80::Z80;
+
+/// Identifies a processor added to a Scheduler
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct CpuId(usize);
+
+/// A chip with its own clock that runs alongside the processors: a sound generator, a blitter...
+pub trait Coprocessor {
+    /// Run for the given number of the coprocessor's own clock cycles
+    fn tick(&self, cycles: u64);
+}
+
+/// Identifies a coprocessor added to a Scheduler
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct CoprocessorId(usize);
+
+struct Chip {
+    device: Box<dyn Coprocessor>,
+    clock: u32,
+    // Cycles ticked so far
+    ticked: u64,
+}
+
+struct Cpu {
+    z80: Z80,
+    clock: u32,
+    // T-states the processor had already run when it was added
+    start: u64,
+}
+
+/// Runs any number of processors in lockstep.
+/// Time is measured in T-states of the first processor added, the main CPU.
+pub struct Scheduler {
+    slice: u32,
+    elapsed: u64,
+    cpus: Vec<Cpu>,
+    chips: Vec<Chip>,
+}
+
+impl Scheduler {
+    /// Create a scheduler that interleaves processors every `slice` T-states of the main CPU.
+    /// Smaller slices keep processors closer together, at some cost in speed.
+    pub fn new(slice: u32) -> Self {
+        assert!(slice > 0, "slice must be positive");
+        Self {
+            slice,
+            elapsed: 0,
+            cpus: vec![],
+            chips: vec![],
+        }
+    }
+
+    /// Add a processor running at `clock` Hz. The first processor added is the main CPU.
+    pub fn add_cpu(&mut self, z80: Z80, clock: u32) -> CpuId {
+        assert!(clock > 0, "clock must be positive");
+        let start = z80.tstates();
+        self.cpus.push(Cpu { z80, clock, start });
+        CpuId(self.cpus.len() - 1)
+    }
+
+    /// Add a coprocessor running at `clock` Hz.
+    /// It is ticked at the end of every slice, by however many of its cycles have passed.
+    pub fn add_coprocessor(&mut self, device: Box<dyn Coprocessor>, clock: u32) -> CoprocessorId {
+        assert!(clock > 0, "clock must be positive");
+        self.chips.push(Chip {
+            device,
+            clock,
+            ticked: 0,
+        });
+        CoprocessorId(self.chips.len() - 1)
+    }
+
+    /// The number of cycles a coprocessor has been ticked for so far
+    pub fn coprocessor_cycles(&self, id: CoprocessorId) -> u64 {
+        self.chips[id.0].ticked
+    }
+
+    pub fn cpu(&self, id: CpuId) -> &Z80 {
+        &self.cpus[id.0].z80
+    }
+
+    pub fn cpu_mut(&mut self, id: CpuId) -> &mut Z80 {
+        &mut self.cpus[id.0].z80
+    }
+
+    /// The time run so far, in T-states of the main CPU
+    pub fn elapsed(&self) -> u64 {
+        self.elapsed
+    }
+
+    /// Run every processor for the given number of T-states of the main CPU.
+    /// Each processor runs whole instructions, so may finish a few T-states past its target;
+    /// the overshoot is taken out of its next slice.
+    pub fn run_for(&mut self, tstates: u64) {
+        let end = self.elapsed + tstates;
+        while self.elapsed < end {
+            self.elapsed = end.min(self.elapsed + u64::from(self.slice));
+            self.run_slice();
+        }
+    }
+
+    fn run_slice(&mut self) {
+        let main_clock = match self.cpus.first() {
+            Some(cpu) => cpu.clock,
+            None => return,
+        };
+        for cpu in self.cpus.iter_mut() {
+            let target = scale(self.elapsed, cpu.clock, main_clock);
+            while cpu.z80.tstates() - cpu.start < target {
+                cpu.z80.step();
+            }
+        }
+        for chip in self.chips.iter_mut() {
+            // Counted from the start, so that rounding never accumulates
+            let target = scale(self.elapsed, chip.clock, main_clock);
+            if target > chip.ticked {
+                chip.device.tick(target - chip.ticked);
+                chip.ticked = target;
+            }
+        }
+    }
+}
+
+// Convert T-states at one clock rate to T-states at another
+fn scale(tstates: u64, to: u32, from: u32) -> u64 {
+    (u128::from(tstates) * u128::from(to) / u128::from(from)) as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::FlatRam;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn looping() -> Z80 {
+        let mut z80 = Z80::default();
+        // JR -2, forever. 12 T-states a go
+        z80.load(&[0x18, 0xFE]);
+        z80
+    }
+
+    #[test]
+    fn clock_ratio() {
+        let mut scheduler = Scheduler::new(100);
+        let main = scheduler.add_cpu(looping(), 4_000_000);
+        let sound = scheduler.add_cpu(looping(), 2_000_000);
+        scheduler.run_for(1000);
+        assert_eq!(1000, scheduler.elapsed());
+        // 84 jumps is 1008 T-states, and 42 is 504
+        assert_eq!(1008, scheduler.cpu(main).tstates());
+        assert_eq!(504, scheduler.cpu(sound).tstates());
+
+        // No drift over time
+        scheduler.run_for(999_000);
+        assert!(scheduler.cpu(main).tstates() - 1_000_000 < 12);
+        assert!(scheduler.cpu(sound).tstates() - 500_000 < 12);
+    }
+
+    // Remembers every tick
+    #[derive(Clone, Default)]
+    struct Ticks(Rc<RefCell<Vec<u64>>>);
+
+    impl Coprocessor for Ticks {
+        fn tick(&self, cycles: u64) {
+            self.0.borrow_mut().push(cycles);
+        }
+    }
+
+    #[test]
+    fn coprocessor() {
+        let mut scheduler = Scheduler::new(100);
+        scheduler.add_cpu(looping(), 3_500_000);
+        let ticks = Ticks::default();
+        // An AY chip at half the CPU clock, and something at a third of it
+        let ay = scheduler.add_coprocessor(Box::new(ticks.clone()), 1_750_000);
+        let third = scheduler.add_coprocessor(Box::new(Ticks::default()), 1_166_666);
+        scheduler.run_for(250);
+        assert_eq!(vec![50, 50, 25], *ticks.0.borrow());
+        assert_eq!(125, scheduler.coprocessor_cycles(ay));
+
+        scheduler.run_for(3_499_750);
+        assert_eq!(1_750_000, scheduler.coprocessor_cycles(ay));
+        assert_eq!(1_166_666, scheduler.coprocessor_cycles(third));
+    }
+
+    #[test]
+    fn shared_memory() {
+        // Two processors sharing 64K of RAM
+        let ram = FlatRam::new();
+        // Main CPU at 0x0000: LD A, 0x99; LD (0x8000), A; HALT
+        ram.load(0x0000, &[0x3E, 0x99, 0x32, 0x00, 0x80, 0x76]);
+        // Sound CPU at 0x1000: LD A, (0x8000); OR A; JR Z, -6; HALT
+        ram.load(0x1000, &[0x3A, 0x00, 0x80, 0xB7, 0x28, 0xFA, 0x76]);
+
+        let mut main = Z80::default();
+        main.set_bus(Box::new(ram.clone()));
+        let mut sound = Z80::default();
+        sound.set_bus(Box::new(ram.clone()));
+        sound.registers.set_pc(0x1000);
+
+        let mut scheduler = Scheduler::new(10);
+        let main = scheduler.add_cpu(main, 4_000_000);
+        let sound = scheduler.add_cpu(sound, 4_000_000);
+        scheduler.run_for(200);
+        assert!(scheduler.cpu(main).is_halted());
+        assert!(scheduler.cpu(sound).is_halted());
+        assert_eq!(
+            0x99,
+            scheduler.cpu(sound).registers.get_reg8(crate::ops::Reg8::A)
+        );
+    }
+}
@@ -0,0 +1,225 @@
+//! Screenshots. The Spectrum's screen can be saved and loaded as a .SCR file (the 6912 bytes
+//! of display memory at 0x4000: the bitmap, then the attributes), and drawn as a picture.
+//! With the `png` feature, any picture can be saved as a PNG, for looking at, or comparing
+//! against in tests. The PNG is uncompressed, to keep the encoder small and dependency free.
+use crate::machine::Framebuffer;
+use crate::z80::Z80;
+
+/// The size of a .SCR file
+pub const SCR_SIZE: usize = 6912;
+/// Where the Spectrum's display memory starts
+pub const SCREEN_ADDR: u16 = 0x4000;
+/// The size of the Spectrum's picture, without the border
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 192;
+
+const BITMAP_SIZE: usize = 6144;
+
+/// The Spectrum's colours as red, green and blue: 0 to 7, then 0 to 7 bright
+pub const PALETTE: [[u8; 3]; 16] = [
+    [0x00, 0x00, 0x00],
+    [0x00, 0x00, 0xD7],
+    [0xD7, 0x00, 0x00],
+    [0xD7, 0x00, 0xD7],
+    [0x00, 0xD7, 0x00],
+    [0x00, 0xD7, 0xD7],
+    [0xD7, 0xD7, 0x00],
+    [0xD7, 0xD7, 0xD7],
+    [0x00, 0x00, 0x00],
+    [0x00, 0x00, 0xFF],
+    [0xFF, 0x00, 0x00],
+    [0xFF, 0x00, 0xFF],
+    [0x00, 0xFF, 0x00],
+    [0x00, 0xFF, 0xFF],
+    [0xFF, 0xFF, 0x00],
+    [0xFF, 0xFF, 0xFF],
+];
+
+/// Draw a .SCR file. `flash` is whether flashing characters are in their swapped phase, which
+/// the Spectrum changes every 16 frames.
+///
+/// # Panics
+/// Panics if `scr` is shorter than SCR_SIZE
+pub fn render_scr(scr: &[u8], flash: bool) -> Framebuffer {
+    assert!(scr.len() >= SCR_SIZE, "a SCR file is {} bytes", SCR_SIZE);
+    let mut indices = Vec::with_capacity(WIDTH * HEIGHT);
+    for y in 0..HEIGHT {
+        // The rows of each third of the screen are interleaved by character row
+        let row = (y & 0xC0) << 5 | (y & 0x07) << 8 | (y & 0x38) << 2;
+        for x in 0..WIDTH / 8 {
+            let pixels = scr[row | x];
+            let attr = scr[BITMAP_SIZE + y / 8 * 32 + x];
+            let bright = (attr & 0x40) >> 3;
+            let (mut ink, mut paper) = (attr & 0x07 | bright, (attr >> 3) & 0x07 | bright);
+            if flash && attr & 0x80 != 0 {
+                std::mem::swap(&mut ink, &mut paper);
+            }
+            indices.extend((0..8).rev().map(
+                |bit| {
+                    if pixels & (1 << bit) != 0 {
+                        ink
+                    } else {
+                        paper
+                    }
+                },
+            ));
+        }
+    }
+    Framebuffer::from_indexed(WIDTH, HEIGHT, &indices, &PALETTE)
+}
+
+impl Z80 {
+    /// The Spectrum's display memory, as a .SCR file. The screen is above the processor's own
+    /// memory, so this needs a bus, and returns None without one. For example:
+    /// ```
+    /// use zeerust::screenshot;
+    /// # use zeerust::testing::FlatRam;
+    /// # use zeerust::z80::Z80;
+    /// # let mut z80 = Z80::default();
+    /// # z80.set_bus(Box::new(FlatRam::new()));
+    ///
+    /// // A Spectrum, with a bus: LD A, 0xFF; LD (0x4000), A; HALT
+    /// z80.load(&[0x3E, 0xFF, 0x32, 0x00, 0x40, 0x76]);
+    /// z80.run();
+    /// let scr = z80.save_scr().unwrap();
+    /// assert_eq!(screenshot::SCR_SIZE, scr.len());
+    /// let picture = screenshot::render_scr(&scr, false);
+    /// // Ink 0 on paper 0 is black either way; the attributes are all 0
+    /// assert_eq!([0, 0, 0], picture.pixels[0]);
+    ///```
+    pub fn save_scr(&self) -> Option<Vec<u8>> {
+        (0..SCR_SIZE as u16)
+            .map(|i| self.peek(SCREEN_ADDR + i))
+            .collect()
+    }
+
+    /// Put a .SCR file on the screen. Returns false if there's nowhere to put it (there's no
+    /// bus) or the file is the wrong size.
+    pub fn load_scr(&mut self, scr: &[u8]) -> bool {
+        scr.len() == SCR_SIZE
+            && scr
+                .iter()
+                .enumerate()
+                .all(|(i, b)| self.poke(SCREEN_ADDR + i as u16, *b))
+    }
+}
+
+#[cfg(feature = "png")]
+impl Framebuffer {
+    /// The picture as a PNG file: 8 bit RGB, unfiltered and uncompressed
+    pub fn to_png(&self) -> Vec<u8> {
+        let mut raw = Vec::with_capacity((self.width * 3 + 1) * self.height);
+        for row in self.pixels.chunks(self.width.max(1)).take(self.height) {
+            raw.push(0);
+            row.iter().for_each(|rgb| raw.extend_from_slice(rgb));
+        }
+
+        // A zlib stream of stored deflate blocks
+        let mut zlib = vec![0x78, 0x01];
+        let mut blocks = raw.chunks(0xFFFF).peekable();
+        if blocks.peek().is_none() {
+            zlib.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+        }
+        while let Some(block) = blocks.next() {
+            zlib.push(u8::from(blocks.peek().is_none()));
+            let len = block.len() as u16;
+            zlib.extend_from_slice(&len.to_le_bytes());
+            zlib.extend_from_slice(&(!len).to_le_bytes());
+            zlib.extend_from_slice(block);
+        }
+        zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+        let mut header = vec![];
+        header.extend_from_slice(&(self.width as u32).to_be_bytes());
+        header.extend_from_slice(&(self.height as u32).to_be_bytes());
+        // 8 bits per sample, RGB, and the only compression, filtering and interlacing
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        for (kind, data) in [(b"IHDR", &header), (b"IDAT", &zlib), (b"IEND", &vec![])] {
+            png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            let start = png.len();
+            png.extend_from_slice(kind);
+            png.extend_from_slice(data);
+            let crc = crc32(&png[start..]);
+            png.extend_from_slice(&crc.to_be_bytes());
+        }
+        png
+    }
+}
+
+#[cfg(feature = "png")]
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, b| {
+        (0..8).fold(crc ^ u32::from(*b), |crc, _| {
+            if crc & 1 != 0 {
+                crc >> 1 ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+#[cfg(feature = "png")]
+fn adler32(data: &[u8]) -> u32 {
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), byte| {
+        let a = (a + u32::from(*byte)) % 65521;
+        (a, (b + a) % 65521)
+    });
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scr() {
+        let mut scr = vec![0; SCR_SIZE];
+        // The second pixel row of the screen is 256 bytes on, and the second third 2K
+        scr[0x0100] = 0b1000_0001;
+        scr[0x0800] = 0b1000_0000;
+        // Bright red ink on blue paper, flashing; and plain white on black
+        scr[BITMAP_SIZE] = 0b1100_1010;
+        scr[BITMAP_SIZE + 8 * 32] = 0b0000_0111;
+        let picture = render_scr(&scr, false);
+        assert_eq!((WIDTH, HEIGHT), (picture.width, picture.height));
+        let at = |picture: &Framebuffer, x: usize, y: usize| picture.pixels[y * WIDTH + x];
+        assert_eq!(PALETTE[10], at(&picture, 0, 1));
+        assert_eq!(PALETTE[9], at(&picture, 1, 1));
+        assert_eq!(PALETTE[10], at(&picture, 7, 1));
+        assert_eq!(PALETTE[9], at(&picture, 0, 0));
+        assert_eq!(PALETTE[7], at(&picture, 0, 64));
+        assert_eq!(PALETTE[0], at(&picture, 1, 64));
+        let flashed = render_scr(&scr, true);
+        assert_eq!(PALETTE[9], at(&flashed, 0, 1));
+        assert_eq!(PALETTE[7], at(&flashed, 0, 64));
+
+        // There's no screen without a bus
+        let mut z80 = Z80::default();
+        assert_eq!(None, z80.save_scr());
+        assert!(!z80.load_scr(&scr));
+    }
+
+    #[cfg(feature = "png")]
+    #[test]
+    fn png() {
+        let picture = Framebuffer {
+            width: 2,
+            height: 1,
+            pixels: vec![[0xFF, 0, 0], [0, 0, 0xFF]],
+        };
+        let png = picture.to_png();
+        assert_eq!(b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR", &png[..16]);
+        assert_eq!(b"\0\0\0\x02\0\0\0\x01\x08\x02\0\0\0", &png[16..29]);
+        // The well known CRC of an empty IEND chunk
+        assert_eq!(b"IEND\xae\x42\x60\x82", &png[png.len() - 8..]);
+        // One stored block: the filter byte, then the pixels
+        assert_eq!(
+            &[0x78, 0x01, 1, 7, 0, 0xF8, 0xFF, 0, 0xFF, 0, 0, 0, 0, 0xFF],
+            &png[41..55]
+        );
+        assert_eq!(0x11E6_0398, adler32(b"Wikipedia"));
+    }
+}
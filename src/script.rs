@@ -0,0 +1,259 @@
+//! Driving a machine from a script, with the `script` feature. Scripts are Rhai
+//! (<https://rhai.rs>), and get these functions on top of the language's own:
+//!
+//! * `run(FRAMES)`: run the machine for some frames
+//! * `step()`, `step(COUNT)`: execute single instructions, outside of any frame's timing
+//! * `peek(ADDRESS)`, `poke(ADDRESS, VALUE)`: read and write memory as the processor sees it
+//! * `value(EXPRESSION)`: a register or sum, as the debugger takes them (`"hl+2"`)
+//! * `frame()`: the number of frames run so far
+//! * `pixel(X, Y)`: the colour of a pixel of the picture, as 0xRRGGBB
+//! * `key(ROW, COLUMN, PRESSED)`, `keypress(ROW, COLUMN)`: press or release a key, or press
+//!   it for a few frames and let go, given a keyboard with `on_key`
+//! * `assert(CONDITION, MESSAGE)`: stop the script with a failure unless the condition holds
+//!
+//! So a test that boots, types and checks the screen needs no Rust recompiled:
+//! ```
+//! use zeerust::machine::homebrew::Homebrew;
+//! use zeerust::script::Script;
+//!
+//! // LD A, 42; LD (0x2000), A; loop: JR loop
+//! let mut script = Script::new(Homebrew::new(&[0x3E, 0x2A, 0x32, 0x00, 0x20, 0x18, 0xFE]));
+//! script.run("run(2); assert(peek(0x2000) == 42, \"not stored\"); poke(0x2001, value(\"a\") + 1);").unwrap();
+//! assert_eq!(Some(43), script.machine().borrow_mut().z80.peek(0x2001));
+//! let failed = script.run("assert(frame() == 0, \"already ran\")").unwrap_err();
+//! assert!(failed.to_string().contains("already ran"));
+//!```
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
+
+use rhai::{Engine, EvalAltResult, INT};
+
+use crate::debug::expression::evaluate;
+use crate::debug::symbols::Symbols;
+use crate::machine::Machine;
+
+// How long `keypress` holds a key down, and then waits after letting go, for the machine to
+// scan its keyboard
+const KEY_FRAMES: INT = 3;
+
+type KeyHandler<M> = Box<dyn FnMut(&mut M, INT, INT, bool)>;
+
+/// What went wrong with a script: where, and why
+#[derive(Debug, PartialEq)]
+pub struct ScriptError(pub String);
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "script failed: {}", self.0)
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+/// A machine, and a scripting engine to drive it with
+pub struct Script<M: Machine + 'static> {
+    engine: Engine,
+    machine: Rc<RefCell<M>>,
+    on_key: Rc<RefCell<Option<KeyHandler<M>>>>,
+}
+
+// Script functions fail with a message
+fn fail<T>(message: String) -> Result<T, Box<EvalAltResult>> {
+    Err(message.into())
+}
+
+fn address(addr: INT) -> Result<u16, Box<EvalAltResult>> {
+    u16::try_from(addr).or_else(|_| fail(format!("{} is not an address", addr)))
+}
+
+impl<M: Machine + 'static> Script<M> {
+    pub fn new(machine: M) -> Self {
+        let mut script = Self {
+            engine: Engine::new(),
+            machine: Rc::new(RefCell::new(machine)),
+            on_key: Rc::new(RefCell::new(None)),
+        };
+        script.register();
+        script
+    }
+
+    fn register(&mut self) {
+        let engine = &mut self.engine;
+        let m = self.machine.clone();
+        engine.register_fn("run", move |frames: INT| {
+            for _ in 0..frames {
+                m.borrow_mut().run_frame();
+            }
+        });
+        let m = self.machine.clone();
+        engine.register_fn("step", move || m.borrow_mut().z80().step());
+        let m = self.machine.clone();
+        engine.register_fn("step", move |count: INT| {
+            for _ in 0..count {
+                m.borrow_mut().z80().step();
+            }
+        });
+        let m = self.machine.clone();
+        engine.register_fn(
+            "peek",
+            move |addr: INT| -> Result<INT, Box<EvalAltResult>> {
+                let addr = address(addr)?;
+                match m.borrow_mut().z80().peek(addr) {
+                    Some(val) => Ok(INT::from(val)),
+                    None => fail(format!("nothing at {:04x}", addr)),
+                }
+            },
+        );
+        let m = self.machine.clone();
+        engine.register_fn(
+            "poke",
+            move |addr: INT, val: INT| -> Result<(), Box<EvalAltResult>> {
+                let addr = address(addr)?;
+                let val = u8::try_from(val).or_else(|_| fail(format!("{} is not a byte", val)))?;
+                if m.borrow_mut().z80().poke(addr, val) {
+                    Ok(())
+                } else {
+                    fail(format!("nothing at {:04x}", addr))
+                }
+            },
+        );
+        let m = self.machine.clone();
+        engine.register_fn(
+            "value",
+            move |expression: &str| -> Result<INT, Box<EvalAltResult>> {
+                let mut machine = m.borrow_mut();
+                match evaluate(expression, machine.z80(), &Symbols::default()) {
+                    Ok(value) => Ok(INT::from(value)),
+                    Err(e) => fail(e.to_string()),
+                }
+            },
+        );
+        let m = self.machine.clone();
+        engine.register_fn("frame", move || m.borrow().frame() as INT);
+        let m = self.machine.clone();
+        engine.register_fn(
+            "pixel",
+            move |x: INT, y: INT| -> Result<INT, Box<EvalAltResult>> {
+                let picture = m.borrow().framebuffer();
+                let inside = |n: INT, size: usize| usize::try_from(n).ok().filter(|n| *n < size);
+                match (inside(x, picture.width), inside(y, picture.height)) {
+                    (Some(x), Some(y)) => {
+                        let [r, g, b] = picture.pixels[y * picture.width + x];
+                        Ok(INT::from(u32::from_be_bytes([0, r, g, b])))
+                    }
+                    _ => fail(format!("({}, {}) is off the picture", x, y)),
+                }
+            },
+        );
+        let (m, keys) = (self.machine.clone(), self.on_key.clone());
+        engine.register_fn("key", move |row: INT, col: INT, pressed: bool| {
+            press(&m, &keys, row, col, pressed)
+        });
+        let (m, keys) = (self.machine.clone(), self.on_key.clone());
+        engine.register_fn(
+            "keypress",
+            move |row: INT, col: INT| -> Result<(), Box<EvalAltResult>> {
+                for pressed in [true, false] {
+                    press(&m, &keys, row, col, pressed)?;
+                    for _ in 0..KEY_FRAMES {
+                        m.borrow_mut().run_frame();
+                    }
+                }
+                Ok(())
+            },
+        );
+        engine.register_fn("assert", |condition: bool, message: &str| {
+            if condition {
+                Ok(())
+            } else {
+                fail(format!("assertion failed: {}", message))
+            }
+        });
+    }
+
+    /// How the script's `key` and `keypress` press keys on this machine. For example, on a CPC:
+    /// `script.on_key(Box::new(|cpc, row, col, pressed| cpc.set_key(row as usize, col as u8, pressed)))`
+    pub fn on_key(&mut self, handler: KeyHandler<M>) {
+        *self.on_key.borrow_mut() = Some(handler);
+    }
+
+    /// Run a script
+    pub fn run(&mut self, source: &str) -> Result<(), ScriptError> {
+        self.engine
+            .run(source)
+            .map_err(|e| ScriptError(e.to_string()))
+    }
+
+    /// The machine, to look at or change between scripts
+    pub fn machine(&self) -> Rc<RefCell<M>> {
+        self.machine.clone()
+    }
+
+    /// The engine, to add functions of your own for scripts to call
+    pub fn engine(&mut self) -> &mut Engine {
+        &mut self.engine
+    }
+}
+
+fn press<M: Machine>(
+    machine: &RefCell<M>,
+    keys: &RefCell<Option<KeyHandler<M>>>,
+    row: INT,
+    col: INT,
+    pressed: bool,
+) -> Result<(), Box<EvalAltResult>> {
+    match &mut *keys.borrow_mut() {
+        Some(handler) => {
+            handler(&mut machine.borrow_mut(), row, col, pressed);
+            Ok(())
+        }
+        None => fail("there's no keyboard; see Script::on_key".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::machine::invaders::{Input, SpaceInvaders};
+
+    #[test]
+    fn functions() {
+        // Copy the input port to the top left of the screen, forever:
+        // loop: IN A, (1); LD (0x2400 + 31), A; JP loop
+        let rom = [0xDB, 0x01, 0x32, 0x1F, 0x24, 0xC3, 0x00, 0x00];
+        let mut script = Script::new(SpaceInvaders::new(&rom));
+        script.on_key(Box::new(|machine, _row, _col, pressed| {
+            machine.set_input(Input::P1Start, pressed)
+        }));
+        script
+            .run(
+                r#"
+                step(3);
+                assert(value("pc") == 0, "looped");
+                key(0, 0, true);
+                run(1);
+                // The byte is the left column's top eight pixels, bit 7 first, and start is bit 2
+                assert(pixel(0, 5) == 0xFFFFFF, "lit");
+                assert(pixel(0, 0) == 0, "dark");
+                keypress(0, 0);
+                assert(frame() == 7, "frames");
+                poke(0x2000, 0x12);
+                assert(peek(0x2000) == 0x12, "poked");
+                "#,
+            )
+            .unwrap();
+
+        let mut error = |source: &str| script.run(source).unwrap_err().0;
+        assert!(error("peek(0x10000)").contains("65536 is not an address"));
+        assert!(error("pixel(0, 256)").contains("(0, 256) is off the picture"));
+        assert!(error("assert(peek(0x2000) == 1, \"poked\")").contains("assertion failed: poked"));
+        assert!(error("value(\"nope\")").contains("nope"));
+        assert!(Script::new(SpaceInvaders::new(&rom))
+            .run("keypress(0, 0)")
+            .unwrap_err()
+            .0
+            .contains("no keyboard"));
+    }
+}
@@ -0,0 +1,187 @@
+//! Running small programs in tests, for crates that want to check what a Z80 program does
+//! without setting up a processor and its devices each time.
+//!
+//! run_program loads the program at 0x0000, feeds it input on port 0x00 and collects what it
+//! writes to port 0x00, as the examples do. By convention a program exits with
+//! `OUT (0xFF), A`, the code in A; a program that halts instead exits with 0.
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use crate::z80::bus::Bus;
+use crate::z80::io::{BufOutput, InputDevice, OutputDevice};
+use crate::z80::Z80;
+
+/// The port for input and output
+pub const IO_PORT: u8 = 0x00;
+/// The port a program writes its exit code to
+pub const EXIT_PORT: u8 = 0xFF;
+/// How long a program may run for before it's taken to be stuck
+pub const MAX_TSTATES: u64 = 100_000_000;
+
+/// What a program did
+#[derive(Debug, PartialEq, Clone)]
+pub struct ProgramResult {
+    /// Everything written to the output port, in order
+    pub outputs: Vec<u8>,
+    pub exit_code: u8,
+    /// The T-states it ran for
+    pub cycles: u64,
+}
+
+// Gives the inputs in order, then 0xFF as an empty bus would
+struct Inputs(RefCell<VecDeque<u8>>);
+
+impl InputDevice for Inputs {
+    fn input(&self) -> u8 {
+        self.0.borrow_mut().pop_front().unwrap_or(0xFF)
+    }
+}
+
+struct Exit(Rc<Cell<Option<u8>>>);
+
+impl OutputDevice for Exit {
+    fn output(&self, val: u8) {
+        self.0.set(Some(val));
+    }
+}
+
+/// Run a program until it exits, with `inputs` to read. For example:
+/// ```
+/// use zeerust::testing::run_program;
+///
+/// // IN A, (0); INC A; OUT (0), A; OUT (0xFF), A
+/// let result = run_program(&[0xDB, 0x00, 0x3C, 0xD3, 0x00, 0xD3, 0xFF], &[41]);
+/// assert_eq!((vec![42], 42), (result.outputs, result.exit_code));
+///```
+///
+/// # Panics
+/// Panics if the program runs for more than MAX_TSTATES without exiting
+pub fn run_program(program: &[u8], inputs: &[u8]) -> ProgramResult {
+    run_program_for(program, inputs, MAX_TSTATES)
+}
+
+/// Run a program as run_program does, taking it to be stuck after `limit` T-states
+pub fn run_program_for(program: &[u8], inputs: &[u8], limit: u64) -> ProgramResult {
+    let mut z80 = Z80::default();
+    let output = BufOutput::default();
+    let exit = Rc::new(Cell::new(None));
+    z80.install_input(
+        IO_PORT,
+        Box::new(Inputs(RefCell::new(inputs.to_vec().into()))),
+    );
+    z80.install_output(IO_PORT, Box::new(output.clone()));
+    z80.install_output(EXIT_PORT, Box::new(Exit(exit.clone())));
+    z80.load(program);
+
+    while exit.get().is_none() && !z80.is_halted() {
+        assert!(
+            z80.tstates() <= limit,
+            "the program ran for more than {} T-states without exiting, and is at 0x{:04x}",
+            limit,
+            z80.registers.get_pc()
+        );
+        z80.step();
+    }
+    ProgramResult {
+        outputs: output.result(),
+        exit_code: exit.get().unwrap_or(0),
+        cycles: z80.tstates(),
+    }
+}
+
+/// 64K of RAM on a bus, for tests that need the whole address space rather than the
+/// processor's own memory. Reading a port gives the low byte of its address, and writes to ports
+/// are kept in order. Clones share everything, so keep one to see what the processor did:
+/// ```
+/// use zeerust::testing::FlatRam;
+/// use zeerust::z80::Z80;
+///
+/// let ram = FlatRam::new();
+/// // IN A, (0x42); LD (0xC000), A; OUT (0x07), A; HALT
+/// ram.load(0x8000, &[0xDB, 0x42, 0x32, 0x00, 0xC0, 0xD3, 0x07, 0x76]);
+/// let mut z80 = Z80::default();
+/// z80.set_bus(Box::new(ram.clone()));
+/// z80.registers.set_pc(0x8000);
+/// z80.run();
+/// assert_eq!(0x42, ram.peek(0xC000));
+/// assert_eq!(vec![(0x4207, 0x42)], ram.outputs());
+///```
+#[derive(Clone)]
+pub struct FlatRam {
+    memory: Rc<RefCell<Vec<u8>>>,
+    outputs: Rc<RefCell<Vec<(u16, u8)>>>,
+}
+
+impl Default for FlatRam {
+    fn default() -> Self {
+        Self {
+            memory: Rc::new(RefCell::new(vec![0; 0x10000])),
+            outputs: Rc::default(),
+        }
+    }
+}
+
+impl FlatRam {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Copy bytes into memory, starting at addr
+    pub fn load(&self, addr: u16, bytes: &[u8]) {
+        let addr = usize::from(addr);
+        self.memory.borrow_mut()[addr..addr + bytes.len()].copy_from_slice(bytes);
+    }
+
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.memory.borrow()[usize::from(addr)]
+    }
+
+    /// Everything written to the ports, as (port, value)
+    pub fn outputs(&self) -> Vec<(u16, u8)> {
+        self.outputs.borrow().clone()
+    }
+}
+
+impl Bus for FlatRam {
+    fn mem_read(&self, addr: u16) -> u8 {
+        self.peek(addr)
+    }
+
+    fn mem_write(&self, addr: u16, val: u8) {
+        self.memory.borrow_mut()[usize::from(addr)] = val;
+    }
+
+    fn io_read(&self, port: u16) -> u8 {
+        port as u8
+    }
+
+    fn io_write(&self, port: u16, val: u8) {
+        self.outputs.borrow_mut().push((port, val));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::examples::HELLO_WORLD_BIN;
+
+    #[test]
+    fn programs() {
+        let result = run_program(HELLO_WORLD_BIN, &[]);
+        assert_eq!(b"Hello World\n".to_vec(), result.outputs);
+        assert_eq!(0, result.exit_code);
+
+        // IN A, (0) twice, then exit with the second: past the inputs, it reads 0xFF
+        let echo = [0xDB, 0x00, 0xDB, 0x00, 0xD3, 0xFF];
+        assert_eq!(0xFF, run_program(&echo, &[1]).exit_code);
+        let result = run_program(&echo, &[1, 2, 3]);
+        assert_eq!((2, 11 + 11 + 11), (result.exit_code, result.cycles));
+    }
+
+    #[test]
+    #[should_panic(expected = "without exiting, and is at 0x0000")]
+    fn stuck() {
+        run_program_for(&[0x18, 0xFE], &[], 10_000); // JR -2
+    }
+}
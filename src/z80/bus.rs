@@ -0,0 +1,43 @@
+//! A single object owning the whole address decode.
+//! By default the processor reads and writes its own `memory`, and routes port accesses through
+//! a PortDecoder. Some systems are easier to describe as one bus that sees every access, memory
+//! and I/O alike: memory-mapped peripherals, banking, mirrored ROMs and so on.
+use super::Z80;
+
+/// A Bus receives every memory and I/O access the processor makes
+pub trait Bus {
+    /// Read a byte from memory
+    fn mem_read(&self, addr: u16) -> u8;
+    /// Write a byte to memory
+    fn mem_write(&self, addr: u16, val: u8);
+    /// Read from a peripheral, given the full 16-bit port address
+    fn io_read(&self, port: u16) -> u8;
+    /// Write to a peripheral, given the full 16-bit port address
+    fn io_write(&self, port: u16, val: u8);
+
+    /// The byte put on the data bus during an interrupt acknowledge cycle.
+    /// See InputDevice::acknowledge.
+    fn acknowledge(&self) -> u8 {
+        0xFF
+    }
+
+    /// Whether a different bank (of ROM or RAM) has been paged in since this was last asked,
+    /// which a bus with banking should answer. The processor asks after every instruction, and
+    /// reapplies its cheats if so.
+    fn take_bank_switch(&self) -> bool {
+        false
+    }
+}
+
+impl Z80 {
+    /// Hand every memory and I/O access to the given bus, replacing any bus already set.
+    /// While a bus is set, `memory` and the installed devices are left alone.
+    pub fn set_bus(&mut self, bus: Box<dyn Bus>) {
+        self.bus = Some(bus);
+    }
+
+    /// Go back to using `memory` and the installed devices, returning the bus if one was set
+    pub fn clear_bus(&mut self) -> Option<Box<dyn Bus>> {
+        self.bus.take()
+    }
+}
@@ -0,0 +1,187 @@
+//! Cheats: pokes and code patches that stay applied.
+//! Trainers, and tests that want to skip copy-protection delays, patch a few bytes of a program.
+//! Those bytes are lost whenever memory is reloaded or a different bank is paged in, so the
+//! processor remembers them, and reapplies them after a checkpoint or savestate is restored,
+//! and whenever the bus reports a bank switch (see `Bus::take_bank_switch`).
+//! ROM can't be written, so ROM cheats are laid over what the processor reads instead.
+use std::collections::HashMap;
+
+use super::Z80;
+
+/// A single byte to patch
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Poke {
+    pub addr: u16,
+    /// The byte expected at the address before patching. If set, the poke is only applied when
+    /// that byte (or the replacement) is there, so that it never lands in the wrong bank.
+    pub original: Option<u8>,
+    pub value: u8,
+}
+
+/// Identifies a cheat, so it can be removed later
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct CheatId(usize);
+
+#[derive(Default)]
+pub(super) struct Cheats {
+    next: usize,
+    active: Vec<(CheatId, Vec<Poke>)>,
+    rom: Vec<(CheatId, Vec<Poke>)>,
+    // The ROM pokes by address, the latest added winning
+    overlay: HashMap<u16, Poke>,
+}
+
+impl Cheats {
+    fn next_id(&mut self) -> CheatId {
+        let id = CheatId(self.next);
+        self.next += 1;
+        id
+    }
+
+    fn rebuild_overlay(&mut self) {
+        self.overlay = self
+            .rom
+            .iter()
+            .flat_map(|(_, pokes)| pokes.iter().map(|p| (p.addr, *p)))
+            .collect();
+    }
+}
+
+// The pokes making up a patch of the given original bytes
+fn patch(addr: u16, original: &[u8], replacement: &[u8]) -> Vec<Poke> {
+    assert_eq!(
+        original.len(),
+        replacement.len(),
+        "a patch must be the same length as what it replaces"
+    );
+    original
+        .iter()
+        .zip(replacement)
+        .enumerate()
+        .map(|(i, (o, r))| Poke {
+            addr: addr.wrapping_add(i as u16),
+            original: Some(*o),
+            value: *r,
+        })
+        .collect()
+}
+
+impl Z80 {
+    /// Add a cheat made of any number of pokes, and apply it straight away. For example:
+    /// ```
+    /// use zeerust::z80::{cheat::Poke, Z80};
+    ///
+    /// let mut z80 = Z80::default();
+    /// // Infinite lives
+    /// z80.add_cheat(vec![Poke { addr: 0x1234, original: None, value: 0x00 }]);
+    /// assert_eq!(0x00, z80.memory.read_u8(0x1234));
+    ///```
+    /// Pokes are made as ordinary memory writes, which a Bus is free to ignore for ROM: use
+    /// add_rom_cheat for those.
+    pub fn add_cheat(&mut self, pokes: Vec<Poke>) -> CheatId {
+        let id = self.cheats.next_id();
+        for poke in &pokes {
+            self.apply_poke(poke);
+        }
+        self.cheats.active.push((id, pokes));
+        id
+    }
+
+    /// Add a patch replacing the given original bytes, starting at addr
+    ///
+    /// # Panics
+    /// Panics if original and replacement are different lengths
+    pub fn add_patch(&mut self, addr: u16, original: &[u8], replacement: &[u8]) -> CheatId {
+        self.add_cheat(patch(addr, original, replacement))
+    }
+
+    /// Add a cheat for ROM. Nothing is written: instead, every read the processor makes of a
+    /// poked address gives the poke's value, as long as the byte underneath is the original
+    /// (so a poke for one bank doesn't show through another). For example:
+    /// ```
+    /// use zeerust::z80::{cheat::Poke, Z80};
+    ///
+    /// let mut z80 = Z80::default();
+    /// z80.load(&[0x3E, 0x03, 0x76]); // LD A, 3; HALT
+    /// z80.add_rom_cheat(vec![Poke { addr: 0x0001, original: Some(0x03), value: 0x09 }]);
+    /// z80.run();
+    /// assert_eq!(Some(0x09), z80.peek(0x0001));
+    /// assert_eq!(0x03, z80.memory.read_u8(0x0001));
+    ///```
+    pub fn add_rom_cheat(&mut self, pokes: Vec<Poke>) -> CheatId {
+        let id = self.cheats.next_id();
+        self.cheats.rom.push((id, pokes));
+        self.cheats.rebuild_overlay();
+        id
+    }
+
+    /// Add a patch to ROM, replacing the given original bytes, starting at addr.
+    /// See add_rom_cheat.
+    ///
+    /// # Panics
+    /// Panics if original and replacement are different lengths
+    pub fn add_rom_patch(&mut self, addr: u16, original: &[u8], replacement: &[u8]) -> CheatId {
+        self.add_rom_cheat(patch(addr, original, replacement))
+    }
+
+    /// Remove a cheat, restoring the original bytes where they are known.
+    /// Returns false if there was no such cheat.
+    pub fn remove_cheat(&mut self, id: CheatId) -> bool {
+        if let Some(pos) = self.cheats.rom.iter().position(|(i, _)| *i == id) {
+            self.cheats.rom.remove(pos);
+            self.cheats.rebuild_overlay();
+            return true;
+        }
+        let pos = match self.cheats.active.iter().position(|(i, _)| *i == id) {
+            Some(pos) => pos,
+            None => return false,
+        };
+        let (_, pokes) = self.cheats.active.remove(pos);
+        for poke in pokes {
+            if let Some(original) = poke.original {
+                if self.peek_mem(poke.addr) == poke.value {
+                    self.write_mem(poke.addr, original);
+                }
+            }
+        }
+        true
+    }
+
+    /// Reapply every cheat. This is done after restoring a checkpoint or savestate, and when
+    /// the bus reports a bank switch; call it after changing memory any other way.
+    pub fn apply_cheats(&mut self) {
+        let pokes: Vec<Poke> = self
+            .cheats
+            .active
+            .iter()
+            .flat_map(|(_, pokes)| pokes.iter().copied())
+            .collect();
+        for poke in &pokes {
+            self.apply_poke(poke);
+        }
+    }
+
+    fn apply_poke(&mut self, poke: &Poke) {
+        let current = self.peek_mem(poke.addr);
+        match poke.original {
+            Some(original) if current != original && current != poke.value => (),
+            _ => self.write_mem(poke.addr, poke.value),
+        }
+    }
+
+    // Reapply the cheats if the bus has switched banks
+    pub(super) fn follow_bank_switch(&mut self) {
+        let switched = self.bus.as_ref().is_some_and(|bus| bus.take_bank_switch());
+        if switched && !self.cheats.active.is_empty() {
+            self.apply_cheats();
+        }
+    }
+
+    // The byte read from an address, with any ROM cheat laid over it
+    pub(super) fn overlay(&self, addr: u16, val: u8) -> u8 {
+        match self.cheats.overlay.get(&addr) {
+            Some(poke) if poke.original.is_none_or(|o| o == val) => poke.value,
+            _ => val,
+        }
+    }
+}
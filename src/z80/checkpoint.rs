@@ -0,0 +1,315 @@
+//! Checkpoints: the processor's state at a moment, to go back to later.
+//! Memory is kept in 1K pages, and a checkpoint taken after another shares every page that
+//! hasn't changed since, so a run of them costs little more than the memory written in
+//! between. Checkpoints keeps one every few frames, so a front end can offer "rewind 10
+//! seconds", or resynchronise a netplay session from an agreed frame.
+//!
+//! Only the processor and memory are kept, along with any state chunks added to the processor
+//! (see `savestate::StateChunk`): devices, traps and the like aren't. With a bus, the whole
+//! address space is read through it, and restored by writing through it.
+//!
+//! That is sound for a bare processor, or one on a bus of plain memory. A bus with banking or
+//! memory-mapped registers needs a state chunk that holds all of its memory, so nothing is
+//! written back through it: the machines in `machine` add one of their own, and the Sega
+//! mapper, DivMMC and Interface 1 each offer one to add. The machines' frame timers aren't
+//! kept, so take checkpoints between frames, as `Checkpoints::record` expects.
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use super::savestate::SaveStateError;
+use super::Z80;
+use crate::cpu::mem::MEMORY_SIZE;
+use crate::cpu::reg::Registers;
+
+pub(super) const PAGE_SIZE: usize = 1024;
+
+/// The processor and its memory at a moment
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    pub(super) registers: Registers,
+    pub(super) iff1: bool,
+    pub(super) iff2: bool,
+    pub(super) interrupt_mode: u8,
+    pub(super) int_pending: bool,
+    pub(super) nmi_pending: bool,
+    pub(super) ei_delay: bool,
+    pub(super) is_halted: bool,
+    pub(super) tstates: u64,
+    pub(super) pages: Vec<Rc<[u8]>>,
+    // The state chunks, by tag, as StateChunk::save gave them
+    pub(super) chunks: Vec<([u8; 4], Rc<[u8]>)>,
+}
+
+impl Checkpoint {
+    /// The number of pages this checkpoint shares with another, which take no extra space
+    pub fn shared_pages(&self, other: &Checkpoint) -> usize {
+        self.pages
+            .iter()
+            .zip(&other.pages)
+            .filter(|(a, b)| Rc::ptr_eq(a, b))
+            .count()
+    }
+
+    // The saved contents of the state chunk with the given tag
+    pub(super) fn chunk(&self, tag: &[u8; 4]) -> Option<&[u8]> {
+        self.chunks
+            .iter()
+            .find(|(t, _)| t == tag)
+            .map(|(_, chunk)| &**chunk)
+    }
+}
+
+impl Z80 {
+    /// Take a checkpoint
+    /// ```
+    /// use zeerust::z80::Z80;
+    ///
+    /// let mut z80 = Z80::default();
+    /// z80.load(&[0x3E, 0x2A, 0x32, 0x00, 0x20, 0x76]); // LD A, 42; LD (0x2000), A; HALT
+    /// let start = z80.checkpoint();
+    /// z80.run();
+    /// z80.restore(&start);
+    /// assert_eq!(0x0000, z80.registers.get_pc());
+    /// assert_eq!(Some(0), z80.peek(0x2000));
+    ///```
+    pub fn checkpoint(&self) -> Checkpoint {
+        self.take_checkpoint(None)
+    }
+
+    /// Take a checkpoint, sharing the pages of memory that haven't changed since `previous`
+    pub fn checkpoint_after(&self, previous: &Checkpoint) -> Checkpoint {
+        self.take_checkpoint(Some(previous))
+    }
+
+    fn take_checkpoint(&self, previous: Option<&Checkpoint>) -> Checkpoint {
+        let memory: Vec<u8> = match &self.bus {
+            _ if self.state_chunks.iter().any(|c| c.holds_memory()) => vec![],
+            Some(bus) => (0..=0xFFFF).map(|addr| bus.mem_read(addr)).collect(),
+            None => self.memory.view(..MEMORY_SIZE).unwrap().to_vec(),
+        };
+        let pages = memory
+            .chunks(PAGE_SIZE)
+            .enumerate()
+            .map(|(i, page)| match previous.and_then(|p| p.pages.get(i)) {
+                Some(old) if **old == *page => Rc::clone(old),
+                _ => Rc::from(page),
+            })
+            .collect();
+        let chunks = self
+            .state_chunks
+            .iter()
+            .map(|chunk| {
+                let tag = chunk.tag();
+                let saved = chunk.save();
+                match previous.and_then(|p| p.chunks.iter().find(|(t, _)| *t == tag)) {
+                    Some((_, old)) if **old == *saved => (tag, Rc::clone(old)),
+                    _ => (tag, Rc::from(saved)),
+                }
+            })
+            .collect();
+        Checkpoint {
+            registers: self.registers.clone(),
+            iff1: self.iff1,
+            iff2: self.iff2,
+            interrupt_mode: self.interrupt_mode,
+            int_pending: self.int_pending,
+            nmi_pending: self.nmi_pending,
+            ei_delay: self.ei_delay,
+            is_halted: self.is_halted,
+            tstates: self.tstates,
+            pages,
+            chunks,
+        }
+    }
+
+    /// Go back to a checkpoint, then reapply any cheats
+    ///
+    /// # Panics
+    /// Panics if a state chunk can't load what it saved
+    pub fn restore(&mut self, checkpoint: &Checkpoint) {
+        if let Err(e) = self.restore_chunks(checkpoint) {
+            panic!("{}", e);
+        }
+        self.restore_processor(checkpoint);
+    }
+
+    // Hand every state chunk its saved contents
+    pub(super) fn restore_chunks(&self, checkpoint: &Checkpoint) -> Result<(), SaveStateError> {
+        for chunk in &self.state_chunks {
+            chunk.load(checkpoint.chunk(&chunk.tag()))?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn restore_processor(&mut self, checkpoint: &Checkpoint) {
+        self.registers = checkpoint.registers.clone();
+        self.iff1 = checkpoint.iff1;
+        self.iff2 = checkpoint.iff2;
+        self.interrupt_mode = checkpoint.interrupt_mode;
+        self.int_pending = checkpoint.int_pending;
+        self.nmi_pending = checkpoint.nmi_pending;
+        self.ei_delay = checkpoint.ei_delay;
+        self.is_halted = checkpoint.is_halted;
+        self.tstates = checkpoint.tstates;
+        let memory = checkpoint.pages.iter().flat_map(|page| page.iter());
+        match &self.bus {
+            Some(bus) => {
+                for (addr, b) in memory.enumerate() {
+                    bus.mem_write(addr as u16, *b);
+                }
+            }
+            None => {
+                for (dest, b) in self.memory.view_mut(..).unwrap().iter_mut().zip(memory) {
+                    *dest = *b;
+                }
+            }
+        }
+        self.apply_cheats();
+    }
+}
+
+/// Checkpoints taken every few frames, keeping the most recent.
+/// See the module documentation for what they can be used with.
+#[derive(Debug)]
+pub struct Checkpoints {
+    interval: u64,
+    capacity: usize,
+    // Oldest first, with the frame each was taken at
+    taken: VecDeque<(u64, Checkpoint)>,
+}
+
+impl Checkpoints {
+    /// Take a checkpoint every `interval` frames, keeping at most `capacity` of them.
+    /// Fifty checkpoints every ten frames covers the last ten seconds of a 50Hz machine.
+    /// ```
+    /// use zeerust::z80::checkpoint::Checkpoints;
+    /// use zeerust::z80::Z80;
+    ///
+    /// let mut z80 = Z80::default();
+    /// let mut checkpoints = Checkpoints::new(10, 50);
+    /// for frame in 0..200 {
+    ///     // Run the frame, then
+    ///     checkpoints.record(frame, &z80);
+    /// }
+    /// // Two seconds back from the last frame
+    /// assert_eq!(Some(90), checkpoints.rewind(&mut z80, 199, 100));
+    ///```
+    pub fn new(interval: u64, capacity: usize) -> Self {
+        Self {
+            interval: interval.max(1),
+            capacity,
+            taken: VecDeque::new(),
+        }
+    }
+
+    /// Call at the end of every frame: it takes a checkpoint if one is due
+    pub fn record(&mut self, frame: u64, z80: &Z80) {
+        if !frame.is_multiple_of(self.interval) || self.capacity == 0 {
+            return;
+        }
+        let checkpoint = match self.taken.back() {
+            Some((_, last)) => z80.checkpoint_after(last),
+            None => z80.checkpoint(),
+        };
+        if self.taken.len() == self.capacity {
+            self.taken.pop_front();
+        }
+        self.taken.push_back((frame, checkpoint));
+    }
+
+    /// The checkpoint taken at a frame, if it's still kept
+    pub fn get(&self, frame: u64) -> Option<&Checkpoint> {
+        self.taken
+            .iter()
+            .find(|(f, _)| *f == frame)
+            .map(|(_, checkpoint)| checkpoint)
+    }
+
+    /// The frames of the checkpoints kept, oldest first
+    pub fn frames(&self) -> impl Iterator<Item = u64> + '_ {
+        self.taken.iter().map(|(frame, _)| *frame)
+    }
+
+    /// Go back at least `frames` frames from frame `now`, to the latest checkpoint that far
+    /// back, and forget the checkpoints after it. Returns the frame gone back to, or None
+    /// (leaving the processor as it was) if there's no checkpoint that old.
+    pub fn rewind(&mut self, z80: &mut Z80, now: u64, frames: u64) -> Option<u64> {
+        let target = now.checked_sub(frames)?;
+        let index = self.taken.iter().rposition(|(f, _)| *f <= target)?;
+        self.taken.truncate(index + 1);
+        let (frame, checkpoint) = self.taken.back()?;
+        z80.restore(checkpoint);
+        Some(*frame)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ops::Reg8;
+
+    #[test]
+    fn sharing() {
+        let mut z80 = Z80::default();
+        let first = z80.checkpoint();
+        z80.poke(0x0401, 1);
+        let second = z80.checkpoint_after(&first);
+        assert_eq!(MEMORY_SIZE / PAGE_SIZE - 1, second.shared_pages(&first));
+        z80.poke(0x0401, 0);
+        let third = z80.checkpoint_after(&second);
+        assert_eq!(MEMORY_SIZE / PAGE_SIZE - 1, third.shared_pages(&second));
+        assert_eq!(MEMORY_SIZE / PAGE_SIZE - 1, third.shared_pages(&first));
+    }
+
+    #[test]
+    fn memory_held_elsewhere() {
+        use crate::z80::savestate::StateChunk;
+        // A board whose state (memory included) is kept some other way
+        struct Board;
+        impl StateChunk for Board {
+            fn tag(&self) -> [u8; 4] {
+                *b"BORD"
+            }
+            fn save(&self) -> Vec<u8> {
+                vec![]
+            }
+            fn load(&self, _chunk: Option<&[u8]>) -> Result<(), SaveStateError> {
+                Ok(())
+            }
+            fn holds_memory(&self) -> bool {
+                true
+            }
+        }
+
+        let mut z80 = Z80::default();
+        z80.add_state_chunk(Box::new(Board));
+        let checkpoint = z80.checkpoint();
+        assert!(checkpoint.pages.is_empty());
+        z80.poke(0x0401, 1);
+        z80.restore(&checkpoint);
+        assert_eq!(Some(1), z80.peek(0x0401));
+    }
+
+    #[test]
+    fn rewinding() {
+        let mut z80 = Z80::default();
+        // loop: INC A; LD (0x2000), A; JR loop
+        z80.load(&[0x3C, 0x32, 0x00, 0x20, 0x18, 0xFA]);
+        let mut checkpoints = Checkpoints::new(2, 3);
+        for frame in 0..8 {
+            for _ in 0..3 {
+                z80.step();
+            }
+            checkpoints.record(frame, &z80);
+        }
+        assert_eq!(vec![2, 4, 6], checkpoints.frames().collect::<Vec<_>>());
+        let a = |c: &Checkpoint| c.registers.get_reg8(Reg8::A);
+        assert_eq!(Some(5), checkpoints.get(4).map(a));
+
+        assert_eq!(None, checkpoints.rewind(&mut z80, 7, 6));
+        assert_eq!(Some(8), z80.peek(0x2000));
+        assert_eq!(Some(4), checkpoints.rewind(&mut z80, 7, 2));
+        assert_eq!(Some(5), z80.peek(0x2000));
+        assert_eq!(vec![2, 4], checkpoints.frames().collect::<Vec<_>>());
+    }
+}
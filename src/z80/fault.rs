@@ -0,0 +1,103 @@
+//! Context for when the emulator gives up.
+//! Illegal operations (undecodable opcodes, unmapped ports, stores to immediates) still panic,
+//! but the panic's payload is a Fault describing where the processor was and what it was doing.
+//! Catch it with `std::panic::catch_unwind` and downcast it to get at the details; displayed, it
+//! reads:
+//! ```text
+//! no peripheral installed in 0x2a42 at 0002: IN A, ($42)
+//! PC:0002 AF:2A00 BC:0000 DE:0000 HL:0000 IX:0000 IY:0000 SP:4000 T:7 (db 42 76 00)
+//! ```
+use std::fmt;
+
+use super::Z80;
+use crate::cpu::opcodes::table::disassemble;
+use crate::ops::Op;
+
+/// An illegal operation, and the state of the processor when it happened
+#[derive(Debug, PartialEq, Clone)]
+pub struct Fault {
+    pub message: String,
+    /// Where the instruction began
+    pub pc: u16,
+    /// The instruction, if it had been decoded
+    pub op: Option<Op>,
+    /// The registers, in the trace format
+    pub registers: String,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {:04x}", self.message, self.pc)?;
+        if let Some(op) = &self.op {
+            write!(f, ": {}", disassemble(op))?;
+        }
+        write!(f, "\n{}", self.registers)
+    }
+}
+
+impl std::error::Error for Fault {}
+
+impl Z80 {
+    /// Describe an illegal operation by the instruction currently executing.
+    /// Useful for devices and traps that want to report errors the same way as the processor,
+    /// by raising it with `std::panic::panic_any`.
+    pub fn fault<S: Into<String>>(&self, message: S) -> Fault {
+        Fault {
+            message: message.into(),
+            pc: self.registers.get_pc(),
+            op: self.executing.clone(),
+            registers: self.trace_line(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::opcodes::panic_message;
+    use crate::ops::{Location8, Reg8};
+    use std::panic;
+
+    fn fault_of(z80: &mut Z80) -> String {
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| z80.run()));
+        panic_message(&*result.unwrap_err())
+    }
+
+    #[test]
+    fn payload() {
+        let mut z80 = Z80::default();
+        z80.load(&[0xDB, 0x42]); // IN A, (0x42)
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| z80.run()));
+        let fault = result.unwrap_err().downcast::<Fault>().unwrap();
+        assert_eq!("no peripheral installed in 0x42", fault.message);
+        assert_eq!(0x0000, fault.pc);
+        assert_eq!(
+            Some(Op::IN(Location8::Reg(Reg8::A), Location8::Immediate(0x42))),
+            fault.op
+        );
+    }
+
+    #[test]
+    fn unmapped_port() {
+        let mut z80 = Z80::default();
+        // LD A, 42; IN A, (0x42)
+        z80.load(&[0x3E, 0x2A, 0xDB, 0x42]);
+        assert_eq!(
+            "no peripheral installed in 0x2a42 at 0002: IN A, ($42)\n\
+             PC:0002 AF:2A00 BC:0000 DE:0000 HL:0000 IX:0000 IY:0000 SP:4000 T:7 (db 42 00 00)",
+            fault_of(&mut z80)
+        );
+    }
+
+    #[test]
+    fn bad_opcode() {
+        let mut z80 = Z80::default();
+        // NOP; an ED opcode that doesn't exist
+        z80.load(&[0x00, 0xED, 0x77]);
+        assert_eq!(
+            "Unknown ExtendeD operation 77 at 0001\n\
+             PC:0001 AF:0000 BC:0000 DE:0000 HL:0000 IX:0000 IY:0000 SP:4000 T:4 (ed 77 00 00)",
+            fault_of(&mut z80)
+        );
+    }
+}
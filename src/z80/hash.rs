@@ -0,0 +1,84 @@
+//! A stable digest of the processor's state, for comparing the end of a run against a known-good
+//! value in tests without keeping a whole snapshot around.
+use super::Z80;
+use crate::cpu::mem::MEMORY_SIZE;
+use crate::ops::{Reg16, Reg8};
+
+// 64-bit FNV-1a. Unlike std's hashers, it is guaranteed never to change between releases.
+struct Fnv(u64);
+
+impl Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= u64::from(*b);
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01B3);
+        }
+    }
+}
+
+impl Z80 {
+    /// The version of what state_hash digests. It goes up whenever that changes, which changes
+    /// every hash; keep it next to known-good hashes to tell why they no longer match.
+    pub const STATE_HASH_VERSION: u16 = 1;
+
+    /// A digest of the registers (I and R included), the halted and interrupt state, and memory.
+    /// Two processors in the same state have the same hash on any platform, and with any version
+    /// of this crate with the same STATE_HASH_VERSION. Elapsed T-states, devices and traps (and
+    /// anything else not listed) are not included.
+    /// If a bus is set, the whole 64K address space is read through it.
+    /// ```
+    /// use zeerust::z80::Z80;
+    ///
+    /// let mut z80 = Z80::default();
+    /// z80.load(&[0x3E, 0x2A, 0x76]); // LD A, 42; HALT
+    /// z80.run();
+    /// assert_eq!(0x45a0_3858_14e7_a43c, z80.state_hash());
+    /// ```
+    pub fn state_hash(&self) -> u64 {
+        let mut hash = Fnv(0xCBF2_9CE4_8422_2325);
+        hash.write(&Self::STATE_HASH_VERSION.to_le_bytes());
+        let regs = &self.registers;
+        for r in &[
+            Reg8::A,
+            Reg8::F,
+            Reg8::B,
+            Reg8::C,
+            Reg8::D,
+            Reg8::E,
+            Reg8::H,
+            Reg8::L,
+            Reg8::AP,
+            Reg8::FP,
+            Reg8::BP,
+            Reg8::CP,
+            Reg8::DP,
+            Reg8::EP,
+            Reg8::HP,
+            Reg8::LP,
+            Reg8::I,
+            Reg8::R,
+        ] {
+            hash.write(&[regs.get_reg8(*r)]);
+        }
+        for r in &[Reg16::IX, Reg16::IY, Reg16::SP] {
+            hash.write(&regs.get_reg16(r).to_le_bytes());
+        }
+        hash.write(&regs.get_pc().to_le_bytes());
+        hash.write(&[
+            self.is_halted as u8,
+            self.iff1 as u8,
+            self.iff2 as u8,
+            self.interrupt_mode,
+        ]);
+
+        let size = if self.bus.is_some() {
+            0x10000
+        } else {
+            MEMORY_SIZE
+        };
+        for addr in 0..size {
+            hash.write(&[self.peek_mem(addr as u16)]);
+        }
+        hash.0
+    }
+}
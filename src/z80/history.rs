@@ -0,0 +1,319 @@
+//! Recording execution history, for time-travel debugging in other tools.
+//! While recording, the processor keeps the state it was in before each of its most recent
+//! steps: the registers, the T-states elapsed and the four bytes at the program counter, the
+//! same as a trace line. The history can be saved in a compact binary format, and read back
+//! or converted to JSON.
+//!
+//! The format is little-endian throughout. A 12 byte header:
+//!
+//! | Offset | Size | Contents                           |
+//! |--------|------|------------------------------------|
+//! | 0      | 4    | `ZHST`                             |
+//! | 4      | 2    | The format version, 1              |
+//! | 6      | 2    | The size of each entry, 28         |
+//! | 8      | 4    | The number of entries              |
+//!
+//! is followed by the entries, oldest first:
+//!
+//! | Offset | Size | Contents                                          |
+//! |--------|------|---------------------------------------------------|
+//! | 0      | 16   | PC, AF, BC, DE, HL, IX, IY and SP, 2 bytes each   |
+//! | 16     | 8    | T-states elapsed before the step                  |
+//! | 24     | 4    | The bytes at PC                                   |
+//!
+//! Readers should use the entry size from the header, so later versions can add to the end
+//! of an entry.
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::fmt;
+
+use super::Z80;
+use crate::debug::json::Value;
+use crate::ops::{Reg16, Reg8};
+
+const SIGNATURE: &[u8; 4] = b"ZHST";
+const VERSION: u16 = 1;
+const HEADER_SIZE: usize = 12;
+const ENTRY_SIZE: usize = 28;
+
+#[derive(Debug, PartialEq)]
+pub enum HistoryError {
+    /// The data doesn't start with the history signature
+    BadSignature,
+    /// The history was written by a newer version of the format than this one reads
+    UnsupportedVersion(u16),
+    /// The data ends before the header says it should
+    Truncated,
+}
+
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HistoryError::BadSignature => write!(f, "not an execution history"),
+            HistoryError::UnsupportedVersion(v) => {
+                write!(f, "execution history version {} is not supported", v)
+            }
+            HistoryError::Truncated => write!(f, "execution history is truncated"),
+        }
+    }
+}
+
+impl std::error::Error for HistoryError {}
+
+/// The processor's state just before a step
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Entry {
+    pub pc: u16,
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub ix: u16,
+    pub iy: u16,
+    pub sp: u16,
+    pub tstates: u64,
+    /// The four bytes at the program counter
+    pub bytes: [u8; 4],
+}
+
+impl Entry {
+    fn registers(&self) -> [u16; 8] {
+        [
+            self.pc, self.af, self.bc, self.de, self.hl, self.ix, self.iy, self.sp,
+        ]
+    }
+}
+
+/// The most recent steps, oldest first. See Z80::record_history.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct History {
+    entries: VecDeque<Entry>,
+    capacity: usize,
+}
+
+impl History {
+    /// An empty history, which keeps at most `capacity` entries
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(capacity.min(0x10000)),
+            capacity,
+        }
+    }
+
+    /// Add an entry, forgetting the oldest if the history is full
+    pub fn push(&mut self, entry: Entry) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Every entry, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.iter()
+    }
+
+    /// The history in the binary format
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(HEADER_SIZE + ENTRY_SIZE * self.len());
+        data.extend_from_slice(SIGNATURE);
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        data.extend_from_slice(&(ENTRY_SIZE as u16).to_le_bytes());
+        data.extend_from_slice(&(self.len() as u32).to_le_bytes());
+        for entry in self.iter() {
+            for reg in &entry.registers() {
+                data.extend_from_slice(&reg.to_le_bytes());
+            }
+            data.extend_from_slice(&entry.tstates.to_le_bytes());
+            data.extend_from_slice(&entry.bytes);
+        }
+        data
+    }
+
+    /// Read a history in the binary format. It keeps as many entries as it was saved with.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, HistoryError> {
+        if data.len() < HEADER_SIZE {
+            return Err(if data.starts_with(&SIGNATURE[..data.len().min(4)]) {
+                HistoryError::Truncated
+            } else {
+                HistoryError::BadSignature
+            });
+        }
+        if &data[0..4] != SIGNATURE {
+            return Err(HistoryError::BadSignature);
+        }
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        if version != VERSION {
+            return Err(HistoryError::UnsupportedVersion(version));
+        }
+        let size = usize::from(u16::from_le_bytes([data[6], data[7]]));
+        let count = u32::from_le_bytes(data[8..12].try_into().unwrap()) as usize;
+        if size < ENTRY_SIZE {
+            return Err(HistoryError::Truncated);
+        }
+        let body = &data[HEADER_SIZE..];
+        if body.len() / size < count {
+            return Err(HistoryError::Truncated);
+        }
+        let mut history = Self::new(count);
+        for chunk in body.chunks(size).take(count) {
+            let word = |i: usize| u16::from_le_bytes([chunk[i * 2], chunk[i * 2 + 1]]);
+            history.push(Entry {
+                pc: word(0),
+                af: word(1),
+                bc: word(2),
+                de: word(3),
+                hl: word(4),
+                ix: word(5),
+                iy: word(6),
+                sp: word(7),
+                tstates: u64::from_le_bytes(chunk[16..24].try_into().unwrap()),
+                bytes: chunk[24..28].try_into().unwrap(),
+            });
+        }
+        Ok(history)
+    }
+
+    /// The history as a JSON array of entries, oldest first, each an object with the
+    /// registers (`pc`, `af` and so on), `tstates` and `bytes`
+    pub fn to_json(&self) -> String {
+        const NAMES: [&str; 8] = ["pc", "af", "bc", "de", "hl", "ix", "iy", "sp"];
+        let entries = self.iter().map(|entry| {
+            let mut fields: Vec<(&str, Value)> = NAMES
+                .iter()
+                .zip(entry.registers().iter())
+                .map(|(name, reg)| (*name, (*reg).into()))
+                .collect();
+            fields.push(("tstates", entry.tstates.into()));
+            fields.push(("bytes", entry.bytes.to_vec().into()));
+            Value::object(fields)
+        });
+        Value::Array(entries.collect()).to_string()
+    }
+}
+
+impl Z80 {
+    /// Start recording the state before each step, keeping the most recent `capacity` of them
+    /// and forgetting any history so far. For example:
+    /// ```
+    /// use zeerust::z80::history::History;
+    /// use zeerust::z80::Z80;
+    ///
+    /// let mut z80 = Z80::default();
+    /// z80.load(&[0x3E, 0x2A, 0x76]); // LD A, 42; HALT
+    /// z80.record_history(1000);
+    /// z80.run();
+    /// let saved = z80.take_history().unwrap().to_bytes();
+    /// let history = History::from_bytes(&saved).unwrap();
+    /// assert_eq!(vec![0x0000, 0x0002], history.iter().map(|e| e.pc).collect::<Vec<_>>());
+    /// assert_eq!(0x2A00, history.iter().last().unwrap().af);
+    ///```
+    pub fn record_history(&mut self, capacity: usize) {
+        self.history = Some(Box::new(History::new(capacity)));
+    }
+
+    /// The history so far, if it's being recorded
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_deref()
+    }
+
+    /// Stop recording, returning the history
+    pub fn take_history(&mut self) -> Option<History> {
+        self.history.take().map(|history| *history)
+    }
+
+    // Record the state before a step, if history is being recorded
+    pub(super) fn record_entry(&mut self) {
+        if self.history.is_none() {
+            return;
+        }
+        let regs = &self.registers;
+        let pair = |high, low| u16::from_be_bytes([regs.get_reg8(high), regs.get_reg8(low)]);
+        let pc = regs.get_pc();
+        let entry = Entry {
+            pc,
+            af: pair(Reg8::A, Reg8::F),
+            bc: pair(Reg8::B, Reg8::C),
+            de: pair(Reg8::D, Reg8::E),
+            hl: pair(Reg8::H, Reg8::L),
+            ix: regs.get_reg16(&Reg16::IX),
+            iy: regs.get_reg16(&Reg16::IY),
+            sp: regs.get_reg16(&Reg16::SP),
+            tstates: self.tstates,
+            bytes: [0, 1, 2, 3].map(|i| self.peek_mem(pc.wrapping_add(i))),
+        };
+        if let Some(history) = &mut self.history {
+            history.push(entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ring() {
+        let mut history = History::new(2);
+        for pc in 0..3 {
+            history.push(Entry {
+                pc,
+                ..Entry::default()
+            });
+        }
+        assert_eq!(vec![1, 2], history.iter().map(|e| e.pc).collect::<Vec<_>>());
+        History::new(0).push(Entry::default());
+    }
+
+    #[test]
+    fn format() {
+        let mut z80 = Z80::default();
+        // LD B, 2; DJNZ -2; HALT
+        z80.load(&[0x06, 0x02, 0x10, 0xFE, 0x76]);
+        z80.record_history(2);
+        z80.run();
+        let history = z80.history().unwrap();
+        let data = history.to_bytes();
+        assert_eq!(12 + 2 * 28, data.len());
+        assert_eq!(b"ZHST\x01\x00\x1c\x00\x02\x00\x00\x00", &data[..12]);
+        // The last DJNZ, which doesn't jump, and the HALT
+        assert_eq!(
+            &[2, 0, 0, 0, 0, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x40, 20, 0, 0, 0, 0, 0, 0, 0],
+            &data[12..36]
+        );
+        assert_eq!(&[0x10, 0xFE, 0x76, 0], &data[36..40]);
+        assert_eq!(Ok(history), History::from_bytes(&data).as_ref());
+
+        let json = History::from_bytes(&data).unwrap().to_json();
+        assert!(json.starts_with(
+            r#"[{"pc":2,"af":0,"bc":256,"de":0,"hl":0,"ix":0,"iy":0,"sp":16384,"tstates":20,"bytes":[16,254,118,0]}"#
+        ));
+
+        assert_eq!(
+            Err(HistoryError::Truncated),
+            History::from_bytes(&data[..50])
+        );
+        assert_eq!(Err(HistoryError::Truncated), History::from_bytes(b"ZHS"));
+        assert_eq!(
+            Err(HistoryError::BadSignature),
+            History::from_bytes(b"RZX!")
+        );
+        let mut newer = data.clone();
+        newer[4] = 2;
+        assert_eq!(
+            Err(HistoryError::UnsupportedVersion(2)),
+            History::from_bytes(&newer)
+        );
+    }
+}
@@ -0,0 +1,95 @@
+//! What's in the registers at power on.
+//! A reset clears PC, I and R, but leaves everything else as it comes: all ones on some
+//! machines, whatever was there before on others. A program that reads a register before
+//! setting it works on one and not another, so running it under each profile, and a few random
+//! seeds, flushes out the bug.
+use super::Z80;
+use crate::devices::rng::Rng;
+use crate::ops::{Reg16, Reg8};
+
+/// How to fill the registers a reset doesn't set
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum InitProfile {
+    /// Every register 0x00
+    Zeros,
+    /// Every register 0xFF, as most Z80s come up
+    Ones,
+    /// Random bytes, the same for the same seed
+    Random(u64),
+}
+
+const PAIRS: [Reg16; 10] = [
+    Reg16::AF,
+    Reg16::BC,
+    Reg16::DE,
+    Reg16::HL,
+    Reg16::AFP,
+    Reg16::BCP,
+    Reg16::DEP,
+    Reg16::HLP,
+    Reg16::IX,
+    Reg16::IY,
+];
+
+impl Z80 {
+    /// Fill the registers as `profile` has them, with PC, I and R cleared as a reset does.
+    /// SP is set to `sp`, or filled like the rest if it's None. For example:
+    /// ```
+    /// use zeerust::ops::Reg16;
+    /// use zeerust::z80::init::InitProfile;
+    /// use zeerust::z80::Z80;
+    ///
+    /// let mut z80 = Z80::default();
+    /// z80.init_registers(InitProfile::Ones, Some(0x4000));
+    /// assert_eq!(0xFFFF, z80.registers.get_reg16(&Reg16::HL));
+    /// assert_eq!(0x4000, z80.registers.get_reg16(&Reg16::SP));
+    ///```
+    pub fn init_registers(&mut self, profile: InitProfile, sp: Option<u16>) {
+        let rng = match profile {
+            InitProfile::Random(seed) => Some(Rng::new(seed)),
+            _ => None,
+        };
+        let fill = || match (profile, &rng) {
+            (InitProfile::Zeros, _) => 0x0000,
+            (_, Some(rng)) => rng.next_u64() as u16,
+            _ => 0xFFFF,
+        };
+        for pair in &PAIRS {
+            self.registers.set_reg16(pair, fill());
+        }
+        let sp = sp.unwrap_or_else(fill);
+        self.registers.set_reg16(&Reg16::SP, sp);
+        self.registers.set_pc(0x0000);
+        self.registers.set_reg8(Reg8::I, 0);
+        self.registers.set_reg8(Reg8::R, 0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn profiles() {
+        let mut z80 = Z80::default();
+        z80.registers.set_pc(0x1234);
+        z80.registers.set_reg8(Reg8::R, 0x55);
+        z80.init_registers(InitProfile::Ones, None);
+        assert_eq!(0xFFFF, z80.registers.get_reg16(&Reg16::SP));
+        assert_eq!(0xFFFF, z80.registers.get_reg16(&Reg16::HLP));
+        assert_eq!(0x0000, z80.registers.get_pc());
+        assert_eq!(0x00, z80.registers.get_reg8(Reg8::R));
+
+        z80.init_registers(InitProfile::Zeros, Some(0x8000));
+        assert_eq!(0x0000, z80.registers.get_reg16(&Reg16::IY));
+        assert_eq!(0x8000, z80.registers.get_reg16(&Reg16::SP));
+
+        z80.init_registers(InitProfile::Random(7), None);
+        let first = z80.registers.clone();
+        z80.init_registers(InitProfile::Random(7), None);
+        assert_eq!(first, z80.registers);
+        z80.init_registers(InitProfile::Random(8), None);
+        assert_ne!(first, z80.registers);
+        assert_ne!(first.get_reg16(&Reg16::BC), first.get_reg16(&Reg16::DE));
+    }
+}
@@ -0,0 +1,164 @@
+//! Maskable and non-maskable interrupts.
+//! Peripherals raise an interrupt with request_interrupt or request_nmi. The request is accepted
+//! between instructions, which also wakes the processor if it was halted. A HALT with interrupts
+//! disabled (and no NMI coming) therefore halts forever.
+//! Maskable interrupts are not accepted straight after an EI, but only once the instruction
+//! following it has run, so that `EI; RETI` at the end of a handler can't be interrupted.
+use super::Z80;
+use crate::cpu::{opcodes, timing};
+use crate::ops;
+
+impl Z80 {
+    /// Returns true if the processor is halted, waiting for an interrupt
+    pub fn is_halted(&self) -> bool {
+        self.is_halted
+    }
+
+    /// Halt or wake the processor
+    pub fn set_halted(&mut self, halted: bool) {
+        self.is_halted = halted;
+    }
+
+    /// Returns true if maskable interrupts are enabled
+    pub fn iff1(&self) -> bool {
+        self.iff1
+    }
+
+    pub fn set_iff1(&mut self, enabled: bool) {
+        self.iff1 = enabled;
+    }
+
+    /// The copy of IFF1 kept while a non-maskable interrupt is serviced, restored by RETN
+    pub fn iff2(&self) -> bool {
+        self.iff2
+    }
+
+    pub fn set_iff2(&mut self, enabled: bool) {
+        self.iff2 = enabled;
+    }
+
+    /// The mode set by the last IM instruction: 0, 1 or 2
+    pub fn interrupt_mode(&self) -> u8 {
+        self.interrupt_mode
+    }
+
+    /// # Panics
+    /// Panics if the mode is not 0, 1 or 2
+    pub fn set_interrupt_mode(&mut self, mode: u8) {
+        assert!(mode <= 2, "invalid interrupt mode {}", mode);
+        self.interrupt_mode = mode;
+    }
+
+    /// Returns true if a maskable interrupt has been requested but not yet accepted
+    pub fn is_interrupt_pending(&self) -> bool {
+        self.int_pending
+    }
+
+    /// Raise or withdraw a maskable interrupt request.
+    /// Unlike request_interrupt, this also works during a replay.
+    pub fn set_interrupt_pending(&mut self, pending: bool) {
+        self.int_pending = pending;
+    }
+
+    /// Returns true if a non-maskable interrupt has been requested but not yet accepted
+    pub fn is_nmi_pending(&self) -> bool {
+        self.nmi_pending
+    }
+
+    /// Raise or withdraw a non-maskable interrupt request.
+    /// Unlike request_nmi, this also works during a replay.
+    pub fn set_nmi_pending(&mut self, pending: bool) {
+        self.nmi_pending = pending;
+    }
+
+    /// Raise the maskable interrupt line.
+    /// The request stays pending until interrupts are enabled and it is accepted.
+    /// Ignored while a recorded run is being replayed, as the log provides the interrupts.
+    pub fn request_interrupt(&mut self) {
+        if !self.is_replaying() {
+            self.int_pending = true;
+        }
+    }
+
+    /// Raise a non-maskable interrupt, which is accepted before the next instruction.
+    /// Ignored while a recorded run is being replayed, as the log provides the interrupts.
+    pub fn request_nmi(&mut self) {
+        if !self.is_replaying() {
+            self.nmi_pending = true;
+        }
+    }
+
+    pub(super) fn set_iff(&mut self, enabled: bool) {
+        self.iff1 = enabled;
+        self.iff2 = enabled;
+    }
+
+    /// Accept a pending interrupt, if there is one that can be accepted.
+    /// `replayed` is the data bus byte of a replayed interrupt; otherwise the devices are asked.
+    /// Returns true if an interrupt took the place of the next instruction.
+    pub(super) fn accept_interrupt(&mut self, replayed: Option<u8>) -> bool {
+        if self.nmi_pending {
+            self.nmi_pending = false;
+            #[cfg(feature = "tracing")]
+            tracing::debug!(tstate = self.tstates, nmi = true, "interrupt");
+            self.record_interrupt(true, 0xFF);
+            // IFF2 remembers whether maskable interrupts were enabled, for RETN
+            self.iff1 = false;
+            self.cycles = timing::nmi_cycles();
+            self.interrupt_jump(0x0066);
+            return true;
+        }
+        if !(self.int_pending && self.iff1) || self.ei_delay {
+            return false;
+        }
+
+        self.int_pending = false;
+        let data = replayed.unwrap_or_else(|| match &self.bus {
+            Some(bus) => bus.acknowledge(),
+            None => self.ports.acknowledge(),
+        });
+        self.record_interrupt(false, data);
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            tstate = self.tstates,
+            nmi = false,
+            mode = self.interrupt_mode,
+            data,
+            "interrupt"
+        );
+        self.set_iff(false);
+        match self.interrupt_mode {
+            0 => {
+                // The device supplies an instruction, usually an RST.
+                // Only its first byte is asked for, so longer instructions read as zeroes.
+                let (op, _) = opcodes::opcode([data, 0x00, 0x00, 0x00]);
+                self.is_halted = false;
+                let next = self.exec_with_offset(op, 0);
+                self.cycles = timing::acknowledge(&self.cycles);
+                if let Some(pc) = next {
+                    self.registers.set_pc(pc);
+                }
+            }
+            1 => {
+                self.cycles = timing::interrupt_cycles(1);
+                self.interrupt_jump(0x0038);
+            }
+            _ => {
+                self.cycles = timing::interrupt_cycles(2);
+                let i = self.registers.get_reg8(ops::Reg8::I);
+                let vector = u16::from_le_bytes([data, i]);
+                // An ordinary pair of memory reads
+                let addr = self.get_loc16(&ops::Location16::ImmediateIndirect(vector));
+                self.interrupt_jump(addr);
+            }
+        }
+        true
+    }
+
+    fn interrupt_jump(&mut self, addr: u16) {
+        self.refresh();
+        self.is_halted = false;
+        self.push_val(self.registers.get_pc());
+        self.registers.set_pc(addr);
+    }
+}
@@ -2,12 +2,22 @@
 use std::cell::RefCell;
 use std::rc::Rc;
 
+use super::ports::PortDecoder;
 use super::Z80;
+use crate::cpu::timing::CycleKind;
 
 /// An InputDevice can be read from, one byte at a time
 pub trait InputDevice {
     /// Read a single byte
     fn input(&self) -> u8;
+
+    /// Called during the acknowledge cycle of a maskable interrupt.
+    /// A device that raised the interrupt returns the byte it puts on the data bus: an opcode
+    /// in mode 0 (usually an RST), or the low byte of the vector address in mode 2.
+    /// Everything else should leave the bus alone.
+    fn acknowledge(&self) -> Option<u8> {
+        None
+    }
 }
 
 /// An OutputDevice can be written to, one byte at a time
@@ -16,6 +26,29 @@ pub trait OutputDevice {
     fn output(&self, val: u8);
 }
 
+/// Whether a value was read from or written to a peripheral
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+/// A single access to a peripheral
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct IoEvent {
+    /// The T-state at which the I/O cycle began
+    pub tstate: u64,
+    /// The full 16-bit port address
+    pub port: u16,
+    pub value: u8,
+    pub direction: Direction,
+}
+
+/// An IoTrace receives every access made to a peripheral, in order
+pub trait IoTrace {
+    fn trace(&self, event: IoEvent);
+}
+
 impl Z80 {
     /// Install an input device at the given index. For example:
     /// ```
@@ -26,8 +59,10 @@ impl Z80 {
     /// z80.install_input(0, Box::new(inp.clone()));
     ///```
     /// This will then be usable with `IN (0), <register>`.
-    pub fn install_input(&mut self, index: u8, device: Box<InputDevice>) {
-        self.input_devices.insert(index, device);
+    /// Only the low byte of the port address is decoded; use port_decoder_mut for anything more
+    /// elaborate.
+    pub fn install_input(&mut self, index: u8, device: Box<dyn InputDevice>) {
+        self.ports.route_input(0x00FF, u16::from(index), device);
     }
 
     /// Install an output device at the given index. For example:
@@ -39,8 +74,60 @@ impl Z80 {
     /// z80.install_output(0, Box::new(out.clone()));
     ///```
     /// This will then be usable with `OUT (0), <register>`.
-    pub fn install_output(&mut self, index: u8, device: Box<OutputDevice>) {
-        self.output_devices.insert(index, device);
+    /// Only the low byte of the port address is decoded; use port_decoder_mut for anything more
+    /// elaborate.
+    pub fn install_output(&mut self, index: u8, device: Box<dyn OutputDevice>) {
+        self.ports.route_output(0x00FF, u16::from(index), device);
+    }
+
+    /// Replace all installed devices with the given decoder
+    pub fn set_port_decoder(&mut self, decoder: PortDecoder) {
+        self.ports = decoder;
+    }
+
+    /// The decoder routing port addresses to devices
+    pub fn port_decoder_mut(&mut self) -> &mut PortDecoder {
+        &mut self.ports
+    }
+
+    /// Log every IN and OUT to the given trace, replacing any trace already set.
+    /// ```
+    /// use zeerust::z80;
+    ///
+    /// let mut z80 = z80::Z80::default();
+    /// let trace = z80::io::BufIoTrace::default();
+    /// z80.set_io_trace(Box::new(trace.clone()));
+    ///```
+    pub fn set_io_trace(&mut self, trace: Box<dyn IoTrace>) {
+        self.io_trace = Some(trace);
+    }
+
+    /// Stop tracing I/O
+    pub fn clear_io_trace(&mut self) {
+        self.io_trace = None;
+    }
+
+    pub(super) fn trace_io(&self, port: u16, value: u8, direction: Direction) {
+        #[cfg(feature = "tracing")]
+        tracing::trace!(tstate = self.io_tstate(), port, value, ?direction, "io");
+        if let Some(trace) = &self.io_trace {
+            trace.trace(IoEvent {
+                tstate: self.io_tstate(),
+                port,
+                value,
+                direction,
+            });
+        }
+    }
+
+    // The T-state the current instruction's I/O cycle began at
+    fn io_tstate(&self) -> u64 {
+        let offset = self
+            .cycles
+            .iter()
+            .find(|c| c.kind == CycleKind::IoRead || c.kind == CycleKind::IoWrite)
+            .map_or(0, |c| c.start);
+        self.tstates + u64::from(offset)
     }
 }
 
@@ -85,3 +172,22 @@ impl OutputDevice for BufOutput {
         self.output.borrow_mut().push(val)
     }
 }
+
+/// BufIoTrace is a simple IoTrace that collects every event into an internal vector.
+#[derive(Default, PartialEq, Clone)]
+pub struct BufIoTrace {
+    events: Rc<RefCell<Vec<IoEvent>>>,
+}
+
+impl BufIoTrace {
+    /// All of the events traced so far, most recent last.
+    pub fn events(&self) -> Vec<IoEvent> {
+        self.events.borrow().to_vec()
+    }
+}
+
+impl IoTrace for BufIoTrace {
+    fn trace(&self, event: IoEvent) {
+        self.events.borrow_mut().push(event)
+    }
+}
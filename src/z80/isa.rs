@@ -0,0 +1,84 @@
+//! Swapping out the instruction set.
+//! Everything but the opcode table and its timings is shared between processors: memory, buses,
+//! devices, traps, tracing and the run loop. An InstructionSet replaces the table, so that a
+//! sibling of the Z80 (an SM83, say, in a Game Boy) can be built on top of this one, as long as
+//! its instructions can be written as Ops. Processors with a different flag layout need to
+//! translate F themselves, for example in PUSH AF traps.
+use super::variant::CpuVariant;
+use super::Z80;
+use crate::cpu::opcodes;
+use crate::cpu::timing::{self, MachineCycle};
+use crate::ops::Op;
+
+/// An opcode table, and how long its instructions take
+pub trait InstructionSet {
+    /// Decode the instruction at the start of the given bytes, returning it and its length.
+    /// Returns the reason if the bytes aren't an instruction.
+    fn decode(&self, bytes: [u8; 4]) -> Result<(Op, usize), String>;
+
+    /// The machine cycles taken by an operation; see `timing::machine_cycles`
+    fn machine_cycles(&self, op: &Op, taken: bool) -> Vec<MachineCycle> {
+        timing::machine_cycles(op, taken)
+    }
+}
+
+impl Z80 {
+    /// Decode and time instructions with the given instruction set, instead of the Z80's own
+    /// (or those of the variant being emulated). For example, to decode the SM83's `LDH (n), A`:
+    /// ```
+    /// use zeerust::cpu::opcodes;
+    /// use zeerust::ops::{Location8, Op, Reg8};
+    /// use zeerust::z80::{isa::InstructionSet, Z80};
+    ///
+    /// struct Sm83;
+    /// impl InstructionSet for Sm83 {
+    ///     fn decode(&self, bytes: [u8; 4]) -> Result<(Op, usize), String> {
+    ///         match bytes {
+    ///             [0xE0, n, _, _] => {
+    ///                 let high = Location8::ImmediateIndirect(0xFF00 | u16::from(n));
+    ///                 Ok((Op::LD8(high, Location8::Reg(Reg8::A)), 2))
+    ///             }
+    ///             // ...and so on, down to what the two share
+    ///             _ => opcodes::try_opcode(bytes),
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// let mut z80 = Z80::default();
+    /// z80.set_instruction_set(Box::new(Sm83));
+    ///```
+    pub fn set_instruction_set(&mut self, isa: Box<dyn InstructionSet>) {
+        self.isa = Some(isa);
+    }
+
+    /// Go back to the Z80's instruction set, returning the one that was set
+    pub fn clear_instruction_set(&mut self) -> Option<Box<dyn InstructionSet>> {
+        self.isa.take()
+    }
+
+    // Decode an instruction the way this processor would, including any instructions of its own
+    pub(super) fn decode_horizon(&self, horizon: [u8; 4]) -> Result<(Op, usize), String> {
+        if let Some(isa) = &self.isa {
+            return isa.decode(horizon);
+        }
+        let extra = match self.variant {
+            CpuVariant::Z180 => opcodes::z180::opcode(horizon),
+            CpuVariant::R800 => opcodes::r800::opcode(horizon),
+            CpuVariant::I8080 => opcodes::i8080::opcode(horizon),
+            _ => None,
+        };
+        extra.map_or_else(|| opcodes::try_opcode(horizon), Ok)
+    }
+
+    // The machine cycles this processor takes for an operation
+    pub(super) fn machine_cycles(&self, op: &Op, taken: bool) -> Vec<MachineCycle> {
+        if let Some(isa) = &self.isa {
+            return isa.machine_cycles(op, taken);
+        }
+        match self.variant {
+            CpuVariant::R800 => timing::r800_machine_cycles(op, taken),
+            CpuVariant::I8080 => timing::i8080_machine_cycles(op, taken),
+            _ => timing::machine_cycles(op, taken),
+        }
+    }
+}
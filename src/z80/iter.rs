@@ -0,0 +1,82 @@
+//! Iterating over the instructions a program executes.
+use super::Z80;
+use crate::cpu::timing;
+use crate::ops::Op;
+
+/// Steps the processor, yielding `(pc, op, tstates)` for each instruction executed until it
+/// halts. The HALT itself is the last item. Interrupts and traps are run, but not yielded.
+/// Created by `Z80::iter`.
+pub struct Steps<'a> {
+    z80: &'a mut Z80,
+}
+
+impl<'a> Iterator for Steps<'a> {
+    type Item = (u16, Op, u32);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.z80.is_halted {
+            let pc = self.z80.registers.get_pc();
+            if let Some(op) = self.z80.step_op() {
+                return Some((pc, op, timing::total(&self.z80.cycles)));
+            }
+        }
+        None
+    }
+}
+
+impl Z80 {
+    /// Run the program one instruction at a time, as an iterator. For example:
+    /// ```
+    /// use zeerust::ops::Op;
+    /// use zeerust::z80::Z80;
+    ///
+    /// let mut z80 = Z80::default();
+    /// // LD B, 3; DJNZ -2; HALT
+    /// z80.load(&[0x06, 0x03, 0x10, 0xFE, 0x76]);
+    /// let jumps = z80.iter().filter(|(_, op, _)| matches!(op, Op::DJNZ(_))).count();
+    /// assert_eq!(3, jumps);
+    ///```
+    pub fn iter(&mut self) -> Steps<'_> {
+        Steps { z80: self }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ops::build::*;
+    use crate::ops::Reg8;
+
+    #[test]
+    fn steps() {
+        let mut z80 = Z80::default();
+        // LD A, 42; OUT (0), A; HALT
+        z80.load(&[0x3E, 0x2A, 0xD3, 0x00, 0x76]);
+        z80.install_output(0x00, Box::new(crate::z80::io::BufOutput::default()));
+        let steps: Vec<_> = z80.iter().collect();
+        assert_eq!(
+            vec![
+                (0x0000, Op::ld(a(), imm(42)), 7),
+                (0x0002, Op::out(0x00), 11),
+                (0x0004, Op::HALT, 4),
+            ],
+            steps
+        );
+        assert_eq!(22, z80.tstates());
+        assert_eq!(0, z80.iter().count());
+    }
+
+    #[test]
+    fn interrupts_not_yielded() {
+        let mut z80 = Z80::default();
+        // LD A, 1; HALT, with a HALT at 0x38 too
+        z80.load(&[0x3E, 0x01, 0x76]);
+        z80.memory.write_u8(0x38, 0x76);
+        z80.set_iff1(true);
+        z80.set_interrupt_mode(1);
+        z80.request_interrupt();
+        let pcs: Vec<u16> = z80.iter().map(|(pc, _, _)| pc).collect();
+        assert_eq!(vec![0x0038], pcs);
+        assert_eq!(0, z80.registers.get_reg8(Reg8::A));
+    }
+}
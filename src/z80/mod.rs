@@ -5,25 +5,74 @@ use std::collections::HashMap;
 use crate::cpu;
 use crate::ops;
 
+pub mod bus;
+pub mod cheat;
+pub mod checkpoint;
+pub mod fault;
+mod hash;
+pub mod history;
+pub mod init;
+mod interrupt;
 pub mod io;
+pub mod isa;
+pub mod iter;
+pub mod ports;
+pub mod replay;
 mod run;
+pub mod savestate;
+pub mod stack;
+pub mod stats;
 #[cfg(test)]
 mod tests;
+pub mod trace;
+pub mod trap;
+pub mod variant;
+pub mod wait;
+pub mod watch;
+pub mod z180;
 
 /// The core emulation type.
 /// Create one with ::default().
 /// This will initialize everything to zero, except the stack pointer, which is set to MAX_MEMORY.
 /// (currently 16 kibibytes).
 /// By default, no input or output devices are attached.
-/// Use install_input and install_output to connect them.
+/// Use install_input and install_output to connect them, or set_bus to take over every memory and
+/// I/O access.
 pub struct Z80 {
     pub registers: cpu::reg::Registers,
     pub memory: cpu::mem::Memory,
 
     is_halted: bool,
-
-    input_devices: HashMap<u8, Box<io::InputDevice>>,
-    output_devices: HashMap<u8, Box<io::OutputDevice>>,
+    iff1: bool,
+    iff2: bool,
+    interrupt_mode: u8,
+    int_pending: bool,
+    nmi_pending: bool,
+    // Set by EI, to hold off interrupts until after the next instruction
+    ei_delay: bool,
+    tstates: u64,
+    // The instruction being executed, for faults
+    executing: Option<ops::Op>,
+    cycles: Vec<cpu::timing::MachineCycle>,
+    replay: replay::Replay,
+    io_trace: Option<Box<dyn io::IoTrace>>,
+    traps: HashMap<u16, trap::Trap>,
+    fetch_hook: Option<trap::FetchHook>,
+    cheats: cheat::Cheats,
+    watches: Vec<(watch::Register, watch::RegWatch)>,
+    stack_guard: Option<stack::StackGuard>,
+    stack_hook: Option<stack::StackHook>,
+    state_chunks: Vec<Box<dyn savestate::StateChunk>>,
+    stats: Option<Box<stats::Stats>>,
+    history: Option<Box<history::History>>,
+    variant: variant::CpuVariant,
+    z180_io: [u8; z180::IO_REGISTERS],
+    isa: Option<Box<dyn isa::InstructionSet>>,
+
+    ports: ports::PortDecoder,
+    bus: Option<Box<dyn bus::Bus>>,
+    wait_states: Option<Box<dyn wait::WaitStates>>,
+    accesses: wait::AccessLog,
 }
 
 impl Default for Z80 {
@@ -35,8 +84,51 @@ impl Default for Z80 {
             memory: cpu::mem::Memory::default(),
 
             is_halted: false,
-            input_devices: HashMap::new(),
-            output_devices: HashMap::new(),
+            iff1: false,
+            iff2: false,
+            interrupt_mode: 0,
+            int_pending: false,
+            nmi_pending: false,
+            ei_delay: false,
+            tstates: 0,
+            executing: None,
+            cycles: Vec::new(),
+            replay: replay::Replay::Off,
+            io_trace: None,
+            traps: HashMap::new(),
+            fetch_hook: None,
+            cheats: cheat::Cheats::default(),
+            watches: Vec::new(),
+            stack_guard: None,
+            stack_hook: None,
+            state_chunks: Vec::new(),
+            stats: None,
+            history: None,
+            variant: variant::CpuVariant::Nmos,
+            z180_io: [0; z180::IO_REGISTERS],
+            isa: None,
+            ports: ports::PortDecoder::default(),
+            bus: None,
+            wait_states: None,
+            accesses: wait::AccessLog::default(),
+        }
+    }
+}
+
+/// Where the processor goes after an instruction
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Next {
+    /// On to the instruction that follows, at the given address
+    Continue(u16),
+    /// A jump, call, return or restart was taken, to the given address
+    Jump(u16),
+}
+
+impl Next {
+    /// The address of the next instruction, either way
+    pub fn pc(self) -> u16 {
+        match self {
+            Next::Continue(pc) | Next::Jump(pc) => pc,
         }
     }
 }
@@ -47,32 +139,84 @@ impl Z80 {
     const HL_INDIRECT: ops::Location8 = ops::Location8::RegIndirect(ops::Reg16::HL);
 
     /// Execute a single instruction.
-    /// The program counter will not be incremented
+    /// The program counter will not be incremented.
+    /// Operations that depend on their own length (such as relative jumps) assume they were
+    /// encoded in their usual form.
     pub fn exec(&mut self, op: ops::Op) {
-        let _ = self.exec_with_offset(op);
+        let length = Self::standard_length(&op);
+        let _ = self.exec_with_offset(op, length);
+    }
+
+    /// Execute an instruction as though it were `length` bytes long and at the program counter,
+    /// returning where the processor should go next.
+    /// This is the heart of `step`, for custom run loops. Like `step`, the machine cycles taken
+    /// are available from `last_cycles`; unlike it, the program counter is left alone, no
+    /// T-states are counted and interrupts, traps and hooks are not looked at.
+    /// ```
+    /// use zeerust::ops::{JumpConditional, Op};
+    /// use zeerust::z80::{Next, Z80};
+    ///
+    /// let mut z80 = Z80::default();
+    /// z80.registers.set_pc(0x0100);
+    /// assert_eq!(Next::Continue(0x0101), z80.execute(Op::NOP, 1));
+    /// // JR Z isn't taken, as the zero flag is clear
+    /// let jr = |cond| Op::JR(cond, 0x10);
+    /// assert_eq!(Next::Continue(0x0102), z80.execute(jr(JumpConditional::Zero), 2));
+    /// assert_eq!(Next::Jump(0x0112), z80.execute(jr(JumpConditional::NonZero), 2));
+    /// ```
+    pub fn execute(&mut self, op: ops::Op, length: u16) -> Next {
+        match self.exec_with_offset(op, length) {
+            Some(pc) => Next::Jump(pc),
+            None => Next::Continue(self.registers.get_pc().wrapping_add(length)),
+        }
     }
 
-    fn exec_with_offset(&mut self, op: ops::Op) -> Option<u16> {
+    // Only relative jumps, calls and restarts care how long they are
+    fn standard_length(op: &ops::Op) -> u16 {
         match op {
-            ops::Op::LD8(dst, src) => self.set_loc8(&dst, self.get_loc8(&src)),
-            ops::Op::LD16(dst, src) => self.set_loc16(&dst, self.get_loc16(&src)),
-            ops::Op::PUSH(src) => self.push(&src),
-            ops::Op::POP(dst) => self.pop(&dst),
-
-            ops::Op::ADD8(dst, src) => self.add(&dst, &src, false),
-            ops::Op::ADC(dst, src) => self.add(&dst, &src, true),
-            ops::Op::INC(dst) => self.add(&dst, &Self::ONE_IMM, false),
-
-            ops::Op::SUB8(dst, src) => self.subtract(&dst, &src, false, true),
-            ops::Op::SBC(dst, src) => self.subtract(&dst, &src, true, true),
-            ops::Op::DEC(dst) => self.subtract(&dst, &Self::ONE_IMM, false, true),
-            ops::Op::CP(src) => self.subtract(&Self::ACC, &src, false, false),
+            ops::Op::JR(_, _) | ops::Op::DJNZ(_) => 2,
+            ops::Op::CALL(_, _) => 3,
+            ops::Op::RETI | ops::Op::RETN => 2,
+            _ => 1,
+        }
+    }
 
-            ops::Op::AND(src) => self.bool_op(&src, |d, s| d & s),
-            ops::Op::OR(src) => self.bool_op(&src, |d, s| d | s),
-            ops::Op::XOR(src) => self.bool_op(&src, |d, s| d ^ s),
+    // Execute an operation that is `length` bytes long, returning the new program counter if
+    // it jumped.
+    fn exec_with_offset(&mut self, op: ops::Op, length: u16) -> Option<u16> {
+        self.cycles = self.machine_cycles(&op, false);
+        self.refresh();
+        self.executing = Some(op.clone());
+        let next = self.dispatch(&op, length);
+        self.fix_flags();
+        if next.is_some() {
+            self.cycles = self.machine_cycles(&op, true);
+        }
+        next
+    }
 
-            ops::Op::DAA => unimplemented!(),
+    fn dispatch(&mut self, op: &ops::Op, length: u16) -> Option<u16> {
+        match op {
+            ops::Op::LD8(dst, src) => self.load8(dst, src),
+            ops::Op::LD16(dst, src) => self.set_loc16(dst, self.get_loc16(src)),
+            ops::Op::PUSH(src) => self.push(src),
+            ops::Op::POP(dst) => self.pop(dst),
+            ops::Op::EX(a, b) => self.exchange(a, b),
+
+            ops::Op::ADD8(dst, src) => self.add(dst, src, false),
+            ops::Op::ADC(dst, src) => self.add(dst, src, true),
+            ops::Op::INC(dst) => self.increment(dst, true),
+
+            ops::Op::SUB8(dst, src) => self.subtract(dst, src, false, true),
+            ops::Op::SBC(dst, src) => self.subtract(dst, src, true, true),
+            ops::Op::DEC(dst) => self.increment(dst, false),
+            ops::Op::CP(src) => self.subtract(&Self::ACC, src, false, false),
+
+            ops::Op::AND(src) => self.bool_op(src, true, |d, s| d & s),
+            ops::Op::OR(src) => self.bool_op(src, false, |d, s| d | s),
+            ops::Op::XOR(src) => self.bool_op(src, false, |d, s| d ^ s),
+
+            ops::Op::DAA => std::panic::panic_any(self.fault("DAA is not implemented")),
             ops::Op::CPL => self.complement(),
             ops::Op::NEG => self.negate(),
             ops::Op::CCF => self.toggle_carry(),
@@ -80,39 +224,90 @@ impl Z80 {
 
             ops::Op::NOP => (),
             ops::Op::HALT => self.is_halted = true,
+            ops::Op::DI => self.set_iff(false),
+            ops::Op::EI => {
+                self.set_iff(true);
+                self.ei_delay = true;
+            }
+            ops::Op::IM(mode) => self.interrupt_mode = *mode,
 
             ops::Op::RLCA => self.rotate_left(&Self::ACC, false),
             ops::Op::RLA => self.rotate_left_thru_acc(&Self::ACC, false),
             ops::Op::RRCA => self.rotate_right(&Self::ACC, false),
             ops::Op::RRA => self.rotate_right_thru_acc(&Self::ACC, false),
-            ops::Op::RLC(reg) => self.rotate_left(&reg, true),
-            ops::Op::RL(reg) => self.rotate_left_thru_acc(&reg, true),
-            ops::Op::RRC(reg) => self.rotate_right(&reg, true),
-            ops::Op::RR(reg) => self.rotate_right_thru_acc(&reg, true),
+            ops::Op::RLC(reg) => self.rotate_left(reg, true),
+            ops::Op::RL(reg) => self.rotate_left_thru_acc(reg, true),
+            ops::Op::RRC(reg) => self.rotate_right(reg, true),
+            ops::Op::RR(reg) => self.rotate_right_thru_acc(reg, true),
 
-            ops::Op::SRL(loc) => self.shift_right(&loc, false),
-            ops::Op::SLA(loc) => self.shift_left(&loc),
-            ops::Op::SRA(loc) => self.shift_right(&loc, true),
+            ops::Op::SRL(loc) => self.shift_right(loc, false),
+            ops::Op::SLA(loc) => self.shift_left(loc),
+            ops::Op::SRA(loc) => self.shift_right(loc, true),
 
             ops::Op::RLD => self.rotate_nibble_left(),
             ops::Op::RRD => self.rotate_nibble_right(),
 
-            ops::Op::BIT(b, loc) => self.get_bit(b, &loc),
-            ops::Op::SET(b, loc) => self.set_bit(b, &loc),
-            ops::Op::RES(b, loc) => self.reset_bit(b, &loc),
-
-            ops::Op::IN(dst, src_port) => self.read_in(&src_port, &dst),
-            ops::Op::OUT(src, dst_port) => self.write_out(&dst_port, &src),
+            ops::Op::BIT(b, loc) => self.get_bit(*b, loc),
+            ops::Op::SET(b, loc) => self.set_bit(*b, loc),
+            ops::Op::RES(b, loc) => self.reset_bit(*b, loc),
+
+            ops::Op::IN(dst, src_port) => self.read_in(src_port, dst),
+            ops::Op::OUT(src, dst_port) => self.write_out(dst_port, src),
+
+            ops::Op::JP(cond, addr) => return self.jump_cond(*cond, addr),
+            ops::Op::JPI(reg) => return Some(self.registers.get_reg16(reg)),
+            ops::Op::JR(cond, offset) => return self.jump_relative(*cond, *offset, length),
+            ops::Op::DJNZ(offset) => return self.decrement_jump(*offset, length),
+            ops::Op::CALL(cond, addr) => return self.call(*cond, *addr, length),
+            ops::Op::RET(cond) => return self.return_(*cond),
+            ops::Op::RETI | ops::Op::RETN => {
+                self.iff1 = self.iff2;
+                return Some(self.pop_val());
+            }
+            ops::Op::RST(addr) => return self.restart(*addr, length),
+
+            ops::Op::MLT(reg) => self.multiply(reg),
+            ops::Op::TST(src) => self.test(src),
+            ops::Op::IN0(dst, port) => self.read_in0(*port, dst),
+            ops::Op::OUT0(src, port) => {
+                let val = self.get_loc8(src);
+                self.output(u16::from(*port), val);
+            }
+            ops::Op::SLP => self.is_halted = true,
 
-            ops::Op::JP(cond, addr) => return self.jump_cond(cond, &addr),
-            ops::Op::JR(cond, offset) => return self.jump_relative(cond, offset),
-            ops::Op::DJNZ(offset) => return self.decrement_jump(offset),
-            ops::Op::CALL(cond, addr) => return self.call(cond, addr),
-            ops::Op::RET(cond) => return self.return_(cond),
+            ops::Op::MULUB(reg) => {
+                let product =
+                    u16::from(self.get_loc8(&Self::ACC)) * u16::from(self.registers.get_reg8(*reg));
+                self.registers.set_reg16(&ops::Reg16::HL, product);
+                self.multiply_flags(product == 0, product > 0xFF);
+            }
+            ops::Op::MULUW(reg) => {
+                let product = u32::from(self.registers.get_reg16(&ops::Reg16::HL))
+                    * u32::from(self.registers.get_reg16(reg));
+                let [de, hl] = [(product >> 16) as u16, product as u16];
+                self.registers.set_reg16(&ops::Reg16::DE, de);
+                self.registers.set_reg16(&ops::Reg16::HL, hl);
+                self.multiply_flags(product == 0, de != 0);
+            }
         };
         None
     }
 
+    fn load8(&mut self, dst: &ops::Location8, src: &ops::Location8) {
+        let val = self.get_loc8(src);
+        self.set_loc8(dst, val);
+
+        // LD A, I and LD A, R are the only loads to set flags
+        if let ops::Location8::Reg(ops::Reg8::I) | ops::Location8::Reg(ops::Reg8::R) = src {
+            self.parity_flags(val);
+            self.registers
+                .set_flag(&ops::StatusFlag::ParityOverflow, self.iff2);
+            self.registers.set_flag(&ops::StatusFlag::HalfCarry, false);
+            self.registers
+                .set_flag(&ops::StatusFlag::AddSubtract, false);
+        }
+    }
+
     fn is_borrow(min: u8, sub: u8, bit: u8) -> bool {
         let mask = (1 << (bit + 1)) - 1;
         (min & mask) < (sub & mask)
@@ -135,7 +330,7 @@ impl Z80 {
 
         let (sum, ov) = v1.overflowing_sub(v2);
         if store_result {
-            self.set_loc8(&dst, sum);
+            self.set_loc8(dst, sum);
         }
 
         // Seven bit carry
@@ -144,8 +339,7 @@ impl Z80 {
         // Subtracting
         self.registers.set_flag(&ops::StatusFlag::AddSubtract, true);
         // Eight bit carry
-        self.registers
-            .set_flag(&ops::StatusFlag::ParityOverflow, ov);
+        self.overflow_flag(ov, sum);
         // Third bit carry
         self.registers
             .set_flag(&ops::StatusFlag::HalfCarry, Self::is_borrow(v1, v2, 2));
@@ -166,7 +360,7 @@ impl Z80 {
         }
 
         let (sum, ov) = v1.overflowing_add(v2);
-        self.set_loc8(&dst, sum);
+        self.set_loc8(dst, sum);
         // Seven bit carry
         self.registers
             .set_flag(&ops::StatusFlag::Carry, (v1 & v2 & 0b0100_0000) != 0);
@@ -174,8 +368,7 @@ impl Z80 {
         self.registers
             .set_flag(&ops::StatusFlag::AddSubtract, false);
         // Eight bit carry
-        self.registers
-            .set_flag(&ops::StatusFlag::ParityOverflow, ov);
+        self.overflow_flag(ov, sum);
         // Third bit carry
         self.registers
             .set_flag(&ops::StatusFlag::HalfCarry, (v1 & v2 & 0b00100) != 0);
@@ -186,7 +379,20 @@ impl Z80 {
             .set_flag(&ops::StatusFlag::Sign, (sum & 0b1000_0000) != 0);
     }
 
-    fn bool_op<F>(&mut self, src: &ops::Location8, f: F)
+    // INC and DEC set the flags as ADD and SUB do, but leave the carry alone
+    fn increment(&mut self, dst: &ops::Location8, up: bool) {
+        let carry = self.registers.get_flag(&ops::StatusFlag::Carry);
+        if up {
+            self.add(dst, &Self::ONE_IMM, false);
+        } else {
+            self.subtract(dst, &Self::ONE_IMM, false, true);
+        }
+        self.registers.set_flag(&ops::StatusFlag::Carry, carry);
+    }
+
+    // Logical operations always act on the accumulator.
+    // AND sets the half carry flag, OR and XOR reset it.
+    fn bool_op<F>(&mut self, src: &ops::Location8, half_carry: bool, f: F)
     where
         F: Fn(u8, u8) -> u8,
     {
@@ -195,14 +401,23 @@ impl Z80 {
 
         let result = f(v1, v2);
         self.set_loc8(&Self::ACC, result);
+        self.logic_flags(result, half_carry);
+    }
 
+    fn test(&mut self, src: &ops::Location8) {
+        let result = self.get_loc8(&Self::ACC) & self.get_loc8(src);
+        self.logic_flags(result, true);
+    }
+
+    fn logic_flags(&mut self, result: u8, half_carry: bool) {
         // Seven bit carry is reset
         self.registers.set_flag(&ops::StatusFlag::Carry, false);
         // Adding
         self.registers
             .set_flag(&ops::StatusFlag::AddSubtract, false);
         // Third bit carry
-        self.registers.set_flag(&ops::StatusFlag::HalfCarry, false);
+        self.registers
+            .set_flag(&ops::StatusFlag::HalfCarry, half_carry);
 
         self.parity_flags(result);
     }
@@ -236,6 +451,24 @@ impl Z80 {
         self.registers.set_flag(&ops::StatusFlag::Carry, a != 0x00);
     }
 
+    fn multiply(&mut self, reg: &ops::Reg16) {
+        let [high, low] = self.registers.get_reg16(reg).to_be_bytes();
+        self.registers
+            .set_reg16(reg, u16::from(high) * u16::from(low));
+    }
+
+    // The R800 multipliers set zero, and carry if the product overflows the lower half
+    fn multiply_flags(&mut self, zero: bool, carry: bool) {
+        self.registers.set_flag(&ops::StatusFlag::Sign, false);
+        self.registers.set_flag(&ops::StatusFlag::Zero, zero);
+        self.registers.set_flag(&ops::StatusFlag::HalfCarry, false);
+        self.registers
+            .set_flag(&ops::StatusFlag::ParityOverflow, false);
+        self.registers
+            .set_flag(&ops::StatusFlag::AddSubtract, false);
+        self.registers.set_flag(&ops::StatusFlag::Carry, carry);
+    }
+
     fn toggle_carry(&mut self) {
         let carry = self.registers.get_flag(&ops::StatusFlag::Carry);
         self.registers.set_flag(&ops::StatusFlag::Carry, !carry);
@@ -405,26 +638,66 @@ impl Z80 {
         self.set_loc8(loc, val & !(1 << bit));
     }
 
-    fn read_in(&mut self, peripheral: &ops::Location8, loc: &ops::Location8) {
-        let peripheral = self.get_loc8(peripheral);
-        let result = match self.input_devices.get_mut(&peripheral) {
-            None => panic!("no peripheral installed in 0x{:02x}", peripheral),
-            Some(d) => d.input(),
+    // The full 16-bit port address put on the bus.
+    // (n) forms put A in the top byte, (C) forms put B there.
+    fn port_address(&self, peripheral: &ops::Location8) -> u16 {
+        let high = match peripheral {
+            ops::Location8::Immediate(_) => self.registers.get_reg8(ops::Reg8::A),
+            _ => self.registers.get_reg8(ops::Reg8::B),
         };
+        u16::from_le_bytes([self.get_loc8(peripheral), high])
+    }
+
+    // IN r, (C) sets the flags, IN A, (n) doesn't
+    fn read_in(&mut self, peripheral: &ops::Location8, loc: &ops::Location8) {
+        let port = self.port_address(peripheral);
+        let result = self.input(port);
+        self.set_loc8(loc, result);
+        if let ops::Location8::Reg(_) = peripheral {
+            self.input_flags(result);
+        }
+    }
+
+    // IN0 sets the flags, like IN r, (C)
+    fn read_in0(&mut self, port: u8, loc: &ops::Location8) {
+        let result = self.input(u16::from(port));
         self.set_loc8(loc, result);
+        self.input_flags(result);
+    }
+
+    fn input_flags(&mut self, result: u8) {
+        self.registers.set_flag(&ops::StatusFlag::HalfCarry, false);
+        self.registers
+            .set_flag(&ops::StatusFlag::AddSubtract, false);
+        self.parity_flags(result);
+    }
+
+    fn input(&mut self, port: u16) -> u8 {
+        let result = match self.replay_input(port) {
+            Some(result) => result,
+            None => self.read_io(port),
+        };
+        self.record_input(port, result);
+        self.trace_io(port, result, io::Direction::In);
+        result
     }
 
     fn write_out(&mut self, peripheral: &ops::Location8, loc: &ops::Location8) {
-        let peripheral = self.get_loc8(peripheral);
-        let val = self.get_loc8(loc);
-        match self.output_devices.get_mut(&peripheral) {
-            None => panic!("no peripheral installed in 0x{:02x}", peripheral),
-            Some(d) => d.output(val),
+        let port = self.port_address(peripheral);
+        let val = match loc {
+            ops::Location8::Immediate(0) if self.variant == variant::CpuVariant::Cmos => 0xFF,
+            _ => self.get_loc8(loc),
         };
+        self.output(port, val);
+    }
+
+    fn output(&mut self, port: u16, val: u8) {
+        self.trace_io(port, val, io::Direction::Out);
+        self.write_io(port, val);
     }
 
     fn parity_flags(&mut self, val: u8) {
-        let parity = val.count_zeros() % 2 == 0;
+        let parity = val.count_zeros().is_multiple_of(2);
 
         self.registers
             .set_flag(&ops::StatusFlag::ParityOverflow, parity);
@@ -437,28 +710,124 @@ impl Z80 {
         match loc {
             ops::Location8::Immediate(v) => *v,
             ops::Location8::Reg(reg) => self.registers.get_reg8(*reg),
-            ops::Location8::RegIndirect(reg) => {
-                let addr = self.registers.get_reg16(&reg);
-                self.memory.memory[addr as usize]
+            ops::Location8::RegIndirect(reg) => self.read_mem(self.registers.get_reg16(reg)),
+            ops::Location8::ImmediateIndirect(addr) => self.read_mem(*addr),
+            ops::Location8::Indexed(reg, d) | ops::Location8::IndexedCopy(reg, d, _) => {
+                self.read_mem(self.indexed_address(reg, *d))
             }
-            ops::Location8::ImmediateIndirect(addr) => self.memory.memory[*addr as usize],
         }
     }
 
     fn set_loc8(&mut self, loc: &ops::Location8, val: u8) {
         match loc {
-            ops::Location8::Immediate(_) => panic!("Attempting to set immediate value!"),
-            ops::Location8::Reg(reg) => self.registers.set_reg8(*reg, val),
-            ops::Location8::ImmediateIndirect(addr) => {
-                self.memory.memory[*addr as usize] = val;
+            ops::Location8::Immediate(_) => {
+                std::panic::panic_any(self.fault("Attempting to set immediate value!"))
             }
+            ops::Location8::Reg(reg) => self.registers.set_reg8(*reg, val),
+            ops::Location8::ImmediateIndirect(addr) => self.write_mem(*addr, val),
             ops::Location8::RegIndirect(reg) => {
                 let addr = self.registers.get_reg16(reg);
-                self.memory.memory[addr as usize] = val;
+                self.write_mem(addr, val);
+            }
+            ops::Location8::Indexed(reg, d) => {
+                let addr = self.indexed_address(reg, *d);
+                self.write_mem(addr, val);
+            }
+            ops::Location8::IndexedCopy(reg, d, copy) => {
+                let addr = self.indexed_address(reg, *d);
+                self.write_mem(addr, val);
+                self.registers.set_reg8(*copy, val);
             }
         }
     }
 
+    /// Read memory as the processor sees it (through the bus, if one is set), without it
+    /// counting as an access. Returns None past the end of `memory` when there is no bus.
+    pub fn peek(&self, addr: u16) -> Option<u8> {
+        let val = match &self.bus {
+            Some(bus) => bus.mem_read(addr),
+            None => self
+                .memory
+                .view(usize::from(addr)..=usize::from(addr))
+                .map(|b| b[0])?,
+        };
+        Some(self.overlay(addr, val))
+    }
+
+    /// Write memory as the processor sees it, without it counting as an access.
+    /// Returns false past the end of `memory` when there is no bus.
+    pub fn poke(&mut self, addr: u16, val: u8) -> bool {
+        match &self.bus {
+            Some(bus) => bus.mem_write(addr, val),
+            None => match self.memory.view_mut(usize::from(addr)..=usize::from(addr)) {
+                Some(b) => b[0] = val,
+                None => return false,
+            },
+        }
+        true
+    }
+
+    // Every memory access made by an instruction (or interrupt) goes through these two
+    fn read_mem(&self, addr: u16) -> u8 {
+        self.log_access(cpu::timing::CycleKind::MemoryRead, addr);
+        self.peek_mem(addr)
+    }
+
+    // Read memory without it counting as an access by the processor
+    fn peek_mem(&self, addr: u16) -> u8 {
+        let val = match &self.bus {
+            Some(bus) => bus.mem_read(addr),
+            None => self.memory.read_u8(addr),
+        };
+        self.overlay(addr, val)
+    }
+
+    fn write_mem(&mut self, addr: u16, val: u8) {
+        self.log_access(cpu::timing::CycleKind::MemoryWrite, addr);
+        match &self.bus {
+            Some(bus) => bus.mem_write(addr, val),
+            None => self.memory.write_u8(addr, val),
+        }
+    }
+
+    fn read_io(&self, port: u16) -> u8 {
+        self.log_access(cpu::timing::CycleKind::IoRead, port);
+        if let Some(reg) = self.z180_internal(port) {
+            return self.z180_io[reg];
+        }
+        match &self.bus {
+            Some(bus) => bus.io_read(port),
+            None => self
+                .ports
+                .input(port)
+                .unwrap_or_else(|| std::panic::panic_any(self.no_peripheral(port))),
+        }
+    }
+
+    fn write_io(&mut self, port: u16, val: u8) {
+        self.log_access(cpu::timing::CycleKind::IoWrite, port);
+        if let Some(reg) = self.z180_internal(port) {
+            self.z180_io[reg] = val;
+            return;
+        }
+        match &self.bus {
+            Some(bus) => bus.io_write(port, val),
+            None => {
+                if !self.ports.output(port, val) {
+                    std::panic::panic_any(self.no_peripheral(port))
+                }
+            }
+        }
+    }
+
+    fn no_peripheral(&self, port: u16) -> fault::Fault {
+        self.fault(format!("no peripheral installed in 0x{:02x}", port))
+    }
+
+    fn indexed_address(&self, reg: &ops::Reg16, d: i8) -> u16 {
+        self.registers.get_reg16(reg).wrapping_add(d as u16)
+    }
+
     fn get_loc16(&self, loc: &ops::Location16) -> u16 {
         match loc {
             ops::Location16::Reg(reg) => self.registers.get_reg16(reg),
@@ -466,16 +835,17 @@ impl Z80 {
                 &ops::Location16::ImmediateIndirect(self.registers.get_reg16(reg)),
             ),
             ops::Location16::Immediate(n) => *n,
-            ops::Location16::ImmediateIndirect(n) => u16::from_le_bytes([
-                self.memory.memory[*n as usize],
-                self.memory.memory[(*n + 1) as usize],
-            ]),
+            ops::Location16::ImmediateIndirect(n) => {
+                u16::from_le_bytes([self.read_mem(*n), self.read_mem(n.wrapping_add(1))])
+            }
         }
     }
 
     fn set_loc16(&mut self, loc: &ops::Location16, v: u16) {
         match loc {
-            ops::Location16::Immediate(_) => panic!("Attempting to set immediate value!"),
+            ops::Location16::Immediate(_) => {
+                std::panic::panic_any(self.fault("Attempting to set immediate value!"))
+            }
             ops::Location16::Reg(reg) => self.registers.set_reg16(reg, v),
             ops::Location16::RegIndirect(reg) => self.set_loc16(
                 &ops::Location16::ImmediateIndirect(self.registers.get_reg16(reg)),
@@ -483,8 +853,8 @@ impl Z80 {
             ),
             ops::Location16::ImmediateIndirect(n) => {
                 let [n1, n2] = v.to_le_bytes();
-                self.memory.memory[*n as usize] = n1;
-                self.memory.memory[(*n + 1) as usize] = n2;
+                self.write_mem(*n, n1);
+                self.write_mem(n.wrapping_add(1), n2);
             }
         }
     }
@@ -518,37 +888,44 @@ impl Z80 {
         }
     }
 
-    fn pc_offset(&self, offset: i8) -> u16 {
+    // Relative jumps are relative to the address of the following instruction
+    fn pc_offset(&self, offset: i8, length: u16) -> u16 {
         self.registers
             .get_pc()
+            .wrapping_add(length)
             .wrapping_add(offset as u16)
-            .wrapping_add(2)
     }
 
-    fn jump_relative(&mut self, cond: ops::JumpConditional, offset: i8) -> Option<u16> {
+    fn jump_relative(
+        &mut self,
+        cond: ops::JumpConditional,
+        offset: i8,
+        length: u16,
+    ) -> Option<u16> {
         if self.eval_cond(cond) {
-            // Range is -126 to 129
-            Some(self.pc_offset(offset))
+            // For a two byte JR, range is -126 to 129
+            Some(self.pc_offset(offset, length))
         } else {
             None
         }
     }
 
-    fn decrement_jump(&mut self, offset: i8) -> Option<u16> {
+    fn decrement_jump(&mut self, offset: i8, length: u16) -> Option<u16> {
         let b = self.registers.get_reg8(ops::Reg8::B);
         let b = b.wrapping_sub(1);
         self.registers.set_reg8(ops::Reg8::B, b);
         if b != 0 {
-            Some(self.pc_offset(offset))
+            Some(self.pc_offset(offset, length))
         } else {
             None
         }
     }
 
     fn push_val(&mut self, val: u16) {
+        self.check_push();
         self.registers.set_reg16(
             &ops::Reg16::SP,
-            self.registers.get_reg16(&ops::Reg16::SP) - 2,
+            self.registers.get_reg16(&ops::Reg16::SP).wrapping_sub(2),
         );
         self.set_loc16(&ops::Location16::RegIndirect(ops::Reg16::SP), val);
     }
@@ -558,10 +935,11 @@ impl Z80 {
     }
 
     fn pop_val(&mut self) -> u16 {
+        self.check_pop();
         let n = self.get_loc16(&ops::Location16::RegIndirect(ops::Reg16::SP));
         self.registers.set_reg16(
             &ops::Reg16::SP,
-            self.registers.get_reg16(&ops::Reg16::SP) + 2,
+            self.registers.get_reg16(&ops::Reg16::SP).wrapping_add(2),
         );
         n
     }
@@ -571,15 +949,27 @@ impl Z80 {
         self.set_loc16(dst, val);
     }
 
-    fn call(&mut self, cond: ops::JumpConditional, loc: u16) -> Option<u16> {
+    fn exchange(&mut self, a: &ops::Location16, b: &ops::Location16) {
+        let (va, vb) = (self.get_loc16(a), self.get_loc16(b));
+        self.set_loc16(a, vb);
+        self.set_loc16(b, va);
+    }
+
+    // Call, returning to the instruction after this one, which is `length` bytes long
+    fn call(&mut self, cond: ops::JumpConditional, loc: u16, length: u16) -> Option<u16> {
         if self.eval_cond(cond) {
-            self.push_val(self.registers.get_pc() + 3); // All CALL instructions are 3 bytes
+            self.push_val(self.registers.get_pc().wrapping_add(length));
             Some(loc)
         } else {
             None
         }
     }
 
+    fn restart(&mut self, addr: u8, length: u16) -> Option<u16> {
+        self.push_val(self.registers.get_pc().wrapping_add(length));
+        Some(u16::from(addr))
+    }
+
     fn return_(&mut self, cond: ops::JumpConditional) -> Option<u16> {
         if self.eval_cond(cond) {
             Some(self.pop_val())
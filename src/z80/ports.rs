@@ -0,0 +1,163 @@
+//! Routing of port addresses to peripherals.
+//! The z80 puts a full 16-bit address on the bus for every IN and OUT: `IN A, (n)` sends A in
+//! the top byte, and `IN r, (C)` sends B. Real machines rarely decode all of those bits, so a
+//! PortDecoder routes each access by (mask, value) pairs: a device sees every port address where
+//! `port & mask == value`.
+use super::io::{InputDevice, OutputDevice};
+
+struct Route<D> {
+    mask: u16,
+    value: u16,
+    device: D,
+}
+
+impl<D> Route<D> {
+    fn matches(&self, port: u16) -> bool {
+        port & self.mask == self.value
+    }
+}
+
+/// Routes port addresses to input and output devices.
+/// When several devices decode the same address, they all see writes, and reads return the
+/// bitwise AND of every device's value (as happens with open-collector buses).
+/// ```
+/// use zeerust::z80::io::{BufInput, BufOutput};
+/// use zeerust::z80::ports::PortDecoder;
+///
+/// let mut ports = PortDecoder::default();
+/// // Every even port, like the ZX Spectrum ULA
+/// ports.route_input(0x0001, 0x0000, Box::new(BufInput::new(vec![0xBF])));
+/// assert_eq!(Some(0xBF), ports.input(0xFEFE));
+/// assert_eq!(None, ports.input(0x00FF));
+/// ```
+#[derive(Default)]
+pub struct PortDecoder {
+    inputs: Vec<Route<Box<dyn InputDevice>>>,
+    outputs: Vec<Route<Box<dyn OutputDevice>>>,
+}
+
+impl PortDecoder {
+    /// Route reads of matching ports to the given device.
+    /// Replaces any device already routed with the same mask and value.
+    pub fn route_input(&mut self, mask: u16, value: u16, device: Box<dyn InputDevice>) {
+        self.inputs
+            .retain(|r| !(r.mask == mask && r.value == value & mask));
+        self.inputs.push(Route {
+            mask,
+            value: value & mask,
+            device,
+        });
+    }
+
+    /// Route writes to matching ports to the given device.
+    /// Replaces any device already routed with the same mask and value.
+    pub fn route_output(&mut self, mask: u16, value: u16, device: Box<dyn OutputDevice>) {
+        self.outputs
+            .retain(|r| !(r.mask == mask && r.value == value & mask));
+        self.outputs.push(Route {
+            mask,
+            value: value & mask,
+            device,
+        });
+    }
+
+    /// Read from every device decoding the given port.
+    /// Returns None if no device responds.
+    pub fn input(&self, port: u16) -> Option<u8> {
+        self.inputs
+            .iter()
+            .filter(|r| r.matches(port))
+            .map(|r| r.device.input())
+            .fold(None, |acc, v| Some(acc.unwrap_or(0xFF) & v))
+    }
+
+    /// Ask every input device for the byte to put on the data bus during an interrupt
+    /// acknowledge cycle. Returns 0xFF, as the pull-up resistors would, if no device responds.
+    pub fn acknowledge(&self) -> u8 {
+        self.inputs
+            .iter()
+            .filter_map(|r| r.device.acknowledge())
+            .fold(0xFF, |acc, v| acc & v)
+    }
+
+    /// Write to every device decoding the given port.
+    /// Returns false if no device was listening.
+    pub fn output(&self, port: u16, val: u8) -> bool {
+        let mut found = false;
+        for r in self.outputs.iter().filter(|r| r.matches(port)) {
+            r.device.output(val);
+            found = true;
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::z80::io::{BufInput, BufOutput};
+
+    #[test]
+    fn full_decoding() {
+        let mut ports = PortDecoder::default();
+        ports.route_input(0xFFFF, 0x7FFD, Box::new(BufInput::new(vec![0x12])));
+        assert_eq!(None, ports.input(0x7FFC));
+        assert_eq!(None, ports.input(0xFFFD));
+        assert_eq!(Some(0x12), ports.input(0x7FFD));
+    }
+
+    #[test]
+    fn replace_route() {
+        let mut ports = PortDecoder::default();
+        ports.route_input(0x00FF, 0x0001, Box::new(BufInput::new(vec![0x12])));
+        ports.route_input(0x00FF, 0x0001, Box::new(BufInput::new(vec![0x34])));
+        assert_eq!(Some(0x34), ports.input(0x0001));
+        assert_eq!(None, ports.input(0x0002));
+    }
+
+    #[test]
+    fn overlapping_inputs() {
+        let mut ports = PortDecoder::default();
+        ports.route_input(0x0001, 0x0000, Box::new(BufInput::new(vec![0b1111_0011])));
+        ports.route_input(0x00FF, 0x001F, Box::new(BufInput::new(vec![0b0011_1111])));
+        ports.route_input(0x00FF, 0x00FE, Box::new(BufInput::new(vec![0b1011_1111])));
+        assert_eq!(Some(0b0011_1111), ports.input(0x001F));
+        assert_eq!(Some(0b1011_0011), ports.input(0x00FE));
+    }
+
+    struct Interrupting(u8);
+
+    impl InputDevice for Interrupting {
+        fn input(&self) -> u8 {
+            0x00
+        }
+
+        fn acknowledge(&self) -> Option<u8> {
+            Some(self.0)
+        }
+    }
+
+    #[test]
+    fn acknowledge() {
+        let mut ports = PortDecoder::default();
+        ports.route_input(0x00FF, 0x0001, Box::new(BufInput::new(vec![0x12])));
+        assert_eq!(0xFF, ports.acknowledge());
+        ports.route_input(0x00FF, 0x0002, Box::new(Interrupting(0xE7)));
+        assert_eq!(0xE7, ports.acknowledge());
+    }
+
+    #[test]
+    fn overlapping_outputs() {
+        let mut ports = PortDecoder::default();
+        let high = BufOutput::default();
+        let low = BufOutput::default();
+        // The CPC gate array only looks at the top two bits
+        ports.route_output(0xC000, 0x4000, Box::new(high.clone()));
+        ports.route_output(0x00FF, 0x0000, Box::new(low.clone()));
+        assert!(ports.output(0x7F00, 0x01));
+        assert!(ports.output(0x0000, 0x02));
+        assert!(!ports.output(0x0001, 0x03));
+        assert_eq!(vec![0x01], high.result());
+        assert_eq!(vec![0x01, 0x02], low.result());
+    }
+}
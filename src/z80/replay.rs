@@ -0,0 +1,128 @@
+//! Recording and replaying the inputs of a run, so that it can be reproduced exactly without the
+//! original peripherals attached. Useful for headless testing in CI.
+use super::Z80;
+
+/// Something that happened during a recorded run
+#[derive(Debug, PartialEq, Clone)]
+pub enum ReplayEvent {
+    /// A value was read from a peripheral by an instruction starting at the given T-state
+    Input { tstate: u64, port: u16, value: u8 },
+    /// An interrupt was accepted at the given T-state.
+    /// `data` is the byte the interrupting device put on the bus, which NMIs don't use.
+    Interrupt { tstate: u64, nmi: bool, data: u8 },
+}
+
+/// Everything needed to reproduce a run, in the order it happened
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ReplayLog {
+    pub events: Vec<ReplayEvent>,
+}
+
+#[derive(Default)]
+pub(super) enum Replay {
+    #[default]
+    Off,
+    Recording(ReplayLog),
+    Replaying(ReplayLog, usize),
+}
+
+impl Z80 {
+    /// Start recording every input read and interrupt accepted by the processor.
+    /// Any recording or replay already in progress is discarded.
+    pub fn start_recording(&mut self) {
+        self.replay = Replay::Recording(ReplayLog::default());
+    }
+
+    /// Stop recording, returning the log. Returns None if no recording was in progress.
+    pub fn stop_recording(&mut self) -> Option<ReplayLog> {
+        match std::mem::take(&mut self.replay) {
+            Replay::Recording(log) => Some(log),
+            other => {
+                self.replay = other;
+                None
+            }
+        }
+    }
+
+    /// Replay a recorded log. Until the log is exhausted, inputs are served from the log rather
+    /// than the installed devices, which need not be present, and interrupts are raised at the
+    /// recorded times.
+    pub fn start_replay(&mut self, log: ReplayLog) {
+        self.replay = Replay::Replaying(log, 0);
+    }
+
+    /// Returns true while there are events left to replay
+    pub fn is_replaying(&self) -> bool {
+        match &self.replay {
+            Replay::Replaying(log, pos) => *pos < log.events.len(),
+            _ => false,
+        }
+    }
+
+    /// Fetch a replayed input, if a replay is running.
+    ///
+    /// # Panics
+    /// Panics if the program reads from a different port than was recorded
+    pub(super) fn replay_input(&mut self, port: u16) -> Option<u8> {
+        let (log, pos) = match &mut self.replay {
+            Replay::Replaying(log, pos) if *pos < log.events.len() => (log, pos),
+            _ => return None,
+        };
+        match log.events[*pos] {
+            ReplayEvent::Input { port: p, value, .. } => {
+                assert_eq!(
+                    port, p,
+                    "replay desynchronised: expected input from port 0x{:04x}",
+                    p
+                );
+                *pos += 1;
+                Some(value)
+            }
+            ReplayEvent::Interrupt { tstate, .. } => panic!(
+                "replay desynchronised: expected an interrupt at T-state {}",
+                tstate
+            ),
+        }
+    }
+
+    /// Raise the next replayed interrupt, if it is due.
+    /// Returns the byte the interrupting device supplied.
+    pub(super) fn replay_interrupt(&mut self) -> Option<u8> {
+        let (log, pos) = match &mut self.replay {
+            Replay::Replaying(log, pos) if *pos < log.events.len() => (log, pos),
+            _ => return None,
+        };
+        match log.events[*pos] {
+            ReplayEvent::Interrupt { tstate, nmi, data } if tstate <= self.tstates => {
+                *pos += 1;
+                if nmi {
+                    self.nmi_pending = true;
+                } else {
+                    self.int_pending = true;
+                }
+                Some(data)
+            }
+            _ => None,
+        }
+    }
+
+    pub(super) fn record_input(&mut self, port: u16, value: u8) {
+        if let Replay::Recording(log) = &mut self.replay {
+            log.events.push(ReplayEvent::Input {
+                tstate: self.tstates,
+                port,
+                value,
+            });
+        }
+    }
+
+    pub(super) fn record_interrupt(&mut self, nmi: bool, data: u8) {
+        if let Replay::Recording(log) = &mut self.replay {
+            log.events.push(ReplayEvent::Interrupt {
+                tstate: self.tstates,
+                nmi,
+                data,
+            });
+        }
+    }
+}
@@ -1,18 +1,47 @@
 extern crate log;
 use log::debug;
+use std::convert::TryFrom;
 
+use super::trap::FetchOverride;
+use super::variant::CpuVariant;
 use super::Z80;
-use crate::cpu::opcodes;
+use crate::cpu::timing::{self, MachineCycle};
+use crate::frame::FrameTimer;
 use crate::ops::{Op, Reg16, Reg8};
 
 impl Z80 {
     /// Load a function into memory.
     /// This is done by mapping the provided bytes into memory, starting at 0x0000
     /// You only have 16 kibibytes to work with, so be careful!
+    /// If a bus is set, the bytes are written to it instead.
     pub fn load(&mut self, program: &[u8]) {
+        self.load_at(program, 0x0000)
+    }
+
+    /// Load a program into memory, starting at the given origin, then reapply any cheats.
+    /// Addresses wrap around the top of the address space.
+    pub fn load_at(&mut self, program: &[u8], org: u16) {
         for (i, b) in program.iter().enumerate() {
-            self.memory.memory[i] = *b
+            self.write_mem(org.wrapping_add(i as u16), *b)
         }
+        self.apply_cheats();
+    }
+
+    /// Create a processor with a program loaded at the given origin, ready to run from there.
+    /// Handy with `include_bytes!`:
+    /// ```
+    /// use zeerust::z80::Z80;
+    ///
+    /// const ROM: &[u8] = &[0x3E, 0x2A, 0x76]; // Or include_bytes!("rom.bin")
+    /// let mut z80 = Z80::with_program(ROM, 0x0100);
+    /// z80.run();
+    /// assert_eq!(0x0103, z80.registers.get_pc());
+    /// ```
+    pub fn with_program(program: &[u8], org: u16) -> Self {
+        let mut z80 = Self::default();
+        z80.load_at(program, org);
+        z80.registers.set_pc(org);
+        z80
     }
 
     /// Parse the CPU instruction at the given location.
@@ -22,29 +51,99 @@ impl Z80 {
     /// # Panics
     /// Panics if no valid opcode is found and the specified location
     pub fn parse_opcode(&self, location: usize) -> Option<(Op, usize)> {
-        let mem = self.memory.memory;
-        let byte = match mem.get(location) {
-            Some(byte) => *byte,
-            None => return None,
-        };
+        self.opcode_horizon(location)
+            .map(|horizon| match self.decode_horizon(horizon) {
+                Ok(decoded) => decoded,
+                Err(message) => panic!("{}", message),
+            })
+    }
+
+    // The bytes an instruction at the given location could be made of
+    fn opcode_horizon(&self, location: usize) -> Option<[u8; 4]> {
+        if self.bus.is_some() {
+            // The bus decodes the whole address space
+            let addr = u16::try_from(location).ok()?;
+            return Some([
+                self.peek_mem(addr),
+                self.peek_mem(addr.wrapping_add(1)),
+                self.peek_mem(addr.wrapping_add(2)),
+                self.peek_mem(addr.wrapping_add(3)),
+            ]);
+        }
 
-        let opcode_horizon = [
-            byte,
-            mem.get(location + 1).map_or(0x00, |i| *i),
-            mem.get(location + 2).map_or(0x00, |i| *i),
-            mem.get(location + 3).map_or(0x00, |i| *i),
-        ];
-        Some(opcodes::opcode(opcode_horizon))
+        let bytes = self.memory.view(location..)?;
+        if bytes.is_empty() {
+            return None;
+        }
+        let mut opcode_horizon = [0x00; 4];
+        for (i, (h, b)) in opcode_horizon.iter_mut().zip(bytes).enumerate() {
+            *h = self.overlay((location + i) as u16, *b);
+        }
+        Some(opcode_horizon)
     }
 
     /// Execute a single instruction.
-    /// The program counter will be updated to the new position, ready to call step again
+    /// The program counter will be updated to the new position, ready to call step again.
+    /// A pending interrupt is accepted instead of executing an instruction, and a halted
+    /// processor idles for the length of a NOP.
+    /// If a trap is installed at the program counter, it is run first, and then the fetch hook
+    /// is given the chance to replace the instruction.
     ///
     /// # Panics
     /// Panics if program counter is beyond the end of CPU memory
     pub fn step(&mut self) {
+        let _ = self.step_op();
+    }
+
+    // Step, returning the instruction executed at the program counter.
+    // Returns None if an interrupt or trap took its place, or the processor idled.
+    pub(super) fn step_op(&mut self) -> Option<Op> {
         let pc = self.registers.get_pc();
-        let (opc, consumed) = self.parse_opcode(pc as usize).expect("out of memory range");
+        self.record_entry();
+        let before = self.watched_values();
+        let op = self.execute_step();
+        self.check_watches(pc, before);
+        self.follow_bank_switch();
+        op
+    }
+
+    fn execute_step(&mut self) -> Option<Op> {
+        let pc = self.registers.get_pc();
+        self.accesses.clear();
+        let replayed = self.replay_interrupt();
+        let accepted = self.accept_interrupt(replayed);
+        self.ei_delay = false;
+        if accepted {
+            self.end_step(pc);
+            return None;
+        }
+        if self.is_halted {
+            self.cycles = self.machine_cycles(&Op::NOP, false);
+            self.refresh();
+            self.end_step(pc);
+            return None;
+        }
+        if self.run_trap() {
+            return None;
+        }
+        self.executing = None;
+        let (opc, consumed) = match self.fetch_override() {
+            Some(FetchOverride::Exec(op, length)) => (op, usize::from(length)),
+            Some(FetchOverride::Jump(addr)) => {
+                self.cycles.clear();
+                self.registers.set_pc(addr);
+                return None;
+            }
+            None => self.decode(pc),
+        };
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!(
+            "instruction",
+            pc,
+            tstate = self.tstates,
+            op = %crate::cpu::opcodes::table::disassemble(&opc)
+        )
+        .entered();
         debug!("Running {:?}", opc);
         debug!(
             "A: {:02x}, B: {:02x}, C: {:02x}, D: {:02x}, HL: {:04x}, F: {:08b}, PC: {:02x}",
@@ -56,10 +155,58 @@ impl Z80 {
             self.registers.get_reg8(Reg8::F),
             self.registers.get_pc(),
         );
-        let pc = self
-            .exec_with_offset(opc) //dbg!(opc))
-            .unwrap_or(pc + consumed as u16);
-        self.registers.set_pc(pc as u16)
+        let next = self.exec_with_offset(opc.clone(), consumed as u16);
+        if let Some(stats) = &mut self.stats {
+            stats.record(pc, &opc);
+        }
+        self.end_step(pc);
+        self.registers
+            .set_pc(next.unwrap_or_else(|| pc.wrapping_add(consumed as u16)));
+        Some(opc)
+    }
+
+    // Decode the instruction at pc, panicking with the context if that fails.
+    // A Z180 traps instead, which is a restart that pushes pc itself.
+    fn decode(&self, pc: u16) -> (Op, usize) {
+        let horizon = match self.opcode_horizon(pc as usize) {
+            Some(horizon) => horizon,
+            None => std::panic::panic_any(self.fault("out of memory range")),
+        };
+        match self.decode_horizon(horizon) {
+            Ok(decoded) => decoded,
+            Err(_) if self.variant == CpuVariant::Z180 => (Op::RST(0x00), 0),
+            Err(message) => std::panic::panic_any(self.fault(message)),
+        }
+    }
+
+    // Account for the cycles taken by a step that began at pc
+    fn end_step(&mut self, pc: u16) {
+        self.apply_wait_states(pc);
+        self.tstates += u64::from(timing::total(&self.cycles));
+    }
+
+    // Advance the refresh register once for every opcode fetch about to be made
+    pub(super) fn refresh(&mut self) {
+        let fetches = self
+            .cycles
+            .iter()
+            .filter(|c| {
+                c.kind == timing::CycleKind::OpcodeFetch
+                    || c.kind == timing::CycleKind::InterruptAcknowledge
+            })
+            .count();
+        self.registers.refresh(fetches as u8);
+    }
+
+    /// The total number of T-states executed since the emulator was created
+    pub fn tstates(&self) -> u64 {
+        self.tstates
+    }
+
+    /// The machine cycles taken by the most recent call to step, in the order they occurred.
+    /// Empty if no instruction has been stepped yet.
+    pub fn last_cycles(&self) -> &[MachineCycle] {
+        &self.cycles
     }
 
     /// Start executing.
@@ -70,4 +217,24 @@ impl Z80 {
             self.step()
         }
     }
+
+    /// Execute instructions until the frame timer completes a frame.
+    /// A halted processor keeps idling until the end of the frame, as an interrupt may wake it.
+    /// Returns false instead if the processor halts with interrupts disabled, as nothing but
+    /// an NMI can wake it then.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "frame", level = "debug", skip_all)
+    )]
+    pub fn run_frame(&mut self, timer: &mut FrameTimer) -> bool {
+        loop {
+            if self.is_halted && !self.iff1 && !self.nmi_pending {
+                return false;
+            }
+            self.step();
+            if timer.advance(timing::total(&self.cycles)) {
+                return true;
+            }
+        }
+    }
 }
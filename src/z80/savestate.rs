@@ -0,0 +1,579 @@
+//! Savestates: a checkpoint saved as bytes, which keep loading as the emulator changes.
+//! A savestate is `ZSAV` and a little-endian format version, followed by chunks, each a four
+//! byte tag, a little-endian u32 length, and that many bytes:
+//!
+//! | Tag    | Contents                                                                   |
+//! |--------|----------------------------------------------------------------------------|
+//! | `REGS` | A F B C D E H L, the same shadowed, I and R, then IX IY SP PC (LE words)   |
+//! | `INTS` | IFF1, IFF2, the interrupt mode, INT and NMI pending, EI delay and halted   |
+//! | `TIME` | The T-states elapsed, as a little-endian u64                               |
+//! | `MEM ` | Memory, from address 0x0000 (empty when a state chunk holds it)            |
+//!
+//! followed by a chunk for every StateChunk added to the processor, which is how peripherals
+//! and boards save their own state.
+//!
+//! Chunks that a reader doesn't know are skipped, and chunks added later (for MEMPTR, say, or
+//! a new peripheral) are given defaults when an older state doesn't have them, so adding
+//! state doesn't need a new version. A change that can't be made that way bumps the version,
+//! and adds a migration that turns the chunks of the previous version into those of the new
+//! one. States of any older version are migrated up one version at a time as they load. The
+//! migrations see every chunk, those of state chunks included.
+//!
+//! With the `deflate` feature (on by default), a state can also be saved compressed: `ZSVZ`
+//! followed by the whole state, `ZSAV` and all, as a zlib stream (RFC 1950). Mostly empty
+//! memory shrinks to a few hundred bytes. Loading takes either kind.
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::rc::Rc;
+
+use super::checkpoint::{Checkpoint, PAGE_SIZE};
+use super::Z80;
+use crate::cpu::reg::Registers;
+use crate::ops::{Reg16, Reg8};
+
+const SIGNATURE: &[u8; 4] = b"ZSAV";
+const COMPRESSED_SIGNATURE: &[u8; 4] = b"ZSVZ";
+/// The version of the format written
+pub const VERSION: u16 = 1;
+
+// The tags of the chunks the processor saves itself
+const PROCESSOR_CHUNKS: [[u8; 4]; 4] = [*b"REGS", *b"INTS", *b"TIME", *b"MEM "];
+
+// The chunks of a state, by tag
+type Chunks = BTreeMap<[u8; 4], Vec<u8>>;
+
+// MIGRATIONS[n] turns the chunks of version n + 1 into those of version n + 2
+const MIGRATIONS: &[fn(&mut Chunks)] = &[];
+
+const REG8: [Reg8; 18] = [
+    Reg8::A,
+    Reg8::F,
+    Reg8::B,
+    Reg8::C,
+    Reg8::D,
+    Reg8::E,
+    Reg8::H,
+    Reg8::L,
+    Reg8::AP,
+    Reg8::FP,
+    Reg8::BP,
+    Reg8::CP,
+    Reg8::DP,
+    Reg8::EP,
+    Reg8::HP,
+    Reg8::LP,
+    Reg8::I,
+    Reg8::R,
+];
+const REG16: [Reg16; 3] = [Reg16::IX, Reg16::IY, Reg16::SP];
+
+#[derive(Debug, PartialEq)]
+pub enum SaveStateError {
+    /// The data doesn't start with the savestate signature
+    BadSignature,
+    /// The state was saved by a newer version of the format than this one reads
+    UnsupportedVersion(u16),
+    /// The data ends in the middle of a chunk
+    Truncated,
+    /// A chunk that every state needs isn't there, or is too short
+    BadChunk([u8; 4]),
+    /// The state is compressed, and the `deflate` feature is off
+    Compressed,
+    /// A compressed state doesn't decompress
+    BadCompression,
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveStateError::BadSignature => write!(f, "not a savestate"),
+            SaveStateError::UnsupportedVersion(v) => {
+                write!(f, "savestate version {} is not supported", v)
+            }
+            SaveStateError::Truncated => write!(f, "savestate is truncated"),
+            SaveStateError::BadChunk(tag) => write!(
+                f,
+                "savestate chunk {} is missing or bad",
+                String::from_utf8_lossy(tag)
+            ),
+            SaveStateError::Compressed => {
+                write!(f, "compressed savestates need the deflate feature")
+            }
+            SaveStateError::BadCompression => write!(f, "compressed savestate is corrupt"),
+        }
+    }
+}
+
+impl std::error::Error for SaveStateError {}
+
+/// State kept outside the processor, such as a peripheral's, saved in checkpoints and
+/// savestates as a chunk of its own. Add one with `Z80::add_state_chunk`.
+/// A change to the chunk's contents that can't be read by giving new fields defaults needs a
+/// migration, like any other chunk.
+pub trait StateChunk {
+    /// The chunk's tag, which must be none of those the processor uses
+    fn tag(&self) -> [u8; 4];
+
+    /// The state as it is, as the chunk's contents
+    fn save(&self) -> Vec<u8>;
+
+    /// Go back to a state saved by `save`, or to a default if the savestate doesn't have the
+    /// chunk (None). Return `SaveStateError::BadChunk` if it can't be read.
+    /// Chunks are loaded before the processor's memory is written back, so a bus with banking
+    /// has the banks paged in that it had when the memory was saved.
+    fn load(&self, chunk: Option<&[u8]>) -> Result<(), SaveStateError>;
+
+    /// Whether the chunk holds all of the memory behind the bus (banks that aren't paged in
+    /// included), so the processor needn't save what it sees of it. Restoring memory by writing
+    /// through a bus with banking or memory-mapped registers would upset them.
+    fn holds_memory(&self) -> bool {
+        false
+    }
+}
+
+// Reads the fields of a saved state in order, failing with Truncated if it runs out
+pub(crate) struct StateReader<'a>(&'a [u8]);
+
+impl<'a> StateReader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Self(data)
+    }
+
+    pub(crate) fn bytes(&mut self, len: usize) -> Result<&'a [u8], SaveStateError> {
+        if self.0.len() < len {
+            return Err(SaveStateError::Truncated);
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    pub(crate) fn array<const N: usize>(&mut self) -> Result<[u8; N], SaveStateError> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub(crate) fn bool(&mut self) -> Result<bool, SaveStateError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16, SaveStateError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, SaveStateError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    // A flag and a value, as written by write_option
+    pub(crate) fn option(&mut self) -> Result<Option<u8>, SaveStateError> {
+        let present = self.bool()?;
+        let val = self.u8()?;
+        Ok(if present { Some(val) } else { None })
+    }
+
+    // A length-prefixed run of bytes, as written by write_block
+    pub(crate) fn block(&mut self) -> Result<&'a [u8], SaveStateError> {
+        let len = self.u32()? as usize;
+        self.bytes(len)
+    }
+}
+
+// Write an optional byte, for StateReader::option
+pub(crate) fn write_option(out: &mut Vec<u8>, val: Option<u8>) {
+    out.extend_from_slice(&[val.is_some() as u8, val.unwrap_or(0)]);
+}
+
+// Write a run of bytes with its length in front, for StateReader::block
+pub(crate) fn write_block(out: &mut Vec<u8>, block: &[u8]) {
+    out.extend_from_slice(&(block.len() as u32).to_le_bytes());
+    out.extend_from_slice(block);
+}
+
+impl Checkpoint {
+    /// The checkpoint as a savestate
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let regs = &self.registers;
+        let mut registers: Vec<u8> = REG8.iter().map(|r| regs.get_reg8(*r)).collect();
+        for r in &REG16 {
+            registers.extend_from_slice(&regs.get_reg16(r).to_le_bytes());
+        }
+        registers.extend_from_slice(&regs.get_pc().to_le_bytes());
+        let interrupts = [
+            self.iff1 as u8,
+            self.iff2 as u8,
+            self.interrupt_mode,
+            self.int_pending as u8,
+            self.nmi_pending as u8,
+            self.ei_delay as u8,
+            self.is_halted as u8,
+        ];
+        let memory: Vec<u8> = self.pages.iter().flat_map(|p| p.iter().copied()).collect();
+
+        let mut data = SIGNATURE.to_vec();
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        let chunks = [
+            (*b"REGS", &registers[..]),
+            (*b"INTS", &interrupts[..]),
+            (*b"TIME", &self.tstates.to_le_bytes()[..]),
+            (*b"MEM ", &memory[..]),
+        ];
+        let state_chunks = self.chunks.iter().map(|(tag, chunk)| (*tag, &**chunk));
+        for (tag, chunk) in chunks.iter().copied().chain(state_chunks) {
+            data.extend_from_slice(&tag);
+            data.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+            data.extend_from_slice(chunk);
+        }
+        data
+    }
+
+    /// The checkpoint as a compressed savestate
+    #[cfg(feature = "deflate")]
+    pub fn to_compressed_bytes(&self) -> Vec<u8> {
+        compress(&self.to_bytes())
+    }
+
+    /// Read a savestate, compressed or not, of this version of the format or any before it
+    pub fn from_bytes(data: &[u8]) -> Result<Self, SaveStateError> {
+        let decompressed;
+        let data = if data.starts_with(COMPRESSED_SIGNATURE) {
+            decompressed = decompress(data)?;
+            &decompressed[..]
+        } else {
+            data
+        };
+        let (version, mut chunks) = read_chunks(data)?;
+        migrate(&mut chunks, version, MIGRATIONS)?;
+        from_chunks(&chunks)
+    }
+}
+
+#[cfg(feature = "deflate")]
+fn compress(state: &[u8]) -> Vec<u8> {
+    let mut data = COMPRESSED_SIGNATURE.to_vec();
+    data.extend_from_slice(&miniz_oxide::deflate::compress_to_vec_zlib(state, 6));
+    data
+}
+
+#[cfg(feature = "deflate")]
+fn decompress(data: &[u8]) -> Result<Vec<u8>, SaveStateError> {
+    miniz_oxide::inflate::decompress_to_vec_zlib(&data[COMPRESSED_SIGNATURE.len()..])
+        .map_err(|_| SaveStateError::BadCompression)
+}
+
+#[cfg(not(feature = "deflate"))]
+fn decompress(_data: &[u8]) -> Result<Vec<u8>, SaveStateError> {
+    Err(SaveStateError::Compressed)
+}
+
+// The version and chunks of a savestate
+fn read_chunks(data: &[u8]) -> Result<(u16, Chunks), SaveStateError> {
+    if !data.starts_with(SIGNATURE) {
+        return Err(SaveStateError::BadSignature);
+    }
+    let version = data
+        .get(4..6)
+        .map(|v| u16::from_le_bytes([v[0], v[1]]))
+        .ok_or(SaveStateError::Truncated)?;
+    let mut chunks = Chunks::new();
+    let mut rest = &data[6..];
+    while !rest.is_empty() {
+        let header = rest.get(..8).ok_or(SaveStateError::Truncated)?;
+        let tag: [u8; 4] = header[..4].try_into().unwrap();
+        let len = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+        let chunk = rest.get(8..8 + len).ok_or(SaveStateError::Truncated)?;
+        chunks.insert(tag, chunk.to_vec());
+        rest = &rest[8 + len..];
+    }
+    Ok((version, chunks))
+}
+
+// Bring the chunks of a state up to the current version
+fn migrate(
+    chunks: &mut Chunks,
+    version: u16,
+    migrations: &[fn(&mut Chunks)],
+) -> Result<(), SaveStateError> {
+    let current = migrations.len() + 1;
+    if version == 0 || usize::from(version) > current {
+        return Err(SaveStateError::UnsupportedVersion(version));
+    }
+    for migration in &migrations[usize::from(version) - 1..] {
+        migration(chunks);
+    }
+    Ok(())
+}
+
+fn from_chunks(chunks: &Chunks) -> Result<Checkpoint, SaveStateError> {
+    let registers = chunks
+        .get(b"REGS")
+        .filter(|r| r.len() >= 26)
+        .ok_or(SaveStateError::BadChunk(*b"REGS"))?;
+    let mut regs = Registers::default();
+    for (r, v) in REG8.iter().zip(registers) {
+        regs.set_reg8(*r, *v);
+    }
+    let word = |i: usize| u16::from_le_bytes([registers[18 + i * 2], registers[19 + i * 2]]);
+    for (i, r) in REG16.iter().enumerate() {
+        regs.set_reg16(r, word(i));
+    }
+    regs.set_pc(word(3));
+
+    let memory = chunks
+        .get(b"MEM ")
+        .ok_or(SaveStateError::BadChunk(*b"MEM "))?;
+    // As if just reset, if there's no interrupt state
+    let interrupts = chunks.get(b"INTS").map_or(&[][..], Vec::as_slice);
+    let flag = |i: usize| interrupts.get(i).is_some_and(|f| *f != 0);
+    let tstates = chunks
+        .get(b"TIME")
+        .and_then(|t| t.get(..8))
+        .map_or(0, |t| u64::from_le_bytes(t.try_into().unwrap()));
+
+    Ok(Checkpoint {
+        registers: regs,
+        iff1: flag(0),
+        iff2: flag(1),
+        interrupt_mode: interrupts.get(2).copied().unwrap_or(0),
+        int_pending: flag(3),
+        nmi_pending: flag(4),
+        ei_delay: flag(5),
+        is_halted: flag(6),
+        tstates,
+        pages: memory.chunks(PAGE_SIZE).map(Rc::from).collect(),
+        // Whichever state chunks are added will pick theirs out
+        chunks: chunks
+            .iter()
+            .filter(|(tag, _)| !PROCESSOR_CHUNKS.contains(tag))
+            .map(|(tag, chunk)| (*tag, Rc::from(&chunk[..])))
+            .collect(),
+    })
+}
+
+impl Z80 {
+    /// Save the processor and memory as a savestate. For example:
+    /// ```
+    /// use zeerust::z80::Z80;
+    ///
+    /// let mut z80 = Z80::default();
+    /// z80.load(&[0x3E, 0x2A, 0x76]); // LD A, 42; HALT
+    /// let state = z80.save_state();
+    /// z80.run();
+    /// z80.load_state(&state).unwrap();
+    /// assert_eq!(0x0000, z80.registers.get_pc());
+    /// assert!(!z80.is_halted());
+    ///```
+    pub fn save_state(&self) -> Vec<u8> {
+        self.checkpoint().to_bytes()
+    }
+
+    /// Save the processor and memory as a compressed savestate, which load_state also reads
+    #[cfg(feature = "deflate")]
+    pub fn save_state_compressed(&self) -> Vec<u8> {
+        self.checkpoint().to_compressed_bytes()
+    }
+
+    /// Load a savestate, compressed or not. The processor is left as it was if the state
+    /// can't be read, or one of the state chunks can't load its chunk.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), SaveStateError> {
+        let checkpoint = Checkpoint::from_bytes(data)?;
+        self.restore_chunks(&checkpoint)?;
+        self.restore_processor(&checkpoint);
+        Ok(())
+    }
+
+    /// Save and restore another piece of state along with the processor, in checkpoints and
+    /// savestates. For example:
+    /// ```
+    /// use std::cell::Cell;
+    /// use std::rc::Rc;
+    /// use zeerust::z80::savestate::{SaveStateError, StateChunk};
+    /// use zeerust::z80::Z80;
+    ///
+    /// // A peripheral's latch
+    /// struct Latch(Rc<Cell<u8>>);
+    /// impl StateChunk for Latch {
+    ///     fn tag(&self) -> [u8; 4] {
+    ///         *b"LTCH"
+    ///     }
+    ///     fn save(&self) -> Vec<u8> {
+    ///         vec![self.0.get()]
+    ///     }
+    ///     fn load(&self, chunk: Option<&[u8]>) -> Result<(), SaveStateError> {
+    ///         self.0.set(chunk.map_or(0, |c| c[0]));
+    ///         Ok(())
+    ///     }
+    /// }
+    ///
+    /// let latch = Rc::new(Cell::new(0x12));
+    /// let mut z80 = Z80::default();
+    /// z80.add_state_chunk(Box::new(Latch(latch.clone())));
+    /// let state = z80.save_state();
+    /// latch.set(0x34);
+    /// z80.load_state(&state).unwrap();
+    /// assert_eq!(0x12, latch.get());
+    ///```
+    pub fn add_state_chunk(&mut self, chunk: Box<dyn StateChunk>) {
+        self.state_chunks.push(chunk);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn state() -> Z80 {
+        let mut z80 = Z80::default();
+        // LD A, 42; LD (0x2000), A; EI; HALT
+        z80.load(&[0x3E, 0x2A, 0x32, 0x00, 0x20, 0xFB, 0x76]);
+        z80.registers.set_reg16(&Reg16::HLP, 0x1234);
+        z80.run();
+        z80
+    }
+
+    #[test]
+    fn round_trip() {
+        let z80 = state();
+        let data = z80.save_state();
+        assert_eq!(b"ZSAV\x01\x00REGS\x1a\x00\x00\x00\x2a", &data[..15]);
+
+        let mut loaded = Z80::default();
+        loaded.load_state(&data).unwrap();
+        assert_eq!(z80.state_hash(), loaded.state_hash());
+        assert_eq!(z80.tstates(), loaded.tstates());
+        assert!(loaded.is_halted() && loaded.iff1());
+
+        assert_eq!(
+            Err(SaveStateError::Truncated),
+            loaded.load_state(&data[..data.len() - 1])
+        );
+        assert_eq!(
+            Err(SaveStateError::BadSignature),
+            loaded.load_state(b"ZHST")
+        );
+        let mut newer = data.clone();
+        newer[4] = 2;
+        assert_eq!(
+            Err(SaveStateError::UnsupportedVersion(2)),
+            loaded.load_state(&newer)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "deflate")]
+    fn compressed() {
+        let z80 = state();
+        let data = z80.save_state_compressed();
+        assert_eq!(b"ZSVZ", &data[..4]);
+        assert!(data.len() < 500, "{} bytes", data.len());
+
+        let mut loaded = Z80::default();
+        loaded.load_state(&data).unwrap();
+        assert_eq!(z80.state_hash(), loaded.state_hash());
+        assert_eq!(Some(42), loaded.peek(0x2000));
+
+        // The rest is plain zlib
+        let state = miniz_oxide::inflate::decompress_to_vec_zlib(&data[4..]).unwrap();
+        assert_eq!(z80.save_state(), state);
+        assert_eq!(
+            Err(SaveStateError::BadCompression),
+            loaded.load_state(&data[..data.len() - 1])
+        );
+    }
+
+    // A peripheral with a single register, which insists on having been saved
+    struct Register(Rc<std::cell::Cell<u8>>);
+
+    impl StateChunk for Register {
+        fn tag(&self) -> [u8; 4] {
+            *b"PREG"
+        }
+        fn save(&self) -> Vec<u8> {
+            vec![self.0.get()]
+        }
+        fn load(&self, chunk: Option<&[u8]>) -> Result<(), SaveStateError> {
+            let value = chunk.and_then(|c| c.first());
+            self.0
+                .set(*value.ok_or(SaveStateError::BadChunk(*b"PREG"))?);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn state_chunks() {
+        let register = Rc::new(std::cell::Cell::new(0x12));
+        let mut z80 = state();
+        z80.add_state_chunk(Box::new(Register(register.clone())));
+        let data = z80.save_state();
+        let checkpoint = z80.checkpoint();
+        assert_eq!(Some(&[0x12][..]), checkpoint.chunk(b"PREG"));
+        assert_eq!(
+            checkpoint.chunks[0].1.as_ptr(),
+            z80.checkpoint_after(&checkpoint).chunks[0].1.as_ptr()
+        );
+
+        register.set(0x34);
+        z80.load_state(&data).unwrap();
+        assert_eq!(0x12, register.get());
+        register.set(0x34);
+        z80.restore(&checkpoint);
+        assert_eq!(0x12, register.get());
+
+        // A state from before the peripheral, which it won't load
+        let pc = z80.registers.get_pc();
+        assert_eq!(
+            Err(SaveStateError::BadChunk(*b"PREG")),
+            z80.load_state(&Z80::default().save_state())
+        );
+        assert_eq!(pc, z80.registers.get_pc());
+
+        // Migrations see the peripheral's chunk too
+        let (_, mut chunks) = read_chunks(&z80.save_state()).unwrap();
+        let widen: fn(&mut Chunks) = |chunks| {
+            let old = chunks.remove(b"PREG").unwrap();
+            chunks.insert(*b"PREG", vec![old[0], 0x00]);
+        };
+        migrate(&mut chunks, 1, &[widen]).unwrap();
+        assert_eq!(Some(&vec![0x12, 0x00]), chunks.get(b"PREG"));
+    }
+
+    #[test]
+    fn compatibility() {
+        let z80 = state();
+        let (version, mut chunks) = read_chunks(&z80.save_state()).unwrap();
+        assert_eq!(VERSION, version);
+
+        // Chunks from the future are ignored, and missing ones get defaults
+        chunks.insert(*b"MPTR", vec![0x00, 0x20]);
+        chunks.remove(b"INTS");
+        chunks.remove(b"TIME");
+        let loaded = from_chunks(&chunks).unwrap();
+        assert!(!loaded.is_halted && !loaded.iff1);
+        assert_eq!(0, loaded.tstates);
+        chunks.remove(b"MEM ");
+        assert_eq!(
+            Err(SaveStateError::BadChunk(*b"MEM ")),
+            from_chunks(&chunks).map(|_| ())
+        );
+
+        // A version 1 state, migrated by a version 2 that moved the registers
+        let (_, mut chunks) = read_chunks(&z80.save_state()).unwrap();
+        let rename: fn(&mut Chunks) = |chunks| {
+            let regs = chunks.remove(b"REGS").unwrap();
+            chunks.insert(*b"REG2", regs);
+        };
+        migrate(&mut chunks, 1, &[rename]).unwrap();
+        assert!(chunks.contains_key(b"REG2") && !chunks.contains_key(b"REGS"));
+        let mut unchanged = chunks.clone();
+        migrate(&mut unchanged, 2, &[rename]).unwrap();
+        assert_eq!(chunks, unchanged);
+        assert_eq!(
+            Err(SaveStateError::UnsupportedVersion(3)),
+            migrate(&mut chunks, 3, &[rename])
+        );
+    }
+}
@@ -0,0 +1,283 @@
+//! Guarding the stack.
+//! Runaway recursion or unbalanced pushes and pops usually go unnoticed until the stack has
+//! trampled over something important. A StackGuard catches the moment the stack pointer leaves
+//! its bounds, wraps around the address space, or a push lands on the program's own code.
+use std::fmt;
+use std::ops::{Range, RangeInclusive};
+
+use super::Z80;
+use crate::cpu::mem::MEMORY_SIZE;
+use crate::ops::Reg16;
+
+/// Where the stack is allowed to be.
+/// The default allows the whole address space. Without a bus, the stack is also kept within
+/// `memory`. The stack pointer may wrap around the address space (`LD SP, 0` puts the first push
+/// at 0xFFFE), as long as it stays within the bounds.
+#[derive(Debug, PartialEq, Clone)]
+pub struct StackGuard {
+    /// The lowest the stack pointer may go
+    pub low: u16,
+    /// The highest the stack pointer may go; the empty stack
+    pub high: u16,
+    /// Addresses that pushes must never write to
+    pub code: Option<Range<u16>>,
+}
+
+impl Default for StackGuard {
+    fn default() -> Self {
+        Self {
+            low: 0x0000,
+            high: 0xFFFF,
+            code: None,
+        }
+    }
+}
+
+/// Something the stack did that it shouldn't have.
+/// `pc` is where the offending instruction (or interrupt) began.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StackFault {
+    /// A push took the stack pointer below the lower bound
+    Overflow { pc: u16, sp: u16 },
+    /// A pop took the stack pointer above the upper bound
+    Underflow { pc: u16, sp: u16 },
+    /// The stack pointer wrapped around the top or bottom of the address space, out of bounds
+    Wrap { pc: u16, sp: u16 },
+    /// A push wrote to the protected code
+    CodeOverwrite { pc: u16, addr: u16 },
+}
+
+impl fmt::Display for StackFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StackFault::Overflow { pc, sp } => {
+                write!(f, "stack overflow at {:04x}: SP is {:04x}", pc, sp)
+            }
+            StackFault::Underflow { pc, sp } => {
+                write!(f, "stack underflow at {:04x}: SP is {:04x}", pc, sp)
+            }
+            StackFault::Wrap { pc, sp } => {
+                write!(f, "stack wrapped around at {:04x}: SP is {:04x}", pc, sp)
+            }
+            StackFault::CodeOverwrite { pc, addr } => {
+                write!(
+                    f,
+                    "stack push at {:04x} overwrites code at {:04x}",
+                    pc, addr
+                )
+            }
+        }
+    }
+}
+
+/// A stack hook is handed the processor, before the offending push or pop is carried out
+pub type StackHook = Box<dyn FnMut(&Z80, StackFault)>;
+
+impl Z80 {
+    /// Start checking every push and pop against the guard.
+    /// Faults are passed to the hook set with on_stack_fault. If there is none, the fault is raised
+    /// as the panic's payload.
+    /// ```
+    /// use zeerust::z80::stack::StackGuard;
+    /// use zeerust::z80::Z80;
+    ///
+    /// let mut z80 = Z80::default();
+    /// z80.load(&[0xC5, 0x76]); // PUSH BC; HALT
+    /// // Keep the stack above 0x3000, and off the code
+    /// z80.set_stack_guard(StackGuard {
+    ///     low: 0x3000,
+    ///     code: Some(0x0000..0x0002),
+    ///     ..StackGuard::default()
+    /// });
+    /// z80.run();
+    ///```
+    pub fn set_stack_guard(&mut self, guard: StackGuard) {
+        self.stack_guard = Some(guard);
+    }
+
+    /// Stop checking the stack
+    pub fn clear_stack_guard(&mut self) {
+        self.stack_guard = None;
+    }
+
+    /// Report stack faults to the given hook rather than panicking
+    pub fn on_stack_fault(&mut self, hook: StackHook) {
+        self.stack_hook = Some(hook);
+    }
+
+    // Check a push, which is about to move the stack pointer down by two
+    pub(super) fn check_push(&mut self) {
+        let guard = match &self.stack_guard {
+            Some(guard) => guard,
+            None => return,
+        };
+        let pc = self.registers.get_pc();
+        let sp = self.registers.get_reg16(&Reg16::SP);
+        let new = sp.wrapping_sub(2);
+        let fault = if sp < 2 && !self.stack_bounds(guard).contains(&new) {
+            Some(StackFault::Wrap { pc, sp: new })
+        } else if new < guard.low {
+            Some(StackFault::Overflow { pc, sp: new })
+        } else {
+            guard.code.as_ref().and_then(|code| {
+                [new, new.wrapping_add(1)]
+                    .iter()
+                    .find(|addr| code.contains(addr))
+                    .map(|addr| StackFault::CodeOverwrite { pc, addr: *addr })
+            })
+        };
+        if let Some(fault) = fault {
+            self.stack_fault(fault);
+        }
+    }
+
+    // Check a pop, which is about to move the stack pointer up by two
+    pub(super) fn check_pop(&mut self) {
+        let guard = match &self.stack_guard {
+            Some(guard) => guard,
+            None => return,
+        };
+        let pc = self.registers.get_pc();
+        let sp = self.registers.get_reg16(&Reg16::SP);
+        let new = sp.wrapping_add(2);
+        let bounds = self.stack_bounds(guard);
+        let fault = if sp > 0xFFFD && !bounds.contains(&new) {
+            Some(StackFault::Wrap { pc, sp: new })
+        } else if new > *bounds.end() {
+            Some(StackFault::Underflow { pc, sp: new })
+        } else {
+            None
+        };
+        if let Some(fault) = fault {
+            self.stack_fault(fault);
+        }
+    }
+
+    // The guard's bounds, kept within memory when there's no bus
+    fn stack_bounds(&self, guard: &StackGuard) -> RangeInclusive<u16> {
+        match self.bus {
+            Some(_) => guard.low..=guard.high,
+            None => guard.low..=guard.high.min(MEMORY_SIZE as u16),
+        }
+    }
+
+    fn stack_fault(&mut self, fault: StackFault) {
+        match self.stack_hook.take() {
+            Some(mut hook) => {
+                hook(self, fault);
+                self.stack_hook = Some(hook);
+            }
+            None => std::panic::panic_any(fault),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::FlatRam;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn faults(z80: &mut Z80) -> Rc<RefCell<Vec<StackFault>>> {
+        let faults = Rc::new(RefCell::new(vec![]));
+        let f = faults.clone();
+        z80.on_stack_fault(Box::new(move |_, fault| f.borrow_mut().push(fault)));
+        faults
+    }
+
+    #[test]
+    fn bounds() {
+        let mut z80 = Z80::default();
+        // PUSH BC; PUSH BC; POP BC; POP BC; HALT
+        z80.load(&[0xC5, 0xC5, 0xC1, 0xC1, 0x76]);
+        // Room for one push
+        z80.set_stack_guard(StackGuard {
+            low: 0x3FFE,
+            high: 0x3FFE,
+            code: None,
+        });
+        let faults = faults(&mut z80);
+        z80.run();
+        assert_eq!(
+            vec![
+                StackFault::Overflow {
+                    pc: 0x0001,
+                    sp: 0x3FFC
+                },
+                StackFault::Underflow {
+                    pc: 0x0003,
+                    sp: 0x4000
+                },
+            ],
+            *faults.borrow()
+        );
+    }
+
+    #[test]
+    fn code_overwrite() {
+        let mut z80 = Z80::default();
+        // LD SP, 0x0005; PUSH BC; HALT
+        z80.load(&[0x31, 0x05, 0x00, 0xC5, 0x76]);
+        z80.set_stack_guard(StackGuard {
+            code: Some(0x0000..0x0005),
+            ..StackGuard::default()
+        });
+        let faults = faults(&mut z80);
+        z80.step();
+        z80.step();
+        assert_eq!(
+            vec![StackFault::CodeOverwrite {
+                pc: 0x0003,
+                addr: 0x0003
+            }],
+            *faults.borrow()
+        );
+    }
+
+    #[test]
+    fn top_of_memory() {
+        let machine = |guard| {
+            let mut z80 = Z80::default();
+            z80.set_bus(Box::new(FlatRam::new()));
+            // LD SP, 0; PUSH BC; POP BC; LD SP, 0x8000; POP BC; HALT
+            z80.load(&[0x31, 0x00, 0x00, 0xC5, 0xC1, 0x31, 0x00, 0x80, 0xC1, 0x76]);
+            z80.set_stack_guard(guard);
+            let faults = faults(&mut z80);
+            z80.run();
+            faults
+        };
+        assert!(machine(StackGuard::default()).borrow().is_empty());
+
+        // Unless the top of memory is out of bounds
+        let faults = machine(StackGuard {
+            high: 0xC000,
+            ..StackGuard::default()
+        });
+        assert_eq!(
+            vec![StackFault::Wrap {
+                pc: 0x0003,
+                sp: 0xFFFE
+            }],
+            *faults.borrow()
+        );
+    }
+
+    #[test]
+    fn unhooked() {
+        let mut z80 = Z80::default();
+        z80.load(&[0xC1]); // POP BC
+        z80.set_stack_guard(StackGuard::default());
+        let payload = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            z80.step();
+        }))
+        .unwrap_err();
+        assert_eq!(
+            Some(&StackFault::Underflow {
+                pc: 0x0000,
+                sp: 0x4002
+            }),
+            payload.downcast_ref::<StackFault>()
+        );
+    }
+}
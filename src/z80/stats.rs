@@ -0,0 +1,166 @@
+//! Counting what a program executes: how often each kind of instruction runs, and how often
+//! each address is executed. The first shows which instructions a program leans on (and which
+//! of the emulator's implementations it exercises), and the second is a heatmap of where it
+//! spends its time. Both can be exported as CSV or JSON for other tools.
+use std::collections::BTreeMap;
+
+use super::Z80;
+use crate::debug::json::Value;
+use crate::ops::Op;
+
+/// Execution counts, collected while the processor runs. See Z80::collect_stats.
+#[derive(Debug, Clone)]
+pub struct Stats {
+    // By instruction name, such as "LD8" or "DJNZ"
+    by_op: BTreeMap<String, u64>,
+    // By the address each instruction began at
+    by_addr: Vec<u64>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            by_op: BTreeMap::new(),
+            by_addr: vec![0; 0x10000],
+        }
+    }
+}
+
+impl Stats {
+    pub(super) fn record(&mut self, pc: u16, op: &Op) {
+        *self.by_op.entry(op_name(op)).or_insert(0) += 1;
+        self.by_addr[usize::from(pc)] += 1;
+    }
+
+    /// The number of instructions executed
+    pub fn total(&self) -> u64 {
+        self.by_op.values().sum()
+    }
+
+    /// How often each kind of instruction was executed, by name (as in Op), in name order.
+    /// Kinds that were never executed are left out.
+    pub fn ops(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.by_op
+            .iter()
+            .map(|(name, count)| (name.as_str(), *count))
+    }
+
+    /// How often the instruction at each address was executed, in address order.
+    /// Addresses that were never executed are left out.
+    pub fn addresses(&self) -> impl Iterator<Item = (u16, u64)> + '_ {
+        self.by_addr
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(addr, count)| (addr as u16, *count))
+    }
+
+    /// How often the instruction at an address was executed
+    pub fn count_at(&self, addr: u16) -> u64 {
+        self.by_addr[usize::from(addr)]
+    }
+
+    /// The instruction counts as CSV, with an `op,count` header
+    pub fn ops_csv(&self) -> String {
+        let mut csv = "op,count\n".to_string();
+        for (name, count) in self.ops() {
+            csv.push_str(&format!("{},{}\n", name, count));
+        }
+        csv
+    }
+
+    /// The heatmap as CSV, with an `address,count` header and the addresses in hex
+    pub fn addresses_csv(&self) -> String {
+        let mut csv = "address,count\n".to_string();
+        for (addr, count) in self.addresses() {
+            csv.push_str(&format!("{:04x},{}\n", addr, count));
+        }
+        csv
+    }
+
+    /// Everything as a JSON object: the `total`, `ops` as an object of counts by name, and
+    /// `addresses` as an array of `[address, count]` pairs
+    pub fn to_json(&self) -> String {
+        let ops = self
+            .ops()
+            .map(|(name, count)| (name, count.into()))
+            .collect();
+        let addresses = self
+            .addresses()
+            .map(|(addr, count)| Value::Array(vec![addr.into(), count.into()]))
+            .collect();
+        Value::object(vec![
+            ("total", self.total().into()),
+            ("ops", Value::object(ops)),
+            ("addresses", Value::Array(addresses)),
+        ])
+        .to_string()
+    }
+}
+
+// An instruction's name, without its operands
+fn op_name(op: &Op) -> String {
+    let debug = format!("{:?}", op);
+    match debug.find('(') {
+        Some(i) => debug[..i].to_string(),
+        None => debug,
+    }
+}
+
+impl Z80 {
+    /// Start counting the instructions executed, forgetting any counts so far. For example:
+    /// ```
+    /// use zeerust::z80::Z80;
+    ///
+    /// let mut z80 = Z80::default();
+    /// z80.load(&[0x06, 0x03, 0x10, 0xFE, 0x76]); // LD B, 3; DJNZ -2; HALT
+    /// z80.collect_stats();
+    /// z80.run();
+    /// let stats = z80.stats().unwrap();
+    /// assert_eq!(3, stats.count_at(0x0002));
+    /// assert_eq!("op,count\nDJNZ,3\nHALT,1\nLD8,1\n", stats.ops_csv());
+    ///```
+    pub fn collect_stats(&mut self) {
+        self.stats = Some(Box::default());
+    }
+
+    /// The counts so far, if they're being collected
+    pub fn stats(&self) -> Option<&Stats> {
+        self.stats.as_deref()
+    }
+
+    /// Stop counting, returning the counts
+    pub fn take_stats(&mut self) -> Option<Stats> {
+        self.stats.take().map(|stats| *stats)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counts() {
+        let mut z80 = Z80::default();
+        // LD A, 2; loop: DEC A; JR NZ, loop; HALT
+        z80.load(&[0x3E, 0x02, 0x3D, 0x20, 0xFD, 0x76]);
+        z80.step();
+        z80.collect_stats();
+        z80.run();
+        let stats = z80.take_stats().unwrap();
+        assert_eq!(5, stats.total());
+        assert_eq!(
+            vec![(0x0002, 2), (0x0003, 2), (0x0005, 1)],
+            stats.addresses().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            "address,count\n0002,2\n0003,2\n0005,1\n",
+            stats.addresses_csv()
+        );
+        assert_eq!(
+            r#"{"total":5,"ops":{"DEC":2,"HALT":1,"JR":2},"addresses":[[2,2],[3,2],[5,1]]}"#,
+            stats.to_json()
+        );
+        assert!(z80.stats().is_none());
+    }
+}
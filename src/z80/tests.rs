@@ -1,14 +1,15 @@
 use super::Z80;
 use crate::ops::{JumpConditional, Location16, Location8, Op, Reg16, Reg8, StatusFlag};
+use crate::testing::FlatRam;
 
 #[test]
 fn get_loc8() {
     let mut z80 = Z80::default();
     z80.registers.set_reg8(Reg8::A, 0xC5);
-    z80.registers.set_reg8(Reg8::H, 0xAA);
-    z80.registers.set_reg8(Reg8::L, 0x0F);
-    z80.memory.memory[0x0FAA] = 0xD1;
-    z80.memory.memory[0x0DCC] = 0x75;
+    z80.registers.set_reg8(Reg8::H, 0x0F);
+    z80.registers.set_reg8(Reg8::L, 0xAA);
+    z80.memory.write_u8(0x0FAA, 0xD1);
+    z80.memory.write_u8(0x0DCC, 0x75);
 
     assert_hex!(0xC5, z80.get_loc8(&Location8::Reg(Reg8::A)));
     assert_hex!(0xD1, z80.get_loc8(&Location8::RegIndirect(Reg16::HL)));
@@ -19,14 +20,14 @@ fn get_loc8() {
 #[test]
 fn get_loc16() {
     let mut z80 = Z80::default();
-    z80.registers.set_reg8(Reg8::H, 0xCC);
-    z80.registers.set_reg8(Reg8::L, 0x0D);
+    z80.registers.set_reg8(Reg8::H, 0x0D);
+    z80.registers.set_reg8(Reg8::L, 0xCC);
 
     assert_hex!(0x0DCC, z80.get_loc16(&Location16::Reg(Reg16::HL)));
     assert_hex!(0xF0C5, z80.get_loc16(&Location16::Immediate(0xF0C5)));
 
-    z80.memory.memory[0x0545] = 0x37;
-    z80.memory.memory[0x0546] = 0xA1;
+    z80.memory.write_u8(0x0545, 0x37);
+    z80.memory.write_u8(0x0546, 0xA1);
     assert_hex!(
         0xa137,
         z80.get_loc16(&Location16::ImmediateIndirect(0x0545))
@@ -55,14 +56,14 @@ fn set_loc8() {
     z80.set_loc8(&Location8::Reg(Reg8::A), 0xDD);
     assert_hex!(0xDD, z80.registers.get_reg8(Reg8::A));
 
-    z80.registers.set_reg8(Reg8::H, 0x11);
-    z80.registers.set_reg8(Reg8::L, 0x0A);
+    z80.registers.set_reg8(Reg8::H, 0x0A);
+    z80.registers.set_reg8(Reg8::L, 0x11);
 
     z80.set_loc8(&Location8::RegIndirect(Reg16::HL), 0xEE);
-    assert_hex!(0xEE, z80.memory.memory[0x0A11]);
+    assert_hex!(0xEE, z80.memory.read_u8(0x0A11));
 
     z80.set_loc8(&Location8::ImmediateIndirect(0x0C22), 0xF5);
-    assert_hex!(0xF5, z80.memory.memory[0x0C22]);
+    assert_hex!(0xF5, z80.memory.read_u8(0x0C22));
 }
 
 #[test]
@@ -79,8 +80,8 @@ fn set_loc16() {
     assert_hex!(0xDDEE, z80.registers.get_reg16(&Reg16::DE));
 
     z80.set_loc16(&Location16::ImmediateIndirect(0x1000), 0x4644);
-    assert_hex!(0x44, z80.memory.memory[0x1000]);
-    assert_hex!(0x46, z80.memory.memory[0x1001]);
+    assert_hex!(0x44, z80.memory.read_u8(0x1000));
+    assert_hex!(0x46, z80.memory.read_u8(0x1001));
 }
 
 #[test]
@@ -98,8 +99,8 @@ fn ld16_op() {
         Location16::Immediate(0xF5C5),
     ));
     assert_hex!(0xF5C5, z80.registers.get_reg16(&Reg16::SP));
-    z80.memory.memory[0x2130] = 0x65;
-    z80.memory.memory[0x2131] = 0x78;
+    z80.memory.write_u8(0x2130, 0x65);
+    z80.memory.write_u8(0x2131, 0x78);
 
     z80.exec(Op::LD16(
         Location16::Reg(Reg16::BC),
@@ -114,8 +115,8 @@ fn push_op() {
     z80.registers.set_reg16(&Reg16::AF, 0x2233);
     z80.registers.set_reg16(&Reg16::SP, 0x1007);
     z80.exec(Op::PUSH(Location16::Reg(Reg16::AF)));
-    assert_hex!(0x22, z80.memory.memory[0x1006]);
-    assert_hex!(0x33, z80.memory.memory[0x1005]);
+    assert_hex!(0x22, z80.memory.read_u8(0x1006));
+    assert_hex!(0x33, z80.memory.read_u8(0x1005));
     assert_hex!(0x1005, z80.registers.get_reg16(&Reg16::SP));
 }
 
@@ -123,13 +124,30 @@ fn push_op() {
 fn pop_op() {
     let mut z80 = Z80::default();
     z80.registers.set_reg16(&Reg16::SP, 0x1000);
-    z80.memory.memory[0x1000] = 0x55;
-    z80.memory.memory[0x1001] = 0x33;
+    z80.memory.write_u8(0x1000, 0x55);
+    z80.memory.write_u8(0x1001, 0x33);
     z80.exec(Op::POP(Location16::Reg(Reg16::HL)));
     assert_hex!(0x3355, z80.registers.get_reg16(&Reg16::HL));
     assert_hex!(0x1002, z80.registers.get_reg16(&Reg16::SP));
 }
 
+#[test]
+fn ex_sp_op() {
+    let mut z80 = Z80::default();
+    z80.registers.set_reg16(&Reg16::SP, 0x1000);
+    z80.memory.write_u8(0x1000, 0x55);
+    z80.memory.write_u8(0x1001, 0x33);
+    z80.registers.set_reg16(&Reg16::IX, 0xABCD);
+    z80.exec(Op::EX(
+        Location16::RegIndirect(Reg16::SP),
+        Location16::Reg(Reg16::IX),
+    ));
+    assert_hex!(0x3355, z80.registers.get_reg16(&Reg16::IX));
+    assert_hex!(0xCD, z80.memory.read_u8(0x1000));
+    assert_hex!(0xAB, z80.memory.read_u8(0x1001));
+    assert_hex!(0x1000, z80.registers.get_reg16(&Reg16::SP));
+}
+
 #[test]
 fn add8_op() {
     let mut z80 = Z80::default();
@@ -169,13 +187,13 @@ fn add8_op() {
 #[test]
 fn inc_op() {
     let mut z80 = Z80::default();
-    z80.registers.set_reg8(Reg8::H, 0xCC);
-    z80.registers.set_reg8(Reg8::L, 0x20);
-    z80.memory.memory[0x20CC] = 0xFF;
+    z80.registers.set_reg8(Reg8::H, 0x20);
+    z80.registers.set_reg8(Reg8::L, 0xCC);
+    z80.memory.write_u8(0x20CC, 0xFF);
 
     z80.exec(Op::INC(Location8::RegIndirect(Reg16::HL)));
 
-    assert_hex!(0x00, z80.memory.memory[0x20CC]);
+    assert_hex!(0x00, z80.memory.read_u8(0x20CC));
     assert_flags!(
         z80.registers,
         Sign = false,
@@ -229,7 +247,7 @@ fn sub8_op() {
         Location8::Reg(Reg8::A),
         Location8::Immediate(0x0B), // 11
     ));
-    assert_bin!((0xFF) as u8, z80.registers.get_reg8(Reg8::A)); // -1
+    assert_bin!(0xFF_u8, z80.registers.get_reg8(Reg8::A)); // -1
     assert_flags!(
         z80.registers,
         Sign = true,
@@ -303,13 +321,36 @@ fn and_op() {
         z80.registers,
         Sign = false,
         Zero = true,
-        HalfCarry = false,
+        HalfCarry = true,
         ParityOverflow = true,
         AddSubtract = false,
         Carry = false,
     );
 }
 
+#[test]
+fn and_op_half_carry() {
+    let mut z80 = Z80::default();
+    z80.registers.set_flag(&StatusFlag::Carry, true);
+    z80.registers.set_reg8(Reg8::A, 0b1111_0000);
+    z80.registers.set_reg8(Reg8::B, 0b1010_1010);
+    z80.exec(Op::AND(Location8::Reg(Reg8::B)));
+    assert_bin!(0b1010_0000, z80.registers.get_reg8(Reg8::A));
+    assert_flags!(
+        z80.registers,
+        Sign = true,
+        Zero = false,
+        HalfCarry = true,
+        ParityOverflow = true,
+        AddSubtract = false,
+        Carry = false,
+    );
+
+    // OR clears it again
+    z80.exec(Op::OR(Location8::Reg(Reg8::B)));
+    assert!(!z80.registers.get_flag(&StatusFlag::HalfCarry));
+}
+
 #[test]
 fn or_op() {
     let mut z80 = Z80::default();
@@ -665,15 +706,15 @@ fn sra_op() {
 #[test]
 fn rld_op() {
     let mut z80 = Z80::default();
-    z80.registers.set_reg8(Reg8::H, 0xCC);
-    z80.registers.set_reg8(Reg8::L, 0x20);
+    z80.registers.set_reg8(Reg8::H, 0x20);
+    z80.registers.set_reg8(Reg8::L, 0xCC);
     z80.registers.set_reg8(Reg8::A, 0b0111_1010);
-    z80.memory.memory[0x20CC] = 0b0011_0001;
+    z80.memory.write_u8(0x20CC, 0b0011_0001);
 
     z80.exec(Op::RLD);
 
     assert_bin!(0b0111_0011, z80.registers.get_reg8(Reg8::A));
-    assert_bin!(0b0001_1010, z80.memory.memory[0x20CC]);
+    assert_bin!(0b0001_1010, z80.memory.read_u8(0x20CC));
     assert_flags!(
         z80.registers,
         Sign = false,
@@ -684,15 +725,15 @@ fn rld_op() {
     );
 
     // Zero accumulator
-    z80.registers.set_reg8(Reg8::H, 0xCC);
-    z80.registers.set_reg8(Reg8::L, 0x20);
+    z80.registers.set_reg8(Reg8::H, 0x20);
+    z80.registers.set_reg8(Reg8::L, 0xCC);
     z80.registers.set_reg8(Reg8::A, 0b0000_1010);
-    z80.memory.memory[0x20CC] = 0b0000_1110;
+    z80.memory.write_u8(0x20CC, 0b0000_1110);
 
     z80.exec(Op::RLD);
 
     assert_bin!(0b0000_0000, z80.registers.get_reg8(Reg8::A));
-    assert_bin!(0b1110_1010, z80.memory.memory[0x20CC]);
+    assert_bin!(0b1110_1010, z80.memory.read_u8(0x20CC));
     assert_flags!(
         z80.registers,
         Sign = false,
@@ -706,15 +747,15 @@ fn rld_op() {
 #[test]
 fn rrd_op() {
     let mut z80 = Z80::default();
-    z80.registers.set_reg8(Reg8::H, 0xCC);
-    z80.registers.set_reg8(Reg8::L, 0x20);
+    z80.registers.set_reg8(Reg8::H, 0x20);
+    z80.registers.set_reg8(Reg8::L, 0xCC);
     z80.registers.set_reg8(Reg8::A, 0b1000_0100);
-    z80.memory.memory[0x20CC] = 0b0010_0000;
+    z80.memory.write_u8(0x20CC, 0b0010_0000);
 
     z80.exec(Op::RRD);
 
     assert_bin!(0b1000_0000, z80.registers.get_reg8(Reg8::A));
-    assert_bin!(0b0100_0010, z80.memory.memory[0x20CC]);
+    assert_bin!(0b0100_0010, z80.memory.read_u8(0x20CC));
     assert_flags!(
         z80.registers,
         Sign = true,
@@ -818,11 +859,17 @@ fn in_op() {
     assert_hex!(0xBB, z80.registers.get_reg8(Reg8::A));
 }
 
+// The fault raised by running f
+fn fault_of<F: FnOnce()>(f: F) -> super::fault::Fault {
+    let payload = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_err();
+    *payload.downcast().unwrap()
+}
+
 #[test]
-#[should_panic(expected = "no peripheral installed in 0x00")]
 fn in_no_device_installed() {
     let mut z80 = Z80::default();
-    z80.exec(Op::IN(Location8::Reg(Reg8::A), Location8::Immediate(0x00)));
+    let fault = fault_of(|| z80.exec(Op::IN(Location8::Reg(Reg8::A), Location8::Immediate(0x00))));
+    assert_eq!("no peripheral installed in 0x00", fault.message);
 }
 
 #[test]
@@ -852,10 +899,10 @@ fn out_op() {
 }
 
 #[test]
-#[should_panic(expected = "no peripheral installed in 0x00")]
 fn out_no_device_installed() {
     let mut z80 = Z80::default();
-    z80.exec(Op::OUT(Location8::Reg(Reg8::A), Location8::Immediate(0x00)));
+    let fault = fault_of(|| z80.exec(Op::OUT(Location8::Reg(Reg8::A), Location8::Immediate(0x00))));
+    assert_eq!("no peripheral installed in 0x00", fault.message);
 }
 
 #[test]
@@ -870,22 +917,33 @@ fn jp() {
     let mut z80 = Z80::default();
     assert_eq!(
         Some(0x0CFF),
-        z80.exec_with_offset(Op::JP(
-            JumpConditional::Unconditional,
-            Location16::Immediate(0x0CFF)
-        )),
+        z80.exec_with_offset(
+            Op::JP(
+                JumpConditional::Unconditional,
+                Location16::Immediate(0x0CFF)
+            ),
+            3
+        ),
     );
 
     z80.set_loc16(&Location16::Reg(Reg16::HL), 0xABBA); // Dancing queen
     assert_eq!(
         Some(0xABBA),
-        z80.exec_with_offset(Op::JP(
-            JumpConditional::Unconditional,
-            Location16::Reg(Reg16::HL)
-        )),
+        z80.exec_with_offset(
+            Op::JP(JumpConditional::Unconditional, Location16::Reg(Reg16::HL)),
+            3
+        ),
     )
 }
 
+#[test]
+fn jpi() {
+    let mut z80 = Z80::default();
+    z80.registers.set_reg16(&Reg16::IX, 0x1234);
+    z80.memory.write_u8(0x1234, 0xFF);
+    assert_eq!(Some(0x1234), z80.exec_with_offset(Op::JPI(Reg16::IX), 1));
+}
+
 #[test]
 fn jp_conditional() {
     let mut z80 = Z80::default();
@@ -893,38 +951,38 @@ fn jp_conditional() {
     z80.registers.set_flag(&StatusFlag::Zero, true);
     let op1 = Op::JP(JumpConditional::Zero, Location16::Immediate(0x0CFF));
     let op2 = Op::JP(JumpConditional::NonZero, Location16::Immediate(0x0CDD));
-    assert_eq!(Some(0x0CFF), z80.exec_with_offset(op1.clone()));
-    assert_eq!(None, z80.exec_with_offset(op2.clone()));
+    assert_eq!(Some(0x0CFF), z80.exec_with_offset(op1.clone(), 3));
+    assert_eq!(None, z80.exec_with_offset(op2.clone(), 3));
     z80.registers.set_flag(&StatusFlag::Zero, false);
-    assert_eq!(None, z80.exec_with_offset(op1));
-    assert_eq!(Some(0x0CDD), z80.exec_with_offset(op2));
+    assert_eq!(None, z80.exec_with_offset(op1, 3));
+    assert_eq!(Some(0x0CDD), z80.exec_with_offset(op2, 3));
 
     z80.registers.set_flag(&StatusFlag::Carry, true);
     let op1 = Op::JP(JumpConditional::Carry, Location16::Immediate(0x0CFF));
     let op2 = Op::JP(JumpConditional::NoCarry, Location16::Immediate(0x0CDD));
-    assert_eq!(Some(0x0CFF), z80.exec_with_offset(op1.clone()));
-    assert_eq!(None, z80.exec_with_offset(op2.clone()));
+    assert_eq!(Some(0x0CFF), z80.exec_with_offset(op1.clone(), 3));
+    assert_eq!(None, z80.exec_with_offset(op2.clone(), 3));
     z80.registers.set_flag(&StatusFlag::Carry, false);
-    assert_eq!(None, z80.exec_with_offset(op1));
-    assert_eq!(Some(0x0CDD), z80.exec_with_offset(op2));
+    assert_eq!(None, z80.exec_with_offset(op1, 3));
+    assert_eq!(Some(0x0CDD), z80.exec_with_offset(op2, 3));
 
     z80.registers.set_flag(&StatusFlag::ParityOverflow, true);
     let op1 = Op::JP(JumpConditional::ParityEven, Location16::Immediate(0x0CDD));
     let op2 = Op::JP(JumpConditional::ParityOdd, Location16::Immediate(0x0CFF));
-    assert_eq!(Some(0x0CDD), z80.exec_with_offset(op1.clone()));
-    assert_eq!(None, z80.exec_with_offset(op2.clone()));
+    assert_eq!(Some(0x0CDD), z80.exec_with_offset(op1.clone(), 3));
+    assert_eq!(None, z80.exec_with_offset(op2.clone(), 3));
     z80.registers.set_flag(&StatusFlag::ParityOverflow, false);
-    assert_eq!(None, z80.exec_with_offset(op1));
-    assert_eq!(Some(0x0CFF), z80.exec_with_offset(op2));
+    assert_eq!(None, z80.exec_with_offset(op1, 3));
+    assert_eq!(Some(0x0CFF), z80.exec_with_offset(op2, 3));
 
     z80.registers.set_flag(&StatusFlag::Sign, true);
     let op1 = Op::JP(JumpConditional::SignNegative, Location16::Immediate(0x0CFF));
     let op2 = Op::JP(JumpConditional::SignPositive, Location16::Immediate(0x0CDD));
-    assert_eq!(Some(0x0CFF), z80.exec_with_offset(op1.clone()));
-    assert_eq!(None, z80.exec_with_offset(op2.clone()));
+    assert_eq!(Some(0x0CFF), z80.exec_with_offset(op1.clone(), 3));
+    assert_eq!(None, z80.exec_with_offset(op2.clone(), 3));
     z80.registers.set_flag(&StatusFlag::Sign, false);
-    assert_eq!(None, z80.exec_with_offset(op1));
-    assert_eq!(Some(0x0CDD), z80.exec_with_offset(op2));
+    assert_eq!(None, z80.exec_with_offset(op1, 3));
+    assert_eq!(Some(0x0CDD), z80.exec_with_offset(op2, 3));
 }
 
 #[test]
@@ -934,26 +992,26 @@ fn jr() {
 
     assert_eq!(
         Some(0xA123 + 129),
-        z80.exec_with_offset(Op::JR(JumpConditional::Unconditional, 127))
+        z80.exec_with_offset(Op::JR(JumpConditional::Unconditional, 127), 2)
     );
 
     z80.registers.set_flag(&StatusFlag::Zero, true);
     let op1 = Op::JR(JumpConditional::Zero, -128);
     let op2 = Op::JR(JumpConditional::NonZero, 127);
-    assert_eq!(Some(0xA123 - 126), z80.exec_with_offset(op1.clone()));
-    assert_eq!(None, z80.exec_with_offset(op2.clone()));
+    assert_eq!(Some(0xA123 - 126), z80.exec_with_offset(op1.clone(), 2));
+    assert_eq!(None, z80.exec_with_offset(op2.clone(), 2));
     z80.registers.set_flag(&StatusFlag::Zero, false);
-    assert_eq!(None, z80.exec_with_offset(op1));
-    assert_eq!(Some(0xA123 + 129), z80.exec_with_offset(op2));
+    assert_eq!(None, z80.exec_with_offset(op1, 2));
+    assert_eq!(Some(0xA123 + 129), z80.exec_with_offset(op2, 2));
 
     z80.registers.set_flag(&StatusFlag::Carry, true);
     let op1 = Op::JR(JumpConditional::Carry, 15);
     let op2 = Op::JR(JumpConditional::NoCarry, -100);
-    assert_eq!(Some(0xA123 + 17), z80.exec_with_offset(op1.clone()));
-    assert_eq!(None, z80.exec_with_offset(op2.clone()));
+    assert_eq!(Some(0xA123 + 17), z80.exec_with_offset(op1.clone(), 2));
+    assert_eq!(None, z80.exec_with_offset(op2.clone(), 2));
     z80.registers.set_flag(&StatusFlag::Carry, false);
-    assert_eq!(None, z80.exec_with_offset(op1));
-    assert_eq!(Some(0xA123 - 98), z80.exec_with_offset(op2));
+    assert_eq!(None, z80.exec_with_offset(op1, 2));
+    assert_eq!(Some(0xA123 - 98), z80.exec_with_offset(op2, 2));
 }
 
 #[test]
@@ -962,14 +1020,14 @@ fn djnz() {
     z80.set_loc8(&Location8::Reg(Reg8::B), 2);
     z80.registers.set_pc(0xAB50);
 
-    assert_eq!(Some(0xAB00), z80.exec_with_offset(Op::DJNZ(-82)));
-    assert_eq!(None, z80.exec_with_offset(Op::DJNZ(-52)));
+    assert_eq!(Some(0xAB00), z80.exec_with_offset(Op::DJNZ(-82), 2));
+    assert_eq!(None, z80.exec_with_offset(Op::DJNZ(-52), 2));
     assert_eq!(0, z80.registers.get_reg8(Reg8::B));
 
     // Don't underflow when we add our offset
     z80.set_loc8(&Location8::Reg(Reg8::B), 2);
     z80.registers.set_pc(0x0010);
-    assert_eq!(Some(0x0001), z80.exec_with_offset(Op::DJNZ(-17)));
+    assert_eq!(Some(0x0001), z80.exec_with_offset(Op::DJNZ(-17), 2));
     assert_eq!(1, z80.registers.get_reg8(Reg8::B));
 }
 
@@ -980,11 +1038,11 @@ fn call() {
     z80.registers.set_reg16(&Reg16::SP, 0x3002);
     assert_eq!(
         Some(0x2135),
-        z80.exec_with_offset(Op::CALL(JumpConditional::Unconditional, 0x2135)),
+        z80.exec_with_offset(Op::CALL(JumpConditional::Unconditional, 0x2135), 3),
     );
 
-    assert_eq!(0x4A, z80.memory.memory[0x3000]);
-    assert_eq!(0x1A, z80.memory.memory[0x3001]);
+    assert_eq!(0x4A, z80.memory.read_u8(0x3000));
+    assert_eq!(0x1A, z80.memory.read_u8(0x3001));
     assert_eq!(0x3000, z80.registers.get_reg16(&Reg16::SP));
 }
 
@@ -999,11 +1057,11 @@ fn call_cond_zero() {
     let op1 = Op::CALL(JumpConditional::Zero, 0x2135);
     let op2 = Op::CALL(JumpConditional::NonZero, 0x2135);
     assert_eq!(0x3002, z80.registers.get_reg16(&Reg16::SP));
-    assert_eq!(None, z80.exec_with_offset(op2));
-    assert_eq!(Some(0x2135), z80.exec_with_offset(op1));
+    assert_eq!(None, z80.exec_with_offset(op2, 3));
+    assert_eq!(Some(0x2135), z80.exec_with_offset(op1, 3));
 
-    assert_eq!(0x4A, z80.memory.memory[0x3000]);
-    assert_eq!(0x1A, z80.memory.memory[0x3001]);
+    assert_eq!(0x4A, z80.memory.read_u8(0x3000));
+    assert_eq!(0x1A, z80.memory.read_u8(0x3001));
     assert_eq!(0x3000, z80.registers.get_reg16(&Reg16::SP));
 
     // Not testing the other states, well covered by the JP tests
@@ -1014,11 +1072,11 @@ fn ret() {
     let mut z80 = Z80::default();
     z80.registers.set_pc(0x3535);
     z80.registers.set_reg16(&Reg16::SP, 0x2000);
-    z80.memory.memory[0x2000] = 0xB5;
-    z80.memory.memory[0x2001] = 0x18;
+    z80.memory.write_u8(0x2000, 0xB5);
+    z80.memory.write_u8(0x2001, 0x18);
     assert_eq!(
         Some(0x18B5),
-        z80.exec_with_offset(Op::RET(JumpConditional::Unconditional)),
+        z80.exec_with_offset(Op::RET(JumpConditional::Unconditional), 1),
     );
     assert_eq!(0x2002, z80.registers.get_reg16(&Reg16::SP));
 }
@@ -1030,16 +1088,1208 @@ fn call_cond_carry() {
     z80.registers.set_reg16(&Reg16::SP, 0x2000);
 
     z80.registers.set_flag(&StatusFlag::Carry, true);
-    z80.memory.memory[0x2000] = 0xB5;
-    z80.memory.memory[0x2001] = 0x18;
+    z80.memory.write_u8(0x2000, 0xB5);
+    z80.memory.write_u8(0x2001, 0x18);
 
     let op1 = Op::RET(JumpConditional::Carry);
     let op2 = Op::RET(JumpConditional::NoCarry);
-    assert_eq!(None, z80.exec_with_offset(op2));
+    assert_eq!(None, z80.exec_with_offset(op2, 1));
     assert_eq!(0x2000, z80.registers.get_reg16(&Reg16::SP));
 
-    assert_eq!(Some(0x18B5), z80.exec_with_offset(op1));
+    assert_eq!(Some(0x18B5), z80.exec_with_offset(op1, 1));
     assert_eq!(0x2002, z80.registers.get_reg16(&Reg16::SP));
 
     // Not testing the other states, well covered by the JP tests
 }
+
+#[test]
+fn step_tstates() {
+    let mut z80 = Z80::default();
+    // LD A, 0x05; DJNZ -2; HALT
+    z80.load(&[0x3E, 0x05, 0x10, 0xFE, 0x76]);
+    z80.registers.set_reg8(Reg8::B, 2);
+
+    z80.step();
+    assert_eq!(7, z80.tstates());
+    z80.step();
+    assert_eq!(7 + 13, z80.tstates());
+    z80.step();
+    assert_eq!(7 + 13 + 8, z80.tstates());
+    assert_eq!(3, z80.last_cycles().len());
+    assert_eq!(
+        crate::cpu::timing::CycleKind::OpcodeFetch,
+        z80.last_cycles()[0].kind
+    );
+}
+
+#[test]
+fn run_frame() {
+    use crate::frame::{FrameTimer, FrameTiming};
+    let mut z80 = Z80::default();
+    // JR -2, forever
+    z80.load(&[0x18, 0xFE]);
+    let mut timer = FrameTimer::new(FrameTiming {
+        tstates_per_line: 12,
+        lines_per_frame: 10,
+    });
+    assert!(z80.run_frame(&mut timer));
+    assert_eq!(120, z80.tstates());
+    assert!(z80.run_frame(&mut timer));
+    assert_eq!(2, timer.frame());
+}
+
+#[test]
+fn record_replay() {
+    // IN A, (1); OUT (0), A; IN A, (1); OUT (0), A; HALT
+    let program = [0xDB, 0x01, 0xD3, 0x00, 0xDB, 0x01, 0xD3, 0x00, 0x76];
+
+    let mut z80 = Z80::default();
+    z80.install_input(0x01, Box::new(super::io::BufInput::new(vec![0x22, 0x11])));
+    z80.install_output(0x00, Box::new(super::io::BufOutput::default()));
+    z80.load(&program);
+    z80.start_recording();
+    z80.run();
+    let log = z80.stop_recording().unwrap();
+    assert_eq!(
+        vec![
+            super::replay::ReplayEvent::Input {
+                tstate: 0,
+                port: 0x01,
+                value: 0x11
+            },
+            super::replay::ReplayEvent::Input {
+                tstate: 22,
+                port: 0x1101,
+                value: 0x22
+            },
+        ],
+        log.events
+    );
+
+    // No input device needed this time
+    let mut z80 = Z80::default();
+    let out = super::io::BufOutput::default();
+    z80.install_output(0x00, Box::new(out.clone()));
+    z80.load(&program);
+    z80.start_replay(log);
+    assert!(z80.is_replaying());
+    z80.run();
+    assert!(!z80.is_replaying());
+    assert_eq!(vec![0x11, 0x22], out.result());
+}
+
+#[test]
+#[should_panic(expected = "replay desynchronised")]
+fn replay_desync() {
+    let mut z80 = Z80::default();
+    z80.start_replay(super::replay::ReplayLog {
+        events: vec![super::replay::ReplayEvent::Input {
+            tstate: 0,
+            port: 0x01,
+            value: 0x11,
+        }],
+    });
+    z80.exec(Op::IN(Location8::Reg(Reg8::A), Location8::Immediate(0x02)));
+}
+
+#[test]
+fn io_trace() {
+    use super::io::{BufInput, BufIoTrace, BufOutput, Direction, IoEvent};
+    let mut z80 = Z80::default();
+    let trace = BufIoTrace::default();
+    z80.set_io_trace(Box::new(trace.clone()));
+    z80.install_input(0x01, Box::new(BufInput::new(vec![0x42])));
+    z80.install_output(0x02, Box::new(BufOutput::default()));
+    // IN A, (1); LD C, 2; OUT (C), A
+    z80.load(&[0xDB, 0x01, 0x0E, 0x02, 0xED, 0x79]);
+    z80.step();
+    z80.step();
+    z80.step();
+    assert_eq!(
+        vec![
+            IoEvent {
+                tstate: 7,
+                port: 0x01,
+                value: 0x42,
+                direction: Direction::In
+            },
+            IoEvent {
+                tstate: 11 + 7 + 8,
+                port: 0x0002,
+                value: 0x42,
+                direction: Direction::Out
+            },
+        ],
+        trace.events()
+    );
+}
+
+#[test]
+fn traps() {
+    use super::trap::TrapAction;
+    let mut z80 = Z80::default();
+    // CALL 0x0010; LD A, 0x01; HALT
+    z80.load(&[0xCD, 0x10, 0x00, 0x3E, 0x01, 0x76]);
+    // The routine at 0x0010 doesn't exist, the trap does its job instead
+    z80.install_trap(
+        0x0010,
+        Box::new(|z80| {
+            z80.registers.set_reg8(Reg8::B, 0x55);
+            TrapAction::Return
+        }),
+    );
+    // Skip the LD and go straight to the HALT
+    z80.install_trap(0x0003, Box::new(|_| TrapAction::Jump(0x0005)));
+    z80.run();
+    assert_eq!(0x55, z80.registers.get_reg8(Reg8::B));
+    assert_eq!(0x00, z80.registers.get_reg8(Reg8::A));
+    assert_eq!(0x4000, z80.registers.get_reg16(&Reg16::SP));
+
+    // Traps can also just observe
+    z80.set_halted(false);
+    z80.registers.set_pc(0x0003);
+    assert!(z80.remove_trap(0x0003));
+    assert!(!z80.remove_trap(0x0003));
+    z80.install_trap(
+        0x0003,
+        Box::new(|z80| {
+            z80.registers.set_reg8(Reg8::C, 0x66);
+            TrapAction::Continue
+        }),
+    );
+    z80.step();
+    assert_eq!(0x66, z80.registers.get_reg8(Reg8::C));
+    assert_eq!(0x01, z80.registers.get_reg8(Reg8::A));
+}
+
+#[test]
+fn pop_af_flags() {
+    let mut z80 = Z80::default();
+    // Every bit of F survives, including the undocumented 3 and 5
+    z80.registers.set_reg16(&Reg16::BC, 0x12FF);
+    z80.exec(Op::PUSH(Location16::Reg(Reg16::BC)));
+    z80.exec(Op::POP(Location16::Reg(Reg16::AF)));
+    assert_hex!(0x12, z80.registers.get_reg8(Reg8::A));
+    assert_hex!(0xFF, z80.registers.get_reg8(Reg8::F));
+
+    // And conditional jumps see them
+    z80.registers.set_reg16(&Reg16::BC, 0x0041);
+    z80.exec(Op::PUSH(Location16::Reg(Reg16::BC)));
+    z80.exec(Op::POP(Location16::Reg(Reg16::AF)));
+    let jp = |cond| Op::JP(cond, Location16::Immediate(0x1000));
+    assert_eq!(
+        Some(0x1000),
+        z80.exec_with_offset(jp(JumpConditional::Zero), 3)
+    );
+    assert_eq!(
+        Some(0x1000),
+        z80.exec_with_offset(jp(JumpConditional::Carry), 3)
+    );
+    assert_eq!(
+        None,
+        z80.exec_with_offset(jp(JumpConditional::SignNegative), 3)
+    );
+}
+
+#[test]
+fn push_pop_af_index() {
+    let mut z80 = Z80::default();
+    // PUSH IX; POP IY; PUSH AF; POP HL
+    z80.load(&[0xDD, 0xE5, 0xFD, 0xE1, 0xF5, 0xE1]);
+    z80.registers.set_reg16(&Reg16::IX, 0xBEEF);
+    z80.registers.set_reg8(Reg8::A, 0x34);
+    z80.registers.set_reg8(Reg8::F, 0x56);
+    z80.step();
+    // Little-endian on the stack, like everything else
+    assert_hex!(0xEF, z80.memory.read_u8(0x3FFE));
+    assert_hex!(0xBE, z80.memory.read_u8(0x3FFF));
+    assert_eq!(15, z80.tstates());
+    z80.step();
+    assert_hex!(0xBEEF, z80.registers.get_reg16(&Reg16::IY));
+    assert_eq!(29, z80.tstates());
+
+    // F is the low byte of AF
+    z80.step();
+    assert_hex!(0x56, z80.memory.read_u8(0x3FFE));
+    assert_hex!(0x34, z80.memory.read_u8(0x3FFF));
+    z80.step();
+    assert_hex!(0x34, z80.registers.get_reg8(Reg8::H));
+    assert_hex!(0x56, z80.registers.get_reg8(Reg8::L));
+}
+
+#[test]
+fn ld_sp() {
+    let mut z80 = Z80::default();
+    // LD HL, 0x3000; LD SP, HL; LD IX, 0x2000; LD SP, IX; LD IY, 0x1000; LD SP, IY
+    z80.load(&[
+        0x21, 0x00, 0x30, 0xF9, 0xDD, 0x21, 0x00, 0x20, 0xDD, 0xF9, 0xFD, 0x21, 0x00, 0x10, 0xFD,
+        0xF9,
+    ]);
+    for (sp, tstates) in &[(0x3000, 6), (0x2000, 10), (0x1000, 10)] {
+        z80.step();
+        let before = z80.tstates();
+        z80.step();
+        assert_eq!(*sp, z80.registers.get_reg16(&Reg16::SP));
+        assert_eq!(*tstates, z80.tstates() - before);
+    }
+}
+
+#[test]
+fn fetch_hook() {
+    use super::trap::FetchOverride;
+    let mut z80 = Z80::default();
+    // OUT (0x01), A; LD B, 0x01; LD C, 0x02; HALT
+    z80.load(&[0xD3, 0x01, 0x06, 0x01, 0x0E, 0x02, 0x76]);
+    let printed = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+    let p = printed.clone();
+    z80.on_fetch(Box::new(move |pc| match pc {
+        // Print without a device, as a BDOS call might
+        0x0000 => {
+            p.borrow_mut().push(pc);
+            Some(FetchOverride::Jump(0x0002))
+        }
+        0x0002 => Some(FetchOverride::Exec(
+            Op::LD8(Location8::Reg(Reg8::B), Location8::Immediate(0x99)),
+            2,
+        )),
+        _ => None,
+    }));
+    z80.run();
+    assert_eq!(vec![0x0000], *printed.borrow());
+    assert_eq!(0x99, z80.registers.get_reg8(Reg8::B));
+    assert_eq!(0x02, z80.registers.get_reg8(Reg8::C));
+    assert_eq!(0x0007, z80.registers.get_pc());
+    // Guest memory is untouched
+    assert_eq!(0xD3, z80.memory.read_u8(0x0000));
+
+    z80.clear_fetch_hook();
+    z80.registers.set_pc(0x0002);
+    z80.set_halted(false);
+    z80.step();
+    assert_eq!(0x01, z80.registers.get_reg8(Reg8::B));
+}
+
+#[test]
+fn call_length() {
+    let mut z80 = Z80::default();
+    z80.registers.set_pc(0x1000);
+    let call = Op::CALL(JumpConditional::Unconditional, 0x2000);
+    // The return address is after the instruction, however long it is
+    assert_eq!(Some(0x2000), z80.exec_with_offset(call.clone(), 4));
+    assert_eq!(0x1004, z80.pop_val());
+
+    z80.registers.set_pc(0xFFFE);
+    z80.exec(call);
+    assert_eq!(0x0001, z80.pop_val());
+
+    // In interrupt mode 0 the instruction doesn't come from memory, so the return address is the
+    // interrupted instruction itself
+    z80.install_input(0x10, Box::new(Interrupter(0xCD)));
+    z80.set_iff1(true);
+    z80.registers.set_pc(0x0100);
+    z80.request_interrupt();
+    z80.step();
+    assert_eq!(0x0000, z80.registers.get_pc());
+    assert_eq!(0x0100, z80.pop_val());
+}
+
+#[test]
+fn conditional_call_ret_tstates() {
+    let mut z80 = Z80::default();
+    // CALL Z, 0x0010 (not taken); CALL NZ, 0x0010 (taken)
+    // and at 0x0010: RET Z (not taken); RET NZ (taken)
+    let mut program = vec![0x00; 0x12];
+    program[..6].copy_from_slice(&[0xCC, 0x10, 0x00, 0xC4, 0x10, 0x00]);
+    program[0x10..].copy_from_slice(&[0xC8, 0xC0]);
+    z80.load(&program);
+    let mut steps = vec![];
+    for _ in 0..4 {
+        let before = z80.tstates();
+        z80.step();
+        steps.push((z80.registers.get_pc(), z80.tstates() - before));
+    }
+    assert_eq!(
+        vec![(0x0003, 10), (0x0010, 17), (0x0011, 5), (0x0006, 11)],
+        steps
+    );
+}
+
+#[test]
+fn custom_run_loop() {
+    use super::Next;
+    let mut z80 = Z80::default();
+    // LD B, 3; loop: DJNZ loop; JP 0x0000
+    z80.load(&[0x06, 0x03, 0x10, 0xFE, 0xC3, 0x00, 0x00]);
+    let mut decisions = vec![];
+    for _ in 0..5 {
+        let pc = z80.registers.get_pc();
+        let (op, length) = z80.parse_opcode(pc as usize).unwrap();
+        let next = z80.execute(op, length as u16);
+        decisions.push(next);
+        z80.registers.set_pc(next.pc());
+    }
+    assert_eq!(
+        vec![
+            Next::Continue(0x0002),
+            Next::Jump(0x0002),
+            Next::Jump(0x0002),
+            Next::Continue(0x0004),
+            Next::Jump(0x0000),
+        ],
+        decisions
+    );
+}
+
+#[test]
+fn jr_length() {
+    let mut z80 = Z80::default();
+    z80.registers.set_pc(0x1000);
+    // Relative to the end of the instruction, however long it is
+    assert_eq!(
+        Some(0x1003),
+        z80.exec_with_offset(Op::JR(JumpConditional::Unconditional, 0), 3)
+    );
+
+    // Wrapping around the top of the address space
+    z80.registers.set_pc(0xFFFE);
+    assert_eq!(
+        Some(0x0003),
+        z80.exec_with_offset(Op::JR(JumpConditional::Unconditional, 3), 2)
+    );
+    z80.registers.set_pc(0x0001);
+    assert_eq!(
+        Some(0xFFF3),
+        z80.exec_with_offset(Op::JR(JumpConditional::Unconditional, -16), 2)
+    );
+}
+
+#[test]
+fn ei_delay() {
+    // IM 1; EI; EI; LD A, 1; HALT, with a HALT at 0x38
+    let mut program = vec![0x00; 0x39];
+    program[..7].copy_from_slice(&[0xED, 0x56, 0xFB, 0xFB, 0x3E, 0x01, 0x76]);
+    program[0x38] = 0x76;
+    let mut z80 = Z80::default();
+    z80.load(&program);
+    z80.step();
+    z80.request_interrupt();
+
+    // Each EI holds off the interrupt for another instruction
+    for pc in &[0x0003, 0x0004, 0x0006] {
+        z80.step();
+        assert_eq!(*pc, z80.registers.get_pc());
+    }
+    assert_eq!(1, z80.registers.get_reg8(Reg8::A));
+    assert!(z80.is_interrupt_pending());
+    z80.step();
+    assert_eq!(0x0038, z80.registers.get_pc());
+    assert_eq!(0x0006, z80.get_loc16(&Location16::RegIndirect(Reg16::SP)));
+
+    // NMIs are never held off
+    z80.set_iff1(false);
+    z80.exec(Op::EI);
+    z80.request_nmi();
+    z80.step();
+    assert_eq!(0x0066, z80.registers.get_pc());
+}
+
+#[test]
+fn halt_interrupt() {
+    // IM 1; EI; HALT, with LD A, 0x42; RETI at 0x38
+    let mut program = vec![0x00; 0x3C];
+    program[..4].copy_from_slice(&[0xED, 0x56, 0xFB, 0x76]);
+    program[0x38..].copy_from_slice(&[0x3E, 0x42, 0xED, 0x4D]);
+    let mut z80 = Z80::default();
+    z80.load(&program);
+    for _ in 0..3 {
+        z80.step();
+    }
+    assert!(z80.is_halted());
+    assert_eq!(0x0004, z80.registers.get_pc());
+
+    // Halted, the processor idles
+    let tstates = z80.tstates();
+    z80.step();
+    assert!(z80.is_halted());
+    assert_eq!(0x0004, z80.registers.get_pc());
+    assert_eq!(tstates + 4, z80.tstates());
+
+    z80.request_interrupt();
+    z80.step();
+    assert!(!z80.is_halted());
+    assert_eq!(0x0038, z80.registers.get_pc());
+    assert_eq!(tstates + 17, z80.tstates());
+    assert_eq!(0x0004, z80.get_loc16(&Location16::RegIndirect(Reg16::SP)));
+
+    // Interrupts are disabled on entry
+    z80.request_interrupt();
+    z80.step();
+    z80.step();
+    assert_eq!(0x42, z80.registers.get_reg8(Reg8::A));
+    assert_eq!(0x0004, z80.registers.get_pc());
+}
+
+#[test]
+fn halt_without_interrupts() {
+    let mut z80 = Z80::default();
+    z80.load(&[0x76]);
+    z80.step();
+    z80.request_interrupt();
+    z80.step();
+    assert!(z80.is_halted());
+    assert_eq!(0x0001, z80.registers.get_pc());
+}
+
+#[test]
+fn im2() {
+    let mut z80 = Z80::default();
+    z80.exec(Op::IM(2));
+    z80.set_iff1(true);
+    z80.registers.set_reg8(Reg8::I, 0x20);
+    z80.set_loc16(&Location16::ImmediateIndirect(0x20FF), 0x1234);
+    z80.registers.set_pc(0x0100);
+    z80.request_interrupt();
+    z80.step();
+    assert_eq!(0x1234, z80.registers.get_pc());
+    assert_eq!(19, z80.tstates());
+}
+
+#[test]
+fn im0() {
+    let mut z80 = Z80::default();
+    z80.set_iff1(true);
+    z80.registers.set_pc(0x0100);
+    z80.request_interrupt();
+    z80.step();
+    // The floating bus reads as RST 38h
+    assert_eq!(0x0038, z80.registers.get_pc());
+    assert_eq!(13, z80.tstates());
+    assert_eq!(0x0100, z80.get_loc16(&Location16::RegIndirect(Reg16::SP)));
+}
+
+#[test]
+fn nmi() {
+    let mut z80 = Z80::default();
+    z80.exec(Op::EI);
+    z80.registers.set_pc(0x0100);
+    z80.request_nmi();
+    z80.step();
+    assert_eq!(0x0066, z80.registers.get_pc());
+    assert_eq!(11, z80.tstates());
+
+    // Maskable interrupts wait until RETN
+    z80.request_interrupt();
+    z80.memory.write_u8(0x0066, 0xED);
+    z80.memory.write_u8(0x0067, 0x45);
+    z80.step();
+    assert_eq!(0x0100, z80.registers.get_pc());
+    z80.step();
+    assert_eq!(0x0038, z80.registers.get_pc());
+}
+
+#[test]
+fn reti_restores_iff1() {
+    let mut z80 = Z80::default();
+    z80.set_iff2(true);
+    z80.load(&[0xED, 0x4D]); // RETI
+    z80.registers.set_reg16(&Reg16::SP, 0x1000);
+    z80.memory.write_u8(0x1000, 0x34);
+    z80.memory.write_u8(0x1001, 0x12);
+    z80.step();
+    assert_eq!(0x1234, z80.registers.get_pc());
+    assert!(z80.iff1());
+}
+
+#[test]
+fn rst() {
+    let mut z80 = Z80::default();
+    z80.registers.set_pc(0x1000);
+    assert_eq!(Some(0x0028), z80.exec_with_offset(Op::RST(0x28), 1));
+    assert_eq!(0x1001, z80.get_loc16(&Location16::RegIndirect(Reg16::SP)));
+}
+
+#[test]
+fn ld_a_i_r() {
+    let mut z80 = Z80::default();
+    z80.registers.set_reg8(Reg8::I, 0x80);
+    z80.exec(Op::LD8(Location8::Reg(Reg8::A), Location8::Reg(Reg8::I)));
+    assert_eq!(0x80, z80.registers.get_reg8(Reg8::A));
+    assert!(z80.registers.get_flag(&StatusFlag::Sign));
+    assert!(!z80.registers.get_flag(&StatusFlag::ParityOverflow));
+
+    // P/V holds IFF2
+    z80.exec(Op::EI);
+    z80.exec(Op::LD8(Location8::Reg(Reg8::A), Location8::Reg(Reg8::R)));
+    assert!(z80.registers.get_flag(&StatusFlag::ParityOverflow));
+
+    // R counts opcode fetches, including those of LD A, R itself
+    assert_eq!(0x05, z80.registers.get_reg8(Reg8::A));
+    z80.registers.set_reg8(Reg8::R, 0xFF);
+    z80.load(&[0x00, 0xED, 0x5F]);
+    z80.step();
+    z80.step();
+    // Bit 7 is left alone
+    assert_eq!(0x82, z80.registers.get_reg8(Reg8::A));
+}
+
+#[test]
+fn replay_interrupts() {
+    // IM 1; EI; HALT, with INC B; EI; RETI at 0x38
+    let mut program = vec![0x00; 0x3C];
+    program[..4].copy_from_slice(&[0xED, 0x56, 0xFB, 0x76]);
+    program[0x38..].copy_from_slice(&[0x04, 0xFB, 0xED, 0x4D]);
+
+    let mut z80 = Z80::default();
+    z80.load(&program);
+    z80.start_recording();
+    for i in 0..20 {
+        if i % 7 == 6 {
+            z80.request_interrupt();
+        }
+        z80.step();
+    }
+    let log = z80.stop_recording().unwrap();
+    assert_eq!(2, log.events.len());
+    let b = z80.registers.get_reg8(Reg8::B);
+    let tstates = z80.tstates();
+
+    let mut z80 = Z80::default();
+    z80.load(&program);
+    z80.start_replay(log);
+    for i in 0..20 {
+        if i == 3 {
+            // Ignored during replay
+            z80.request_interrupt();
+        }
+        z80.step();
+    }
+    assert_eq!(b, z80.registers.get_reg8(Reg8::B));
+    assert_eq!(tstates, z80.tstates());
+}
+
+#[test]
+fn interrupt_state() {
+    let mut z80 = Z80::default();
+    assert!(!z80.iff1());
+    assert!(!z80.iff2());
+    assert_eq!(0, z80.interrupt_mode());
+
+    z80.exec(Op::EI);
+    z80.exec(Op::IM(2));
+    assert!(z80.iff1());
+    assert!(z80.iff2());
+    assert_eq!(2, z80.interrupt_mode());
+
+    z80.set_iff1(false);
+    z80.set_interrupt_mode(1);
+    z80.request_interrupt();
+    assert!(z80.is_interrupt_pending());
+    z80.step();
+    // Still pending, as interrupts are disabled
+    assert!(z80.is_interrupt_pending());
+    z80.set_interrupt_pending(false);
+
+    z80.set_iff1(true);
+    z80.request_nmi();
+    assert!(z80.is_nmi_pending());
+    z80.step();
+    assert!(!z80.is_nmi_pending());
+    assert!(!z80.iff1());
+    assert!(z80.iff2());
+
+    z80.set_halted(true);
+    assert!(z80.is_halted());
+    z80.set_halted(false);
+    assert!(!z80.is_halted());
+}
+
+#[test]
+#[should_panic]
+fn bad_interrupt_mode() {
+    let mut z80 = Z80::default();
+    z80.set_interrupt_mode(3);
+}
+
+#[test]
+fn indexed_bits() {
+    let mut z80 = Z80::default();
+    z80.registers.set_reg16(&Reg16::IX, 0x1000);
+    z80.registers.set_reg16(&Reg16::IY, 0x2000);
+    z80.memory.write_u8(0x0FFE, 0b0000_0100);
+    z80.memory.write_u8(0x2005, 0b1000_0001);
+
+    z80.exec(Op::BIT(2, Location8::Indexed(Reg16::IX, -2)));
+    assert!(!z80.registers.get_flag(&StatusFlag::Zero));
+    z80.exec(Op::BIT(3, Location8::Indexed(Reg16::IX, -2)));
+    assert!(z80.registers.get_flag(&StatusFlag::Zero));
+
+    z80.exec(Op::SET(7, Location8::Indexed(Reg16::IX, -2)));
+    assert_bin!(0b1000_0100, z80.memory.read_u8(0x0FFE));
+
+    z80.exec(Op::RLC(Location8::IndexedCopy(Reg16::IY, 5, Reg8::B)));
+    assert_bin!(0b0000_0011, z80.memory.read_u8(0x2005));
+    assert_bin!(0b0000_0011, z80.registers.get_reg8(Reg8::B));
+}
+
+#[test]
+fn indexed_bits_step() {
+    let mut z80 = Z80::default();
+    // LD IX, 0x0100; SET 0, (IX+2), C; HALT
+    z80.load(&[0xDD, 0x21, 0x00, 0x01, 0xDD, 0xCB, 0x02, 0xC1, 0x76]);
+    z80.run();
+    assert_eq!(0x01, z80.memory.read_u8(0x0102));
+    assert_eq!(0x01, z80.registers.get_reg8(Reg8::C));
+    assert_eq!(14 + 23 + 4, z80.tstates());
+}
+
+// A peripheral that drives the data bus whenever an interrupt is acknowledged
+struct Interrupter(u8);
+
+impl super::io::InputDevice for Interrupter {
+    fn input(&self) -> u8 {
+        0x00
+    }
+
+    fn acknowledge(&self) -> Option<u8> {
+        Some(self.0)
+    }
+}
+
+#[test]
+fn interrupt_data_bus() {
+    let mut z80 = Z80::default();
+    z80.install_input(0x10, Box::new(Interrupter(0xD7)));
+    z80.set_iff1(true);
+    z80.registers.set_pc(0x0100);
+    z80.request_interrupt();
+    z80.step();
+    // RST 10h
+    assert_eq!(0x0010, z80.registers.get_pc());
+
+    z80.install_input(0x10, Box::new(Interrupter(0x40)));
+    z80.exec(Op::IM(2));
+    z80.set_iff1(true);
+    z80.registers.set_reg8(Reg8::I, 0x20);
+    z80.set_loc16(&Location16::ImmediateIndirect(0x2040), 0x1234);
+    z80.request_interrupt();
+    z80.step();
+    assert_eq!(0x1234, z80.registers.get_pc());
+}
+
+#[test]
+fn replay_interrupt_data() {
+    let mut z80 = Z80::default();
+    z80.install_input(0x10, Box::new(Interrupter(0xCF)));
+    z80.set_iff1(true);
+    z80.start_recording();
+    z80.request_interrupt();
+    z80.step();
+    let log = z80.stop_recording().unwrap();
+    assert_eq!(
+        vec![super::replay::ReplayEvent::Interrupt {
+            tstate: 0,
+            nmi: false,
+            data: 0xCF
+        }],
+        log.events
+    );
+
+    // No device needed to replay it
+    let mut z80 = Z80::default();
+    z80.set_iff1(true);
+    z80.start_replay(log);
+    z80.step();
+    assert_eq!(0x0008, z80.registers.get_pc());
+}
+
+#[test]
+fn im2_vector_across_pages() {
+    use crate::cpu::timing::CycleKind;
+    use std::cell::RefCell;
+
+    // Four 8K banks, any of which can be mapped into each 8K page of the address space.
+    // Reads of the watched addresses are noted, as a watchpoint would.
+    struct Banked {
+        banks: RefCell<Vec<[u8; 0x2000]>>,
+        pages: [usize; 8],
+        watched: Vec<u16>,
+        hits: RefCell<Vec<u16>>,
+    }
+    impl super::bus::Bus for std::rc::Rc<Banked> {
+        fn mem_read(&self, addr: u16) -> u8 {
+            if self.watched.contains(&addr) {
+                self.hits.borrow_mut().push(addr);
+            }
+            let page = self.pages[usize::from(addr >> 13)];
+            self.banks.borrow()[page][usize::from(addr & 0x1FFF)]
+        }
+        fn mem_write(&self, addr: u16, val: u8) {
+            let page = self.pages[usize::from(addr >> 13)];
+            self.banks.borrow_mut()[page][usize::from(addr & 0x1FFF)] = val;
+        }
+        fn io_read(&self, _port: u16) -> u8 {
+            0xFF
+        }
+        fn io_write(&self, _port: u16, _val: u8) {}
+    }
+    impl Banked {
+        fn new() -> std::rc::Rc<Self> {
+            let mut banks = vec![[0xEE; 0x2000]; 4];
+            // The vector's low byte at the top of bank 2, and its high byte at the bottom of 3
+            banks[2][0x1FFF] = 0x34;
+            banks[3][0x0000] = 0x12;
+            std::rc::Rc::new(Self {
+                banks: RefCell::new(banks),
+                pages: [2, 3, 0, 0, 0, 0, 0, 0],
+                watched: vec![0x1FFF, 0x2000],
+                hits: RefCell::new(vec![]),
+            })
+        }
+    }
+    // Contended memory: a wait state on each read of the vector
+    struct Contended;
+    impl super::wait::WaitStates for Contended {
+        fn wait_states(&self, kind: CycleKind, addr: u16) -> u32 {
+            match (kind, addr) {
+                (CycleKind::MemoryRead, 0x1FFF..=0x2000) => 1,
+                _ => 0,
+            }
+        }
+    }
+
+    let interrupt = |z80: &mut Z80| {
+        z80.exec(Op::IM(2));
+        z80.set_iff1(true);
+        // The vector straddles the two pages
+        z80.registers.set_reg8(Reg8::I, 0x1F);
+        z80.request_interrupt();
+        z80.step();
+    };
+
+    let bus = Banked::new();
+    let mut z80 = Z80::default();
+    z80.set_bus(Box::new(bus.clone()));
+    interrupt(&mut z80);
+    assert_eq!(0x1234, z80.registers.get_pc());
+    assert_eq!(vec![0x1FFF, 0x2000], *bus.hits.borrow());
+    let reads: Vec<_> = z80
+        .last_cycles()
+        .iter()
+        .filter(|c| c.kind == CycleKind::MemoryRead)
+        .collect();
+    assert_eq!(2, reads.len());
+    assert_eq!(13, reads[0].start);
+    let uncontended = z80.tstates();
+
+    let mut z80 = Z80::default();
+    z80.set_bus(Box::new(Banked::new()));
+    z80.set_wait_states(Box::new(Contended));
+    interrupt(&mut z80);
+    assert_eq!(0x1234, z80.registers.get_pc());
+    assert_eq!(uncontended + 2, z80.tstates());
+}
+
+#[test]
+fn bus() {
+    let bus = FlatRam::new();
+    let mut z80 = Z80::default();
+    z80.set_bus(Box::new(bus.clone()));
+    // JP 0x8000, far beyond the built-in memory
+    z80.load(&[0xC3, 0x00, 0x80]);
+    // IN A, (0x42); LD (0xC000), A; OUT (0x07), A; HALT
+    let program = [0xDB, 0x42, 0x32, 0x00, 0xC0, 0xD3, 0x07, 0x76];
+    bus.load(0x8000, &program);
+    z80.run();
+
+    assert_eq!(0x42, bus.peek(0xC000));
+    assert_eq!(vec![(0x4207, 0x42)], bus.outputs());
+    assert_eq!(0, z80.memory.read_u8(0));
+
+    assert!(z80.clear_bus().is_some());
+    assert!(z80.clear_bus().is_none());
+}
+
+#[test]
+fn address_space_edges() {
+    let bus = FlatRam::new();
+    let mut z80 = Z80::default();
+    z80.set_bus(Box::new(bus.clone()));
+    z80.registers.set_reg16(&Reg16::HL, 0x1234);
+
+    // The stack wraps around both ends of the address space
+    z80.registers.set_reg16(&Reg16::SP, 0x0001);
+    z80.exec(Op::PUSH(Location16::Reg(Reg16::HL)));
+    assert_eq!(0xFFFF, z80.registers.get_reg16(&Reg16::SP));
+    z80.exec(Op::POP(Location16::Reg(Reg16::DE)));
+    assert_eq!(0x0001, z80.registers.get_reg16(&Reg16::SP));
+    assert_eq!(0x1234, z80.registers.get_reg16(&Reg16::DE));
+
+    // As do 16-bit loads and stores
+    for op in crate::z80_asm! {
+        LD (0xFFFF), HL;
+        LD BC, (0xFFFF);
+    } {
+        z80.exec(op);
+    }
+    assert_eq!(0x1234, z80.registers.get_reg16(&Reg16::BC));
+    assert_eq!([0x12, 0x34], [bus.peek(0x0000), bus.peek(0xFFFF)]);
+
+    // A CALL at the very top returns to 0x0000
+    z80.registers.set_pc(0xFFFD);
+    z80.registers.set_reg16(&Reg16::SP, 0x8000);
+    z80.exec(Op::CALL(JumpConditional::Unconditional, 0x1000));
+    assert_eq!(
+        0x0000,
+        z80.get_loc16(&Location16::ImmediateIndirect(0x7FFE))
+    );
+}
+
+// One wait state on every access in the bottom 16K, two on port 0xFE
+struct Contended;
+
+impl super::wait::WaitStates for Contended {
+    fn wait_states(&self, kind: crate::cpu::timing::CycleKind, addr: u16) -> u32 {
+        use crate::cpu::timing::CycleKind;
+        match kind {
+            CycleKind::IoRead | CycleKind::IoWrite if addr & 0xFF == 0xFE => 2,
+            CycleKind::IoRead | CycleKind::IoWrite => 0,
+            _ if addr < 0x1000 => 1,
+            _ => 0,
+        }
+    }
+}
+
+#[test]
+fn wait_states() {
+    let mut z80 = Z80::default();
+    z80.set_wait_states(Box::new(Contended));
+    z80.install_output(0xFE, Box::new(super::io::BufOutput::default()));
+    // LD A, (0x2000); LD (0x0100), A; OUT (0xFE), A
+    z80.load(&[0x3A, 0x00, 0x20, 0x32, 0x00, 0x01, 0xD3, 0xFE]);
+
+    // Fetch and operands are slow, the data read at 0x2000 isn't
+    z80.step();
+    assert_eq!(13 + 3, z80.tstates());
+    let starts: Vec<u32> = z80.last_cycles().iter().map(|c| c.start).collect();
+    assert_eq!(vec![0, 5, 9, 13], starts);
+
+    // ...but the data write at 0x0100 is
+    z80.step();
+    assert_eq!(16 + 13 + 4, z80.tstates());
+
+    z80.step();
+    assert_eq!(33 + 11 + 2 + 2, z80.tstates());
+
+    z80.clear_wait_states();
+    z80.registers.set_pc(0x0000);
+    z80.step();
+    assert_eq!(48 + 13, z80.tstates());
+}
+
+#[test]
+fn state_hash() {
+    let program = [0x3E, 0x2A, 0x06, 0x07, 0x80, 0x76];
+    let mut a = Z80::default();
+    a.load(&program);
+    a.run();
+    let mut b = Z80::default();
+    b.load(&program);
+    b.run();
+    assert_eq!(a.state_hash(), b.state_hash());
+
+    b.memory.write_u8(0x3FFF, 0x01);
+    assert_ne!(a.state_hash(), b.state_hash());
+    b.memory.write_u8(0x3FFF, 0x00);
+    b.exec(Op::EI);
+    assert_ne!(a.state_hash(), b.state_hash());
+}
+
+#[test]
+fn with_program() {
+    use crate::examples::HELLO_ZEERUST_BIN;
+    let mut z80 = Z80::with_program(HELLO_ZEERUST_BIN, 0x2000);
+    let out = super::io::BufOutput::default();
+    z80.install_output(0x00, Box::new(out.clone()));
+    assert_eq!(0x2000, z80.registers.get_pc());
+    assert_eq!(0x00, z80.memory.read_u8(0x0000));
+    z80.run();
+    assert_eq!(b"ZEERUST".to_vec(), out.result());
+}
+
+#[test]
+fn cheats() {
+    use super::cheat::Poke;
+    let mut z80 = Z80::default();
+    // LD A, 3; HALT
+    z80.load(&[0x3E, 0x03, 0x76]);
+    let lives = z80.add_patch(0x0001, &[0x03], &[0x09]);
+    z80.add_cheat(vec![Poke {
+        addr: 0x1000,
+        original: None,
+        value: 0xAA,
+    }]);
+    assert_eq!(0x09, z80.memory.read_u8(0x0001));
+    assert_eq!(0xAA, z80.memory.read_u8(0x1000));
+
+    // Reloading keeps the patch
+    z80.load(&[0x3E, 0x03, 0x76]);
+    z80.run();
+    assert_eq!(0x09, z80.registers.get_reg8(Reg8::A));
+
+    // And so does going back to a state saved before it
+    let mut unpatched = Z80::default();
+    unpatched.load(&[0x3E, 0x03, 0x76]);
+    z80.load_state(&unpatched.save_state()).unwrap();
+    assert_eq!(0x09, z80.memory.read_u8(0x0001));
+
+    // Something else paged in: leave it alone
+    z80.memory.write_u8(0x0001, 0x55);
+    z80.apply_cheats();
+    assert_eq!(0x55, z80.memory.read_u8(0x0001));
+
+    z80.memory.write_u8(0x0001, 0x09);
+    assert!(z80.remove_cheat(lives));
+    assert!(!z80.remove_cheat(lives));
+    assert_eq!(0x03, z80.memory.read_u8(0x0001));
+    z80.apply_cheats();
+    assert_eq!(0x03, z80.memory.read_u8(0x0001));
+}
+
+#[test]
+fn rom_cheats() {
+    use super::bus::Bus;
+    use std::cell::Cell;
+
+    // Two 8K banks of ROM, either of which can be paged into 0x0000 by writing to port 0
+    struct Banked {
+        banks: [Vec<u8>; 2],
+        bank: Cell<usize>,
+    }
+    impl Bus for Banked {
+        fn mem_read(&self, addr: u16) -> u8 {
+            self.banks[self.bank.get()]
+                .get(usize::from(addr))
+                .copied()
+                .unwrap_or(0xFF)
+        }
+        fn mem_write(&self, _addr: u16, _val: u8) {}
+        fn io_read(&self, _port: u16) -> u8 {
+            0xFF
+        }
+        fn io_write(&self, _port: u16, val: u8) {
+            self.bank.set(usize::from(val & 1));
+        }
+    }
+
+    // LD A, 1; OUT (0), A, and a bank with LD A, 3; HALT to follow it
+    let banks = [
+        vec![0x3E, 0x01, 0xD3, 0x00, 0xAA, 0xBB],
+        vec![0x00, 0x00, 0x00, 0x00, 0x3E, 0x03, 0x76],
+    ];
+    let mut z80 = Z80::default();
+    z80.set_bus(Box::new(Banked {
+        banks,
+        bank: Cell::new(0),
+    }));
+    let id = z80.add_rom_patch(0x0004, &[0x3E, 0x03], &[0x3E, 0x09]);
+    // Only laid over the bank it was meant for
+    assert_eq!(Some(0xAA), z80.peek(0x0004));
+    assert_eq!(Some(0xBB), z80.peek(0x0005));
+    z80.run();
+    assert_eq!(0x09, z80.registers.get_reg8(Reg8::A));
+    assert_eq!(Some(0x09), z80.peek(0x0005));
+    assert!(z80.remove_cheat(id));
+    assert_eq!(Some(0x03), z80.peek(0x0005));
+}
+
+#[test]
+fn out_c_zero() {
+    let out = super::io::BufOutput::default();
+    let mut z80 = Z80::default();
+    z80.install_output(0x10, Box::new(out.clone()));
+    // LD C, 0x10; OUT (C), 0; HALT
+    let program = [0x0E, 0x10, 0xED, 0x71, 0x76];
+    z80.load(&program);
+    z80.run();
+    assert_eq!(7 + 12 + 4, z80.tstates());
+
+    z80.set_variant(super::variant::CpuVariant::Cmos);
+    z80.load(&program);
+    z80.registers.set_pc(0x0000);
+    z80.set_halted(false);
+    z80.run();
+    assert_eq!(vec![0x00, 0xFF], out.result());
+}
+
+#[test]
+fn z180_trap() {
+    let mut z80 = Z80::default();
+    z80.set_variant(super::variant::CpuVariant::Z180);
+    // At 0x0100: NOP; an ED opcode that doesn't exist
+    z80.load_at(&[0x00, 0xED, 0x77], 0x0100);
+    z80.registers.set_pc(0x0100);
+    z80.step();
+    z80.step();
+    assert_eq!(0x0000, z80.registers.get_pc());
+    assert_eq!(
+        0x0101,
+        z80.memory.read_u16(z80.registers.get_reg16(&Reg16::SP))
+    );
+    assert_eq!(4 + 11, z80.tstates());
+}
+
+#[test]
+fn z180_instructions() {
+    let out = super::io::BufOutput::default();
+    let mut z80 = Z80::default();
+    z80.set_variant(super::variant::CpuVariant::Z180);
+    z80.install_output(0x0040, Box::new(out.clone()));
+    z80.load(&[
+        0x01, 0x0C, 0x15, // LD BC, 0x150C
+        0xED, 0x4C, // MLT BC
+        0x3E, 0x0F, // LD A, 0x0F
+        0xED, 0x64, 0xF0, // TST 0xF0
+        0xED, 0x39, 0x05, // OUT0 (0x05), A
+        0xED, 0x20, 0x05, // IN0 H, (0x05)
+        0xED, 0x39, 0x40, // OUT0 (0x40), A
+        0xED, 0x76, // SLP
+    ]);
+    for _ in 0..4 {
+        z80.step();
+    }
+    assert_eq!(0x00FC, z80.registers.get_reg16(&Reg16::BC));
+    assert!(z80.registers.get_flag(&StatusFlag::Zero));
+    assert!(z80.registers.get_flag(&StatusFlag::HalfCarry));
+    assert_eq!(0x0F, z80.registers.get_reg8(Reg8::A));
+
+    z80.run();
+    assert_eq!(0x0F, z80.z180_register(0x05));
+    assert_eq!(0x0F, z80.registers.get_reg8(Reg8::H));
+    assert!(!z80.registers.get_flag(&StatusFlag::Zero));
+    assert_eq!(vec![0x0F], out.result());
+    assert_eq!(0x0015, z80.registers.get_pc());
+
+    // Move the internal registers up to 0x40, over the device
+    z80.set_z180_register(super::z180::ICR, 0x40);
+    z80.load_at(&[0xED, 0x39, 0x40, 0x76], 0x0015);
+    z80.set_halted(false);
+    z80.run();
+    assert_eq!(vec![0x0F], out.result());
+    assert_eq!(0x0F, z80.z180_register(0x00));
+}
+
+#[test]
+fn z180_instructions_need_z180() {
+    let mut z80 = Z80::default();
+    // MLT BC
+    z80.load(&[0xED, 0x4C]);
+    let fault = fault_of(|| {
+        z80.step();
+    });
+    assert_eq!("Unknown ExtendeD operation 4c", fault.message);
+}
+
+#[test]
+fn r800() {
+    let mut z80 = Z80::default();
+    z80.set_variant(super::variant::CpuVariant::R800);
+    z80.load(&[
+        0x3E, 0xC8, // LD A, 200
+        0x06, 0x03, // LD B, 3
+        0xED, 0xC1, // MULUB A, B
+        0x01, 0x00, 0x01, // LD BC, 0x0100
+        0xED, 0xC3, // MULUW HL, BC
+        0x76, // HALT
+    ]);
+    for _ in 0..3 {
+        z80.step();
+    }
+    assert_eq!(600, z80.registers.get_reg16(&Reg16::HL));
+    assert!(z80.registers.get_flag(&StatusFlag::Carry));
+    assert_eq!(2 + 2 + 14, z80.tstates());
+
+    z80.run();
+    assert_eq!(0x0002, z80.registers.get_reg16(&Reg16::DE));
+    assert_eq!(0x5800, z80.registers.get_reg16(&Reg16::HL));
+    assert!(z80.registers.get_flag(&StatusFlag::Carry));
+    assert!(!z80.registers.get_flag(&StatusFlag::Zero));
+    assert_eq!(2 + 2 + 14 + 3 + 36 + 1, z80.tstates());
+}
+
+// A sliver of the SM83, for trying out instruction sets
+struct Sm83;
+
+impl super::isa::InstructionSet for Sm83 {
+    fn decode(&self, bytes: [u8; 4]) -> Result<(Op, usize), String> {
+        match bytes {
+            // LDH (n), A
+            [0xE0, n, _, _] => Ok((
+                Op::LD8(
+                    Location8::ImmediateIndirect(0xFF00 | u16::from(n)),
+                    Location8::Reg(Reg8::A),
+                ),
+                2,
+            )),
+            // STOP
+            [0x10, _, _, _] => Ok((Op::HALT, 2)),
+            [0xDD, ..] | [0xFD, ..] | [0xED, ..] => Err("not an SM83 opcode".to_string()),
+            _ => crate::cpu::opcodes::try_opcode(bytes),
+        }
+    }
+
+    // Every machine cycle is four clocks
+    fn machine_cycles(&self, op: &Op, taken: bool) -> Vec<crate::cpu::timing::MachineCycle> {
+        let mut cycles = crate::cpu::timing::machine_cycles(op, taken);
+        for (i, c) in cycles.iter_mut().enumerate() {
+            c.start = 4 * i as u32;
+            c.tstates = 4;
+        }
+        cycles
+    }
+}
+
+#[test]
+fn instruction_set() {
+    let mut z80 = Z80::default();
+    z80.set_bus(Box::new(FlatRam::new()));
+    z80.set_instruction_set(Box::new(Sm83));
+    // LD A, 0x42; LDH (0x80), A; STOP
+    z80.load(&[0x3E, 0x42, 0xE0, 0x80, 0x10, 0x00]);
+    z80.run();
+    assert_eq!(0x42, z80.peek_mem(0xFF80));
+    assert_eq!(0x0006, z80.registers.get_pc());
+    // The real LDH doesn't read the high byte, but it is timed as though it did here
+    assert_eq!(8 + 16 + 4, z80.tstates());
+
+    assert!(z80.clear_instruction_set().is_some());
+    // DJNZ is the Z80's reading of 0x10
+    z80.registers.set_pc(0x0004);
+    z80.set_halted(false);
+    z80.step();
+    assert!(!z80.is_halted());
+    assert_eq!(0xFF, z80.registers.get_reg8(Reg8::B));
+}
+
+#[test]
+fn i8080() {
+    let mut z80 = Z80::default();
+    z80.set_variant(super::variant::CpuVariant::I8080);
+    z80.load(&[
+        0x3E, 0x7F, // LD A, 0x7F
+        0xC6, 0x02, // ADD A, 2
+        0xF5, // PUSH AF
+        0xDD, 0x00, 0x01, // An alias of CALL 0x0100
+    ]);
+    z80.load_at(&[0x76], 0x0100);
+    for _ in 0..3 {
+        z80.step();
+    }
+    // 0x81 has even parity
+    assert!(z80.registers.get_flag(&StatusFlag::ParityOverflow));
+    let f = z80.memory.read_u8(0x3FFE);
+    assert_eq!(0b0000_0010, f & 0b0010_1010);
+
+    z80.run();
+    assert_eq!(0x0101, z80.registers.get_pc());
+    assert_eq!(0x0008, z80.memory.read_u16(0x3FFC));
+}
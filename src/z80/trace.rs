@@ -0,0 +1,104 @@
+//! Instruction traces, and comparing them against known-good ("golden") traces.
+//! A trace has one line per instruction, describing the processor just before it runs:
+//! ```text
+//! PC:0000 AF:0000 BC:0000 DE:0000 HL:0000 IX:0000 IY:0000 SP:4000 T:0 (3e 2a 06 07)
+//! ```
+//! The bytes in brackets are the four at the program counter. Tests can run a program, and diff
+//! its trace against one checked into the repository to find exactly where behaviour changed.
+use super::Z80;
+use crate::ops::{Reg16, Reg8};
+
+impl Z80 {
+    /// Describe the processor's state in the trace format
+    pub fn trace_line(&self) -> String {
+        let regs = &self.registers;
+        let pair = |high, low| u16::from_be_bytes([regs.get_reg8(high), regs.get_reg8(low)]);
+        let pc = regs.get_pc();
+        let bytes: Vec<String> = (0..4)
+            .map(|i| format!("{:02x}", self.peek_mem(pc.wrapping_add(i))))
+            .collect();
+        format!(
+            "PC:{:04X} AF:{:04X} BC:{:04X} DE:{:04X} HL:{:04X} IX:{:04X} IY:{:04X} SP:{:04X} T:{} ({})",
+            pc,
+            pair(Reg8::A, Reg8::F),
+            pair(Reg8::B, Reg8::C),
+            pair(Reg8::D, Reg8::E),
+            pair(Reg8::H, Reg8::L),
+            regs.get_reg16(&Reg16::IX),
+            regs.get_reg16(&Reg16::IY),
+            regs.get_reg16(&Reg16::SP),
+            self.tstates,
+            bytes.join(" "),
+        )
+    }
+
+    /// Step until the processor halts (or max_steps have run), returning the trace
+    pub fn trace_run(&mut self, max_steps: usize) -> Vec<String> {
+        let mut trace = vec![];
+        while !self.is_halted && trace.len() < max_steps {
+            trace.push(self.trace_line());
+            self.step();
+        }
+        trace
+    }
+}
+
+/// Compare a trace against a golden one.
+/// Returns None if they match, or a report showing `context` lines either side of the first
+/// difference otherwise.
+/// ```
+/// use zeerust::z80::{trace, Z80};
+///
+/// let mut z80 = Z80::default();
+/// z80.load(&[0x3E, 0x2A, 0x76]); // LD A, 42; HALT
+/// let golden = "\
+/// PC:0000 AF:0000 BC:0000 DE:0000 HL:0000 IX:0000 IY:0000 SP:4000 T:0 (3e 2a 76 00)
+/// PC:0002 AF:2A00 BC:0000 DE:0000 HL:0000 IX:0000 IY:0000 SP:4000 T:7 (76 00 00 00)
+/// ";
+/// assert_eq!(None, trace::diff(golden, &z80.trace_run(100), 3));
+/// ```
+pub fn diff(golden: &str, trace: &[String], context: usize) -> Option<String> {
+    let expected: Vec<&str> = golden.lines().collect();
+    let len = expected.len().max(trace.len());
+    let first =
+        (0..len).find(|i| expected.get(*i).copied() != trace.get(*i).map(String::as_str))?;
+
+    let mut report = format!("trace diverges from golden at line {}\n", first + 1);
+    let window = first.saturating_sub(context)..len.min(first + context + 1);
+    for i in window {
+        let e = expected.get(i).copied().unwrap_or("<end of trace>");
+        let a = trace.get(i).map_or("<end of trace>", String::as_str);
+        if e == a {
+            report += &format!("  {:>6}  {}\n", i + 1, e);
+        } else {
+            report += &format!("- {:>6}  {}\n+ {:>6}  {}\n", i + 1, e, i + 1, a);
+        }
+    }
+    Some(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn window() {
+        let golden = "a\nb\nc\nd\ne\nf\n";
+        let trace: Vec<String> = ["a", "b", "c", "x", "e"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(
+            "trace diverges from golden at line 4\n\
+             \x20      3  c\n\
+             -      4  d\n\
+             +      4  x\n\
+             \x20      5  e\n",
+            diff(golden, &trace, 1).unwrap()
+        );
+        assert!(diff(golden, &trace[..3], 0)
+            .unwrap()
+            .contains("+      4  <end of trace>"));
+        assert_eq!(None, diff("a\nb\n", &trace[..2], 3));
+    }
+}
@@ -0,0 +1,101 @@
+//! Traps let Rust code take over from the guest at specific addresses.
+//! When the program counter reaches a trapped address, the trap runs instead of (or before) the
+//! guest instruction there. This is how BDOS calls, tape loading and slow ROM routines can be
+//! emulated at a high level.
+//! For simpler cases, a fetch hook can swap the instruction at any address for another one.
+use super::Z80;
+use crate::ops::Op;
+
+/// What the processor should do once a trap has run
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TrapAction {
+    /// Execute the guest instruction at the trapped address as normal
+    Continue,
+    /// Return from the trapped routine, as if a RET had been executed
+    Return,
+    /// Continue execution from the given address
+    Jump(u16),
+}
+
+/// A trap is handed the whole processor, and can inspect or modify anything
+pub type Trap = Box<dyn FnMut(&mut Z80) -> TrapAction>;
+
+/// What to do instead of the instruction about to be fetched
+#[derive(Debug, PartialEq, Clone)]
+pub enum FetchOverride {
+    /// Execute this instruction instead, as though it were `length` bytes long
+    Exec(Op, u16),
+    /// Skip the instruction, continuing from the given address
+    Jump(u16),
+}
+
+/// A fetch hook is given the program counter, and returns None to run the guest instruction
+pub type FetchHook = Box<dyn FnMut(u16) -> Option<FetchOverride>>;
+
+impl Z80 {
+    /// Install a trap at the given address, replacing any trap already there. For example:
+    /// ```
+    /// use zeerust::z80;
+    /// use zeerust::z80::trap::TrapAction;
+    ///
+    /// let mut z80 = z80::Z80::default();
+    /// // Pretend a routine at 0x0005 printed something
+    /// z80.install_trap(0x0005, Box::new(|_z80| TrapAction::Return));
+    ///```
+    pub fn install_trap(&mut self, addr: u16, trap: Trap) {
+        self.traps.insert(addr, trap);
+    }
+
+    /// Remove the trap at the given address. Returns false if there was none.
+    pub fn remove_trap(&mut self, addr: u16) -> bool {
+        self.traps.remove(&addr).is_some()
+    }
+
+    /// Consult the hook before every instruction is fetched, replacing any hook already set.
+    /// For example, to make a delay routine at 0x1000 return at once:
+    /// ```
+    /// use zeerust::ops::{JumpConditional, Op};
+    /// use zeerust::z80::trap::FetchOverride;
+    /// use zeerust::z80::Z80;
+    ///
+    /// let mut z80 = Z80::default();
+    /// z80.on_fetch(Box::new(|pc| match pc {
+    ///     0x1000 => Some(FetchOverride::Exec(Op::RET(JumpConditional::Unconditional), 1)),
+    ///     _ => None,
+    /// }));
+    ///```
+    pub fn on_fetch(&mut self, hook: FetchHook) {
+        self.fetch_hook = Some(hook);
+    }
+
+    pub fn clear_fetch_hook(&mut self) {
+        self.fetch_hook = None;
+    }
+
+    /// Ask the fetch hook about the instruction at the program counter
+    pub(super) fn fetch_override(&mut self) -> Option<FetchOverride> {
+        let pc = self.registers.get_pc();
+        self.fetch_hook.as_mut().and_then(|hook| hook(pc))
+    }
+
+    /// Run the trap at the current program counter, if there is one.
+    /// Returns true if the trap has taken care of this step.
+    pub(super) fn run_trap(&mut self) -> bool {
+        let pc = self.registers.get_pc();
+        let mut trap = match self.traps.remove(&pc) {
+            Some(trap) => trap,
+            None => return false,
+        };
+        let action = trap(self);
+        self.traps.entry(pc).or_insert(trap);
+
+        let next = match action {
+            TrapAction::Continue => return false,
+            TrapAction::Return => self.pop_val(),
+            TrapAction::Jump(addr) => addr,
+        };
+        self.cycles.clear();
+        self.registers.set_pc(next);
+        true
+    }
+}
@@ -0,0 +1,61 @@
+//! The quirks of particular processors.
+//! Z80s from different makers and generations agree on the documented instructions, but not on
+//! everything else. Pick the variant of the hardware being emulated with set_variant.
+use super::Z80;
+use crate::ops::{Reg8, StatusFlag};
+
+/// A member of the Z80 family, and the behaviours that set it apart
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum CpuVariant {
+    /// The original NMOS Z80 (and the Z80A, which only runs faster).
+    /// `OUT (C), 0` outputs 0x00.
+    #[default]
+    Nmos,
+    /// The CMOS Z80, where `OUT (C), 0` outputs 0xFF instead
+    Cmos,
+    /// The Z180, with its extra instructions (MLT, TST, IN0, OUT0 and SLP) and internal I/O
+    /// registers (see the z180 module).
+    /// It traps undefined opcodes rather than running them as something else.
+    /// The trap pushes the address of the offending instruction and jumps to 0x0000, like
+    /// `RST 0x00`. The ITC register that would tell the handler what happened is not emulated.
+    Z180,
+    /// The R800 of the MSX turbo R. It runs the Z80 instruction set in far fewer clocks (see
+    /// `timing::r800_machine_cycles`), and adds the MULUB and MULUW multipliers.
+    /// Interrupts are still accepted in the Z80's time.
+    R800,
+    /// The Intel 8080. There are no prefixed instructions: the prefixes and the other opcodes
+    /// the Z80 added are aliases of 8080 instructions. Arithmetic sets the parity flag rather
+    /// than overflow, F bit 1 always reads as set and bits 3 and 5 as clear.
+    /// Instructions take the 8080's time (see `timing::i8080_machine_cycles`); interrupts are
+    /// still accepted in the Z80's.
+    I8080,
+}
+
+impl Z80 {
+    /// The processor being emulated
+    pub fn variant(&self) -> CpuVariant {
+        self.variant
+    }
+
+    pub fn set_variant(&mut self, variant: CpuVariant) {
+        self.variant = variant;
+    }
+
+    // Arithmetic sets P/V for overflow on a Z80, and for parity on an 8080
+    pub(super) fn overflow_flag(&mut self, overflow: bool, result: u8) {
+        let flag = match self.variant {
+            CpuVariant::I8080 => result.count_ones().is_multiple_of(2),
+            _ => overflow,
+        };
+        self.registers.set_flag(&StatusFlag::ParityOverflow, flag);
+    }
+
+    // Give F the fixed bits of the 8080's
+    pub(super) fn fix_flags(&mut self) {
+        if self.variant == CpuVariant::I8080 {
+            let f = self.registers.get_reg8(Reg8::F);
+            self.registers
+                .set_reg8(Reg8::F, f & 0b1101_0111 | 0b0000_0010);
+        }
+    }
+}
@@ -0,0 +1,123 @@
+//! Wait states: extra T-states requested by the hardware on specific accesses.
+//! Slow ROMs, video memory contention and anything else driving the WAIT line can stretch a machine
+//! cycle. The processor asks a WaitStates source about every memory and I/O cycle it makes.
+use std::cell::RefCell;
+
+use super::Z80;
+use crate::cpu::timing::CycleKind;
+
+/// Decides how many wait states to insert into each access
+pub trait WaitStates {
+    /// The number of extra T-states to add to a machine cycle of the given kind, accessing the
+    /// given address (or port, for I/O cycles).
+    fn wait_states(&self, kind: CycleKind, addr: u16) -> u32;
+}
+
+// The memory and I/O accesses made during a step, in order
+#[derive(Default)]
+pub(super) struct AccessLog(RefCell<Vec<(CycleKind, u16)>>);
+
+impl AccessLog {
+    pub(super) fn push(&self, kind: CycleKind, addr: u16) {
+        self.0.borrow_mut().push((kind, addr));
+    }
+
+    pub(super) fn clear(&self) {
+        self.0.borrow_mut().clear();
+    }
+
+    fn take(&self) -> Vec<(CycleKind, u16)> {
+        self.0.replace(vec![])
+    }
+}
+
+impl Z80 {
+    /// Insert wait states as decided by the given source, replacing any source already set.
+    /// ```
+    /// use zeerust::cpu::timing::CycleKind;
+    /// use zeerust::z80::{wait::WaitStates, Z80};
+    ///
+    /// // A ROM in the bottom 16K that needs an extra T-state on every read
+    /// struct SlowRom;
+    /// impl WaitStates for SlowRom {
+    ///     fn wait_states(&self, kind: CycleKind, addr: u16) -> u32 {
+    ///         match kind {
+    ///             CycleKind::OpcodeFetch | CycleKind::MemoryRead if addr < 0x4000 => 1,
+    ///             _ => 0,
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// let mut z80 = Z80::default();
+    /// z80.set_wait_states(Box::new(SlowRom));
+    /// z80.step();
+    /// assert_eq!(5, z80.tstates());
+    /// ```
+    pub fn set_wait_states(&mut self, source: Box<dyn WaitStates>) {
+        self.wait_states = Some(source);
+    }
+
+    /// Stop inserting wait states
+    pub fn clear_wait_states(&mut self) {
+        self.wait_states = None;
+    }
+
+    pub(super) fn log_access(&self, kind: CycleKind, addr: u16) {
+        if self.wait_states.is_some() {
+            self.accesses.push(kind, addr);
+        }
+    }
+
+    /// Stretch the cycles just taken by the wait states the source asks for.
+    /// Instruction bytes are fetched and read from consecutive addresses starting at `pc`;
+    /// every other access is matched, in order, with the accesses actually made.
+    pub(super) fn apply_wait_states(&mut self, pc: u16) {
+        let accesses = self.accesses.take();
+        let source = match &self.wait_states {
+            Some(source) => source,
+            None => return,
+        };
+        let logged = |kind| accesses.iter().filter(move |a| a.0 == kind).map(|a| a.1);
+        let mut reads = logged(CycleKind::MemoryRead);
+        let mut writes = logged(CycleKind::MemoryWrite);
+        let mut inputs = logged(CycleKind::IoRead);
+        let mut outputs = logged(CycleKind::IoWrite);
+
+        // Operand bytes are read before any data
+        let read_cycles = self
+            .cycles
+            .iter()
+            .filter(|c| c.kind == CycleKind::MemoryRead)
+            .count();
+        let mut operand_reads = read_cycles.saturating_sub(reads.clone().count());
+        let mut next_byte = pc;
+        let mut instruction_byte = || {
+            let addr = next_byte;
+            next_byte = next_byte.wrapping_add(1);
+            Some(addr)
+        };
+
+        let mut delay = 0;
+        for c in self.cycles.iter_mut() {
+            let addr = match c.kind {
+                CycleKind::OpcodeFetch => instruction_byte(),
+                CycleKind::MemoryRead if operand_reads > 0 => {
+                    operand_reads -= 1;
+                    instruction_byte()
+                }
+                CycleKind::MemoryRead => reads.next(),
+                CycleKind::MemoryWrite => writes.next(),
+                CycleKind::IoRead => inputs.next(),
+                CycleKind::IoWrite => outputs.next(),
+                CycleKind::InterruptAcknowledge => Some(pc),
+                CycleKind::Internal => None,
+            };
+            c.start += delay;
+            if let Some(addr) = addr {
+                let waits = source.wait_states(c.kind, addr);
+                c.tstates += waits;
+                delay += waits;
+            }
+        }
+    }
+}
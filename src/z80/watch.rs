@@ -0,0 +1,135 @@
+//! Watching registers for changes.
+//! A watch is called after every step that changes its register, including interrupts, which is
+//! handy for catching stack corruption or a stray write to I.
+use super::Z80;
+use crate::ops::{Reg16, Reg8};
+
+/// A register that can be watched: either an 8-bit or a 16-bit one
+#[derive(Debug, PartialEq, Clone)]
+pub enum Register {
+    R8(Reg8),
+    R16(Reg16),
+}
+
+impl From<Reg8> for Register {
+    fn from(reg: Reg8) -> Self {
+        Register::R8(reg)
+    }
+}
+
+impl From<Reg16> for Register {
+    fn from(reg: Reg16) -> Self {
+        Register::R16(reg)
+    }
+}
+
+/// A change to a watched register
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct RegChange {
+    /// Where the step that made the change began
+    pub pc: u16,
+    pub old: u16,
+    pub new: u16,
+}
+
+/// A watch is handed the processor (after the change) and what changed
+pub type RegWatch = Box<dyn FnMut(&Z80, RegChange)>;
+
+impl Z80 {
+    /// Watch a register, replacing any watch already on it. For example:
+    /// ```
+    /// use zeerust::ops::Reg16;
+    /// use zeerust::z80::Z80;
+    ///
+    /// let mut z80 = Z80::default();
+    /// z80.watch_reg(Reg16::SP, Box::new(|_z80, change| {
+    ///     assert!(change.new >= 0x3000, "stack overflow at {:04x}", change.pc)
+    /// }));
+    ///```
+    pub fn watch_reg<R: Into<Register>>(&mut self, reg: R, watch: RegWatch) {
+        let reg = reg.into();
+        self.watches.retain(|(r, _)| *r != reg);
+        self.watches.push((reg, watch));
+    }
+
+    /// Remove the watch on a register. Returns false if there was none.
+    pub fn unwatch_reg<R: Into<Register>>(&mut self, reg: R) -> bool {
+        let reg = reg.into();
+        let len = self.watches.len();
+        self.watches.retain(|(r, _)| *r != reg);
+        self.watches.len() != len
+    }
+
+    // The current value of every watched register
+    pub(super) fn watched_values(&self) -> Vec<u16> {
+        self.watches
+            .iter()
+            .map(|(reg, _)| self.register_value(reg))
+            .collect()
+    }
+
+    // Call the watches on any register that no longer has the value it had before
+    pub(super) fn check_watches(&mut self, pc: u16, before: Vec<u16>) {
+        if self.watches.is_empty() {
+            return;
+        }
+        let mut watches = std::mem::take(&mut self.watches);
+        for ((reg, watch), old) in watches.iter_mut().zip(before) {
+            let new = self.register_value(reg);
+            if new != old {
+                watch(self, RegChange { pc, old, new });
+            }
+        }
+        self.watches = watches;
+    }
+
+    fn register_value(&self, reg: &Register) -> u16 {
+        match reg {
+            Register::R8(r) => u16::from(self.registers.get_reg8(*r)),
+            Register::R16(r) => self.registers.get_reg16(r),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn changes() {
+        let mut z80 = Z80::default();
+        // PUSH HL; LD A, 0x12; LD I, A; POP HL; HALT
+        z80.load(&[0xE5, 0x3E, 0x12, 0xED, 0x47, 0xE1, 0x76]);
+        let seen = Rc::new(RefCell::new(vec![]));
+        let s = seen.clone();
+        z80.watch_reg(Reg16::SP, Box::new(move |_, c| s.borrow_mut().push(c)));
+        let s = seen.clone();
+        z80.watch_reg(Reg8::I, Box::new(move |_, c| s.borrow_mut().push(c)));
+        z80.run();
+        assert_eq!(
+            vec![
+                RegChange {
+                    pc: 0x0000,
+                    old: 0x4000,
+                    new: 0x3FFE
+                },
+                RegChange {
+                    pc: 0x0003,
+                    old: 0x00,
+                    new: 0x12
+                },
+                RegChange {
+                    pc: 0x0005,
+                    old: 0x3FFE,
+                    new: 0x4000
+                },
+            ],
+            *seen.borrow()
+        );
+
+        assert!(z80.unwatch_reg(Reg8::I));
+        assert!(!z80.unwatch_reg(Reg8::I));
+    }
+}
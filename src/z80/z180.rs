@@ -0,0 +1,39 @@
+//! The Z180's internal I/O registers.
+//! A Z180 keeps 64 control registers of its own in I/O space, at ports 0x0000 to 0x003F unless
+//! the I/O control register (ICR) moves them to 0x0040, 0x0080 or 0x00C0. Accesses there never
+//! reach the devices or bus.
+//! Only the registers themselves are emulated, not the peripherals behind them (MMU, serial
+//! ports, timers, DMA), so the program reads back whatever it last wrote. Use an I/O trace to
+//! see what it asked for.
+use super::variant::CpuVariant;
+use super::Z80;
+
+/// The number of internal I/O registers
+pub const IO_REGISTERS: usize = 64;
+/// The I/O control register, which picks where the internal registers live
+pub const ICR: u8 = 0x3F;
+
+impl Z80 {
+    /// Read an internal I/O register, by its number from 0x00 to 0x3F
+    pub fn z180_register(&self, reg: u8) -> u8 {
+        self.z180_io[usize::from(reg)]
+    }
+
+    pub fn set_z180_register(&mut self, reg: u8, val: u8) {
+        self.z180_io[usize::from(reg)] = val;
+    }
+
+    // The internal register a port reaches, if any
+    pub(super) fn z180_internal(&self, port: u16) -> Option<usize> {
+        if self.variant != CpuVariant::Z180 {
+            return None;
+        }
+        let base = u16::from(self.z180_io[usize::from(ICR)] & 0b1100_0000);
+        let offset = port.wrapping_sub(base);
+        if offset < IO_REGISTERS as u16 {
+            Some(usize::from(offset))
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,30 @@
+extern crate zeerust;
+
+use std::fs;
+use std::path::Path;
+
+use zeerust::cpu::opcodes::audit;
+
+// The gaps in the decoder are tracked in tests/decode_gaps.txt, which must be kept up to date.
+// Set ZEERUST_BLESS=1 to rewrite it.
+#[test]
+fn decode_gaps() {
+    let report = audit::report(&audit::audit());
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/decode_gaps.txt");
+    if std::env::var_os("ZEERUST_BLESS").is_some() {
+        fs::write(&path, &report).unwrap();
+        return;
+    }
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        report,
+        "decoder gaps have changed, rerun with ZEERUST_BLESS=1"
+    );
+}
+
+#[cfg(feature = "strict-decode")]
+#[test]
+fn decode_complete() {
+    let gaps = audit::audit();
+    assert!(gaps.is_empty(), "{}", audit::report(&gaps));
+}
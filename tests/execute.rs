@@ -1,6 +1,8 @@
 extern crate zeerust;
 
-use zeerust::examples::{COUNTDOWN_BIN, FIZZBUZZ_BIN, HELLO_WORLD_BIN, HELLO_ZEERUST_BIN};
+use zeerust::examples::{
+    COUNTDOWN_BIN, FIZZBUZZ_BIN, HELLO_WORLD_BIN, HELLO_ZEERUST_BIN, MEMCPY_BIN,
+};
 use zeerust::z80;
 
 fn run(program: &[u8]) -> Vec<u8> {
@@ -39,3 +41,8 @@ fn fizzbuzz() {
     .to_vec();
     assert_eq!(expected, run(FIZZBUZZ_BIN));
 }
+
+#[test]
+fn memcpy() {
+    assert_eq!(b"Z".to_vec(), run(MEMCPY_BIN));
+}
@@ -0,0 +1,106 @@
+extern crate zeerust;
+
+use std::cell::Cell;
+
+use zeerust::cpu::opcodes::table::{self, FlagEffect, Instruction, FLAGS};
+use zeerust::ops::{Reg16, Reg8};
+use zeerust::z80::io::{BufOutput, InputDevice};
+use zeerust::z80::Z80;
+
+struct Input(Cell<u8>);
+
+impl InputDevice for Input {
+    fn input(&self) -> u8 {
+        let value = self.0.get();
+        self.0.set(value.wrapping_add(0x35));
+        value
+    }
+}
+
+// Some bytes that decode to an instruction, with operands that keep it inside memory
+fn assemble(instruction: &Instruction) -> Option<[u8; 4]> {
+    let mut code = vec![];
+    let mut opcode = 0;
+    for byte in instruction.encoding.split(' ') {
+        match byte {
+            "n" => code.push(0x5A),
+            "nn" => code.extend_from_slice(&[0x34, 0x12]),
+            "e" => code.push(0x02),
+            "d" => code.push(0x05),
+            _ if byte.len() == 2 => code.push(u8::from_str_radix(byte, 16).unwrap()),
+            _ => {
+                opcode = code.len();
+                code.push(0);
+            }
+        }
+    }
+    let mut bytes = [0; 4];
+    bytes[..code.len()].copy_from_slice(&code);
+    (0..=0xFF).find_map(|op| {
+        if code.len() > opcode {
+            bytes[opcode] = op;
+        }
+        Some(bytes).filter(|b| table::lookup(*b) == Some(instruction))
+    })
+}
+
+// Every instruction in the table, run with F and the other registers set a few ways, leaves the
+// flags it says it doesn't change alone, and sets and resets the ones it says it does
+#[test]
+fn claims() {
+    let mut failures = vec![];
+    for instruction in table::instructions() {
+        let code = match assemble(instruction) {
+            Some(code) => code,
+            None => {
+                failures.push(format!("{}: nothing decodes to it", instruction.syntax));
+                continue;
+            }
+        };
+
+        for (seed, f) in [(0x00_u8, 0x00_u8), (0xFF, 0xFF), (0x80, 0x55), (0x01, 0xAA)] {
+            let mut z80 = Z80::default();
+            for port in 0..=0xFF {
+                z80.install_input(port, Box::new(Input(Cell::new(seed))));
+                z80.install_output(port, Box::new(BufOutput::default()));
+            }
+            z80.load(&code);
+            // B and D stay low enough for BC and DE to point into memory
+            for (i, reg) in [Reg8::A, Reg8::C, Reg8::E, Reg8::B, Reg8::D]
+                .iter()
+                .enumerate()
+            {
+                let value = seed.wrapping_add(i as u8 * 0x31);
+                let value = if i < 3 { value } else { value & 0x1F };
+                z80.registers.set_reg8(*reg, value);
+            }
+            for reg in [Reg16::HL, Reg16::IX, Reg16::IY] {
+                z80.registers.set_reg16(&reg, 0x2000);
+            }
+            z80.registers.set_reg16(&Reg16::SP, 0x3000);
+            z80.registers.set_reg8(Reg8::F, f);
+            z80.step();
+            let after = z80.registers.get_reg8(Reg8::F);
+
+            for (flag, bit) in FLAGS.iter() {
+                let expected = match instruction.flag_effect(flag) {
+                    Some(FlagEffect::Unchanged) => f & bit,
+                    Some(FlagEffect::Reset) => 0,
+                    Some(FlagEffect::Set) => *bit,
+                    _ => continue,
+                };
+                if after & bit != expected {
+                    failures.push(format!(
+                        "{} ({:02x?}) with F={:02x}: {:?} is {}",
+                        instruction.syntax,
+                        &code[..instruction.length],
+                        f,
+                        flag,
+                        after & bit != 0
+                    ));
+                }
+            }
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
@@ -0,0 +1,38 @@
+extern crate zeerust;
+
+use std::fs;
+use std::path::Path;
+
+use zeerust::examples::{COUNTDOWN_BIN, HELLO_ZEERUST_BIN};
+use zeerust::z80::{self, trace};
+
+// Run the program, and compare its trace against tests/golden/<name>.trace.
+// Set ZEERUST_BLESS=1 to write the current trace as the new golden one instead.
+fn check_golden(name: &str, program: &[u8]) {
+    let mut z80 = z80::Z80::default();
+    z80.install_output(0x00, Box::new(z80::io::BufOutput::default()));
+    z80.load(program);
+    let actual = z80.trace_run(10_000);
+
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{}.trace", name));
+    if std::env::var_os("ZEERUST_BLESS").is_some() {
+        fs::write(&path, actual.join("\n") + "\n").unwrap();
+        return;
+    }
+    let golden = fs::read_to_string(&path).unwrap();
+    if let Some(report) = trace::diff(&golden, &actual, 5) {
+        panic!("{}", report);
+    }
+}
+
+#[test]
+fn hello_zeerust() {
+    check_golden("hello_zeerust", HELLO_ZEERUST_BIN);
+}
+
+#[test]
+fn countdown() {
+    check_golden("countdown", COUNTDOWN_BIN);
+}
@@ -0,0 +1,152 @@
+extern crate zeerust;
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use zeerust::cpu::opcodes::table;
+
+// Every opcode page, as the bytes preceding the opcode
+const PAGES: [&[u8]; 7] = [
+    &[],
+    &[0xCB],
+    &[0xED],
+    &[0xDD],
+    &[0xFD],
+    &[0xDD, 0xCB],
+    &[0xFD, 0xCB],
+];
+
+// What the operands of the instructions in tests/zilog_opcodes.txt are filled in with
+const N: u8 = 0x2A;
+const NN: u16 = 0x1234;
+const D: i8 = -10;
+const E: i8 = 5;
+
+fn is_byte(token: &str) -> bool {
+    ["n", "nn", "d", "e"].contains(&token)
+        || (token.len() == 2 && u8::from_str_radix(token, 16).is_ok())
+}
+
+// The bytes of a line, with its operands filled in
+fn assemble(bytes: &[&str]) -> Vec<u8> {
+    let mut code = vec![];
+    for byte in bytes {
+        match *byte {
+            "n" => code.push(N),
+            "nn" => code.extend_from_slice(&NN.to_le_bytes()),
+            "d" => code.push(D as u8),
+            "e" => code.push(E as u8),
+            hex => code.push(u8::from_str_radix(hex, 16).unwrap()),
+        }
+    }
+    code
+}
+
+// The manual's syntax, with its operands filled in, written as the disassembler writes it
+fn expected(syntax: &str) -> String {
+    let (mnemonic, operands) = syntax.split_once(' ').unwrap_or((syntax, ""));
+    let operands: Vec<String> = operands
+        .split(',')
+        .filter(|o| !o.is_empty())
+        .map(|o| {
+            let o = o
+                .replace("+d", &format!("{:+}", D))
+                .replace("nn", &format!("${:04x}", NN))
+                .replace('n', &format!("${:02x}", N));
+            match o.as_str() {
+                "e" => E.to_string(),
+                // RST 38H
+                hex if hex.len() == 3 && hex.ends_with('H') => {
+                    format!("${}", hex[..2].to_lowercase())
+                }
+                _ => o,
+            }
+        })
+        .collect();
+    if operands.is_empty() {
+        mnemonic.to_string()
+    } else {
+        format!("{} {}", mnemonic, operands.join(", "))
+    }
+}
+
+// Every documented instruction the table has is decoded as the manual has it, and every one it
+// hasn't is reported as a gap rather than decoded as something else
+#[test]
+fn zilog() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/zilog_opcodes.txt");
+    let mut mismatches = vec![];
+    for line in fs::read_to_string(path).unwrap().lines() {
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let split = tokens.iter().position(|t| !is_byte(t)).unwrap();
+        let bytes = assemble(&tokens[..split]);
+        let syntax = tokens[split..].join(" ");
+        let mut code = [0; 4];
+        code[..bytes.len()].copy_from_slice(&bytes);
+
+        let decoded = table::decode(code);
+        let found = match (table::lookup(code), &decoded) {
+            (None, Err(_)) => continue,
+            (Some(_), Ok((op, length))) => (table::disassemble(op), *length),
+            _ => (format!("{:?}", decoded), 0),
+        };
+        if found != (expected(&syntax), bytes.len()) {
+            mismatches.push(format!(
+                "{:<14}{} was decoded as {}, {} bytes long",
+                tokens[..split].join(" "),
+                syntax,
+                found.0,
+                found.1
+            ));
+        }
+    }
+    assert!(mismatches.is_empty(), "\n{}", mismatches.join("\n"));
+}
+
+// Every op that's decoded is encoded back to bytes that decode to the same op
+#[test]
+fn round_trip() {
+    let mut used = HashSet::new();
+    for prefix in PAGES.iter() {
+        for opcode in 0..=0xFF_u8 {
+            for fill in [0x00, 0x01, 0x7F, 0x80, 0xFF] {
+                let mut code = [fill; 4];
+                code[..prefix.len()].copy_from_slice(prefix);
+                code[if prefix.len() == 2 { 3 } else { prefix.len() }] = opcode;
+                let (op, length) = match table::decode(code) {
+                    Ok(decoded) => decoded,
+                    Err(_) => continue,
+                };
+                let instruction = table::lookup(code).unwrap();
+                used.insert(&instruction.encoding);
+                assert_eq!(instruction.length, length);
+
+                let bytes = table::encode(&op)
+                    .unwrap_or_else(|| panic!("{:?} from {:02x?} can't be encoded", op, code));
+                let mut encoded = [0; 4];
+                encoded[..bytes.len()].copy_from_slice(&bytes);
+                assert_eq!(
+                    Ok((op.clone(), bytes.len())),
+                    table::decode(encoded),
+                    "{:02x?}",
+                    code
+                );
+                assert!(table::find(&op).is_some());
+            }
+        }
+    }
+
+    // A row that nothing decodes to is shadowed by an earlier one, and must be a mistake
+    for instruction in table::instructions() {
+        assert!(
+            used.contains(&instruction.encoding),
+            "nothing decodes to {} ({})",
+            instruction.syntax,
+            instruction.encoding
+        );
+    }
+}
@@ -0,0 +1,89 @@
+// The spans and events the tracing feature emits, as a subscriber sees them
+#![cfg(feature = "tracing")]
+extern crate zeerust;
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+use zeerust::frame::{FrameTimer, FrameTiming};
+use zeerust::z80::io::BufOutput;
+use zeerust::z80::Z80;
+
+// Writes down every span and event, with its fields
+#[derive(Default, Clone)]
+struct Recorder {
+    seen: Arc<Mutex<Vec<String>>>,
+    ids: Arc<AtomicU64>,
+}
+
+struct Fields(String);
+
+impl Visit for Fields {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.push_str(&format!(" {}={:?}", field.name(), value));
+    }
+}
+
+impl Subscriber for Recorder {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &Attributes) -> Id {
+        let mut fields = Fields(span.metadata().name().to_string());
+        span.record(&mut fields);
+        self.seen.lock().unwrap().push(fields.0);
+        Id::from_u64(self.ids.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+
+    fn record(&self, _: &Id, _: &Record) {}
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, event: &Event) {
+        let mut fields = Fields(String::new());
+        event.record(&mut fields);
+        self.seen
+            .lock()
+            .unwrap()
+            .push(fields.0.trim_start().to_string());
+    }
+
+    fn enter(&self, _: &Id) {}
+
+    fn exit(&self, _: &Id) {}
+}
+
+#[test]
+fn spans_and_events() {
+    let recorder = Recorder::default();
+    let mut z80 = Z80::default();
+    z80.install_output(0x00, Box::new(BufOutput::default()));
+    z80.load(&[0x3E, 0x2A, 0xD3, 0x00, 0x18, 0xFE]); // LD A, 42; OUT (0), A; JR -2
+    let mut timer = FrameTimer::new(FrameTiming {
+        tstates_per_line: 10,
+        lines_per_frame: 2,
+    });
+    tracing::subscriber::with_default(recorder.clone(), || {
+        assert!(z80.run_frame(&mut timer));
+        z80.request_nmi();
+        z80.step();
+    });
+    assert_eq!(
+        vec![
+            "frame",
+            "instruction pc=0 tstate=0 op=LD A, $2a",
+            "instruction pc=2 tstate=7 op=OUT ($00), A",
+            // OUT (n), A puts A on the top half of the address
+            "message=io tstate=14 port=10752 value=42 direction=Out",
+            "instruction pc=4 tstate=18 op=JR -2",
+            "message=frame frame=1",
+            "message=interrupt tstate=30 nmi=true",
+        ],
+        *recorder.seen.lock().unwrap()
+    );
+}
@@ -0,0 +1,81 @@
+extern crate zeerust;
+
+use std::cell::RefCell;
+use std::path::Path;
+use std::rc::Rc;
+
+use zeerust::audio::{self, Mixer};
+use zeerust::machine::cpc::{self, Cpc};
+use zeerust::machine::invaders::SpaceInvaders;
+use zeerust::machine::regression::{self, Tolerance};
+use zeerust::machine::Machine;
+
+// Run the machine, and compare its picture against tests/visual/<name>.ppm.
+// Set ZEERUST_BLESS=1 to write the current picture as the new reference instead.
+fn check_visual<M: Machine>(name: &str, machine: &mut M, frames: u64) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/visual")
+        .join(format!("{}.ppm", name));
+    if let Err(report) = regression::check_frames(machine, frames, &path, Tolerance::EXACT) {
+        panic!("{}", report);
+    }
+}
+
+#[test]
+fn invaders_stripes() {
+    let rom = [
+        0x21, 0x00, 0x24, // 0000 LD HL, 0x2400: the start of video RAM
+        0x75, // 0003 loop: LD (HL), L
+        0x2C, // 0004 INC L
+        0xC2, 0x03, 0x00, // 0005 JP NZ, loop
+        0x24, // 0008 INC H
+        0x7C, // 0009 LD A, H
+        0xFE, 0x40, // 000A CP 0x40
+        0xC2, 0x03, 0x00, // 000C JP NZ, loop
+        0x76, // 000F HALT
+    ];
+    check_visual("invaders_stripes", &mut SpaceInvaders::new(&rom), 2);
+}
+
+#[test]
+fn cpc_mode_1() {
+    let os = [
+        0x01, 0x00, 0x7F, // 0000 LD BC, 0x7F00: pen 0
+        0xED, 0x49, // 0003 OUT (C), C
+        0x0E, 0x54, // 0005 LD C, 0x54: black
+        0xED, 0x49, // 0007 OUT (C), C
+        0x0E, 0x01, // 0009 LD C, 1: pen 1
+        0xED, 0x49, // 000B OUT (C), C
+        0x0E, 0x4B, // 000D LD C, 0x4B: bright white
+        0xED, 0x49, // 000F OUT (C), C
+        0x0E, 0x02, // 0011 LD C, 2: pen 2
+        0xED, 0x49, // 0013 OUT (C), C
+        0x0E, 0x4C, // 0015 LD C, 0x4C: bright red
+        0xED, 0x49, // 0017 OUT (C), C
+        0x0E, 0x03, // 0019 LD C, 3: pen 3
+        0xED, 0x49, // 001B OUT (C), C
+        0x0E, 0x52, // 001D LD C, 0x52: bright green
+        0xED, 0x49, // 001F OUT (C), C
+        0x0E, 0x89, // 0021 LD C, 0x89: mode 1, upper ROM off
+        0xED, 0x49, // 0023 OUT (C), C
+        0x01, 0x01, 0xBC, // 0025 LD BC, 0xBC01: 8 characters across
+        0xED, 0x49, // 0028 OUT (C), C
+        0x01, 0x08, 0xBD, // 002A LD BC, 0xBD08
+        0xED, 0x49, // 002D OUT (C), C
+        0x01, 0x06, 0xBC, // 002F LD BC, 0xBC06: 4 rows down
+        0xED, 0x49, // 0032 OUT (C), C
+        0x01, 0x04, 0xBD, // 0034 LD BC, 0xBD04
+        0xED, 0x49, // 0037 OUT (C), C
+        0x21, 0x00, 0xC0, // 0039 LD HL, 0xC000
+        0x75, // 003C loop: LD (HL), L
+        0x2C, // 003D INC L
+        0x20, 0xFC, // 003E JR NZ, loop
+        0x24, // 0040 INC H
+        0x20, 0xF9, // 0041 JR NZ, loop
+        0x76, // 0043 HALT, with interrupts still off
+    ];
+    let mixer = Mixer::new(cpc::CLOCK, audio::RATE_44_1KHZ, 1024);
+    let mut machine = Cpc::new(&os, &[], Rc::new(RefCell::new(mixer)));
+    check_visual("cpc_mode_1", &mut machine, 20);
+    assert_eq!((128, 32), machine.screen_size());
+}